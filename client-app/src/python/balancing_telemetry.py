@@ -315,14 +315,13 @@ class BalancingRoverTelemetry(Collection):
         self._graph_data["data_points"] = TelemetryGraphData(self.telemetry_client, self.telemetry_client.streams['balance-data'], 'data_points', 10, 0)
         self._graph_data["status"] = TelemetryGraphData(self.telemetry_client, self.telemetry_client.streams['balance-data'], 'status', 255, 0)
         self._graph_data["fifo_status"] = TelemetryGraphData(self.telemetry_client, self.telemetry_client.streams['balance-data'], 'fifo_status', 255, 0)
-        self._graph_data["apitch"] = TelemetryGraphData(self.telemetry_client, self.telemetry_client.streams['balance-data'], 'apitch', 180.0, -180.0)
-        self._graph_data["aroll"] = TelemetryGraphData(self.telemetry_client, self.telemetry_client.streams['balance-data'], 'aroll', 180, -180.0)
-        self._graph_data["ayaw"] = TelemetryGraphData(self.telemetry_client, self.telemetry_client.streams['balance-data'], 'ayaw', 180, -180.0)
+        self._graph_data["atx"] = TelemetryGraphData(self.telemetry_client, self.telemetry_client.streams['balance-data'], 'atx', 180, -180.0)
+        self._graph_data["aty"] = TelemetryGraphData(self.telemetry_client, self.telemetry_client.streams['balance-data'], 'aty', 180.0, -180.0)
         self._graph_data["lw"] = TelemetryGraphData(self.telemetry_client, self.telemetry_client.streams['balance-data'], 'lw', 360, 0.0)
         self._graph_data["rw"] = TelemetryGraphData(self.telemetry_client, self.telemetry_client.streams['balance-data'], 'rw', 360, 0.0)
-        self._graph_data["cx"] = TelemetryGraphData(self.telemetry_client, self.telemetry_client.streams['balance-data'], 'cx', 180, -180.0)
-        self._graph_data["cy"] = TelemetryGraphData(self.telemetry_client, self.telemetry_client.streams['balance-data'], 'cy', 180, -180.0)
-        self._graph_data["cz"] = TelemetryGraphData(self.telemetry_client, self.telemetry_client.streams['balance-data'], 'cz', 180, -180.0)
+        self._graph_data["roll"] = TelemetryGraphData(self.telemetry_client, self.telemetry_client.streams['balance-data'], 'roll', 180, -180.0)
+        self._graph_data["pitch"] = TelemetryGraphData(self.telemetry_client, self.telemetry_client.streams['balance-data'], 'pitch', 180, -180.0)
+        self._graph_data["yaw"] = TelemetryGraphData(self.telemetry_client, self.telemetry_client.streams['balance-data'], 'yaw', 180, -180.0)
         self._graph_data["pi_p"] = TelemetryGraphData(self.telemetry_client, self.telemetry_client.streams['balance-data'], 'pi_p', 0.1, -0.1, auto_scale=True)
         self._graph_data["pi_i"] = TelemetryGraphData(self.telemetry_client, self.telemetry_client.streams['balance-data'], 'pi_i', 0.1, -0.1, auto_scale=True)
         self._graph_data["pi_d"] = TelemetryGraphData(self.telemetry_client, self.telemetry_client.streams['balance-data'], 'pi_d', 0.1, -0.1, auto_scale=True)
@@ -334,23 +333,23 @@ class BalancingRoverTelemetry(Collection):
         self._graph_data["out"] = TelemetryGraphData(self.telemetry_client, self.telemetry_client.streams['balance-data'], 'out', 0.1, -0.1, auto_scale=True)
         self._graph_data["pi_slo"] = ChangedSingleTelemetryGraphData(self.telemetry_client, self.telemetry_client.streams['balance-data'], 'out', 10.0, -10.0, lambda x: x * 100, auto_scale=True)
 
-        self.sensors_graphs_panel.main_graph.set_graph_data(self._graph_data['cy'])
+        self.sensors_graphs_panel.main_graph.set_graph_data(self._graph_data['pitch'])
         self.sensors_graphs_panel.graphs[0][0].set_graph_data(self._graph_data['gdy'])
         self.sensors_graphs_panel.graphs[0][1].set_graph_data(self._graph_data['gy'])
         self.sensors_graphs_panel.graphs[0][2].set_graph_data(self._graph_data['data_points'])
-        self.sensors_graphs_panel.graphs[0][3].set_graph_data(self._graph_data['cy'])
+        self.sensors_graphs_panel.graphs[0][3].set_graph_data(self._graph_data['pitch'])
 
         self.sensors_graphs_panel.graphs[1][0].set_graph_data(self._graph_data['adx'])
         self.sensors_graphs_panel.graphs[1][1].set_graph_data(self._graph_data['ady'])
         self.sensors_graphs_panel.graphs[1][2].set_graph_data(self._graph_data['adz'])
-        self.sensors_graphs_panel.graphs[1][3].set_graph_data(self._graph_data['aroll'])
+        self.sensors_graphs_panel.graphs[1][3].set_graph_data(self._graph_data['atx'])
 
         self.sensors_graphs_panel.graphs[2][0].set_graph_data(self._graph_data['lw'])
         self.sensors_graphs_panel.graphs[2][1].set_graph_data(self._graph_data['rw'])
         self.sensors_graphs_panel.graphs[2][2].set_graph_data(self._graph_data['az'])
-        self.sensors_graphs_panel.graphs[2][3].set_graph_data(self._graph_data['apitch'])
+        self.sensors_graphs_panel.graphs[2][3].set_graph_data(self._graph_data['aty'])
 
-        self.pid_graphs_panel.main_graph.set_graph_data(self._graph_data['cy'])
+        self.pid_graphs_panel.main_graph.set_graph_data(self._graph_data['pitch'])
         self.pid_graphs_panel.graphs[0][0].set_graph_data(self._graph_data['pi_pg'])
         self.pid_graphs_panel.graphs[0][1].set_graph_data(self._graph_data['pi_ig'])
         self.pid_graphs_panel.graphs[0][2].set_graph_data(self._graph_data['pi_dg'])
@@ -466,7 +465,7 @@ class BalancingRoverTelemetry(Collection):
 
     def save_graph(self, *_args):
 
-        fields_to_save = ["lw", "rw", "cy", "pi_p", "pi_i", "pi_d", "pi_pg", "pi_ig", "pi_dg", "pi_dt", "pi_o", "out"]
+        fields_to_save = ["lw", "rw", "pitch", "pi_p", "pi_i", "pi_d", "pi_pg", "pi_ig", "pi_dg", "pi_dt", "pi_o", "out"]
         field_indexes = [0]
 
         with open("logs.csv", "wt") as file:
@@ -476,6 +475,10 @@ class BalancingRoverTelemetry(Collection):
 
             def write_header(stream):
                 filtered_fields = [f.name for f in stream.fields if f.name in fields_to_save]
+                # Prefer the gyro sample time over the record write time when the stream has it
+                field_names = [f.name for f in stream.fields]
+                if "gt" in field_names:
+                    field_indexes[0] = field_names.index("gt") + 1
                 field_indexes.extend(i + 1 for i in range(len(stream.fields)) if stream.fields[i].name in filtered_fields)
                 print(f" fields {filtered_fields} and indexes {field_indexes}")
                 file.write("timestamp," + ",".join(f for f in filtered_fields) + "\n")
@@ -0,0 +1,106 @@
+################################################################################
+# Copyright (C) 2020 Abstract Horizon
+# All rights reserved. This program and the accompanying materials
+# are made available under the terms of the Apache License v2.0
+# which accompanies this distribution, and is available at
+# https://www.apache.org/licenses/LICENSE-2.0
+#
+#  Contributors:
+#    Daniel Sendula - initial API and implementation
+#
+#################################################################################
+
+#
+# Turns a capture of the rover's serial telemetry (see telemetry_serial.rs) into the byte
+# stream a TCP telemetry client receives, so the same tools can read either:
+#
+#   python3 serial_capture.py serial.bin tcp.bin
+#   cat /dev/ttyUSB0 | python3 serial_capture.py - - | ...
+#
+# Frames with a bad CRC are skipped; repeated stream definitions are dropped.
+#
+
+import struct
+import sys
+
+
+FRAME_SYNC = b"\xa5\x5a"
+FRAME_DEFINITIONS = ord("D")
+FRAME_META = ord("M")
+FRAME_RECORD = ord("R")
+FRAME_OVERHEAD = 7
+
+
+def crc16(data):
+    crc = 0xFFFF
+    for byte in data:
+        crc ^= byte << 8
+        for _ in range(8):
+            crc = ((crc << 1) ^ 0x1021) if crc & 0x8000 else (crc << 1)
+            crc &= 0xFFFF
+    return crc
+
+
+class SerialDeframer:
+    def __init__(self):
+        self.buf = b""
+        self.definitions = None
+        self.bad_frames = 0
+
+    # Returns TCP stream bytes for the complete frames in data; partial frames are kept for the next call.
+    def feed(self, data):
+        self.buf += data
+        result = b""
+        start = 0
+        while start + FRAME_OVERHEAD <= len(self.buf):
+            if self.buf[start:start + 2] != FRAME_SYNC:
+                start += 1
+                continue
+            frame_type, length = struct.unpack("<BH", self.buf[start + 2:start + 5])
+            end = start + 5 + length + 2
+            if end > len(self.buf):
+                break
+            crc = struct.unpack("<H", self.buf[end - 2:end])[0]
+            if crc != crc16(self.buf[start + 2:end - 2]):
+                self.bad_frames += 1
+                start += 1
+                continue
+
+            payload = self.buf[start + 5:end - 2]
+            if frame_type == FRAME_DEFINITIONS:
+                if self.definitions is None:
+                    self.definitions = payload
+                    result += payload
+                elif payload != self.definitions:
+                    print("Stream definitions changed mid capture; ignoring the new ones", file=sys.stderr)
+            elif self.definitions is not None:
+                # Records before the first definitions cannot be decoded
+                result += payload
+            start = end
+
+        self.buf = self.buf[start:]
+        return result
+
+
+def main(args):
+    if len(args) != 2:
+        print("Usage: serial_capture.py <serial capture|-> <tcp capture|->", file=sys.stderr)
+        return 1
+
+    source = sys.stdin.buffer if args[0] == "-" else open(args[0], "rb")
+    target = sys.stdout.buffer if args[1] == "-" else open(args[1], "wb")
+    deframer = SerialDeframer()
+    while True:
+        data = source.read1(4096) if hasattr(source, "read1") else source.read(4096)
+        if not data:
+            break
+        target.write(deframer.feed(data))
+        target.flush()
+
+    if deframer.bad_frames > 0:
+        print("Skipped {} frames with a bad CRC".format(deframer.bad_frames), file=sys.stderr)
+    return 0
+
+
+if __name__ == "__main__":
+    sys.exit(main(sys.argv[1:]))
@@ -0,0 +1,94 @@
+################################################################################
+# Copyright (C) 2020 Abstract Horizon
+# All rights reserved. This program and the accompanying materials
+# are made available under the terms of the Apache License v2.0
+# which accompanies this distribution, and is available at
+# https://www.apache.org/licenses/LICENSE-2.0
+#
+#  Contributors:
+#    Daniel Sendula - initial API and implementation
+#
+#################################################################################
+
+import socket
+import struct
+import time
+
+
+class SyncSample:
+    """One PING/PONG exchange: client send time, server wall clock at reply and client receive time."""
+    def __init__(self, client_sent, server_time, client_received):
+        self.client_sent = client_sent
+        self.server_time = server_time
+        self.client_received = client_received
+
+    def client_time(self):
+        # Assume the reply was taken half way through the round trip
+        return (self.client_sent + self.client_received) / 2.0
+
+
+class OffsetModel:
+    """server_time = client_time + offset + drift * (client_time - reference)"""
+    def __init__(self, offset, drift, reference):
+        self.offset = offset
+        self.drift = drift
+        self.reference = reference
+
+    def to_client_time(self, server_time):
+        return (server_time - self.offset + self.drift * self.reference) / (1.0 + self.drift)
+
+    def to_server_time(self, client_time):
+        return client_time + self.offset + self.drift * (client_time - self.reference)
+
+
+class Recording:
+    def __init__(self, name, sync_samples):
+        self.name = name
+        self.sync_samples = sync_samples
+
+
+def fit(sync_samples):
+    """Least squares fit of the server clock against the client clock."""
+    if len(sync_samples) == 0:
+        return OffsetModel(0.0, 0.0, 0.0)
+
+    xs = [s.client_time() for s in sync_samples]
+    offsets = [s.server_time - s.client_time() for s in sync_samples]
+    reference = sum(xs) / len(xs)
+    mean_offset = sum(offsets) / len(offsets)
+
+    variance = sum((x - reference) ** 2 for x in xs)
+    if variance == 0.0:
+        return OffsetModel(mean_offset, 0.0, reference)
+
+    drift = sum((x - reference) * (o - mean_offset) for x, o in zip(xs, offsets)) / variance
+    return OffsetModel(mean_offset, drift, reference)
+
+
+def align(recordings):
+    """Offset models mapping each recording's server timestamps onto the local (client) clock."""
+    return [fit(recording.sync_samples) for recording in recordings]
+
+
+def ping(host, port, count=8, network_byte_order=False):
+    """Performs the handshake PING exchange with a telemetry server and returns the sync samples."""
+    endian = ">" if network_byte_order else "<"
+    samples = []
+    with socket.create_connection((host, port)) as s:
+        if network_byte_order:
+            s.sendall(b"NBOR")
+        for _ in range(count):
+            client_sent = time.time()
+            s.sendall(b"PING" + struct.pack(endian + "d", client_sent))
+            reply = b""
+            while len(reply) < 32:
+                chunk = s.recv(32 - len(reply))
+                if not chunk:
+                    return samples
+                reply += chunk
+            client_received = time.time()
+            if reply[0:4] != b"PONG":
+                break
+            server_wall, _server_monotonic = struct.unpack(endian + "dd", reply[16:32])
+            samples.append(SyncSample(client_sent, server_wall, client_received))
+    return samples
@@ -0,0 +1,90 @@
+//
+// Copyright (C) 2020 Abstract Horizon
+// All rights reserved. This program and the accompanying materials
+// are made available under the terms of the Apache License v2.0
+// which accompanies this distribution, and is available at
+// https://www.apache.org/licenses/LICENSE-2.0
+//
+//  Contributors:
+//    Daniel Sendula - initial API and implementation
+//
+
+use core::fmt;
+
+
+// Outcome of a clamping or validating setter, so callers can report what actually took effect.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Applied {
+    Applied(f64),
+    Clamped { requested: f64, effective: f64 },
+    Rejected { requested: f64, reason: &'static str },
+}
+
+impl Applied {
+    pub fn clamp(requested: f64, min: Option<f64>, max: Option<f64>) -> Applied {
+        if !requested.is_finite() {
+            return Applied::Rejected { requested, reason: "not a number" };
+        }
+        let effective = match min { Some(min) => requested.max(min), None => requested };
+        let effective = match max { Some(max) => effective.min(max), None => effective };
+        if effective != requested {
+            Applied::Clamped { requested, effective }
+        } else {
+            Applied::Applied(requested)
+        }
+    }
+
+    // Value in effect after the setter; None when it was rejected and the old value stays.
+    pub fn effective(&self) -> Option<f64> {
+        match *self {
+            Applied::Applied(value) => Some(value),
+            Applied::Clamped { effective, .. } => Some(effective),
+            Applied::Rejected { .. } => None,
+        }
+    }
+
+    pub fn is_adjusted(&self) -> bool {
+        !matches!(self, Applied::Applied(_))
+    }
+}
+
+impl fmt::Display for Applied {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Applied::Applied(value) => write!(f, "{}", value),
+            Applied::Clamped { requested, effective } => write!(f, "{} clamped to {}", requested, effective),
+            Applied::Rejected { requested, reason } => write!(f, "{} rejected ({})", requested, reason),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn clamp_reports_what_took_effect() {
+        assert_eq!(Applied::clamp(0.5, Some(0.0), Some(1.0)), Applied::Applied(0.5));
+        assert_eq!(Applied::clamp(-0.5, Some(0.0), Some(1.0)), Applied::Clamped { requested: -0.5, effective: 0.0 });
+        assert_eq!(Applied::clamp(1.5, Some(0.0), Some(1.0)), Applied::Clamped { requested: 1.5, effective: 1.0 });
+        assert_eq!(Applied::clamp(1.5, None, None), Applied::Applied(1.5));
+    }
+
+    #[test]
+    fn clamp_rejects_nan_and_infinity() {
+        for requested in [f64::NAN, f64::INFINITY, f64::NEG_INFINITY].iter() {
+            let applied = Applied::clamp(*requested, Some(0.0), Some(1.0));
+            assert!(matches!(applied, Applied::Rejected { reason: "not a number", .. }), "{}", requested);
+            assert_eq!(applied.effective(), None);
+            assert!(applied.is_adjusted());
+        }
+    }
+
+    #[test]
+    fn only_applied_values_are_not_adjusted() {
+        assert!(!Applied::Applied(1.0).is_adjusted());
+        assert!(Applied::Clamped { requested: 2.0, effective: 1.0 }.is_adjusted());
+        assert_eq!(Applied::Clamped { requested: 2.0, effective: 1.0 }.effective(), Some(1.0));
+    }
+}
@@ -0,0 +1,103 @@
+//
+// Copyright (C) 2020 Abstract Horizon
+// All rights reserved. This program and the accompanying materials
+// are made available under the terms of the Apache License v2.0
+// which accompanies this distribution, and is available at
+// https://www.apache.org/licenses/LICENSE-2.0
+//
+//  Contributors:
+//    Daniel Sendula - initial API and implementation
+//
+
+// Driving while balancing: a forward speed and a turn rate, each from -1 (full backwards or
+// clockwise) to 1 (full forwards or counter-clockwise). A command only holds for timeout
+// seconds; after that both ramp back to zero, so the rover stops by itself when commands
+// stop arriving.
+
+use libm::fabs;
+
+
+pub struct Drive {
+    pub timeout: f64,
+    // Full scale per second the command ramps down by once it has timed out
+    pub decay_rate: f64,
+    forward: f64,
+    turn: f64,
+    last_command: f64,
+    last_update: f64,
+}
+
+impl Drive {
+    pub fn new(timeout: f64, decay_rate: f64) -> Drive {
+        Drive { timeout, decay_rate, forward: 0.0, turn: 0.0, last_command: 0.0, last_update: 0.0 }
+    }
+
+    // Values outside -1..1 are clamped and ones that are not numbers count as 0.
+    pub fn command(&mut self, time: f64, forward: f64, turn: f64) {
+        self.forward = clamp_unit(forward);
+        self.turn = clamp_unit(turn);
+        self.last_command = time;
+        self.last_update = time;
+    }
+
+    pub fn stop(&mut self) {
+        self.forward = 0.0;
+        self.turn = 0.0;
+    }
+
+    // Called every loop; returns the forward and turn to apply now.
+    pub fn update(&mut self, time: f64) -> (f64, f64) {
+        let expired = self.last_command + self.timeout;
+        if time > expired {
+            let decay = self.decay_rate * (time - self.last_update.max(expired)).max(0.0);
+            self.forward = towards_zero(self.forward, decay);
+            self.turn = towards_zero(self.turn, decay);
+        }
+        self.last_update = time;
+        (self.forward, self.turn)
+    }
+}
+
+fn clamp_unit(value: f64) -> f64 {
+    if value.is_nan() {
+        0.0
+    } else {
+        value.clamp(-1.0, 1.0)
+    }
+}
+
+fn towards_zero(value: f64, step: f64) -> f64 {
+    if fabs(value) <= step {
+        0.0
+    } else if value > 0.0 {
+        value - step
+    } else {
+        value + step
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn close(a: (f64, f64), b: (f64, f64)) -> bool {
+        fabs(a.0 - b.0) < 1e-9 && fabs(a.1 - b.1) < 1e-9
+    }
+
+    #[test]
+    fn commands_are_clamped_and_held_for_the_timeout() {
+        let mut drive = Drive::new(0.5, 2.0);
+        drive.command(10.0, 2.0, f64::NAN);
+        assert_eq!(drive.update(10.4), (1.0, 0.0));
+    }
+
+    #[test]
+    fn commands_ramp_down_after_the_timeout() {
+        let mut drive = Drive::new(0.5, 2.0);
+        drive.command(10.4, 0.5, -0.5);
+        assert!(close(drive.update(11.0), (0.3, -0.3)));
+        assert_eq!(drive.update(11.2), (0.0, 0.0));
+        assert_eq!(drive.update(12.0), (0.0, 0.0));
+    }
+}
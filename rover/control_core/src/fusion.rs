@@ -0,0 +1,284 @@
+//
+// Copyright (C) 2020 Abstract Horizon
+// All rights reserved. This program and the accompanying materials
+// are made available under the terms of the Apache License v2.0
+// which accompanies this distribution, and is available at
+// https://www.apache.org/licenses/LICENSE-2.0
+//
+//  Contributors:
+//    Daniel Sendula - initial API and implementation
+//
+
+// Sensor fusion of body frame gyro rates (deg/s) and accelerometer readings (g) into an
+// Orientation. All filters follow the conventions of the complementary filter: roll and pitch
+// grow with rate x and rate y and settle on tilt_about_x and tilt_about_y at rest.
+
+use core::f64::consts::PI;
+
+use libm::{asin, atan2, sqrt};
+
+use crate::orientation::{self, Orientation};
+
+
+pub trait SensorFusion {
+    fn update(&mut self, gyro_dps: (f64, f64, f64), accel_g: (f64, f64, f64), dt: f64) -> Orientation;
+
+    // Orientation as of the last update.
+    fn orientation(&self) -> Orientation;
+
+    // Roll and pitch from the accelerometer alone, e.g. after gyro samples were lost; yaw is kept.
+    fn reset_tilt(&mut self, accel_g: (f64, f64, f64));
+
+    // What the accelerometer corrected by in the last update, per body axis; see each filter.
+    fn error_terms(&self) -> (f64, f64, f64);
+}
+
+
+// Integrated gyro rates pulled towards the accelerometer tilts by 1 - gyro_accel_factor every update.
+pub struct Complementary {
+    pub gyro_accel_factor: f64,
+    orientation: Orientation,
+    // Degrees the accel tilts differ from roll and pitch integrated from the gyro alone
+    error: (f64, f64, f64),
+}
+
+impl Complementary {
+    pub fn new(gyro_accel_factor: f64, orientation: Orientation) -> Complementary {
+        Complementary { gyro_accel_factor, orientation, error: (0.0, 0.0, 0.0) }
+    }
+}
+
+impl SensorFusion for Complementary {
+    fn update(&mut self, gyro_dps: (f64, f64, f64), accel_g: (f64, f64, f64), dt: f64) -> Orientation {
+        let (rate_x, rate_y, rate_z) = gyro_dps;
+        let (accel_tilt_x, accel_tilt_y) = accel_tilts(accel_g);
+        self.error = (
+            accel_tilt_x - (self.orientation.roll + rate_x * dt),
+            accel_tilt_y - (self.orientation.pitch + rate_y * dt),
+            0.0
+        );
+        self.orientation.update((rate_x, rate_y, rate_z), (accel_tilt_x, accel_tilt_y), dt, self.gyro_accel_factor);
+        self.orientation
+    }
+
+    fn orientation(&self) -> Orientation {
+        self.orientation
+    }
+
+    fn reset_tilt(&mut self, accel_g: (f64, f64, f64)) {
+        let (accel_tilt_x, accel_tilt_y) = accel_tilts(accel_g);
+        self.orientation.reset_tilt(accel_tilt_x, accel_tilt_y);
+    }
+
+    fn error_terms(&self) -> (f64, f64, f64) {
+        self.error
+    }
+}
+
+
+// Mahony's filter: the gyro rates integrated into a quaternion, corrected by kp times the angle
+// between measured and estimated gravity, plus ki times its integral, which takes out gyro bias.
+//
+// Works in a right handed frame; the body frame above has pitch the other way round, so rate y
+// and pitch change sign on the way in and out.
+pub struct Mahony {
+    pub kp: f64,
+    pub ki: f64,
+    q: (f64, f64, f64, f64),
+    // Correction rates, rad/s, of the last update
+    error: (f64, f64, f64),
+    integral: (f64, f64, f64),
+}
+
+impl Mahony {
+    pub fn new(kp: f64, ki: f64, orientation: Orientation) -> Mahony {
+        let mut mahony = Mahony { kp, ki, q: (1.0, 0.0, 0.0, 0.0), error: (0.0, 0.0, 0.0), integral: (0.0, 0.0, 0.0) };
+        mahony.set_orientation(orientation);
+        mahony
+    }
+
+    // Integrated correction, rad/s; what the filter takes to be gyro bias.
+    pub fn integral(&self) -> (f64, f64, f64) {
+        self.integral
+    }
+
+    fn set_orientation(&mut self, orientation: Orientation) {
+        let half = |deg: f64| deg * PI / 360.0;
+        let (sr, cr) = (libm::sin(half(orientation.roll)), libm::cos(half(orientation.roll)));
+        let (sp, cp) = (libm::sin(half(-orientation.pitch)), libm::cos(half(-orientation.pitch)));
+        let (sy, cy) = (libm::sin(half(orientation.yaw)), libm::cos(half(orientation.yaw)));
+        self.q = (
+            cr * cp * cy + sr * sp * sy,
+            sr * cp * cy - cr * sp * sy,
+            cr * sp * cy + sr * cp * sy,
+            cr * cp * sy - sr * sp * cy,
+        );
+    }
+}
+
+impl SensorFusion for Mahony {
+    fn update(&mut self, gyro_dps: (f64, f64, f64), accel_g: (f64, f64, f64), dt: f64) -> Orientation {
+        let (q0, q1, q2, q3) = self.q;
+        let to_rad = PI / 180.0;
+        let (mut gx, mut gy, mut gz) = (gyro_dps.0 * to_rad, -gyro_dps.1 * to_rad, gyro_dps.2 * to_rad);
+
+        let (ax, ay, az) = accel_g;
+        let norm = sqrt(ax * ax + ay * ay + az * az);
+        self.error = (0.0, 0.0, 0.0);
+        // Free fall or no reading: the gyro alone until the accelerometer is back
+        if norm > 0.0 {
+            let (ax, ay, az) = (ax / norm, ay / norm, az / norm);
+            // Gravity as the quaternion has it, in body frame
+            let vx = 2.0 * (q1 * q3 - q0 * q2);
+            let vy = 2.0 * (q0 * q1 + q2 * q3);
+            let vz = q0 * q0 - q1 * q1 - q2 * q2 + q3 * q3;
+            let (ex, ey, ez) = (ay * vz - az * vy, az * vx - ax * vz, ax * vy - ay * vx);
+
+            if self.ki > 0.0 {
+                self.integral = (self.integral.0 + self.ki * ex * dt, self.integral.1 + self.ki * ey * dt, self.integral.2 + self.ki * ez * dt);
+            } else {
+                self.integral = (0.0, 0.0, 0.0);
+            }
+            self.error = (self.kp * ex + self.integral.0, self.kp * ey + self.integral.1, self.kp * ez + self.integral.2);
+            gx += self.error.0;
+            gy += self.error.1;
+            gz += self.error.2;
+        }
+
+        let (hx, hy, hz) = (gx * 0.5 * dt, gy * 0.5 * dt, gz * 0.5 * dt);
+        let q = (
+            q0 - q1 * hx - q2 * hy - q3 * hz,
+            q1 + q0 * hx + q2 * hz - q3 * hy,
+            q2 + q0 * hy - q1 * hz + q3 * hx,
+            q3 + q0 * hz + q1 * hy - q2 * hx,
+        );
+        let norm = sqrt(q.0 * q.0 + q.1 * q.1 + q.2 * q.2 + q.3 * q.3);
+        self.q = (q.0 / norm, q.1 / norm, q.2 / norm, q.3 / norm);
+        self.orientation()
+    }
+
+    fn orientation(&self) -> Orientation {
+        let (q0, q1, q2, q3) = self.q;
+        let to_deg = 180.0 / PI;
+        let sin_pitch = (2.0 * (q0 * q2 - q3 * q1)).clamp(-1.0, 1.0);
+        Orientation {
+            roll: atan2(2.0 * (q0 * q1 + q2 * q3), 1.0 - 2.0 * (q1 * q1 + q2 * q2)) * to_deg,
+            pitch: -asin(sin_pitch) * to_deg,
+            yaw: atan2(2.0 * (q0 * q3 + q1 * q2), 1.0 - 2.0 * (q2 * q2 + q3 * q3)) * to_deg,
+        }
+    }
+
+    fn reset_tilt(&mut self, accel_g: (f64, f64, f64)) {
+        let (accel_tilt_x, accel_tilt_y) = accel_tilts(accel_g);
+        let yaw = self.orientation().yaw;
+        self.set_orientation(Orientation { roll: accel_tilt_x, pitch: accel_tilt_y, yaw });
+        self.integral = (0.0, 0.0, 0.0);
+    }
+
+    // In rad/s, with the sign of rate y turned back to the body frame's
+    fn error_terms(&self) -> (f64, f64, f64) {
+        (self.error.0, -self.error.1, self.error.2)
+    }
+}
+
+
+fn accel_tilts(accel_g: (f64, f64, f64)) -> (f64, f64) {
+    let (x, y, z) = accel_g;
+    (orientation::tilt_about_x(x, y, z), orientation::tilt_about_y(x, y, z))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libm::{cos, fabs, sin};
+
+    const DT: f64 = 1.0 / 200.0;
+
+    // Body frame gravity, in g, of a rover at rest with the given roll and pitch in degrees
+    fn gravity(roll: f64, pitch: f64) -> (f64, f64, f64) {
+        let (roll, pitch) = (roll * PI / 180.0, pitch * PI / 180.0);
+        (sin(pitch), sin(roll) * cos(pitch), cos(roll) * cos(pitch))
+    }
+
+    fn filters() -> [(&'static str, Box<dyn SensorFusion>); 2] {
+        [
+            ("complementary", Box::new(Complementary::new(0.98, Orientation::new()))),
+            ("mahony", Box::new(Mahony::new(2.0, 0.0, Orientation::new()))),
+        ]
+    }
+
+    #[test]
+    fn complementary_blends_integrated_rate_with_accel_tilt() {
+        let mut filter = Complementary::new(0.98, Orientation::new());
+        let orientation = filter.update((10.0, 0.0, 4.0), gravity(5.0, 0.0), 0.1);
+        assert!(fabs(orientation.roll - (1.0 * 0.98 + 5.0 * 0.02)) < 1e-9, "{:?}", orientation);
+        assert!(fabs(orientation.pitch) < 1e-9, "{:?}", orientation);
+        assert!(fabs(orientation.yaw - 0.4) < 1e-9, "{:?}", orientation);
+    }
+
+    // Pitched forward and back at 30 deg/s, then rolled at 20 deg/s, a second each
+    #[test]
+    fn filters_follow_a_rotation() {
+        for (name, mut filter) in filters() {
+            let mut orientation = Orientation::new();
+            for i in 0..600 {
+                let t = (i % 200 + 1) as f64 * DT;
+                let (rates, roll, pitch) = match i / 200 {
+                    0 => ((0.0, 30.0, 0.0), 0.0, 30.0 * t),
+                    1 => ((0.0, -30.0, 0.0), 0.0, 30.0 - 30.0 * t),
+                    _ => ((20.0, 0.0, 0.0), 20.0 * t, 0.0),
+                };
+                orientation = filter.update(rates, gravity(roll, pitch), DT);
+            }
+            assert!(fabs(orientation.pitch) < 1.0 && fabs(orientation.roll - 20.0) < 1.0 && fabs(orientation.yaw) < 1.0,
+                "{} after rotating: {:?}", name, orientation);
+        }
+    }
+
+    #[test]
+    fn filters_settle_on_accel_tilt_and_reset_to_it() {
+        for (name, mut filter) in filters() {
+            let mut orientation = Orientation::new();
+            for _ in 0..2000 {
+                orientation = filter.update((0.0, 0.0, 0.0), gravity(0.0, 12.0), DT);
+            }
+            assert!(fabs(orientation.pitch - 12.0) < 0.1 && fabs(orientation.roll) < 0.1, "{} settled on {:?}", name, orientation);
+
+            filter.reset_tilt(gravity(0.0, -7.0));
+            let reset = filter.orientation();
+            assert!(fabs(reset.pitch + 7.0) < 1e-6 && fabs(reset.roll) < 1e-6, "{} reset to {:?}", name, reset);
+        }
+    }
+
+    #[test]
+    fn mahony_takes_out_gyro_bias() {
+        let mut mahony = Mahony::new(2.0, 0.5, Orientation::new());
+        let mut orientation = Orientation::new();
+        for _ in 0..6000 {
+            orientation = mahony.update((0.0, 1.0, 0.0), gravity(0.0, 0.0), DT);
+        }
+        let bias = mahony.error_terms().1 * 180.0 / PI;
+        assert!(fabs(orientation.pitch) < 0.1, "{:?}", orientation);
+        assert!(fabs(bias + 1.0) < 0.05, "correcting by {} deg/s", bias);
+    }
+
+    #[test]
+    fn mahony_starts_from_the_given_orientation() {
+        let start = Orientation { roll: 10.0, pitch: -25.0, yaw: 40.0 };
+        let carried = Mahony::new(1.0, 0.0, start).orientation();
+        assert!(fabs(carried.roll - start.roll) < 1e-9 && fabs(carried.pitch - start.pitch) < 1e-9 && fabs(carried.yaw - start.yaw) < 1e-9,
+            "started from {:?}, has {:?}", start, carried);
+    }
+
+    #[test]
+    fn mahony_runs_on_gyro_alone_without_gravity() {
+        let mut mahony = Mahony::new(2.0, 0.5, Orientation::new());
+        let mut orientation = Orientation::new();
+        for _ in 0..200 {
+            orientation = mahony.update((0.0, 0.0, 10.0), (0.0, 0.0, 0.0), DT);
+        }
+        assert!(fabs(orientation.yaw - 10.0) < 1e-6, "{:?}", orientation);
+        assert_eq!(mahony.integral(), (0.0, 0.0, 0.0));
+    }
+}
@@ -0,0 +1,26 @@
+//
+// Copyright (C) 2020 Abstract Horizon
+// All rights reserved. This program and the accompanying materials
+// are made available under the terms of the Apache License v2.0
+// which accompanies this distribution, and is available at
+// https://www.apache.org/licenses/LICENSE-2.0
+//
+//  Contributors:
+//    Daniel Sendula - initial API and implementation
+//
+
+// Control maths with no dependency on time sources, allocation or I/O: callers supply
+// time and dt. Floating point functions come from libm in every build so the Pi and the
+// co-processor compute bit-for-bit the same results.
+
+#![cfg_attr(not(feature = "std"), no_std)]
+
+pub mod pid;
+pub mod orientation;
+pub mod fusion;
+pub mod windup;
+pub mod applied;
+pub mod turn;
+pub mod drive;
+pub mod slew;
+pub mod velocity;
@@ -0,0 +1,277 @@
+//
+// Copyright (C) 2020 Abstract Horizon
+// All rights reserved. This program and the accompanying materials
+// are made available under the terms of the Apache License v2.0
+// which accompanies this distribution, and is available at
+// https://www.apache.org/licenses/LICENSE-2.0
+//
+//  Contributors:
+//    Daniel Sendula - initial API and implementation
+//
+
+// Body frame used for all orientation maths:
+//
+//   x - forward, the direction the rover drives in
+//   y - sideways, along the wheel axle
+//   z - up, along gravity when the rover stands upright
+//
+// Tilt about y (pitch) is the balancing angle; tilt about x (roll) is sideways lean.
+// Rotation about z (yaw) cannot be observed from gravity and is integrated from the gyro only.
+
+use core::f64::consts::PI;
+use core::fmt;
+
+use libm::{atan2, sqrt};
+
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SensorAxis {
+    X, Y, Z
+}
+
+impl SensorAxis {
+    pub fn name(&self) -> &'static str {
+        match self {
+            SensorAxis::X => "x",
+            SensorAxis::Y => "y",
+            SensorAxis::Z => "z",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct AxisSource {
+    pub axis: SensorAxis,
+    pub sign: f64,
+}
+
+impl AxisSource {
+    pub const fn new(axis: SensorAxis, sign: f64) -> AxisSource {
+        AxisSource { axis, sign }
+    }
+
+    pub fn select(&self, x: f64, y: f64, z: f64) -> f64 {
+        self.sign * match self.axis {
+            SensorAxis::X => x,
+            SensorAxis::Y => y,
+            SensorAxis::Z => z,
+        }
+    }
+}
+
+// Which sensor axis (and sign) feeds each body axis.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct AxisMapping {
+    pub x: AxisSource,
+    pub y: AxisSource,
+    pub z: AxisSource,
+}
+
+// IMU mounting on the rover: sensor x points up, sensor z points forward.
+pub const ROVER_AXIS_MAPPING: AxisMapping = AxisMapping {
+    x: AxisSource::new(SensorAxis::Z, 1.0),
+    y: AxisSource::new(SensorAxis::Y, 1.0),
+    z: AxisSource::new(SensorAxis::X, 1.0),
+};
+
+impl AxisMapping {
+    pub fn to_body(&self, x: f64, y: f64, z: f64) -> (f64, f64, f64) {
+        (self.x.select(x, y, z), self.y.select(x, y, z), self.z.select(x, y, z))
+    }
+
+    // Sensor axes feeding body x, y and z, each optionally signed, e.g. "y,-x,z"; every sensor
+    // axis must be used once. ROVER_AXIS_MAPPING is "z,y,x".
+    pub fn parse(s: &str) -> Result<AxisMapping, AxisMappingError<'_>> {
+        let mut sources = [AxisSource::new(SensorAxis::X, 1.0); 3];
+        let mut count = 0;
+        for (i, part) in s.split(',').enumerate() {
+            count += 1;
+            if i >= sources.len() {
+                continue;
+            }
+            let part = part.trim();
+            let (sign, name) = match part.strip_prefix('-') {
+                Some(name) => (-1.0, name),
+                None => (1.0, part.strip_prefix('+').unwrap_or(part))
+            };
+            let axis = match name.trim() {
+                "x" | "X" => SensorAxis::X,
+                "y" | "Y" => SensorAxis::Y,
+                "z" | "Z" => SensorAxis::Z,
+                _ => return Err(AxisMappingError::UnknownAxis(BODY_AXES[i], part))
+            };
+            if sources[..i].iter().any(|source| source.axis == axis) {
+                return Err(AxisMappingError::Repeated(axis));
+            }
+            sources[i] = AxisSource::new(axis, sign);
+        }
+        if count != sources.len() {
+            return Err(AxisMappingError::AxisCount(count));
+        }
+        Ok(AxisMapping { x: sources[0], y: sources[1], z: sources[2] })
+    }
+}
+
+const BODY_AXES: [&str; 3] = ["x", "y", "z"];
+
+// As parse takes it.
+impl fmt::Display for AxisMapping {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let sign = |source: &AxisSource| if source.sign < 0.0 { "-" } else { "" };
+        write!(f, "{}{},{}{},{}{}", sign(&self.x), self.x.axis.name(), sign(&self.y), self.y.axis.name(), sign(&self.z), self.z.axis.name())
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AxisMappingError<'a> {
+    // Number of comma separated parts, when not 3
+    AxisCount(usize),
+    // Body axis and what was given for it
+    UnknownAxis(&'static str, &'a str),
+    // Sensor axis given for more than one body axis
+    Repeated(SensorAxis),
+}
+
+impl fmt::Display for AxisMappingError<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AxisMappingError::AxisCount(count) => write!(f, "expected sensor axes for body x, y and z, e.g. \"y,-x,z\", got {} part(s)", count),
+            AxisMappingError::UnknownAxis(body_axis, given) => write!(f, "{:?} for body {} is not x, y or z with an optional sign", given, body_axis),
+            AxisMappingError::Repeated(axis) => write!(f, "sensor {} is given for more than one body axis", axis.name()),
+        }
+    }
+}
+
+
+// Tilt about body x axis (roll) in degrees from a body frame gravity vector.
+pub fn tilt_about_x(x: f64, y: f64, z: f64) -> f64 {
+    atan2(y, sqrt(x * x + z * z)) * 180.0 / PI
+}
+
+// Tilt about body y axis (pitch) in degrees from a body frame gravity vector.
+pub fn tilt_about_y(x: f64, y: f64, z: f64) -> f64 {
+    atan2(x, sqrt(y * y + z * z)) * 180.0 / PI
+}
+
+
+// Complementary filter combining integrated gyro rates with accelerometer tilts; see fusion for
+// it and other filters behind a common trait.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Orientation {
+    pub roll: f64,
+    pub pitch: f64,
+    pub yaw: f64,
+}
+
+impl Orientation {
+    pub fn new() -> Orientation {
+        Orientation { roll: 0.0, pitch: 0.0, yaw: 0.0 }
+    }
+
+    // Rates are body frame deg/s, accel tilts are in degrees and dt in seconds.
+    pub fn update(&mut self, rates: (f64, f64, f64), accel_tilts: (f64, f64), dt: f64, gyro_accel_factor: f64) {
+        let (rate_x, rate_y, rate_z) = rates;
+        let (accel_tilt_x, accel_tilt_y) = accel_tilts;
+        let invert_gyro_accel_factor = 1.0 - gyro_accel_factor;
+
+        self.roll = (self.roll + rate_x * dt) * gyro_accel_factor + accel_tilt_x * invert_gyro_accel_factor;
+        self.pitch = (self.pitch + rate_y * dt) * gyro_accel_factor + accel_tilt_y * invert_gyro_accel_factor;
+        self.yaw += rate_z * dt;
+    }
+
+    // Takes roll and pitch straight from the accelerometer, e.g. after gyro samples were lost;
+    // yaw has nothing to go back to and is kept.
+    pub fn reset_tilt(&mut self, accel_tilt_x: f64, accel_tilt_y: f64) {
+        self.roll = accel_tilt_x;
+        self.pitch = accel_tilt_y;
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libm::{cos, fabs, sin};
+
+    fn close(a: f64, b: f64) -> bool {
+        fabs(a - b) < 1e-9
+    }
+
+    // Body frame gravity for a rover pitched forward by pitch and then rolled by roll, in degrees.
+    fn gravity(pitch: f64, roll: f64) -> (f64, f64, f64) {
+        let (pitch, roll) = (pitch.to_radians(), roll.to_radians());
+        (sin(pitch), cos(pitch) * sin(roll), cos(pitch) * cos(roll))
+    }
+
+    #[test]
+    fn level_has_no_tilt() {
+        assert_eq!(tilt_about_x(0.0, 0.0, 1.0), 0.0);
+        assert_eq!(tilt_about_y(0.0, 0.0, 1.0), 0.0);
+    }
+
+    #[test]
+    fn pitch_is_tilt_about_y_only() {
+        for pitch in [30.0, -30.0].iter() {
+            let (x, y, z) = gravity(*pitch, 0.0);
+            assert!(close(tilt_about_y(x, y, z), *pitch), "pitch {} gave {}", pitch, tilt_about_y(x, y, z));
+            assert!(close(tilt_about_x(x, y, z), 0.0), "pitch {} gave roll {}", pitch, tilt_about_x(x, y, z));
+        }
+    }
+
+    #[test]
+    fn roll_is_tilt_about_x_only() {
+        for roll in [30.0, -30.0].iter() {
+            let (x, y, z) = gravity(0.0, *roll);
+            assert!(close(tilt_about_x(x, y, z), *roll), "roll {} gave {}", roll, tilt_about_x(x, y, z));
+            assert!(close(tilt_about_y(x, y, z), 0.0), "roll {} gave pitch {}", roll, tilt_about_y(x, y, z));
+        }
+    }
+
+    #[test]
+    fn tilts_do_not_depend_on_the_magnitude() {
+        let (x, y, z) = gravity(30.0, 0.0);
+        assert!(close(tilt_about_y(2.0 * x, 2.0 * y, 2.0 * z), 30.0));
+        let (x, y, z) = gravity(0.0, -30.0);
+        assert!(close(tilt_about_x(0.5 * x, 0.5 * y, 0.5 * z), -30.0));
+    }
+
+    #[test]
+    fn near_vertical_axes_stay_finite_and_signed() {
+        // Lying on its front or back, and on either side
+        assert!(close(tilt_about_y(1.0, 0.0, 0.0), 90.0));
+        assert!(close(tilt_about_y(-1.0, 0.0, 0.0), -90.0));
+        assert!(close(tilt_about_x(0.0, 1.0, 0.0), 90.0));
+        assert!(close(tilt_about_x(0.0, -1.0, 0.0), -90.0));
+
+        // Just short of it from either side of z
+        for z in [1e-6, -1e-6].iter() {
+            let tilt = tilt_about_y(1.0, 0.0, *z);
+            assert!(tilt > 89.9999 && tilt < 90.0, "z {} gave {}", z, tilt);
+        }
+        let (x, y, z) = gravity(89.0, 0.0);
+        assert!(fabs(tilt_about_y(x, y, z) - 89.0) < 1e-9);
+        assert!(fabs(tilt_about_x(x, y, z)) < 1e-9);
+    }
+
+    #[test]
+    fn mappings_read_back_as_written_signs_included() {
+        let mapping = AxisMapping::parse("y,-x,z").unwrap();
+        assert_eq!(mapping.to_body(1.0, 2.0, 3.0), (2.0, -1.0, 3.0));
+        assert_eq!(mapping.to_string(), "y,-x,z");
+        assert_eq!(AxisMapping::parse(" Z, +y ,x"), Ok(ROVER_AXIS_MAPPING));
+        assert_eq!(ROVER_AXIS_MAPPING.to_string(), "z,y,x");
+    }
+
+    #[test]
+    fn mistyped_mappings_name_the_part_at_fault() {
+        for (s, expected) in [
+            ("y,-w,z", AxisMappingError::UnknownAxis("y", "-w")),
+            ("y,x", AxisMappingError::AxisCount(2)),
+            ("x,y,z,x", AxisMappingError::AxisCount(4)),
+            ("x,-x,z", AxisMappingError::Repeated(SensorAxis::X)),
+            ("", AxisMappingError::UnknownAxis("x", "")),
+        ].iter() {
+            assert_eq!(AxisMapping::parse(s), Err(*expected), "{:?}", s);
+        }
+    }
+}
@@ -0,0 +1,265 @@
+//
+// Copyright (C) 2020 Abstract Horizon
+// All rights reserved. This program and the accompanying materials
+// are made available under the terms of the Apache License v2.0
+// which accompanies this distribution, and is available at
+// https://www.apache.org/licenses/LICENSE-2.0
+//
+//  Contributors:
+//    Daniel Sendula - initial API and implementation
+//
+
+use libm::fabs;
+
+use crate::applied::Applied;
+
+#[allow(non_snake_case)]
+pub fn SIMPLE_DIFFERENCE(x: f64, y: f64) -> f64 { x - y }
+
+pub struct PID {
+    pub set_point: f64,
+    pub p: f64,
+    pub i: f64,
+    pub d: f64,
+    pub kp: f64,
+    pub ki: f64,
+    pub kd: f64,
+    pub kg: f64,
+    pub i_gain_scale: f64,
+    pub d_gain_scale: f64,
+    pub dead_band: f64,
+    pub i_limit: f64,
+    pub last_error: f64,
+    pub last_time: f64,
+    pub last_output: f64,
+    pub last_delta: f64,
+    // Set once a non-finite value reached the controller; stays set until clear_fault
+    pub fault: bool,
+    first: bool,
+    difference: fn(f64, f64) -> f64,
+}
+
+impl PID {
+    // Same arguments, in the same order, as PID in pid.py
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        p_gain: f64, i_gain: f64, d_gain: f64, gain: f64,
+        dead_band: f64, i_gain_scale: f64, d_gain_scale: f64,
+        difference: fn(f64, f64) -> f64) -> PID {
+
+        PID {
+            set_point: 0.0,
+            p: 0.0, i: 0.0, d: 0.0,
+            kp: p_gain, ki: i_gain, kd: d_gain, kg: gain,
+            i_gain_scale, d_gain_scale,
+            dead_band,
+            i_limit: f64::INFINITY,
+            last_error: 0.0,
+            last_time: 0.0,
+            last_output: 0.0,
+            last_delta: 0.0,
+            fault: false,
+            first: true,
+            difference
+        }
+    }
+
+    pub fn with_i_limit(mut self, i_limit: f64) -> PID {
+        self.set_i_limit(i_limit);
+        self
+    }
+
+    // Infinity leaves the integrator unclamped; negative limits are rejected.
+    pub fn set_i_limit(&mut self, i_limit: f64) -> Applied {
+        if i_limit.is_nan() || i_limit < 0.0 {
+            Applied::Rejected { requested: i_limit, reason: "must not be negative" }
+        } else {
+            self.i_limit = i_limit;
+            Applied::Applied(i_limit)
+        }
+    }
+
+    pub fn process(&mut self, time:f64, set_point: f64, current: f64) -> f64 {
+
+        let mut error = (self.difference)(set_point, current);
+
+        if !error.is_finite() {
+            self.fault = true;
+            error = 0.0;
+        }
+
+        if fabs(error) <= self.dead_band {
+            error = 0.0;
+        }
+
+        if self.first {
+            self.first = false;
+            self.set_point = set_point;
+            self.last_error = error;
+            self.last_time = time;
+
+            0.0
+        } else if time - self.last_time <= 0.0 || (time - self.last_time).is_nan() {
+            // No time passed, or it went backwards: nothing to integrate or differentiate over,
+            // so the last output holds and the next call counts from here
+            self.set_point = set_point;
+            self.last_error = error;
+            self.last_time = time;
+            self.last_output
+        } else {
+            let delta_time = time - self.last_time;
+
+            self.p = error;
+            if (self.last_error < 0.0 && 0.0 < error) || (self.last_error > 0.0 && 0.0 > error) {
+                self.i = 0.0
+            } else if fabs(error) <= 0.01 {
+                self.i = 0.0;
+            } else {
+                self.i += error * delta_time * self.i_gain_scale
+            }
+
+            // anti-windup: keep the integral contribution (i * ki) within i_limit
+            if self.ki != 0.0 {
+                let i_max = self.i_limit / fabs(self.ki);
+                self.i = self.i.max(-i_max).min(i_max);
+            }
+
+            self.d = (error - self.last_error) / (delta_time * self.d_gain_scale);
+
+            let mut output = self.p * self.kp + self.i * self.ki + self.d * self.kd;
+
+            output *= self.kg;
+
+            if !output.is_finite() {
+                // never hand a non-finite output on; drop the state it came from
+                self.fault = true;
+                self.i = 0.0;
+                self.d = 0.0;
+                output = 0.0;
+            }
+
+            self.set_point = set_point;
+            self.last_output = output;
+            self.last_error = error;
+            self.last_time = time;
+            self.last_delta = delta_time;
+
+            output
+        }
+    }
+
+    pub fn clear_fault(&mut self) {
+        self.fault = false;
+    }
+
+    pub fn integrator_fraction(&self) -> f64 {
+        if self.i_limit.is_finite() && self.i_limit > 0.0 {
+            fabs(self.i * self.ki) / self.i_limit
+        } else {
+            0.0
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn close(a: f64, b: f64) -> bool {
+        fabs(a - b) < 1e-9
+    }
+
+    #[test]
+    fn proportional_term_is_scaled_by_gain() {
+        let mut pid = PID::new(2.0, 0.0, 0.0, 1.5, 0.0, 1.0, 1.0, SIMPLE_DIFFERENCE);
+        assert_eq!(pid.process(0.0, 1.0, 0.0), 0.0);
+        assert!(close(pid.process(0.1, 1.0, 0.0), 3.0));
+    }
+
+    #[test]
+    fn integral_accumulates_and_resets_on_sign_change() {
+        let mut pid = PID::new(0.0, 1.0, 0.0, 1.0, 0.0, 1.0, 1.0, SIMPLE_DIFFERENCE);
+        pid.process(0.0, 1.0, 0.0);
+        assert!(close(pid.process(0.1, 1.0, 0.0), 0.1));
+        assert!(close(pid.process(0.3, 1.0, 0.0), 0.3));
+        assert_eq!(pid.process(0.4, -1.0, 0.0), 0.0);
+        assert_eq!(pid.i, 0.0);
+    }
+
+    #[test]
+    fn derivative_is_divided_by_its_scale() {
+        let mut pid = PID::new(0.0, 0.0, 1.0, 1.0, 0.0, 1.0, 100.0, SIMPLE_DIFFERENCE);
+        pid.process(0.0, 0.0, 0.0);
+        assert!(close(pid.process(0.01, 1.0, 0.0), 1.0));
+    }
+
+    #[test]
+    fn errors_within_dead_band_are_zero() {
+        let mut pid = PID::new(1.0, 1.0, 1.0, 1.0, 0.5, 1.0, 1.0, SIMPLE_DIFFERENCE);
+        pid.process(0.0, 0.4, 0.0);
+        assert_eq!(pid.process(0.1, 0.4, 0.0), 0.0);
+    }
+
+    #[test]
+    fn integrator_is_unbounded_by_default() {
+        let mut pid = PID::new(0.0, 2.0, 0.0, 1.0, 0.0, 1.0, 1.0, SIMPLE_DIFFERENCE);
+        pid.process(0.0, 1.0, 0.0);
+        for step in 1..=100 {
+            pid.process(step as f64 * 0.1, 1.0, 0.0);
+        }
+        assert!(close(pid.i, 10.0));
+        assert_eq!(pid.integrator_fraction(), 0.0);
+    }
+
+    #[test]
+    fn i_limit_bounds_the_integral_contribution() {
+        let mut pid = PID::new(0.0, 2.0, 0.0, 1.0, 0.0, 1.0, 1.0, SIMPLE_DIFFERENCE).with_i_limit(0.5);
+        pid.process(0.0, 1.0, 0.0);
+        for step in 1..=100 {
+            pid.process(step as f64 * 0.1, 1.0, 0.0);
+        }
+        assert!(close(pid.last_output, 0.5));
+        assert!(close(pid.integrator_fraction(), 1.0));
+        assert!(pid.set_i_limit(-1.0).is_adjusted());
+        assert!(close(pid.i_limit, 0.5));
+    }
+
+    #[test]
+    fn negative_or_nan_i_limit_is_rejected_and_the_old_one_stays() {
+        let mut pid = PID::new(0.0, 2.0, 0.0, 1.0, 0.0, 1.0, 1.0, SIMPLE_DIFFERENCE).with_i_limit(0.5);
+        assert_eq!(pid.set_i_limit(-1.0), Applied::Rejected { requested: -1.0, reason: "must not be negative" });
+        assert!(matches!(pid.set_i_limit(f64::NAN), Applied::Rejected { .. }));
+        assert!(close(pid.i_limit, 0.5));
+
+        assert_eq!(pid.set_i_limit(f64::INFINITY), Applied::Applied(f64::INFINITY));
+        assert_eq!(pid.set_i_limit(0.0), Applied::Applied(0.0));
+        assert_eq!(pid.i_limit, 0.0);
+    }
+
+    #[test]
+    fn non_finite_input_sets_fault_and_gives_finite_output() {
+        let mut pid = PID::new(1.0, 1.0, 1.0, 1.0, 0.0, 1.0, 1.0, SIMPLE_DIFFERENCE);
+        pid.process(0.0, 0.0, 0.0);
+        let output = pid.process(0.01, 0.0, f64::NAN);
+        assert!(output.is_finite());
+        assert!(pid.fault);
+        pid.clear_fault();
+        assert!(!pid.fault);
+    }
+
+    #[test]
+    fn clock_step_back_holds_last_output() {
+        let mut pid = PID::new(1.0, 1.0, 1.0, 1.0, 0.0, 1.0, 1.0, SIMPLE_DIFFERENCE);
+        pid.process(1.0, 0.0, 1.0);
+        let output = pid.process(1.01, 0.0, 2.0);
+        let i = pid.i;
+        for time in [1.01, 0.5].iter() {
+            assert_eq!(pid.process(*time, 0.0, 3.0), output, "at time {}", time);
+            assert_eq!(pid.i, i, "at time {}", time);
+        }
+        let output = pid.process(0.51, 0.0, 3.0);
+        assert!(output.is_finite());
+        assert!(close(pid.last_delta, 0.01));
+    }
+}
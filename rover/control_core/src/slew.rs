@@ -0,0 +1,102 @@
+//
+// Copyright (C) 2020 Abstract Horizon
+// All rights reserved. This program and the accompanying materials
+// are made available under the terms of the Apache License v2.0
+// which accompanies this distribution, and is available at
+// https://www.apache.org/licenses/LICENSE-2.0
+//
+//  Contributors:
+//    Daniel Sendula - initial API and implementation
+//
+
+// Slew rate limiting: a value that follows its target by at most max_rate per second, so a step
+// from 0 to 0.8 becomes a ramp. A max_rate of 0 (or below) is no limit.
+
+
+pub struct SlewLimiter {
+    pub max_rate: f64,
+    value: f64,
+    last_time: Option<f64>,
+}
+
+impl SlewLimiter {
+    pub fn new(max_rate: f64) -> SlewLimiter {
+        SlewLimiter { max_rate, value: 0.0, last_time: None }
+    }
+
+    // Moves towards target by what max_rate allows since the last update or reset; a new target
+    // mid ramp ramps on from where this one got to.
+    pub fn update(&mut self, time: f64, target: f64) -> f64 {
+        let elapsed = self.last_time.map_or(0.0, |last_time| (time - last_time).max(0.0));
+        self.last_time = Some(time);
+        if self.max_rate.is_nan() || self.max_rate <= 0.0 || target.is_nan() {
+            self.value = target;
+        } else {
+            let step = self.max_rate * elapsed;
+            self.value = target.max(self.value - step).min(self.value + step);
+        }
+        self.value
+    }
+
+    // Jumps to value, cancelling any ramp; e.g. when the output was stopped by other means.
+    pub fn reset(&mut self, time: f64, value: f64) {
+        self.value = value;
+        self.last_time = Some(time);
+    }
+
+    pub fn value(&self) -> f64 {
+        self.value
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn close(a: f64, b: f64) -> bool {
+        libm::fabs(a - b) < 1e-9
+    }
+
+    #[test]
+    fn steps_become_ramps_at_max_rate() {
+        let mut slew = SlewLimiter::new(2.0);
+        let steps = [(0.0, 0.0, 0.0), (0.1, 0.8, 0.2), (0.2, 0.8, 0.4), (0.3, 0.8, 0.6), (0.45, 0.8, 0.8), (0.5, -1.0, 0.7), (0.6, -1.0, 0.5)];
+        for (time, target, expected) in steps.iter() {
+            let value = slew.update(*time, *target);
+            assert!(close(value, *expected), "at {} got {}, expected {}", time, value, expected);
+        }
+    }
+
+    #[test]
+    fn reset_cancels_the_ramp() {
+        let mut slew = SlewLimiter::new(2.0);
+        slew.update(0.0, 0.0);
+        slew.update(0.1, 1.0);
+        slew.reset(0.7, 0.0);
+        assert!(close(slew.update(0.8, 0.8), 0.2));
+    }
+
+    #[test]
+    fn no_max_rate_is_no_limit() {
+        let mut slew = SlewLimiter::new(0.0);
+        assert_eq!(slew.update(0.0, -0.9), -0.9);
+        slew.max_rate = f64::NAN;
+        assert_eq!(slew.update(0.01, 0.9), 0.9);
+    }
+
+    #[test]
+    fn time_going_back_moves_nothing() {
+        let mut slew = SlewLimiter::new(2.0);
+        slew.update(1.0, 0.0);
+        assert_eq!(slew.update(0.5, 1.0), 0.0);
+        assert!(close(slew.update(0.6, 1.0), 0.2));
+    }
+
+    #[test]
+    fn not_a_number_target_passes_through() {
+        let mut slew = SlewLimiter::new(2.0);
+        slew.update(0.0, 0.0);
+        assert!(slew.update(0.1, f64::NAN).is_nan());
+    }
+}
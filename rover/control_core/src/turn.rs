@@ -0,0 +1,244 @@
+//
+// Copyright (C) 2020 Abstract Horizon
+// All rights reserved. This program and the accompanying materials
+// are made available under the terms of the Apache License v2.0
+// which accompanies this distribution, and is available at
+// https://www.apache.org/licenses/LICENSE-2.0
+//
+//  Contributors:
+//    Daniel Sendula - initial API and implementation
+//
+
+// Turn in place by a requested angle. Headings are in degrees, counter-clockwise positive
+// (same as yaw) and not wrapped, so a +720 turn really turns twice.
+
+use core::f64::consts::PI;
+
+use libm::{fabs, fmod};
+
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TurnOutcome {
+    // Heading got within the tolerance of the target
+    Completed,
+    TimedOut,
+    // Balancing stopped mid turn
+    Aborted,
+    Cancelled,
+}
+
+impl TurnOutcome {
+    pub fn name(&self) -> &'static str {
+        match self {
+            TurnOutcome::Completed => "completed",
+            TurnOutcome::TimedOut => "timed_out",
+            TurnOutcome::Aborted => "aborted",
+            TurnOutcome::Cancelled => "cancelled",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TurnResult {
+    pub outcome: TurnOutcome,
+    pub requested: f64,
+    pub achieved: f64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TurnRequest {
+    TurnBy(f64),
+    Cancel,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TurnRejected {
+    // Only a cancel is accepted while a turn is in progress
+    Busy,
+    NotBalancing,
+    InvalidAngle,
+}
+
+impl TurnRejected {
+    pub fn name(&self) -> &'static str {
+        match self {
+            TurnRejected::Busy => "busy",
+            TurnRejected::NotBalancing => "not_balancing",
+            TurnRejected::InvalidAngle => "invalid_angle",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum TurnState {
+    Idle,
+    Turning { start_heading: f64, requested: f64, started: f64 },
+}
+
+pub struct TurnSequencer {
+    pub tolerance: f64,
+    pub timeout: f64,
+    state: TurnState,
+}
+
+impl TurnSequencer {
+    pub fn new(tolerance: f64, timeout: f64) -> TurnSequencer {
+        TurnSequencer { tolerance, timeout, state: TurnState::Idle }
+    }
+
+    pub fn is_turning(&self) -> bool {
+        self.state != TurnState::Idle
+    }
+
+    // Heading the yaw controller should hold, while a turn is in progress.
+    pub fn target_heading(&self) -> Option<f64> {
+        match self.state {
+            TurnState::Idle => None,
+            TurnState::Turning { start_heading, requested, .. } => Some(start_heading + requested)
+        }
+    }
+
+    // A started turn returns Ok(None); a cancelled one returns its result straight away.
+    pub fn request(&mut self, request: TurnRequest, time: f64, heading: f64, balancing: bool) -> Result<Option<TurnResult>, TurnRejected> {
+        match (request, self.state) {
+            (TurnRequest::Cancel, TurnState::Idle) => Ok(None),
+            (TurnRequest::Cancel, TurnState::Turning { .. }) => Ok(Some(self.finish(TurnOutcome::Cancelled, heading))),
+            (TurnRequest::TurnBy(_), TurnState::Turning { .. }) => Err(TurnRejected::Busy),
+            (TurnRequest::TurnBy(angle), TurnState::Idle) => {
+                if !angle.is_finite() {
+                    Err(TurnRejected::InvalidAngle)
+                } else if !balancing {
+                    Err(TurnRejected::NotBalancing)
+                } else {
+                    self.state = TurnState::Turning { start_heading: heading, requested: angle, started: time };
+                    Ok(None)
+                }
+            }
+        }
+    }
+
+    // Called every loop; returns the result once the turn is over.
+    pub fn update(&mut self, time: f64, heading: f64, balancing: bool) -> Option<TurnResult> {
+        match self.state {
+            TurnState::Idle => None,
+            TurnState::Turning { start_heading, requested, started } => {
+                if !balancing {
+                    Some(self.finish(TurnOutcome::Aborted, heading))
+                } else if fabs(start_heading + requested - heading) <= self.tolerance {
+                    Some(self.finish(TurnOutcome::Completed, heading))
+                } else if time - started >= self.timeout {
+                    Some(self.finish(TurnOutcome::TimedOut, heading))
+                } else {
+                    None
+                }
+            }
+        }
+    }
+
+    fn finish(&mut self, outcome: TurnOutcome, heading: f64) -> TurnResult {
+        let result = match self.state {
+            TurnState::Turning { start_heading, requested, .. } => TurnResult { outcome, requested, achieved: heading - start_heading },
+            TurnState::Idle => TurnResult { outcome, requested: 0.0, achieved: 0.0 }
+        };
+        self.state = TurnState::Idle;
+        result
+    }
+}
+
+
+// Difference of two angles in degrees, wrapped into [-180, 180).
+pub fn wrap_degrees(delta: f64) -> f64 {
+    let mut r = fmod(delta, 360.0);
+    if r >= 180.0 {
+        r -= 360.0;
+    } else if r < -180.0 {
+        r += 360.0;
+    }
+    r
+}
+
+// Heading from the difference in wheel travel. Wheel positions are absolute encoder angles in
+// degrees, increasing as the wheel drives forward.
+pub struct OdometryHeading {
+    pub wheel_radius: f64,
+    pub wheel_track: f64,
+    pub heading: f64,
+    last_left: Option<f64>,
+    last_right: Option<f64>,
+}
+
+impl OdometryHeading {
+    pub fn new(wheel_radius: f64, wheel_track: f64) -> OdometryHeading {
+        OdometryHeading { wheel_radius, wheel_track, heading: 0.0, last_left: None, last_right: None }
+    }
+
+    pub fn update(&mut self, left_position: f64, right_position: f64) -> f64 {
+        if let (Some(last_left), Some(last_right)) = (self.last_left, self.last_right) {
+            let to_distance = PI / 180.0 * self.wheel_radius;
+            let left = wrap_degrees(left_position - last_left) * to_distance;
+            let right = wrap_degrees(right_position - last_right) * to_distance;
+            self.heading += (right - left) / self.wheel_track * 180.0 / PI;
+        }
+        self.last_left = Some(left_position);
+        self.last_right = Some(right_position);
+        self.heading
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn turn_completes_within_tolerance_and_a_second_one_waits() {
+        let mut sequencer = TurnSequencer::new(2.0, 5.0);
+        assert_eq!(sequencer.request(TurnRequest::TurnBy(90.0), 0.0, 10.0, true), Ok(None));
+        assert_eq!(sequencer.request(TurnRequest::TurnBy(45.0), 0.1, 10.0, true), Err(TurnRejected::Busy));
+        assert_eq!(sequencer.target_heading(), Some(100.0));
+        assert!(sequencer.update(1.0, 50.0, true).is_none());
+
+        let result = sequencer.update(2.0, 98.5, true).unwrap();
+        assert_eq!(result.outcome, TurnOutcome::Completed);
+        assert_eq!(result.achieved, 88.5);
+    }
+
+    #[test]
+    fn turn_times_out() {
+        let mut sequencer = TurnSequencer::new(2.0, 5.0);
+        sequencer.request(TurnRequest::TurnBy(-90.0), 10.0, 0.0, true).unwrap();
+        let result = sequencer.update(15.0, -30.0, true).unwrap();
+        assert_eq!(result.outcome, TurnOutcome::TimedOut);
+        assert_eq!(result.achieved, -30.0);
+    }
+
+    #[test]
+    fn turn_aborts_when_balance_is_lost_and_does_not_start_without_it() {
+        let mut sequencer = TurnSequencer::new(2.0, 5.0);
+        sequencer.request(TurnRequest::TurnBy(180.0), 20.0, 0.0, true).unwrap();
+        assert_eq!(sequencer.update(20.5, 40.0, false).map(|result| result.outcome), Some(TurnOutcome::Aborted));
+        assert_eq!(sequencer.request(TurnRequest::TurnBy(180.0), 21.0, 0.0, false), Err(TurnRejected::NotBalancing));
+    }
+
+    #[test]
+    fn cancel_stops_a_turn_and_does_nothing_otherwise() {
+        let mut sequencer = TurnSequencer::new(2.0, 5.0);
+        sequencer.request(TurnRequest::TurnBy(45.0), 30.0, 0.0, true).unwrap();
+        let result = sequencer.request(TurnRequest::Cancel, 30.5, 20.0, true).unwrap().unwrap();
+        assert_eq!(result.outcome, TurnOutcome::Cancelled);
+        assert_eq!(result.achieved, 20.0);
+        assert_eq!(sequencer.request(TurnRequest::Cancel, 31.0, 20.0, true), Ok(None));
+        assert!(!sequencer.is_turning());
+    }
+
+    // Five wheel turns of 1m circumference (across the 0/360 wrap) around a 5m track circle
+    #[test]
+    fn odometry_gives_a_full_turn_across_the_wrap() {
+        let mut odometry = OdometryHeading::new(0.5 / PI, 2.5 / PI);
+        odometry.update(350.0, 350.0);
+        for step in 1..=36 {
+            odometry.update(350.0, (350.0 + step as f64 * 50.0) % 360.0);
+        }
+        assert!(fabs(odometry.heading - 360.0) < 1e-6, "heading {}", odometry.heading);
+    }
+}
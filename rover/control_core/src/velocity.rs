@@ -0,0 +1,68 @@
+//
+// Copyright (C) 2020 Abstract Horizon
+// All rights reserved. This program and the accompanying materials
+// are made available under the terms of the Apache License v2.0
+// which accompanies this distribution, and is available at
+// https://www.apache.org/licenses/LICENSE-2.0
+//
+//  Contributors:
+//    Daniel Sendula - initial API and implementation
+//
+
+// Wheel velocity feedback: the speed from the encoders low pass filtered, and gain times it to
+// take off the motor command, so a rover that balances but rolls away is pulled back to a stop.
+
+
+pub struct VelocityFeedback {
+    pub gain: f64,
+    // Weight of the newest speed, as with the gyro filter: 1 is no filtering
+    pub filter: f64,
+    velocity: f64,
+}
+
+impl VelocityFeedback {
+    pub fn new(gain: f64, filter: f64) -> VelocityFeedback {
+        VelocityFeedback { gain, filter, velocity: 0.0 }
+    }
+
+    // Filtered speed; a speed that is not a number leaves it as it was.
+    pub fn update(&mut self, speed: f64) -> f64 {
+        if speed.is_finite() {
+            self.velocity = speed * self.filter + (1.0 - self.filter) * self.velocity;
+        }
+        self.velocity
+    }
+
+    pub fn velocity(&self) -> f64 {
+        self.velocity
+    }
+
+    // What comes off the motor command.
+    pub fn contribution(&self) -> f64 {
+        self.gain * self.velocity
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unfiltered_without_gain_takes_nothing_off() {
+        let mut feedback = VelocityFeedback::new(0.0, 1.0);
+        assert_eq!(feedback.update(0.3), 0.3);
+        assert_eq!(feedback.contribution(), 0.0);
+    }
+
+    #[test]
+    fn filtered_speed_ignores_missing_readings() {
+        let mut feedback = VelocityFeedback::new(2.0, 0.5);
+        let velocities: [f64; 4] = [1.0, 1.0, f64::NAN, 1.0];
+        let filtered: [f64; 4] = [0.5, 0.75, 0.75, 0.875];
+        for (speed, expected) in velocities.iter().zip(filtered.iter()) {
+            assert_eq!(feedback.update(*speed), *expected);
+        }
+        assert_eq!(feedback.contribution(), 1.75);
+    }
+}
@@ -0,0 +1,151 @@
+//
+// Copyright (C) 2020 Abstract Horizon
+// All rights reserved. This program and the accompanying materials
+// are made available under the terms of the Apache License v2.0
+// which accompanies this distribution, and is available at
+// https://www.apache.org/licenses/LICENSE-2.0
+//
+//  Contributors:
+//    Daniel Sendula - initial API and implementation
+//
+
+use libm::fabs;
+
+// Integrator at or above this fraction of its clamp counts as wound up.
+const INTEGRATOR_WOUND_UP_FRACTION: f64 = 0.9;
+
+
+#[derive(Clone, Copy, Default, Debug)]
+pub struct WindupStatus {
+    pub saturated: bool,
+    pub integrator_fraction: f64,
+    pub windup: bool,
+    pub triggered: bool,
+}
+
+pub struct WindupDetector {
+    pub max_duty: f64,
+    pub saturation_steps: u32,
+    pub windup_time: f64,
+    saturated_for: u32,
+    windup_since: Option<f64>,
+    windup: bool,
+}
+
+impl WindupDetector {
+    pub fn new(max_duty: f64, saturation_steps: u32, windup_time: f64) -> WindupDetector {
+        WindupDetector {
+            max_duty,
+            saturation_steps,
+            windup_time,
+            saturated_for: 0,
+            windup_since: None,
+            windup: false,
+        }
+    }
+
+    // applied_duty is what was sent to the motors, integrator_fraction is |integrator| / clamp.
+    pub fn update(&mut self, time: f64, applied_duty: f64, integrator_fraction: f64) -> WindupStatus {
+        if fabs(applied_duty) >= self.max_duty {
+            self.saturated_for += 1;
+        } else {
+            self.saturated_for = 0;
+        }
+        let saturated = self.saturated_for > self.saturation_steps;
+
+        if saturated && integrator_fraction >= INTEGRATOR_WOUND_UP_FRACTION {
+            if self.windup_since.is_none() {
+                self.windup_since = Some(time);
+            }
+        } else {
+            self.windup_since = None;
+        }
+
+        let windup = match self.windup_since {
+            Some(since) => time - since > self.windup_time,
+            None => false
+        };
+        let triggered = windup && !self.windup;
+        self.windup = windup;
+
+        WindupStatus { saturated, integrator_fraction, windup, triggered }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STEPS: u32 = 3;
+    const WINDUP_TIME: f64 = 0.5;
+    const DT: f64 = 0.1;
+
+    // Statuses of a run of updates DT apart starting at time, all with the same duty and integrator.
+    fn run(detector: &mut WindupDetector, time: &mut f64, count: usize, duty: f64, integrator_fraction: f64) -> Vec<WindupStatus> {
+        (0..count).map(|_| {
+            *time += DT;
+            detector.update(*time, duty, integrator_fraction)
+        }).collect()
+    }
+
+    #[test]
+    fn saturated_only_after_more_than_saturation_steps() {
+        let mut detector = WindupDetector::new(1.0, STEPS, WINDUP_TIME);
+        let mut time = 0.0;
+        let statuses = run(&mut detector, &mut time, STEPS as usize + 2, -1.0, 0.0);
+        let saturated: Vec<bool> = statuses.iter().map(|status| status.saturated).collect();
+        assert_eq!(saturated, vec![false, false, false, true, true]);
+    }
+
+    #[test]
+    fn one_unsaturated_step_starts_the_count_again() {
+        let mut detector = WindupDetector::new(1.0, STEPS, WINDUP_TIME);
+        let mut time = 0.0;
+        assert!(run(&mut detector, &mut time, STEPS as usize + 1, 1.0, 0.0).last().unwrap().saturated);
+        assert!(!run(&mut detector, &mut time, 1, 0.99, 0.0)[0].saturated);
+
+        let saturated: Vec<bool> = run(&mut detector, &mut time, STEPS as usize + 1, 1.0, 0.0).iter().map(|status| status.saturated).collect();
+        assert_eq!(saturated, vec![false, false, false, true]);
+    }
+
+    #[test]
+    fn windup_only_once_saturated_and_wound_up_for_windup_time() {
+        let mut detector = WindupDetector::new(1.0, STEPS, WINDUP_TIME);
+        let mut time = 0.0;
+        // Saturated with the integrator below the wound up fraction never winds up
+        assert!(run(&mut detector, &mut time, 20, 1.0, 0.5).iter().all(|status| !status.windup));
+
+        let mut detector = WindupDetector::new(1.0, STEPS, WINDUP_TIME);
+        let mut time = 0.0;
+        let statuses = run(&mut detector, &mut time, 12, 1.0, 0.95);
+        // Saturated from the 4th update, at 0.4; wound up once more than 0.5 later, at 1.0
+        let windup: Vec<bool> = statuses.iter().map(|status| status.windup).collect();
+        assert_eq!(windup, vec![false, false, false, false, false, false, false, false, false, true, true, true]);
+        assert_eq!(statuses[9].integrator_fraction, 0.95);
+    }
+
+    #[test]
+    fn triggered_once_per_episode() {
+        let mut detector = WindupDetector::new(1.0, STEPS, WINDUP_TIME);
+        let mut time = 0.0;
+        let statuses = run(&mut detector, &mut time, 30, 1.0, 1.0);
+        assert_eq!(statuses.iter().filter(|status| status.triggered).count(), 1);
+        assert!(statuses.iter().find(|status| status.triggered).unwrap().windup);
+    }
+
+    #[test]
+    fn rearms_after_the_condition_clears() {
+        let mut detector = WindupDetector::new(1.0, STEPS, WINDUP_TIME);
+        let mut time = 0.0;
+        assert_eq!(run(&mut detector, &mut time, 20, 1.0, 1.0).iter().filter(|status| status.triggered).count(), 1);
+
+        // The integrator unwinding clears it while the output stays saturated
+        let cleared = run(&mut detector, &mut time, 1, 1.0, 0.2);
+        assert!(cleared[0].saturated && !cleared[0].windup && !cleared[0].triggered);
+
+        let again = run(&mut detector, &mut time, 20, 1.0, 1.0);
+        assert_eq!(again.iter().filter(|status| status.triggered).count(), 1);
+        assert!(!again[0].windup);
+    }
+}
@@ -0,0 +1,62 @@
+//! Drives one pin at a fixed duty, to check a wire or an H-bridge input with a meter or a scope.
+//!
+//! Duty is from 0 to 1 of the board's default period. The pin is held for the given number of
+//! seconds (10 if not given) and then released.
+//!
+//! ```no_run
+//! sudo ./pwm_test 20 0.25 30
+//! ```
+
+use std::env;
+use std::process;
+use std::thread::sleep;
+use std::time::Duration;
+
+use dma_gpio::pi::BoardBuilder;
+
+
+struct Options {
+    pin: u8,
+    duty: f32,
+    seconds: f64,
+}
+
+impl Options {
+    fn parse() -> Result<Options, String> {
+        let args: Vec<String> = env::args().skip(1).collect();
+        if args.len() < 2 || args.len() > 3 {
+            return Err("Usage: pwm_test <pin> <duty 0..1> [seconds]".to_string());
+        }
+        let options = Options {
+            pin: args[0].parse().map_err(|_| format!("Invalid pin {}", args[0]))?,
+            duty: args[1].parse().map_err(|_| format!("Invalid duty {}", args[1]))?,
+            seconds: match args.get(2) {
+                Some(seconds) => seconds.parse().map_err(|_| format!("Invalid time {}", seconds))?,
+                None => 10.0
+            },
+        };
+        if !(options.duty >= 0.0 && options.duty <= 1.0) || !(options.seconds > 0.0) {
+            return Err("Duty must be between 0 and 1 and time above 0".to_string());
+        }
+        Ok(options)
+    }
+}
+
+fn main() {
+    let options = Options::parse().unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(2);
+    });
+
+    let mut board = BoardBuilder::new().build_with_pins(vec![options.pin])
+        .unwrap_or_else(|e| {
+            eprintln!("Cannot set up board: {}; is this run as root on a Pi?", e);
+            process::exit(1);
+        });
+    board.set_pwm(options.pin, options.duty).unwrap_or_else(|e| panic!("Cannot set duty: {}", e));
+    println!("Pin {} at {:.1}% of {} us for {}s", options.pin, options.duty * 100.0, board.period_us(options.pin), options.seconds);
+
+    sleep(Duration::from_secs_f64(options.seconds));
+
+    board.release_pwm(options.pin).unwrap_or_else(|e| panic!("Cannot release pin: {}", e));
+}
@@ -0,0 +1,77 @@
+//! Sweeps a hobby servo between its ends and back.
+//!
+//! Pulses go from 1000 us to 2000 us and back over the sweep time, in steps of the board's
+//! resolution, then the servo is released.
+//!
+//! ```no_run
+//! sudo ./servo_sweep --pin 18 --min 1000 --max 2000 --sweep 2 --sweeps 5
+//! ```
+
+use std::env;
+use std::process;
+use std::thread::sleep;
+use std::time::Duration;
+
+use dma_gpio::pi::BoardBuilder;
+
+
+struct Options {
+    pin: u8,
+    min_us: f64,
+    max_us: f64,
+    sweep: f64,
+    sweeps: usize,
+}
+
+impl Options {
+    fn parse() -> Result<Options, String> {
+        let mut options = Options {
+            pin: 18,
+            min_us: 1000.0,
+            max_us: 2000.0,
+            sweep: 2.0,
+            sweeps: 5,
+        };
+        let mut args = env::args().skip(1);
+        while let Some(arg) = args.next() {
+            let value = args.next().ok_or(format!("Missing value for {}", arg))?;
+            match arg.as_str() {
+                "--pin" => options.pin = value.parse().map_err(|_| format!("Invalid pin {}", value))?,
+                "--min" => options.min_us = value.parse().map_err(|_| format!("Invalid pulse {}", value))?,
+                "--max" => options.max_us = value.parse().map_err(|_| format!("Invalid pulse {}", value))?,
+                "--sweep" => options.sweep = value.parse().map_err(|_| format!("Invalid sweep time {}", value))?,
+                "--sweeps" => options.sweeps = value.parse().map_err(|_| format!("Invalid number of sweeps {}", value))?,
+                _ => return Err(format!("Unknown option {}", arg))
+            }
+        }
+        if !(options.min_us < options.max_us) || !(options.sweep > 0.0) {
+            return Err("--min must be below --max and --sweep above 0".to_string());
+        }
+        Ok(options)
+    }
+}
+
+fn main() {
+    let options = Options::parse().unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(2);
+    });
+
+    let mut board = BoardBuilder::new().servo_defaults().build_with_pins(vec![options.pin])
+        .unwrap_or_else(|e| panic!("Cannot set up board: {}", e));
+    let period_us = board.period_us(options.pin);
+    let step_us = board.step_us(options.pin);
+    println!("Servo on {}: {} us period in {} us steps", options.pin, period_us, step_us);
+
+    // One new pulse per period; more often would not reach the servo anyway
+    let steps = ((options.sweep * 1_000_000.0 / period_us) as usize).max(1);
+    let pulse = |step: usize| options.min_us + (options.max_us - options.min_us) * step as f64 / steps as f64;
+    for _ in 0..options.sweeps {
+        for step in (0..=steps).chain((0..steps).rev()) {
+            board.set_servo_pulse(options.pin, pulse(step)).unwrap_or_else(|e| panic!("Cannot set pulse: {}", e));
+            sleep(Duration::from_micros(period_us as u64));
+        }
+    }
+
+    board.set_pwm(options.pin, 0.0).unwrap_or_else(|e| panic!("Cannot release servo: {}", e));
+}
@@ -0,0 +1,195 @@
+//! Endurance test for Board PWM.
+//!
+//! Drives the given pins through sine sweeps, random steps and simultaneous updates for the
+//! requested duration while watching the DMA status and the process' RSS. A CSV line is written
+//! to the report file every report interval; the process exits with 1 if any fault is detected.
+//!
+//! ```no_run
+//! sudo ./soak --pins 20,26 --duration 7200 --report-interval 10 --report soak.csv
+//! ```
+
+use std::env;
+use std::fs::{self, File};
+use std::io::Write;
+use std::process;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use dma_gpio::pi::BoardBuilder;
+use dma_gpio::soak::detect_leak;
+
+
+const UPDATE_INTERVAL: Duration = Duration::from_millis(2);
+const PATTERN_DURATION: Duration = Duration::from_secs(5);
+const SWEEP_PERIOD: f64 = 2.0;
+
+
+struct Options {
+    pins: Vec<u8>,
+    duration: Duration,
+    report_interval: Duration,
+    report: String,
+}
+
+impl Options {
+    fn parse() -> Result<Options, String> {
+        let mut options = Options {
+            pins: vec![20, 26],
+            duration: Duration::from_secs(3600),
+            report_interval: Duration::from_secs(10),
+            report: "soak.csv".to_string(),
+        };
+        let mut args = env::args().skip(1);
+        while let Some(arg) = args.next() {
+            let value = args.next().ok_or(format!("Missing value for {}", arg))?;
+            match arg.as_str() {
+                "--pins" => options.pins = value.split(',')
+                    .map(|pin| pin.trim().parse().map_err(|_| format!("Invalid pin {}", pin)))
+                    .collect::<Result<Vec<u8>, String>>()?,
+                "--duration" => options.duration = Duration::from_secs(value.parse().map_err(|_| format!("Invalid duration {}", value))?),
+                "--report-interval" => options.report_interval = Duration::from_secs(value.parse().map_err(|_| format!("Invalid interval {}", value))?),
+                "--report" => options.report = value,
+                _ => return Err(format!("Unknown option {}", arg))
+            }
+        }
+        Ok(options)
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Pattern {
+    SineSweep,
+    RandomSteps,
+    Simultaneous,
+}
+
+impl Pattern {
+    fn next(self) -> Pattern {
+        match self {
+            Pattern::SineSweep => Pattern::RandomSteps,
+            Pattern::RandomSteps => Pattern::Simultaneous,
+            Pattern::Simultaneous => Pattern::SineSweep,
+        }
+    }
+}
+
+// xorshift; good enough for picking duty cycles without pulling in a dependency
+struct Random(u32);
+
+impl Random {
+    fn next_width(&mut self) -> f32 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 17;
+        self.0 ^= self.0 << 5;
+        (self.0 % 1001) as f32 / 1000.0
+    }
+}
+
+fn rss_bytes() -> Option<f64> {
+    let statm = fs::read_to_string("/proc/self/statm").ok()?;
+    let resident_pages: f64 = statm.split_whitespace().nth(1)?.parse().ok()?;
+    Some(resident_pages * 4096.0)
+}
+
+fn main() {
+    let options = match Options::parse() {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("{}", e);
+            eprintln!("Usage: soak [--pins 20,26] [--duration seconds] [--report-interval seconds] [--report file.csv]");
+            process::exit(2);
+        }
+    };
+
+    let mut report = File::create(&options.report).unwrap_or_else(|e| panic!("Cannot create {}: {:?}", options.report, e));
+    let _ = writeln!(report, "elapsed,updates,dma_active,dma_errors,rss");
+
+    let mut board = BoardBuilder::new().build_with_pins(options.pins.clone()).unwrap_or_else(|e| panic!("Cannot set up board: {:?}", e));
+    board.print_info();
+    if let Err(e) = board.verify_cb_chain() {
+        eprintln!("Control block chain is broken: {:?}", e);
+        process::exit(1);
+    }
+
+    let started = Instant::now();
+    let mut last_report = started;
+    let mut pattern = Pattern::SineSweep;
+    let mut pattern_started = started;
+    let mut random = Random(0x2545_f491);
+    let mut updates: u64 = 0;
+    let mut dma_errors: u64 = 0;
+    let mut rss_samples: Vec<(f64, f64)> = vec![];
+    let mut faults: Vec<String> = vec![];
+
+    while started.elapsed() < options.duration {
+        if pattern_started.elapsed() >= PATTERN_DURATION {
+            pattern = pattern.next();
+            pattern_started = Instant::now();
+        }
+
+        let t = started.elapsed().as_secs_f64();
+        let result = match pattern {
+            Pattern::SineSweep => {
+                let mut result = Ok(());
+                for (i, pin) in options.pins.iter().enumerate() {
+                    let phase = i as f64 / options.pins.len() as f64;
+                    let width = 0.5 + 0.5 * (2.0 * std::f64::consts::PI * (t / SWEEP_PERIOD + phase)).sin();
+                    result = result.and(board.set_pwm(*pin, width as f32));
+                }
+                result
+            },
+            Pattern::RandomSteps => {
+                let pin = options.pins[updates as usize % options.pins.len()];
+                board.set_pwm(pin, random.next_width())
+            },
+            Pattern::Simultaneous => board.set_all_pwm(random.next_width()),
+        };
+        if let Err(e) = result {
+            faults.push(format!("set_pwm failed at {:.1}s: {:?}", t, e));
+            break;
+        }
+        updates += 1;
+
+        let status = board.dma_status();
+        if status.has_error() {
+            dma_errors += 1;
+        }
+        if !status.active {
+            faults.push(format!("DMA stopped at {:.1}s", t));
+            break;
+        }
+
+        if last_report.elapsed() >= options.report_interval {
+            last_report = Instant::now();
+            let rss = rss_bytes().unwrap_or(0.0);
+            rss_samples.push((t, rss));
+            let _ = writeln!(report, "{:.1},{},{},{},{}", t, updates, status.active, dma_errors, rss);
+            let _ = report.flush();
+        }
+
+        sleep(UPDATE_INTERVAL);
+    }
+
+    if dma_errors > 0 {
+        faults.push(format!("DMA reported errors {} times", dma_errors));
+    }
+    if let Some(leak) = detect_leak(&rss_samples) {
+        faults.push(leak);
+    }
+    #[cfg(feature = "stats")]
+    {
+        println!("Idle stats: {:?}", board.idle_stats());
+    }
+
+    board.release_all_pwm().unwrap();
+
+    println!("Soak finished after {:.0}s and {} updates.", started.elapsed().as_secs_f64(), updates);
+    if faults.is_empty() {
+        println!("No faults detected.");
+    } else {
+        for fault in &faults {
+            println!("FAULT: {}", fault);
+        }
+        process::exit(1);
+    }
+}
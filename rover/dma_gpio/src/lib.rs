@@ -56,7 +56,7 @@
 //! ```
 //! 
 //! # Features
-//! There are two features you can enable in this crate: 'debug' and 'bind_process'. To enable these features, write the dependency for this crate as shown below.
+//! There are features you can enable in this crate: 'bind_process' and 'stats' ('debug' is still accepted, but logging no longer needs it). To enable these features, write the dependency for this crate as shown below.
 //! ```no_run
 //! Cargo.toml
 //! 
@@ -66,12 +66,15 @@
 //! 
 //! [dependencies.dma_gpio]
 //! version = "0.1.8"
-//! features = ["debug", "bind_process"]
+//! features = ["bind_process"]
 //! ```
 //! 
-//! ## 'debug' feature
-//! By enabling this feature, the library will print out the process every step of the way. This feature will be useful when debugging.
-//! Also, after enabling this feature in Cargo.toml, you have to call [enable_logger](fn.enable_logger.html) function to see the logs in the terminal.
+//! # Logging
+//! The library logs every step of setting up the mailbox, DMA, PWM and PCM through the [log](https://docs.rs/log) crate at trace level,
+//! which costs next to nothing while that level is off. With env_logger, as the rover uses it, `RUST_LOG=dma_gpio=trace` shows them
+//! without rebuilding; programs without a logger of their own can call [enable_logger](fn.enable_logger.html).
+//! Register and sample dumps are written on request by [Board::debug_dump_hw](pi/struct.Board.html#method.debug_dump_hw)
+//! and [Board::debug_dump_samples](pi/struct.Board.html#method.debug_dump_samples).
 //! ```no_run
 //! use std::thread::sleep;
 //! use std::time::Duration;
@@ -88,7 +91,7 @@
 //! 
 //! ```
 //! ## 'bind_process' feature
-//! This feature lets you access the [pi_core](pi_core/index.html) module which only has one function [bind_process_to_last](pi_core/fn.bind_process_to_last.html). This function binds the process to the last core of the Pi. However, to use this function, you have to first install a C library called [hwloc](https://github.com/daschl/hwloc-rs#install-hwloc-on-os-x). Also, enabling debug feature will print out if you have correctly bound process to the last core.
+//! This feature lets you access the [pi_core](pi_core/index.html) module which only has one function [bind_process_to_last](pi_core/fn.bind_process_to_last.html). This function binds the process to the last core of the Pi. However, to use this function, you have to first install a C library called [hwloc](https://github.com/daschl/hwloc-rs#install-hwloc-on-os-x).
 //! ```no_run
 //! use std::thread::sleep;
 //! use std::time::Duration;
@@ -108,24 +111,35 @@
 
 #[macro_use] extern crate log;
 
-#[cfg(feature = "debug")]
+use std::env;
+
 use env_logger;
 
 pub mod mailbox;
 pub mod pi;
+pub mod soak;
+
+/// Optional features this crate was compiled with, and whether each is on.
+pub const FEATURES: [(&str, bool); 3] = [
+    ("bind_process", cfg!(feature = "bind_process")),
+    ("debug", cfg!(feature = "debug")),
+    ("stats", cfg!(feature = "stats")),
+];
 
 // if you wanna bind the process to core for better performance?
 #[cfg(feature = "bind_process")]
 pub mod pi_core;
 
-/// Only accessable with "debug" feature. Use it to see traces when running
-#[cfg(feature = "debug")]
+/// Logs to stderr as RUST_LOG says, or when it is not set warnings and everything this crate traces.
 pub fn enable_logger(){
-    env_logger::Builder::new()
+    let mut builder = env_logger::Builder::new();
+    builder
     .default_format()
     .default_format_module_path(false)
-    .default_format_timestamp(false)
-    .filter_level(log::LevelFilter::Warn)
-    .filter_module("dma_gpio", log::LevelFilter::Trace)
-    .init();
+    .default_format_timestamp(false);
+    match env::var("RUST_LOG") {
+        Ok(filters) => { builder.parse(&filters); },
+        Err(_) => { builder.filter_level(log::LevelFilter::Warn).filter_module("dma_gpio", log::LevelFilter::Trace); }
+    }
+    builder.init();
 }
\ No newline at end of file
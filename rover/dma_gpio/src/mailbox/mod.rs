@@ -13,20 +13,18 @@ use libc;
 pub mod ioctl;
 
 /* from https://github.com/raspberrypi/firmware/wiki/Mailbox-property-interface */
-pub const MEM_FLAG_DISCARDABLE: usize = 1 << 0; /* can be resized to 0 at any time. Use for cached data */
-pub const MEM_FLAG_NORMAL: usize = 0 << 2; /* normal allocating alias. Don't use from ARM */
-pub const MEM_FLAG_DIRECT: usize = 1 << 2; /* 0xC alias uncached */
-pub const MEM_FLAG_COHERENT: usize = 2 << 2; /* 0x8 alias. Non-allocating in L2 but coherent */
-pub const MEM_FLAG_L1_NONALLOCATING: usize = MEM_FLAG_DIRECT | MEM_FLAG_COHERENT; /* Allocating in L2 */
-pub const MEM_FLAG_ZERO: usize = 1 << 4;  /* initialise buffer to all zeros */
-pub const MEM_FLAG_NO_INIT: usize = 1 << 5; /* don't initialise (default is initialise to all ones */
-pub const MEM_FLAG_HINT_PERMALOCK: usize = 1 << 6; /* Likely to be locked for long periods of time. */
-
-// pointer size is 4 bytes for 32-bit machine a.k.a. rpi
-const PTR_SIZE: usize = 4;
+pub const MEM_FLAG_DISCARDABLE: u32 = 1 << 0; /* can be resized to 0 at any time. Use for cached data */
+pub const MEM_FLAG_NORMAL: u32 = 0 << 2; /* normal allocating alias. Don't use from ARM */
+pub const MEM_FLAG_DIRECT: u32 = 1 << 2; /* 0xC alias uncached */
+pub const MEM_FLAG_COHERENT: u32 = 2 << 2; /* 0x8 alias. Non-allocating in L2 but coherent */
+pub const MEM_FLAG_L1_NONALLOCATING: u32 = MEM_FLAG_DIRECT | MEM_FLAG_COHERENT; /* Allocating in L2 */
+pub const MEM_FLAG_ZERO: u32 = 1 << 4;  /* initialise buffer to all zeros */
+pub const MEM_FLAG_NO_INIT: u32 = 1 << 5; /* don't initialise (default is initialise to all ones */
+pub const MEM_FLAG_HINT_PERMALOCK: u32 = 1 << 6; /* Likely to be locked for long periods of time. */
+
 pub const MAJOR_NUM: usize = 100;
 
-const PAGE_SIZE: usize = PTR_SIZE*1024;
+const PAGE_SIZE: usize = 4096;
 
 pub fn mapmem(base: usize, size: usize) -> Result<usize, Error> {
     let offset = base % PAGE_SIZE;
@@ -35,7 +33,7 @@ pub fn mapmem(base: usize, size: usize) -> Result<usize, Error> {
 
     // open /dev/mem
     let dev_mem =  CString::new("/dev/mem").unwrap().into_bytes_with_nul();
-    let mem_fd = match unsafe { libc::open(dev_mem.as_ptr(), libc::O_RDWR|libc::O_SYNC) }{
+    let mem_fd = match unsafe { libc::open(dev_mem.as_ptr() as *const libc::c_char, libc::O_RDWR|libc::O_SYNC) }{
         fd if fd < 0 => {
             error!("can't open /dev/mem\nThis program should be run as root. Try prefixing command with: sudo");
             return Err(Error::new(ErrorKind::PermissionDenied, "can't open /dev/mem\nThis program should be run as root. Try prefixing command with: sudo"))
@@ -43,7 +41,7 @@ pub fn mapmem(base: usize, size: usize) -> Result<usize, Error> {
         fd => fd
     };
 
-#[cfg(target_arch = "aarch64")]
+#[cfg(any(target_arch = "aarch64", target_arch = "x86_64"))]
     let mem = unsafe {
         libc::mmap(
             0 as *mut c_void,
@@ -67,10 +65,7 @@ pub fn mapmem(base: usize, size: usize) -> Result<usize, Error> {
             ) as usize
     };
 
-    #[cfg(feature = "debug")]
-    {
-        trace!("base={:#010x}, mem={:#010x}", base, mem);
-    }
+    trace!("base={:#010x}, mem={:#010x}", base, mem);
 
     if mem == libc::MAP_FAILED as usize {
         error!("map error {:?}\n", mem);
@@ -94,21 +89,20 @@ pub fn unmapmem(addr: *mut c_void, size: usize) -> Result<(), Error> {
     }
 }
 
-pub fn mbox_property(file_desc: i32, buf: &mut [usize; 32], _len: usize) -> Result<usize, Error> {
-    #[cfg(feature = "debug")]
-    {
-        trace!("Mbox request:");
-        for i in 0.._len {
-            trace!("{:#04x}: {:#010x}", i*size_of::<u8>(), buf[i]);
-        }
-        trace!("\n");
+/// Sends a property tag buffer to the firmware. Tag words are 32 bit whatever the userland is, so
+/// the buffer is too; on aarch64 a [usize] buffer would put every word in the wrong place.
+pub fn mbox_property(file_desc: i32, buf: &mut [u32; 32], len: usize) -> Result<usize, Error> {
+    trace!("Mbox request:");
+    for i in 0..len {
+        trace!("{:#04x}: {:#010x}", i*size_of::<u32>(), buf[i]);
     }
+    trace!("\n");
 
     // the third parameter is the size of a pointer
     // in c code, this is passed in as "char *"
-    // so sizeof(char *) is 4 for a 32-bit machine a.k.a. rpi
-    let IOCTL_MBOX_PROPERTY: usize = ioctl::_IOWR(MAJOR_NUM, 0, PTR_SIZE);
-#[cfg(target_arch = "aarch64")]
+    // so sizeof(char *): 4 on armv6 and armv7, 8 on aarch64
+    let IOCTL_MBOX_PROPERTY: usize = ioctl::_IOWR(MAJOR_NUM, 0, size_of::<*mut c_void>());
+#[cfg(any(target_arch = "aarch64", target_arch = "x86_64"))]
     let ret_val = match unsafe{ libc::ioctl(file_desc, IOCTL_MBOX_PROPERTY as u64, buf.as_mut_ptr() as *mut c_void) }{
         x if x < 0 => {
             error!("ioctl_set_msg failed: {:?}", x);
@@ -125,27 +119,21 @@ pub fn mbox_property(file_desc: i32, buf: &mut [usize; 32], _len: usize) -> Resu
         x => x as usize
     };
 
-    #[cfg(feature = "debug")]
-    {
-        trace!("Mbox responses:");
-        for i in 0.._len {
-            trace!("{:#04x}: {:#010x}", i*size_of::<u8>(), buf[i]);
-        }
-        trace!("\n");
+    trace!("Mbox responses:");
+    for i in 0..len {
+        trace!("{:#04x}: {:#010x}", i*size_of::<u32>(), buf[i]);
     }
+    trace!("\n");
 
     Ok(ret_val)
 }
 
-pub fn mem_alloc(file_desc: i32, size: usize, align: usize, flags: usize) -> Result<usize, Error> {
-    let mut p: [usize;32] = [0; 32];
-    #[cfg(feature = "debug")]
-    {
-        trace!("mem_alloc");
-        trace!("Requesting {} bytes", size);
-        trace!("Alignment {} bytes", align);
-        trace!("mem_alloc flags: {:#010x} \n", flags);
-    }
+pub fn mem_alloc(file_desc: i32, size: u32, align: u32, flags: u32) -> Result<u32, Error> {
+    let mut p: [u32;32] = [0; 32];
+    trace!("mem_alloc");
+    trace!("Requesting {} bytes", size);
+    trace!("Alignment {} bytes", align);
+    trace!("mem_alloc flags: {:#010x} \n", flags);
 
     p[1] = 0x00000000; // process request
 
@@ -157,19 +145,16 @@ pub fn mem_alloc(file_desc: i32, size: usize, align: usize, flags: usize) -> Res
     p[7] = flags; // (MEM_FLAG_L!_NOMALLOCATING)
     p[8] = 0x00000000; // end tag
 
-    p[0] = 9*size_of::<usize>();
+    p[0] = (9*size_of::<u32>()) as u32;
     match mbox_property(file_desc, &mut p, 9){
         Ok(_) => Ok(p[5]),
         Err(e) => Err(e),
     }
 }
 
-pub fn mem_free(file_desc: i32, handle: usize) -> Result<usize, Error> {
-    #[cfg(feature = "debug")]
-    {
-        trace!("mem_free");
-    }
-    let mut p: [usize;32] = [0; 32];
+pub fn mem_free(file_desc: i32, handle: u32) -> Result<u32, Error> {
+    trace!("mem_free");
+    let mut p: [u32;32] = [0; 32];
 
     p[1] = 0x00000000; // process request
 
@@ -179,19 +164,16 @@ pub fn mem_free(file_desc: i32, handle: usize) -> Result<usize, Error> {
     p[5] = handle;
     p[6] = 0x00000000; // end tag
 
-    p[0] = 7*size_of::<usize>();
+    p[0] = (7*size_of::<u32>()) as u32;
     match mbox_property(file_desc, &mut p, 7){
         Ok(_) => Ok(p[5]),
         Err(e) => Err(e),
     }
 }
 
-pub fn mem_lock(file_desc: i32, handle: usize) -> Result<usize, Error> {
-    #[cfg(feature = "debug")]
-    {
-        trace!("mem_lock");
-    }
-    let mut p: [usize;32] = [0; 32];
+pub fn mem_lock(file_desc: i32, handle: u32) -> Result<u32, Error> {
+    trace!("mem_lock");
+    let mut p: [u32;32] = [0; 32];
 
     p[1] = 0x00000000; // process request
 
@@ -201,19 +183,16 @@ pub fn mem_lock(file_desc: i32, handle: usize) -> Result<usize, Error> {
     p[5] = handle;
     p[6] = 0x00000000; // end tag
 
-    p[0] = 7*size_of::<usize>();
+    p[0] = (7*size_of::<u32>()) as u32;
     match mbox_property(file_desc, &mut p, 7){
         Ok(_) => Ok(p[5]),
         Err(e) => Err(e),
     }
 }
 
-pub fn mem_unlock(file_desc: i32, handle: usize) -> Result<usize, Error> {
-    #[cfg(feature = "debug")]
-    {
-        trace!("mem_unlock");
-    }
-    let mut p: [usize;32] = [0; 32];
+pub fn mem_unlock(file_desc: i32, handle: u32) -> Result<u32, Error> {
+    trace!("mem_unlock");
+    let mut p: [u32;32] = [0; 32];
 
     p[1] = 0x00000000; // process request
 
@@ -223,19 +202,16 @@ pub fn mem_unlock(file_desc: i32, handle: usize) -> Result<usize, Error> {
     p[5] = handle;
     p[6] = 0x00000000; // end tag
 
-    p[0] = 7*size_of::<usize>();
+    p[0] = (7*size_of::<u32>()) as u32;
     match mbox_property(file_desc, &mut p, 7){
         Ok(_) => Ok(p[5]),
         Err(e) => Err(e),
     }
 }
 
-pub fn execute_code(file_desc: i32, code: usize, r0: usize, r1: usize, r2: usize, r3: usize, r4: usize, r5: usize) -> Result<usize, Error> {
-    #[cfg(feature = "debug")]
-    {
-        trace!("execute_code");
-    }
-    let mut p: [usize;32] = [0; 32];
+pub fn execute_code(file_desc: i32, code: u32, r0: u32, r1: u32, r2: u32, r3: u32, r4: u32, r5: u32) -> Result<u32, Error> {
+    trace!("execute_code");
+    let mut p: [u32;32] = [0; 32];
 
     p[1] = 0x00000000; // process request
 
@@ -251,19 +227,16 @@ pub fn execute_code(file_desc: i32, code: usize, r0: usize, r1: usize, r2: usize
     p[11] = r5;
     p[12] = 0x00000000; // end tag
 
-    p[0] = 13*size_of::<usize>();
+    p[0] = (13*size_of::<u32>()) as u32;
     match mbox_property(file_desc, &mut p, 13){
         Ok(_) => Ok(p[5]),
         Err(e) => Err(e),
     }
 }
 
-pub fn qpu_enable(file_desc: i32, enable: usize) -> Result<usize, Error> {
-    #[cfg(feature = "debug")]
-    {
-        trace!("qpu_enable");
-    }
-    let mut p: [usize;32] = [0; 32];
+pub fn qpu_enable(file_desc: i32, enable: u32) -> Result<u32, Error> {
+    trace!("qpu_enable");
+    let mut p: [u32;32] = [0; 32];
 
     p[1] = 0x00000000; // process request
 
@@ -273,7 +246,7 @@ pub fn qpu_enable(file_desc: i32, enable: usize) -> Result<usize, Error> {
     p[5] = enable;
     p[6] = 0x00000000; // end tag
 
-    p[0] = 7*size_of::<usize>();
+    p[0] = (7*size_of::<u32>()) as u32;
 
     match mbox_property(file_desc, &mut p, 7){
         Ok(_) => Ok(p[5]),
@@ -281,12 +254,9 @@ pub fn qpu_enable(file_desc: i32, enable: usize) -> Result<usize, Error> {
     }
 }
 
-pub fn execute_qpu(file_desc: i32, num_qpus: usize, control: usize, noflush: usize, timeout: usize) -> Result<usize, Error> {
-    #[cfg(feature = "debug")]
-    {
-        trace!("execute_qpu");
-    }
-    let mut p: [usize;32] = [0; 32];
+pub fn execute_qpu(file_desc: i32, num_qpus: u32, control: u32, noflush: u32, timeout: u32) -> Result<u32, Error> {
+    trace!("execute_qpu");
+    let mut p: [u32;32] = [0; 32];
 
     p[1] = 0x00000000; // process request
 
@@ -300,7 +270,7 @@ pub fn execute_qpu(file_desc: i32, num_qpus: usize, control: usize, noflush: usi
 
     p[9] = 0x00000000; // end tag
 
-    p[0] = 10*size_of::<usize>();
+    p[0] = (10*size_of::<u32>()) as u32;
 
     match mbox_property(file_desc, &mut p, 10){
         Ok(_) => Ok(p[5]),
@@ -308,12 +278,9 @@ pub fn execute_qpu(file_desc: i32, num_qpus: usize, control: usize, noflush: usi
     }
 }
 
-pub fn get_firmware_revision(file_desc: i32) -> Result<usize, Error> {
-    #[cfg(feature = "debug")]
-    {
-        trace!("get_firmware_revision");
-    }
-    let mut p: [usize;32] = [0; 32];
+pub fn get_firmware_revision(file_desc: i32) -> Result<u32, Error> {
+    trace!("get_firmware_revision");
+    let mut p: [u32;32] = [0; 32];
 
     p[1] = 0x00000000; // process request
 
@@ -324,7 +291,7 @@ pub fn get_firmware_revision(file_desc: i32) -> Result<usize, Error> {
 
     p[6] = 0x00000000; // end tag
 
-    p[0] = 7*size_of::<usize>();
+    p[0] = (7*size_of::<u32>()) as u32;
 
     match mbox_property(file_desc, &mut p, 7){
         Ok(_) => Ok(p[5]),
@@ -332,12 +299,9 @@ pub fn get_firmware_revision(file_desc: i32) -> Result<usize, Error> {
     }
 }
 
-pub fn get_board_model(file_desc: i32) -> Result<usize, Error> {
-    #[cfg(feature = "debug")]
-    {
-        trace!("get_board_model");
-    }
-    let mut p: [usize;32] = [0; 32];
+pub fn get_board_model(file_desc: i32) -> Result<u32, Error> {
+    trace!("get_board_model");
+    let mut p: [u32;32] = [0; 32];
 
     p[1] = 0x00000000; // process request
 
@@ -347,7 +311,7 @@ pub fn get_board_model(file_desc: i32) -> Result<usize, Error> {
     p[5] = 0;
     p[6] = 0x00000000; // end tag
 
-    p[0] = 7*size_of::<usize>();
+    p[0] = (7*size_of::<u32>()) as u32;
 
     match mbox_property(file_desc, &mut p, 7){
         Ok(_) => Ok(p[5]),
@@ -355,12 +319,9 @@ pub fn get_board_model(file_desc: i32) -> Result<usize, Error> {
     }
 }
 
-pub fn get_board_revision(file_desc: i32) -> Result<usize, Error> {
-    #[cfg(feature = "debug")]
-    {
-        trace!("get_board_revision");
-    }
-    let mut p: [usize;32] = [0; 32];
+pub fn get_board_revision(file_desc: i32) -> Result<u32, Error> {
+    trace!("get_board_revision");
+    let mut p: [u32;32] = [0; 32];
 
     p[1] = 0x00000000; // process request
 
@@ -370,7 +331,7 @@ pub fn get_board_revision(file_desc: i32) -> Result<usize, Error> {
     p[5] = 0;
     p[6] = 0x00000000; // end tag
 
-    p[0] = 7*size_of::<usize>();
+    p[0] = (7*size_of::<u32>()) as u32;
 
     match mbox_property(file_desc, &mut p, 7){
         Ok(_) => Ok(p[5]),
@@ -378,12 +339,9 @@ pub fn get_board_revision(file_desc: i32) -> Result<usize, Error> {
     }
 }
 
-pub fn get_dma_channels(file_desc: i32) -> Result<usize, Error> {
-    #[cfg(feature = "debug")]
-    {
-        trace!("get_dma_channels");
-    }
-    let mut p: [usize;32] = [0; 32];
+pub fn get_dma_channels(file_desc: i32) -> Result<u32, Error> {
+    trace!("get_dma_channels");
+    let mut p: [u32;32] = [0; 32];
 
     p[1] = 0x00000000; // process request
 
@@ -393,7 +351,7 @@ pub fn get_dma_channels(file_desc: i32) -> Result<usize, Error> {
     p[5] = 0;
     p[6] = 0x00000000; // end tag
 
-    p[0] = 7*size_of::<usize>();
+    p[0] = (7*size_of::<u32>()) as u32;
 
     match mbox_property(file_desc, &mut p, 7){
         Ok(_) => Ok(p[5]),
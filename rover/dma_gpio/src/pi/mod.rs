@@ -5,6 +5,15 @@
 //! 
 //! Use [BoardBuilder](struct.BoardBuilder.html) struct to initialize the [Board](struct.Board.html) struct,
 //! and use [Board](struct.Board.html) struct to manipulate GPIO Pins.
+//!
+//! ## Memory model
+//!
+//! Samples and control blocks live in mailbox memory that the CPU writes through an ordinary
+//! mapping while the DMA engine reads it through the uncached bus alias. Those writes are not
+//! ordered against each other or against later peripheral register writes unless a barrier is
+//! issued, so every place that finishes changing sample or control block memory calls
+//! `dma_barrier` before returning or before (re)starting the DMA. Peripheral registers are
+//! accessed through volatile_register and need no extra barrier.
 
 use crate::mailbox;
 
@@ -14,9 +23,14 @@ use std::mem::size_of;
 use std::ffi::CString;
 use core::ffi::c_void;
 use std::thread::sleep;
-use std::time::Duration;
-use std::io::{Error, ErrorKind};
+use std::time::{Duration, Instant};
+use std::io::{self, Error, ErrorKind, Write};
 use std::fs;
+use std::fmt;
+use std::collections::BTreeSet;
+use std::sync::Mutex;
+use std::sync::atomic::{fence, Ordering};
+use std::alloc::{self, Layout};
 use volatile_register::RW;
 
 
@@ -26,6 +40,10 @@ pub const MAX_CHANNELS: usize = 32;
 /// = 9. Default number of channels.
 pub const DEFAULT_NUM_CHANNELS: usize = 9;
 
+/// = 0xFF. Marks the unused entries of pin tables such as [DEFAULT_PINS](static.DEFAULT_PINS.html);
+/// GPIO 0 is a pin like any other.
+pub const NO_PIN: u8 = 0xFF;
+
 /// [4, 17, 18, 27, 21, 22, 23, 24, 25]. 9 default GPIO pins on pi.
 pub static DEFAULT_PINS: [u8; MAX_CHANNELS] = [
     4,              // P1-7
@@ -37,7 +55,8 @@ pub static DEFAULT_PINS: [u8; MAX_CHANNELS] = [
     23,             // P1-16
     24,             // P1-18
     25,             // P1-22
-    0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0 //empty possible channels
+    NO_PIN, NO_PIN, NO_PIN, NO_PIN, NO_PIN, NO_PIN, NO_PIN, NO_PIN, NO_PIN, NO_PIN, NO_PIN, NO_PIN,
+    NO_PIN, NO_PIN, NO_PIN, NO_PIN, NO_PIN, NO_PIN, NO_PIN, NO_PIN, NO_PIN, NO_PIN, NO_PIN //empty possible channels
     ];
 
 /// [6, 28, 29, 30, 31, 40, 45, 46, 47, 48, 49, 50, 51, 52, 53]. List of reserved GPIO pins
@@ -82,17 +101,70 @@ pub const DEFAULT_SAMPLE_DELAY: usize = 10;
 /// You can change this configuration with [BoardBuilder::divide_pwm(mut self, divisor)](struct.BoardBuilder.html#method.divide_pwm: usize)
 pub const DEFAULT_PWM_DIVISOR: usize = 500;
 
+/// = 20000. Cycle time of [BoardBuilder::servo_defaults](struct.BoardBuilder.html#method.servo_defaults):
+/// 20 ms (50 Hz) at the default divisor on a Pi 1 to 3.
+pub const SERVO_CYCLE_TIME: usize = 20000;
+
+/// = 10. Sample delay of [BoardBuilder::servo_defaults](struct.BoardBuilder.html#method.servo_defaults):
+/// 10 us steps, 2000 samples a cycle.
+pub const SERVO_SAMPLE_DELAY: usize = 10;
+
+/// = 1000. Largest divisor [BoardBuilder::divide_pwm](struct.BoardBuilder.html#method.divide_pwm) accepts with PWM pacing.
+pub const MAX_PWM_DIVISOR: usize = 1000;
+
+/// = 4095. Largest divisor [BoardBuilder::divide_pwm](struct.BoardBuilder.html#method.divide_pwm) accepts with
+/// [use_pcm](struct.BoardBuilder.html#method.use_pcm): the PCM clock's integer divider has 12 bits.
+pub const MAX_PCM_DIVISOR: usize = 4095;
+
+// PCM frame lengths in clocks: a frame has to hold the one 8 bit channel, and FLEN has 10 bits
+const MIN_PCM_FRAME: usize = 8;
+const MAX_PCM_FRAME: usize = 1024;
+
+/// = 200. Shortest cycle time [BoardBuilder::set_cycle_time](struct.BoardBuilder.html#method.set_cycle_time) accepts.
+pub const MIN_CYCLE_TIME: usize = 200;
+
+/// = 1000. Longest cycle time [BoardBuilder::set_cycle_time](struct.BoardBuilder.html#method.set_cycle_time) accepts.
+pub const MAX_CYCLE_TIME: usize = 1000;
+
+/// = 100. Longest sample delay [BoardBuilder::set_sample_delay](struct.BoardBuilder.html#method.set_sample_delay) accepts.
+pub const MAX_SAMPLE_DELAY: usize = 100;
+
 /// = DEFAULT_CYCLE_TIME/DEFAULT_SAMPLE_DELAY = 200. Number of samples.
 pub const NUM_SAMPLES: usize = DEFAULT_CYCLE_TIME as usize/DEFAULT_SAMPLE_DELAY;
 
-/// = NUM_SAMPLES * 2 = 400. Number of Control Blocks.
+/// = 1 s. Time all channels must stay at 0.0 before an auto-idle board pauses the DMA.
+pub const DEFAULT_AUTO_IDLE_TIME: Duration = Duration::from_secs(1);
+
+/// = 500 MHz. Clock feeding the PWM/PCM pacing (PLLD) on a Pi 1 to 3; divided by the PWM divisor.
+pub const PWM_CLOCK_HZ: usize = 500_000_000;
+
+/// = 750 MHz. PLLD on a Pi 4, which runs the same divisor 1.5 times as fast.
+pub const PI4_PWM_CLOCK_HZ: usize = 750_000_000;
+
+/// = 2. Control blocks the DMA executes per sample (GPIO write + delay).
 ///
-/// This is how much memory that will be allocated for control blocks.
-/// Setting a different number for cycle time ([BoardBuilder::set_cycle_time](struct.BoardBuilder.html#method.set_cycle_time))
-/// and setting a different number of sample delay ([BoardBuilder::set_sample_delay](struct.BoardBuilder.html#method.set_sample_delay))
-/// will still allocate memory for 400 control blocks, but will only initialize (cycle_time/sample_delay) control blocks. 
+/// Each one costs a 32-byte control block read and a 4-byte write on the bus.
+pub const DMA_TRANSFERS_PER_SAMPLE: usize = 2;
+
+/// Conservative raw DMA transfer rates (control blocks per second) per Pi model.
+///
+/// Measured rates top out around 1.6 MHz on a Pi 3; older models are slower.
+pub const PI1_DMA_THROUGHPUT_HZ: usize = 1_000_000;
+pub const PI2_DMA_THROUGHPUT_HZ: usize = 1_200_000;
+pub const PI3_DMA_THROUGHPUT_HZ: usize = 1_600_000;
+/// Not measured yet; taken as the Pi 3's.
+pub const PI4_DMA_THROUGHPUT_HZ: usize = 1_600_000;
+
+/// = NUM_SAMPLES * 2 = 400. Number of Control Blocks with the default settings.
+///
+/// Neither this nor NUM_SAMPLES limits a board; see [Board::ctl_allocation](struct.Board.html#method.ctl_allocation).
+/// Memory for samples and control blocks is sized from the cycle time and sample delay of every group
+/// ([BoardBuilder::add_group](struct.BoardBuilder.html#method.add_group)), up to [MAX_CTL_PAGES](constant.MAX_CTL_PAGES.html).
 pub const NUM_CBS: usize = NUM_SAMPLES*2;
 
+/// = 256 (1 MiB). Most pages of mailbox memory a Board asks for, for the samples and control blocks together.
+pub const MAX_CTL_PAGES: usize = 256;
+
 const DMA_NO_WIDE_BURSTS: usize = 1<<26;
 const DMA_WAIT_RESP: usize = 1<<3;
 const DMA_D_DREQ: usize = 1<<6;
@@ -102,6 +174,14 @@ fn DMA_PER_MAP(x: usize) -> usize{
 const DMA_END: usize = 1<<1;
 const DMA_RESET: usize = 1<<31;
 const DMA_INT: usize = 1<<2;
+const DMA_ACTIVE: usize = 1<<0;
+const DMA_ERROR: usize = 1<<8;
+const DMA_DEBUG_ERRORS: usize = 7; // read error, FIFO error, read last not set error
+const DMA_DEBUG_READ_LAST_NOT_SET: usize = 1<<0;
+const DMA_DEBUG_FIFO_ERROR: usize = 1<<1;
+const DMA_DEBUG_READ_ERROR: usize = 1<<2;
+// The reset bit clears itself within a few cycles; much longer and the channel is not answering
+const DMA_RESET_TIMEOUT: Duration = Duration::from_millis(10);
 
 const DMA_CS: usize = 0x00/4;
 const DMA_CONBLK_AD: usize = 0x04/4;
@@ -146,6 +226,16 @@ const PCM_GRAY: usize = 0x20/4;
 const PCMCLK_CNTL: usize = 38;
 const PCMCLK_DIV: usize = 39;
 
+const CLK_PASSWD: usize = 0x5A000000;
+const CLK_CTL_SRC_PLLD: usize = 6;
+const CLK_CTL_ENAB: usize = 1<<4;
+const CLK_CTL_KILL: usize = 1<<5;
+const CLK_CTL_BUSY: usize = 1<<7;
+const CLK_DIV_DIVI_SHIFT: usize = 12;
+const CLK_DIV_DIVI_MASK: usize = 0xFFF;
+// A disabled clock stops at the end of its current cycle, well within this even at the largest divisor
+const CLK_BUSY_TIMEOUT: Duration = Duration::from_millis(10);
+
 /// Indicates using PWM
 pub const DELAY_VIA_PWM: u8 = 0;
 
@@ -175,6 +265,9 @@ const BOARD_REVISION_MANUFACTURER_EMBEST2: usize = 4 << 16;
 const BOARD_REVISION_PROCESSOR_MASK: usize = 0xF << 12;
 const BOARD_REVISION_PROCESSOR_2835: usize = 0 << 12;
 const BOARD_REVISION_PROCESSOR_2836: usize = 1 << 12;
+const BOARD_REVISION_PROCESSOR_2837: usize = 2 << 12;
+const BOARD_REVISION_PROCESSOR_2711: usize = 3 << 12;
+const BOARD_REVISION_PROCESSOR_2712: usize = 4 << 12;
 const BOARD_REVISION_TYPE_MASK: usize = 0xFF << 4;
 const BOARD_REVISION_TYPE_PI1_A: usize = 0 << 4;
 const BOARD_REVISION_TYPE_PI1_B: usize = 1 << 4;
@@ -183,19 +276,78 @@ const BOARD_REVISION_TYPE_PI1_B_PLUS: usize = 3 << 4;
 const BOARD_REVISION_TYPE_PI2_B: usize = 4 << 4;
 const BOARD_REVISION_TYPE_ALPHA: usize = 5 << 4;
 const BOARD_REVISION_TYPE_PI3_B: usize = 8 << 4;
+// Pi 3 Model B, reported by dry-run boards in place of the mailbox
+const DRY_RUN_BOARD_REVISION: usize = 0xa02082;
+// Channels a Pi 3's firmware typically leaves to the ARM, reported by dry-run boards in place of the mailbox
+const DRY_RUN_DMA_CHANNELS: usize = 0x7f35;
+// and a Pi 4's
+const DRY_RUN_PI4_DMA_CHANNELS: usize = 0x71f5;
+// Bus address dry-run boards pretend their mailbox memory is at, in the Pi 2/3 uncached alias
+const DRY_RUN_BUS_ADDR: usize = 0xcf000000;
 const BOARD_REVISION_TYPE_PI3_BP: usize = 0xD << 4;
 const BOARD_REVISION_TYPE_CM: usize = 6 << 4;
 const BOARD_REVISION_TYPE_CM3: usize = 10 << 4;
+const BOARD_REVISION_TYPE_PI4_B: usize = 0x11 << 4;
+const BOARD_REVISION_TYPE_PI400: usize = 0x13 << 4;
+const BOARD_REVISION_TYPE_CM4: usize = 0x14 << 4;
 const BOARD_REVISION_REV_MASK: usize = 0xF;
 
 fn BUS_TO_PHYS(x: usize) -> usize {
     x & (!0xC0000000)
 }
 
+const BUS_ALIAS_MASK: usize = 0xC0000000;
+// Cached alias; the DMA may read stale data through it
+const BUS_ALIAS_CACHED: usize = 0x00000000;
+
+/// Alias (top two bits) of a bus address: 0x4 for L1 non-allocating, 0x8 for coherent, 0xC for uncached.
+pub fn bus_alias(bus_addr: usize) -> usize {
+    bus_addr & BUS_ALIAS_MASK
+}
+
+/// Bus address of offset bytes into mailbox memory at bus_addr, in the same alias the mailbox returned.
+pub fn bus_address(bus_addr: usize, offset: usize) -> usize {
+    bus_alias(bus_addr) | (BUS_TO_PHYS(bus_addr) + offset)
+}
+
+/// Alias the mailbox is expected to return for the given allocation flags.
+pub fn expected_bus_alias(mem_flag: u32) -> usize {
+    match mem_flag & mailbox::MEM_FLAG_L1_NONALLOCATING {
+        mailbox::MEM_FLAG_L1_NONALLOCATING => 0x40000000,
+        mailbox::MEM_FLAG_COHERENT => 0x80000000,
+        mailbox::MEM_FLAG_DIRECT => 0xC0000000,
+        _ => BUS_ALIAS_CACHED,
+    }
+}
+
+/// RAM size in bytes from the board revision; old style revisions are taken as the largest of their time.
+pub fn ram_size(mbox_board_rev: usize) -> usize {
+    if (mbox_board_rev & BOARD_REVISION_SCHEME_MASK) == BOARD_REVISION_SCHEME_NEW {
+        (256 * 1024 * 1024) << ((mbox_board_rev & BOARD_REVISION_RAM_MASK) >> 20)
+    } else {
+        512 * 1024 * 1024
+    }
+}
+
+/// Checks that len bytes of mailbox memory at bus_addr can be used by the DMA: page aligned,
+/// within RAM and not in the cached alias.
+pub fn check_mailbox_memory(bus_addr: usize, len: usize, ram_size: usize) -> Result<(), String> {
+    let phys = BUS_TO_PHYS(bus_addr);
+    if phys & (PAGE_SIZE - 1) != 0 {
+        Err(format!("bus address {:#010x} is not page aligned", bus_addr))
+    } else if phys + len > ram_size {
+        Err(format!("bus address {:#010x} is beyond the {} MB of RAM", bus_addr, ram_size / (1024 * 1024)))
+    } else if bus_alias(bus_addr) == BUS_ALIAS_CACHED {
+        Err(format!("bus address {:#010x} is in the cached alias", bus_addr))
+    } else {
+        Ok(())
+    }
+}
+
 
 const DMA_CHAN_SIZE: usize = 0x100; /* size of register space for a single DMA channel */
 const DMA_CHAN_MAX: usize = 14; // number of DMA Channels we have... actually, there are 15... but channel fifteen is mapped at a different DMA_BASE, so we leave that one alone
-const DMA_CHAN_NUM: usize = 14; // the DMA Channel we are using, NOTE: DMA Ch 0 seems to be used by X... better not use it ;)
+const DMA_CHAN_NUM: usize = 14; // the DMA Channel used by default, NOTE: DMA Ch 0 seems to be used by X... better not use it ;)
 const PWM_BASE_OFFSET: usize = 0x0020c000;
 const PWM_LEN: usize = 0x28;
 const CLK_BASE_OFFSET: usize = 0x00101000;
@@ -205,33 +357,41 @@ const GPIO_LEN: usize = 0x100;
 const PCM_BASE_OFFSET: usize = 0x00203000;
 const PCM_LEN: usize = 0x24;
 
-// DMA Control Block
+// DMA Control Block. The DMA engine reads it as eight 32-bit words from a 32-byte aligned address.
+#[repr(C, align(32))]
 struct DmaCbT {
-    info: RW<usize>,
-    src: RW<usize>,
-    dst: RW<usize>,
-    length: RW<usize>,
-    stride: RW<usize>,
-    next: RW<usize>,
-    _pad: [usize; 2],
+    info: RW<u32>,
+    src: RW<u32>,
+    dst: RW<u32>,
+    length: RW<u32>,
+    stride: RW<u32>,
+    next: RW<u32>,
+    _pad: [u32; 2],
 }
 
-// DMA Controller
-struct Ctl {
-    sample: [RW<usize>; NUM_SAMPLES],
-    cb: [DmaCbT; NUM_CBS],
-}
+const _: () = assert!(size_of::<DmaCbT>() == 32);
+const _: () = assert!(std::mem::align_of::<DmaCbT>() == 32);
+
+// DMA Controller memory is split in two page aligned regions so CPU writes to the samples
+// do not share cache lines (or pages) with control blocks the DMA engine is fetching:
+//  - samples of all groups one after the other, written by update_pwm
+//  - control blocks, read by the DMA engine
+// Both are sized when the board is built, see CtlLayout.
+type Samples = [RW<usize>];
+type ControlBlocks = [DmaCbT];
+
+const SAMPLES_OFFSET: usize = 0;
 
 // MailBox
 struct Mbox {
     handle: i32,                // from mbox_open()
-    mem_ref: usize,               // from mem_allox()
+    mem_ref: u32,                 // from mem_allox()
     bus_addr: usize,              // from mem_lock()
     virt_addr: *mut c_void         // from mapmem()
 }
 
 impl Mbox {
-    fn new(handle: i32, mem_ref: usize, bus_addr: usize, virt_addr: *mut c_void) -> Self {
+    fn new(handle: i32, mem_ref: u32, bus_addr: usize, virt_addr: *mut c_void) -> Self {
         Mbox{handle, mem_ref, bus_addr, virt_addr}
     }
 }
@@ -316,6 +476,688 @@ pub struct BoardBuilder {
     pwm_divisor: usize,
     cycle_time: usize,
     sample_delay: usize,
+    groups: Vec<GroupSettings>,
+
+    dma_throughput: Option<usize>,
+    reject_infeasible_dma: bool,
+
+    auto_idle_time: Option<Duration>,
+
+    adjusted_settings: Vec<AdjustedSetting>,
+    strict: bool,
+
+    dma_channel: Option<usize>,
+
+    waveforms: bool,
+
+    dry_run: bool,
+    dry_run_revision: usize,
+}
+
+/// Snapshot of the DMA channel's control/status and debug registers.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DmaStatus {
+    /// DMA is running (false while auto-idle has it paused).
+    pub active: bool,
+    /// ERROR flag of the CS register.
+    pub error: bool,
+    /// Error bits of the DEBUG register.
+    pub debug_errors: usize,
+    /// AXI read error, from debug_errors.
+    pub read_error: bool,
+    /// Read FIFO error, from debug_errors.
+    pub fifo_error: bool,
+    /// The last read of a burst was not flagged as such, from debug_errors.
+    pub read_last_not_set: bool,
+}
+
+impl DmaStatus {
+    /// Decodes the values of the channel's CS and DEBUG registers.
+    pub fn from_registers(cs: usize, debug: usize) -> DmaStatus {
+        DmaStatus {
+            active: cs & DMA_ACTIVE != 0,
+            error: cs & DMA_ERROR != 0,
+            debug_errors: debug & DMA_DEBUG_ERRORS,
+            read_error: debug & DMA_DEBUG_READ_ERROR != 0,
+            fifo_error: debug & DMA_DEBUG_FIFO_ERROR != 0,
+            read_last_not_set: debug & DMA_DEBUG_READ_LAST_NOT_SET != 0,
+        }
+    }
+
+    /// true when the channel reports any error.
+    pub fn has_error(&self) -> bool {
+        self.error || self.debug_errors != 0
+    }
+}
+
+/// One PWM channel as set up on the board.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ChannelDump {
+    pub pin: u8,
+    /// Index of the pin's group in [SampleDump::group_masks](struct.SampleDump.html#structfield.group_masks).
+    pub group: usize,
+    /// Width as requested with set_pwm.
+    pub width: f32,
+    /// Width the pin runs at after its pulse constraint and quantisation, as from
+    /// [Board::effective_width](struct.Board.html#method.effective_width).
+    pub effective_width: f32,
+    pub constraint: PulseConstraint,
+    /// Set with [Board::set_pin_invert](struct.Board.html#method.set_pin_invert).
+    pub invert: bool,
+    /// Playing a pattern set with [Board::set_waveform](struct.Board.html#method.set_waveform) instead of its width.
+    pub waveform: bool,
+    /// First sample after which the pin is no longer active; None when it stays on for the whole cycle.
+    /// Inverted pins are active at the end of the cycle instead, for as many samples.
+    pub off_index: Option<usize>,
+}
+
+/// Sample masks as read back from the DMA's sample memory, with the channel table they should come from.
+///
+/// Sample 0 sets the pins and every later sample clears pins (see [high_samples](fn.high_samples.html) for
+/// inverted pins), so comparing the masks with each channel's off_index shows where memory differs from the intended state.
+/// Boards built with [BoardBuilder::waveforms](struct.BoardBuilder.html#method.waveforms) clear with every sample
+/// including 0, and set with group_set_masks (see [waveform_masks](fn.waveform_masks.html)).
+#[derive(Clone, Debug, PartialEq)]
+pub struct SampleDump {
+    /// Masks of the first group, the only one unless [BoardBuilder::add_group](struct.BoardBuilder.html#method.add_group) was used.
+    pub masks: Vec<usize>,
+    /// Masks of every group, in the order of [BoardInfo::groups](struct.BoardInfo.html#structfield.groups).
+    pub group_masks: Vec<Vec<usize>>,
+    /// Set masks of every group, written just before the masks of the same sample; empty without waveforms.
+    pub group_set_masks: Vec<Vec<usize>>,
+    pub channels: Vec<ChannelDump>,
+}
+
+impl SampleDump {
+    /// Masks and channels as a JSON object.
+    pub fn to_json(&self) -> String {
+        let masks: Vec<String> = self.masks.iter().map(|mask| format!("{}", mask)).collect();
+        let channels: Vec<String> = self.channels.iter().map(|channel| format!(
+            "{{\"pin\": {}, \"group\": {}, \"width\": {}, \"effective_width\": {}, \"min_on\": {}, \"min_off\": {}, \"invert\": {}, \"waveform\": {}, \"off_index\": {}}}",
+            channel.pin, channel.group, channel.width, channel.effective_width, channel.constraint.min_on, channel.constraint.min_off, channel.invert, channel.waveform,
+            match channel.off_index { Some(index) => format!("{}", index), None => "null".to_string() }
+        )).collect();
+        let masks_json = |group_masks: &Vec<Vec<usize>>| group_masks.iter()
+            .map(|masks| format!("[{}]", masks.iter().map(|mask| format!("{}", mask)).collect::<Vec<String>>().join(", ")))
+            .collect::<Vec<String>>()
+            .join(", ");
+        format!("{{\"channels\": [{}], \"masks\": [{}], \"group_masks\": [{}], \"group_set_masks\": [{}]}}",
+            channels.join(", "), masks.join(", "), masks_json(&self.group_masks), masks_json(&self.group_set_masks))
+    }
+
+    /// Whether the masks of pin's group leave it on (active) at the given sample; low for inverted pins,
+    /// high for pins playing a waveform.
+    pub fn is_on(&self, pin: u8, sample: usize) -> bool {
+        let bit = 1 << pin;
+        let masks = self.pin_masks(pin);
+        let channel = self.channels.iter().find(|channel| channel.pin == pin);
+        let set_masks = channel.and_then(|channel| self.group_set_masks.get(channel.group));
+        let high = match set_masks {
+            Some(set_masks) => {
+                // Whatever level the end of the cycle leaves carries over to the start of the next
+                let next = |high: bool, j: usize| if masks[j] & bit != 0 { false } else if set_masks[j] & bit != 0 { true } else { high };
+                let end = (0..masks.len()).fold(false, next);
+                (0..=sample.min(masks.len() - 1)).fold(end, next)
+            },
+            None => masks.first().map_or(false, |mask| mask & bit != 0)
+                && masks.iter().take(sample + 1).skip(1).all(|mask| mask & bit == 0)
+        };
+        let invert = channel.map_or(false, |channel| channel.invert && !channel.waveform);
+        high != invert
+    }
+
+    /// Masks of the group pin is in; the first group's for pins without a channel.
+    pub fn pin_masks(&self, pin: u8) -> &[usize] {
+        self.channels.iter().find(|channel| channel.pin == pin)
+            .and_then(|channel| self.group_masks.get(channel.group))
+            .unwrap_or(&self.masks)
+    }
+
+    /// One row per channel with '█' for samples where the pin is on and '·' where it is off.
+    ///
+    /// ## Example
+    ///
+    /// ```no_run
+    /// use dma_gpio::pi::BoardBuilder;
+    ///
+    /// fn main() {
+    ///     let mut board = BoardBuilder::new().build_with_pins(vec![21]).unwrap();
+    ///     board.set_pwm(21, 0.25).unwrap();
+    ///     print!("{}", board.dump_samples_structured().waveform());
+    /// }
+    /// ```
+    pub fn waveform(&self) -> String {
+        let mut s = String::new();
+        for channel in &self.channels {
+            s.push_str(&format!("{:>2} {:5.3} ", channel.pin, channel.effective_width));
+            for sample in 0..self.pin_masks(channel.pin).len() {
+                s.push(if self.is_on(channel.pin, sample) { '█' } else { '·' });
+            }
+            s.push('\n');
+        }
+        s
+    }
+}
+
+/// Register blocks that can be read back with [Board::read_register](struct.Board.html#method.read_register).
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Peripheral {
+    Gpio,
+    Pwm,
+    Pcm,
+    Clk,
+    /// The registers of the board's DMA channel only.
+    Dma,
+}
+
+/// A control block whose fields do not match what the chain is built with.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ChainViolation {
+    /// src does not point into sample memory.
+    Source { index: usize, src: u32 },
+    /// dst is not the GPIO set/clear register (sample copies) or the PWM/PCM FIFO (pacing blocks).
+    Destination { index: usize, dst: u32 },
+    Length { index: usize, length: u32 },
+    Stride { index: usize, stride: u32 },
+}
+
+/// Result of walking the control block chain with [Board::verify_cb_chain](struct.Board.html#method.verify_cb_chain).
+#[derive(Clone, Debug, PartialEq)]
+pub struct ChainReport {
+    /// Number of control blocks visited before the chain returned to its head.
+    pub length: usize,
+    pub violations: Vec<ChainViolation>,
+}
+
+/// Why a control block chain failed verification.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ChainError {
+    /// The next pointer of the block at index does not point at a control block.
+    Broken { index: usize, next: u32 },
+    /// The chain returned to its head after length blocks, or did not return within expected + 1 (length is then expected + 1).
+    WrongLength { length: usize, expected: usize },
+    /// The chain is intact but some blocks have unexpected fields.
+    Violations(ChainReport),
+}
+
+// Completes the writes to sample/control block memory before anything that follows,
+// so the DMA never reads a mix of two updates.
+#[inline]
+fn dma_barrier() {
+    fence(Ordering::SeqCst);
+}
+
+/// First sample (after the initial one) at which a channel with the given width is switched off.
+///
+/// Same quantisation as used when the sample masks are written.
+pub fn quantized_off_index(width: f32, num_samples: usize) -> Option<usize> {
+    (1..num_samples).find(|&j| j as f32 / num_samples as f32 > width)
+}
+
+/// Shortest pulse and shortest gap after it a channel may have, as fractions of the cycle.
+///
+/// Set per pin with [Board::set_pulse_constraints](struct.Board.html#method.set_pulse_constraints);
+/// the default of (0.0, 0.0) leaves widths as they are.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct PulseConstraint {
+    pub min_on: f32,
+    pub min_off: f32,
+}
+
+impl PulseConstraint {
+    /// Both fractions must be between 0.0 and 1.0 and together not more than 1.0.
+    pub fn new(min_on: f32, min_off: f32) -> Result<PulseConstraint, Error> {
+        if !(0.0..=1.0).contains(&min_on) || !(0.0..=1.0).contains(&min_off) {
+            Err(Error::new(ErrorKind::Other, format!("Pulse constraints {}, {} out of range.", min_on, min_off)))
+        } else if min_on + min_off > 1.0 {
+            Err(Error::new(ErrorKind::Other, format!("Minimum on time {} and off time {} do not fit in one cycle.", min_on, min_off)))
+        } else {
+            Ok(PulseConstraint { min_on, min_off })
+        }
+    }
+
+    /// Widths below min_on become 0.0 and widths above 1.0 - min_off become 1.0 - min_off.
+    pub fn apply(&self, width: f32) -> f32 {
+        let cap = 1.0 - self.min_off;
+        if width > cap {
+            cap
+        } else if width < self.min_on {
+            0.0
+        } else {
+            width
+        }
+    }
+}
+
+/// [quantized_off_index](fn.quantized_off_index.html) of the width after the constraint, with at least
+/// min_off of the cycle's samples left off.
+pub fn constrained_off_index(width: f32, constraint: PulseConstraint, num_samples: usize) -> Option<usize> {
+    let off_index = quantized_off_index(constraint.apply(width), num_samples);
+    if constraint.min_off > 0.0 {
+        let max_on = num_samples.saturating_sub((constraint.min_off * num_samples as f32).ceil() as usize);
+        Some(off_index.unwrap_or(num_samples).min(max_on).max(1))
+    } else {
+        off_index
+    }
+}
+
+/// Samples from the start of the cycle a pin is high for: its active samples, or for an inverted
+/// pin the samples it is not active.
+///
+/// Inverted pins are the mirror image of a normal one: high from sample 0 and low for the active
+/// part at the end of the cycle, instead of low at the start. So every pin, inverted or not, is only
+/// ever set at sample 0 and cleared later, and one set mask and then one clear mask per sample do.
+pub fn high_samples(width: f32, constraint: PulseConstraint, invert: bool, num_samples: usize) -> usize {
+    let active = if constraint.apply(width) > 0.0 {
+        constrained_off_index(width, constraint, num_samples).unwrap_or(num_samples)
+    } else {
+        0
+    };
+    if invert {
+        num_samples - active
+    } else {
+        active
+    }
+}
+
+/// Masks of one group's cycle for pins with their [high_samples](fn.high_samples.html): the first
+/// is written to the set register and has the pins high for any samples, the others to the clear
+/// register and have the pins whose high samples are over.
+pub fn sample_masks(pins: &[(u8, usize)], num_samples: usize) -> Vec<usize> {
+    (0..num_samples).map(|j| pins.iter()
+        .filter(|(_, high)| if j == 0 { *high > 0 } else { j >= *high })
+        .fold(0, |mask, (pin, _)| mask | 1 << pin)
+    ).collect()
+}
+
+/// Set and clear masks of one group's cycle on a board built with [BoardBuilder::waveforms](struct.BoardBuilder.html#method.waveforms):
+/// the PWM pins as in pwm_masks (from [sample_masks](fn.sample_masks.html)), set at sample 0 and cleared after,
+/// merged with pins playing a pattern, set at the samples it is true and cleared at the others.
+pub fn waveform_masks(pwm_masks: &[usize], waveforms: &[(u8, &[bool])]) -> (Vec<usize>, Vec<usize>) {
+    let mut set_masks: Vec<usize> = pwm_masks.iter().enumerate().map(|(j, mask)| if j == 0 { *mask } else { 0 }).collect();
+    let mut clear_masks: Vec<usize> = pwm_masks.iter().enumerate().map(|(j, mask)| if j == 0 { 0 } else { *mask }).collect();
+    for (pin, pattern) in waveforms {
+        for (j, high) in pattern.iter().enumerate().take(pwm_masks.len()) {
+            if *high {
+                set_masks[j] |= 1 << pin;
+            } else {
+                clear_masks[j] |= 1 << pin;
+            }
+        }
+    }
+    (set_masks, clear_masks)
+}
+
+/// Pull resistor of a GPIO pin, set with [Board::set_pull](struct.Board.html#method.set_pull).
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Pull {
+    None,
+    Down,
+    Up,
+}
+
+impl Pull {
+    // GPPUD control value
+    fn control(&self) -> usize {
+        match self {
+            Pull::None => 0,
+            Pull::Down => 1,
+            Pull::Up => 2,
+        }
+    }
+}
+
+// Where a model has its peripherals and what its DMA and clocks can do.
+struct ModelSettings {
+    // 1 to 4
+    model: usize,
+    periph_virt_base: usize,
+    periph_phys_base: usize,
+    mem_flag: u32,
+    dma_throughput: usize,
+    // Hz of PLLD, the clock the PWM divisor divides
+    plld_freq: usize,
+    // Bitmask of the DMA channels this crate can drive on the model
+    usable_dma_channels: usize,
+    default_dma_channel: usize,
+}
+
+// Pins given their own cycle time with BoardBuilder::add_group.
+#[derive(Clone, Debug)]
+struct GroupSettings {
+    cycle_time: usize,
+    sample_delay: usize,
+    pins: Vec<u8>,
+}
+
+// Pins sharing a cycle time, with their own samples in sample memory.
+#[derive(Clone, Debug)]
+struct GroupLayout {
+    pins: Vec<u8>,
+    cycle_time: usize,
+    sample_delay: usize,
+    num_samples: usize,
+    // Index of the group's sample 0 in sample memory
+    first_sample: usize,
+    // Ring slots per sample
+    step: usize,
+}
+
+// Where the samples and control blocks of all groups go in mailbox memory.
+//
+// The DMA is paced in slots of the greatest common divisor of the groups' sample delays. Each slot has
+// one control block per group due a new sample in it, copying that sample to the GPIO set/clear
+// register, and one pacing control block. The ring is as many slots long as it takes every group to
+// come back to its sample 0 at the same time, so with one group it is the familiar two blocks per sample.
+//
+// With waveforms, sample memory has a second set of num_samples masks after the first, copied to the
+// set register just before each sample's own copy to the clear register.
+struct CtlLayout {
+    groups: Vec<GroupLayout>,
+    // Index into groups, by gpio
+    pin_group: [usize; MAX_CHANNELS],
+    sample_delay: usize,
+    num_samples: usize,
+    waveforms: bool,
+    // Sample each control block copies, None for the pacing ones
+    cb_samples: Vec<Option<usize>>,
+    cbs_offset: usize,
+    num_pages: usize,
+}
+
+fn gcd(a: usize, b: usize) -> usize {
+    if b == 0 { a } else { gcd(b, a % b) }
+}
+
+impl CtlLayout {
+    // Pins of the board not in any group run at the board's cycle time and sample delay.
+    fn new(known_pins: &[u8; MAX_CHANNELS], num_channels: usize, cycle_time: usize, sample_delay: usize, settings: &[GroupSettings], waveforms: bool) -> Result<CtlLayout, BuildError> {
+        let pins: Vec<u8> = known_pins.iter().cloned().take(num_channels).filter(|&pin| pin != NO_PIN).collect();
+
+        let mut grouped = [false; MAX_CHANNELS];
+        for (i, group) in settings.iter().enumerate() {
+            if group.pins.is_empty() {
+                return Err(BuildError::EmptyGroup(i + 1))
+            }
+            if group.sample_delay == 0 || group.cycle_time / group.sample_delay < 2 {
+                return Err(BuildError::TooFewSamples { group: i + 1, cycle_time: group.cycle_time, sample_delay: group.sample_delay })
+            }
+            for &pin in &group.pins {
+                if !pins.contains(&pin) {
+                    return Err(BuildError::PinNotOnBoard { pin, group: i + 1, pins })
+                }
+                if grouped[pin as usize] {
+                    return Err(BuildError::PinInTwoGroups(pin))
+                }
+                grouped[pin as usize] = true;
+            }
+        }
+
+        let mut group_settings = vec![];
+        let ungrouped: Vec<u8> = pins.iter().cloned().filter(|&pin| !grouped[pin as usize]).collect();
+        if !ungrouped.is_empty() || settings.is_empty() {
+            group_settings.push(GroupSettings { cycle_time, sample_delay, pins: ungrouped });
+        }
+        group_settings.extend(settings.iter().cloned());
+
+        let tick = group_settings.iter().fold(0, |acc, group| gcd(acc, group.sample_delay));
+        let mut groups = vec![];
+        let mut pin_group = [0; MAX_CHANNELS];
+        let mut num_samples = 0;
+        let mut ring_slots: usize = 1;
+        for (index, group) in group_settings.into_iter().enumerate() {
+            let group_samples = group.cycle_time / group.sample_delay;
+            let step = group.sample_delay / tick;
+            let period = group_samples * step;
+            ring_slots = (ring_slots / gcd(ring_slots, period)).saturating_mul(period);
+            for &pin in &group.pins {
+                pin_group[pin as usize] = index;
+            }
+            groups.push(GroupLayout {
+                pins: group.pins,
+                cycle_time: group.cycle_time,
+                sample_delay: group.sample_delay,
+                num_samples: group_samples,
+                first_sample: num_samples,
+                step,
+            });
+            num_samples += group_samples;
+        }
+
+        let copies = if waveforms { 2 } else { 1 };
+        let num_cbs = groups.iter().fold(ring_slots, |acc, group| acc.saturating_add(ring_slots / group.step * copies));
+        let cbs_offset = ((num_samples * copies * size_of::<usize>() + PAGE_SIZE - 1) >> PAGE_SHIFT) << PAGE_SHIFT;
+        let num_pages = cbs_offset.saturating_add(num_cbs.saturating_mul(size_of::<DmaCbT>())).saturating_add(PAGE_SIZE - 1) >> PAGE_SHIFT;
+        if num_pages > MAX_CTL_PAGES {
+            return Err(BuildError::SampleCountTooHigh { samples: num_samples, control_blocks: num_cbs, pages: num_pages })
+        }
+
+        let mut cb_samples = Vec::with_capacity(num_cbs);
+        for slot in 0..ring_slots {
+            for group in &groups {
+                if slot % group.step == 0 {
+                    let sample = group.first_sample + (slot / group.step) % group.num_samples;
+                    if waveforms {
+                        cb_samples.push(Some(num_samples + sample));
+                    }
+                    cb_samples.push(Some(sample));
+                }
+            }
+            cb_samples.push(None);
+        }
+
+        Ok(CtlLayout { groups, pin_group, sample_delay: tick, num_samples, waveforms, cb_samples, cbs_offset, num_pages })
+    }
+
+    // Words of sample memory: the clear masks, then with waveforms the set masks.
+    fn sample_words(&self) -> usize {
+        if self.waveforms { self.num_samples * 2 } else { self.num_samples }
+    }
+}
+
+// Direction GPIO driven together with a PWM pin by set_signed_pwm.
+#[derive(Clone, Copy, Debug)]
+struct DirectionBinding {
+    pwm_pin: u8,
+    dir_pin: u8,
+    active_high: bool,
+}
+
+/// A builder setting that was clamped into its allowed range, with [BoardBuilder::strict](struct.BoardBuilder.html#method.strict) off.
+///
+/// Available from [Board::adjusted_settings](struct.Board.html#method.adjusted_settings) so callers can report it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct AdjustedSetting {
+    pub name: &'static str,
+    pub requested: usize,
+    pub effective: usize,
+}
+
+/// Why [BoardBuilder::build](struct.BoardBuilder.html#method.build) or
+/// [build_with_pins](struct.BoardBuilder.html#method.build_with_pins) failed.
+///
+/// Converts into std::io::Error, so `?` still works in functions returning io::Result.
+#[derive(Debug)]
+pub enum BuildError {
+    /// Divisor given to divide_pwm is not between 1 and [MAX_PWM_DIVISOR](constant.MAX_PWM_DIVISOR.html), or
+    /// [MAX_PCM_DIVISOR](constant.MAX_PCM_DIVISOR.html) with PCM pacing.
+    InvalidDivisor(usize),
+    /// PCM cannot pace samples at divisor times sample delay PLLD clocks; see [pcm_pacing](fn.pcm_pacing.html).
+    PcmPacing { divisor: usize, sample_delay: usize },
+    /// Cycle time given to set_cycle_time is not between [MIN_CYCLE_TIME](constant.MIN_CYCLE_TIME.html)
+    /// and [MAX_CYCLE_TIME](constant.MAX_CYCLE_TIME.html).
+    InvalidCycleTime(usize),
+    /// Sample delay given to set_sample_delay is not between 1 and [MAX_SAMPLE_DELAY](constant.MAX_SAMPLE_DELAY.html).
+    InvalidSampleDelay(usize),
+    /// Cycle time is not a whole number of sample delays; group 0 is the board's own pins.
+    CycleTimeNotMultiple { group: usize, cycle_time: usize, sample_delay: usize },
+    /// A group's cycle time and sample delay give fewer than 2 samples.
+    TooFewSamples { group: usize, cycle_time: usize, sample_delay: usize },
+    /// Samples and control blocks of all groups need more than [MAX_CTL_PAGES](constant.MAX_CTL_PAGES.html) of memory.
+    SampleCountTooHigh { samples: usize, control_blocks: usize, pages: usize },
+    /// GPIO number is not below [MAX_CHANNELS](constant.MAX_CHANNELS.html).
+    InvalidPin(u8),
+    /// GPIO is one of [BANNED_PINS](static.BANNED_PINS.html).
+    BannedPin(u8),
+    /// More pins than [MAX_CHANNELS](constant.MAX_CHANNELS.html).
+    TooManyPins(usize),
+    /// The same pin given to build_with_pins more than once.
+    DuplicatePin(u8),
+    /// A group added with add_group has no pins.
+    EmptyGroup(usize),
+    /// A pin of a group is not one of the board's pins.
+    PinNotOnBoard { pin: u8, group: usize, pins: Vec<u8> },
+    PinInTwoGroups(u8),
+    /// The DMA cannot sustain the sample rate and [reject_infeasible_dma](struct.BoardBuilder.html#method.reject_infeasible_dma) was set.
+    InfeasibleDma(DmaFeasibility),
+    /// Another Board of this process has the DMA channel asked for with use_dma_channel or, without it,
+    /// every channel free for the ARM.
+    DmaChannelInUse(usize),
+    /// Another Board of this process paces with the same peripheral ("PWM" or "PCM"); see [use_pcm](struct.BoardBuilder.html#method.use_pcm).
+    PacingInUse(&'static str),
+    /// Mailbox, device or memory failure while setting up the hardware, or an unusable DMA channel or model.
+    Io(Error),
+}
+
+impl fmt::Display for BuildError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BuildError::InvalidDivisor(divisor) =>
+                write!(f, "PWM divisor {} is out of range 1 to {} ({} with PCM pacing).", divisor, MAX_PWM_DIVISOR, MAX_PCM_DIVISOR),
+            BuildError::PcmPacing { divisor, sample_delay } =>
+                write!(f, "PCM cannot pace divisor {} times sample delay {}: it needs a frame of {} to {} clocks at a divisor of at most {}.",
+                       divisor, sample_delay, MIN_PCM_FRAME, MAX_PCM_FRAME, MAX_PCM_DIVISOR),
+            BuildError::InvalidCycleTime(units) => write!(f, "Cycle time {} is out of range {} to {}.", units, MIN_CYCLE_TIME, MAX_CYCLE_TIME),
+            BuildError::InvalidSampleDelay(units) => write!(f, "Sample delay {} is out of range 1 to {}.", units, MAX_SAMPLE_DELAY),
+            BuildError::CycleTimeNotMultiple { group, cycle_time, sample_delay } =>
+                write!(f, "Cycle time {} of group {} is not a multiple of sample delay {}.", cycle_time, group, sample_delay),
+            BuildError::TooFewSamples { group, cycle_time, sample_delay } =>
+                write!(f, "Group {} needs at least 2 samples; cycle time {} and sample delay {} do not give them.", group, cycle_time, sample_delay),
+            BuildError::SampleCountTooHigh { samples, control_blocks, pages } =>
+                write!(f, "Cycle times of the groups need {} samples and a ring of {} control blocks in {} pages of DMA memory; the limit is {} pages ({} bytes).",
+                    samples, control_blocks, pages, MAX_CTL_PAGES, MAX_CTL_PAGES * PAGE_SIZE),
+            BuildError::InvalidPin(pin) => write!(f, "{} is an invalid gpio", pin),
+            BuildError::BannedPin(pin) => write!(f, "{} is a banned gpio; banned pins: {:?}", pin, BANNED_PINS),
+            BuildError::TooManyPins(count) => write!(f, "number of pins {} exceeds max number of channels: {}", count, MAX_CHANNELS),
+            BuildError::DuplicatePin(pin) => write!(f, "pin {} is given more than once", pin),
+            BuildError::EmptyGroup(group) => write!(f, "Group {} has no pins.", group),
+            BuildError::PinNotOnBoard { pin, group, pins } => write!(f, "Pin {} of group {} is not one of the board's pins {:?}.", pin, group, pins),
+            BuildError::PinInTwoGroups(pin) => write!(f, "Pin {} is in more than one group.", pin),
+            BuildError::InfeasibleDma(feasibility) =>
+                write!(f, "requested sample rate {:.0} Hz exceeds the DMA's ~{:.0} Hz; nearest achievable divisor is {}",
+                    feasibility.requested_sample_rate, feasibility.max_sample_rate, feasibility.nearest_divisor),
+            BuildError::DmaChannelInUse(ch) => write!(f, "DMA channel {} is already in use by this process; claimed channels: {:?}", ch, claimed_dma_channels()),
+            BuildError::PacingInUse(pacing) => write!(f, "{} pacing is already in use by another board of this process; build this one with the other peripheral", pacing),
+            BuildError::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for BuildError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            BuildError::Io(e) => Some(e),
+            _ => None
+        }
+    }
+}
+
+impl From<Error> for BuildError {
+    fn from(e: Error) -> BuildError {
+        BuildError::Io(e)
+    }
+}
+
+impl From<BuildError> for Error {
+    fn from(e: BuildError) -> Error {
+        match e {
+            BuildError::Io(e) => e,
+            e => Error::new(ErrorKind::InvalidInput, e)
+        }
+    }
+}
+
+/// What the board was built as, from [Board::info](struct.Board.html#method.info).
+#[derive(Clone, Debug, PartialEq)]
+pub struct BoardInfo {
+    /// Pi model (1 to 4) as derived from the revision, or from where the device tree puts the peripherals.
+    pub model: usize,
+    /// Board revision reported by the mailbox.
+    pub revision: usize,
+    /// "PWM" or "PCM", whichever paces the DMA.
+    pub pacing: &'static str,
+    pub dma_channel: usize,
+    /// Physical address of the DMA controller's registers.
+    pub dma_base: usize,
+    /// Hz of the clock the PWM or PCM divides down for pacing.
+    pub plld_frequency: usize,
+    /// Divisor of the pacing peripheral's clock and clocks per sample: the PWM range, or the PCM frame length.
+    pub pacing_divisor: usize,
+    pub pacing_clocks: usize,
+    /// Divisor the pacing clock's divider register read back once programmed; anything other than
+    /// pacing_divisor means the clock is not running at the rate asked for.
+    pub clock_divisor: usize,
+    /// Samples per second the DMA is paced at, plld_frequency / (pacing_divisor * pacing_clocks);
+    /// the same for PWM and PCM pacing of the same settings.
+    pub sample_frequency: f64,
+    pub pins: Vec<u8>,
+    pub pwm_frequency: f64,
+    pub pwm_steps: usize,
+    pub dry_run: bool,
+    /// Pulse constraint of each of pins, in the same order.
+    pub pulse_constraints: Vec<PulseConstraint>,
+    /// Pins with their frequency; one group unless some were given their own with
+    /// [BoardBuilder::add_group](struct.BoardBuilder.html#method.add_group). pwm_frequency and pwm_steps are the first's.
+    pub groups: Vec<GroupInfo>,
+}
+
+/// Pins running at one cycle time, as part of [BoardInfo](struct.BoardInfo.html).
+#[derive(Clone, Debug, PartialEq)]
+pub struct GroupInfo {
+    pub pins: Vec<u8>,
+    pub pwm_frequency: f64,
+    pub pwm_steps: usize,
+    /// Length of one sample, the shortest pulse other than none, in microseconds.
+    pub min_period_us: f64,
+    /// Length of the whole cycle (100 %) in microseconds.
+    pub max_period_us: f64,
+}
+
+/// Result of comparing the requested sample pacing with what the DMA can sustain.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DmaFeasibility {
+    /// Samples per second the divisor and sample delay ask for.
+    pub requested_sample_rate: f64,
+    /// Samples per second the DMA can sustain at the assumed throughput.
+    pub max_sample_rate: f64,
+    /// Smallest divisor (fastest pacing) that stays within the DMA's throughput.
+    pub nearest_divisor: usize,
+}
+
+impl DmaFeasibility {
+    /// Computes feasibility of pacing samples at PWM_CLOCK_HZ / pwm_divisor / sample_delay
+    /// on a DMA that manages dma_throughput transfers per second.
+    pub fn new(pwm_divisor: usize, sample_delay: usize, dma_throughput: usize) -> DmaFeasibility {
+        DmaFeasibility::with_clock(PWM_CLOCK_HZ, pwm_divisor, sample_delay, dma_throughput)
+    }
+
+    /// As [new](struct.DmaFeasibility.html#method.new), for a board whose PLLD runs at clock_hz.
+    pub fn with_clock(clock_hz: usize, pwm_divisor: usize, sample_delay: usize, dma_throughput: usize) -> DmaFeasibility {
+        let requested_sample_rate = clock_hz as f64 / pwm_divisor as f64 / sample_delay as f64;
+        let max_sample_rate = dma_throughput as f64 / DMA_TRANSFERS_PER_SAMPLE as f64;
+        let nearest_divisor = (clock_hz as f64 / (sample_delay as f64 * max_sample_rate)).ceil() as usize;
+
+        DmaFeasibility {
+            requested_sample_rate,
+            max_sample_rate,
+            nearest_divisor: nearest_divisor.max(1).min(1000),
+        }
+    }
+
+    /// true when the DMA can keep up with the requested sample rate.
+    pub fn is_achievable(&self) -> bool {
+        self.requested_sample_rate <= self.max_sample_rate
+    }
 }
 
 impl BoardBuilder {
@@ -330,7 +1172,53 @@ impl BoardBuilder {
             pwm_divisor: DEFAULT_PWM_DIVISOR,
             cycle_time: DEFAULT_CYCLE_TIME,
             sample_delay: DEFAULT_SAMPLE_DELAY,
+            groups: vec![],
+
+            dma_throughput: None,
+            reject_infeasible_dma: false,
+
+            auto_idle_time: None,
+
+            adjusted_settings: vec![],
+            strict: true,
+
+            dma_channel: None,
+
+            waveforms: false,
+
+            dry_run: false,
+            dry_run_revision: DRY_RUN_BOARD_REVISION,
+        }
+    }
+
+    fn record_setting(&mut self, name: &'static str, requested: usize, effective: usize) {
+        self.adjusted_settings.retain(|setting| setting.name != name);
+        if requested != effective {
+            self.adjusted_settings.push(AdjustedSetting { name, requested, effective });
+        }
+    }
+
+    // Settings the setters had to clamp, and cycle times that leave part of a sample delay over.
+    fn validate(&self) -> Result<(), BuildError> {
+        if let Some(setting) = self.adjusted_settings.first() {
+            return Err(match setting.name {
+                "pwm_divisor" => BuildError::InvalidDivisor(setting.requested),
+                "cycle_time" => BuildError::InvalidCycleTime(setting.requested),
+                _ => BuildError::InvalidSampleDelay(setting.requested)
+            })
+        }
+        if self.delay_hw == DELAY_VIA_PWM && self.pwm_divisor > MAX_PWM_DIVISOR {
+            return Err(BuildError::InvalidDivisor(self.pwm_divisor))
         }
+        if self.cycle_time % self.sample_delay != 0 {
+            return Err(BuildError::CycleTimeNotMultiple { group: 0, cycle_time: self.cycle_time, sample_delay: self.sample_delay })
+        }
+        for (i, group) in self.groups.iter().enumerate() {
+            if group.sample_delay > 0 && group.cycle_time % group.sample_delay != 0 {
+                return Err(BuildError::CycleTimeNotMultiple { group: i + 1, cycle_time: group.cycle_time, sample_delay: group.sample_delay })
+            }
+        }
+        Ok(())
     }
 
     /// Builds and returns Result<[Board](struct.Board.html)>.
@@ -346,13 +1234,39 @@ impl BoardBuilder {
     ///     
     /// }
     /// ```
-    pub fn build(&self) -> Result<Board, Error> {
-        Board::new(self.delay_hw, self.known_pins, self.num_channels, self.pwm_divisor, self.cycle_time, self.sample_delay)
+    ///
+    /// If the divisor and sample delay ask for more samples per second than the DMA can deliver
+    /// (see [assume_dma_throughput](struct.BoardBuilder.html#method.assume_dma_throughput)),
+    /// a warning with the nearest achievable divisor is logged, or, with
+    /// [reject_infeasible_dma](struct.BoardBuilder.html#method.reject_infeasible_dma), an error is returned.
+    /// The outcome is available through [Board::dma_feasibility](struct.Board.html#method.dma_feasibility).
+    ///
+    /// Unless [strict](struct.BoardBuilder.html#method.strict) was turned off, a divisor, cycle time or sample delay
+    /// out of range, or a cycle time that is not a multiple of its sample delay, fails with a [BuildError](enum.BuildError.html).
+    pub fn build(&self) -> Result<Board, BuildError> {
+        if self.strict {
+            if let Err(e) = self.validate() {
+                error!("{}", e);
+                return Err(e)
+            }
+        }
+        // divide_pwm leaves room for the PCM clock's divider; PWM's takes no more than MAX_PWM_DIVISOR
+        let mut pwm_divisor = self.pwm_divisor;
+        let mut adjusted_settings = self.adjusted_settings.clone();
+        if self.delay_hw == DELAY_VIA_PWM && pwm_divisor > MAX_PWM_DIVISOR {
+            let requested = adjusted_settings.iter().find(|setting| setting.name == "pwm_divisor").map_or(pwm_divisor, |setting| setting.requested);
+            pwm_divisor = MAX_PWM_DIVISOR;
+            adjusted_settings.retain(|setting| setting.name != "pwm_divisor");
+            adjusted_settings.push(AdjustedSetting { name: "pwm_divisor", requested, effective: pwm_divisor });
+        }
+        Board::new(self, pwm_divisor, adjusted_settings)
     }
 
     /// Builds and returns Result<[Board](struct.Board.html)> with specific pins.
     /// 
     /// Be sure to look out for banned pins: [6, 28, 29, 30, 31, 40, 45, 46, 47, 48, 49, 50, 51, 52, 53]
+    ///
+    /// Each pin is one channel, so a pin given twice is an error rather than two channels driving the same GPIO.
     /// 
     /// ## Example
     /// ```no_run
@@ -365,28 +1279,31 @@ impl BoardBuilder {
     ///     
     /// }
     /// ```
-    pub fn build_with_pins(mut self, pins: Vec<u8>) -> Result<Board, Error> {
-        let pins: Vec<u8> = pins.iter().filter(|&&pin| pin > 0).map(|&pin| pin).collect();
+    pub fn build_with_pins(mut self, pins: Vec<u8>) -> Result<Board, BuildError> {
         let pins_len = pins.len();
-        let mut temp_pins = [0; MAX_CHANNELS];
+        let mut temp_pins = [NO_PIN; MAX_CHANNELS];
         if pins_len <= MAX_CHANNELS {
             for i in 0..pins_len {
                 if pins[i] >= MAX_CHANNELS as u8 {
-                    let error = format!("ERROR: {:} is an invalid gpio\n", pins[i]);
+                    let error = BuildError::InvalidPin(pins[i]);
                     error!("{}", error);
-                    return Err(Error::new(ErrorKind::Other, error))
+                    return Err(error)
                 }else if is_banned_pin(pins[i]){
-                    let error = format!("ERROR: {:} is a banned gpio\nBanned pins: {:?}", pins[i], BANNED_PINS);
+                    let error = BuildError::BannedPin(pins[i]);
                     error!("{}", error);
-                    return Err(Error::new(ErrorKind::Other, error))
+                    return Err(error)
+                }else if pins[..i].contains(&pins[i]) {
+                    let error = BuildError::DuplicatePin(pins[i]);
+                    error!("{}", error);
+                    return Err(error)
                 }else{
                     temp_pins[i] = pins[i];
                 }
             }
         }else {
-            let error = format!("ERROR: number of pins {} exceeds max number of channels: {}\n", pins_len, MAX_CHANNELS);
+            let error = BuildError::TooManyPins(pins_len);
             error!("{}", error);
-            return Err(Error::new(ErrorKind::Other, error))
+            return Err(error)
         }
 
         self.num_channels = pins_len;
@@ -395,6 +1312,10 @@ impl BoardBuilder {
     }
 
     /// Use pcm instead of pwm for dma scheduling
+    ///
+    /// Only one Board of a process can pace with each, so a second Board, e.g. LEDs next to motors,
+    /// is built with the one the first does not use; otherwise build fails with
+    /// [BuildError::PacingInUse](enum.BuildError.html#variant.PacingInUse).
     /// 
     /// ## Example
     /// ```no_run
@@ -428,12 +1349,18 @@ impl BoardBuilder {
     /// }
     /// ```
     /// 
+    /// PWM pacing takes divisors up to [MAX_PWM_DIVISOR](constant.MAX_PWM_DIVISOR.html), PCM pacing up to
+    /// [MAX_PCM_DIVISOR](constant.MAX_PCM_DIVISOR.html); either paces a sample every divisor * sample delay
+    /// PLLD clocks (see [pcm_pacing](fn.pcm_pacing.html)).
     pub fn divide_pwm(mut self, divisor: usize) -> Self {
-        if divisor > 1000 {
-            self.pwm_divisor = 1000;
+        if divisor == 0 {
+            self.pwm_divisor = 1;
+        }else if divisor > MAX_PCM_DIVISOR {
+            self.pwm_divisor = MAX_PCM_DIVISOR;
         }else {
             self.pwm_divisor = divisor;
         }
+        self.record_setting("pwm_divisor", divisor, self.pwm_divisor);
         self
     }
 
@@ -456,13 +1383,14 @@ impl BoardBuilder {
     /// }
     /// ```
     pub fn set_cycle_time(mut self, units: usize) -> Self {
-        if units < 200{
-            self.cycle_time = 200;
-        }else if units > 1000 {
-            self.cycle_time = 1000;
+        if units < MIN_CYCLE_TIME {
+            self.cycle_time = MIN_CYCLE_TIME;
+        }else if units > MAX_CYCLE_TIME {
+            self.cycle_time = MAX_CYCLE_TIME;
         }else {
             self.cycle_time = units;
         }
+        self.record_setting("cycle_time", units, self.cycle_time);
         self
     }
 
@@ -487,64 +1415,276 @@ impl BoardBuilder {
     pub fn set_sample_delay(mut self, units: usize) -> Self {
         if units == 0 {
             self.sample_delay = 1;
-        }else if units > 100{
-            self.sample_delay = 100;
+        }else if units > MAX_SAMPLE_DELAY {
+            self.sample_delay = MAX_SAMPLE_DELAY;
         }else {
             self.sample_delay = units;
         }
+        self.record_setting("sample_delay", units, self.sample_delay);
         self
     }
-}
 
-/// Struct for dealing with GPIO Pins.
-/// 
-/// Board is initialized through [BoardBuilder](struct.BoardBuilder.html).
-/// 
-/// Note that you can only manipulate pins that are set from BoardBuilder,
-/// 
-/// so if the pin you want to access is not one of the default pins: [4, 17, 18, 27, 21, 22, 23, 24, 25], make sure to set it with [BoardBuilder::build_with_pins](struct.BoardBuilder.html#method.build_with_pins).
-/// 
-/// ## Example
-/// This example uses pins [21, 22, 23],
-/// 
-/// sets pin 21 to 25%, pin 22 to 50%, and pin 23 to 75%,
-/// 
-/// then, after 1 second, releases pin 22,
-/// 
-/// then, after 1 second, release all pins.
-/// 
-/// ```no_run
-/// use std::thread::sleep;
-/// use std::time::Duration;
-/// use dma_gpio::pi::BoardBuilder;
-/// 
-/// fn main() {
-///     let mut board = BoardBuilder::new().build_with_pins(vec![21, 22, 23]).unwrap();
-///     board.print_info();
-///     
-///     board.set_pwm(21, 0.25).unwrap();
-///     board.set_pwm(22, 0.50).unwrap();
-///     board.set_pwm(23, 0.75).unwrap();
-///     
-///     let sec = Duration::from_millis(1000);
-///     sleep(millis);
-///     
-///     board.release_pwm(22).unwrap();
-///     
-///     sleep(millis);
-///     
-///     board.release_all_pwm().unwrap();
+    /// Settings for hobby servos: a 20 ms period ([SERVO_CYCLE_TIME](constant.SERVO_CYCLE_TIME.html)) in 10 us steps
+    /// ([SERVO_SAMPLE_DELAY](constant.SERVO_SAMPLE_DELAY.html)) at the default divisor, for use with
+    /// [Board::set_servo_pulse](struct.Board.html#method.set_servo_pulse).
+    ///
+    /// The cycle time is beyond what [set_cycle_time](struct.BoardBuilder.html#method.set_cycle_time) accepts, so setting
+    /// the cycle time, sample delay or divisor afterwards replaces these settings rather than adjusting them.
+    /// A Pi 4's faster clock makes the period 13.3 ms; [Board::period_us](struct.Board.html#method.period_us) tells.
+    /// To have servos next to faster PWM, use [add_group](struct.BoardBuilder.html#method.add_group) instead.
+    ///
+    /// ## Example
+    /// ```no_run
+    /// use dma_gpio::pi::BoardBuilder;
+    ///
+    /// fn main() {
+    ///     let mut board = BoardBuilder::new().servo_defaults().build_with_pins(vec![18]).unwrap();
+    ///     board.set_servo_pulse(18, 1500.0).unwrap();
+    /// }
+    /// ```
+    pub fn servo_defaults(mut self) -> Self {
+        self.pwm_divisor = DEFAULT_PWM_DIVISOR;
+        self.cycle_time = SERVO_CYCLE_TIME;
+        self.sample_delay = SERVO_SAMPLE_DELAY;
+        self.adjusted_settings.clear();
+        self
+    }
+
+    /// Run some of the board's pins at their own cycle time and sample delay, e.g. a servo at 50 Hz
+    /// next to motors at a few kHz. [Board::set_pwm](struct.Board.html#method.set_pwm) is the same for them;
+    /// pins in no group keep the board's cycle time and sample delay.
+    ///
+    /// Each group gets its own samples, all written from one DMA ring paced at the greatest common divisor
+    /// of the sample delays, so keep the sample delays multiples of each other and the cycle times
+    /// multiples of each other. Unlike [set_cycle_time](struct.BoardBuilder.html#method.set_cycle_time),
+    /// cycle_time is not clamped; [build](struct.BoardBuilder.html#method.build) fails when the ring needs
+    /// more than [MAX_CTL_PAGES](constant.MAX_CTL_PAGES.html) of memory, when a pin is not one of the board's
+    /// or is in two groups, or when a group has fewer than 2 samples.
+    ///
+    /// ## Example
+    /// ```no_run
+    /// use dma_gpio::pi::BoardBuilder;
+    ///
+    /// fn main() {
+    ///     // motors on 20 and 21 at 1 MHz / 400 = 2.5 kHz, a servo on 26 at 1 MHz / 20000 = 50 Hz
+    ///     let mut board = BoardBuilder::new()
+    ///         .set_cycle_time(400)
+    ///         .set_sample_delay(4)
+    ///         .add_group(20000, 100, vec![26])
+    ///         .build_with_pins(vec![20, 21, 26]).unwrap();
+    ///     board.print_info();
+    ///     board.set_pwm(26, 0.075).unwrap();
+    /// }
+    /// ```
+    pub fn add_group(mut self, cycle_time: usize, sample_delay: usize, pins: Vec<u8>) -> Self {
+        self.groups.push(GroupSettings { cycle_time, sample_delay, pins });
+        self
+    }
+
+    /// Override the raw DMA transfer rate (control blocks per second) used to check the configuration.
+    ///
+    /// By default a conservative value for the detected Pi model is used.
+    ///
+    /// ## Example
+    /// ```no_run
+    /// use dma_gpio::pi::BoardBuilder;
+    ///
+    /// fn main() {
+    ///     let mut board = BoardBuilder::new()
+    ///         .assume_dma_throughput(1_400_000)
+    ///         .build().unwrap();
+    ///     board.set_pwm(21, 0.5).unwrap();
+    /// }
+    /// ```
+    pub fn assume_dma_throughput(mut self, hz: usize) -> Self {
+        self.dma_throughput = Some(hz.max(1));
+        self
+    }
+
+    /// Build a Board backed by zeroed memory instead of the mailbox and the peripherals in /dev/mem.
+    ///
+    /// A dry-run Board keeps the same state, sample memory and control blocks as a real one, so everything
+    /// can be inspected with [dump_samples_structured](struct.Board.html#method.dump_samples_structured) and
+    /// [verify_cb_chain](struct.Board.html#method.verify_cb_chain), and the registers read back with
+    /// [read_register](struct.Board.html#method.read_register), but no device files are opened and root is not needed.
+    /// It reports itself as a Pi 3, unless given another revision with [dry_run_revision](struct.BoardBuilder.html#method.dry_run_revision).
+    ///
+    /// ## Example
+    /// ```no_run
+    /// use dma_gpio::pi::BoardBuilder;
+    ///
+    /// fn main() {
+    ///     let mut board = BoardBuilder::new().dry_run(true).build_with_pins(vec![21]).unwrap();
+    ///     board.set_pwm(21, 0.5).unwrap();
+    ///     print!("{}", board.dump_samples_structured().waveform());
+    /// }
+    /// ```
+    pub fn dry_run(mut self, dry_run: bool) -> Self {
+        self.dry_run = dry_run;
+        self
+    }
+
+    /// Board revision a dry-run Board reports in place of the mailbox's, e.g. 0xc03111 for a Pi 4 B,
+    /// to see how the settings come out on that model.
+    pub fn dry_run_revision(mut self, revision: usize) -> Self {
+        self.dry_run_revision = revision;
+        self
+    }
+
+    /// With strict off, [build](struct.BoardBuilder.html#method.build) keeps a divisor, cycle time and sample delay
+    /// clamped into range and a cycle time that is not a multiple of the sample delay rounded down to one, as it used to,
+    /// instead of failing. What was clamped is in [Board::adjusted_settings](struct.Board.html#method.adjusted_settings).
+    /// Strict is on by default.
+    ///
+    /// ## Example
+    /// ```no_run
+    /// use dma_gpio::pi::BoardBuilder;
+    ///
+    /// fn main() {
+    ///     // runs with a divisor of 1000
+    ///     let board = BoardBuilder::new().strict(false).divide_pwm(1250).build_with_pins(vec![21]).unwrap();
+    ///     println!("{:?}", board.adjusted_settings());
+    /// }
+    /// ```
+    pub fn strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+
+    /// Fail [build](struct.BoardBuilder.html#method.build) instead of warning when the DMA cannot sustain the requested sample rate.
+    pub fn reject_infeasible_dma(mut self) -> Self {
+        self.reject_infeasible_dma = true;
+        self
+    }
+
+    /// Use DMA channel ch instead of the default, 14 (7 on a Pi 4), e.g. when another driver already uses it.
+    ///
+    /// The channel must be between 0 and 14 (0 and 10 on a Pi 4) and one the firmware reports as free for the ARM;
+    /// otherwise [build](struct.BoardBuilder.html#method.build) fails with an error listing the channels that are.
+    ///
+    /// Without it, a Board built while another Board of this process holds the default channel takes the next free
+    /// one below it, then above it. A channel given here that another Board holds fails with
+    /// [BuildError::DmaChannelInUse](enum.BuildError.html#variant.DmaChannelInUse).
+    ///
+    /// ## Example
+    /// ```no_run
+    /// use dma_gpio::pi::BoardBuilder;
+    ///
+    /// fn main() {
+    ///     let mut board = BoardBuilder::new().use_dma_channel(10).build_with_pins(vec![21]).unwrap();
+    ///     board.set_pwm(21, 0.5).unwrap();
+    /// }
+    /// ```
+    pub fn use_dma_channel(mut self, ch: usize) -> Self {
+        self.dma_channel = Some(ch);
+        self
+    }
+
+    /// Pause the DMA once every channel has been at 0.0 (or released) for [DEFAULT_AUTO_IDLE_TIME](constant.DEFAULT_AUTO_IDLE_TIME.html).
+    ///
+    /// Pins are driven to their idle level through GPIO before the DMA is paused,
+    /// and the DMA resumes on the next nonzero [Board::set_pwm](struct.Board.html#method.set_pwm).
+    ///
+    /// The board has no thread of its own: the time at zero is checked when widths are set and on
+    /// [Board::poll_idle](struct.Board.html#method.poll_idle), which callers that stop setting
+    /// widths once they are at 0.0 should call periodically.
+    ///
+    /// ## Example
+    /// ```no_run
+    /// use std::thread::sleep;
+    /// use std::time::Duration;
+    /// use dma_gpio::pi::BoardBuilder;
+    ///
+    /// fn main() {
+    ///     let mut board = BoardBuilder::new().auto_idle().build().unwrap();
+    ///     board.set_pwm(21, 0.5).unwrap();
+    ///     board.set_pwm(21, 0.0).unwrap();
+    ///     // Nothing sets a width from here on, so the time at 0.0 is checked here
+    ///     loop {
+    ///         board.poll_idle();
+    ///         sleep(Duration::from_millis(100));
+    ///     }
+    /// }
+    /// ```
+    pub fn auto_idle(mut self) -> Self {
+        self.auto_idle_time = Some(DEFAULT_AUTO_IDLE_TIME);
+        self
+    }
+
+    /// Enable auto-idle with a custom time the channels must stay at 0.0; see [auto_idle](#method.auto_idle).
+    pub fn set_auto_idle_time(mut self, time: Duration) -> Self {
+        self.auto_idle_time = Some(time);
+        self
+    }
+
+    /// Lets pins play repeating bit patterns with [Board::set_waveform](struct.Board.html#method.set_waveform)
+    /// next to the PWM pins.
+    ///
+    /// Every sample then has a set mask as well as a clear mask, so the DMA makes one more copy per sample
+    /// and the sample rate it can keep up with drops by a third; see [DmaFeasibility](struct.DmaFeasibility.html).
+    ///
+    /// ## Example
+    /// ```no_run
+    /// use dma_gpio::pi::BoardBuilder;
+    ///
+    /// fn main() {
+    ///     let mut board = BoardBuilder::new().set_cycle_time(200).set_sample_delay(20).waveforms().build_with_pins(vec![20, 21]).unwrap();
+    ///     board.set_pwm(20, 0.5).unwrap();
+    ///     board.set_waveform(21, &[true, false, true, false, true, true, false, false, false, false]).unwrap();
+    /// }
+    /// ```
+    pub fn waveforms(mut self) -> Self {
+        self.waveforms = true;
+        self
+    }
+}
+
+/// Struct for dealing with GPIO Pins.
+/// 
+/// Board is initialized through [BoardBuilder](struct.BoardBuilder.html).
+/// 
+/// Note that you can only manipulate pins that are set from BoardBuilder,
+/// 
+/// so if the pin you want to access is not one of the default pins: [4, 17, 18, 27, 21, 22, 23, 24, 25], make sure to set it with [BoardBuilder::build_with_pins](struct.BoardBuilder.html#method.build_with_pins).
+/// 
+/// ## Example
+/// This example uses pins [21, 22, 23],
+/// 
+/// sets pin 21 to 25%, pin 22 to 50%, and pin 23 to 75%,
+/// 
+/// then, after 1 second, releases pin 22,
+/// 
+/// then, after 1 second, release all pins.
+/// 
+/// ```no_run
+/// use std::thread::sleep;
+/// use std::time::Duration;
+/// use dma_gpio::pi::BoardBuilder;
+/// 
+/// fn main() {
+///     let mut board = BoardBuilder::new().build_with_pins(vec![21, 22, 23]).unwrap();
+///     board.print_info();
+///     
+///     board.set_pwm(21, 0.25).unwrap();
+///     board.set_pwm(22, 0.50).unwrap();
+///     board.set_pwm(23, 0.75).unwrap();
+///     
+///     let sec = Duration::from_millis(1000);
+///     sleep(millis);
+///     
+///     board.release_pwm(22).unwrap();
+///     
+///     sleep(millis);
+///     
+///     board.release_all_pwm().unwrap();
 ///     
 /// }
 /// 
 /// ```
 pub struct Board {
     pwm_divisor: usize,
-    cycle_time: usize,
-    sample_delay: usize,
-
-    num_pages: usize,
-    num_samples: usize,
+    // Groups, and the sample delay pacing the DMA
+    layout: CtlLayout,
 
     // pi version specific addresses
     dma_base: usize,
@@ -573,12 +1713,91 @@ pub struct Board {
 
     // pin2gpio array is not setup as empty to avoid locking all GPIO
     // inputs as PWM, they are set on the fly by the pin param passed.
+    // Entries not in use are NO_PIN.
     pin2gpio: [u8;MAX_CHANNELS],
 
     mbox: Mbox,
+    // Allocated for the layout the board was built with; reconfigure_timing must fit in them
+    mbox_pages: usize,
     delay_hw: u8,
+    // Groups as added with add_group, for laying them out again with new timing
+    group_settings: Vec<GroupSettings>,
+
+    // Indexed by gpio like pulse_constraints
+    inverted: [bool; MAX_CHANNELS],
+    // Patterns of pins set with set_waveform, by gpio; None for PWM pins
+    waveforms: Vec<Option<Vec<bool>>>,
+
+    dma_feasibility: DmaFeasibility,
+    // Transfers per second assumed for dma_feasibility, and whether falling short of them is an error
+    dma_throughput: usize,
+    reject_infeasible_dma: bool,
+
+    auto_idle_time: Option<Duration>,
+    adjusted_settings: Vec<AdjustedSetting>,
+    dma_channel: usize,
+    direction_bindings: Vec<DirectionBinding>,
+    // Sign each PWM gpio's direction pins are currently set for, indexed by gpio
+    directions: [i8; MAX_CHANNELS],
+    // Indexed by gpio, so they stay with the pin when its channel is released
+    pulse_constraints: [PulseConstraint; MAX_CHANNELS],
+    zero_since: Option<Instant>,
+    idle: bool,
+    // Set by pause(), DMA stays reset until resume()
+    paused: bool,
+    #[cfg(feature = "stats")]
+    idle_stats: IdleStats,
+
+    board_revision: usize,
+    // As detected; the revision alone may not tell
+    model: usize,
+    // Hz of the clock pwm_divisor divides
+    plld_freq: usize,
+    // Integer divisor the pacing clock's DIV register held once programmed
+    clock_divisor: usize,
+
+    // Some for dry-run boards; freed after terminate when the board is dropped
+    fake_memory: Option<FakeMemory>,
+    // Released after terminate, when the board is dropped
+    claims: Claims,
+}
+
+// Zeroed, page aligned blocks standing in for the peripherals and the mailbox memory of a dry-run board.
+struct FakeMemory {
+    blocks: Vec<(*mut u8, Layout)>,
+}
+
+impl FakeMemory {
+    fn alloc(&mut self, len: usize) -> *mut c_void {
+        let layout = Layout::from_size_align(len, PAGE_SIZE).unwrap();
+        let block = unsafe { alloc::alloc_zeroed(layout) };
+        if block.is_null() {
+            alloc::handle_alloc_error(layout);
+        }
+        self.blocks.push((block, layout));
+        block as *mut c_void
+    }
+
+    // Register blocks are indexed as RW<usize>, which is wider than the 32 bit registers on 64 bit targets.
+    fn alloc_peripheral(&mut self, len: usize) -> *mut c_void {
+        self.alloc(len.max(len / 4 * size_of::<usize>()))
+    }
+}
+
+impl Drop for FakeMemory {
+    fn drop(&mut self) {
+        for (block, layout) in self.blocks.drain(..) {
+            unsafe { alloc::dealloc(block, layout) };
+        }
+    }
+}
 
-    invert_mode: bool,
+/// Only accessable with "stats" feature. Counts of auto-idle transitions.
+#[cfg(feature = "stats")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct IdleStats {
+    pub entries: usize,
+    pub exits: usize,
 }
 
 impl Drop for Board {
@@ -592,11 +1811,11 @@ impl Board {
     fn mbox_open() -> Result<i32, Error> {
         // try to use /dev/vcio first (kernel 4.1+)
         let dev_vcio =  CString::new(DEVFILE_VCIO).unwrap().into_bytes_with_nul();
-        match unsafe { libc::open(dev_vcio.as_ptr() as *const u8, 0) }{
+        match unsafe { libc::open(dev_vcio.as_ptr() as *const libc::c_char, 0) }{
             fd if fd < 0 => {
                 // initialize mbox
                 let dev_mbox =  CString::new(DEVFILE_MBOX).unwrap().into_bytes_with_nul();
-                let mbox_ptr = dev_mbox.as_ptr();
+                let mbox_ptr = dev_mbox.as_ptr() as *const libc::c_char;
                 match fs::remove_file(DEVFILE_MBOX){
                     Ok(_) => (),
                     Err(e) => return Err(e),
@@ -627,52 +1846,128 @@ impl Board {
         }
     }
 
-    // determine which pi model we're running on
-    fn get_model(mbox_board_rev: usize) -> Result<(usize, usize, usize), Error> {
-
-        let board_model = if (mbox_board_rev & BOARD_REVISION_SCHEME_MASK) == BOARD_REVISION_SCHEME_NEW {
+    // 1 to 4; 5 for a Pi 5, which is not supported; 0 when the revision does not tell.
+    fn board_model(mbox_board_rev: usize) -> usize {
+        if (mbox_board_rev & BOARD_REVISION_SCHEME_MASK) == BOARD_REVISION_SCHEME_NEW {
             match mbox_board_rev & BOARD_REVISION_TYPE_MASK {
                 BOARD_REVISION_TYPE_PI2_B => 2,
                 BOARD_REVISION_TYPE_PI3_B | BOARD_REVISION_TYPE_PI3_BP | BOARD_REVISION_TYPE_CM3 => 3,
-                _ => 1,
+                BOARD_REVISION_TYPE_PI4_B | BOARD_REVISION_TYPE_PI400 | BOARD_REVISION_TYPE_CM4 => 4,
+                // Zeros, A+, B+, CMs and whatever comes later go by their processor
+                _ => match mbox_board_rev & BOARD_REVISION_PROCESSOR_MASK {
+                    BOARD_REVISION_PROCESSOR_2835 => 1,
+                    BOARD_REVISION_PROCESSOR_2836 => 2,
+                    BOARD_REVISION_PROCESSOR_2837 => 3,
+                    BOARD_REVISION_PROCESSOR_2711 => 4,
+                    BOARD_REVISION_PROCESSOR_2712 => 5,
+                    _ => 0,
+                }
             }
         }else {
             1
-        };
+        }
+    }
 
-        #[cfg(feature = "debug")]
-        {
-            trace!("This is Pi-{}", board_model);
+    // Bus address of the peripherals in the ARM's physical address space, from the device tree:
+    // the second cell of soc/ranges, or the third where the parent address takes two cells.
+    fn peripheral_base_from_device_tree() -> Option<usize> {
+        let ranges = std::fs::read("/proc/device-tree/soc/ranges").ok()?;
+        let cell = |i: usize| ranges.get(i * 4..i * 4 + 4).map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]) as usize);
+        match cell(1)? {
+            0 => cell(2),
+            base => Some(base),
         }
+    }
 
-        return match board_model {
-            1 => {
-                let periph_virt_base = 0x20000000;
-                let periph_phys_base = 0x7e000000;
-                let mem_flag = mailbox::MEM_FLAG_L1_NONALLOCATING | mailbox::MEM_FLAG_ZERO;
-                Ok((periph_virt_base, periph_phys_base, mem_flag))
+    fn model_settings(model: usize, periph_virt_base: usize) -> ModelSettings {
+        match model {
+            1 => ModelSettings {
+                model,
+                periph_virt_base,
+                periph_phys_base: 0x7e000000,
+                // 0x4 alias: bypasses L1, the only cache the DMA does not see
+                mem_flag: mailbox::MEM_FLAG_L1_NONALLOCATING | mailbox::MEM_FLAG_ZERO,
+                dma_throughput: PI1_DMA_THROUGHPUT_HZ,
+                plld_freq: PWM_CLOCK_HZ,
+                usable_dma_channels: 0x7fff,
+                default_dma_channel: DMA_CHAN_NUM,
             },
-            2 | 3 => {
-                let periph_virt_base = 0x3f000000;
-                let periph_phys_base = 0x7e000000;
-                let mem_flag = mailbox::MEM_FLAG_L1_NONALLOCATING | mailbox::MEM_FLAG_ZERO;
-                Ok((periph_virt_base, periph_phys_base, mem_flag))
+            4 => ModelSettings {
+                model,
+                periph_virt_base,
+                periph_phys_base: 0x7e000000,
+                // 0xC alias as on the Pi 2/3; it covers the first GB, where the mailbox allocates
+                mem_flag: mailbox::MEM_FLAG_DIRECT | mailbox::MEM_FLAG_ZERO,
+                dma_throughput: PI4_DMA_THROUGHPUT_HZ,
+                plld_freq: PI4_PWM_CLOCK_HZ,
+                // 11 to 14 are DMA4 channels with a different register layout; 7 to 10 are DMA lite,
+                // which is all the two control blocks per sample need
+                usable_dma_channels: 0x07ff,
+                default_dma_channel: 7,
+            },
+            _ => ModelSettings {
+                model,
+                periph_virt_base,
+                periph_phys_base: 0x7e000000,
+                // 0xC alias: the ARM's L2 is not shared with the VideoCore here, so bypass all caches
+                mem_flag: mailbox::MEM_FLAG_DIRECT | mailbox::MEM_FLAG_ZERO,
+                dma_throughput: if model == 2 { PI2_DMA_THROUGHPUT_HZ } else { PI3_DMA_THROUGHPUT_HZ },
+                plld_freq: PWM_CLOCK_HZ,
+                usable_dma_channels: 0x7fff,
+                default_dma_channel: DMA_CHAN_NUM,
+            },
+        }
+    }
+
+    // determine which pi model we're running on
+    fn get_model(mbox_board_rev: usize, dry_run: bool) -> Result<ModelSettings, Error> {
+
+        let board_model = Board::board_model(mbox_board_rev);
+
+        trace!("This is Pi-{}", board_model);
+
+        return match board_model {
+            1 => Ok(Board::model_settings(1, 0x20000000)),
+            2 | 3 => Ok(Board::model_settings(board_model, 0x3f000000)),
+            4 => Ok(Board::model_settings(4, 0xfe000000)),
+            5 => {
+                Err(Error::new(ErrorKind::Other, format!("Pi 5 (board revision {:#x}) is not supported: its GPIO is behind RP1", mbox_board_rev)))
             },
             _ => {
-                Err(Error::new(ErrorKind::Other, format!("Unable to detect Board Model from board revision: {:?}", mbox_board_rev)))
+                // A board newer than this crate; its peripherals may still be where one of the known ones has them
+                let base = if dry_run { None } else { Board::peripheral_base_from_device_tree() };
+                match base {
+                    Some(base) => {
+                        let model = match base {
+                            0x20000000 => 1,
+                            0xfe000000 => 4,
+                            _ => 3,
+                        };
+                        warn!("Unknown board revision {:#x}; peripherals at {:#010x} per the device tree, treating it as a Pi {}", mbox_board_rev, base, model);
+                        Ok(Board::model_settings(model, base))
+                    },
+                    None => Err(Error::new(ErrorKind::Other, format!("Unable to detect Board Model from board revision: {:?}", mbox_board_rev)))
+                }
             },
         }
     }
 
+    fn map_peripheral_or_fake(fake_memory: &mut Option<FakeMemory>, base: usize, len: usize) -> Result<*mut c_void, Error> {
+        match fake_memory {
+            Some(fake_memory) => Ok(fake_memory.alloc_peripheral(len)),
+            None => Board::map_peripheral(base, len)
+        }
+    }
+
     fn map_peripheral(base: usize, len: usize) -> Result<*mut c_void, Error> {
         let dev_mem =  CString::new("/dev/mem").unwrap().into_bytes_with_nul();
         let dmem_ptr = dev_mem.as_ptr();
-        match unsafe { libc::open(dmem_ptr as *const u8, libc::O_RDWR | libc::O_SYNC)}{
+        match unsafe { libc::open(dmem_ptr as *const libc::c_char, libc::O_RDWR | libc::O_SYNC)}{
             fd if fd < 0 => {
                 let error = format!("dma_gpio: failed to open /dev/mem.");
                 Err(Error::new(ErrorKind::Other, error))
             },
-#[cfg(target_arch = "aarch64")]
+#[cfg(any(target_arch = "aarch64", target_arch = "x86_64"))]
             fd => match unsafe{ libc::mmap(ptr::null_mut(), len, libc::PROT_READ | libc::PROT_WRITE, libc::MAP_SHARED, fd, base as i64) } {
                 libc::MAP_FAILED => {
                     let error = format!("pi_gpio: Failed to map peripheral at {:#010x}.", base);
@@ -697,41 +1992,86 @@ impl Board {
         }
     }
 
-    fn new(delay_hw: u8, known_pins: [u8;MAX_CHANNELS], num_channels: usize, pwm_divisor: usize, cycle_time: usize, sample_delay: usize) -> Result<Self, Error> {
-        let mut mbox_handle: i32 = match Board::mbox_open(){
-            Ok(fd) => fd,
+    // The builder's settings, but for the divisor build settled on and the settings it adjusted.
+    fn new(builder: &BoardBuilder, pwm_divisor: usize, adjusted_settings: Vec<AdjustedSetting>) -> Result<Self, BuildError> {
+        let BoardBuilder { delay_hw, known_pins, num_channels, cycle_time, sample_delay, ref groups, dma_throughput, reject_infeasible_dma,
+                           auto_idle_time, dma_channel, waveforms, dry_run, dry_run_revision, .. } = *builder;
+        let layout = match CtlLayout::new(&known_pins, num_channels, cycle_time, sample_delay, groups, waveforms) {
+            Ok(layout) => layout,
             Err(e) => {
+                error!("{}", e);
                 return Err(e)
             }
         };
-        #[cfg(feature = "debug")]
-        {
-            trace!("mbox_handle: {:?}", mbox_handle);
+        let num_pages = layout.num_pages;
+
+        if delay_hw == DELAY_VIA_PCM && pcm_pacing(pwm_divisor, layout.sample_delay).is_none() {
+            let error = BuildError::PcmPacing { divisor: pwm_divisor, sample_delay: layout.sample_delay };
+            error!("{}", error);
+            return Err(error)
         }
 
-        let mbox_board_rev = match mailbox::get_board_revision(mbox_handle){
-            Ok(rev) => rev,
+        let mut claims = match Claims::pacing(delay_hw) {
+            Ok(claims) => claims,
             Err(e) => {
-                return Err(Error::new(ErrorKind::Other, format!("could not get board revision: {:?}", e)))
+                error!("{}", e);
+                return Err(e)
             }
         };
-        #[cfg(feature = "debug")]
-        {
-            trace!("MBox Board Revision: {:#010x}", mbox_board_rev);
-        }
 
+        let mut fake_memory = if dry_run { Some(FakeMemory { blocks: vec![] }) } else { None };
+
+        let mut mbox_handle: i32 = if dry_run { -1 } else {
+            match Board::mbox_open(){
+                Ok(fd) => fd,
+                Err(e) => {
+                    return Err(e.into())
+                }
+            }
+        };
+        trace!("mbox_handle: {:?}", mbox_handle);
 
-        let num_samples = cycle_time as usize/sample_delay;
+        let mbox_board_rev = if dry_run { dry_run_revision } else {
+            match mailbox::get_board_revision(mbox_handle){
+                Ok(rev) => rev as usize,
+                Err(e) => {
+                    return Err(Error::new(ErrorKind::Other, format!("could not get board revision: {:?}", e)).into())
+                }
+            }
+        };
+        trace!("MBox Board Revision: {:#010x}", mbox_board_rev);
 
-        let num_pages: usize = (NUM_CBS * size_of::<DmaCbT>() as usize + NUM_SAMPLES * 4 + PAGE_SIZE - 1)>>PAGE_SHIFT;
 
-        let (periph_virt_base, periph_phys_base, mem_flag) = match Board::get_model(mbox_board_rev){
+        let model_settings = match Board::get_model(mbox_board_rev, dry_run){
             Ok(res) => res,
             Err(e) => {
                 let error = format!("could not get the pi model: {:?}", e);
-                return Err(Error::new(ErrorKind::Other, error))
+                if !dry_run {
+                    let _ = Board::mbox_close(mbox_handle);
+                }
+                return Err(Error::new(ErrorKind::Other, error).into())
             }
         };
+        let periph_virt_base = model_settings.periph_virt_base;
+        let periph_phys_base = model_settings.periph_phys_base;
+        let mem_flag = model_settings.mem_flag;
+        let plld_freq = model_settings.plld_freq;
+
+        // Waveform samples take a set copy on top of the clear copy and the pacing write
+        let dma_throughput = dma_throughput.unwrap_or(model_settings.dma_throughput);
+        let dma_throughput = if waveforms { dma_throughput * DMA_TRANSFERS_PER_SAMPLE / (DMA_TRANSFERS_PER_SAMPLE + 1) } else { dma_throughput };
+        let dma_feasibility = DmaFeasibility::with_clock(plld_freq, pwm_divisor, layout.sample_delay, dma_throughput);
+        if !dma_feasibility.is_achievable() {
+            let error = BuildError::InfeasibleDma(dma_feasibility);
+            if reject_infeasible_dma {
+                error!("{}", error);
+                if !dry_run {
+                    let _ = Board::mbox_close(mbox_handle);
+                }
+                return Err(error)
+            }
+            warn!("{}", error);
+        }
 
         let dma_base = 0x00007000 + periph_virt_base;
 
@@ -747,119 +2087,137 @@ impl Board {
         let pcm_phys_base: usize = PCM_BASE_OFFSET + periph_phys_base;
         
 
-        #[cfg(feature = "debug")]
-        {
+        let dma_channels = if dry_run {
+            if model_settings.model == 4 { DRY_RUN_PI4_DMA_CHANNELS } else { DRY_RUN_DMA_CHANNELS }
+        } else {
             match mailbox::get_dma_channels(mbox_handle){
-                Ok(channels) => {
-                    trace!("DMA Channels Info: {:#010x}, using DMA Channel: {}\n", channels, DMA_CHAN_NUM);
-                },
-                Err(e) => return Err(e)
-            };
-        }
+                Ok(channels) => channels as usize,
+                Err(e) => {
+                    let _ = Board::mbox_close(mbox_handle);
+                    return Err(e.into())
+                }
+            }
+        };
+        let dma_channel = match claims.dma_channel(dma_channel, model_settings.default_dma_channel, dma_channels & model_settings.usable_dma_channels) {
+            Ok(ch) => ch,
+            Err(e) => {
+                error!("{}", e);
+                if !dry_run {
+                    let _ = Board::mbox_close(mbox_handle);
+                }
+                return Err(e)
+            }
+        };
+        trace!("DMA Channels Info: {:#010x}, using DMA Channel: {}\n", dma_channels, dma_channel);
 
         /* map the registers for all DMA Channels */
-        let _dma_virt_base = match Board::map_peripheral(dma_base, DMA_CHAN_SIZE * (DMA_CHAN_MAX + 1)){
+        let _dma_virt_base = match Board::map_peripheral_or_fake(&mut fake_memory, dma_base, DMA_CHAN_SIZE * (DMA_CHAN_MAX + 1)){
             Ok(ptr) => ptr as *const [RW<usize>;DMA_CHAN_SIZE/4],
-            Err(e) => return Err(e)
+            Err(e) => return Err(e.into())
         };
-        #[cfg(feature = "debug")]
-        {
-            trace!("dma_virt_base: {:?}", _dma_virt_base);
-        }
+        trace!("dma_virt_base: {:?}", _dma_virt_base);
 
         /* set dma_reg to point to the DMA Channel we are using */
-        let dma_reg = (_dma_virt_base as usize + DMA_CHAN_NUM * DMA_CHAN_SIZE) as *const [RW<usize>;DMA_CHAN_SIZE/4];
-        #[cfg(feature = "debug")]
-        {
-            trace!("dma_reg_ptr: {:?}", dma_reg);
+        let dma_reg = (_dma_virt_base as usize + dma_channel * DMA_CHAN_SIZE) as *const [RW<usize>;DMA_CHAN_SIZE/4];
+        trace!("dma_reg_ptr: {:?}", dma_reg);
+
+        // A process killed without cleaning up leaves the channel running through control blocks that
+        // are no longer its; stop it before the new memory, which may be the same, is allocated.
+        if !dry_run {
+            match reset_dma_registers(dma_reg) {
+                Ok(was_active) => {
+                    if was_active {
+                        warn!("DMA channel {} was still active, probably left running by a previous process; reset it", dma_channel);
+                    }
+                },
+                Err(e) => {
+                    error!("{}", e);
+                    let _ = Board::mbox_close(mbox_handle);
+                    return Err(e.into())
+                }
+            }
         }
 
         // let dma_reg = unsafe{ *dma_reg_ptr };
-        let pwm_reg = match Board::map_peripheral(_pwm_base, PWM_LEN){
+        let pwm_reg = match Board::map_peripheral_or_fake(&mut fake_memory, _pwm_base, PWM_LEN){
             Ok(ptr) => ptr as *const [RW<usize>;PWM_LEN/4],
-            Err(e) => return Err(e)
+            Err(e) => return Err(e.into())
         };
-        #[cfg(feature = "debug")]
-        {
-            trace!("pwm_reg: {:?}", pwm_reg);
-        }
+        trace!("pwm_reg: {:?}", pwm_reg);
 
-        let pcm_reg = match Board::map_peripheral(_pcm_base, PCM_LEN){
+        let pcm_reg = match Board::map_peripheral_or_fake(&mut fake_memory, _pcm_base, PCM_LEN){
             Ok(ptr) => ptr as *const [RW<usize>;PCM_LEN/4],
-            Err(e) => return Err(e)
+            Err(e) => return Err(e.into())
         };
-        #[cfg(feature = "debug")]
-        {
-            trace!("pcm_reg: {:?}", pcm_reg);
-        }
+        trace!("pcm_reg: {:?}", pcm_reg);
 
-        let clk_reg = match Board::map_peripheral(_clk_base, CLK_LEN){
+        let clk_reg = match Board::map_peripheral_or_fake(&mut fake_memory, _clk_base, CLK_LEN){
             Ok(ptr) => ptr as *const [RW<usize>;CLK_LEN/4],
-            Err(e) => return Err(e)
+            Err(e) => return Err(e.into())
         };
-        #[cfg(feature = "debug")]
-        {
-            trace!("clk_reg: {:?}", clk_reg);
-        }
+        trace!("clk_reg: {:?}", clk_reg);
 
-        let gpio_reg = match Board::map_peripheral(_gpio_base, GPIO_LEN){
+        let gpio_reg = match Board::map_peripheral_or_fake(&mut fake_memory, _gpio_base, GPIO_LEN){
             Ok(ptr) => ptr as *const [RW<usize>;GPIO_LEN/4],
-            Err(e) => return Err(e)
-        };
-        #[cfg(feature = "debug")]
-        {
-            trace!("gpio_reg: {:?}", gpio_reg);
-        }
-
-        /* Use the mailbox interface to the VC to ask for physical memory */
-        let mbox_mem_ref = match mailbox::mem_alloc(mbox_handle, num_pages * PAGE_SIZE, PAGE_SIZE, mem_flag) {
-            Ok(ret) => ret,
-            Err(e) => return Err(e)
+            Err(e) => return Err(e.into())
         };
-        // TODO: How do we know that succeeded?
-        #[cfg(feature = "debug")]
-        {
-            trace!("mem_ref: {:#010x}", mbox_mem_ref);
-        }
+        trace!("gpio_reg: {:?}", gpio_reg);
+
+        let (mbox_mem_ref, mbox_bus_addr, mbox_virt_addr) = match &mut fake_memory {
+            Some(fake_memory) => (0, DRY_RUN_BUS_ADDR, fake_memory.alloc(num_pages * PAGE_SIZE) as usize),
+            None => {
+                /* Use the mailbox interface to the VC to ask for physical memory */
+                let mbox_mem_ref = match mailbox::mem_alloc(mbox_handle, (num_pages * PAGE_SIZE) as u32, PAGE_SIZE as u32, mem_flag) {
+                    Ok(ret) => ret,
+                    Err(e) => return Err(e.into())
+                };
+                // TODO: How do we know that succeeded?
+                trace!("mem_ref: {:#010x}", mbox_mem_ref);
+
+                // Bus addresses are 32 bit; usize from here on, as the DMA code works in it
+                let mbox_bus_addr = match mailbox::mem_lock(mbox_handle, mbox_mem_ref) {
+                    Ok(ret) => ret as usize,
+                    Err(e) => return Err(e.into())
+                };
+                trace!("bus_addr: {:#010x}", mbox_bus_addr);
+
+                if let Err(e) = check_mailbox_memory(mbox_bus_addr, num_pages * PAGE_SIZE, ram_size(mbox_board_rev)) {
+                    error!("Unusable mailbox memory: {}", e);
+                    let _ = mailbox::mem_unlock(mbox_handle, mbox_mem_ref);
+                    let _ = mailbox::mem_free(mbox_handle, mbox_mem_ref);
+                    let _ = Board::mbox_close(mbox_handle);
+                    return Err(Error::new(ErrorKind::Other, format!("unusable mailbox memory: {}; try a larger gpu_mem in /boot/config.txt", e)).into())
+                }
+                if bus_alias(mbox_bus_addr) != expected_bus_alias(mem_flag) {
+                    warn!("Mailbox memory at {:#010x} is not in the expected {:#010x} alias; using its own", mbox_bus_addr, expected_bus_alias(mem_flag));
+                }
 
-        let mbox_bus_addr = match mailbox::mem_lock(mbox_handle, mbox_mem_ref) {
-            Ok(ret) => ret,
-            Err(e) => return Err(e)
-        };
-        #[cfg(feature = "debug")]
-        {
-            trace!("bus_addr: {:#010x}", mbox_bus_addr);
-        }
+                let mbox_virt_addr = match mailbox::mapmem(BUS_TO_PHYS(mbox_bus_addr), num_pages * PAGE_SIZE){
+                    Ok(ret) => ret,
+                    Err(e) => return Err(e.into())
+                };
+                trace!("virt_addr: {:#010x}\n", mbox_virt_addr);
 
-        let mbox_virt_addr = match mailbox::mapmem(BUS_TO_PHYS(mbox_bus_addr), num_pages * PAGE_SIZE){
-            Ok(ret) => ret,
-            Err(e) => return Err(e)
-        };
-        #[cfg(feature = "debug")]
-        {
-            trace!("virt_addr: {:#010x}\n", mbox_virt_addr);
-        }
+                if (mbox_virt_addr & (PAGE_SIZE - 1)) > 0 {
+                    return Err(Error::new(ErrorKind::Other, "pi-gpio: Virtual address is not page aligned.").into())
+                }
 
-        if (mbox_virt_addr & (PAGE_SIZE - 1)) > 0 {
-            return Err(Error::new(ErrorKind::Other, "pi-gpio: Virtual address is not page aligned."))
-        }
+                // we're done with mbox now
+                match Board::mbox_close(mbox_handle){
+                    Ok(()) => (),
+                    Err(e) => return Err(e.into())
+                }
 
-        // we're done with mbox now
-        match Board::mbox_close(mbox_handle){
-            Ok(()) => (),
-            Err(e) => return Err(e)
-        }
+                (mbox_mem_ref, mbox_bus_addr, mbox_virt_addr)
+            }
+        };
         mbox_handle = -1;
 
         let mbox = Mbox::new(mbox_handle, mbox_mem_ref, mbox_bus_addr, mbox_virt_addr as *mut c_void);
 
         let mut board = Board{
             pwm_divisor,
-            cycle_time,
-            sample_delay,
-
-            num_pages,
-            num_samples,
+            layout,
 
             dma_base,
 
@@ -885,42 +2243,72 @@ impl Board {
 
             known_pins,
             num_channels,
-            pin2gpio: [0; MAX_CHANNELS],
+            pin2gpio: [NO_PIN; MAX_CHANNELS],
             channel_pwm: [0.0; MAX_CHANNELS],
 
             mbox,
+            mbox_pages: num_pages,
 
             delay_hw,
-            invert_mode: false,
+            group_settings: groups.to_vec(),
+            inverted: [false; MAX_CHANNELS],
+            waveforms: vec![None; MAX_CHANNELS],
+
+            dma_feasibility,
+            dma_throughput,
+            reject_infeasible_dma,
+
+            auto_idle_time,
+            adjusted_settings,
+            dma_channel,
+            direction_bindings: vec![],
+            directions: [0; MAX_CHANNELS],
+            pulse_constraints: [PulseConstraint::default(); MAX_CHANNELS],
+            zero_since: None,
+            idle: false,
+            paused: false,
+            #[cfg(feature = "stats")]
+            idle_stats: IdleStats::default(),
+
+            board_revision: mbox_board_rev,
+            model: model_settings.model,
+            plld_freq,
+            clock_divisor: 0,
+
+            fake_memory,
+            claims,
         };
 
         board.init_ctrl_data();
-        board.init_hardware(pwm_divisor, sample_delay);
+        board.init_hardware(pwm_divisor, board.layout.sample_delay)?;
+        board.start_pacing();
         board.init_pwm();
 
         Ok(board)
     }
 
-    fn mem_virt_to_phys(&self, virt: *const usize) -> usize {
-        let offset = virt as usize - self.mbox.virt_addr as usize;
-        offset + self.mbox.bus_addr
+    // Bus address for the DMA in the alias the mailbox allocated in (see get_model for which one is
+    // asked for per model), rather than a fixed one: another alias would see different cache state.
+    // (source: http://en.wikibooks.org/wiki/Aros/Platforms/Arm_Raspberry_Pi_support#Framebuffer )
+    fn virt_to_uncached_phys(&self, virt: *const usize) -> usize {
+        bus_address(self.mbox.bus_addr, virt as usize - self.mbox.virt_addr as usize)
     }
 
-    // bus address of the ram is 0x40000000. With this binary-or, writes to the returned address will bypass the CPU (L1) cache, but not the L2 cache. 0xc0000000 should be the base address if L2 must also be bypassed. However, the DMA engine is aware of L2 cache - just not the L1 cache (source: http://en.wikibooks.org/wiki/Aros/Platforms/Arm_Raspberry_Pi_support#Framebuffer )
-    fn virt_to_uncached_phys(&self, virt: *const usize) -> usize {
-        self.mem_virt_to_phys(virt) | 0x40000000
+    fn samples(&self) -> *const Samples {
+        ptr::slice_from_raw_parts((self.mbox.virt_addr as usize + SAMPLES_OFFSET) as *const RW<usize>, self.layout.sample_words())
+    }
+
+    fn control_blocks(&self) -> *const ControlBlocks {
+        ptr::slice_from_raw_parts((self.mbox.virt_addr as usize + self.layout.cbs_offset) as *const DmaCbT, self.layout.cb_samples.len())
     }
 
     fn init_ctrl_data(&self) {
-        #[cfg(feature = "debug")]
-        {
-            trace!("Initializing DMA...\n");
-        }
+        trace!("Initializing DMA...\n");
 
-        let ctl_ptr = self.mbox.virt_addr as *const Ctl;
+        let samples = self.samples();
+        let cbs = self.control_blocks();
 
         let phys_gpclr0 = self.gpio_phys_base + 0x28;
-        let phys_gpset0 = self.gpio_phys_base + 0x1c;
         let phys_fifo_addr = if self.delay_hw == DELAY_VIA_PWM {
             self.pwm_phys_base + 0x18
         }else {
@@ -928,8 +2316,7 @@ impl Board {
         };
 
         unsafe{
-            let sample_ptr = &((*ctl_ptr).sample) as *const [RW<usize>; NUM_SAMPLES];
-            libc::memset(sample_ptr as *mut c_void, 0, size_of::<[usize;NUM_SAMPLES]>());
+            libc::memset(samples as *mut c_void, 0, self.layout.sample_words() * size_of::<usize>());
         }
 
         // calculate a mask to turn off all the servos
@@ -937,77 +2324,96 @@ impl Board {
         for i in 0..self.num_channels {
             mask |= 1 << self.known_pins[i];
         }
-        #[cfg(feature = "debug")]
-        {
-            trace!("mask: {:#010x}", mask);
-        }
+        trace!("mask: {:#010x}", mask);
         unsafe{
-            for i in 0..self.num_samples {
-                (*ctl_ptr).sample[i].write(mask);
+            for i in 0..self.layout.num_samples {
+                (*samples)[i].write(mask);
             }
         }
 
-        /* Initialize all the DMA commands. They come in pairs.
-        *  - 1st command copies a value from the sample memory to a destination
-        *    address which can be either the gpclr0 register or the gpset0 register
-        *  - 2nd command waits for a trigger from an external source (PWM or PCM)
+        /* Initialize all the DMA commands. Each slot of the ring has
+        *  - a command per group due a new sample, copying a value from the sample memory to
+        *    a destination address which can be either the gpclr0 register or the gpset0 register
+        *  - a command waiting for a trigger from an external source (PWM or PCM)
+        *  With a single group they come in pairs, one pair per sample.
         */
-        let mut j = 0;
         let mut cbp;
         let cb_size = size_of::<DmaCbT>();
         unsafe{
-            for i in 0..self.num_samples {
-                // first DMA command
-                cbp = &(*ctl_ptr).cb[j];
-                cbp.info.write(DMA_NO_WIDE_BURSTS | DMA_WAIT_RESP);
-                cbp.src.write(self.virt_to_uncached_phys((&((*ctl_ptr).sample[i]) as *const RW<usize>) as *const usize));
-                cbp.dst.write(if self.invert_mode {
-                    phys_gpset0
-                }else {
-                    phys_gpclr0
-                });
-                cbp.length.write(4);
-                cbp.stride.write(0);
-                cbp.next.write(self.virt_to_uncached_phys((cbp as *const DmaCbT as usize + cb_size) as *const usize));
-
-                j += 1;
-                cbp = &(*ctl_ptr).cb[j];
-                cbp.info.write(if self.delay_hw == DELAY_VIA_PWM {
-                    DMA_NO_WIDE_BURSTS | DMA_WAIT_RESP | DMA_D_DREQ | DMA_PER_MAP(5)
+            for (j, sample) in self.layout.cb_samples.iter().enumerate() {
+                cbp = &(*cbs)[j];
+                if let Some(i) = *sample {
+                    cbp.info.write((DMA_NO_WIDE_BURSTS | DMA_WAIT_RESP) as u32);
+                    cbp.src.write(self.virt_to_uncached_phys((&(*samples)[i] as *const RW<usize>) as *const usize) as u32);
+                    cbp.dst.write(phys_gpclr0 as u32);
                 }else {
-                    DMA_NO_WIDE_BURSTS | DMA_WAIT_RESP | DMA_D_DREQ | DMA_PER_MAP(2)
-                });
-                cbp.src.write(self.virt_to_uncached_phys(ctl_ptr as *const usize)); // any data will do
-                cbp.dst.write(phys_fifo_addr);
+                    cbp.info.write(if self.delay_hw == DELAY_VIA_PWM {
+                        DMA_NO_WIDE_BURSTS | DMA_WAIT_RESP | DMA_D_DREQ | DMA_PER_MAP(5)
+                    }else {
+                        DMA_NO_WIDE_BURSTS | DMA_WAIT_RESP | DMA_D_DREQ | DMA_PER_MAP(2)
+                    } as u32);
+                    cbp.src.write(self.virt_to_uncached_phys(samples as *const usize) as u32); // any data will do
+                    cbp.dst.write(phys_fifo_addr as u32);
+                }
                 cbp.length.write(4);
                 cbp.stride.write(0);
-                cbp.next.write(self.virt_to_uncached_phys((cbp as *const DmaCbT as usize + cb_size) as *const usize));
-
-                j += 1;
+                cbp.next.write(self.virt_to_uncached_phys((cbp as *const DmaCbT as usize + cb_size) as *const usize) as u32);
             }
-            (*ctl_ptr).cb[j - 1].next.write(self.virt_to_uncached_phys(&(*ctl_ptr).cb as *const DmaCbT as *const usize));
+            (*cbs)[self.layout.cb_samples.len() - 1].next.write(self.virt_to_uncached_phys(cbs as *const usize) as u32);
         }
+        dma_barrier();
     }
 
-    fn init_hardware(&self, pwm_divisor: usize, sample_delay: usize) {
-        #[cfg(feature = "debug")]
-        {
-            trace!("Initializing PWM/PCM HW...\n");
+    // Leaves a clock already running from PLLD at divisor alone: stopping it to program it
+    // again would glitch whatever else paces from it. PWM and PCM have a clock each and terminate
+    // stops neither, so dropping one Board never stops the clock of another.
+    //
+    // Otherwise follows the clock manager's sequence: the divider must not change while the clock
+    // is busy, so it is disabled (killed if it will not stop) and only programmed once BUSY is
+    // clear, then enabled. Returns the divisor DIV reads back.
+    fn init_clock(&self, cntl: usize, div: usize, divisor: usize) -> Result<usize, Error> {
+        unsafe {
+            let running = (*self.clk_reg)[cntl].read() & (CLK_CTL_ENAB | 0xF) == CLK_CTL_ENAB | CLK_CTL_SRC_PLLD;
+            if running && ((*self.clk_reg)[div].read() >> CLK_DIV_DIVI_SHIFT) & CLK_DIV_DIVI_MASK == divisor {
+                return Ok(divisor)
+            }
+            (*self.clk_reg)[cntl].write(CLK_PASSWD | CLK_CTL_SRC_PLLD);
+            if self.wait_clock_idle(cntl).is_err() {
+                warn!("Clock {} still busy after disabling it, killing it", cntl);
+                (*self.clk_reg)[cntl].write(CLK_PASSWD | CLK_CTL_KILL | CLK_CTL_SRC_PLLD);
+                self.wait_clock_idle(cntl)?;
+                (*self.clk_reg)[cntl].write(CLK_PASSWD | CLK_CTL_SRC_PLLD);
+            }
+            (*self.clk_reg)[div].write(CLK_PASSWD | (divisor << CLK_DIV_DIVI_SHIFT));
+            udelay(10);
+            (*self.clk_reg)[cntl].write(CLK_PASSWD | CLK_CTL_SRC_PLLD | CLK_CTL_ENAB);
+            udelay(10);
+            Ok(((*self.clk_reg)[div].read() >> CLK_DIV_DIVI_SHIFT) & CLK_DIV_DIVI_MASK)
+        }
+    }
+
+    fn wait_clock_idle(&self, cntl: usize) -> Result<(), Error> {
+        let started = Instant::now();
+        unsafe {
+            while (*self.clk_reg)[cntl].read() & CLK_CTL_BUSY != 0 {
+                if started.elapsed() > CLK_BUSY_TIMEOUT {
+                    return Err(Error::new(ErrorKind::TimedOut, format!("Clock {} still busy {:?} after stopping it; its divider was left alone", cntl, CLK_BUSY_TIMEOUT)))
+                }
+                udelay(1);
+            }
         }
+        Ok(())
+    }
 
-        let ctl_ptr = self.mbox.virt_addr as *mut Ctl;
+    fn init_hardware(&mut self, pwm_divisor: usize, sample_delay: usize) -> Result<(), Error> {
+        trace!("Initializing PWM/PCM HW...\n");
 
         unsafe {
             if self.delay_hw == DELAY_VIA_PWM {
                 // Initialize PWM
                 (*self.pwm_reg)[PWM_CTL].write(0);
                 udelay(10);
-                (*self.clk_reg)[PWMCLK_CNTL].write(0x5A000006); // Source=PLLD (500 MHz)
-                udelay(100);
-                (*self.clk_reg)[PWMCLK_DIV].write(0x5A000000 | (pwm_divisor << 12)); // set pwm div to 500, giving 1MHz
-                udelay(100);
-                (*self.clk_reg)[PWMCLK_CNTL].write(0x5A000016); // Source = PLLD and enable
-                udelay(100);
+                self.clock_divisor = self.init_clock(PWMCLK_CNTL, PWMCLK_DIV, pwm_divisor)?;
                 (*self.pwm_reg)[PWM_RNG1].write(sample_delay as usize);
                 udelay(10);
                 (*self.pwm_reg)[PWM_DMAC].write((PWMDMAC_ENAB | PWMDMAC_THRSHLD) as usize);
@@ -1020,15 +2426,12 @@ impl Board {
                 // Initialize PCM
                 (*self.pcm_reg)[PCM_CS_A].write(1); // Disable Rx+Tx, Enable PCM block
                 udelay(100);
-                (*self.clk_reg)[PCMCLK_CNTL].write(0x5A000006); // Source=PLLD (500 MHz)
-                udelay(100);
-                (*self.clk_reg)[PCMCLK_DIV].write(0x5A000000 | (pwm_divisor << 12)); // set pcm div to 500, giving 1MHz
-                udelay(100);
-                (*self.clk_reg)[PCMCLK_CNTL].write(0x5A000016); // Source = PLLD and enable
-                udelay(100);
+                // Checked by Board::new
+                let (pcm_divisor, frame_len) = pcm_pacing(pwm_divisor, sample_delay).unwrap();
+                self.clock_divisor = self.init_clock(PCMCLK_CNTL, PCMCLK_DIV, pcm_divisor)?;
                 (*self.pcm_reg)[PCM_TXC_A].write(0<<31 | 1<<30 | 0<<20 | 0<<16); // 1 channel, 8 bits
                 udelay(100);
-                (*self.pcm_reg)[PCM_MODE_A].write((sample_delay - 1) << 10);
+                (*self.pcm_reg)[PCM_MODE_A].write((frame_len - 1) << 10);
                 udelay(100);
                 (*self.pcm_reg)[PCM_CS_A].modify(|val| val | 1<<4 | 1<<3); // Clear FIFOs
                 udelay(100);
@@ -1037,15 +2440,13 @@ impl Board {
                 (*self.pcm_reg)[PCM_CS_A].modify(|val| val | 1<<9); // Enable DMA
                 udelay(100);
             }
-
-            // Initialize the DMA
-            (*self.dma_reg)[DMA_CS].write(DMA_RESET);
-            udelay(10);
-            (*self.dma_reg)[DMA_CS].write(DMA_INT | DMA_END);
-            (*self.dma_reg)[DMA_CONBLK_AD].write(self.virt_to_uncached_phys(&(*ctl_ptr).cb as *const DmaCbT as *const usize));
-            (*self.dma_reg)[DMA_DEBUG].write(7); // clear debug error flags
-            (*self.dma_reg)[DMA_CS].write(0x10880001); // go, mid priority, wait for outstanding writes
         }
+        Ok(())
+    }
+
+    // Starts the DMA, and with PCM the transmitter whose DREQs pace it; init_hardware leaves it off.
+    fn start_pacing(&self) {
+        self.start_dma();
 
         if self.delay_hw == DELAY_VIA_PCM {
             unsafe {
@@ -1054,20 +2455,32 @@ impl Board {
         }
     }
 
-    fn init_pwm(&mut self) {
-        #[cfg(feature = "debug")]
-        {
-            trace!("Initializing PWM...\n");
+    // Resets the DMA channel and starts it again from the first control block.
+    fn start_dma(&self) {
+        let cbs = self.control_blocks();
+        unsafe {
+            (*self.dma_reg)[DMA_CS].write(DMA_RESET);
+            udelay(10);
+            (*self.dma_reg)[DMA_CS].write(DMA_INT | DMA_END);
+            (*self.dma_reg)[DMA_CONBLK_AD].write(self.virt_to_uncached_phys(cbs as *const usize));
+            // Clear debug error flags; a dry run's registers are plain memory and would keep the 7
+            (*self.dma_reg)[DMA_DEBUG].write(if self.is_dry_run() { 0 } else { 7 });
+            (*self.dma_reg)[DMA_CS].write(0x10880001); // go, mid priority, wait for outstanding writes
         }
+    }
+
+    fn init_pwm(&mut self) {
+        trace!("Initializing PWM...\n");
         self.update_pwm();
     }
 }
 
 impl Board {
 
+    // Drives pin to its idle level.
     fn gpio_set(&mut self, pin: u8) {
         unsafe {
-            if self.invert_mode {
+            if self.inverted[pin as usize] {
                 (*self.gpio_reg)[GPIO_SET0].write(1 << pin);
             }else{
                 (*self.gpio_reg)[GPIO_CLR0].write(1 << pin);
@@ -1086,27 +2499,80 @@ impl Board {
         }
     }
 
+    // Widths must be in 0.0..=1.0; NaN is rejected on its own as it compares false to everything.
+    fn check_width(pin: u8, width: f32) -> Result<(), Error> {
+        if width.is_nan() {
+            Err(Error::new(ErrorKind::Other, format!("Width for pin {} is not a number.", pin)))
+        } else if !(0.0..=1.0).contains(&width) {
+            Err(Error::new(ErrorKind::Other, format!("Width {} for pin {} is out of range 0.0 to 1.0.", width, pin)))
+        } else {
+            Ok(())
+        }
+    }
+
     // Set the pin to a pin2gpio element so pi_gpio can write to it,
     // and set the width of the PWM pulse to the element with the same index
     // in channel_pwm array.
     fn set_pin2gpio(&mut self, pin: u8, width: f32) -> Result<(), Error> {
-        if width >= 0.0 || width <= 1.0 {
-            for i in 0..self.num_channels {
-                if self.pin2gpio[i] == pin {
-                    self.channel_pwm[i] = width;
-                    return Ok(())
-                }else if self.pin2gpio[i] == 0 {
-                    self.pin2gpio[i] = pin;
-                    self.gpio_set(pin);
-                    self.gpio_set_mode(pin as usize, GPIO_MODE_OUT);
-                    self.channel_pwm[i] = width;
-                    return Ok(())
-                }
+        Board::check_width(pin, width)?;
+        for i in 0..self.num_channels {
+            if self.pin2gpio[i] == pin {
+                self.channel_pwm[i] = width;
+                self.waveforms[pin as usize] = None;
+                return Ok(())
+            }else if self.pin2gpio[i] == NO_PIN {
+                self.pin2gpio[i] = pin;
+                self.gpio_set(pin);
+                self.gpio_set_mode(pin as usize, GPIO_MODE_OUT);
+                self.channel_pwm[i] = width;
+                self.waveforms[pin as usize] = None;
+                return Ok(())
             }
-            Err(Error::new(ErrorKind::Other, format!("Pin {} is not one of the known pins", pin)))
-        }else {
-            Err(Error::new(ErrorKind::Other, format!("Width {} out of range.", width)))
         }
+        Err(Error::new(ErrorKind::Other, format!("Pin {} is not one of the known pins", pin)))
+    }
+
+    /// Plays pattern on pin over and over, one entry per sample of the pin's cycle: high where it is true,
+    /// low where it is false, e.g. a pulse train or a bit pattern. The pin leaves PWM for as long as it has
+    /// a pattern; [set_pwm](#method.set_pwm) or [release_pwm](#method.release_pwm) end it. PWM pins carry on as before.
+    ///
+    /// Needs a board built with [BoardBuilder::waveforms](struct.BoardBuilder.html#method.waveforms). The pin must
+    /// be a known pin that is not banned, and the pattern exactly as long as its cycle has samples
+    /// ([BoardInfo::pwm_steps](struct.BoardInfo.html#structfield.pwm_steps), or its group's). Inversion does not apply to patterns.
+    ///
+    /// ## Example
+    ///
+    /// ```no_run
+    /// use dma_gpio::pi::BoardBuilder;
+    ///
+    /// fn main() {
+    ///     let mut board = BoardBuilder::new().waveforms().build_with_pins(vec![21]).unwrap();
+    ///     // Two short pulses at the start of every cycle
+    ///     let pattern: Vec<bool> = (0..board.info().pwm_steps).map(|sample| sample == 0 || sample == 2).collect();
+    ///     board.set_waveform(21, &pattern).unwrap();
+    /// }
+    /// ```
+    pub fn set_waveform(&mut self, pin: u8, pattern: &[bool]) -> Result<(), Error> {
+        if !self.layout.waveforms {
+            return Err(Error::new(ErrorKind::Other, "Board was not built with waveforms."))
+        }
+        if !self.is_known_pin(pin) || self.is_banned_pin(pin) {
+            return Err(Error::new(ErrorKind::Other, format!("Pin {} is not one of the known pins", pin)))
+        }
+        let num_samples = self.pin_samples(pin);
+        if pattern.len() != num_samples {
+            return Err(Error::new(ErrorKind::Other, format!("Pattern for pin {} has {} samples, its cycle has {}.", pin, pattern.len(), num_samples)))
+        }
+        self.set_pin2gpio(pin, 0.0)?;
+        self.waveforms[pin as usize] = Some(pattern.to_vec());
+        self.update_pwm();
+        self.update_idle();
+        Ok(())
+    }
+
+    /// Pattern pin plays, as given to [set_waveform](#method.set_waveform); None for PWM pins.
+    pub fn waveform(&self, pin: u8) -> Option<&[bool]> {
+        self.waveforms.get(pin as usize).and_then(|pattern| pattern.as_ref().map(|pattern| pattern.as_slice()))
     }
 
     // Set each provided pin to one in pin2gpio
@@ -1120,11 +2586,66 @@ impl Board {
     }
 
     /// Set GPIO pin's pwm width.
+    ///
+    /// Width must be between 0.0 and 1.0 inclusive; widths outside that range and NaN are
+    /// rejected with an error and leave the pin as it was. See set_pwm_clamped for saturation.
     pub fn set_pwm(&mut self, pin: u8, width: f32) -> Result<(), Error> {
+        Board::check_width(pin, width)?;
         match self.set_pin(pin, width) {
             Ok(()) => self.update_pwm(),
             Err(e) => return Err(e)
         }
+        self.update_idle();
+        Ok(())
+    }
+
+    /// Set GPIO pin's pwm width, saturating widths below 0.0 to 0.0 and above 1.0 to 1.0.
+    ///
+    /// NaN cannot be saturated and is rejected with an error, as in set_pwm.
+    pub fn set_pwm_clamped(&mut self, pin: u8, width: f32) -> Result<(), Error> {
+        if width.is_nan() {
+            return Board::check_width(pin, width)
+        }
+        self.set_pwm(pin, width.max(0.0).min(1.0))
+    }
+
+    /// Set the pwm widths of several GPIO pins at once, so they all change in the same cycle
+    /// and the sample table is rewritten only once.
+    ///
+    /// All or nothing: if any pin is unknown or given more than once, or any width is invalid
+    /// as for set_pwm, an error is returned and no pin is changed.
+    ///
+    /// ## Example
+    ///
+    /// ```no_run
+    /// use dma_gpio::pi::BoardBuilder;
+    ///
+    /// fn main() {
+    ///     let mut board = BoardBuilder::new().build_with_pins(vec![20, 21]).unwrap();
+    ///     board.set_multi_pwm(&[(20, 0.25), (21, 0.75)]).unwrap();
+    /// }
+    /// ```
+    pub fn set_multi_pwm(&mut self, updates: &[(u8, f32)]) -> Result<(), Error> {
+        self.check_updates(updates, false)?;
+        for (pin, width) in updates {
+            self.set_pin2gpio(*pin, *width)?;
+        }
+        self.update_pwm();
+        self.update_idle();
+        Ok(())
+    }
+
+    // Signed widths are checked by their magnitude.
+    fn check_updates(&self, updates: &[(u8, f32)], signed: bool) -> Result<(), Error> {
+        for (i, (pin, width)) in updates.iter().enumerate() {
+            if !self.is_known_pin(*pin) {
+                return Err(Error::new(ErrorKind::Other, format!("Pin {} is not one of the known pins", pin)))
+            }
+            if updates[..i].iter().any(|(other, _)| other == pin) {
+                return Err(Error::new(ErrorKind::Other, format!("Pin {} is given more than once", pin)))
+            }
+            Board::check_width(*pin, if signed { width.abs() } else { *width })?;
+        }
         Ok(())
     }
 
@@ -1137,203 +2658,876 @@ impl Board {
             }
         }
         self.update_pwm();
+        self.update_idle();
         Ok(())
     }
 
-    /// Invert all known GPIO pins' outputs.
-    pub fn set_invert_mode(&mut self, mode: bool) {
-        self.invert_mode = mode;
+    /// Limits the pulses of pin to at least min_on_fraction of the cycle and leaves at least
+    /// min_off_fraction of it off, e.g. for gate drivers whose bootstrap capacitor must be refreshed
+    /// every cycle. Widths below the minimum on time become 0.0, widths above 1.0 - min_off_fraction
+    /// are capped there, in whole samples. The constraint stays with the pin until set again.
+    ///
+    /// Both fractions must be between 0.0 and 1.0 and together not more than 1.0.
+    ///
+    /// ## Example
+    ///
+    /// ```no_run
+    /// use dma_gpio::pi::BoardBuilder;
+    ///
+    /// fn main() {
+    ///     let mut board = BoardBuilder::new().build_with_pins(vec![21]).unwrap();
+    ///     board.set_pulse_constraints(21, 0.02, 0.05).unwrap();
+    ///     board.set_pwm(21, 1.0).unwrap();
+    ///     println!("Running at {}", board.effective_width(21, 1.0));
+    /// }
+    /// ```
+    pub fn set_pulse_constraints(&mut self, pin: u8, min_on_fraction: f32, min_off_fraction: f32) -> Result<(), Error> {
+        if !self.is_known_pin(pin) {
+            return Err(Error::new(ErrorKind::Other, format!("Pin {} is not one of the known pins", pin)))
+        }
+        self.pulse_constraints[pin as usize] = PulseConstraint::new(min_on_fraction, min_off_fraction)?;
         self.update_pwm();
+        self.update_idle();
+        Ok(())
     }
 
-    // To avoid storing the same pin 2 times after one pin has been released
-    // we compact the pin2gpio array so all ON PWM pins are at the begining.
-    fn compact_pin2gpio(&mut self) {
-        let mut j = 0;
-        let mut tmp_pin2gpio: [u8; MAX_CHANNELS] = [0; MAX_CHANNELS];
-        let mut tmp_channel_pwm: [f32; MAX_CHANNELS] = [0.0; MAX_CHANNELS];
+    /// Current pulse constraint of pin; the default for pins without one.
+    pub fn pulse_constraint(&self, pin: u8) -> PulseConstraint {
+        self.pulse_constraints.get(pin as usize).cloned().unwrap_or_default()
+    }
 
-        for i in 0..self.num_channels {
-            if self.pin2gpio[i] != 0 {
-                tmp_pin2gpio[j] = self.pin2gpio[i];
-                tmp_channel_pwm[j] = self.channel_pwm[i];
-                j += 1;
-            }
+    /// Width pin would run at for the given width, after its pulse constraint and quantisation to the PWM steps.
+    pub fn effective_width(&self, pin: u8, width: f32) -> f32 {
+        let constraint = self.pulse_constraint(pin);
+        let num_samples = self.pin_samples(pin);
+        if constraint.apply(width) > 0.0 {
+            constrained_off_index(width, constraint, num_samples).unwrap_or(num_samples) as f32 / num_samples as f32
+        } else {
+            0.0
         }
+    }
 
-        // Set the remaining slots in the arrays to 0, to disable them
-        for i in 0..self.num_channels {
-            self.pin2gpio[i] = tmp_pin2gpio[i];
-            self.channel_pwm[i] = tmp_channel_pwm[i];
+    /// Registers dir_pin as a direction output for pwm_pin, used by [set_signed_pwm](struct.Board.html#method.set_signed_pwm).
+    ///
+    /// With active_high the pin is high for positive widths and low for negative ones; otherwise the other way round.
+    /// A PWM pin can have more than one direction pin bound, e.g. both inputs of an H-bridge.
+    pub fn bind_direction(&mut self, pwm_pin: u8, dir_pin: u8, active_high: bool) -> Result<(), Error> {
+        if !self.is_known_pin(pwm_pin) {
+            return Err(Error::new(ErrorKind::Other, format!("Pin {} is not one of the known pins", pwm_pin)))
         }
-        self.num_channels = j;
+        // Only PWM channels are kept off BANNED_PINS; a direction pin is a plain output the caller picked,
+        // as GPIO 6 is on the rover's motor driver
+        if dir_pin as usize >= MAX_CHANNELS || self.is_known_pin(dir_pin) {
+            return Err(Error::new(ErrorKind::Other, format!("GPIO {} cannot be used as a direction pin", dir_pin)))
+        }
+
+        let binding = DirectionBinding { pwm_pin, dir_pin, active_high };
+        self.gpio_set_mode(dir_pin as usize, GPIO_MODE_OUT);
+        self.direction_bindings.retain(|b| b.dir_pin != dir_pin);
+        self.direction_bindings.push(binding);
+        if self.directions[pwm_pin as usize] == 0 {
+            self.directions[pwm_pin as usize] = 1;
+        }
+        self.write_direction(binding, self.directions[pwm_pin as usize]);
+        Ok(())
     }
 
-    // Pins can be relesead after being setup as PWM pins by writing the release <pin>
-    // command to the /dev/pi_gpio file. We make sure to compact the pin2gpio array
-    // that contains currently working pwm pins.
-    fn release_pin2gpio(&mut self, pin: u8) -> Result<(), Error> {
-        for i in 0..self.num_channels {
-            if self.pin2gpio[i] == pin {
-                self.channel_pwm[i] = 0.0;
-                self.pin2gpio[i] = 0;
-                return Ok(())
+    /// Set a signed pwm width (-1.0 to 1.0) on a pin with direction pins bound through
+    /// [bind_direction](struct.Board.html#method.bind_direction).
+    ///
+    /// When the sign changes the output is taken to 0.0 before the direction pins are switched,
+    /// so the new direction is never paired with the old width. A width of 0.0 leaves the direction as it is.
+    pub fn set_signed_pwm(&mut self, pin: u8, width: f32) -> Result<(), Error> {
+        if !(width >= -1.0 && width <= 1.0) {
+            return Err(Error::new(ErrorKind::Other, format!("Width {} out of range.", width)))
+        }
+        if !self.is_known_pin(pin) {
+            return Err(Error::new(ErrorKind::Other, format!("Pin {} is not one of the known pins", pin)))
+        }
+
+        let sign: i8 = if width > 0.0 { 1 } else if width < 0.0 { -1 } else { 0 };
+        if sign != 0 && sign != self.directions[pin as usize] {
+            if self.current_width(pin) > 0.0 {
+                self.set_pwm(pin, 0.0)?;
+                // every sample after the first clears the pin now; give the DMA time to write one
+                udelay(self.sample_period_us() * 2);
             }
+            for binding in self.direction_bindings.clone().iter().filter(|b| b.pwm_pin == pin) {
+                self.write_direction(*binding, sign);
+            }
+            self.directions[pin as usize] = sign;
         }
-        self.compact_pin2gpio();
-        Err(Error::new(ErrorKind::Other, format!("Pin {} is not one of the known pins", pin)))
+        self.set_pwm(pin, width.abs())
     }
 
-    // Function make sure the pin we want to release is a valid pin, if it is
-    // then calls release_pin2gpio to delete it from currently ON pins.
-    fn release_pin(&mut self, pin: u8) -> Result<(), Error> {
-        if self.is_known_pin(pin) {
-            self.release_pin2gpio(pin)
-        }else{
-            let err = format!("GPIO {:?} is not enabled for dma-gpio module", pin);
-            Err(Error::new(ErrorKind::Other, err))
+    /// Signed version of [set_multi_pwm](struct.Board.html#method.set_multi_pwm): sets signed widths
+    /// (-1.0 to 1.0) on several pins with bound direction pins, all or nothing.
+    ///
+    /// Pins whose sign changes are taken to 0.0 together before their direction pins are switched,
+    /// as in set_signed_pwm; the sample table is then rewritten once more, otherwise only once.
+    pub fn set_multi_signed_pwm(&mut self, updates: &[(u8, f32)]) -> Result<(), Error> {
+        self.check_updates(updates, true)?;
+
+        let flips: Vec<(u8, i8)> = updates.iter()
+            .map(|(pin, width)| (*pin, if *width > 0.0 { 1 } else if *width < 0.0 { -1 } else { 0 }))
+            .filter(|(pin, sign)| *sign != 0 && *sign != self.directions[*pin as usize])
+            .collect();
+
+        if !flips.is_empty() {
+            let stop: Vec<(u8, f32)> = flips.iter()
+                .filter(|(pin, _)| self.current_width(*pin) > 0.0)
+                .map(|(pin, _)| (*pin, 0.0))
+                .collect();
+            if !stop.is_empty() {
+                self.set_multi_pwm(&stop)?;
+                // every sample after the first clears the pins now; give the DMA time to write one
+                udelay(self.sample_period_us() * 2);
+            }
+            for (pin, sign) in flips {
+                for binding in self.direction_bindings.clone().iter().filter(|b| b.pwm_pin == pin) {
+                    self.write_direction(*binding, sign);
+                }
+                self.directions[pin as usize] = sign;
+            }
         }
+
+        let widths: Vec<(u8, f32)> = updates.iter().map(|(pin, width)| (*pin, width.abs())).collect();
+        self.set_multi_pwm(&widths)
     }
 
-    /// Releases GPIO pin.
-    pub fn release_pwm(&mut self, pin: u8) -> Result<(), Error> {
-        match self.release_pin(pin) {
-            Ok(()) => self.update_pwm(),
-            Err(e) => return Err(e)
+    // Only the first register bank is used, and banned pins are left alone.
+    fn check_plain_pin(pin: u8) -> Result<(), Error> {
+        if pin as usize >= MAX_CHANNELS || is_banned_pin(pin) {
+            Err(Error::new(ErrorKind::Other, format!("GPIO {} cannot be used as plain GPIO", pin)))
+        } else {
+            Ok(())
+        }
+    }
+
+    // Pins driven as plain GPIO must also not be a PWM channel, whose level the DMA would keep overwriting.
+    fn check_plain_output(&self, pin: u8) -> Result<(), Error> {
+        Board::check_plain_pin(pin)?;
+        if self.is_known_pin(pin) || self.pin2gpio.iter().any(|p| *p == pin) {
+            return Err(Error::new(ErrorKind::Other, format!("GPIO {} is a PWM channel", pin)))
         }
         Ok(())
     }
 
-    /// Releases all GPIO pins.
-    pub fn release_all_pwm(&mut self) -> Result<(), Error> {
-        self.channel_pwm = [0.0; MAX_CHANNELS];
-        self.update_pwm();
-        self.num_channels = 0;
-        self.pin2gpio = [0; MAX_CHANNELS];
+    /// Makes pin a plain digital output, for use with [set_output](struct.Board.html#method.set_output).
+    ///
+    /// Only for pins that are not PWM channels of this board; the DMA control blocks are not touched.
+    pub fn set_mode_output(&mut self, pin: u8) -> Result<(), Error> {
+        self.check_plain_output(pin)?;
+        self.gpio_set_mode(pin as usize, GPIO_MODE_OUT);
         Ok(())
     }
-    
-    /*    
-    What we need to do here is:
-    First DMA command turns on the pins that are >0
-    All the other packets turn off the pins that are not used
 
-    For the cpb packets (The DMA control packet)
-    -> cbp[0]->dst = gpset0: set   the pwms that are active
-    -> cbp[]->dst = gpclr0: clear when the sample has a value
+    /// Drives a plain digital output pin high or low, directly through the GPIO registers.
+    ///
+    /// Pins that are PWM channels of this board are rejected with an error, as the DMA would
+    /// overwrite the level on its next sample.
+    ///
+    /// ## Example
+    ///
+    /// ```no_run
+    /// use dma_gpio::pi::BoardBuilder;
+    ///
+    /// fn main() {
+    ///     let mut board = BoardBuilder::new().build_with_pins(vec![21]).unwrap();
+    ///     board.set_mode_output(5).unwrap();
+    ///     board.set_output(5, true).unwrap();
+    /// }
+    /// ```
+    pub fn set_output(&mut self, pin: u8, high: bool) -> Result<(), Error> {
+        self.check_plain_output(pin)?;
+        unsafe {
+            if high {
+                (*self.gpio_reg)[GPIO_SET0].write(1 << pin);
+            } else {
+                (*self.gpio_reg)[GPIO_CLR0].write(1 << pin);
+            }
+        }
+        Ok(())
+    }
 
-    For the samples     (The value that is written by the DMA command to cbp[n]->dst)
-    -> dp[0] = mask of the pwms that are active
-    -> dp[n] = mask of the pwm to stop at time n
+    /// Makes pin a digital input, for use with [read_pin](struct.Board.html#method.read_pin).
+    ///
+    /// Only for pins that are not PWM channels of this board.
+    pub fn set_mode_input(&mut self, pin: u8) -> Result<(), Error> {
+        self.check_plain_output(pin)?;
+        self.gpio_set_mode(pin as usize, GPIO_MODE_IN);
+        Ok(())
+    }
 
-    We dont really need to reset the cb->dst each time but I believe it helps a lot
-    in code readability in case someone wants to generate more complex signals.
-    */
-    fn update_pwm(&self) {
-        let phys_gpclr0: usize = self.gpio_phys_base + 0x28;
-        let phys_gpset0: usize = self.gpio_phys_base + 0x1c;
+    /// Sets the pull up or down resistor of pin, or removes it with Pull::None.
+    ///
+    /// ## Example
+    ///
+    /// ```no_run
+    /// use dma_gpio::pi::{BoardBuilder, Pull};
+    ///
+    /// fn main() {
+    ///     let mut board = BoardBuilder::new().build_with_pins(vec![21]).unwrap();
+    ///     board.set_mode_input(16).unwrap();
+    ///     board.set_pull(16, Pull::Up).unwrap();
+    ///     println!("Bumper {}", if board.read_pin(16).unwrap() { "free" } else { "pressed" });
+    /// }
+    /// ```
+    pub fn set_pull(&mut self, pin: u8, pull: Pull) -> Result<(), Error> {
+        Board::check_plain_pin(pin)?;
+        // Control first, then clock it into the pin; each needs 150 cycles to settle
+        unsafe {
+            (*self.gpio_reg)[GPIO_PULLEN].write(pull.control());
+            udelay(10);
+            (*self.gpio_reg)[GPIO_PULLCLK].write(1 << pin);
+            udelay(10);
+            (*self.gpio_reg)[GPIO_PULLEN].write(0);
+            (*self.gpio_reg)[GPIO_PULLCLK].write(0);
+        }
+        Ok(())
+    }
 
-        let ctl_ptr = self.mbox.virt_addr as *const Ctl;
+    /// Level of pin, true when high. Works for any pin that is not banned, PWM channels included.
+    pub fn read_pin(&self, pin: u8) -> Result<bool, Error> {
+        Board::check_plain_pin(pin)?;
+        let levels = unsafe { (*self.gpio_reg)[GPIO_LEV0].read() };
+        Ok(levels & (1 << pin) != 0)
+    }
 
-        // first we turn on the channels that need to be on
-        // take the first DMA Packet and set its target to start pulse
+    fn write_direction(&self, binding: DirectionBinding, sign: i8) {
+        let high = (sign > 0) == binding.active_high;
         unsafe {
-            (*ctl_ptr).cb[0].dst.write(
-                if self.invert_mode {
-                    phys_gpclr0
-                }else {
-                    phys_gpset0
-                });
+            if high {
+                (*self.gpio_reg)[GPIO_SET0].write(1 << binding.dir_pin);
+            } else {
+                (*self.gpio_reg)[GPIO_CLR0].write(1 << binding.dir_pin);
+            }
         }
+    }
 
-        // now create a mask of all the pins that should be on
-        let mut mask = 0;
+    fn current_width(&self, pin: u8) -> f32 {
         for i in 0..self.num_channels {
-            // check the pin2gpio pin has been set to avoid locking all of them as PWM.
-            if (self.channel_pwm[i] > 0.0) && (self.pin2gpio[i] > 0) {
-                mask |= 1 << self.pin2gpio[i];
+            if self.pin2gpio[i] == pin {
+                return self.channel_pwm[i]
             }
         }
+        0.0
+    }
 
-        // and give that to the DMA controller to write
-        unsafe {
-            (*ctl_ptr).sample[0].write(mask);
-        }
+    /// Period of pin's PWM cycle in microseconds, as the board runs it. Pins that are not on
+    /// the board get the board's own cycle.
+    pub fn period_us(&self, pin: u8) -> f64 {
+        let group = &self.layout.groups[self.layout.pin_group.get(pin as usize).cloned().unwrap_or(0)];
+        (group.cycle_time * self.pwm_divisor) as f64 * 1_000_000.0 / self.plld_freq as f64
+    }
 
-        // now we go through all the samples and turn the pins off when needed
-        unsafe {
-            for j in 1..self.num_samples {
-                (*ctl_ptr).cb[j*2].dst.write(
-                    if self.invert_mode {
-                        phys_gpset0
-                    }else {
-                        phys_gpclr0
-                    });
-                mask = 0;
-                for i in 0..self.num_channels {
-                    // check the pin2gpio pin has been set to avoid locking all of them as PWM.
-                    if self.pin2gpio[i] > 0 && (j as f32/self.num_samples as f32 > self.channel_pwm[i]) {
-                        mask |= 1 << self.pin2gpio[i];
-                    }
-                }
-                (*ctl_ptr).sample[j].write(mask);
-            }
+    /// Length of one sample of pin's cycle in microseconds; the resolution of its pulses.
+    pub fn step_us(&self, pin: u8) -> f64 {
+        let group = &self.layout.groups[self.layout.pin_group.get(pin as usize).cloned().unwrap_or(0)];
+        (group.sample_delay * self.pwm_divisor) as f64 * 1_000_000.0 / self.plld_freq as f64
+    }
+
+    /// Set pin to pulses of pulse_us microseconds each cycle, rounded to whole samples, as servos expect.
+    ///
+    /// Fails, leaving the pin as it was, when the pulse is shorter than one sample
+    /// ([step_us](struct.Board.html#method.step_us)) or longer than the period ([period_us](struct.Board.html#method.period_us)).
+    /// Set the pin to 0.0 with [set_pwm](struct.Board.html#method.set_pwm) to stop the pulses.
+    ///
+    /// ## Example
+    /// ```no_run
+    /// use dma_gpio::pi::BoardBuilder;
+    ///
+    /// fn main() {
+    ///     let mut board = BoardBuilder::new().servo_defaults().build_with_pins(vec![18]).unwrap();
+    ///     println!("{} us period in {} us steps", board.period_us(18), board.step_us(18));
+    ///     board.set_servo_pulse(18, 1000.0).unwrap();
+    /// }
+    /// ```
+    pub fn set_servo_pulse(&mut self, pin: u8, pulse_us: f64) -> Result<(), Error> {
+        if !self.is_known_pin(pin) {
+            return Err(Error::new(ErrorKind::Other, format!("Pin {} is not one of the known pins", pin)))
+        }
+        let step_us = self.step_us(pin);
+        let period_us = self.period_us(pin);
+        if !(pulse_us >= step_us) {
+            return Err(Error::new(ErrorKind::Other, format!("Pulse {} us for pin {} is shorter than one sample of {} us.", pulse_us, pin, step_us)))
         }
+        if pulse_us > period_us {
+            return Err(Error::new(ErrorKind::Other, format!("Pulse {} us for pin {} exceeds the cycle period of {} us.", pulse_us, pin, period_us)))
+        }
+
+        let num_samples = self.pin_samples(pin);
+        let samples = ((pulse_us / step_us).round() as usize).max(1).min(num_samples);
+        // Halfway between two sample boundaries, so quantisation lands on exactly this many samples
+        let width = if samples == num_samples { 1.0 } else { (samples as f32 - 0.5) / num_samples as f32 };
+        self.set_pwm(pin, width)
     }
 
+    // Of the group with the longest sample delay, so any pin has had a new sample by then.
+    fn sample_period_us(&self) -> u64 {
+        let plld_mhz = (self.plld_freq / 1_000_000) as u64;
+        let sample_delay = self.layout.groups.iter().map(|group| group.sample_delay).max().unwrap_or(self.layout.sample_delay);
+        ((sample_delay * self.pwm_divisor) as u64 + plld_mhz - 1) / plld_mhz
+    }
 
-    /// Check if the pin provided is found in the list of known pins set with [BoardBuilder::build_with_pins](struct.BoardBuilder.html#method.build_with_pins).
-    pub fn is_known_pin(&self, pin: u8) -> bool {
-        for i in 0..MAX_CHANNELS {
-            if self.known_pins[i] == pin {
-                return true
-            }
-        }
-        false
+    // Number of samples in the cycle of pin's group.
+    fn pin_samples(&self, pin: u8) -> usize {
+        let group = self.layout.pin_group.get(pin as usize).cloned().unwrap_or(0);
+        self.layout.groups[group].num_samples
     }
 
-    /// Check if the pin provided is found in the list of BANNED pins.
-    pub fn is_banned_pin(&self, pin: u8) -> bool {
-        for i in 0..BANNED_PINS.len() {
-            if BANNED_PINS[i] == pin {
-                return true
-            }
+    // Divisor and clocks per sample as init_hardware programs them
+    fn pacing_clock(&self) -> (usize, usize) {
+        if self.delay_hw == DELAY_VIA_PWM {
+            (self.pwm_divisor, self.layout.sample_delay)
+        } else {
+            pcm_pacing(self.pwm_divisor, self.layout.sample_delay).unwrap()
         }
-        false
     }
 
-    /// Sets all GPIO pins' pwm width to 0.0, and frees the memory used for the process.
-    /// 
-    /// Board already implements Drop trait that calls this method,
-    /// so you won't ever have to call this method.
-    pub fn terminate(&mut self) {
-        let mut has_error = false;
+    fn group_frequency(&self, group: &GroupLayout) -> f64 {
+        self.plld_freq as f64/(self.pwm_divisor * group.cycle_time) as f64
+    }
 
-        #[cfg(feature = "debug")]
-        {
-            trace!("Resetting DMA...");
-        }
-        if (self.dma_reg as usize > 0) && (self.mbox.virt_addr as usize > 0) {
-            for i in 0..self.num_channels {
-                self.channel_pwm[i] = 0.0;
-            }
-            self.update_pwm();
-            udelay(DEFAULT_CYCLE_TIME as u64);
-            unsafe {(*self.dma_reg)[DMA_CS].write(DMA_RESET)};
-            udelay(10);
+    /// Invert all GPIO pins' outputs, as [set_pin_invert](struct.Board.html#method.set_pin_invert) for every pin.
+    pub fn set_invert_mode(&mut self, mode: bool) {
+        self.inverted = [mode; MAX_CHANNELS];
+        self.update_inverted();
+    }
+
+    /// Makes pin active low: idle high, and low for its width of each cycle. Other pins keep their polarity,
+    /// e.g. for an H-bridge with one active low input.
+    ///
+    /// Inverted pins are low at the end of the cycle rather than at its start; a normal and an inverted
+    /// pin at the same width change together at the start of the cycle and are active in turn.
+    ///
+    /// ## Example
+    ///
+    /// ```no_run
+    /// use dma_gpio::pi::BoardBuilder;
+    ///
+    /// fn main() {
+    ///     let mut board = BoardBuilder::new().build_with_pins(vec![20, 21]).unwrap();
+    ///     board.set_pin_invert(21, true).unwrap();
+    ///     board.set_pwm(20, 0.25).unwrap();
+    ///     board.set_pwm(21, 0.25).unwrap();
+    ///     print!("{}", board.dump_samples_structured().waveform());
+    /// }
+    /// ```
+    pub fn set_pin_invert(&mut self, pin: u8, invert: bool) -> Result<(), Error> {
+        if !self.is_known_pin(pin) {
+            return Err(Error::new(ErrorKind::Other, format!("Pin {} is not one of the known pins", pin)))
         }
+        self.inverted[pin as usize] = invert;
+        self.update_inverted();
+        Ok(())
+    }
 
+    /// Whether pin is inverted.
+    pub fn pin_invert(&self, pin: u8) -> bool {
+        self.inverted.get(pin as usize).cloned().unwrap_or(false)
+    }
 
-        #[cfg(feature = "debug")]
-        {
-            trace!("Freeing mbox memory...");
+    fn update_inverted(&mut self) {
+        self.update_pwm();
+        if self.idle || self.paused {
+            self.drive_idle_level();
         }
-        if !self.mbox.virt_addr.is_null() {
-            match mailbox::unmapmem(self.mbox.virt_addr, self.num_pages * PAGE_SIZE){
-                Ok(_) => (),
-                Err(e) => {
-                    error!("{:?}", e);
-                    has_error = true;
+    }
+
+    /// true while auto-idle has the DMA paused.
+    pub fn is_idle(&self) -> bool {
+        self.idle
+    }
+
+    /// Pauses the DMA when auto-idle is on and all channels have been at 0.0 for the auto-idle time.
+    ///
+    /// Setting widths checks this too, so only callers that leave the widths alone need it; call it
+    /// from the control loop, or at least a few times per auto-idle time, and the board pauses
+    /// within one call of the time running out. Does nothing without
+    /// [BoardBuilder::auto_idle](struct.BoardBuilder.html#method.auto_idle).
+    ///
+    /// ## Example
+    /// ```no_run
+    /// use dma_gpio::pi::BoardBuilder;
+    ///
+    /// fn main() {
+    ///     let mut board = BoardBuilder::new().auto_idle().build_with_pins(vec![21]).unwrap();
+    ///     board.set_pwm(21, 0.0).unwrap();
+    ///     loop {
+    ///         board.poll_idle();
+    ///         std::thread::sleep(std::time::Duration::from_millis(100));
+    ///     }
+    /// }
+    /// ```
+    pub fn poll_idle(&mut self) {
+        self.update_idle();
+    }
+
+    /// Only accessable with "stats" feature. Number of times the board entered and left auto-idle.
+    #[cfg(feature = "stats")]
+    pub fn idle_stats(&self) -> IdleStats {
+        self.idle_stats
+    }
+
+    // Patterns count as zero only when they never go high
+    fn all_channels_zero(&self) -> bool {
+        (0..self.num_channels).all(|i| {
+            let pin = self.pin2gpio[i];
+            pin == NO_PIN || match &self.waveforms[pin as usize] {
+                Some(pattern) => !pattern.contains(&true),
+                None => self.channel_constraint(i).apply(self.channel_pwm[i]) <= 0.0
+            }
+        })
+    }
+
+    // Pauses the DMA once all channels have been at zero for auto_idle_time and resumes it
+    // as soon as any of them is not. Called after every change of the samples and by poll_idle.
+    fn update_idle(&mut self) {
+        if self.paused {
+            return;
+        }
+        let auto_idle_time = match self.auto_idle_time {
+            Some(time) => time,
+            None => return
+        };
+
+        if self.all_channels_zero() {
+            let zero_since = *self.zero_since.get_or_insert_with(Instant::now);
+            if !self.idle && zero_since.elapsed() >= auto_idle_time {
+                self.enter_idle();
+            }
+        } else {
+            self.zero_since = None;
+            if self.idle {
+                self.exit_idle();
+            }
+        }
+    }
+
+    // Writes the idle level to all pins directly, the same level the DMA keeps writing while all widths are 0.0.
+    fn drive_idle_level(&self) {
+        let mut set_mask = 0;
+        let mut clr_mask = 0;
+        for i in 0..MAX_CHANNELS {
+            let pin = self.pin2gpio[i];
+            if pin != NO_PIN {
+                if self.inverted[pin as usize] {
+                    set_mask |= 1 << pin;
+                } else {
+                    clr_mask |= 1 << pin;
+                }
+            }
+        }
+        unsafe {
+            (*self.gpio_reg)[GPIO_SET0].write(set_mask);
+            (*self.gpio_reg)[GPIO_CLR0].write(clr_mask);
+        }
+    }
+
+    fn enter_idle(&mut self) {
+        self.drive_idle_level();
+        unsafe {
+            (*self.dma_reg)[DMA_CS].modify(|val| val & !DMA_ACTIVE);
+        }
+        self.idle = true;
+        #[cfg(feature = "stats")]
+        {
+            self.idle_stats.entries += 1;
+        }
+        trace!("DMA paused, all channels idle");
+    }
+
+    // The DMA carries on from the control block it was paused at, so the new samples
+    // are on the pins within one cycle.
+    fn exit_idle(&mut self) {
+        dma_barrier();
+        unsafe {
+            (*self.dma_reg)[DMA_CS].modify(|val| val | DMA_ACTIVE);
+        }
+        self.idle = false;
+        #[cfg(feature = "stats")]
+        {
+            self.idle_stats.exits += 1;
+        }
+        trace!("DMA resumed");
+    }
+
+    /// Stops the DMA and drives all registered pins to their idle level, keeping
+    /// the memory mappings, control blocks and the current widths.
+    ///
+    /// Widths set while paused are written to the samples as usual and go out on
+    /// the pins once [resume](#method.resume) is called.
+    ///
+    /// ## Example
+    ///
+    /// ```no_run
+    /// use dma_gpio::pi::BoardBuilder;
+    ///
+    /// fn main() {
+    ///     let mut board = BoardBuilder::new().build_with_pins(vec![21]).unwrap();
+    ///     board.set_pwm(21, 0.25).unwrap();
+    ///     board.pause().unwrap();
+    ///     board.set_pwm(21, 0.5).unwrap(); // applied on resume
+    ///     board.resume().unwrap();
+    /// }
+    /// ```
+    pub fn pause(&mut self) -> Result<(), Error> {
+        if self.paused {
+            return Err(Error::new(ErrorKind::Other, "Board is already paused."));
+        }
+        unsafe {
+            (*self.dma_reg)[DMA_CS].write(DMA_RESET);
+        }
+        udelay(10);
+        self.drive_idle_level();
+        self.paused = true;
+        self.idle = false;
+        trace!("DMA stopped by pause");
+        Ok(())
+    }
+
+    /// Restarts the DMA stopped by [pause](#method.pause) from the existing control blocks,
+    /// with the widths as they are now.
+    pub fn resume(&mut self) -> Result<(), Error> {
+        if !self.paused {
+            return Err(Error::new(ErrorKind::Other, "Board is not paused."));
+        }
+        self.update_pwm();
+        self.start_pacing();
+        self.paused = false;
+        self.update_idle();
+        trace!("DMA restarted by resume");
+        Ok(())
+    }
+
+    /// true between [pause](#method.pause) and [resume](#method.resume).
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    /// Changes the divisor, cycle time and sample delay of the board's own pins, as given to
+    /// [BoardBuilder::divide_pwm](struct.BoardBuilder.html#method.divide_pwm), [set_cycle_time](struct.BoardBuilder.html#method.set_cycle_time)
+    /// and [set_sample_delay](struct.BoardBuilder.html#method.set_sample_delay), without building the board again:
+    /// the DMA is stopped, the clock and PWM range or PCM frame programmed, the control blocks laid out for
+    /// the new sample count and the DMA started again. Widths, inversion, pulse constraints and direction
+    /// bindings carry over; groups added with add_group keep their own timing.
+    ///
+    /// Settings are checked as a strict builder checks them and fail with the same
+    /// [BuildError](enum.BuildError.html), as an io::Error, leaving the board as it was. So do timings whose
+    /// samples and control blocks do not fit the mailbox memory allocated when the board was built
+    /// (see [ctl_allocation](#method.ctl_allocation)), and timings that change the sample count of a pin playing a waveform.
+    ///
+    /// A paused board is reconfigured but stays paused until [resume](#method.resume).
+    ///
+    /// ## Example
+    ///
+    /// ```no_run
+    /// use dma_gpio::pi::BoardBuilder;
+    ///
+    /// fn main() {
+    ///     let mut board = BoardBuilder::new().divide_pwm(1000).set_cycle_time(200).set_sample_delay(2).build_with_pins(vec![20]).unwrap();
+    ///     board.set_pwm(20, 0.25).unwrap();
+    ///     // 100 steps at 5 kHz rather than 2.5 kHz, still at 25 %
+    ///     board.reconfigure_timing(500, 200, 2).unwrap();
+    /// }
+    /// ```
+    pub fn reconfigure_timing(&mut self, pwm_divisor: usize, cycle_time: usize, sample_delay: usize) -> Result<(), Error> {
+        let layout = match self.check_timing(pwm_divisor, cycle_time, sample_delay) {
+            Ok(layout) => layout,
+            Err(e) => {
+                error!("{}", e);
+                return Err(e.into())
+            }
+        };
+        if layout.num_pages > self.mbox_pages {
+            return Err(Error::new(ErrorKind::InvalidInput, format!("Cycle time {} and sample delay {} need {} pages of DMA memory; the board was built with {}.",
+                                                                   cycle_time, sample_delay, layout.num_pages, self.mbox_pages)))
+        }
+        for (pin, pattern) in self.waveforms.iter().enumerate() {
+            if let Some(pattern) = pattern {
+                let num_samples = layout.groups[layout.pin_group[pin]].num_samples;
+                if pattern.len() != num_samples {
+                    return Err(Error::new(ErrorKind::InvalidInput, format!("Pin {} plays a pattern of {} samples; its cycle would have {}.", pin, pattern.len(), num_samples)))
+                }
+            }
+        }
+        let dma_feasibility = DmaFeasibility::with_clock(self.plld_freq, pwm_divisor, layout.sample_delay, self.dma_throughput);
+        if !dma_feasibility.is_achievable() {
+            let error = BuildError::InfeasibleDma(dma_feasibility);
+            if self.reject_infeasible_dma {
+                error!("{}", error);
+                return Err(error.into())
+            }
+            warn!("{}", error);
+        }
+
+        unsafe {
+            (*self.dma_reg)[DMA_CS].write(DMA_RESET);
+        }
+        udelay(10);
+        self.layout = layout;
+        self.pwm_divisor = pwm_divisor;
+        self.dma_feasibility = dma_feasibility;
+        self.adjusted_settings.clear();
+        self.init_ctrl_data();
+        self.update_pwm();
+        self.init_hardware(pwm_divisor, self.layout.sample_delay)?;
+        if !self.paused {
+            self.start_pacing();
+            self.idle = false;
+            self.update_idle();
+        }
+        trace!("Timing reconfigured to divisor {}, cycle time {}, sample delay {}", pwm_divisor, cycle_time, sample_delay);
+        Ok(())
+    }
+
+    // The checks of a strict BoardBuilder::build for the board's own timing, with the layout it gives.
+    fn check_timing(&self, pwm_divisor: usize, cycle_time: usize, sample_delay: usize) -> Result<CtlLayout, BuildError> {
+        let max_divisor = if self.delay_hw == DELAY_VIA_PWM { MAX_PWM_DIVISOR } else { MAX_PCM_DIVISOR };
+        if pwm_divisor == 0 || pwm_divisor > max_divisor {
+            return Err(BuildError::InvalidDivisor(pwm_divisor))
+        }
+        if cycle_time < MIN_CYCLE_TIME || cycle_time > MAX_CYCLE_TIME {
+            return Err(BuildError::InvalidCycleTime(cycle_time))
+        }
+        if sample_delay == 0 || sample_delay > MAX_SAMPLE_DELAY {
+            return Err(BuildError::InvalidSampleDelay(sample_delay))
+        }
+        if cycle_time % sample_delay != 0 {
+            return Err(BuildError::CycleTimeNotMultiple { group: 0, cycle_time, sample_delay })
+        }
+        let layout = CtlLayout::new(&self.known_pins, self.num_channels, cycle_time, sample_delay, &self.group_settings, self.layout.waveforms)?;
+        if self.delay_hw == DELAY_VIA_PCM && pcm_pacing(pwm_divisor, layout.sample_delay).is_none() {
+            return Err(BuildError::PcmPacing { divisor: pwm_divisor, sample_delay: layout.sample_delay })
+        }
+        Ok(layout)
+    }
+
+    // To avoid storing the same pin 2 times after one pin has been released
+    // we compact the pin2gpio array so all ON PWM pins are at the begining.
+    // num_channels stays the number of known pins, so released pins can be set again.
+    fn compact_pin2gpio(&mut self) {
+        let mut j = 0;
+        let mut tmp_pin2gpio: [u8; MAX_CHANNELS] = [NO_PIN; MAX_CHANNELS];
+        let mut tmp_channel_pwm: [f32; MAX_CHANNELS] = [0.0; MAX_CHANNELS];
+
+        for i in 0..self.num_channels {
+            if self.pin2gpio[i] != NO_PIN {
+                tmp_pin2gpio[j] = self.pin2gpio[i];
+                tmp_channel_pwm[j] = self.channel_pwm[i];
+                j += 1;
+            }
+        }
+
+        // Set the remaining slots in the arrays to NO_PIN and 0.0, to disable them
+        for i in 0..self.num_channels {
+            self.pin2gpio[i] = tmp_pin2gpio[i];
+            self.channel_pwm[i] = tmp_channel_pwm[i];
+        }
+    }
+
+    // Pins can be released after being setup as PWM pins by writing the release <pin>
+    // command to the /dev/pi_gpio file. We make sure to compact the pin2gpio array
+    // that contains currently working pwm pins.
+    fn release_pin2gpio(&mut self, pin: u8) -> Result<(), Error> {
+        for i in 0..self.num_channels {
+            if self.pin2gpio[i] == pin {
+                self.channel_pwm[i] = 0.0;
+                self.pin2gpio[i] = NO_PIN;
+                self.waveforms[pin as usize] = None;
+                self.compact_pin2gpio();
+                return Ok(())
+            }
+        }
+        Err(Error::new(ErrorKind::Other, format!("Pin {} is not one of the known pins", pin)))
+    }
+
+    // Function make sure the pin we want to release is a valid pin, if it is
+    // then calls release_pin2gpio to delete it from currently ON pins.
+    fn release_pin(&mut self, pin: u8) -> Result<(), Error> {
+        if self.is_known_pin(pin) {
+            self.release_pin2gpio(pin)
+        }else{
+            let err = format!("GPIO {:?} is not enabled for dma-gpio module", pin);
+            Err(Error::new(ErrorKind::Other, err))
+        }
+    }
+
+    /// Releases GPIO pin.
+    pub fn release_pwm(&mut self, pin: u8) -> Result<(), Error> {
+        match self.release_pin(pin) {
+            Ok(()) => self.update_pwm(),
+            Err(e) => return Err(e)
+        }
+        self.update_idle();
+        Ok(())
+    }
+
+    /// Releases all GPIO pins. They stay known to the board and can be set again.
+    pub fn release_all_pwm(&mut self) -> Result<(), Error> {
+        self.channel_pwm = [0.0; MAX_CHANNELS];
+        self.waveforms = vec![None; MAX_CHANNELS];
+        self.pin2gpio = [NO_PIN; MAX_CHANNELS];
+        self.update_pwm();
+        self.update_idle();
+        Ok(())
+    }
+
+    /// Pins currently driven, set and not released since, with their widths in the order they were
+    /// first set; pins playing a waveform show 0.0.
+    ///
+    /// ## Example
+    ///
+    /// ```no_run
+    /// use dma_gpio::pi::BoardBuilder;
+    ///
+    /// fn main() {
+    ///     let mut board = BoardBuilder::new().build_with_pins(vec![21, 22]).unwrap();
+    ///     board.set_pwm(22, 0.25).unwrap();
+    ///     board.set_pwm(21, 0.5).unwrap();
+    ///     assert_eq!(board.active_channels(), vec![(22, 0.25), (21, 0.5)]);
+    /// }
+    /// ```
+    pub fn active_channels(&self) -> Vec<(u8, f32)> {
+        (0..self.num_channels)
+            .filter(|&i| self.pin2gpio[i] != NO_PIN)
+            .map(|i| (self.pin2gpio[i], self.channel_pwm[i]))
+            .collect()
+    }
+    
+    /*    
+    What we need to do here is, for every group:
+    First DMA command sets the pins that are high for any of the cycle
+    All the other packets clear the pins whose high samples are over
+
+    For the cpb packets (The DMA control packet)
+    -> cbp[]->dst = gpset0: set   the pwms that are high, when copying the group's sample 0
+    -> cbp[]->dst = gpclr0: clear when the sample has a value
+
+    For the samples     (The value that is written by the DMA command to cbp[n]->dst)
+    -> dp[0] = mask of the pwms that are high
+    -> dp[n] = mask of the pwm to go low at time n
+
+    Normal pins are high while active; inverted pins high while not active and then
+    low for the rest of the cycle (see high_samples), so no sample needs both registers.
+    Only pins playing a waveform do, and a board built for them has a set mask for every
+    sample as well (see waveform_masks).
+
+    We dont really need to reset the cb->dst each time but I believe it helps a lot
+    in code readability in case someone wants to generate more complex signals.
+    */
+    fn update_pwm(&self) {
+        let phys_gpclr0: usize = self.gpio_phys_base + 0x28;
+        let phys_gpset0: usize = self.gpio_phys_base + 0x1c;
+
+        let samples = self.samples();
+        let cbs = self.control_blocks();
+
+        // first we turn on the channels that need to be on
+        // take the DMA Packets copying each group's first sample and set their target to start pulse
+        // (with waveforms sample 0 is a clear like the others, and the set masks have their own packets)
+        unsafe {
+            for (j, sample) in self.layout.cb_samples.iter().enumerate() {
+                if let Some(i) = *sample {
+                    let set = if self.layout.waveforms {
+                        i >= self.layout.num_samples
+                    } else {
+                        self.layout.groups.iter().any(|group| group.first_sample == i)
+                    };
+                    (*cbs)[j].dst.write(if set { phys_gpset0 } else { phys_gpclr0 } as u32);
+                }
+            }
+        }
+
+        for (g, group) in self.layout.groups.iter().enumerate() {
+            // check the pin2gpio pin has been set to avoid locking all of them as PWM.
+            let in_group: Vec<u8> = (0..self.num_channels)
+                .map(|i| self.pin2gpio[i])
+                .filter(|&pin| pin != NO_PIN && self.layout.pin_group[pin as usize] == g)
+                .collect();
+            let pins: Vec<(u8, usize)> = (0..self.num_channels)
+                .filter(|&i| in_group.contains(&self.pin2gpio[i]) && self.waveforms[self.pin2gpio[i] as usize].is_none())
+                .map(|i| {
+                    let pin = self.pin2gpio[i];
+                    (pin, high_samples(self.channel_pwm[i], self.channel_constraint(i), self.inverted[pin as usize], group.num_samples))
+                })
+                .collect();
+            let masks = sample_masks(&pins, group.num_samples);
+
+            // and give the masks to the DMA controller to write
+            unsafe {
+                if self.layout.waveforms {
+                    let waveforms: Vec<(u8, &[bool])> = in_group.iter()
+                        .filter_map(|&pin| self.waveforms[pin as usize].as_ref().map(|pattern| (pin, pattern.as_slice())))
+                        .collect();
+                    let (set_masks, clear_masks) = waveform_masks(&masks, &waveforms);
+                    for j in 0..group.num_samples {
+                        (*samples)[self.layout.num_samples + group.first_sample + j].write(set_masks[j]);
+                        (*samples)[group.first_sample + j].write(clear_masks[j]);
+                    }
+                } else {
+                    for (j, mask) in masks.into_iter().enumerate() {
+                        (*samples)[group.first_sample + j].write(mask);
+                    }
+                }
+            }
+        }
+        dma_barrier();
+    }
+
+
+    fn channel_constraint(&self, channel: usize) -> PulseConstraint {
+        self.pulse_constraint(self.pin2gpio[channel])
+    }
+
+    /// Check if the pin provided is found in the list of known pins set with [BoardBuilder::build_with_pins](struct.BoardBuilder.html#method.build_with_pins).
+    pub fn is_known_pin(&self, pin: u8) -> bool {
+        for i in 0..self.num_channels {
+            if self.known_pins[i] == pin {
+                return true
+            }
+        }
+        false
+    }
+
+    /// Check if the pin provided is found in the list of BANNED pins.
+    pub fn is_banned_pin(&self, pin: u8) -> bool {
+        for i in 0..BANNED_PINS.len() {
+            if BANNED_PINS[i] == pin {
+                return true
+            }
+        }
+        false
+    }
+
+    /// Sets all GPIO pins' pwm width to 0.0, and frees the memory used for the process.
+    /// 
+    /// Board already implements Drop trait that calls this method,
+    /// so you won't ever have to call this method.
+    pub fn terminate(&mut self) {
+        let mut has_error = false;
+
+        trace!("Resetting DMA...");
+        if (self.dma_reg as usize > 0) && (self.mbox.virt_addr as usize > 0) {
+            for i in 0..self.num_channels {
+                self.channel_pwm[i] = 0.0;
+            }
+            self.waveforms = vec![None; MAX_CHANNELS];
+            self.update_pwm();
+            udelay(DEFAULT_CYCLE_TIME as u64);
+            unsafe {(*self.dma_reg)[DMA_CS].write(DMA_RESET)};
+            udelay(10);
+        }
+
+
+        trace!("Freeing mbox memory...");
+        if !self.mbox.virt_addr.is_null() && self.fake_memory.is_none() {
+            match mailbox::unmapmem(self.mbox.virt_addr, self.mbox_pages * PAGE_SIZE){
+                Ok(_) => (),
+                Err(e) => {
+                    error!("{:?}", e);
+                    has_error = true;
                 },
             }
             if self.mbox.handle <= 2 {
@@ -1376,97 +3570,316 @@ impl Board {
         }
     }
 
-    /// print info about the hardware: PWM or PCM, Number of channels, Pins being used, PWM Frequency, PWM steps, Maximum Period, Minimum Period, and DMA Base Address.
-    pub fn print_info(&self) {
-        println!("Using hardware:\t\t\t{:}", if self.delay_hw == DELAY_VIA_PWM {"PWM"} else{"PCM"});
-        println!("Number of channels:\t\t{}", self.num_channels);
+    /// true for boards built with [BoardBuilder::dry_run](struct.BoardBuilder.html#method.dry_run), which do not touch the hardware.
+    pub fn is_dry_run(&self) -> bool {
+        self.fake_memory.is_some()
+    }
 
-        #[allow(array_into_iter)]
-        let print_pins: Vec<&u8> = self.known_pins.into_iter().filter(|&&pin| pin > 0).collect();
-        println!("Pins:\t\t\t\t{:?}", print_pins);
-        println!("PWM frequency:\t\t\t{} Hz", 500000000.0/(self.pwm_divisor * self.cycle_time) as f64);
-        println!("PWM steps:\t\t\t{}", self.num_samples);
-        println!("Maximum period (100 %):\t{} us", ((self.cycle_time * self.pwm_divisor) as f64/500.0));
-        println!("Minimum period ({:3} %):\t{} us", 100.0*self.sample_delay as f64 / self.cycle_time as f64, (self.sample_delay * self.pwm_divisor) as f64/500.0);
-        println!("DMA Base:\t\t\t{:#010x}", self.dma_base);
+    /// Reads the 32 bit register at index (byte offset / 4) of a peripheral, None past its end.
+    ///
+    /// On a dry-run board this is what the Board last wrote there, so GPIO modes, levels and the
+    /// PWM, clock and DMA setup can be checked without the hardware.
+    ///
+    /// ## Example
+    /// ```no_run
+    /// use dma_gpio::pi::{BoardBuilder, Peripheral};
+    ///
+    /// fn main() {
+    ///     let mut board = BoardBuilder::new().dry_run(true).build_with_pins(vec![21]).unwrap();
+    ///     board.set_pwm(21, 0.5).unwrap();
+    ///     // GPFSEL2 holds the modes of pins 20 to 29, three bits each
+    ///     let mode = (board.read_register(Peripheral::Gpio, 2).unwrap() >> 3) & 7;
+    ///     println!("Pin 21 mode {}", mode);
+    /// }
+    /// ```
+    pub fn read_register(&self, peripheral: Peripheral, index: usize) -> Option<u32> {
+        unsafe {
+            match peripheral {
+                Peripheral::Gpio => (*self.gpio_reg).get(index).map(|reg| reg.read()),
+                Peripheral::Pwm => (*self.pwm_reg).get(index).map(|reg| reg.read()),
+                Peripheral::Pcm => (*self.pcm_reg).get(index).map(|reg| reg.read()),
+                Peripheral::Clk => (*self.clk_reg).get(index).map(|reg| reg.read()),
+                Peripheral::Dma => (*self.dma_reg).get(index).map(|reg| reg.read()),
+            }.map(|value| value as u32)
+        }
     }
 
-    /// This method is only available when 'debug' feature is on.
-    /// 
-    /// Print out all informations about the control blocks, PWM, Clock, GPIO and DMA.
-    #[cfg(feature = "debug")]
-    pub fn debug_dump_hw(&self) {
-        trace!("\n");
-        trace!("pwm_reg: {:?}\n", self.pwm_reg);
+    /// Samples and control blocks of the current timing, and bytes of mailbox memory allocated for
+    /// them, as sized from the cycle times and sample delays the board was built with.
+    pub fn ctl_allocation(&self) -> (usize, usize, usize) {
+        (self.layout.num_samples, self.layout.cb_samples.len(), self.mbox_pages * PAGE_SIZE)
+    }
 
-        let ctl_ptr = self.mbox.virt_addr as *const Ctl;
+    /// Builder settings whose effective value differs from the requested one.
+    pub fn adjusted_settings(&self) -> &[AdjustedSetting] {
+        &self.adjusted_settings
+    }
+
+    /// Model, pacing and PWM settings the board ended up with; the same as [print_info](struct.Board.html#method.print_info) shows.
+    pub fn info(&self) -> BoardInfo {
+        let (pacing_divisor, pacing_clocks) = self.pacing_clock();
+        BoardInfo {
+            model: self.model,
+            revision: self.board_revision,
+            pacing: if self.delay_hw == DELAY_VIA_PWM { "PWM" } else { "PCM" },
+            dma_channel: self.dma_channel,
+            dma_base: self.dma_base,
+            plld_frequency: self.plld_freq,
+            pacing_divisor,
+            pacing_clocks,
+            clock_divisor: self.clock_divisor,
+            sample_frequency: self.plld_freq as f64 / (pacing_divisor * pacing_clocks) as f64,
+            pins: self.known_pins.iter().cloned().take(self.num_channels).collect(),
+            pwm_frequency: self.group_frequency(&self.layout.groups[0]),
+            pwm_steps: self.layout.groups[0].num_samples,
+            dry_run: self.is_dry_run(),
+            pulse_constraints: self.known_pins.iter().cloned().take(self.num_channels).map(|pin| self.pulse_constraint(pin)).collect(),
+            groups: self.layout.groups.iter().map(|group| GroupInfo {
+                pins: group.pins.clone(),
+                pwm_frequency: self.group_frequency(group),
+                pwm_steps: group.num_samples,
+                min_period_us: (group.sample_delay * self.pwm_divisor) as f64 * 1_000_000.0 / self.plld_freq as f64,
+                max_period_us: (group.cycle_time * self.pwm_divisor) as f64 * 1_000_000.0 / self.plld_freq as f64,
+            }).collect(),
+        }
+    }
+
+    /// Reads the DMA channel's current state.
+    pub fn dma_status(&self) -> DmaStatus {
+        let (cs, debug) = unsafe {
+            ((*self.dma_reg)[DMA_CS].read(), (*self.dma_reg)[DMA_DEBUG].read())
+        };
+        DmaStatus::from_registers(cs, debug)
+    }
+
+    /// Reads the sample masks back from the DMA's memory together with the current channel table.
+    pub fn dump_samples_structured(&self) -> SampleDump {
+        let samples = self.samples();
+        let group_masks: Vec<Vec<usize>> = self.layout.groups.iter().map(|group| unsafe {
+            (group.first_sample..group.first_sample + group.num_samples).map(|i| (*samples)[i].read()).collect()
+        }).collect();
+        let group_set_masks: Vec<Vec<usize>> = if self.layout.waveforms {
+            self.layout.groups.iter().map(|group| unsafe {
+                (group.first_sample..group.first_sample + group.num_samples).map(|i| (*samples)[self.layout.num_samples + i].read()).collect()
+            }).collect()
+        } else {
+            vec![]
+        };
+        let channels = (0..self.num_channels)
+            .filter(|&i| self.pin2gpio[i] != NO_PIN)
+            .map(|i| ChannelDump {
+                pin: self.pin2gpio[i],
+                group: self.layout.pin_group[self.pin2gpio[i] as usize],
+                width: self.channel_pwm[i],
+                effective_width: self.effective_width(self.pin2gpio[i], self.channel_pwm[i]),
+                constraint: self.channel_constraint(i),
+                invert: self.inverted[self.pin2gpio[i] as usize],
+                waveform: self.waveforms[self.pin2gpio[i] as usize].is_some(),
+                off_index: constrained_off_index(self.channel_pwm[i], self.channel_constraint(i), self.pin_samples(self.pin2gpio[i])),
+            })
+            .collect();
+        SampleDump { masks: group_masks[0].clone(), group_masks, group_set_masks, channels }
+    }
+
+    /// Walks the DMA control blocks from the head following their next pointers and checks the chain
+    /// is as it was built: two blocks per sample looping back to the head (with groups, a sample copy per group
+    /// due one and a pacing block per slot; with waveforms, a set mask copy before each sample copy), sample copies
+    /// going from sample memory to the GPIO set/clear registers, pacing writes going to the PWM/PCM FIFO.
+    ///
+    /// ## Example
+    ///
+    /// ```no_run
+    /// use dma_gpio::pi::BoardBuilder;
+    ///
+    /// fn main() {
+    ///     let board = BoardBuilder::new().build_with_pins(vec![21]).unwrap();
+    ///     match board.verify_cb_chain() {
+    ///         Ok(report) => println!("Chain of {} control blocks is intact", report.length),
+    ///         Err(e) => println!("Control block chain is broken: {:?}", e),
+    ///     }
+    /// }
+    /// ```
+    pub fn verify_cb_chain(&self) -> Result<ChainReport, ChainError> {
+        let cbs = self.control_blocks();
+        let samples = self.samples();
+        let cb_size = size_of::<DmaCbT>();
+        let expected = self.layout.cb_samples.len();
+
+        let cbs_phys = self.virt_to_uncached_phys(cbs as *const usize);
+        let samples_phys = self.virt_to_uncached_phys(samples as *const usize);
+        let samples_end = samples_phys + self.layout.sample_words() * size_of::<usize>();
+        let phys_gpclr0 = self.gpio_phys_base + 0x28;
+        let phys_gpset0 = self.gpio_phys_base + 0x1c;
+        let phys_fifo_addr = if self.delay_hw == DELAY_VIA_PWM {
+            self.pwm_phys_base + 0x18
+        }else {
+            self.pcm_phys_base + 0x04
+        };
+
+        let mut violations = vec![];
+        let mut index = 0;
+        let mut length = 0;
+        loop {
+            let cbp = unsafe { &(*cbs)[index] };
+            let (src, dst, cb_length, stride, next) = (cbp.src.read(), cbp.dst.read(), cbp.length.read(), cbp.stride.read(), cbp.next.read());
+            length += 1;
+
+            if (src as usize) < samples_phys || (src as usize) >= samples_end {
+                violations.push(ChainViolation::Source { index, src });
+            }
+            let dst_ok = if self.layout.cb_samples[index].is_some() {
+                dst as usize == phys_gpset0 || dst as usize == phys_gpclr0
+            } else {
+                dst as usize == phys_fifo_addr
+            };
+            if !dst_ok {
+                violations.push(ChainViolation::Destination { index, dst });
+            }
+            if cb_length != 4 {
+                violations.push(ChainViolation::Length { index, length: cb_length });
+            }
+            if stride != 0 {
+                violations.push(ChainViolation::Stride { index, stride });
+            }
+
+            let offset = (next as usize).wrapping_sub(cbs_phys);
+            if (next as usize) < cbs_phys || offset % cb_size != 0 || offset / cb_size >= expected {
+                return Err(ChainError::Broken { index, next });
+            }
+            index = offset / cb_size;
+            if index == 0 {
+                break;
+            }
+            if length > expected {
+                return Err(ChainError::WrongLength { length, expected });
+            }
+        }
+
+        let report = ChainReport { length, violations };
+        if report.length != expected {
+            Err(ChainError::WrongLength { length: report.length, expected })
+        } else if !report.violations.is_empty() {
+            Err(ChainError::Violations(report))
+        } else {
+            Ok(report)
+        }
+    }
+
+    /// Requested and achievable DMA sample rates for this configuration.
+    pub fn dma_feasibility(&self) -> DmaFeasibility {
+        self.dma_feasibility
+    }
+
+    /// print info about the hardware: PWM or PCM, Number of channels, Pins being used, sample frequency, PWM Frequency, PWM steps, Maximum Period, Minimum Period,
+    /// samples, control blocks and DMA memory allocated, and DMA Base Address.
+    pub fn print_info(&self) {
+        let info = self.info();
+        println!("Using hardware:\t\t\t{:}", info.pacing);
+        println!("Number of channels:\t\t{}", info.pins.len());
+        println!("Pins:\t\t\t\t{:?}", info.pins);
+        println!("PLLD frequency:\t\t\t{} Hz", info.plld_frequency);
+        println!("Sample frequency:\t\t{} Hz (PLLD / {} divisor {} / {} {})", info.sample_frequency, info.pacing, info.pacing_divisor,
+                 if info.pacing == "PWM" { "range" } else { "frame" }, info.pacing_clocks);
+        println!("Clock divisor:\t\t\t{} (read back from {}CLK_DIV)", info.clock_divisor, info.pacing);
+        for group in &info.groups {
+            if info.groups.len() > 1 {
+                println!("Group:\t\t\t\t{:?}", group.pins);
+            }
+            println!("PWM frequency:\t\t\t{} Hz", group.pwm_frequency);
+            println!("PWM steps:\t\t\t{}", group.pwm_steps);
+            println!("Maximum period (100 %):\t{} us", group.max_period_us);
+            println!("Minimum period ({:3} %):\t{} us", 100.0 * group.min_period_us / group.max_period_us, group.min_period_us);
+        }
+        let (samples, control_blocks, bytes) = self.ctl_allocation();
+        println!("Samples:\t\t\t{}", samples);
+        println!("Control blocks:\t\t\t{}", control_blocks);
+        println!("DMA memory:\t\t\t{} bytes ({} pages, {} in use)", bytes, self.mbox_pages, self.layout.num_pages);
+        println!("DMA Base:\t\t\t{:#010x}", info.dma_base);
+    }
+
+    /// Writes out all informations about the control blocks, PWM, Clock, GPIO and DMA, e.g. to
+    /// stderr, a file or a buffer to send elsewhere.
+    ///
+    /// ## Example
+    /// ```no_run
+    /// use dma_gpio::pi::BoardBuilder;
+    ///
+    /// fn main() {
+    ///     let board = BoardBuilder::new().build_with_pins(vec![21]).unwrap();
+    ///     board.debug_dump_hw(&mut std::io::stderr()).unwrap();
+    /// }
+    /// ```
+    pub fn debug_dump_hw<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        writeln!(out)?;
+        writeln!(out, "pwm_reg: {:?}\n", self.pwm_reg)?;
+
+        let cbs = self.control_blocks();
         let mut cbp;
 
-        for i in 0..self.num_samples {
+        for i in 0..self.layout.cb_samples.len() {
             unsafe{
-                cbp = &(*ctl_ptr).cb[i];
+                cbp = &(*cbs)[i];
             }
-            trace!("DMA Control Block: #{} @{:?}", i, cbp as *const DmaCbT);
-            trace!("info:\t{:#010x}", cbp.info.read());
-            trace!("src:\t{:#010x}", cbp.src.read());
-            trace!("dst:\t{:#010x}", cbp.dst.read());
-            trace!("length:\t{:#010x}", cbp.length.read());
-            trace!("stride:\t{:#010x}", cbp.stride.read());
-            trace!("next:\t{:#010x}\n", cbp.next.read());
+            writeln!(out, "DMA Control Block: #{} @{:?}", i, cbp as *const DmaCbT)?;
+            writeln!(out, "info:\t{:#010x}", cbp.info.read())?;
+            writeln!(out, "src:\t{:#010x}", cbp.src.read())?;
+            writeln!(out, "dst:\t{:#010x}", cbp.dst.read())?;
+            writeln!(out, "length:\t{:#010x}", cbp.length.read())?;
+            writeln!(out, "stride:\t{:#010x}", cbp.stride.read())?;
+            writeln!(out, "next:\t{:#010x}\n", cbp.next.read())?;
         }
 
-        trace!("PWM_BASE:\t{:#010x}", self._pwm_base);
-        trace!("PWM_REG:\t{:?}", self.pwm_reg);
+        writeln!(out, "PWM_BASE:\t{:#010x}", self._pwm_base)?;
+        writeln!(out, "PWM_REG:\t{:?}", self.pwm_reg)?;
         unsafe {
             for i in 0..(PWM_LEN/4) {
-                trace!("{:#04X}: {:#010x} {:#010x}", i, self.pwm_reg as usize + 4*i, (*self.pwm_reg)[i].read());
+                writeln!(out, "{:#04X}: {:#010x} {:#010x}", i, self.pwm_reg as usize + 4*i, (*self.pwm_reg)[i].read())?;
             }
         }
-        trace!("\n");
-        trace!("CLK_BASE: {:#010x}", self._clk_base);
-        trace!("PWMCLK_CNTL: {:#010x}", PWMCLK_CNTL);
-        trace!("clk_reg[PWMCLK_CNTL]: {:#010x}", self.clk_reg as usize + 4*PWMCLK_CNTL);
-        trace!("PWMCLK_DIV: {:#010x}", PWMCLK_DIV);
-        trace!("clk_reg: {:?}", self.clk_reg);
-        trace!("virt_to_phys(clk_reg): {:#010x}", self.virt_to_uncached_phys(self.clk_reg as *const usize));
+        writeln!(out)?;
+        writeln!(out, "CLK_BASE: {:#010x}", self._clk_base)?;
+        writeln!(out, "PWMCLK_CNTL: {:#010x}", PWMCLK_CNTL)?;
+        writeln!(out, "clk_reg[PWMCLK_CNTL]: {:#010x}", self.clk_reg as usize + 4*PWMCLK_CNTL)?;
+        writeln!(out, "PWMCLK_DIV: {:#010x}", PWMCLK_DIV)?;
+        writeln!(out, "clk_reg: {:?}", self.clk_reg)?;
+        writeln!(out, "virt_to_phys(clk_reg): {:#010x}", self.virt_to_uncached_phys(self.clk_reg as *const usize))?;
         unsafe {
             for i in 0..(CLK_LEN/4) {
-                trace!("{:#04X}: {:#010x} {:#010x}", i, self.clk_reg as usize + 4*i, (*self.clk_reg)[i].read());
+                writeln!(out, "{:#04X}: {:#010x} {:#010x}", i, self.clk_reg as usize + 4*i, (*self.clk_reg)[i].read())?;
             }
         }
-        trace!("\n");
-        trace!("DMA_BASE: {:#010x}", self.dma_base);
-        trace!("dma_virt_base: {:?}", self._dma_virt_base);
-        trace!("dma_reg: {:?}", self.dma_reg);
-        trace!("virt_to_phys(dma_reg): {:#010x}", self.virt_to_uncached_phys(self.dma_reg as *const usize));
+        writeln!(out)?;
+        writeln!(out, "DMA_BASE: {:#010x}", self.dma_base)?;
+        writeln!(out, "dma_virt_base: {:?}", self._dma_virt_base)?;
+        writeln!(out, "dma_reg: {:?}", self.dma_reg)?;
+        writeln!(out, "virt_to_phys(dma_reg): {:#010x}", self.virt_to_uncached_phys(self.dma_reg as *const usize))?;
         unsafe {
             for i in 0..(DMA_CHAN_SIZE/4) {
-                trace!("{:#04X}: {:#010x} {:#010x}", i, self.dma_reg as usize + i*4, (*self.dma_reg)[i].read());
+                writeln!(out, "{:#04X}: {:#010x} {:#010x}", i, self.dma_reg as usize + i*4, (*self.dma_reg)[i].read())?;
             }
         }
-        trace!("\n");
-        trace!("GPIO_BASE: {:#010x}", self._gpio_base);
-        trace!("gpio_reg: {:?}", self.gpio_reg);
-        trace!("virt_to_phys(gpio_reg): {:#010x}", self.virt_to_uncached_phys(self.gpio_reg as *const usize));
+        writeln!(out)?;
+        writeln!(out, "GPIO_BASE: {:#010x}", self._gpio_base)?;
+        writeln!(out, "gpio_reg: {:?}", self.gpio_reg)?;
+        writeln!(out, "virt_to_phys(gpio_reg): {:#010x}", self.virt_to_uncached_phys(self.gpio_reg as *const usize))?;
         unsafe {
             for i in 0..(GPIO_LEN/4) {
-                trace!("{:#04X}: {:#010x} {:#010x}", i, self.gpio_reg as usize + i*4, (*self.gpio_reg)[i].read());
+                writeln!(out, "{:#04X}: {:#010x} {:#010x}", i, self.gpio_reg as usize + i*4, (*self.gpio_reg)[i].read())?;
             }
         }
+        Ok(())
     }
 
-    /// This method is only available when 'debug' feature is on.
-    /// 
-    /// Print out info about samples' outputs.
-    #[cfg(feature = "debug")]
-    pub fn debug_dump_samples(&self) {
-        let ctl_ptr = self.mbox.virt_addr as *const Ctl;
+    /// Writes out every sample word, as [debug_dump_hw](#method.debug_dump_hw) does registers.
+    pub fn debug_dump_samples<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        let samples = self.samples();
 
         unsafe{
-            for i in 0..self.num_samples {
-                trace!("#{} @{:#010x}", i, (*ctl_ptr).sample[i].read());
+            for i in 0..self.layout.num_samples {
+                writeln!(out, "#{} @{:#010x}", i, (*samples)[i].read())?;
             }
         }
+        Ok(())
     }
 }
 
@@ -1476,6 +3889,155 @@ pub fn udelay(us: u64) {
     sleep(nanos);
 }
 
+// Resets the channel and waits for the reset to complete; true when it was running until then.
+fn reset_dma_registers(dma_reg: *const [RW<usize>;DMA_CHAN_SIZE/4]) -> Result<bool, Error> {
+    let started = Instant::now();
+    unsafe {
+        let was_active = (*dma_reg)[DMA_CS].read() & DMA_ACTIVE != 0;
+        (*dma_reg)[DMA_CS].write(DMA_RESET);
+        while (*dma_reg)[DMA_CS].read() & DMA_RESET != 0 {
+            if started.elapsed() > DMA_RESET_TIMEOUT {
+                return Err(Error::new(ErrorKind::TimedOut, format!("DMA channel did not finish resetting within {:?}", DMA_RESET_TIMEOUT)))
+            }
+            udelay(1);
+        }
+        (*dma_reg)[DMA_DEBUG].write(7); // clear debug error flags
+        Ok(was_active)
+    }
+}
+
+/// Stops DMA channel ch, e.g. after a process using it was killed before it could clean up and
+/// left it running through memory that has since been freed. Needs root, as Board does, but no Board.
+///
+/// [BoardBuilder::build](struct.BoardBuilder.html#method.build) resets its channel itself; this is for
+/// cleaning up without setting up a Board. The channel must be one the firmware reports as free for the ARM,
+/// as for [use_dma_channel](struct.BoardBuilder.html#method.use_dma_channel).
+///
+/// ## Example
+/// ```no_run
+/// use dma_gpio::pi;
+///
+/// fn main() {
+///     pi::reset_dma_channel(14).unwrap();
+/// }
+/// ```
+pub fn reset_dma_channel(ch: usize) -> Result<(), Error> {
+    let mbox_handle = Board::mbox_open()?;
+    let model_settings = mailbox::get_board_revision(mbox_handle)
+        .and_then(|revision| Board::get_model(revision as usize, false))
+        .and_then(|model_settings| {
+            let free_channels = mailbox::get_dma_channels(mbox_handle)? as usize;
+            check_dma_channel(ch, free_channels & model_settings.usable_dma_channels)?;
+            Ok(model_settings)
+        });
+    let _ = Board::mbox_close(mbox_handle);
+    let model_settings = model_settings?;
+
+    let len = DMA_CHAN_SIZE * (DMA_CHAN_MAX + 1);
+    let dma_virt_base = Board::map_peripheral(0x00007000 + model_settings.periph_virt_base, len)?;
+    let dma_reg = (dma_virt_base as usize + ch * DMA_CHAN_SIZE) as *const [RW<usize>;DMA_CHAN_SIZE/4];
+    let result = reset_dma_registers(dma_reg);
+    unsafe {
+        libc::munmap(dma_virt_base, len);
+    }
+    match result {
+        Ok(true) => {
+            info!("DMA channel {} was active; reset it", ch);
+            Ok(())
+        },
+        Ok(false) => Ok(()),
+        Err(e) => Err(e)
+    }
+}
+
+/// Checks ch is a DMA channel this crate can use and is set in free_channels, the mailbox's bitmask
+/// of channels free for the ARM (less any the board model cannot use); the error lists the ones that are.
+pub fn check_dma_channel(ch: usize, free_channels: usize) -> Result<(), Error> {
+    let available: Vec<usize> = (0..=DMA_CHAN_MAX).filter(|c| free_channels & (1 << c) != 0).collect();
+    if ch > DMA_CHAN_MAX {
+        Err(Error::new(ErrorKind::Other, format!("DMA channel {} is out of range 0 to {}; available channels: {:?}", ch, DMA_CHAN_MAX, available)))
+    } else if free_channels & (1 << ch) == 0 {
+        Err(Error::new(ErrorKind::Other, format!("DMA channel {} is in use by the firmware or not usable on this model; available channels: {:?}", ch, available)))
+    } else {
+        Ok(())
+    }
+}
+
+/// PCM clock divisor and frame length, in clocks, that pace one sample every divisor * sample_delay PLLD
+/// clocks, as PWM pacing does with [divide_pwm](struct.BoardBuilder.html#method.divide_pwm)(divisor) and its
+/// range set to sample_delay. None when no frame of 8 to 1024 clocks at a divisor up to
+/// [MAX_PCM_DIVISOR](constant.MAX_PCM_DIVISOR.html) multiplies out to it.
+///
+/// A frame has to hold the 8 bit channel, so sample delays below 8 get a longer frame on a faster clock;
+/// otherwise the divisor and sample delay are used as they are.
+pub fn pcm_pacing(divisor: usize, sample_delay: usize) -> Option<(usize, usize)> {
+    let clocks = divisor * sample_delay;
+    if (MIN_PCM_FRAME..=MAX_PCM_FRAME).contains(&sample_delay) && (1..=MAX_PCM_DIVISOR).contains(&divisor) {
+        return Some((divisor, sample_delay))
+    }
+    (MIN_PCM_FRAME..=MAX_PCM_FRAME)
+        .filter(|frame| clocks % frame == 0 && (1..=MAX_PCM_DIVISOR).contains(&(clocks / frame)))
+        .map(|frame| (clocks / frame, frame))
+        .next()
+}
+
+// DMA channels and pacing peripherals held by the Boards of this process. Without them a second
+// Board would take over the first one's DMA channel or reprogram its PWM or PCM.
+static CLAIMED_DMA_CHANNELS: Mutex<BTreeSet<usize>> = Mutex::new(BTreeSet::new());
+static CLAIMED_PACING: Mutex<BTreeSet<u8>> = Mutex::new(BTreeSet::new());
+
+/// DMA channels held by the Boards of this process, dry-run ones included.
+pub fn claimed_dma_channels() -> Vec<usize> {
+    CLAIMED_DMA_CHANNELS.lock().unwrap().iter().cloned().collect()
+}
+
+fn pacing_name(delay_hw: u8) -> &'static str {
+    if delay_hw == DELAY_VIA_PWM { "PWM" } else { "PCM" }
+}
+
+// What a Board holds in CLAIMED_DMA_CHANNELS and CLAIMED_PACING; given back when dropped, so a
+// build that fails half way releases it too.
+struct Claims {
+    delay_hw: u8,
+    dma_channel: Option<usize>,
+}
+
+impl Claims {
+    fn pacing(delay_hw: u8) -> Result<Claims, BuildError> {
+        if !CLAIMED_PACING.lock().unwrap().insert(delay_hw) {
+            return Err(BuildError::PacingInUse(pacing_name(delay_hw)))
+        }
+        Ok(Claims { delay_hw, dma_channel: None })
+    }
+
+    // requested as given to use_dma_channel. Without it the default channel, or when another Board
+    // has that the next free one below it, then above it.
+    fn dma_channel(&mut self, requested: Option<usize>, default: usize, free_channels: usize) -> Result<usize, BuildError> {
+        let mut claimed = CLAIMED_DMA_CHANNELS.lock().unwrap();
+        let ch = match requested {
+            Some(ch) if claimed.contains(&ch) => return Err(BuildError::DmaChannelInUse(ch)),
+            Some(ch) => ch,
+            None if !claimed.contains(&default) => default,
+            None => (0..default).rev().chain(default + 1..=DMA_CHAN_MAX)
+                .find(|ch| free_channels & (1 << ch) != 0 && !claimed.contains(ch))
+                .ok_or(BuildError::DmaChannelInUse(default))?
+        };
+        check_dma_channel(ch, free_channels)?;
+        claimed.insert(ch);
+        self.dma_channel = Some(ch);
+        Ok(ch)
+    }
+}
+
+impl Drop for Claims {
+    fn drop(&mut self) {
+        CLAIMED_PACING.lock().unwrap().remove(&self.delay_hw);
+        if let Some(ch) = self.dma_channel {
+            CLAIMED_DMA_CHANNELS.lock().unwrap().remove(&ch);
+        }
+    }
+}
+
 /// Check if the pin provided is found in the list of BANNED pins.
 pub fn is_banned_pin(pin: u8) -> bool {
     for i in 0..BANNED_PINS.len() {
@@ -1486,3 +4048,805 @@ pub fn is_banned_pin(pin: u8) -> bool {
     false
 }
 
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::MutexGuard;
+
+    // Boards claim their pacing hardware, so tests that build one take turns.
+    static BOARDS: Mutex<()> = Mutex::new(());
+
+    fn lock_boards() -> MutexGuard<'static, ()> {
+        BOARDS.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    #[test]
+    fn divisor_over_the_pwm_limit_is_reported_as_adjusted() {
+        let _boards = lock_boards();
+        match BoardBuilder::new().dry_run(true).divide_pwm(1500).build_with_pins(vec![20]) {
+            Err(BuildError::InvalidDivisor(1500)) => {},
+            other => panic!("strict divisor 1500: {:?}", other.map(|board| board.info().pacing_divisor))
+        }
+
+        let board = BoardBuilder::new().dry_run(true).strict(false).divide_pwm(1500).build_with_pins(vec![20]).unwrap();
+        assert_eq!(board.adjusted_settings(), &[AdjustedSetting { name: "pwm_divisor", requested: 1500, effective: MAX_PWM_DIVISOR }]);
+        assert_eq!(board.info().pacing_divisor, MAX_PWM_DIVISOR);
+    }
+
+    const IDLE_TIME: Duration = Duration::from_millis(30);
+
+    fn dma_active(board: &Board) -> bool {
+        board.read_register(Peripheral::Dma, DMA_CS).unwrap() as usize & DMA_ACTIVE != 0
+    }
+
+    #[test]
+    fn direction_pin_may_be_a_banned_pin_but_not_a_pwm_channel() {
+        let _boards = lock_boards();
+        let mut board = BoardBuilder::new().dry_run(true).build_with_pins(vec![20, 21]).unwrap();
+        board.bind_direction(20, 6, true).unwrap();
+        assert!(board.bind_direction(20, 21, true).is_err());
+        assert!(board.bind_direction(20, MAX_CHANNELS as u8, true).is_err());
+    }
+
+    #[test]
+    fn dry_run_dma_reports_no_errors() {
+        let _boards = lock_boards();
+        let board = BoardBuilder::new().dry_run(true).build_with_pins(vec![20]).unwrap();
+        let status = board.dma_status();
+        assert!(status.active);
+        assert!(!status.has_error(), "{:?}", status);
+    }
+
+    #[test]
+    fn board_left_at_zero_goes_idle_on_poll() {
+        let _boards = lock_boards();
+        let mut board = BoardBuilder::new().dry_run(true).set_auto_idle_time(IDLE_TIME).build_with_pins(vec![20]).unwrap();
+        board.set_pwm(20, 0.0).unwrap();
+        board.poll_idle();
+        assert!(!board.is_idle());
+        assert!(dma_active(&board));
+
+        sleep(IDLE_TIME * 2);
+        board.poll_idle();
+        assert!(board.is_idle());
+        assert!(!dma_active(&board));
+        assert!(!board.is_paused());
+    }
+
+    #[test]
+    fn nonzero_width_never_goes_idle() {
+        let _boards = lock_boards();
+        let mut board = BoardBuilder::new().dry_run(true).set_auto_idle_time(IDLE_TIME).build_with_pins(vec![20, 21]).unwrap();
+        board.set_pwm(20, 0.0).unwrap();
+        board.set_pwm(21, 0.25).unwrap();
+        sleep(IDLE_TIME * 2);
+        board.poll_idle();
+        assert!(!board.is_idle());
+        assert!(dma_active(&board));
+    }
+
+    #[test]
+    fn setting_a_width_resumes_from_idle() {
+        let _boards = lock_boards();
+        let mut board = BoardBuilder::new().dry_run(true).set_auto_idle_time(IDLE_TIME).build_with_pins(vec![20]).unwrap();
+        board.set_pwm(20, 0.0).unwrap();
+        sleep(IDLE_TIME * 2);
+        board.poll_idle();
+        assert!(board.is_idle());
+
+        board.set_pwm(20, 0.5).unwrap();
+        assert!(!board.is_idle());
+        assert!(dma_active(&board));
+
+        // The time at zero starts again from the next zero
+        board.set_pwm(20, 0.0).unwrap();
+        board.poll_idle();
+        assert!(!board.is_idle());
+    }
+
+    #[test]
+    fn poll_does_nothing_without_auto_idle() {
+        let _boards = lock_boards();
+        let mut board = BoardBuilder::new().dry_run(true).build_with_pins(vec![20]).unwrap();
+        board.set_pwm(20, 0.0).unwrap();
+        sleep(IDLE_TIME * 2);
+        board.poll_idle();
+        assert!(!board.is_idle());
+        assert!(dma_active(&board));
+    }
+
+    fn width_of(board: &Board, pin: u8) -> Option<f32> {
+        board.dump_samples_structured().channels.iter().find(|channel| channel.pin == pin).map(|channel| channel.width)
+    }
+
+    fn on_samples(dump: &SampleDump, pin: u8) -> usize {
+        (0..dump.pin_masks(pin).len()).filter(|&sample| dump.is_on(pin, sample)).count()
+    }
+
+    #[test]
+    fn sample_masks_follow_the_widths_set() {
+        let _boards = lock_boards();
+        let mut board = BoardBuilder::new().dry_run(true).build_with_pins(vec![20, 26]).unwrap();
+        assert!(board.is_dry_run());
+
+        for (left, right) in [(0.25, 0.75), (0.0, 1.0), (0.5, 0.005)].iter() {
+            board.set_pwm(20, *left).unwrap();
+            board.set_pwm(26, *right).unwrap();
+
+            let dump = board.dump_samples_structured();
+            for channel in &dump.channels {
+                let expected = if channel.width > 0.0 {
+                    constrained_off_index(channel.width, channel.constraint, dump.masks.len()).unwrap_or(dump.masks.len())
+                } else {
+                    0
+                };
+                assert_eq!(on_samples(&dump, channel.pin), expected, "pin {} at {}", channel.pin, channel.width);
+            }
+        }
+        board.verify_cb_chain().unwrap();
+    }
+
+    #[test]
+    fn memory_follows_the_configuration() {
+        let _boards = lock_boards();
+        let board = BoardBuilder::new().dry_run(true).build_with_pins(vec![20, 26]).unwrap();
+        // The motors' 200/2 needs 100 samples, the default 2000/10 twice that; PCM paced, as board has PWM
+        let small = BoardBuilder::new().dry_run(true).use_pcm().set_cycle_time(200).set_sample_delay(2).build_with_pins(vec![20]).unwrap();
+        let (samples, control_blocks, bytes) = small.ctl_allocation();
+        assert_eq!((samples, control_blocks), (100, 200));
+        assert_eq!(board.ctl_allocation().0, NUM_SAMPLES);
+        assert!(bytes < board.ctl_allocation().2);
+    }
+
+    #[test]
+    fn pulses_are_never_shorter_than_min_on_nor_the_gap_than_min_off() {
+        let _boards = lock_boards();
+        let mut board = BoardBuilder::new().dry_run(true).build_with_pins(vec![20, 26]).unwrap();
+        let num_samples = board.info().pwm_steps;
+
+        for (min_on, min_off) in [(0.0, 0.0), (0.1, 0.0), (0.0, 0.1), (0.2, 0.3), (0.05, 0.95), (0.5, 0.5)].iter() {
+            board.set_pulse_constraints(20, *min_on, *min_off).unwrap();
+            for step in 0..=100 {
+                let width = step as f32 / 100.0;
+                board.set_pwm(20, width).unwrap();
+
+                let dump = board.dump_samples_structured();
+                let channel = dump.channels.iter().find(|channel| channel.pin == 20).unwrap();
+                let on_samples = on_samples(&dump, 20);
+                let on = on_samples as f32 / num_samples as f32;
+                let at = format!("{}, {} at {}", min_on, min_off, width);
+
+                if *min_on == 0.0 && *min_off == 0.0 {
+                    let expected = if width > 0.0 { quantized_off_index(width, num_samples).unwrap_or(num_samples) } else { 0 };
+                    assert_eq!(on_samples, expected, "{}", at);
+                }
+                assert!(on_samples == 0 || on >= *min_on, "{}: runt pulse of {}", at, on);
+                assert!(on <= 1.0 - *min_off + 0.5 / num_samples as f32, "{}: on for {}", at, on);
+                assert_eq!(on_samples == 0, width < *min_on || width == 0.0 || *min_off == 1.0, "{}: on for {} samples", at, on_samples);
+                assert_eq!(channel.effective_width, on, "{}", at);
+                assert_eq!(board.effective_width(20, width), on, "{}", at);
+            }
+        }
+
+        // Stays with the pin across widths
+        board.set_pwm(20, 1.0).unwrap();
+        assert_eq!(board.info().pulse_constraints[0], PulseConstraint { min_on: 0.5, min_off: 0.5 });
+    }
+
+    #[test]
+    fn impossible_pulse_constraints_are_refused_and_the_old_one_stays() {
+        let _boards = lock_boards();
+        let mut board = BoardBuilder::new().dry_run(true).build_with_pins(vec![20, 26]).unwrap();
+        board.set_pulse_constraints(20, 0.5, 0.5).unwrap();
+
+        for (min_on, min_off) in [(0.6, 0.5), (-0.1, 0.0), (0.0, 1.1), (f32::NAN, 0.0)].iter() {
+            assert!(board.set_pulse_constraints(20, *min_on, *min_off).is_err(), "accepted {}, {}", min_on, min_off);
+        }
+        assert!(board.set_pulse_constraints(21, 0.1, 0.1).is_err());
+        assert_eq!(board.pulse_constraint(20), PulseConstraint { min_on: 0.5, min_off: 0.5 });
+    }
+
+    #[test]
+    fn widths_past_the_ends_are_rejected_and_leave_the_pin_as_it_was() {
+        let _boards = lock_boards();
+        let mut board = BoardBuilder::new().dry_run(true).build_with_pins(vec![20, 26]).unwrap();
+        let above_one = 1.0 + f32::EPSILON;
+
+        for width in [0.0, 1.0, -0.0].iter() {
+            board.set_pwm(20, 0.5).unwrap();
+            board.set_pwm(20, *width).unwrap();
+            assert_eq!(width_of(&board, 20), Some(*width));
+        }
+        for width in [above_one, -f32::EPSILON, f32::NAN, f32::INFINITY].iter() {
+            board.set_pwm(20, 0.5).unwrap();
+            assert!(board.set_pwm(20, *width).is_err(), "set_pwm({}) accepted", width);
+            assert_eq!(width_of(&board, 20), Some(0.5), "rejected {}", width);
+        }
+    }
+
+    #[test]
+    fn clamped_widths_saturate_all_but_nan() {
+        let _boards = lock_boards();
+        let mut board = BoardBuilder::new().dry_run(true).build_with_pins(vec![20, 26]).unwrap();
+
+        for (width, expected) in [(1.0 + f32::EPSILON, 1.0), (-1.0, 0.0), (f32::INFINITY, 1.0), (0.25, 0.25)].iter() {
+            board.set_pwm(20, 0.5).unwrap();
+            board.set_pwm_clamped(20, *width).unwrap();
+            assert_eq!(width_of(&board, 20), Some(*expected), "clamped {}", width);
+        }
+        assert!(board.set_pwm_clamped(20, f32::NAN).is_err());
+        assert!(board.set_pwm_clamped(21, 0.5).is_err());
+    }
+
+    #[test]
+    fn multi_pwm_lands_together_or_not_at_all() {
+        let _boards = lock_boards();
+        let mut board = BoardBuilder::new().dry_run(true).build_with_pins(vec![20, 26]).unwrap();
+        let widths = |board: &Board| (width_of(board, 20), width_of(board, 26));
+
+        board.set_multi_pwm(&[(20, 0.25), (26, 0.75)]).unwrap();
+        assert_eq!(widths(&board), (Some(0.25), Some(0.75)));
+
+        let invalid: [&[(u8, f32)]; 5] = [&[(20, 0.5), (21, 0.5)], &[(20, 0.5), (26, 1.5)], &[(20, 0.5), (26, f32::NAN)],
+                                          &[(20, 0.5), (20, 0.6)], &[(20, -0.5)]];
+        for updates in invalid.iter() {
+            assert!(board.set_multi_pwm(updates).is_err(), "accepted {:?}", updates);
+            assert_eq!(widths(&board), (Some(0.25), Some(0.75)), "rejected {:?}", updates);
+        }
+    }
+
+    #[test]
+    fn plain_outputs_stay_off_the_pwm_channels() {
+        let _boards = lock_boards();
+        let mut board = BoardBuilder::new().dry_run(true).build_with_pins(vec![20, 26]).unwrap();
+        board.set_pwm(20, 0.5).unwrap();
+
+        board.set_mode_output(5).unwrap();
+        board.set_output(5, true).unwrap();
+        board.set_output(5, false).unwrap();
+
+        // 26 has not been set yet; 6 is banned, 47 and 32 out of range
+        for pin in [20, 26, 6, 47, 32].iter() {
+            assert!(board.set_mode_output(*pin).is_err(), "mode of {}", pin);
+            assert!(board.set_output(*pin, true).is_err(), "level of {}", pin);
+        }
+    }
+
+    #[test]
+    fn pwm_channels_can_be_read_but_not_turned_into_inputs() {
+        let _boards = lock_boards();
+        let mut board = BoardBuilder::new().dry_run(true).build_with_pins(vec![20, 26]).unwrap();
+
+        board.set_mode_input(16).unwrap();
+        for pull in [Pull::Up, Pull::Down, Pull::None].iter() {
+            board.set_pull(16, *pull).unwrap();
+        }
+        board.read_pin(16).unwrap();
+        board.read_pin(20).unwrap();
+
+        assert!(board.set_mode_input(20).is_err());
+        for pin in [6, 47, 32].iter() {
+            assert!(board.read_pin(*pin).is_err(), "read {}", pin);
+            assert!(board.set_mode_input(*pin).is_err(), "mode of {}", pin);
+            assert!(board.set_pull(*pin, Pull::Up).is_err(), "pull on {}", pin);
+        }
+    }
+
+    #[test]
+    fn dry_run_boards_see_their_free_dma_channels() {
+        let _boards = lock_boards();
+        let default = BoardBuilder::new().dry_run(true).build_with_pins(vec![20]).unwrap();
+        assert_eq!(default.info().dma_channel, 14);
+        let board = BoardBuilder::new().dry_run(true).use_pcm().use_dma_channel(10).build_with_pins(vec![20]).unwrap();
+        assert_eq!(board.info().dma_channel, 10);
+        board.verify_cb_chain().unwrap();
+        drop((default, board));
+
+        for channel in [1, 3, 15, 100].iter() {
+            let error = BoardBuilder::new().dry_run(true).use_dma_channel(*channel).build_with_pins(vec![20]).err()
+                .unwrap_or_else(|| panic!("channel {} accepted", channel));
+            assert!(error.to_string().contains("available channels: [0, 2, 4, 5, 8, 9, 10, 11, 12, 13, 14]"), "channel {}: {}", channel, error);
+        }
+    }
+
+    #[test]
+    fn second_board_takes_the_next_channel_and_the_other_pacing() {
+        let _boards = lock_boards();
+        let channel = |board: &Board| board.info().dma_channel;
+        // PWMCLK_CNTL and PWMCLK_DIV
+        let pwm_clock = |board: &Board| (board.read_register(Peripheral::Clk, 40), board.read_register(Peripheral::Clk, 41));
+
+        let motors = BoardBuilder::new().dry_run(true).build_with_pins(vec![20]).unwrap();
+        let leds = BoardBuilder::new().dry_run(true).use_pcm().build_with_pins(vec![21]).unwrap();
+        assert_eq!((channel(&motors), channel(&leds)), (14, 13));
+        assert_eq!(claimed_dma_channels(), vec![13, 14]);
+        assert!(matches!(BoardBuilder::new().dry_run(true).build_with_pins(vec![22]), Err(BuildError::PacingInUse("PWM"))));
+
+        // Dropping the second board leaves the first one's clock running
+        let clock = pwm_clock(&motors);
+        assert_eq!(clock.0.map(|cntl| cntl & 0x1F), Some(0x16));
+        drop(leds);
+        assert_eq!(pwm_clock(&motors), clock);
+
+        assert!(matches!(BoardBuilder::new().dry_run(true).use_pcm().use_dma_channel(14).build_with_pins(vec![21]),
+                         Err(BuildError::DmaChannelInUse(14))));
+        // The failed build gave back PCM
+        let leds = BoardBuilder::new().dry_run(true).use_pcm().build_with_pins(vec![21]).unwrap();
+        assert_eq!(channel(&leds), 13);
+        drop((motors, leds));
+        assert!(claimed_dma_channels().is_empty());
+    }
+
+    #[test]
+    fn pwm_and_pcm_pace_the_same_settings_at_the_same_frequency() {
+        let _boards = lock_boards();
+        let clock_divisor = |board: &Board, div: usize| board.read_register(Peripheral::Clk, div).map_or(0, |div| (div >> 12) & 0xFFF);
+        // Read back from the clock divisors and the PWM range or PCM frame length they program
+        let frequency = |board: &Board| {
+            let info = board.info();
+            let clocks = if info.pacing == "PWM" {
+                // PWMCLK_DIV and PWM_RNG1
+                clock_divisor(board, 41) * board.read_register(Peripheral::Pwm, 4).unwrap_or(0)
+            } else {
+                // PCMCLK_DIV and PCM_MODE_A's FLEN
+                clock_divisor(board, 39) * (board.read_register(Peripheral::Pcm, 2).map_or(0, |mode| (mode >> 10) & 0x3FF) + 1)
+            };
+            (info.plld_frequency as f64 / clocks as f64, info.sample_frequency)
+        };
+
+        // Motors, servos, LEDs and a sample delay below the PCM frame's 8 clocks that is no power of 2
+        for (divisor, cycle_time, sample_delay) in [(1000, 200, 2), (500, 1000, 10), (50, 400, 4), (333, 300, 3)].iter() {
+            let builder = || BoardBuilder::new().dry_run(true).divide_pwm(*divisor).set_cycle_time(*cycle_time).set_sample_delay(*sample_delay);
+            let pwm = builder().build_with_pins(vec![20]).unwrap();
+            let pcm = builder().use_pcm().build_with_pins(vec![21]).unwrap();
+            for (board, cntl) in [(&pwm, 40), (&pcm, 38)].iter() {
+                let info = board.info();
+                assert_eq!(info.clock_divisor, info.pacing_divisor, "{} divisor {}", info.pacing, divisor);
+                // Enabled from PLLD, neither killed nor left busy
+                assert_eq!(board.read_register(Peripheral::Clk, *cntl).unwrap_or(0) & 0xFF, 0x16, "{} divisor {}", info.pacing, divisor);
+            }
+            let (pwm_frequency, pcm_frequency) = (frequency(&pwm), frequency(&pcm));
+            assert_eq!(pwm_frequency.0, pwm_frequency.1, "divisor {}", divisor);
+            assert_eq!(pcm_frequency.0, pcm_frequency.1, "divisor {}", divisor);
+            assert_eq!(pwm_frequency.0, pcm_frequency.0, "divisor {}", divisor);
+        }
+        assert_eq!(pcm_pacing(1000, 2), Some((250, 8)));
+        assert_eq!(pcm_pacing(500, 10), Some((500, 10)));
+    }
+
+    #[test]
+    fn pcm_takes_divisors_pwm_does_not() {
+        let _boards = lock_boards();
+        let builder = || BoardBuilder::new().dry_run(true).divide_pwm(2000);
+        assert!(matches!(builder().build_with_pins(vec![20]), Err(BuildError::InvalidDivisor(2000))));
+
+        // PCM's 12 bit divider takes more than PWM's
+        let pcm = builder().use_pcm().build_with_pins(vec![21]).unwrap();
+        assert_eq!(pcm.info().pacing_divisor, 2000);
+        assert!(pcm.adjusted_settings().is_empty());
+        drop(pcm);
+
+        let pcm = builder().strict(false).divide_pwm(5000).use_pcm().build_with_pins(vec![21]).unwrap();
+        assert_eq!(pcm.info().pacing_divisor, MAX_PCM_DIVISOR);
+        drop(pcm);
+
+        // 4093 is prime: no frame of 8 clocks or more divides it
+        assert!(matches!(BoardBuilder::new().dry_run(true).use_pcm().divide_pwm(4093).set_sample_delay(1).build_with_pins(vec![21]),
+                         Err(BuildError::PcmPacing { divisor: 4093, sample_delay: 1 })));
+    }
+
+    #[test]
+    fn revisions_of_other_models_build_with_their_clock_and_channel() {
+        let _boards = lock_boards();
+        let build = |revision: usize| BoardBuilder::new().dry_run(true).dry_run_revision(revision).build_with_pins(vec![20]);
+
+        // Pi 4s run PLLD at 750 MHz and default to a DMA lite channel
+        for (revision, model) in [(0x900092, 1), (0xa01041, 2), (0x902120, 3), (0xa020d3, 3), (0xc03111, 4), (0xc03130, 4), (0xb03140, 4)].iter() {
+            let info = build(*revision).unwrap().info();
+            let (plld, dma_channel) = if *model == 4 { (PI4_PWM_CLOCK_HZ, 7) } else { (PWM_CLOCK_HZ, 14) };
+            let frequency = plld as f64 / (DEFAULT_PWM_DIVISOR * DEFAULT_CYCLE_TIME) as f64;
+            assert_eq!((info.model, info.dma_channel, info.pwm_frequency), (*model, dma_channel, frequency), "{:#x}", revision);
+        }
+
+        assert!(BoardBuilder::new().dry_run(true).dry_run_revision(0xc03111).use_dma_channel(14).build_with_pins(vec![20]).is_err());
+        // A Pi 5 and an unknown processor
+        for revision in [0xd04170, 0xc07200].iter() {
+            assert!(build(*revision).is_err(), "{:#x} accepted", revision);
+        }
+    }
+
+    #[test]
+    fn widths_survive_a_pause_and_the_dma_follows_it() {
+        let _boards = lock_boards();
+        let mut board = BoardBuilder::new().dry_run(true).build_with_pins(vec![20, 26]).unwrap();
+        board.set_pwm(20, 0.25).unwrap();
+        assert!(board.resume().is_err());
+
+        board.pause().unwrap();
+        assert!(board.is_paused());
+        assert!(!board.dma_status().active);
+        assert!(board.pause().is_err());
+        board.set_pwm(26, 0.5).unwrap();
+
+        board.resume().unwrap();
+        assert!(!board.is_paused());
+        assert!(board.dma_status().active);
+        assert_eq!((width_of(&board, 20), width_of(&board, 26)), (Some(0.25), Some(0.5)));
+        assert_eq!(board.dump_samples_structured().masks[0], 1 << 20 | 1 << 26);
+        board.verify_cb_chain().unwrap();
+    }
+
+    #[test]
+    fn reconfigured_timing_keeps_widths_pins_and_memory() {
+        let _boards = lock_boards();
+        let mut board = BoardBuilder::new().dry_run(true).divide_pwm(1000).set_cycle_time(200).set_sample_delay(2).build_with_pins(vec![20, 26]).unwrap();
+        board.set_multi_pwm(&[(20, 0.25), (26, 0.5)]).unwrap();
+        let allocation = board.ctl_allocation();
+
+        // From 2.5 kHz to 5 kHz with 50 steps
+        board.reconfigure_timing(100, 1000, 20).unwrap();
+        let info = board.info();
+        assert_eq!((info.pwm_frequency, info.pwm_steps, info.clock_divisor, info.pins), (5000.0, 50, 100, vec![20, 26]));
+        assert_eq!(board.ctl_allocation(), (50, 100, allocation.2));
+        assert!(board.dma_status().active);
+
+        let dump = board.dump_samples_structured();
+        for (pin, width) in [(20, 0.25), (26, 0.5)].iter() {
+            let channel = dump.channels.iter().find(|channel| channel.pin == *pin).unwrap();
+            assert_eq!(channel.width, *width);
+            assert_eq!(dump.pin_masks(*pin).len(), 50);
+            assert_eq!(Some(on_samples(&dump, *pin)), constrained_off_index(channel.width, channel.constraint, 50), "pin {}", pin);
+        }
+        board.verify_cb_chain().unwrap();
+    }
+
+    #[test]
+    fn reconfiguring_refuses_what_the_builder_and_the_memory_do() {
+        let _boards = lock_boards();
+        let builder = || BoardBuilder::new().dry_run(true).divide_pwm(1000).set_cycle_time(200).set_sample_delay(2);
+        let mut board = builder().build_with_pins(vec![20, 26]).unwrap();
+
+        for (divisor, cycle_time, sample_delay) in [(0, 200, 2), (1000, 150, 2), (1000, 200, 200), (1000, 200, 3)].iter() {
+            let expected = builder().divide_pwm(*divisor).set_cycle_time(*cycle_time).set_sample_delay(*sample_delay)
+                .build_with_pins(vec![20]).err().unwrap().to_string();
+            let error = board.reconfigure_timing(*divisor, *cycle_time, *sample_delay).err().unwrap();
+            assert_eq!(error.to_string(), expected, "{}, {}, {}", divisor, cycle_time, sample_delay);
+        }
+        // 1000 samples do not fit the memory of 100
+        assert!(board.reconfigure_timing(10, 1000, 1).is_err());
+        assert_eq!(board.info().pwm_steps, 100);
+        assert!(board.dma_status().active);
+    }
+
+    #[test]
+    fn paused_board_stays_paused_across_a_reconfiguration() {
+        let _boards = lock_boards();
+        let builder = || BoardBuilder::new().dry_run(true).divide_pwm(1000).set_cycle_time(200).set_sample_delay(2);
+        let mut board = builder().build_with_pins(vec![20, 26]).unwrap();
+
+        board.pause().unwrap();
+        board.reconfigure_timing(50, 400, 4).unwrap();
+        assert!(board.is_paused());
+        assert!(!board.dma_status().active);
+        board.resume().unwrap();
+        assert_eq!(board.info().pwm_frequency, 25000.0);
+        assert!(board.dma_status().active);
+        board.verify_cb_chain().unwrap();
+
+        let mut pcm = builder().use_pcm().build_with_pins(vec![21]).unwrap();
+        pcm.reconfigure_timing(500, 1000, 10).unwrap();
+        let info = pcm.info();
+        assert_eq!(info.pwm_frequency, 1000.0);
+        assert_eq!(info.clock_divisor, info.pacing_divisor);
+        assert!(pcm.dma_status().active);
+    }
+
+    #[test]
+    fn each_pin_follows_its_own_groups_samples() {
+        let _boards = lock_boards();
+        // Motors at 2.5 kHz next to a servo at 50 Hz
+        let mut board = BoardBuilder::new().dry_run(true).set_cycle_time(400).set_sample_delay(4).add_group(20000, 100, vec![26])
+            .build_with_pins(vec![20, 21, 26]).unwrap();
+
+        let groups: Vec<(Vec<u8>, f64, usize)> = board.info().groups.iter().map(|group| (group.pins.clone(), group.pwm_frequency, group.pwm_steps)).collect();
+        assert_eq!(groups, vec![(vec![20, 21], 2500.0, 100), (vec![26], 50.0, 200)]);
+
+        board.set_multi_pwm(&[(20, 0.5), (21, 0.25), (26, 0.075)]).unwrap();
+        let dump = board.dump_samples_structured();
+        for channel in &dump.channels {
+            let num_samples = dump.pin_masks(channel.pin).len();
+            let expected = constrained_off_index(channel.width, channel.constraint, num_samples).unwrap_or(num_samples);
+            assert_eq!(on_samples(&dump, channel.pin), expected, "pin {} at {}", channel.pin, channel.width);
+        }
+        // 5000 slots of 4 units: a pacing block and a motor sample in each, a servo sample in every 25th
+        assert_eq!(board.verify_cb_chain().unwrap().length, 10200);
+    }
+
+    #[test]
+    fn groups_that_do_not_fit_or_match_the_pins_are_refused() {
+        let _boards = lock_boards();
+        let builder = || BoardBuilder::new().dry_run(true).set_cycle_time(400).set_sample_delay(4);
+
+        for ((cycle_time, sample_delay, pins), error) in [
+            ((20000, 100, vec![27]), "not one of the board's pins"),
+            ((20000, 100, vec![]), "has no pins"),
+            ((100, 100, vec![26]), "at least 2 samples"),
+            ((1000000, 1, vec![26]), "the limit is 256 pages"),
+        ].iter() {
+            let got = builder().add_group(*cycle_time, *sample_delay, pins.clone()).build_with_pins(vec![20, 21, 26]).err()
+                .unwrap_or_else(|| panic!("group {:?} accepted", pins)).to_string();
+            assert!(got.contains(error), "group {}, {}, {:?}: {}", cycle_time, sample_delay, pins, got);
+        }
+        let got = builder().add_group(800, 8, vec![26]).add_group(20000, 100, vec![21, 26]).build_with_pins(vec![20, 21, 26]).err()
+            .expect("pin 26 accepted in two groups").to_string();
+        assert!(got.contains("more than one group"), "{}", got);
+    }
+
+    #[test]
+    fn servo_pulses_land_on_whole_samples_and_fit_the_period() {
+        let _boards = lock_boards();
+        let mut board = BoardBuilder::new().dry_run(true).servo_defaults().build_with_pins(vec![20]).unwrap();
+        // 20 ms in 10 us steps
+        assert_eq!((board.period_us(20), board.step_us(20)), (20000.0, 10.0));
+
+        for (pulse_us, samples) in [(1500.0, 150), (1004.0, 100), (10.0, 1), (20000.0, 2000)].iter() {
+            board.set_servo_pulse(20, *pulse_us).unwrap();
+            assert_eq!(on_samples(&board.dump_samples_structured(), 20), *samples, "{} us", pulse_us);
+        }
+        for (pulse_us, error) in [(5.0, "shorter than one sample"), (20010.0, "exceeds the cycle period"), (f64::NAN, "shorter than one sample")].iter() {
+            let got = board.set_servo_pulse(20, *pulse_us).err().unwrap_or_else(|| panic!("{} us accepted", pulse_us)).to_string();
+            assert!(got.contains(error), "{} us: {}", pulse_us, got);
+        }
+    }
+
+    #[test]
+    fn inverted_pins_are_high_while_not_active() {
+        let none = PulseConstraint::default();
+        assert_eq!(high_samples(0.3, none, false, 8), 3);
+        assert_eq!(high_samples(0.3, none, true, 8), 5);
+        assert_eq!(high_samples(0.0, none, true, 8), 8);
+        assert_eq!(high_samples(1.0, none, true, 8), 0);
+
+        // With mixed polarity sample 0 still only sets pins and later samples only clear them
+        assert_eq!(sample_masks(&[(20, 2), (21, 6), (26, 0)], 8), vec![
+            1 << 20 | 1 << 21, 1 << 26, 1 << 20 | 1 << 26, 1 << 20 | 1 << 26, 1 << 20 | 1 << 26, 1 << 20 | 1 << 26,
+            1 << 20 | 1 << 21 | 1 << 26, 1 << 20 | 1 << 21 | 1 << 26]);
+    }
+
+    #[test]
+    fn inverted_pin_is_active_at_the_end_of_the_cycle() {
+        let _boards = lock_boards();
+        let mut board = BoardBuilder::new().dry_run(true).build_with_pins(vec![20, 21]).unwrap();
+        board.set_pin_invert(21, true).unwrap();
+        assert!(board.set_pin_invert(26, true).is_err());
+
+        board.set_multi_pwm(&[(20, 0.25), (21, 0.25)]).unwrap();
+        let dump = board.dump_samples_structured();
+        let num_samples = dump.masks.len();
+        let expected = (board.effective_width(20, 0.25) * num_samples as f32).round() as usize;
+        assert_eq!((on_samples(&dump, 20), on_samples(&dump, 21)), (expected, expected));
+        assert!(dump.is_on(21, num_samples - 1), "{}", dump.waveform());
+        assert!(!dump.is_on(21, 0), "{}", dump.waveform());
+
+        board.set_invert_mode(false);
+        assert!(!board.pin_invert(21));
+        assert!(board.dump_samples_structured().is_on(21, 0));
+    }
+
+    #[test]
+    fn dry_run_writes_exact_masks_and_output_modes() {
+        let _boards = lock_boards();
+        let mut board = BoardBuilder::new().dry_run(true).set_cycle_time(200).set_sample_delay(20).build_with_pins(vec![20, 21]).unwrap();
+        let (b20, b21) = (1 << 20, 1 << 21);
+        let gpio_mode = |board: &Board, pin: usize| board.read_register(Peripheral::Gpio, pin / 10).map(|fsel| (fsel >> (pin % 10 * 3)) & 7);
+
+        board.set_pin_invert(21, true).unwrap();
+        // Off after the first sample past the width: pin 20 high for 3 samples, inverted 21 active for 6 so high for 4
+        board.set_multi_pwm(&[(20, 0.25), (21, 0.5)]).unwrap();
+        assert_eq!(board.dump_samples_structured().masks, vec![b20 | b21, 0, 0, b20, b20 | b21, b20 | b21, b20 | b21, b20 | b21, b20 | b21, b20 | b21]);
+        assert_eq!((gpio_mode(&board, 20), gpio_mode(&board, 21)), (Some(1), Some(1)));
+
+        // Full width is never cleared; the released pin drops out of every mask
+        board.set_pwm(21, 0.0).unwrap();
+        board.set_pwm(20, 1.0).unwrap();
+        board.release_pwm(21).unwrap();
+        let mut expected = vec![0; 10];
+        expected[0] = b20;
+        assert_eq!(board.dump_samples_structured().masks, expected);
+
+        assert_eq!(board.read_register(Peripheral::Gpio, 1000), None);
+    }
+
+    #[test]
+    fn waveforms_play_next_to_pwm_pins() {
+        let (set_masks, clear_masks) = waveform_masks(&[1 << 20, 0, 1 << 20, 1 << 20], &[(21, &[false, true, true, false])]);
+        assert_eq!(set_masks, vec![1 << 20, 1 << 21, 1 << 21, 0]);
+        assert_eq!(clear_masks, vec![1 << 21, 0, 1 << 20, 1 << 20 | 1 << 21]);
+
+        let _boards = lock_boards();
+        let pattern = [true, false, true, false, true, true, false, false, false, false];
+        let builder = || BoardBuilder::new().dry_run(true).set_cycle_time(200).set_sample_delay(20);
+        let mut plain = builder().build_with_pins(vec![20, 21]).unwrap();
+        assert!(plain.set_waveform(21, &pattern).is_err());
+        drop(plain);
+
+        let mut board = builder().waveforms().build_with_pins(vec![20, 21]).unwrap();
+        assert!(board.set_waveform(21, &pattern[1..]).is_err());
+        assert!(board.set_waveform(26, &pattern).is_err());
+        board.set_pwm(20, 0.25).unwrap();
+        board.set_waveform(21, &pattern).unwrap();
+
+        let dump = board.dump_samples_structured();
+        for (sample, high) in pattern.iter().enumerate() {
+            assert_eq!(dump.is_on(21, sample), *high, "sample {}:\n{}", sample, dump.waveform());
+            assert_eq!(dump.is_on(20, sample), sample < 3, "sample {}:\n{}", sample, dump.waveform());
+        }
+        // A set, a clear and a pacing block per sample
+        assert_eq!(board.ctl_allocation().1, 30);
+        board.verify_cb_chain().unwrap();
+
+        board.set_pwm(21, 0.5).unwrap();
+        assert!(board.waveform(21).is_none());
+        assert!(!board.dump_samples_structured().channels.iter().any(|channel| channel.waveform));
+    }
+
+    fn assert_active(board: &Board, expected: Vec<(u8, f32)>) {
+        assert_eq!(board.active_channels(), expected);
+        let pins = expected.iter().fold(0, |all, &(pin, _)| all | (1 << pin));
+        let stray = board.dump_samples_structured().masks.iter().fold(0, |all, mask| all | mask) & !pins;
+        assert_eq!(stray, 0, "masks drive released pins");
+    }
+
+    #[test]
+    fn released_pins_leave_no_hole_behind() {
+        let _boards = lock_boards();
+        let mut board = BoardBuilder::new().dry_run(true).build_with_pins(vec![20, 21, 22]).unwrap();
+
+        board.set_multi_pwm(&[(20, 0.25), (21, 0.5), (22, 0.75)]).unwrap();
+        board.release_pwm(21).unwrap();
+        assert_active(&board, vec![(20, 0.25), (22, 0.75)]);
+        board.set_pwm(22, 0.5).unwrap();
+        assert_active(&board, vec![(20, 0.25), (22, 0.5)]);
+        board.set_pwm(21, 0.25).unwrap();
+        assert_active(&board, vec![(20, 0.25), (22, 0.5), (21, 0.25)]);
+        assert!(board.release_pwm(23).is_err());
+
+        board.release_all_pwm().unwrap();
+        assert_active(&board, vec![]);
+        board.set_pwm(22, 0.25).unwrap();
+        board.set_all_pwm(0.5).unwrap();
+        assert_active(&board, vec![(22, 0.5), (20, 0.5), (21, 0.5)]);
+
+        for cycle in 0..50 {
+            let pin = [20, 21, 22][cycle % 3];
+            board.release_pwm(pin).unwrap();
+            board.set_pwm(pin, 0.25).unwrap();
+        }
+        // Each release moves its pin to the end: 50 cycles end on 21, after 22 and 20
+        assert_active(&board, vec![(22, 0.25), (20, 0.25), (21, 0.25)]);
+        assert!(!board.is_known_pin(0));
+        assert!(board.set_pwm(0, 0.5).is_err());
+    }
+
+    #[test]
+    fn gpio_0_is_a_channel_like_any_other() {
+        let _boards = lock_boards();
+        let mut board = BoardBuilder::new().dry_run(true).build_with_pins(vec![0, 20]).unwrap();
+        assert_eq!(board.info().pins, vec![0, 20]);
+
+        board.set_multi_pwm(&[(0, 0.25), (20, 0.5)]).unwrap();
+        assert_active(&board, vec![(0, 0.25), (20, 0.5)]);
+        board.release_pwm(0).unwrap();
+        assert_active(&board, vec![(20, 0.5)]);
+        board.set_pwm(0, 0.75).unwrap();
+        assert_active(&board, vec![(20, 0.5), (0, 0.75)]);
+        assert_ne!(board.dump_samples_structured().masks[0] & 1, 0);
+        board.release_all_pwm().unwrap();
+        board.set_all_pwm(0.5).unwrap();
+        assert_active(&board, vec![(0, 0.5), (20, 0.5)]);
+    }
+
+    #[test]
+    fn periods_follow_cycle_time_and_running_dma_is_active() {
+        let _boards = lock_boards();
+        let board = BoardBuilder::new().dry_run(true).set_cycle_time(200).set_sample_delay(20).build_with_pins(vec![20, 21]).unwrap();
+        let group = &board.info().groups[0];
+        assert!((group.max_period_us / group.min_period_us - 10.0).abs() < 1e-9);
+        assert!((group.max_period_us - 1_000_000.0 / group.pwm_frequency).abs() < 1e-6);
+
+        // Fake registers keep the 1s written to clear DEBUG, so only CS means anything here
+        let status = board.dma_status();
+        assert!(status.active);
+        assert!(!status.error);
+    }
+
+    #[test]
+    fn each_debug_error_bit_decodes_on_its_own() {
+        for (cs, debug, expected) in [
+            (1, 0, (true, false, false, false, false)),
+            (1 << 8, 4, (false, true, true, false, false)),
+            (0, 2, (false, false, false, true, false)),
+            (1, 1, (true, false, false, false, true)),
+        ].iter() {
+            let status = DmaStatus::from_registers(*cs, *debug);
+            assert_eq!((status.active, status.error, status.read_error, status.fifo_error, status.read_last_not_set), *expected,
+                       "cs {:#x}, debug {:#x}", cs, debug);
+            assert_eq!(status.has_error(), *cs & (1 << 8) != 0 || *debug != 0, "cs {:#x}, debug {:#x}", cs, debug);
+        }
+    }
+
+    #[test]
+    fn out_of_range_settings_fail_to_build() {
+        let _boards = lock_boards();
+        let builder = || BoardBuilder::new().dry_run(true);
+
+        for (result, expected) in vec![
+            (builder().divide_pwm(1250).build_with_pins(vec![20]), "InvalidDivisor(1250)"),
+            (builder().divide_pwm(0).build_with_pins(vec![20]), "InvalidDivisor(0)"),
+            (builder().set_cycle_time(150).build_with_pins(vec![20]), "InvalidCycleTime(150)"),
+            (builder().set_sample_delay(101).build_with_pins(vec![20]), "InvalidSampleDelay(101)"),
+            (builder().set_cycle_time(401).set_sample_delay(4).build_with_pins(vec![20]),
+                "CycleTimeNotMultiple { group: 0, cycle_time: 401, sample_delay: 4 }"),
+            (builder().set_cycle_time(400).set_sample_delay(4).add_group(20050, 100, vec![26]).build_with_pins(vec![20, 26]),
+                "CycleTimeNotMultiple { group: 1, cycle_time: 20050, sample_delay: 100 }"),
+            (builder().add_group(2000000, 1, vec![26]).build_with_pins(vec![20, 26]), "SampleCountTooHigh"),
+            (builder().build_with_pins(vec![6]), "BannedPin(6)"),
+            (builder().build_with_pins(vec![32]), "InvalidPin(32)"),
+            (builder().build_with_pins((1..=33).collect()), "TooManyPins(33)"),
+            (builder().build_with_pins(vec![20, 21, 20]), "DuplicatePin(20)"),
+            (builder().build_with_pins(vec![0, 0]), "DuplicatePin(0)"),
+            (builder().build_with_pins(vec![NO_PIN]), "InvalidPin(255)"),
+        ].into_iter() {
+            let got = format!("{:?}", result.err().unwrap_or_else(|| panic!("built, expected {}", expected)));
+            assert!(got.starts_with(expected), "expected {}, got {}", expected, got);
+        }
+
+        let error: io::Error = builder().build_with_pins(vec![6]).err().unwrap().into();
+        assert!(error.to_string().contains("banned gpio"), "{}", error);
+    }
+
+    #[test]
+    fn out_of_range_settings_are_clamped_unless_strict() {
+        let _boards = lock_boards();
+        let board = BoardBuilder::new().dry_run(true).strict(false).divide_pwm(1250).set_cycle_time(401).set_sample_delay(4)
+            .build_with_pins(vec![20]).unwrap();
+        let adjusted: Vec<(&str, usize, usize)> = board.adjusted_settings().iter().map(|s| (s.name, s.requested, s.effective)).collect();
+        assert_eq!(adjusted, vec![("pwm_divisor", 1250, 1000)]);
+        assert_eq!(board.info().pwm_steps, 100);
+    }
+
+    #[test]
+    fn dma_addresses_stay_in_the_alias_the_mailbox_allocated_in() {
+        for (bus_addr, offset, expected) in [
+                (0x4f000000usize, 0x40, 0x4f000040usize),
+                (0xcf000000, 0x1000, 0xcf001000),
+                (0x8e5fe000, 0x2008, 0x8e600008),
+                (0x3b400000, 0x0, 0x3b400000)].iter() {
+            assert_eq!(bus_address(*bus_addr, *offset), *expected, "{:#010x} + {:#x}", bus_addr, offset);
+        }
+        assert_eq!(expected_bus_alias(mailbox::MEM_FLAG_DIRECT), 0xc0000000);
+        assert_eq!(expected_bus_alias(mailbox::MEM_FLAG_L1_NONALLOCATING | mailbox::MEM_FLAG_ZERO), 0x40000000);
+    }
+
+    #[test]
+    fn unusable_mailbox_allocations_are_refused() {
+        let ram_1g = ram_size(0xa02082);
+        assert_eq!(ram_1g, 1024 * 1024 * 1024);
+        assert_eq!(ram_size(0x0010), 512 * 1024 * 1024);
+
+        for (bus_addr, usable) in [
+            (0xcf000000usize, true),
+            (0x4f000000, true),
+            // Not page aligned
+            (0xcf000100, false),
+            // Beyond 1 GB
+            (0xffff8000, false),
+            // Cached alias
+            (0x0f000000, false),
+        ].iter() {
+            assert_eq!(check_mailbox_memory(*bus_addr, 0x10000, ram_1g).is_ok(), *usable, "{:#010x}", bus_addr);
+        }
+    }
+}
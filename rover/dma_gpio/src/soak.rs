@@ -0,0 +1,75 @@
+//! Leak detection for the soak example: decides from the RSS samples of a long run whether memory
+//! keeps growing once it had time to settle.
+
+
+/// RSS may settle during the first reports; growth after that is treated as a leak.
+pub const MIN_LEAK_SAMPLES: usize = 6;
+/// Growth allowed once settled, in bytes per hour.
+pub const MAX_RSS_GROWTH_PER_HOUR: f64 = 256.0 * 1024.0;
+
+/// Least squares slope of (seconds, bytes) samples, in bytes per second.
+pub fn slope(samples: &[(f64, f64)]) -> f64 {
+    let n = samples.len() as f64;
+    let mean_t = samples.iter().map(|s| s.0).sum::<f64>() / n;
+    let mean_v = samples.iter().map(|s| s.1).sum::<f64>() / n;
+    let covariance: f64 = samples.iter().map(|s| (s.0 - mean_t) * (s.1 - mean_v)).sum();
+    let variance: f64 = samples.iter().map(|s| (s.0 - mean_t) * (s.0 - mean_t)).sum();
+    if variance > 0.0 { covariance / variance } else { 0.0 }
+}
+
+/// Returns a description of the leak if RSS grows faster than allowed.
+pub fn detect_leak(rss_samples: &[(f64, f64)]) -> Option<String> {
+    if rss_samples.len() < MIN_LEAK_SAMPLES {
+        return None;
+    }
+    let settled = &rss_samples[rss_samples.len() / 3..];
+    let growth_per_hour = slope(settled) * 3600.0;
+    if growth_per_hour > MAX_RSS_GROWTH_PER_HOUR {
+        Some(format!("RSS grows by {:.0} bytes/hour", growth_per_hour))
+    } else {
+        None
+    }
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MB: f64 = 1024.0 * 1024.0;
+
+    // A report every 10s for 10 minutes
+    fn samples(rss: impl Fn(f64) -> f64) -> Vec<(f64, f64)> {
+        (0..60).map(|i| i as f64 * 10.0).map(|t| (t, rss(t))).collect()
+    }
+
+    #[test]
+    fn flat_rss_is_not_a_leak() {
+        let flat = samples(|_| 20.0 * MB);
+        assert_eq!(slope(&flat), 0.0);
+        assert_eq!(detect_leak(&flat), None);
+    }
+
+    #[test]
+    fn steady_growth_is_a_leak() {
+        // 1MB an hour
+        let leaking = samples(|t| 20.0 * MB + t * MB / 3600.0);
+        assert!((slope(&leaking) * 3600.0 - MB).abs() < 1.0, "slope {}", slope(&leaking));
+        assert_eq!(detect_leak(&leaking), Some("RSS grows by 1048576 bytes/hour".to_string()));
+    }
+
+    #[test]
+    fn growth_that_settles_early_is_not_a_leak() {
+        // Buffers filling up over the first minute
+        let settling = samples(|t| 20.0 * MB + t.min(60.0) * 64.0 * 1024.0);
+        assert!(slope(&settling) > 0.0);
+        assert_eq!(detect_leak(&settling), None);
+    }
+
+    #[test]
+    fn too_few_samples_are_not_judged() {
+        let short: Vec<(f64, f64)> = samples(|t| t * MB).into_iter().take(MIN_LEAK_SAMPLES - 1).collect();
+        assert_eq!(detect_leak(&short), None);
+    }
+}
@@ -0,0 +1,44 @@
+//
+// Copyright (C) 2020 Abstract Horizon
+// All rights reserved. This program and the accompanying materials
+// are made available under the terms of the Apache License v2.0
+// which accompanies this distribution, and is available at
+// https://www.apache.org/licenses/LICENSE-2.0
+//
+//  Contributors:
+//    Daniel Sendula - initial API and implementation
+//
+
+use std::time::{Duration, Instant};
+
+use mdns_sd::{ServiceDaemon, ServiceEvent};
+
+const SERVICE_TYPES: [&str; 2] = ["_rover-telemetry._tcp.local.", "_rover-mqtt-client._tcp.local."];
+const BROWSE_TIME: Duration = Duration::from_secs(5);
+
+
+fn main() {
+    let daemon = ServiceDaemon::new().expect("Cannot create mDNS daemon");
+
+    let receivers: Vec<_> = SERVICE_TYPES.iter()
+        .map(|service_type| daemon.browse(service_type).expect("Cannot browse for rovers"))
+        .collect();
+
+    println!("Browsing for rovers for {:?}...", BROWSE_TIME);
+
+    let started = Instant::now();
+    while started.elapsed() < BROWSE_TIME {
+        for receiver in receivers.iter() {
+            while let Ok(event) = receiver.recv_timeout(Duration::from_millis(100)) {
+                if let ServiceEvent::ServiceResolved(info) = event {
+                    let properties: Vec<String> = info.get_properties().iter()
+                        .map(|property| format!("{}={}", property.key(), property.val_str()))
+                        .collect();
+                    println!("{}  {:?}:{}  [{}]", info.get_fullname(), info.get_addresses(), info.get_port(), properties.join(", "));
+                }
+            }
+        }
+    }
+
+    let _ = daemon.shutdown();
+}
@@ -0,0 +1,155 @@
+################################################################################
+# Copyright (C) 2020 Abstract Horizon
+# All rights reserved. This program and the accompanying materials
+# are made available under the terms of the Apache License v2.0
+# which accompanies this distribution, and is available at
+# https://www.apache.org/licenses/LICENSE-2.0
+#
+#  Contributors:
+#    Daniel Sendula - initial API and implementation
+#
+#################################################################################
+
+# Generates the golden vectors the Rust filter and PID are checked against (tests/golden_vectors.rs).
+#
+# Each sequence is a few seconds of gyro rates (deg/s, after the gyro's own filter, sensor frame),
+# accel vectors (g, sensor frame) and sample timestamps, shaped like what the sensors report on
+# the rover (sensor x up, sensor z forward), with fixed-seed noise and jitter so the files
+# never change between runs. Next to the inputs each row has:
+#
+#   cx, cy, cz - the complementary filter exactly as in Balance.run_loop
+#   pid        - PID.process(time, 0.0, sin(cy * pi / 90) * 2) with the PID's default gains; the
+#                loop passes a pid_time that is never advanced, here the sample time is used
+#
+# and, where the Rust port intentionally does something else, the values it should produce:
+#
+#   roll       - cx integrates the yaw rate (sensor x) into the roll angle; the port integrates
+#                the roll rate (sensor z)
+#   yaw        - cz pulls the yaw towards an accel tilt, which gravity cannot observe; the port
+#                only integrates the yaw rate
+#   rust_pid   - the port feeds pitch to the PID unshaped, against a set point of -2.6, with the
+#                rover's default gains and the integral term limited to MAX_DUTY
+#
+# Pitch is the same in both, so cy is the expected pitch.
+#
+# Run from src/python:  python3 -m balance.golden_vectors
+
+import math
+import os
+import random
+
+from balance.pid import PID
+
+
+FREQ = 200
+COMBINE_FACTOR_GYRO = 0.95
+
+RUST_SET_POINT = -2.6
+RUST_I_LIMIT = 1.0  # MAX_DUTY
+
+OUTPUT_DIR = os.path.join(os.path.dirname(os.path.abspath(__file__)), "..", "..", "..", "tests", "golden_vectors")
+
+COLUMNS = ["time", "gyro_x", "gyro_y", "gyro_z", "accel_x", "accel_y", "accel_z", "cx", "cy", "cz", "pid", "roll", "yaw", "rust_pid"]
+
+
+def gravity(pitch, roll):
+    # Sensor frame gravity for a rover pitched forward by pitch and leaning sideways by roll (degrees)
+    p = math.radians(pitch)
+    r = math.radians(roll)
+    return math.cos(p) * math.cos(r), math.sin(r), math.sin(p) * math.cos(r)
+
+
+def standing(t):
+    # Held upright by hand: small wobble around slightly forward
+    pitch = 1.5 + 0.8 * math.sin(2 * math.pi * 0.7 * t)
+    pitch_rate = 0.8 * 2 * math.pi * 0.7 * math.cos(2 * math.pi * 0.7 * t)
+    return pitch, pitch_rate, 0.0, 0.0, 0.0
+
+
+def falling(t):
+    # Leaning forward further and further, then caught and pushed back past upright
+    if t < 1.5:
+        pitch = 2.0 + 4.0 * t * t
+        pitch_rate = 8.0 * t
+    else:
+        pitch = 11.0 - 12.0 * (t - 1.5)
+        pitch_rate = -12.0
+    return pitch, pitch_rate, 0.0, 0.0, 0.0
+
+
+def turning(t):
+    # Turning in place at 45 deg/s, rocking on the wheels
+    pitch = -1.0 + 3.0 * math.sin(2 * math.pi * 1.3 * t)
+    pitch_rate = 3.0 * 2 * math.pi * 1.3 * math.cos(2 * math.pi * 1.3 * t)
+    roll = 2.0 * math.sin(2 * math.pi * 0.5 * t)
+    roll_rate = 2.0 * 2 * math.pi * 0.5 * math.cos(2 * math.pi * 0.5 * t)
+    return pitch, pitch_rate, roll, roll_rate, 45.0
+
+
+SEQUENCES = [
+    ("standing", standing, 2.0, 1),
+    ("falling", falling, 3.0, 2),
+    ("turning", turning, 3.0, 3),
+]
+
+
+def generate(motion, duration, seed):
+    rnd = random.Random(seed)
+
+    cx = cy = cz = 0.0
+    roll = yaw = 0.0
+    pid = PID()
+    rust_pid = PID(p_gain=0.75, i_gain=0.2, d_gain=0.05, gain=1.0, dead_band=0.0001, i_gain_scale=1.0, d_gain_scale=1.0)
+    rust_i_max = RUST_I_LIMIT / rust_pid.ki
+
+    rows = []
+    time = 0.0
+    for _ in range(int(duration * FREQ)):
+        time += (1.0 + rnd.uniform(-0.1, 0.1)) / FREQ
+        pitch, pitch_rate, lean, lean_rate, yaw_rate = motion(time)
+
+        gyro_x = yaw_rate + rnd.gauss(0, 0.3)
+        gyro_y = pitch_rate + rnd.gauss(0, 0.3)
+        gyro_z = lean_rate + rnd.gauss(0, 0.3)
+        accel_x, accel_y, accel_z = (a + rnd.gauss(0, 0.01) for a in gravity(pitch, lean))
+
+        # Balance.run_loop
+        accel_pitch = (math.atan2(accel_z, math.sqrt(accel_x * accel_x + accel_y * accel_y)) * 180.0) / math.pi
+        accel_roll = (math.atan2(accel_x, (math.sqrt(accel_z * accel_z + accel_y * accel_y))) * 180.0) / math.pi
+        accel_yav = (math.atan2(accel_y, (math.sqrt(accel_z * accel_z + accel_x * accel_x))) * 180.0) / math.pi
+
+        cx = (cx + gyro_x / FREQ) * COMBINE_FACTOR_GYRO + accel_yav * (1 - COMBINE_FACTOR_GYRO)
+        cy = (cy + gyro_y / FREQ) * COMBINE_FACTOR_GYRO + accel_pitch * (1 - COMBINE_FACTOR_GYRO)
+        cz = (cz + gyro_z / FREQ) * COMBINE_FACTOR_GYRO + accel_roll * (1 - COMBINE_FACTOR_GYRO)
+
+        output = pid.process(time, 0.0, math.sin(cy * math.pi / 90.0) * 2)
+
+        # Rust port
+        roll = (roll + gyro_z / FREQ) * COMBINE_FACTOR_GYRO + accel_yav * (1 - COMBINE_FACTOR_GYRO)
+        yaw += gyro_x / FREQ
+
+        rust_output = rust_pid.process(time, RUST_SET_POINT, cy)
+        clamped_i = max(-rust_i_max, min(rust_i_max, rust_pid.i))
+        if clamped_i != rust_pid.i:
+            rust_pid.i = clamped_i
+            rust_output = (rust_pid.p * rust_pid.kp + rust_pid.i * rust_pid.ki + rust_pid.d * rust_pid.kd) * rust_pid.kg
+            rust_pid.last_output = rust_output
+
+        rows.append([time, gyro_x, gyro_y, gyro_z, accel_x, accel_y, accel_z, cx, cy, cz, output, roll, yaw, rust_output])
+
+    return rows
+
+
+def main():
+    os.makedirs(OUTPUT_DIR, exist_ok=True)
+    for name, motion, duration, seed in SEQUENCES:
+        path = os.path.join(OUTPUT_DIR, name + ".csv")
+        with open(path, "w") as f:
+            f.write(",".join(COLUMNS) + "\n")
+            for row in generate(motion, duration, seed):
+                f.write(",".join(repr(float(value)) for value in row) + "\n")
+        print(f"Written {path}")
+
+
+if __name__ == "__main__":
+    main()
@@ -10,16 +10,24 @@
 //
 
 
+use std::io;
+use std::thread;
+use std::time::Duration;
+
 use byteorder::{ByteOrder, LittleEndian};
 use phf::phf_map;
 
-use rppal::i2c::I2c;
+use rppal::i2c::Error;
+
+use crate::i2c_bus::{I2cBus, SharedBus};
+use crate::orientation::{AxisSource, SensorAxis};
 
-#[allow(dead_code)]
-const EARTH_GRAVITY_MS2: f64 = 9.80665;
+pub const EARTH_GRAVITY_MS2: f64 = 9.80665;
 // const SCALE_MULTIPLIER: f64 = 0.004;
 const SCALE_MULTIPLIER: f64 = 0.00390625;
 
+pub const DEVID: u8 = 0x00;
+pub const DEVICE_ID: u8 = 0xE5;
 const DATA_FORMAT: u8 = 0x31;
 const BW_RATE: u8 = 0x2C;
 const POWER_CTL: u8 = 0x2D;
@@ -32,19 +40,29 @@ const BW_RATE_100HZ: u8 = 0x0B;
 const BW_RATE_50HZ: u8 = 0x0A;
 const BW_RATE_25HZ: u8 = 0x09;
 
-#[allow(dead_code)]
-const RANGE_2G: u8 = 0x00;
-#[allow(dead_code)]
-const RANGE_4G: u8 = 0x01;
-#[allow(dead_code)]
-const RANGE_8G: u8 = 0x02;
-const RANGE_16G: u8 = 0x03;
+pub const RANGE_2G: u8 = 0x00;
+pub const RANGE_4G: u8 = 0x01;
+pub const RANGE_8G: u8 = 0x02;
+pub const RANGE_16G: u8 = 0x03;
 
 const MEASURE: u8 = 0x08;
 const AXES_DATA: u8 = 0x32;
+const FIFO_CTL: u8 = 0x38;
+const FIFO_STATUS: u8 = 0x39;
+
+// FIFO_CTL: keeps the newest 32 samples, dropping the oldest when full
+const FIFO_MODE_STREAM: u8 = 0x80;
+// FIFO_STATUS: samples waiting, the data registers' one included
+const FIFO_ENTRIES: u8 = 0x3F;
+const FIFO_DEPTH: usize = 33;
+// Sample periods read_raw waits for a sample before giving up
+const MAX_SAMPLE_WAITS: usize = 10;
 
+// At rest readings vary by a few mg; more than 20 mg standard deviation on any axis means the rover moved.
+pub const MAX_CALIBRATION_VARIANCE: f64 = 0.0004;
 
-// #[derive(Clone)]
+
+#[derive(Clone)]
 pub struct DataPoint {
     pub raw_x: i16,
     pub raw_y: i16,
@@ -60,6 +78,55 @@ impl DataPoint {
     }
 }
 
+// How the samples drained from the FIFO in one read make its DataPoint.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Averaging {
+    // Newest sample only, as before the FIFO was used
+    Latest,
+    // Mean of all of them, for less noise at the cost of half the batch's age in lag
+    Mean,
+}
+
+impl Averaging {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Averaging::Latest => "latest",
+            Averaging::Mean => "mean",
+        }
+    }
+
+    pub fn from_value(value: f64) -> Averaging {
+        if value >= 0.5 { Averaging::Mean } else { Averaging::Latest }
+    }
+
+    pub fn value(&self) -> f64 {
+        match self {
+            Averaging::Latest => 0.0,
+            Averaging::Mean => 1.0,
+        }
+    }
+}
+
+// Raw sample averaging makes of samples; None when there are none.
+pub fn average(samples: &[(i16, i16, i16)], averaging: Averaging) -> Option<(i16, i16, i16)> {
+    match averaging {
+        Averaging::Latest => samples.last().cloned(),
+        Averaging::Mean if samples.is_empty() => None,
+        Averaging::Mean => {
+            let n = samples.len() as f64;
+            let mean = |select: fn(&(i16, i16, i16)) -> i16| (samples.iter().map(|s| select(s) as f64).sum::<f64>() / n).round() as i16;
+            Some((mean(|s| s.0), mean(|s| s.1), mean(|s| s.2)))
+        }
+    }
+}
+
+// What read got: one filtered DataPoint, and how many FIFO samples went into it. With none waiting
+// the DataPoint is the previous one again.
+pub struct AccelSamples {
+    pub data_point: DataPoint,
+    pub drained: usize,
+}
+
 const ALLOWED_FREQUENCIES: phf::Map<u16, u8> = phf_map! {
     1600u16 => BW_RATE_1600HZ,
     800u16 => BW_RATE_800HZ,
@@ -72,7 +139,7 @@ const ALLOWED_FREQUENCIES: phf::Map<u16, u8> = phf_map! {
 
 
 pub struct ADXL345 {
-    bus: I2c,
+    pub bus: I2cBus,
     pub x: f64,
     pub y: f64,
     pub z: f64,
@@ -80,67 +147,236 @@ pub struct ADXL345 {
     pub y_offset: f64,
     pub z_offset: f64,
     pub combine_filter: f64,
+    pub averaging: Averaging,
+    sample_period: Duration,
+    last: DataPoint,
 }
 
 impl ADXL345 {
-    pub fn new(address: u8, freq: u16, combine_filter: f64) -> ADXL345 {
+    pub fn new(bus: &SharedBus, address: u8, freq: u16, combine_filter: f64, averaging: Averaging) -> ADXL345 {
+        ADXL345::open(bus, address, freq, combine_filter, averaging).unwrap_or_else(|e| panic!("{}", e))
+    }
 
-        let mut bus = I2c::with_bus(1).expect("ADXL345: Cannot initialise i2c bus 1");
-        bus.set_slave_address(address as u16).unwrap_or_else(|_| panic!("ADXL345: Cannot set slave address {}", address));
+    // As new, but a bad frequency or an accelerometer that does not answer is an error rather than a panic.
+    pub fn open(bus: &SharedBus, address: u8, freq: u16, combine_filter: f64, averaging: Averaging) -> Result<ADXL345, String> {
 
-        let adxl345 = ADXL345 {
+        let rate = *ALLOWED_FREQUENCIES.get(&freq).ok_or(format!("ADXL345: Unexpected freqency {}", freq))?;
+        let bus = I2cBus::new("ADXL345", bus, address as u16);
+
+        let mut adxl345 = ADXL345 {
             bus,
             x: 0.0, y: 0.0, z: 0.0, x_offset: 0.0, y_offset: 0.0, z_offset: 0.0,
             combine_filter,
+            averaging,
+            sample_period: Duration::from_secs_f64(1.0 / freq as f64),
+            last: DataPoint::new(0, 0, 0, 0.0, 0.0, 0.0),
         };
 
-        match ALLOWED_FREQUENCIES.get(&freq) {
-            Some(rate) => adxl345.set_bandwidth_rate(*rate),
-            None => panic!("ADXL345: Unexpected freqency {}", freq)
-        }
-
-        adxl345.set_range(RANGE_16G);
+        adxl345.set_bandwidth_rate(rate).map_err(|e| format!("ADXL345: Cannot set BW_RATE on i2c: {:?}", e))?;
+        adxl345.set_range(RANGE_16G).map_err(|e| format!("ADXL345: Cannot set DATA_FORMAT on i2c: {:?}", e))?;
 
-        adxl345.enable_measurement();
+        adxl345.enable_fifo().map_err(|e| format!("ADXL345: Cannot set FIFO_CTL on i2c: {:?}", e))?;
+        adxl345.enable_measurement().map_err(|e| format!("ADXL345: Cannot set POWER_CTL on i2c: {:?}", e))?;
 
-        adxl345
+        Ok(adxl345)
     }
 
-    pub fn set_bandwidth_rate(&self, rate_flag: u8) {
-        self.bus.smbus_write_byte(BW_RATE, rate_flag).expect("ADXL345: Cannot set BW_RATE on i2c");
+    pub fn set_bandwidth_rate(&mut self, rate_flag: u8) -> Result<(), Error> {
+        self.bus.transaction(|dev| dev.smbus_write_byte(BW_RATE, rate_flag))
     }
 
-    pub fn set_range(&self, range_flag: u8) {
-        let mut value = self.bus.smbus_read_byte(DATA_FORMAT).expect("ADXL345: Cannot read DATA_FORMAT byte from i2c");
+    pub fn set_range(&mut self, range_flag: u8) -> Result<(), Error> {
+        // Read, modify and write back without anyone else in between
+        self.bus.transaction(|dev| {
+            let mut value = dev.smbus_read_byte(DATA_FORMAT)?;
 
-        value &= !0x0F;
-        value |= range_flag;
-        value |= 0x08; // FULL RES
+            value &= !0x0F;
+            value |= range_flag;
+            value |= 0x08; // FULL RES
 
-        self.bus.smbus_write_byte(DATA_FORMAT, value).expect("ADXL345: Cannot set DATA_FORMAT on i2c");
+            dev.smbus_write_byte(DATA_FORMAT, value)
+        })
     }
 
-    pub fn enable_measurement(&self) {
-        self.bus.smbus_write_byte(POWER_CTL, MEASURE).expect("ADXL345: Cannot set POWER_CTL on i2c");
+    pub fn enable_measurement(&mut self) -> Result<(), Error> {
+        self.bus.transaction(|dev| dev.smbus_write_byte(POWER_CTL, MEASURE))
     }
 
-    pub fn read(&mut self) -> DataPoint {
+    pub fn enable_fifo(&mut self) -> Result<(), Error> {
+        self.bus.transaction(|dev| dev.smbus_write_byte(FIFO_CTL, FIFO_MODE_STREAM))
+    }
 
-        let command: [u8; 1] = [AXES_DATA];
-        let mut buf = [0u8; 6];
-        let _ = self.bus.write_read(&command, &mut buf).expect("ADXL345: Cannot read 6 bytes from i2c");
+    // Every sample waiting in the FIFO, oldest first. Each is read in a burst of its own six bytes:
+    // a sample is popped once its last byte is read, so a longer burst would run on into FIFO_CTL
+    // rather than into the next sample, and a shorter one would mix axes of two samples.
+    fn read_fifo(&mut self) -> Result<Vec<(i16, i16, i16)>, Error> {
+        self.bus.transaction(|dev| {
+            let entries = (dev.smbus_read_byte(FIFO_STATUS)? & FIFO_ENTRIES) as usize;
+            let mut samples = Vec::with_capacity(entries.min(FIFO_DEPTH));
+            let command: [u8; 1] = [AXES_DATA];
+            let mut buf = [0u8; 6];
+            for _ in 0..entries.min(FIFO_DEPTH) {
+                dev.write_read(&command, &mut buf)?;
+                samples.push((LittleEndian::read_i16(&buf[0..2]), LittleEndian::read_i16(&buf[2..4]), LittleEndian::read_i16(&buf[4..6])));
+            }
+            Ok(samples)
+        })
+    }
+
+    // Newest sample, waiting up to MAX_SAMPLE_WAITS sample periods for one.
+    fn read_raw(&mut self) -> Result<(i16, i16, i16), Error> {
+        for _ in 0..MAX_SAMPLE_WAITS {
+            if let Some(sample) = self.read_fifo()?.pop() {
+                return Ok(sample);
+            }
+            thread::sleep(self.sample_period);
+        }
+        Err(Error::Io(io::Error::new(io::ErrorKind::TimedOut, format!("ADXL345: no sample within {} sample periods", MAX_SAMPLE_WAITS))))
+    }
 
-        let raw_x = LittleEndian::read_i16(&buf[0..2]);
-        let raw_y = LittleEndian::read_i16(&buf[2..4]);
-        let raw_z = LittleEndian::read_i16(&buf[4..6]);
+    // Drains the FIFO into one DataPoint, averaged as set with averaging and then filtered.
+    pub fn read(&mut self) -> Result<AccelSamples, Error> {
+        let samples = self.read_fifo()?;
+        let (raw_x, raw_y, raw_z) = match average(&samples, self.averaging) {
+            Some(sample) => sample,
+            None => return Ok(AccelSamples { data_point: self.last.clone(), drained: 0 })
+        };
 
         let invert_combine_filter = 1.0 - self.combine_filter;
         self.x = (raw_x as f64 * SCALE_MULTIPLIER - self.x_offset) * self.combine_filter + self.x  * invert_combine_filter;
         self.y = (raw_y as f64 * SCALE_MULTIPLIER - self.y_offset) * self.combine_filter + self.y  * invert_combine_filter;
         self.z = (raw_z as f64 * SCALE_MULTIPLIER - self.z_offset) * self.combine_filter + self.z  * invert_combine_filter;
 
-        DataPoint::new(raw_x, raw_y, raw_z, self.x, self.y, self.z)
+        self.last = DataPoint::new(raw_x, raw_y, raw_z, self.x, self.y, self.z);
+        Ok(AccelSamples { data_point: self.last.clone(), drained: samples.len() })
+    }
+
+    // DEVID register; DEVICE_ID for an accelerometer that answers as it should.
+    pub fn device_id(&mut self) -> Result<u8, Error> {
+        self.bus.transaction(|dev| dev.smbus_read_byte(DEVID))
+    }
+
+    // Mean magnitude in g, offsets applied, of samples readings one sample period apart; 1g at rest.
+    pub fn measure_gravity(&mut self, samples: usize) -> Result<f64, Error> {
+        let samples = samples.max(1);
+        let mut sum: f64 = 0.0;
+        for _ in 0..samples {
+            let (raw_x, raw_y, raw_z) = self.read_raw()?;
+            let x = raw_x as f64 * SCALE_MULTIPLIER - self.x_offset;
+            let y = raw_y as f64 * SCALE_MULTIPLIER - self.y_offset;
+            let z = raw_z as f64 * SCALE_MULTIPLIER - self.z_offset;
+            sum += (x * x + y * y + z * z).sqrt();
+            thread::sleep(self.sample_period);
+        }
+        Ok(sum / samples as f64)
+    }
+
+    pub fn offsets(&self) -> (f64, f64, f64) {
+        (self.x_offset, self.y_offset, self.z_offset)
+    }
+
+    pub fn set_offsets(&mut self, x_offset: f64, y_offset: f64, z_offset: f64) {
+        self.x_offset = x_offset;
+        self.y_offset = y_offset;
+        self.z_offset = z_offset;
+    }
+
+    // Averages samples readings, one per sample period, into offsets that make the sensor read 1g along
+    // expected_gravity_axis and 0 along the others. Offsets stay as they were when the rover moved meanwhile.
+    pub fn calibrate(&mut self, samples: usize, expected_gravity_axis: AxisSource) -> Result<(f64, f64, f64), String> {
+        let mut readings = Vec::with_capacity(samples.max(1));
+        while readings.len() < samples.max(1) {
+            let (raw_x, raw_y, raw_z) = self.read_raw().map_err(|e| format!("{:?}", e))?;
+            readings.push((raw_x as f64 * SCALE_MULTIPLIER, raw_y as f64 * SCALE_MULTIPLIER, raw_z as f64 * SCALE_MULTIPLIER));
+            thread::sleep(self.sample_period);
+        }
+
+        let (x_offset, y_offset, z_offset) = offsets_from_readings(&readings, expected_gravity_axis, MAX_CALIBRATION_VARIANCE)?;
+        self.set_offsets(x_offset, y_offset, z_offset);
+        // The filter starts from the at rest vector rather than from readings with the old offsets
+        let (x, y, z) = gravity_vector(expected_gravity_axis);
+        self.x = x;
+        self.y = y;
+        self.z = z;
+
+        Ok((x_offset, y_offset, z_offset))
+    }
+}
+
+
+// 1g along the axis, -1g when the sensor axis points down.
+fn gravity_vector(source: AxisSource) -> (f64, f64, f64) {
+    let g = source.sign.signum();
+    match source.axis {
+        SensorAxis::X => (g, 0.0, 0.0),
+        SensorAxis::Y => (0.0, g, 0.0),
+        SensorAxis::Z => (0.0, 0.0, g),
     }
 }
 
+// Offsets (in g) taking the mean of readings to 1g along expected_gravity_axis (-1g with a negative
+// sign) and 0 along the others.
+// Refused when any axis varies by more than max_variance (g squared) across the readings.
+pub fn offsets_from_readings(readings: &[(f64, f64, f64)], expected_gravity_axis: AxisSource, max_variance: f64) -> Result<(f64, f64, f64), String> {
+    if readings.is_empty() {
+        return Err("no readings".to_string());
+    }
+    let n = readings.len() as f64;
+    let mean = |select: fn(&(f64, f64, f64)) -> f64| readings.iter().map(select).sum::<f64>() / n;
+    let variance = |select: fn(&(f64, f64, f64)) -> f64, mean: f64| readings.iter().map(|r| (select(r) - mean).powi(2)).sum::<f64>() / n;
+
+    let (mean_x, mean_y, mean_z) = (mean(|r| r.0), mean(|r| r.1), mean(|r| r.2));
+    let worst_variance = variance(|r| r.0, mean_x).max(variance(|r| r.1, mean_y)).max(variance(|r| r.2, mean_z));
+    if worst_variance > max_variance {
+        return Err(format!("readings vary by {:.4} g squared, more than {:.4}; keep the rover still", worst_variance, max_variance));
+    }
 
+    let (gravity_x, gravity_y, gravity_z) = gravity_vector(expected_gravity_axis);
+    Ok((mean_x - gravity_x, mean_y - gravity_y, mean_z - gravity_z))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at_rest() -> Vec<(f64, f64, f64)> {
+        (0..400).map(|i| {
+            let noise = if i % 2 == 0 { 0.004 } else { -0.004 };
+            (0.98 + noise, 0.05 - noise, 0.17 + noise)
+        }).collect()
+    }
+
+    fn close(a: f64, b: f64) -> bool {
+        (a - b).abs() < 1e-9
+    }
+
+    #[test]
+    fn tilted_mount_at_rest_calibrates_back_to_1g_along_gravity() {
+        let (x, y, z) = offsets_from_readings(&at_rest(), AxisSource::new(SensorAxis::X, 1.0), MAX_CALIBRATION_VARIANCE).unwrap();
+        assert!(close(x, -0.02) && close(y, 0.05) && close(z, 0.17), "offsets {}, {}, {}", x, y, z);
+    }
+
+    #[test]
+    fn sensor_axis_pointing_down_calibrates_to_minus_1g() {
+        let upside_down: Vec<(f64, f64, f64)> = at_rest().iter().map(|(x, y, z)| (-x, *y, *z)).collect();
+        let (x, _, _) = offsets_from_readings(&upside_down, AxisSource::new(SensorAxis::X, -1.0), MAX_CALIBRATION_VARIANCE).unwrap();
+        assert!(close(x, 0.02), "offset {}", x);
+    }
+
+    #[test]
+    fn moving_rover_or_no_readings_is_refused() {
+        let moving: Vec<(f64, f64, f64)> = (0..400).map(|i| (1.0, 0.0, (i as f64 * 0.1).sin() * 0.3)).collect();
+        assert!(offsets_from_readings(&moving, AxisSource::new(SensorAxis::X, 1.0), MAX_CALIBRATION_VARIANCE).is_err());
+        assert!(offsets_from_readings(&[], AxisSource::new(SensorAxis::X, 1.0), MAX_CALIBRATION_VARIANCE).is_err());
+    }
+
+    #[test]
+    fn batch_gives_its_newest_sample_or_its_rounded_mean() {
+        let samples = [(10, -3, 256), (12, -4, 250), (13, -4, 251)];
+        assert_eq!(average(&samples, Averaging::Latest), Some((13, -4, 251)));
+        assert_eq!(average(&samples, Averaging::Mean), Some((12, -4, 252)));
+        assert_eq!(average(&[], Averaging::Mean), None);
+        assert_eq!(average(&[], Averaging::Latest), None);
+    }
+}
@@ -10,21 +10,27 @@
 //
 
 use byteorder::{ByteOrder, BigEndian};
-use rppal::i2c::I2c;
+use rppal::i2c::Error;
 
+use crate::i2c_bus::{I2cBus, SharedBus};
+
+
+pub const ADDRESS: u16 = 0x36;
+// Has no id register; the status register is read to see whether it answers
+pub const STATUS: u8 = 0x0B;
 
 const _STATUS_ERROR_I2C_WRITE: u8 = 1;
 const _STATUS_ERROR_I2C_READ: u8 = 2;
 const _STATUS_ERROR_MOTOR_OVERHEAT: u8 = 4;
 const _STATUS_ERROR_MAGNET_HIGH: u8 = 8;
-const _STATUS_ERROR_MAGNET_LOW: u8 = 16;
-const _STATUS_ERROR_MAGNET_NOT_DETECTED: u8 = 32;
+pub const STATUS_ERROR_MAGNET_LOW: u8 = 16;
+pub const STATUS_ERROR_MAGNET_NOT_DETECTED: u8 = 32;
 const _STATUS_ERROR_RX_FAILED: u8 = 64;
 const _STATUS_ERROR_TX_FAILED: u8 = 128;
 
 
 pub struct AS5600 {
-    bus: I2c,
+    pub bus: I2cBus,
     dir: i8,
     pub deg: f64,
     pub last_deg: f64,
@@ -32,12 +38,9 @@ pub struct AS5600 {
 }
 
 impl AS5600 {
-    pub fn new(bus: u8, dir: i8) -> AS5600 {
-        let mut bus = I2c::with_bus(bus).unwrap_or_else(|_| panic!("Cannot initialise i2c bus {}", bus));
-        bus.set_slave_address(0x36).expect("Cannot set slave address to 0x36.");
-        
+    pub fn new(bus: &SharedBus, dir: i8) -> AS5600 {
         AS5600 {
-            bus,
+            bus: I2cBus::new("AS5600", bus, ADDRESS),
             dir,
             deg: 0.0,
             last_deg: 0.0,
@@ -45,10 +48,10 @@ impl AS5600 {
         }
     }
 
-    pub fn read(&mut self) -> f64 {
+    pub fn read(&mut self) -> Result<f64, Error> {
         let mut buf = [0u8; 5];
-        let command: [u8; 1] = [0x0B];
-        let _ = self.bus.write_read(&command, &mut buf).expect("AS5600: Cannot read 2 bytes from i2c");
+        let command: [u8; 1] = [STATUS];
+        self.bus.transaction(|dev| dev.write_read(&command, &mut buf))?;
 
         self.last_deg = self.deg;
 
@@ -57,8 +60,28 @@ impl AS5600 {
         } else {
             self.deg = BigEndian::read_i16(&buf[3..5]) as f64 * 360.0 / 4096.0;
         }
-        self.status  = buf[0] & 0b00111000 | _STATUS_ERROR_MAGNET_NOT_DETECTED;
-        
-        self.deg
+        // The chip sets bit 5 when it does detect the magnet
+        self.status = (buf[0] & 0b00111000) ^ STATUS_ERROR_MAGNET_NOT_DETECTED;
+
+        Ok(self.deg)
+    }
+}
+
+// Magnet missing or too far away (too weak) for reliable angles.
+pub fn magnet_fault(status: u8) -> bool {
+    status & (STATUS_ERROR_MAGNET_LOW | STATUS_ERROR_MAGNET_NOT_DETECTED) != 0
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn missing_or_weak_magnet_is_a_fault() {
+        assert!(!magnet_fault(0));
+        assert!(magnet_fault(STATUS_ERROR_MAGNET_NOT_DETECTED));
+        assert!(magnet_fault(STATUS_ERROR_MAGNET_LOW));
+        assert!(!magnet_fault(8));
     }
 }
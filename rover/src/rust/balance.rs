@@ -9,29 +9,116 @@
 //    Daniel Sendula - initial API and implementation
 //
 
-
-use std::f64::consts::PI;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::time::{Duration, Instant};
 
 use std::thread;
-use std::sync::mpsc;
+use std::sync::{mpsc, Arc};
+use std::sync::atomic::{AtomicBool, Ordering};
 
+use dma_gpio::pi::{BoardBuilder, DmaStatus};
 
-use crate::telemetry_socket_server::{SocketTelemetryServerBuilder, SocketTelemetryServer};
+use crate::telemetry_socket_server::{SocketTelemetryServerBuilder, SocketTelemetryServer, StreamHandle, TelemetryServerStats};
 use crate::telemetry_stream::Storable;
-use crate::telemetry_stream::TelemetryStreamDefinition;
+use crate::telemetry_stream::{TelemetryStreamDefinition, Timebase};
 
 
-use crate::motors::Motors;
-use crate::gyro::L3G4200D;
-use crate::accel::ADXL345;
-use crate::as5600::AS5600;
+use crate::capabilities::{self, Capabilities, SensorProbe};
+use crate::motors::{EnableGate, Motors};
+use crate::pi_mutex::PiMutex;
+use crate::safe_state::SafeState;
+use crate::wear::WearCounters;
+use crate::i2c_bus::{I2cStats, LockStats, SharedBus};
+use crate::gyro::{self, BiasPoint, BiasPointKind, L3G4200D, Range};
+use crate::accel::{self, Averaging, ADXL345};
+use crate::as5600;
+use crate::encoders::Encoders;
+use crate::config_topics;
+use crate::selftest::{self, Check, SelfTestReport};
 use crate::pid::{PID, SIMPLE_DIFFERENCE};
+use crate::orientation::{AxisMapping, ROVER_AXIS_MAPPING};
+use crate::turn::{OdometryHeading, TurnRejected, TurnRequest, TurnResult, TurnSequencer};
+use crate::drive::Drive;
+use crate::slew::SlewLimiter;
+use crate::windup::{WindupDetector, WindupStatus};
+use crate::controller::{self, Controller, SensorInput};
+use crate::loop_timing::{self, LoopSections, LoopTiming};
+
+// Bumped whenever fields of the balance-data stream change.
+const BALANCE_DATA_STREAM_VERSION: u32 = 20;
+
+const TELEMETRY_PORT: u16 = 1860;
+const TELEMETRY_MAX_CONNECTIONS: usize = 8;
+const TELEMETRY_DEAD_TIME: Duration = Duration::from_secs(5);
+// Serial fallback (HC-12 radio): a balance-data record is about 250 bytes, so 10 records a second at 115200 baud
+const TELEMETRY_SERIAL_BAUD: u32 = 115200;
+const TELEMETRY_SERIAL_STREAMS: [&str; 1] = ["balance-data"];
+const TELEMETRY_SERIAL_DECIMATION: u32 = 20;
+const TELEMETRY_FILE_FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+const TELEMETRY_FILE_MAX_SIZE: u64 = 64 * 1024 * 1024;
+
+pub const GYRO_ADDRESS: u8 = 0x69;
+pub const ACCEL_ADDRESS: u8 = 0x53;
+
+const MAX_DUTY: f64 = 1.0;
+const WARNING_INTERVAL: f64 = 10.0;
+// How often the PWM DMA is checked for having stopped
+const DMA_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+// How long stop() waits for the run loop to stop the motors, and then for it to finish
+const SHUTDOWN_ACK_TIMEOUT: Duration = Duration::from_secs(1);
+const SHUTDOWN_JOIN_TIMEOUT: Duration = Duration::from_secs(2);
 
+pub const MIN_OUTPUT_SCALE: f64 = 0.1;
+pub const MAX_OUTPUT_SCALE: f64 = 1.0;
+pub const MIN_TURN_TOLERANCE: f64 = 0.5;
+pub const MAX_TURN_TOLERANCE: f64 = 45.0;
+pub const MIN_TURN_TIMEOUT: f64 = 0.5;
+pub const MAX_TURN_TIMEOUT: f64 = 60.0;
+pub const MAX_TURN_OUTPUT: f64 = 1.0;
+// Once every 5s at 200Hz
+pub const MAX_LOG_EVERY_N: f64 = 1000.0;
+// Degrees; the ranges do not overlap, so balancing always starts inside the cutoff angle
+pub const MIN_START_DEGREE: f64 = 0.5;
+pub const MAX_START_DEGREE: f64 = 15.0;
+pub const MIN_MAX_DEGREE: f64 = 15.0;
+pub const MAX_MAX_DEGREE: f64 = 90.0;
+pub const MAX_DEAD_BAND: f64 = 5.0;
+// m/s
+pub const MAX_OUTER_DEAD_BAND: f64 = 0.5;
+// Zero would divide the derivative by zero
+pub const MIN_GAIN_SCALE: f64 = 0.01;
+pub const MAX_GAIN_SCALE: f64 = 100.0;
+pub const MIN_DRIVE_TIMEOUT: f64 = 0.1;
+pub const MAX_DRIVE_TIMEOUT: f64 = 5.0;
+pub const MIN_WATCHDOG_LOOP_TIME: f64 = 0.01;
+pub const MAX_WATCHDOG_LOOP_TIME: f64 = 1.0;
+pub const MAX_WATCHDOG_GYRO_FAILURES: f64 = 1000.0;
+pub const MAX_WATCHDOG_ACCEL_FAILURES: f64 = 1000.0;
+pub const MIN_LOOP_WARNING_TIME: f64 = 0.001;
+pub const MAX_LOOP_WARNING_TIME: f64 = 1.0;
+// Loop passes, and seconds
+pub const MAX_SATURATION_STEPS: f64 = 1000.0;
+pub const MIN_WINDUP_WARNING_TIME: f64 = 0.01;
+pub const MAX_WINDUP_WARNING_TIME: f64 = 10.0;
+// Seconds; the kernel times transactions out in 10ms steps, so a hard deadline is rounded up to them
+pub const MIN_I2C_DEADLINE: f64 = 0.0005;
+pub const MAX_I2C_DEADLINE: f64 = 0.1;
+pub const MAX_FUSION_KP: f64 = 20.0;
+pub const MAX_FUSION_KI: f64 = 5.0;
+pub const MIN_MOTOR_TRIM: f64 = 0.5;
+pub const MAX_MOTOR_TRIM: f64 = 1.5;
+pub const MAX_MOTOR_MIN_DUTY: f64 = 0.5;
+pub const MAX_MOTOR_DEADBAND: f64 = 0.2;
+pub const MAX_MOTOR_MAX_ACCEL: f64 = 100.0;
+// Zero would never let the filtered wheel speed move
+pub const MIN_VELOCITY_FILTER: f64 = 0.01;
+// Drive commands go from full scale to nothing in half a second once they time out
+const DRIVE_DECAY_RATE: f64 = 2.0;
 
-fn create_logger() -> TelemetryStreamDefinition {
-    TelemetryStreamDefinition::new("balance-data", 1,
+
+fn create_logger(timebase: Timebase) -> TelemetryStreamDefinition {
+    TelemetryStreamDefinition::new("balance-data", BALANCE_DATA_STREAM_VERSION,
         vec![
+            // gd* and ad* are raw sensor frame readings; gx to gz and ax to az are in body frame
             TelemetryStreamDefinition::signed_word_field("gdx"),
             TelemetryStreamDefinition::signed_word_field("gdy"),
             TelemetryStreamDefinition::signed_word_field("gdz"),
@@ -41,20 +128,30 @@ fn create_logger() -> TelemetryStreamDefinition {
             TelemetryStreamDefinition::unsigned_word_field("status"),
             TelemetryStreamDefinition::unsigned_byte_field("fifo_status"),
             TelemetryStreamDefinition::unsigned_byte_field("data_points"),
+            TelemetryStreamDefinition::unsigned_byte_field("accel_points"),
+            TelemetryStreamDefinition::unsigned_integer_field("gyro_overruns"),
+            TelemetryStreamDefinition::signed_byte_field("gyro_temp"),
+            TelemetryStreamDefinition::double_field("gt"),
             TelemetryStreamDefinition::signed_word_field("adx"),
             TelemetryStreamDefinition::signed_word_field("ady"),
             TelemetryStreamDefinition::signed_word_field("adz"),
             TelemetryStreamDefinition::double_field("ax"),
             TelemetryStreamDefinition::double_field("ay"),
             TelemetryStreamDefinition::double_field("az"),
-            TelemetryStreamDefinition::double_field("apitch"),
-            TelemetryStreamDefinition::double_field("aroll"),
-            TelemetryStreamDefinition::double_field("ayaw"),
-            TelemetryStreamDefinition::double_field("lw"),
-            TelemetryStreamDefinition::double_field("rw"),
-            TelemetryStreamDefinition::double_field("cx"),
-            TelemetryStreamDefinition::double_field("cy"),
-            TelemetryStreamDefinition::double_field("cz"),
+            TelemetryStreamDefinition::double_field("atx"),
+            TelemetryStreamDefinition::double_field("aty"),
+            TelemetryStreamDefinition::double_field("left_deg"),
+            TelemetryStreamDefinition::double_field("right_deg"),
+            TelemetryStreamDefinition::double_field("left_vel"),
+            TelemetryStreamDefinition::double_field("right_vel"),
+            TelemetryStreamDefinition::unsigned_byte_field("magnet_fault"),
+            TelemetryStreamDefinition::double_field("roll"),
+            TelemetryStreamDefinition::double_field("pitch"),
+            TelemetryStreamDefinition::double_field("yaw"),
+            TelemetryStreamDefinition::unsigned_byte_field("fusion"),
+            TelemetryStreamDefinition::double_field("fe_x"),
+            TelemetryStreamDefinition::double_field("fe_y"),
+            TelemetryStreamDefinition::double_field("fe_z"),
             TelemetryStreamDefinition::double_field("pi_p"),
             TelemetryStreamDefinition::double_field("pi_i"),
             TelemetryStreamDefinition::double_field("pi_d"),
@@ -63,27 +160,175 @@ fn create_logger() -> TelemetryStreamDefinition {
             TelemetryStreamDefinition::double_field("pi_dg"),
             TelemetryStreamDefinition::double_field("pi_dt"),
             TelemetryStreamDefinition::double_field("pi_o"),
+            TelemetryStreamDefinition::double_field("speed"),
+            TelemetryStreamDefinition::double_field("po_p"),
+            TelemetryStreamDefinition::double_field("po_i"),
+            TelemetryStreamDefinition::double_field("po_d"),
+            TelemetryStreamDefinition::double_field("po_o"),
+            TelemetryStreamDefinition::double_field("vel"),
+            TelemetryStreamDefinition::double_field("vel_o"),
             TelemetryStreamDefinition::double_field("out"),
+            TelemetryStreamDefinition::double_field("out_scale"),
+            TelemetryStreamDefinition::float_field("duty_l"),
+            TelemetryStreamDefinition::float_field("duty_r"),
+            TelemetryStreamDefinition::unsigned_byte_field("enabled"),
+            TelemetryStreamDefinition::unsigned_integer_field("holds"),
+            TelemetryStreamDefinition::unsigned_byte_field("sat"),
+            TelemetryStreamDefinition::double_field("i_frac"),
+            TelemetryStreamDefinition::unsigned_byte_field("windup"),
+            TelemetryStreamDefinition::unsigned_byte_field("pid_fault"),
+            TelemetryStreamDefinition::unsigned_byte_field("state"),
+            TelemetryStreamDefinition::unsigned_integer_field("i2c_soft"),
+            TelemetryStreamDefinition::unsigned_integer_field("i2c_hard"),
+            TelemetryStreamDefinition::double_field("i2c_max"),
+            TelemetryStreamDefinition::double_field("i2c_hold"),
+            TelemetryStreamDefinition::double_field("i2c_wait"),
         ]
-    )
+    ).with_timebase(timebase)
 }
 
 
+// Where the heading for turns comes from.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum HeadingSource {
+    // Integrated gyro z; drifts slowly but does not care about wheel slip
+    Gyro,
+    // Difference in wheel travel; no drift while the wheels grip
+    Odometry,
+}
+
+impl HeadingSource {
+    pub fn name(&self) -> &'static str {
+        match self {
+            HeadingSource::Gyro => "gyro",
+            HeadingSource::Odometry => "odometry",
+        }
+    }
+
+    pub fn from_value(value: f64) -> HeadingSource {
+        if value >= 0.5 { HeadingSource::Odometry } else { HeadingSource::Gyro }
+    }
+
+    pub fn value(&self) -> f64 {
+        match self {
+            HeadingSource::Gyro => 0.0,
+            HeadingSource::Odometry => 1.0,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FusionAlgorithm {
+    // Integrated gyro mixed with accel tilts by combine_gyro_accel_factor
+    Complementary,
+    // Quaternion corrected towards gravity with fusion_kp and fusion_ki
+    Mahony,
+}
+
+impl FusionAlgorithm {
+    pub fn name(&self) -> &'static str {
+        match self {
+            FusionAlgorithm::Complementary => "complementary",
+            FusionAlgorithm::Mahony => "mahony",
+        }
+    }
+
+    pub fn from_value(value: f64) -> FusionAlgorithm {
+        if value >= 0.5 { FusionAlgorithm::Mahony } else { FusionAlgorithm::Complementary }
+    }
+
+    pub fn value(&self) -> f64 {
+        match self {
+            FusionAlgorithm::Complementary => 0.0,
+            FusionAlgorithm::Mahony => 1.0,
+        }
+    }
+}
+
 #[derive(Clone, Copy)]
 pub struct ConfigData {
     pub freq: u16,
     pub combine_gyro_accel_factor: f64,
+    pub fusion: FusionAlgorithm,
+    pub fusion_kp: f64,
+    pub fusion_ki: f64,
     pub combine_gyro_factor: f64,
+    // Gyro full scale; wider ranges read faster turns at coarser steps
+    pub gyro_range: Range,
     pub combine_accel_factor: f64,
+    // How the accel samples that came in since the last loop make one reading
+    pub accel_averaging: Averaging,
     pub pid_kp: f64,
     pub pid_ki: f64,
     pub pid_kd: f64,
     pub pid_gain: f64,
+    pub pid_i_limit: f64,
+    // Outer (speed) PID; its output, in degrees, is added to the inner PID's set point
+    pub pid_outer_kp: f64,
+    pub pid_outer_ki: f64,
+    pub pid_outer_kd: f64,
+    pub pid_outer_gain: f64,
+    // m/s; dead_band is the inner PID's, in degrees
+    pub pid_outer_dead_band: f64,
+    // Filtered wheel speed (m/s) times velocity_gain comes off the motor command while balancing;
+    // the filtered speed is what the outer PID sees too
+    pub velocity_gain: f64,
+    pub velocity_filter: f64,
     pub dead_band: f64,
     pub i_gain_scale: f64,
     pub d_gain_scale: f64,
     pub max_degree: f64,
     pub start_degree: f64,
+    pub axis_mapping: AxisMapping,
+    pub output_scale: f64,
+    pub max_hold_samples: u32,
+    // Gyro and accel samples averaged into their bias and offsets on calibrate
+    pub calibration_samples: usize,
+    pub saturation_steps: u32,
+    pub windup_warning_time: f64,
+    pub i2c_soft_deadline: f64,
+    pub i2c_hard_deadline: f64,
+    pub turn_kp: f64,
+    pub turn_ki: f64,
+    pub turn_kd: f64,
+    // Largest differential duty added on top of the balancing output
+    pub turn_max_output: f64,
+    // Degrees
+    pub turn_tolerance: f64,
+    // Seconds
+    pub turn_timeout: f64,
+    pub turn_heading_source: HeadingSource,
+    // Metres
+    pub wheel_radius: f64,
+    pub wheel_track: f64,
+    // Only every n-th sample is logged to telemetry; 0 logs nothing
+    pub log_every_n: u16,
+    // Degrees the angle set point leans by at full forward drive
+    pub drive_max_lean: f64,
+    // m/s the outer PID holds at full forward drive
+    pub drive_max_speed: f64,
+    // Seconds a drive command holds before it ramps down
+    pub drive_timeout: f64,
+    // Motors are stopped when a loop takes longer than this many seconds...
+    pub watchdog_loop_time: f64,
+    // ...or this many gyro reads fail in a row...
+    pub watchdog_gyro_failures: u32,
+    // ...or this many accelerometer reads do
+    pub watchdog_accel_failures: u32,
+    // A pass of the loop taking longer than this many seconds is an overrun, warned about on balance/warnings
+    pub loop_warning_time: f64,
+    // Roll and pitch start again from the accelerometer when gyro samples were lost to a FIFO overrun
+    pub rezero_on_gyro_overrun: bool,
+    // Per motor duty scale, for motors that need different duty for the same speed
+    pub motor_left_trim: f64,
+    pub motor_right_trim: f64,
+    // Least duty a nonzero speed gets, so both motors overcome their static friction
+    pub motor_min_duty: f64,
+    // Speeds below this stop the motors rather than getting motor_min_duty
+    pub motor_deadband: f64,
+    // Full scale per second manual speeds and drive commands may change by, 0 for no limit;
+    // balancing corrections are never limited
+    pub motor_max_accel: f64,
 }
 
 impl ConfigData {
@@ -91,58 +336,264 @@ impl ConfigData {
         ConfigData {
             freq: 200,
             combine_gyro_accel_factor: 0.95,
+            fusion: FusionAlgorithm::Complementary,
+            fusion_kp: 2.0,
+            fusion_ki: 0.05,
             combine_gyro_factor: 0.3,
+            gyro_range: Range::Range250,
             combine_accel_factor: 0.5,
+            accel_averaging: Averaging::Mean,
             pid_kp: 0.75,
             pid_ki: 0.2,
             pid_kd: 0.05,
             pid_gain: 1.0,
+            pid_i_limit: MAX_DUTY,
+            // Off until tuned
+            pid_outer_kp: 0.0,
+            pid_outer_ki: 0.0,
+            pid_outer_kd: 0.0,
+            pid_outer_gain: 1.0,
+            pid_outer_dead_band: 0.0,
+            // Off, and unfiltered, until tuned
+            velocity_gain: 0.0,
+            velocity_filter: 1.0,
             dead_band: 0.0001,
             i_gain_scale: 1.0,
             d_gain_scale: 1.0,
             max_degree: 45.0,
             start_degree: 4.0,
+            axis_mapping: ROVER_AXIS_MAPPING,
+            output_scale: MAX_OUTPUT_SCALE,
+            max_hold_samples: 3,
+            calibration_samples: 400,
+            saturation_steps: 20,
+            windup_warning_time: 0.5,
+            i2c_soft_deadline: 0.002,
+            i2c_hard_deadline: 0.010,
+            turn_kp: 0.01,
+            turn_ki: 0.0,
+            turn_kd: 0.001,
+            turn_max_output: 0.2,
+            turn_tolerance: 2.0,
+            turn_timeout: 10.0,
+            turn_heading_source: HeadingSource::Gyro,
+            wheel_radius: 0.035,
+            wheel_track: 0.16,
+            log_every_n: 1,
+            drive_max_lean: 3.0,
+            drive_max_speed: 0.3,
+            drive_timeout: 0.5,
+            watchdog_loop_time: 0.1,
+            watchdog_gyro_failures: 10,
+            watchdog_accel_failures: 10,
+            // Half as long again as a pass has at 200 Hz
+            loop_warning_time: 0.0075,
+            rezero_on_gyro_overrun: false,
+            motor_left_trim: 1.0,
+            motor_right_trim: 1.0,
+            motor_min_duty: 0.0,
+            motor_deadband: 0.0,
+            motor_max_accel: 0.0,
         }
     }
+
+    // Every config topic field against its topic's range; the first one out of it and why.
+    pub fn validate(&self) -> Result<(), (&'static str, String)> {
+        for config_topic in config_topics::CONFIG_TOPICS.iter() {
+            if let Some(field) = &config_topic.field {
+                config_topic.check((field.get)(self)).map_err(|e| (field.name, e))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Default for ConfigData {
+    fn default() -> ConfigData {
+        ConfigData::new()
+    }
 }
 
 
 pub struct Balance {
     telemetry_server: SocketTelemetryServer,
-    logger: TelemetryStreamDefinition,
+    logger: StreamHandle,
     config_data: ConfigData,
     gyro: L3G4200D,
     accel: ADXL345,
-    as5600_left: AS5600,
-    as5600_right: AS5600,
-    pid: PID,
+    encoders: Encoders,
+    i2c_buses: Vec<SharedBus>,
+    controller: Controller,
+    // Heading PID of move/turn_by; started again for every turn
+    turn_pid: PID,
+    // Every sample's SensorInput, when recording
+    input_logger: Option<StreamHandle>,
+    // Once a second
+    timing_logger: StreamHandle,
+    safe_state: SafeState,
+    capabilities: Capabilities,
+    enable_gate: Option<EnableGate>,
+    force_enable: bool,
+    // Set by BalanceControl::estop straight from the MQTT thread, cleared by the run loop on reset
+    estop: Arc<AtomicBool>,
+    // Built by the run loop, so the board belongs to its thread
+    motor_board: Option<BoardBuilder>,
+}
+
+// What Balance runs on: the rover's own, or simulated buses and a dry-run board for tests.
+pub struct Hardware {
+    // Left encoder has bus 0 to itself; the right one shares bus 1 with the IMU
+    pub buses: [SharedBus; 2],
+    pub motor_board: BoardBuilder,
+    // 0 for any free port
+    pub telemetry_port: u16,
+}
+
+impl Hardware {
+    pub fn rover() -> Hardware {
+        Hardware {
+            buses: [SharedBus::new(0), SharedBus::new(1)],
+            motor_board: Motors::board_builder(),
+            telemetry_port: TELEMETRY_PORT,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Default)]
+pub struct BalanceStatus {
+    pub windup: WindupStatus,
+    pub pid_fault: bool,
+    pub telemetry: TelemetryServerStats,
+    pub wear: WearCounters,
+    pub state: State,
+}
+
+pub enum BalanceEvent {
+    Warning(String),
+    // Finished turn, or why a turn was not started
+    Turn(Result<TurnResult, TurnRejected>),
+    // Capabilities JSON, once the motors are set up
+    Capabilities(String),
+    Calibration(CalibrationStatus),
+    // Why the watchdog stopped the motors
+    Fault(Fault),
+    SelfTest(SelfTestReport),
+    State(StateChange),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Fault {
+    // Seconds the loop took
+    SlowLoop(f64),
+    // Gyro reads that failed in a row
+    GyroFailures(u32),
+    // Accelerometer reads that failed in a row
+    AccelFailures(u32),
+    // PWM DMA reported an error or stopped, leaving the motors at whatever they last got
+    Dma(DmaStatus),
+}
+
+impl Fault {
+    pub fn to_json(&self) -> String {
+        match self {
+            Fault::SlowLoop(loop_time) => format!("{{ \"fault\" : \"slow_loop\", \"loop_time\" : {} }}", loop_time),
+            Fault::GyroFailures(failures) => format!("{{ \"fault\" : \"gyro_failures\", \"failures\" : {} }}", failures),
+            Fault::AccelFailures(failures) => format!("{{ \"fault\" : \"accel_failures\", \"failures\" : {} }}", failures),
+            Fault::Dma(status) => format!("{{ \"fault\" : \"dma\", \"active\" : {}, \"error\" : {}, \"read_error\" : {}, \"fifo_error\" : {}, \"read_last_not_set\" : {} }}",
+                status.active, status.error, status.read_error, status.fifo_error, status.read_last_not_set),
+        }
+    }
+}
+
+pub enum CalibrationStatus {
+    // Gyro samples collected so far, out of how many
+    Progress(usize, usize),
+    // Gyro bias, raw units
+    Done(f64, f64, f64),
+    // Accel offsets, g
+    AccelDone(f64, f64, f64),
+    // Gyro bias taken for temperature compensation
+    BiasPoint(BiasPointKind, BiasPoint),
+    // Sensor, and why
+    Failed(&'static str, String),
+}
+
+impl CalibrationStatus {
+    pub fn to_json(&self) -> String {
+        match self {
+            CalibrationStatus::Progress(collected, samples) =>
+                format!("{{ \"status\" : \"calibrating\", \"collected\" : {}, \"samples\" : {} }}", collected, samples),
+            CalibrationStatus::Done(cx, cy, cz) =>
+                format!("{{ \"status\" : \"done\", \"sensor\" : \"gyro\", \"cx\" : {}, \"cy\" : {}, \"cz\" : {} }}", cx, cy, cz),
+            CalibrationStatus::AccelDone(x_offset, y_offset, z_offset) =>
+                format!("{{ \"status\" : \"done\", \"sensor\" : \"accel\", \"x_offset\" : {}, \"y_offset\" : {}, \"z_offset\" : {} }}", x_offset, y_offset, z_offset),
+            CalibrationStatus::BiasPoint(kind, point) =>
+                format!("{{ \"status\" : \"done\", \"sensor\" : \"gyro_bias\", \"point\" : \"{}\", \"temperature\" : {}, \"cx\" : {}, \"cy\" : {}, \"cz\" : {} }}",
+                    kind.name(), point.temperature, point.cx, point.cy, point.cz),
+            CalibrationStatus::Failed(sensor, error) =>
+                format!("{{ \"status\" : \"failed\", \"sensor\" : \"{}\", \"error\" : \"{}\" }}", sensor, error.replace('\\', "\\\\").replace('"', "\\\"")),
+        }
+    }
 }
 
 enum Command {
     Calibrate,
+    SelfTest,
+    SetAccelOffsets(f64, f64, f64),
+    CalibrateBiasPoint(BiasPointKind),
+    SetBiasPoint(BiasPointKind, BiasPoint),
     StartBalancing,
     StopBalancing,
-    Leave,
-    NewConfig(ConfigData),
-    Manual(f64)
+    // Leaves the latched emergency stop
+    Reset,
+    // Stops the motors, acknowledges and leaves the run loop
+    Shutdown(mpsc::Sender<()>),
+    NewConfig(Box<ConfigData>),
+    Manual(f64),
+    RestoreWear(WearCounters),
+    ResetWear,
+    Turn(TurnRequest),
+    SetDrive { forward: f64, turn: f64 },
 }
 
 
 pub struct BalanceControl {
     pub config_data: ConfigData,
+    pub telemetry_port: u16,
+    pub status: Arc<PiMutex<BalanceStatus>>,
+    pub events: crossbeam_channel::Receiver<BalanceEvent>,
     balance_command_sender: mpsc::Sender<Command>,
-    balance_thread: thread::JoinHandle<()>
+    balance_thread: thread::JoinHandle<()>,
+    safe_state: SafeState,
+    estop: Arc<AtomicBool>,
 }
 
 impl BalanceControl {
     pub fn send_config(&self) {
-        let _ = self.balance_command_sender.send(Command::NewConfig(self.config_data));
+        let _ = self.balance_command_sender.send(Command::NewConfig(Box::new(self.config_data)));
     }
 
     pub fn calibrate(&self) {
         let _ = self.balance_command_sender.send(Command::Calibrate);
     }
 
+    pub fn self_test(&self) {
+        let _ = self.balance_command_sender.send(Command::SelfTest);
+    }
+
+    pub fn set_accel_offsets(&self, x_offset: f64, y_offset: f64, z_offset: f64) {
+        let _ = self.balance_command_sender.send(Command::SetAccelOffsets(x_offset, y_offset, z_offset));
+    }
+
+    // Calibrates the gyro alone and keeps the bias, with the temperature, as the given point.
+    pub fn calibrate_bias_point(&self, kind: BiasPointKind) {
+        let _ = self.balance_command_sender.send(Command::CalibrateBiasPoint(kind));
+    }
+
+    pub fn set_bias_point(&self, kind: BiasPointKind, point: BiasPoint) {
+        let _ = self.balance_command_sender.send(Command::SetBiasPoint(kind, point));
+    }
+
     pub fn start_balancing(&self) {
         let _ = self.balance_command_sender.send(Command::StartBalancing);
     }
@@ -151,67 +602,364 @@ impl BalanceControl {
         let _ = self.balance_command_sender.send(Command::StopBalancing);
     }
 
+    // Zeroes the motors from their next set_speeds on, even one later in the loop iteration already
+    // running, and latches the estop state until reset.
+    pub fn estop(&self) {
+        self.estop.store(true, Ordering::SeqCst);
+    }
+
+    pub fn reset(&self) {
+        let _ = self.balance_command_sender.send(Command::Reset);
+    }
+
     pub fn manual(&self, speed: f64) {
         let _ = self.balance_command_sender.send(Command::Manual(speed));
     }
 
-    pub fn stop(self) {
-        let _ = self.balance_command_sender.send(Command::Leave);
-        let _ = self.balance_thread.join();
+    pub fn restore_wear(&self, stored: WearCounters) {
+        let _ = self.balance_command_sender.send(Command::RestoreWear(stored));
+    }
+
+    pub fn reset_wear(&self) {
+        let _ = self.balance_command_sender.send(Command::ResetWear);
+    }
+
+    pub fn turn(&self, request: TurnRequest) {
+        let _ = self.balance_command_sender.send(Command::Turn(request));
+    }
+
+    // Both from -1 to 1; see drive for how long a command holds.
+    pub fn drive(&self, forward: f64, turn: f64) {
+        let _ = self.balance_command_sender.send(Command::SetDrive { forward, turn });
+    }
+
+    // Motors first, then telemetry (at the end of the run loop), then the thread. A loop that does
+    // not answer, e.g. stuck on a sensor, gets the motors braked by the safe state instead and is
+    // left behind if it does not finish either. Returns true when the loop stopped the motors and
+    // finished without panicking.
+    pub fn stop(self) -> bool {
+        let (ack_sender, ack_receiver) = mpsc::channel();
+        let _ = self.balance_command_sender.send(Command::Shutdown(ack_sender));
+        let acknowledged = match ack_receiver.recv_timeout(SHUTDOWN_ACK_TIMEOUT) {
+            Ok(()) => { println!("Motors stopped."); true },
+            Err(e) => {
+                warn!("Balance loop did not stop the motors in {:?} ({:?}), braking them", SHUTDOWN_ACK_TIMEOUT, e);
+                self.safe_state.engage("balance loop not responding");
+                false
+            }
+        };
+
+        let started = Instant::now();
+        while !self.balance_thread.is_finished() {
+            if started.elapsed() >= SHUTDOWN_JOIN_TIMEOUT {
+                warn!("Balance loop did not finish in {:?}, not waiting for it", SHUTDOWN_JOIN_TIMEOUT);
+                return false;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        match self.balance_thread.join() {
+            Ok(()) => acknowledged,
+            Err(_) => {
+                warn!("Balance loop panicked");
+                false
+            }
+        }
     }
 }
 
-#[derive(PartialEq, Clone)]
-enum State {
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum State {
+    #[default]
     Stopped,
     WaitingForReady,
     Balancing,
     Manual,
+    // Latched emergency stop; only balancing/reset leaves it
+    EStop,
 }
 
-fn angular_distance(a: f64, b: f64) -> f64 {
-    let r = a - b;
-    if r < 0.0 {
-        return r + 360.0;
-    } else if r >= 360.0 {
-        return r - 360.0;
+impl State {
+    // As in the state telemetry field
+    pub fn value(&self) -> u8 {
+        match self {
+            State::Stopped => 0,
+            State::WaitingForReady => 1,
+            State::Balancing => 2,
+            State::Manual => 3,
+            State::EStop => 4,
+        }
+    }
+
+    pub fn from_value(value: u8) -> Option<State> {
+        [State::Stopped, State::WaitingForReady, State::Balancing, State::Manual, State::EStop].iter().copied().find(|state| state.value() == value)
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            State::Stopped => "stopped",
+            State::WaitingForReady => "waiting_for_ready",
+            State::Balancing => "balancing",
+            State::Manual => "manual",
+            State::EStop => "estop",
+        }
+    }
+
+    // Nothing drives the motors, and a fault has nothing more to stop
+    pub fn is_stopped(&self) -> bool {
+        *self == State::Stopped || *self == State::EStop
     }
-    r
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StateCause {
+    Startup,
+    // Start, stop, manual, calibration or self test
+    Command,
+    // Pitch came within start_degree
+    Upright,
+    // Pitch, degrees, that went over max_degree
+    TiltCutoff(f64),
+    SensorFailure,
+    Watchdog,
+    // Motor enable pin went inactive
+    EnableGate,
+    EStop,
+    // Emergency stop reset
+    Reset,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct StateChange {
+    pub state: State,
+    pub previous: State,
+    pub cause: StateCause,
+}
+
+impl StateChange {
+    pub fn to_json(&self) -> String {
+        let cause = match self.cause {
+            StateCause::Startup => "\"startup\"".to_string(),
+            StateCause::Command => "\"command\"".to_string(),
+            StateCause::Upright => "\"upright\"".to_string(),
+            StateCause::TiltCutoff(pitch) => format!("\"tilt_cutoff\", \"angle\" : {}", pitch),
+            StateCause::SensorFailure => "\"sensor_failure\"".to_string(),
+            StateCause::Watchdog => "\"watchdog\"".to_string(),
+            StateCause::EnableGate => "\"enable_gate\"".to_string(),
+            StateCause::EStop => "\"estop\"".to_string(),
+            StateCause::Reset => "\"reset\"".to_string(),
+        };
+        format!("{{ \"state\" : \"{}\", \"value\" : {}, \"previous\" : \"{}\", \"cause\" : {} }}",
+            self.state.name(), self.state.value(), self.previous.name(), cause)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum HoldDecision {
+    Fresh,
+    Hold,
+    Stop,
+}
+
+impl HoldDecision {
+    // As in the hold field of balance-input
+    pub fn value(&self) -> u8 {
+        match self {
+            HoldDecision::Fresh => 0,
+            HoldDecision::Hold => 1,
+            HoldDecision::Stop => 2,
+        }
+    }
+
+    pub fn from_value(value: u8) -> Option<HoldDecision> {
+        [HoldDecision::Fresh, HoldDecision::Hold, HoldDecision::Stop].iter().copied().find(|hold| hold.value() == value)
+    }
+}
+
+// Bridges short sensor dropouts by reusing the last estimate for up to max_hold_samples loops.
+pub(crate) struct SensorHold {
+    // Failed samples in a row
+    pub(crate) consecutive: u32,
+    // Samples held since start
+    pub(crate) total: u32,
+}
+
+impl SensorHold {
+    pub(crate) fn new() -> SensorHold {
+        SensorHold { consecutive: 0, total: 0 }
+    }
+
+    pub(crate) fn update(&mut self, sensors_ok: bool, state_changed: bool, max_hold_samples: u32) -> HoldDecision {
+        if sensors_ok {
+            self.consecutive = 0;
+            return HoldDecision::Fresh;
+        }
+
+        self.consecutive += 1;
+        if state_changed || self.consecutive > max_hold_samples {
+            HoldDecision::Stop
+        } else {
+            self.total += 1;
+            HoldDecision::Hold
+        }
+    }
+}
+
+// Catches the loop or a sensor stalling, which sensor hold cannot: a loop that takes too long never
+// gets to decide anything, and hold only stops balancing to start again once the angle looks right.
+// A read failing now and then (a loose wire NACKing) is ridden out; only failures in a row count.
+pub struct Watchdog {
+    gyro_failures: u32,
+    accel_failures: u32,
+}
+
+impl Watchdog {
+    pub fn new() -> Watchdog {
+        Watchdog { gyro_failures: 0, accel_failures: 0 }
+    }
+
+    pub fn update(&mut self, loop_time: f64, gyro_ok: bool, accel_ok: bool, max_loop_time: f64, max_gyro_failures: u32, max_accel_failures: u32) -> Option<Fault> {
+        self.gyro_failures = if gyro_ok { 0 } else { self.gyro_failures + 1 };
+        self.accel_failures = if accel_ok { 0 } else { self.accel_failures + 1 };
+        if loop_time > max_loop_time {
+            Some(Fault::SlowLoop(loop_time))
+        } else if self.gyro_failures >= max_gyro_failures.max(1) {
+            Some(Fault::GyroFailures(self.gyro_failures))
+        } else if self.accel_failures >= max_accel_failures.max(1) {
+            Some(Fault::AccelFailures(self.accel_failures))
+        } else {
+            None
+        }
+    }
+}
+
+impl Default for Watchdog {
+    fn default() -> Watchdog {
+        Watchdog::new()
+    }
+}
+
+// Sensors as wired on the rover: left encoder on bus 0; right encoder, gyro and accel on bus 1.
+// Buses that could not be opened are passed as None.
+pub fn probe_sensors(bus0: Option<&SharedBus>, bus1: Option<&SharedBus>) -> Vec<SensorProbe> {
+    vec![
+        capabilities::probe_sensor("L3G4200D", 1, bus1, GYRO_ADDRESS as u16, gyro::WHO_AM_I, Some(gyro::DEVICE_ID)),
+        capabilities::probe_sensor("ADXL345", 1, bus1, ACCEL_ADDRESS as u16, accel::DEVID, Some(accel::DEVICE_ID)),
+        capabilities::probe_sensor("AS5600 left", 0, bus0, as5600::ADDRESS, as5600::STATUS, None),
+        capabilities::probe_sensor("AS5600 right", 1, bus1, as5600::ADDRESS, as5600::STATUS, None),
+    ]
 }
 
 impl Balance {
-    pub fn new() -> Balance {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(hardware: Hardware, telemetry_serial_device: Option<String>, telemetry_file: Option<String>, telemetry_timebase: Timebase,
+               record_inputs: bool, enable_gate: Option<EnableGate>, force_enable: bool, safe_state: &SafeState) -> Balance {
         let mut socket_server_builder = SocketTelemetryServerBuilder::new();
-        let logger = socket_server_builder.register_stream(create_logger());
+        let logger = socket_server_builder.register_stream(create_logger(telemetry_timebase)).unwrap_or_else(|e| panic!("Cannot register telemetry stream: {}", e));
+        let input_logger = if record_inputs {
+            Some(socket_server_builder.register_stream(controller::create_input_logger(telemetry_timebase)).unwrap_or_else(|e| panic!("Cannot register telemetry stream: {}", e)))
+        } else {
+            None
+        };
+        let timing_logger = socket_server_builder.register_stream(loop_timing::create_timing_logger(telemetry_timebase)).unwrap_or_else(|e| panic!("Cannot register telemetry stream: {}", e));
+        socket_server_builder.max_connections(TELEMETRY_MAX_CONNECTIONS);
+        socket_server_builder.dead_time(TELEMETRY_DEAD_TIME);
 
-        let telemetry_server = socket_server_builder.create(1860);
+        let mut capabilities = Capabilities::new();
+        let mut transports = vec!["tcp"];
+        if let Some(device) = telemetry_serial_device {
+            socket_server_builder.serial_output(&device, TELEMETRY_SERIAL_BAUD, TELEMETRY_SERIAL_STREAMS.to_vec(), TELEMETRY_SERIAL_DECIMATION);
+            transports.push("serial");
+        }
+        if let Some(path) = telemetry_file {
+            socket_server_builder.file_output(&path, TELEMETRY_FILE_FLUSH_INTERVAL, TELEMETRY_FILE_MAX_SIZE);
+            transports.push("file");
+        }
+        capabilities.telemetry = Some(transports.join("+"));
+
+        let telemetry_server = socket_server_builder.create(hardware.telemetry_port);
 
         let config_data = ConfigData::new();
 
-        Balance {
+        let [bus0, bus1] = hardware.buses;
+        capabilities.sensors = Some(probe_sensors(Some(&bus0), Some(&bus1)));
+        capabilities.heading_source = Some(config_data.turn_heading_source.name());
+
+        let mut balance = Balance {
             telemetry_server,
             logger,
-            gyro: L3G4200D::new(0x69, config_data.freq, "50", config_data.combine_gyro_factor),
-            accel: ADXL345::new(0x53, config_data.freq, config_data.combine_accel_factor),
-            as5600_left: AS5600::new(0x0, 1),
-            as5600_right: AS5600::new(0x1, -1),
-            pid: PID::new(
-                config_data.pid_kp, config_data.pid_ki, config_data.pid_kd,
-                config_data.pid_gain, config_data.dead_band,
-                config_data.i_gain_scale, config_data.d_gain_scale, SIMPLE_DIFFERENCE),
+            gyro: L3G4200D::new(&bus1, GYRO_ADDRESS, config_data.freq, "50", config_data.gyro_range, config_data.combine_gyro_factor),
+            accel: ADXL345::new(&bus1, ACCEL_ADDRESS, config_data.freq, config_data.combine_accel_factor, config_data.accel_averaging),
+            encoders: Encoders::new(&bus0, 1, &bus1, -1),
+            i2c_buses: vec![bus0, bus1],
+            controller: Controller::new(&config_data),
+            turn_pid: Balance::turn_pid(&config_data),
+            input_logger,
+            timing_logger,
             config_data,
+            safe_state: safe_state.clone(),
+            enable_gate,
+            force_enable,
+            estop: Arc::new(AtomicBool::new(false)),
+            motor_board: Some(hardware.motor_board),
+            capabilities,
+        };
+
+        let soft_deadline = Duration::from_secs_f64(config_data.i2c_soft_deadline);
+        let hard_deadline = Duration::from_secs_f64(config_data.i2c_hard_deadline);
+        balance.gyro.bus.set_deadlines(soft_deadline, hard_deadline);
+        balance.accel.bus.set_deadlines(soft_deadline, hard_deadline);
+        balance.encoders.set_deadlines(soft_deadline, hard_deadline);
+        if telemetry_timebase == Timebase::Monotonic {
+            balance.gyro.set_time_origin(Some(balance.telemetry_server.started()));
+        }
+
+        balance
+    }
+
+    // As BalanceControl has it once started; the one picked when Hardware asked for port 0.
+    pub fn telemetry_port(&self) -> u16 {
+        self.telemetry_server.port()
+    }
+
+    // Overruns of all the i2c devices, with the longest transaction since the previous call.
+    fn i2c_stats(&mut self) -> I2cStats {
+        let mut stats = I2cStats::default();
+        let [left_bus, right_bus] = self.encoders.buses();
+        for bus in [&mut self.gyro.bus, &mut self.accel.bus, left_bus, right_bus].iter_mut() {
+            stats.add(&bus.stats());
+            bus.take_longest();
         }
+        stats
+    }
+
+    // Longest hold of and wait for any bus since the previous call.
+    fn i2c_lock_stats(&self) -> LockStats {
+        let mut lock_stats = LockStats::default();
+        for bus in &self.i2c_buses {
+            lock_stats.add(&bus.take_lock_stats());
+        }
+        lock_stats
     }
 
     pub fn start(self) -> BalanceControl {
         let (command_sender, command_receiver) = mpsc::channel();
+        let (event_sender, event_receiver) = crossbeam_channel::unbounded();
+        let status = Arc::new(PiMutex::new(BalanceStatus::default()));
+        let loop_status = status.clone();
+        let safe_state = self.safe_state.clone();
+        let estop = self.estop.clone();
 
         BalanceControl {
             config_data: self.config_data,
+            telemetry_port: self.telemetry_server.port(),
+            status,
+            events: event_receiver,
             balance_command_sender: command_sender,
             balance_thread: thread::spawn(move || {
-                self.run_loop(command_receiver);
-            })
+                self.run_loop(command_receiver, loop_status, event_sender);
+            }),
+            safe_state,
+            estop,
         }
     }
 
@@ -221,109 +969,599 @@ impl Balance {
             println!("Got new combine_gyro_accel_factor {}, old {}", new_config.combine_gyro_accel_factor, self.config_data.combine_gyro_accel_factor);
             self.config_data.combine_gyro_accel_factor = new_config.combine_gyro_accel_factor;
         }
+        // The run loop starts the filter again from the accel tilts on the next sample
+        if new_config.axis_mapping != self.config_data.axis_mapping {
+            println!("Got new axis_mapping {}, old {}", new_config.axis_mapping, self.config_data.axis_mapping);
+            self.config_data.axis_mapping = new_config.axis_mapping;
+        }
+        // The run loop swaps filters on the next sample, carrying the orientation over
+        if new_config.fusion != self.config_data.fusion {
+            println!("Got new fusion {}, old {}", new_config.fusion.name(), self.config_data.fusion.name());
+            self.config_data.fusion = new_config.fusion;
+        }
+        if new_config.fusion_kp != self.config_data.fusion_kp {
+            println!("Got new fusion_kp {}, old {}", new_config.fusion_kp, self.config_data.fusion_kp);
+            self.config_data.fusion_kp = new_config.fusion_kp;
+        }
+        if new_config.fusion_ki != self.config_data.fusion_ki {
+            println!("Got new fusion_ki {}, old {}", new_config.fusion_ki, self.config_data.fusion_ki);
+            self.config_data.fusion_ki = new_config.fusion_ki;
+        }
         if new_config.combine_gyro_factor != self.config_data.combine_gyro_factor {
             println!("Got new combine_gyro_factor {}, old {}", new_config.combine_gyro_factor, self.config_data.combine_gyro_factor);
             self.config_data.combine_gyro_factor = new_config.combine_gyro_factor;
             self.gyro.combine_filter = new_config.combine_gyro_factor
         }
+        if new_config.gyro_range != self.config_data.gyro_range {
+            println!("Got new gyro_range {}, old {}", new_config.gyro_range.dps(), self.config_data.gyro_range.dps());
+            match self.gyro.set_range(new_config.gyro_range) {
+                Ok(()) => self.config_data.gyro_range = new_config.gyro_range,
+                Err(e) => error!("Cannot set gyro range to {} dps: {:?}", new_config.gyro_range.dps(), e)
+            }
+        }
         if new_config.combine_accel_factor != self.config_data.combine_accel_factor {
             println!("Got new combine_accel_factor {}, old {}", new_config.combine_accel_factor, self.config_data.combine_accel_factor);
             self.config_data.combine_accel_factor = new_config.combine_accel_factor;
             self.accel.combine_filter = new_config.combine_accel_factor
         }
+        if new_config.accel_averaging != self.config_data.accel_averaging {
+            println!("Got new accel_averaging {}, old {}", new_config.accel_averaging.name(), self.config_data.accel_averaging.name());
+            self.config_data.accel_averaging = new_config.accel_averaging;
+            self.accel.averaging = new_config.accel_averaging;
+        }
         if new_config.pid_kp != self.config_data.pid_kp {
             println!("Got new pid_kp {}, old {}", new_config.pid_kp, self.config_data.pid_kp);
             self.config_data.pid_kp = new_config.pid_kp;
-            self.pid.kp = new_config.pid_kp
+            self.controller.pid.kp = new_config.pid_kp
         }
         if new_config.pid_ki != self.config_data.pid_ki {
             println!("Got new pid_ki {}, old {}", new_config.pid_ki, self.config_data.pid_ki);
             self.config_data.pid_ki = new_config.pid_ki;
-            self.pid.ki = new_config.pid_ki
+            self.controller.pid.ki = new_config.pid_ki
         }
         if new_config.pid_kd != self.config_data.pid_kd {
             println!("Got new pid_kd {}, old {}", new_config.pid_kd, self.config_data.pid_kd);
             self.config_data.pid_kd = new_config.pid_kd;
-            self.pid.kd = new_config.pid_kd
+            self.controller.pid.kd = new_config.pid_kd
         }
         if new_config.pid_gain != self.config_data.pid_gain {
             println!("Got new pid_gain {}, old {}", new_config.pid_gain, self.config_data.pid_gain);
             self.config_data.pid_gain = new_config.pid_gain;
-            self.pid.kg = new_config.pid_gain
+            self.controller.pid.kg = new_config.pid_gain
+        }
+        if new_config.pid_i_limit != self.config_data.pid_i_limit {
+            let applied = self.controller.pid.set_i_limit(new_config.pid_i_limit);
+            println!("Got new pid_i_limit {}, old {}", applied, self.config_data.pid_i_limit);
+            if let Some(i_limit) = applied.effective() {
+                self.config_data.pid_i_limit = i_limit;
+            }
+        }
+        if new_config.pid_outer_kp != self.config_data.pid_outer_kp {
+            println!("Got new pid_outer_kp {}, old {}", new_config.pid_outer_kp, self.config_data.pid_outer_kp);
+            self.config_data.pid_outer_kp = new_config.pid_outer_kp;
+            self.controller.pid_outer.kp = new_config.pid_outer_kp
+        }
+        if new_config.pid_outer_ki != self.config_data.pid_outer_ki {
+            println!("Got new pid_outer_ki {}, old {}", new_config.pid_outer_ki, self.config_data.pid_outer_ki);
+            self.config_data.pid_outer_ki = new_config.pid_outer_ki;
+            self.controller.pid_outer.ki = new_config.pid_outer_ki
+        }
+        if new_config.pid_outer_kd != self.config_data.pid_outer_kd {
+            println!("Got new pid_outer_kd {}, old {}", new_config.pid_outer_kd, self.config_data.pid_outer_kd);
+            self.config_data.pid_outer_kd = new_config.pid_outer_kd;
+            self.controller.pid_outer.kd = new_config.pid_outer_kd
+        }
+        if new_config.pid_outer_gain != self.config_data.pid_outer_gain {
+            println!("Got new pid_outer_gain {}, old {}", new_config.pid_outer_gain, self.config_data.pid_outer_gain);
+            self.config_data.pid_outer_gain = new_config.pid_outer_gain;
+            self.controller.pid_outer.kg = new_config.pid_outer_gain
+        }
+        if new_config.pid_outer_dead_band != self.config_data.pid_outer_dead_band {
+            println!("Got new pid_outer_dead_band {}, old {}", new_config.pid_outer_dead_band, self.config_data.pid_outer_dead_band);
+            self.config_data.pid_outer_dead_band = new_config.pid_outer_dead_band;
+            self.controller.pid_outer.dead_band = new_config.pid_outer_dead_band
+        }
+        if new_config.velocity_gain != self.config_data.velocity_gain {
+            println!("Got new velocity_gain {}, old {}", new_config.velocity_gain, self.config_data.velocity_gain);
+            self.config_data.velocity_gain = new_config.velocity_gain;
+        }
+        if new_config.velocity_filter != self.config_data.velocity_filter {
+            println!("Got new velocity_filter {}, old {}", new_config.velocity_filter, self.config_data.velocity_filter);
+            self.config_data.velocity_filter = new_config.velocity_filter;
+        }
+        if new_config.output_scale != self.config_data.output_scale {
+            println!("Got new output_scale {}, old {}", new_config.output_scale, self.config_data.output_scale);
+            self.config_data.output_scale = new_config.output_scale;
+        }
+        if new_config.turn_kp != self.config_data.turn_kp {
+            println!("Got new turn_kp {}, old {}", new_config.turn_kp, self.config_data.turn_kp);
+            self.config_data.turn_kp = new_config.turn_kp;
+            self.turn_pid.kp = new_config.turn_kp
+        }
+        if new_config.turn_ki != self.config_data.turn_ki {
+            println!("Got new turn_ki {}, old {}", new_config.turn_ki, self.config_data.turn_ki);
+            self.config_data.turn_ki = new_config.turn_ki;
+            self.turn_pid.ki = new_config.turn_ki
+        }
+        if new_config.turn_kd != self.config_data.turn_kd {
+            println!("Got new turn_kd {}, old {}", new_config.turn_kd, self.config_data.turn_kd);
+            self.config_data.turn_kd = new_config.turn_kd;
+            self.turn_pid.kd = new_config.turn_kd
+        }
+        if new_config.turn_max_output != self.config_data.turn_max_output {
+            let applied = self.turn_pid.set_i_limit(new_config.turn_max_output);
+            println!("Got new turn_max_output {}, old {}", applied, self.config_data.turn_max_output);
+            if let Some(turn_max_output) = applied.effective() {
+                self.config_data.turn_max_output = turn_max_output;
+            }
+        }
+        if new_config.turn_tolerance != self.config_data.turn_tolerance {
+            println!("Got new turn_tolerance {}, old {}", new_config.turn_tolerance, self.config_data.turn_tolerance);
+            self.config_data.turn_tolerance = new_config.turn_tolerance;
+        }
+        if new_config.turn_timeout != self.config_data.turn_timeout {
+            println!("Got new turn_timeout {}, old {}", new_config.turn_timeout, self.config_data.turn_timeout);
+            self.config_data.turn_timeout = new_config.turn_timeout;
+        }
+        if new_config.turn_heading_source != self.config_data.turn_heading_source {
+            println!("Got new turn_heading_source {:?}, old {:?}", new_config.turn_heading_source, self.config_data.turn_heading_source);
+            self.config_data.turn_heading_source = new_config.turn_heading_source;
+        }
+        if new_config.dead_band != self.config_data.dead_band {
+            println!("Got new dead_band {}, old {}", new_config.dead_band, self.config_data.dead_band);
+            self.config_data.dead_band = new_config.dead_band;
+            self.controller.pid.dead_band = new_config.dead_band
+        }
+        if new_config.i_gain_scale != self.config_data.i_gain_scale {
+            println!("Got new i_gain_scale {}, old {}", new_config.i_gain_scale, self.config_data.i_gain_scale);
+            self.config_data.i_gain_scale = new_config.i_gain_scale;
+            self.controller.pid.i_gain_scale = new_config.i_gain_scale
+        }
+        if new_config.d_gain_scale != self.config_data.d_gain_scale {
+            println!("Got new d_gain_scale {}, old {}", new_config.d_gain_scale, self.config_data.d_gain_scale);
+            self.config_data.d_gain_scale = new_config.d_gain_scale;
+            self.controller.pid.d_gain_scale = new_config.d_gain_scale
+        }
+        // Both are read from config_data on every iteration, so the motor cutoff moves on the next sample
+        if new_config.max_degree != self.config_data.max_degree {
+            println!("Got new max_degree {}, old {}", new_config.max_degree, self.config_data.max_degree);
+            self.config_data.max_degree = new_config.max_degree;
+        }
+        if new_config.start_degree != self.config_data.start_degree {
+            println!("Got new start_degree {}, old {}", new_config.start_degree, self.config_data.start_degree);
+            self.config_data.start_degree = new_config.start_degree;
+        }
+        if new_config.drive_timeout != self.config_data.drive_timeout {
+            println!("Got new drive_timeout {}, old {}", new_config.drive_timeout, self.config_data.drive_timeout);
+            self.config_data.drive_timeout = new_config.drive_timeout;
+        }
+        if new_config.watchdog_loop_time != self.config_data.watchdog_loop_time {
+            println!("Got new watchdog_loop_time {}, old {}", new_config.watchdog_loop_time, self.config_data.watchdog_loop_time);
+            self.config_data.watchdog_loop_time = new_config.watchdog_loop_time;
+        }
+        if new_config.watchdog_gyro_failures != self.config_data.watchdog_gyro_failures {
+            println!("Got new watchdog_gyro_failures {}, old {}", new_config.watchdog_gyro_failures, self.config_data.watchdog_gyro_failures);
+            self.config_data.watchdog_gyro_failures = new_config.watchdog_gyro_failures;
+        }
+        if new_config.watchdog_accel_failures != self.config_data.watchdog_accel_failures {
+            println!("Got new watchdog_accel_failures {}, old {}", new_config.watchdog_accel_failures, self.config_data.watchdog_accel_failures);
+            self.config_data.watchdog_accel_failures = new_config.watchdog_accel_failures;
+        }
+        if new_config.loop_warning_time != self.config_data.loop_warning_time {
+            println!("Got new loop_warning_time {}, old {}", new_config.loop_warning_time, self.config_data.loop_warning_time);
+            self.config_data.loop_warning_time = new_config.loop_warning_time;
+        }
+        if new_config.rezero_on_gyro_overrun != self.config_data.rezero_on_gyro_overrun {
+            println!("Got new rezero_on_gyro_overrun {}, old {}", new_config.rezero_on_gyro_overrun, self.config_data.rezero_on_gyro_overrun);
+            self.config_data.rezero_on_gyro_overrun = new_config.rezero_on_gyro_overrun;
+        }
+        // The run loop hands both to the windup detector on every pass
+        if new_config.saturation_steps != self.config_data.saturation_steps {
+            println!("Got new saturation_steps {}, old {}", new_config.saturation_steps, self.config_data.saturation_steps);
+            self.config_data.saturation_steps = new_config.saturation_steps;
+        }
+        if new_config.windup_warning_time != self.config_data.windup_warning_time {
+            println!("Got new windup_warning_time {}, old {}", new_config.windup_warning_time, self.config_data.windup_warning_time);
+            self.config_data.windup_warning_time = new_config.windup_warning_time;
+        }
+        if new_config.motor_left_trim != self.config_data.motor_left_trim {
+            println!("Got new motor_left_trim {}, old {}", new_config.motor_left_trim, self.config_data.motor_left_trim);
+            self.config_data.motor_left_trim = new_config.motor_left_trim;
+        }
+        if new_config.motor_right_trim != self.config_data.motor_right_trim {
+            println!("Got new motor_right_trim {}, old {}", new_config.motor_right_trim, self.config_data.motor_right_trim);
+            self.config_data.motor_right_trim = new_config.motor_right_trim;
         }
+        if new_config.motor_min_duty != self.config_data.motor_min_duty {
+            println!("Got new motor_min_duty {}, old {}", new_config.motor_min_duty, self.config_data.motor_min_duty);
+            self.config_data.motor_min_duty = new_config.motor_min_duty;
+        }
+        if new_config.motor_deadband != self.config_data.motor_deadband {
+            println!("Got new motor_deadband {}, old {}", new_config.motor_deadband, self.config_data.motor_deadband);
+            self.config_data.motor_deadband = new_config.motor_deadband;
+        }
+        if new_config.motor_max_accel != self.config_data.motor_max_accel {
+            println!("Got new motor_max_accel {}, old {}", new_config.motor_max_accel, self.config_data.motor_max_accel);
+            self.config_data.motor_max_accel = new_config.motor_max_accel;
+        }
+        if new_config.log_every_n != self.config_data.log_every_n {
+            println!("Got new log_every_n {}, old {}", new_config.log_every_n, self.config_data.log_every_n);
+            self.config_data.log_every_n = new_config.log_every_n;
+        }
+    }
+
+    // Bias as calibrated, or None if it failed.
+    fn calibrate_gyro(&mut self, events: &crossbeam_channel::Sender<BalanceEvent>) -> Option<(f64, f64, f64)> {
+        let samples = self.config_data.calibration_samples.max(1);
+        println!("Calibrating gyro over {} samples; keep the rover still.", samples);
+        let _ = events.send(BalanceEvent::Calibration(CalibrationStatus::Progress(0, samples)));
+
+        // Progress in tenths, not on every FIFO read
+        let mut reported: usize = 0;
+        let result = self.gyro.calibrate_with_progress(samples, |collected| {
+            if collected < samples && collected * 10 / samples > reported * 10 / samples {
+                reported = collected;
+                let _ = events.send(BalanceEvent::Calibration(CalibrationStatus::Progress(collected, samples)));
+            }
+        });
+
+        let (status, bias) = match result {
+            Ok((cx, cy, cz)) => {
+                println!("Calibrated gyro: bias {:.2}, {:.2}, {:.2} at temperature {}", cx, cy, cz, self.gyro.temperature);
+                (CalibrationStatus::Done(cx, cy, cz), Some((cx, cy, cz)))
+            },
+            Err(e) => {
+                println!("*** Gyro calibration failed: {:?}", e);
+                (CalibrationStatus::Failed("gyro", format!("{:?}", e)), None)
+            }
+        };
+        let _ = events.send(BalanceEvent::Calibration(status));
+        bias
     }
 
-    fn run_loop(mut self, command_receiver: mpsc::Receiver<Command>) {
-        let config_data = self.config_data;
-        let mut motors = Motors::new();
+    fn calibrate_bias_point(&mut self, kind: BiasPointKind, events: &crossbeam_channel::Sender<BalanceEvent>) {
+        if let Some((cx, cy, cz)) = self.calibrate_gyro(events) {
+            let point = self.gyro.bias_point(cx, cy, cz);
+            self.gyro.set_bias_point(kind, point);
+            let _ = events.send(BalanceEvent::Calibration(CalibrationStatus::BiasPoint(kind, point)));
+        }
+    }
+
+    // Offsets are worked out for the body z axis (up) reading 1g, whichever sensor axis feeds it.
+    fn calibrate_accel(&mut self, events: &crossbeam_channel::Sender<BalanceEvent>) {
+        let samples = self.config_data.calibration_samples.max(1);
+        let gravity_axis = self.config_data.axis_mapping.z;
+        println!("Calibrating accel over {} samples with gravity along sensor {}{}; keep the rover upright and still.",
+                 samples, if gravity_axis.sign < 0.0 { "-" } else { "" }, gravity_axis.axis.name());
+
+        let status = match self.accel.calibrate(samples, gravity_axis) {
+            Ok((x_offset, y_offset, z_offset)) => {
+                println!("Calibrated accel: offsets {:.4}, {:.4}, {:.4}", x_offset, y_offset, z_offset);
+                CalibrationStatus::AccelDone(x_offset, y_offset, z_offset)
+            },
+            Err(e) => {
+                println!("*** Accel calibration failed: {}", e);
+                CalibrationStatus::Failed("accel", e)
+            }
+        };
+        let _ = events.send(BalanceEvent::Calibration(status));
+    }
+
+    // Checks the sensors answer and measure what they should, and that the motor direction pins
+    // follow what is written to them. The motors are stopped first and their PWM kept at 0.
+    fn self_test(&mut self, motors: &mut Motors) -> SelfTestReport {
+        println!("Running self-test; keep the rover still.");
+        let mut report = SelfTestReport::new();
+
+        report.add(Check::exact("gyro_who_am_i",
+            self.gyro.who_am_i().map(|id| id as f64).map_err(|e| format!("{:?}", e)), gyro::DEVICE_ID as f64));
+        report.add(Check::exact("accel_devid",
+            self.accel.device_id().map(|id| id as f64).map_err(|e| format!("{:?}", e)), accel::DEVICE_ID as f64));
+        report.add(Check::within("gyro_sample_rate",
+            self.gyro.measure_sample_rate(selftest::GYRO_RATE_WINDOW).map_err(|e| format!("{:?}", e)),
+            self.gyro.freq, self.gyro.freq * selftest::GYRO_RATE_TOLERANCE));
+        report.add(Check::within("accel_gravity",
+            self.accel.measure_gravity(selftest::ACCEL_GRAVITY_SAMPLES).map_err(|e| format!("{:?}", e)),
+            1.0, selftest::ACCEL_GRAVITY_TOLERANCE));
+        for (name, levels) in motors.check_direction_pins() {
+            report.add(Check::exact(name, levels, selftest::DIRECTION_PIN_LEVELS));
+        }
 
-        let mut cx: f64 = 0.0;
-        let mut cy: f64 = 0.0;
-        let mut cz: f64 = 0.0;
+        println!("Self-test {}", if report.passed() { "passed" } else { "failed" });
+        report
+    }
 
-        let mut last_cy: f64 = 0.0;
-        let mut last_left_wheel_position: f64 = 0.0;
-        let mut last_right_wheel_position: f64 = 0.0;
+    fn turn_pid(config_data: &ConfigData) -> PID {
+        PID::new(config_data.turn_kp, config_data.turn_ki, config_data.turn_kd, 1.0, 0.0, 1.0, 1.0, SIMPLE_DIFFERENCE)
+            .with_i_limit(config_data.turn_max_output)
+    }
+
+    fn run_loop(mut self, command_receiver: mpsc::Receiver<Command>, status: Arc<PiMutex<BalanceStatus>>, events: crossbeam_channel::Sender<BalanceEvent>) {
+        // A panic from here on brakes the motors through the safe state's panic hook before unwinding
+        let motor_board = self.motor_board.take().expect("Balance runs once");
+        let mut motors = Motors::new(motor_board, &self.safe_state);
+        if let Some(gate) = self.enable_gate {
+            motors = motors.with_enable_pin(gate.pin, gate.active_high);
+        }
+        motors = motors.with_estop(self.estop.clone());
+        motors.force_enable(self.force_enable);
+        self.capabilities.board = Some(motors.board_info());
+        let capabilities_json = self.capabilities.to_json();
+        self.telemetry_server.set_capabilities(&capabilities_json);
+        let _ = events.send(BalanceEvent::Capabilities(capabilities_json));
+        let mut windup_detector = WindupDetector::new(MAX_DUTY, self.config_data.saturation_steps, self.config_data.windup_warning_time);
+        let mut last_warning_time: f64 = 0.0;
+        let mut samples_since_log: u16 = 0;
+        let mut wear = WearCounters::default();
 
-        let mut last_time = SystemTime::now().duration_since(UNIX_EPOCH).expect("Time went backwards").as_secs_f64();
+        // Loop timing is monotonic, so a clock step (NTP syncing after boot) cannot upset the PIDs
+        let loop_started = Instant::now();
+        let mut last_time = loop_started.elapsed().as_secs_f64();
 
         let mut state = State::WaitingForReady;
         let mut last_state = State::Stopped;
+        let mut state_cause = StateCause::Startup;
 
         let mut manual_speed: f64 = 0.0;
 
+        let mut sensor_hold = SensorHold::new();
+        let mut watchdog = Watchdog::new();
+        let mut gyro_data_point = gyro::DataPoint::empty();
+        let mut accel_data_point = accel::DataPoint::new(0, 0, 0, 0.0, 0.0, 0.0);
+
+        let mut turn_sequencer = TurnSequencer::new(self.config_data.turn_tolerance, self.config_data.turn_timeout);
+        let mut odometry = OdometryHeading::new(self.config_data.wheel_radius, self.config_data.wheel_track);
+        let mut heading_source = self.config_data.turn_heading_source;
+        let mut heading: f64 = 0.0;
+        let mut drive = Drive::new(self.config_data.drive_timeout, DRIVE_DECAY_RATE);
+        let mut forward_slew = SlewLimiter::new(self.config_data.motor_max_accel);
+        let mut turn_slew = SlewLimiter::new(self.config_data.motor_max_accel);
+        let mut last_dma_check = Instant::now();
+        let mut dma_faulted = false;
+
+        let mut loop_timing = LoopTiming::new(last_time);
+        let mut sections = LoopSections::default();
+        let mut pass_started = Instant::now();
+        let mut last_overrun_warning: f64 = 0.0;
+        let mut overruns_since_warning: u32 = 0;
+
         loop {
-            match command_receiver.try_recv() {
-                Ok(msg) => match msg {
+            // The previous pass, however it got back round here
+            sections.total = pass_started.elapsed().as_secs_f64();
+            pass_started = Instant::now();
+            let pass_time = loop_started.elapsed().as_secs_f64();
+            let loop_warning_time = self.config_data.loop_warning_time;
+            if let Some(window) = loop_timing.add(pass_time, &sections, loop_warning_time) {
+                log_with_time!(
+                    self.telemetry_server, self.timing_logger,
+                    window.gyro.max, window.mean(&window.gyro), window.accel.max, window.mean(&window.accel),
+                    window.control.max, window.mean(&window.control), window.log.max, window.mean(&window.log),
+                    window.total.max, window.mean(&window.total),
+                    window.passes, window.overruns);
+            }
+            if sections.total > loop_warning_time {
+                overruns_since_warning += 1;
+                if pass_time - last_overrun_warning >= WARNING_INTERVAL {
+                    last_overrun_warning = pass_time;
+                    let _ = events.send(BalanceEvent::Warning(format!(
+                        "Loop overran: {:.1} ms against {:.1} ms (gyro {:.1}, accel {:.1}, control {:.1}, log {:.1} ms); {} overrun(s) since the last warning",
+                        sections.total * 1000.0, loop_warning_time * 1000.0, sections.gyro * 1000.0, sections.accel * 1000.0,
+                        sections.control * 1000.0, sections.log * 1000.0, overruns_since_warning)));
+                    overruns_since_warning = 0;
+                }
+            }
+            sections = LoopSections::default();
+
+            let commanded_from = state;
+            if let Ok(msg) = command_receiver.try_recv() {
+                match msg {
                     Command::StartBalancing => state = State::WaitingForReady,
                     Command::StopBalancing => state = State::Stopped,
-                    Command::Leave => break,
-                    Command::NewConfig(new_config) => self.process_config(new_config),
-                    Command::Calibrate => {},
+                    Command::Reset => {
+                        self.estop.store(false, Ordering::SeqCst);
+                        if state == State::EStop {
+                            state = State::Stopped;
+                        }
+                    },
+                    Command::Shutdown(ack) => {
+                        motors.stop_all();
+                        let _ = ack.send(());
+                        break;
+                    },
+                    Command::NewConfig(new_config) => self.process_config(*new_config),
+                    Command::SetAccelOffsets(x_offset, y_offset, z_offset) => self.accel.set_offsets(x_offset, y_offset, z_offset),
+                    Command::SetBiasPoint(kind, point) => self.gyro.set_bias_point(kind, point),
+                    Command::CalibrateBiasPoint(kind) => {
+                        motors.stop_all();
+                        state = State::Stopped;
+                        self.calibrate_bias_point(kind, &events);
+                        last_time = loop_started.elapsed().as_secs_f64();
+                        pass_started = Instant::now();
+                    },
+                    Command::Calibrate => {
+                        // Motors stay stopped afterwards, so the rover does not start balancing while still held
+                        motors.stop_all();
+                        state = State::Stopped;
+                        self.calibrate_gyro(&events);
+                        self.calibrate_accel(&events);
+                        // None of the calibration samples go to the filter, and the pause is not a loop period
+                        last_time = loop_started.elapsed().as_secs_f64();
+                        pass_started = Instant::now();
+                    },
+                    Command::SelfTest => {
+                        motors.stop_all();
+                        state = State::Stopped;
+                        let report = self.self_test(&mut motors);
+                        let _ = events.send(BalanceEvent::SelfTest(report));
+                        last_time = loop_started.elapsed().as_secs_f64();
+                        pass_started = Instant::now();
+                    },
                     Command::Manual(speed) => {
                             manual_speed = speed;
                             state = State::Manual
+                        },
+                    Command::RestoreWear(stored) => wear.restore(&stored),
+                    Command::ResetWear => wear = WearCounters::default(),
+                    Command::Turn(request) => {
+                        if !turn_sequencer.is_turning() {
+                            self.turn_pid = Balance::turn_pid(&self.config_data);
                         }
-                },
-                _ => {}
-            };
+                        match turn_sequencer.request(request, last_time, heading, state == State::Balancing) {
+                            Ok(Some(result)) => { let _ = events.send(BalanceEvent::Turn(Ok(result))); },
+                            Ok(None) => {},
+                            Err(rejected) => { let _ = events.send(BalanceEvent::Turn(Err(rejected))); }
+                        }
+                    },
+                    Command::SetDrive { forward, turn } => drive.command(last_time, forward, turn),
+                }
+            }
+            if state != commanded_from {
+                // Only a reset gets out of estop; any other command is put back below
+                state_cause = if commanded_from == State::EStop { StateCause::Reset } else { StateCause::Command };
+            }
+            if self.estop.load(Ordering::SeqCst) {
+                if commanded_from != State::EStop {
+                    state_cause = StateCause::EStop;
+                    motors.stop_all();
+                    println!("*** Emergency stop!");
+                }
+                state = State::EStop;
+            }
+            if !motors.poll_enable() && !state.is_stopped() {
+                state = State::Stopped;
+                state_cause = StateCause::EnableGate;
+                println!("*** Motor enable pin inactive, stopping!");
+            }
+            motors.poll_idle();
 
-            let gyro_data_points = self.gyro.read_deltas();
-            let gyro_data_point_len = gyro_data_points.len();
-            let gyro_data_point = gyro_data_points.last().unwrap();
+            let config_data = self.config_data;
+            motors.set_calibration(config_data.motor_left_trim as f32, config_data.motor_right_trim as f32, config_data.motor_min_duty as f32);
+            motors.set_deadband(config_data.motor_deadband as f32);
+            motors.set_max_accel(config_data.motor_max_accel as f32);
 
-            let accel_data_point = self.accel.read();
+            let section_started = Instant::now();
+            let mut gyro_data_point_len = 0;
+            let mut gyro_overrun = false;
+            let gyro_ok = match self.gyro.read_deltas() {
+                Ok(mut deltas) => {
+                    gyro_data_point_len = deltas.data_points.len();
+                    gyro_overrun = deltas.overrun;
+                    match deltas.data_points.pop() {
+                        Some(data_point) => { gyro_data_point = data_point; true },
+                        None => false
+                    }
+                },
+                Err(e) => { debug!("Failed to read gyro: {:?}", e); false }
+            };
+            sections.gyro = section_started.elapsed().as_secs_f64();
 
-            let left_wheel_position = self.as5600_left.read();
-            let right_wheel_position = self.as5600_right.read();
+            let section_started = Instant::now();
+            let mut accel_drained = 0;
+            let accel_ok = match self.accel.read() {
+                Ok(samples) => { accel_data_point = samples.data_point; accel_drained = samples.drained; true },
+                Err(e) => { debug!("Failed to read accel: {:?}", e); false }
+            };
+            sections.accel = section_started.elapsed().as_secs_f64();
 
-            let accel_pitch = (accel_data_point.z.atan2((accel_data_point.x * accel_data_point.x + accel_data_point.y * accel_data_point.y).sqrt()) * 180.0) / PI;
-            let accel_roll = (accel_data_point.x.atan2((accel_data_point.z * accel_data_point.z + accel_data_point.y * accel_data_point.y).sqrt()) * 180.0) / PI;
-            let accel_yav = (accel_data_point.y.atan2((accel_data_point.z * accel_data_point.z + accel_data_point.x * accel_data_point.x).sqrt()) * 180.0) / PI;
+            let hold_decision = sensor_hold.update(gyro_ok && accel_ok, state != last_state, config_data.max_hold_samples);
+            if hold_decision == HoldDecision::Stop && (state == State::Balancing || state == State::Manual) {
+                state = State::WaitingForReady;
+                state_cause = StateCause::SensorFailure;
+                motors.stop_all();
+                println!("*** Sensors failed for {} samples, stopping!", sensor_hold.consecutive);
+            }
 
+            let now = loop_started.elapsed().as_secs_f64();
 
-            let combine_gyro_accel_factor = config_data.combine_gyro_accel_factor;
-            let invert_combine_gyro_accel_factor = 1.0 - combine_gyro_accel_factor;
+            let delta_time = now - last_time;
+            last_time = now;
 
-            last_cy = cy;
+            // Anything but stopped may be driving the motors, or start to once the angle looks right
+            if let Some(fault) = watchdog.update(delta_time, gyro_ok, accel_ok, config_data.watchdog_loop_time, config_data.watchdog_gyro_failures, config_data.watchdog_accel_failures) {
+                if !state.is_stopped() {
+                    state = State::Stopped;
+                    state_cause = StateCause::Watchdog;
+                    motors.stop_all();
+                    println!("*** Watchdog: {:?}, stopping!", fault);
+                    let _ = events.send(BalanceEvent::Fault(fault));
+                }
+            }
 
-            cx = (cx + self.gyro.px / self.gyro.freq) * combine_gyro_accel_factor + accel_yav * invert_combine_gyro_accel_factor;
-            cy = (cy + self.gyro.py / self.gyro.freq) * combine_gyro_accel_factor + accel_pitch * invert_combine_gyro_accel_factor;
-            cz = (cz + self.gyro.pz / self.gyro.freq) * combine_gyro_accel_factor + accel_roll * invert_combine_gyro_accel_factor;
+            // Reported once each time it goes wrong; the motors cannot be stopped through it then
+            if last_dma_check.elapsed() >= DMA_CHECK_INTERVAL {
+                last_dma_check = Instant::now();
+                let dma_fault = motors.dma_fault();
+                if let (Some(status), false) = (dma_fault, dma_faulted) {
+                    println!("*** PWM DMA failed: {:?}, stopping!", status);
+                    if !state.is_stopped() {
+                        state = State::Stopped;
+                        state_cause = StateCause::Watchdog;
+                        motors.stop_all();
+                    }
+                    let _ = events.send(BalanceEvent::Fault(Fault::Dma(status)));
+                }
+                dma_faulted = dma_fault.is_some();
+            }
 
-            let now = SystemTime::now().duration_since(UNIX_EPOCH).expect("Time went backwards").as_secs_f64();
+            let wheels = self.encoders.read(delta_time);
+            let left_wheel_position = wheels.left.deg;
+            let right_wheel_position = wheels.right.deg;
 
-            let delta_time = now - last_time;
-            last_time = now;
+            // Drive commands are only for balancing; they do not carry over to the next time it starts.
+            // They ramp, the corrections balancing makes on top of them do not.
+            drive.timeout = config_data.drive_timeout;
+            forward_slew.max_rate = config_data.motor_max_accel;
+            turn_slew.max_rate = config_data.motor_max_accel;
+            let (drive_forward, drive_turn) = if state == State::Balancing {
+                let (forward, turn) = drive.update(now);
+                (forward_slew.update(now, forward), turn_slew.update(now, turn))
+            } else {
+                drive.stop();
+                forward_slew.reset(now, 0.0);
+                turn_slew.reset(now, 0.0);
+                (0.0, 0.0)
+            };
 
-            let angular_velocity: f64 = (cy - last_cy) / delta_time;  // dec/s
-            let left_wheel_speed: f64 = angular_distance(left_wheel_position, last_left_wheel_position) / delta_time;
-            let right_wheel_speed: f64 = angular_distance(right_wheel_position, last_right_wheel_position) / delta_time;
+            let input = SensorInput {
+                now,
+                hold: hold_decision,
+                state,
+                gyro_overrun,
+                gyro: (self.gyro.px, self.gyro.py, self.gyro.pz),
+                gyro_dt: 1.0 / self.gyro.freq,
+                accel: (accel_data_point.x, accel_data_point.y, accel_data_point.z),
+                wheel_velocity: (wheels.left.velocity, wheels.right.velocity),
+                drive_forward,
+            };
+            if let Some(input_logger) = &self.input_logger {
+                let section_started = Instant::now();
+                controller::log_input(&self.telemetry_server, input_logger, &input,
+                    (gyro_data_point.dx, gyro_data_point.dy, gyro_data_point.dz),
+                    (accel_data_point.raw_x, accel_data_point.raw_y, accel_data_point.raw_z));
+                sections.log += section_started.elapsed().as_secs_f64();
+            }
+            let section_started = Instant::now();
+            let step = self.controller.step(&config_data, &input);
+            let (rate_x, rate_y, rate_z) = step.rates;
+            let (gravity_x, gravity_y, gravity_z) = step.gravity;
+            let (accel_tilt_x, accel_tilt_y) = step.accel_tilt;
+            let orientation = step.orientation;
+            let pitch = orientation.pitch;
+            let pid_output = step.pid_output;
 
-            // let output = self.pid.process(now, 0.0, (cy * PI / 90.0).sin() * 2.0);
+            // Switching source mid turn would jump the heading the turn is measured against
+            let odometry_heading = odometry.update(left_wheel_position, right_wheel_position);
+            if !turn_sequencer.is_turning() {
+                heading_source = config_data.turn_heading_source;
+            }
+            heading = match heading_source {
+                HeadingSource::Gyro => orientation.yaw,
+                HeadingSource::Odometry => odometry_heading,
+            };
+            turn_sequencer.tolerance = config_data.turn_tolerance;
+            turn_sequencer.timeout = config_data.turn_timeout;
 
             let mut control: f64 = 0.0;
-            let pid_output = self.pid.process(now, -2.6, cy);
 
             match state {
                 State::Stopped => {
@@ -332,47 +1570,221 @@ impl Balance {
                     }
                 },
                 State::WaitingForReady => {
-                    if -config_data.start_degree < cy && cy < config_data.start_degree {
+                    if hold_decision == HoldDecision::Fresh && -config_data.start_degree < pitch && pitch < config_data.start_degree {
                         state = State::Balancing;
+                        state_cause = StateCause::Upright;
                     }
                 },
                 State::Balancing => {
-                    control = pid_output;
-                    if cy < -config_data.max_degree || cy > config_data.max_degree {
+                    control = step.control;
+                    if pitch < -config_data.max_degree || pitch > config_data.max_degree {
                         state = State::WaitingForReady;
+                        state_cause = StateCause::TiltCutoff(pitch);
                         motors.stop_all();
                         println!("*** Got over {} def stopping!", config_data.max_degree);
                     } else {
-                        motors.left_speed(control as f32);
-                        motors.right_speed(control as f32);
+                        // Positive (counter-clockwise) turns drive the right wheel harder
+                        let turn = match turn_sequencer.target_heading() {
+                            Some(target_heading) => self.turn_pid.process(now, target_heading, heading),
+                            None => 0.0
+                        } + drive_turn * config_data.turn_max_output;
+                        let turn = turn.max(-config_data.turn_max_output).min(config_data.turn_max_output);
+                        motors.set_speeds((control - turn) as f32, (control + turn) as f32);
                     }
                 },
                 State::Manual => {
                     control = manual_speed;
-                    motors.left_speed(manual_speed as f32);
-                    motors.right_speed(manual_speed as f32);
-                }
+                    motors.set_speeds_ramped(manual_speed as f32, manual_speed as f32);
+                },
+                // Stopped on the way in; the motors refuse anything else until reset
+                State::EStop => {}
             }
-            
-            last_state = state.clone();
+            sections.control = section_started.elapsed().as_secs_f64();
+
+            if state != last_state {
+                let _ = events.send(BalanceEvent::State(StateChange { state, previous: last_state, cause: state_cause }));
+            }
+            last_state = state;
+
+            if let Some(result) = turn_sequencer.update(now, heading, state == State::Balancing) {
+                let _ = events.send(BalanceEvent::Turn(Ok(result)));
+            }
+
+            let (left_duty, right_duty) = motors.applied_speeds();
+            wear.record(left_duty, right_duty, delta_time);
+
+            windup_detector.saturation_steps = config_data.saturation_steps;
+            windup_detector.windup_time = config_data.windup_warning_time;
+            let windup_status = windup_detector.update(now, control, self.controller.pid.integrator_fraction());
+            if windup_status.triggered && now - last_warning_time >= WARNING_INTERVAL {
+                last_warning_time = now;
+                let _ = events.send(BalanceEvent::Warning(format!(
+                    "Integrator windup: output saturated with integrator at {:.0}% of its limit", windup_status.integrator_fraction * 100.0)));
+            }
+            if let Ok(mut status) = status.lock() {
+                status.windup = windup_status;
+                status.pid_fault = self.controller.pid.fault;
+                status.telemetry = self.telemetry_server.stats();
+                status.wear = wear;
+                status.state = state;
+            }
+
+            // The control loop above runs on every sample; telemetry only gets every log_every_n-th
+            samples_since_log += 1;
+            if config_data.log_every_n == 0 || samples_since_log < config_data.log_every_n {
+                continue;
+            }
+            samples_since_log = 0;
+            let section_started = Instant::now();
+
+            // Worst cases since the last logged sample, skipped ones included
+            let i2c_stats = self.i2c_stats();
+            let i2c_lock_stats = self.i2c_lock_stats();
+            let fusion_error = self.controller.fusion_error();
 
             log_with_time!(
                 self.telemetry_server, self.logger,
                 gyro_data_point.dx, gyro_data_point.dy, gyro_data_point.dz,
-                self.gyro.px, self.gyro.py, self.gyro.pz,
-                gyro_data_point.status, gyro_data_point.fifo_status, gyro_data_point_len as u8,
+                rate_x, rate_y, rate_z,
+                gyro_data_point.status, gyro_data_point.fifo_status, gyro_data_point_len as u8, accel_drained as u8, self.gyro.overruns, self.gyro.temperature, gyro_data_point.time,
                 accel_data_point.raw_x, accel_data_point.raw_y, accel_data_point.raw_z,
-                accel_data_point.x, accel_data_point.y, accel_data_point.z,
-                accel_pitch, accel_roll, accel_yav,
-                left_wheel_position, right_wheel_position,
-                cx, cy, cz,
-                self.pid.p, self.pid.i, self.pid.d,
-                self.pid.p * self.pid.kp, self.pid.i * self.pid.ki, self.pid.d * self.pid.kd,
-                delta_time, pid_output, control);
+                gravity_x, gravity_y, gravity_z,
+                accel_tilt_x, accel_tilt_y,
+                left_wheel_position, right_wheel_position, wheels.left.velocity, wheels.right.velocity, wheels.magnet_fault,
+                orientation.roll, orientation.pitch, orientation.yaw,
+                config_data.fusion.value() as u8, fusion_error.0, fusion_error.1, fusion_error.2,
+                self.controller.pid.p, self.controller.pid.i, self.controller.pid.d,
+                self.controller.pid.p * self.controller.pid.kp, self.controller.pid.i * self.controller.pid.ki, self.controller.pid.d * self.controller.pid.kd,
+                delta_time, pid_output,
+                step.speed, self.controller.pid_outer.p * self.controller.pid_outer.kp, self.controller.pid_outer.i * self.controller.pid_outer.ki, self.controller.pid_outer.d * self.controller.pid_outer.kd, step.outer_output,
+                step.velocity, self.controller.velocity_feedback.contribution(),
+                control, config_data.output_scale, left_duty, right_duty, motors.enabled() as u8,
+                sensor_hold.total,
+                windup_status.saturated as u8, windup_status.integrator_fraction, windup_status.windup as u8,
+                self.controller.pid.fault as u8, state.value(),
+                i2c_stats.soft_overruns, i2c_stats.hard_overruns, i2c_stats.longest,
+                i2c_lock_stats.max_hold, i2c_lock_stats.max_wait);
+            sections.log += section_started.elapsed().as_secs_f64();
         }
 
         println!("Trying to kill threads...");
-        self.telemetry_server.stop();
+        if let Err(e) = self.telemetry_server.stop() {
+            warn!("Telemetry server did not stop cleanly: {}", e);
+        }
         println!("Finishing!");
     }
 }
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAX_HOLD_SAMPLES: u32 = 3;
+
+    // Decisions for a dropout of the given length between fresh samples, with the holds counted.
+    fn dropout(length: usize) -> (Vec<HoldDecision>, u32) {
+        let mut hold = SensorHold::new();
+        let mut decisions = vec![hold.update(true, false, MAX_HOLD_SAMPLES)];
+        decisions.extend((0..length).map(|_| hold.update(false, false, MAX_HOLD_SAMPLES)));
+        decisions.push(hold.update(true, false, MAX_HOLD_SAMPLES));
+        (decisions, hold.total)
+    }
+
+    #[test]
+    fn single_sample_dropout_is_held() {
+        assert_eq!(dropout(1), (vec![HoldDecision::Fresh, HoldDecision::Hold, HoldDecision::Fresh], 1));
+    }
+
+    #[test]
+    fn dropout_of_max_hold_samples_is_held_throughout() {
+        assert_eq!(dropout(3), (vec![HoldDecision::Fresh, HoldDecision::Hold, HoldDecision::Hold, HoldDecision::Hold, HoldDecision::Fresh], 3));
+    }
+
+    #[test]
+    fn longer_dropout_stops_once_max_hold_samples_is_exceeded() {
+        let (decisions, total) = dropout(10);
+        let mut expected = vec![HoldDecision::Fresh];
+        expected.extend(vec![HoldDecision::Hold; 3]);
+        expected.extend(vec![HoldDecision::Stop; 7]);
+        expected.push(HoldDecision::Fresh);
+        assert_eq!(decisions, expected);
+        // Only the held samples count
+        assert_eq!(total, 3);
+    }
+
+    #[test]
+    fn fresh_data_starts_the_hold_count_again() {
+        let mut hold = SensorHold::new();
+        for _ in 0..2 {
+            for _ in 0..MAX_HOLD_SAMPLES {
+                assert_eq!(hold.update(false, false, MAX_HOLD_SAMPLES), HoldDecision::Hold);
+            }
+            assert_eq!(hold.update(true, false, MAX_HOLD_SAMPLES), HoldDecision::Fresh);
+            assert_eq!(hold.consecutive, 0);
+        }
+        assert_eq!(hold.total, 2 * MAX_HOLD_SAMPLES);
+    }
+
+    #[test]
+    fn state_change_is_never_held_across() {
+        let mut hold = SensorHold::new();
+        assert_eq!(hold.update(false, true, MAX_HOLD_SAMPLES), HoldDecision::Stop);
+        assert_eq!(hold.update(false, false, MAX_HOLD_SAMPLES), HoldDecision::Hold);
+        assert_eq!(hold.update(false, true, MAX_HOLD_SAMPLES), HoldDecision::Stop);
+        assert_eq!(hold.total, 1);
+    }
+
+    #[test]
+    fn no_hold_without_max_hold_samples() {
+        let mut hold = SensorHold::new();
+        assert_eq!(hold.update(false, false, 0), HoldDecision::Stop);
+        assert_eq!(hold.total, 0);
+    }
+
+    #[test]
+    fn slow_loop_trips_the_watchdog_at_once() {
+        let mut watchdog = Watchdog::new();
+        assert_eq!(watchdog.update(0.2, true, true, 0.1, 3, 3), Some(Fault::SlowLoop(0.2)));
+    }
+
+    #[test]
+    fn sensor_failures_only_trip_the_watchdog_once_enough_come_in_a_row() {
+        let mut watchdog = Watchdog::new();
+        let faults: Vec<Option<Fault>> = [false, false, true, false, false, false].iter()
+            .map(|gyro_ok| watchdog.update(0.005, *gyro_ok, true, 0.1, 3, 3))
+            .collect();
+        assert_eq!(faults, vec![None, None, None, None, None, Some(Fault::GyroFailures(3))]);
+
+        let mut watchdog = Watchdog::new();
+        let faults: Vec<Option<Fault>> = [false, true, false, false, false].iter()
+            .map(|accel_ok| watchdog.update(0.005, true, *accel_ok, 0.1, 3, 3))
+            .collect();
+        assert_eq!(faults, vec![None, None, None, None, Some(Fault::AccelFailures(3))]);
+        assert!(Fault::GyroFailures(3).to_json().contains("\"fault\" : \"gyro_failures\""));
+    }
+
+    #[test]
+    fn state_values_stay_as_the_telemetry_field_has_them() {
+        let values: Vec<u8> = [State::Stopped, State::WaitingForReady, State::Balancing, State::Manual, State::EStop].iter().map(|state| state.value()).collect();
+        assert_eq!(values, vec![0, 1, 2, 3, 4]);
+        // Faults that stop the motors must not take the rover out of a latched estop
+        assert!(State::EStop.is_stopped());
+        assert!(!State::Manual.is_stopped());
+    }
+
+    #[test]
+    fn tilt_cutoff_names_its_angle() {
+        assert_eq!(StateChange { state: State::Stopped, previous: State::EStop, cause: StateCause::Reset }.to_json(),
+            "{ \"state\" : \"stopped\", \"value\" : 0, \"previous\" : \"estop\", \"cause\" : \"reset\" }");
+        assert_eq!(StateChange { state: State::WaitingForReady, previous: State::Balancing, cause: StateCause::TiltCutoff(-31.5) }.to_json(),
+            "{ \"state\" : \"waiting_for_ready\", \"value\" : 1, \"previous\" : \"balancing\", \"cause\" : \"tilt_cutoff\", \"angle\" : -31.5 }");
+    }
+
+    #[test]
+    fn calibration_status_names_its_sensor() {
+        assert!(CalibrationStatus::AccelDone(-0.02, 0.05, 0.17).to_json().contains("\"sensor\" : \"accel\""));
+        let cold = BiasPoint { temperature: 20.0, cx: 10.0, cy: -4.0, cz: 2.0 };
+        assert!(CalibrationStatus::BiasPoint(BiasPointKind::Cold, cold).to_json().contains("\"point\" : \"cold\", \"temperature\" : 20"));
+    }
+}
@@ -0,0 +1,95 @@
+//
+// Copyright (C) 2020 Abstract Horizon
+// All rights reserved. This program and the accompanying materials
+// are made available under the terms of the Apache License v2.0
+// which accompanies this distribution, and is available at
+// https://www.apache.org/licenses/LICENSE-2.0
+//
+//  Contributors:
+//    Daniel Sendula - initial API and implementation
+//
+
+// Replays the sensor inputs recorded with --record-inputs through the balance loop's filter and
+// PIDs (see replay.rs) and prints how far pitch strayed from the set point and how hard the motors
+// were asked to work. The config is the default one, with the fields of a saved config snapshot
+// (balancing/config/current) on top when given.
+//
+//   balance-replay <telemetry file> [--config <snapshot.json>] [--output <telemetry file>]
+
+use std::env;
+use std::fs;
+use std::process;
+
+use balancing_rover::balance::ConfigData;
+use balancing_rover::config_snapshot;
+use balancing_rover::replay::Replay;
+
+
+struct Options {
+    recording: String,
+    config: Option<String>,
+    output: Option<String>,
+}
+
+impl Options {
+    fn parse() -> Result<Options, String> {
+        let mut recording = None;
+        let mut config = None;
+        let mut output = None;
+        let mut args = env::args().skip(1);
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--config" => config = Some(args.next().ok_or("Missing value for --config")?),
+                "--output" => output = Some(args.next().ok_or("Missing value for --output")?),
+                _ if arg.starts_with("--") => return Err(format!("Unknown option {}", arg)),
+                _ if recording.is_none() => recording = Some(arg),
+                _ => return Err(format!("Unexpected argument {}", arg))
+            }
+        }
+        Ok(Options {
+            recording: recording.ok_or("Usage: balance-replay <telemetry file> [--config <snapshot.json>] [--output <telemetry file>]")?,
+            config,
+            output,
+        })
+    }
+}
+
+fn config_data(snapshot: &Option<String>) -> Result<ConfigData, String> {
+    let current = ConfigData::new();
+    let path = match snapshot {
+        Some(path) => path,
+        None => return Ok(current)
+    };
+    let json = fs::read_to_string(path).map_err(|e| format!("Cannot read {}: {}", path, e))?;
+    let outcome = config_snapshot::load(&json, &current).map_err(|rejected| format!("Config snapshot {} refused: {{ {} }}", path, rejected.to_json()))?;
+    if !outcome.deferred.is_empty() {
+        println!("Ignoring {:?}; they do not change the filter or PIDs", outcome.deferred);
+    }
+    Ok(outcome.config_data)
+}
+
+fn main() {
+    env_logger::init();
+
+    let options = Options::parse().unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(2);
+    });
+
+    let result = config_data(&options.config)
+        .and_then(|config_data| Replay::from_recording(&options.recording, config_data))
+        .and_then(|replay| replay.run(options.output.as_deref()));
+    let summary = result.unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(1);
+    });
+
+    println!("Samples:          {} ({} balancing, {:.2} s)", summary.samples, summary.balancing_samples, summary.balancing_time);
+    println!("Max angle error:  {:.3} deg", summary.max_angle_error);
+    println!("RMS angle error:  {:.3} deg", summary.rms_angle_error);
+    println!("Control effort:   {:.3} duty s", summary.control_effort);
+    println!("Max control:      {:.3}", summary.max_control);
+    if let Some(output) = &options.output {
+        println!("Outputs written to {}", output);
+    }
+}
@@ -0,0 +1,104 @@
+//
+// Copyright (C) 2020 Abstract Horizon
+// All rights reserved. This program and the accompanying materials
+// are made available under the terms of the Apache License v2.0
+// which accompanies this distribution, and is available at
+// https://www.apache.org/licenses/LICENSE-2.0
+//
+//  Contributors:
+//    Daniel Sendula - initial API and implementation
+//
+
+// Bench test for the IMU, without MQTT or the balance loop: prints gyro rates (deg/s) and
+// accelerations (g) from the sensors on i2c bus 1 at the given rate, until stopped. The gyro is
+// not calibrated, so expect it to be off a little at rest.
+//
+//   imu-test [--rate <lines/s>] [--freq <sensor Hz>] [--range <gyro dps>] [--duration <s>]
+
+use std::env;
+use std::process;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use balancing_rover::accel::{Averaging, ADXL345};
+use balancing_rover::gyro::{L3G4200D, Range};
+use balancing_rover::i2c_bus::SharedBus;
+
+
+// As the balance loop has them
+const I2C_BUS: u8 = 1;
+const GYRO_ADDRESS: u8 = 0x69;
+const ACCEL_ADDRESS: u8 = 0x53;
+
+
+struct Options {
+    rate: f64,
+    freq: u16,
+    range: Range,
+    duration: Option<f64>,
+}
+
+impl Options {
+    fn parse() -> Result<Options, String> {
+        let mut options = Options {
+            rate: 10.0,
+            freq: 200,
+            range: Range::Range250,
+            duration: None,
+        };
+        let mut args = env::args().skip(1);
+        while let Some(arg) = args.next() {
+            let value = args.next().ok_or(format!("Missing value for {}", arg))?;
+            match arg.as_str() {
+                "--rate" => options.rate = value.parse().map_err(|_| format!("Invalid rate {}", value))?,
+                "--freq" => options.freq = value.parse().map_err(|_| format!("Invalid frequency {}", value))?,
+                "--range" => options.range = Range::from_dps(value.parse().map_err(|_| format!("Invalid range {}", value))?),
+                "--duration" => options.duration = Some(value.parse().map_err(|_| format!("Invalid duration {}", value))?),
+                _ => return Err(format!("Unknown option {}", arg))
+            }
+        }
+        if options.rate.is_nan() || options.rate <= 0.0 {
+            return Err("--rate must be above 0".to_string());
+        }
+        Ok(options)
+    }
+}
+
+fn wiring_error(e: String) -> ! {
+    eprintln!("{}", e);
+    eprintln!("Check the IMU is powered and wired to i2c bus {} (SDA on pin 3, SCL on pin 5), and that i2c is enabled.", I2C_BUS);
+    process::exit(1);
+}
+
+fn main() {
+    env_logger::init();
+
+    let options = Options::parse().unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(2);
+    });
+
+    let bus = SharedBus::open(I2C_BUS).unwrap_or_else(|e| wiring_error(format!("Cannot open i2c bus {}: {:?}", I2C_BUS, e)));
+    // No smoothing, so the readings are what the sensors give
+    let mut gyro = L3G4200D::open(&bus, GYRO_ADDRESS, options.freq, "50", options.range, 1.0).unwrap_or_else(|e| wiring_error(e));
+    let mut accel = ADXL345::open(&bus, ACCEL_ADDRESS, options.freq, 1.0, Averaging::Latest).unwrap_or_else(|e| wiring_error(e));
+
+    let temperature = gyro.read_temperature().unwrap_or_else(|e| wiring_error(format!("Cannot read gyro temperature: {:?}", e)));
+    println!("Gyro OUT_TEMP {}", temperature);
+
+    let interval = Duration::from_secs_f64(1.0 / options.rate);
+    let started = Instant::now();
+    println!("{:>8} {:>9} {:>9} {:>9} {:>8} {:>8} {:>8} {:>4} {:>7}", "time", "gyro_x", "gyro_y", "gyro_z", "accel_x", "accel_y", "accel_z", "temp", "samples");
+    while options.duration.is_none_or(|duration| started.elapsed().as_secs_f64() < duration) {
+        let deltas = gyro.read_deltas().unwrap_or_else(|e| wiring_error(format!("Cannot read gyro: {:?}", e)));
+        let samples = accel.read().unwrap_or_else(|e| wiring_error(format!("Cannot read accelerometer: {:?}", e)));
+        let data_point = samples.data_point;
+        println!("{:8.3} {:9.3} {:9.3} {:9.3} {:8.4} {:8.4} {:8.4} {:4} {:3}/{:<3}{}",
+                 started.elapsed().as_secs_f64(),
+                 gyro.px, gyro.py, gyro.pz,
+                 data_point.x, data_point.y, data_point.z, gyro.temperature,
+                 deltas.data_points.len(), samples.drained,
+                 if deltas.overrun { " gyro overrun" } else { "" });
+        sleep(interval);
+    }
+}
@@ -0,0 +1,124 @@
+//
+// Copyright (C) 2020 Abstract Horizon
+// All rights reserved. This program and the accompanying materials
+// are made available under the terms of the Apache License v2.0
+// which accompanies this distribution, and is available at
+// https://www.apache.org/licenses/LICENSE-2.0
+//
+//  Contributors:
+//    Daniel Sendula - initial API and implementation
+//
+
+// Bench test for the motors, without MQTT or the balance loop: ramps each side up to the top
+// speed, down through stop to minus it and back, over the given time, then stops.
+//
+//   sudo motors-test [--side left|right|both] [--duration <s>] [--speed <0..1>]
+//
+// Only the H-bridges of the chosen side are set up, so it works with one of them connected.
+// The enable pin is not read here.
+
+#[macro_use] extern crate log;
+
+use std::env;
+use std::process;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use balancing_rover::motors::{Motors, Sides};
+use balancing_rover::safe_state::SafeState;
+
+
+const UPDATE_INTERVAL: Duration = Duration::from_millis(20);
+
+
+struct Options {
+    sides: Sides,
+    duration: f64,
+    speed: f32,
+}
+
+impl Options {
+    fn parse() -> Result<Options, String> {
+        let mut options = Options {
+            sides: Sides::Both,
+            duration: 4.0,
+            speed: 0.5,
+        };
+        let mut args = env::args().skip(1);
+        while let Some(arg) = args.next() {
+            let value = args.next().ok_or(format!("Missing value for {}", arg))?;
+            match arg.as_str() {
+                "--side" => options.sides = Sides::parse(&value)?,
+                "--duration" => options.duration = value.parse().map_err(|_| format!("Invalid duration {}", value))?,
+                "--speed" => options.speed = value.parse().map_err(|_| format!("Invalid speed {}", value))?,
+                _ => return Err(format!("Unknown option {}", arg))
+            }
+        }
+        if options.duration.is_nan() || options.duration <= 0.0 || options.speed.is_nan() || options.speed <= 0.0 || options.speed > 1.0 {
+            return Err("--duration must be above 0 and --speed between 0 and 1".to_string());
+        }
+        Ok(options)
+    }
+}
+
+// Speed at fraction (0..1) of the ramp: up to speed, down to -speed and back to 0.
+fn ramp(fraction: f64, speed: f32) -> f32 {
+    let fraction = fraction.clamp(0.0, 1.0) as f32;
+    if fraction < 0.25 {
+        speed * fraction * 4.0
+    } else if fraction < 0.75 {
+        speed * (1.0 - (fraction - 0.25) * 4.0)
+    } else {
+        speed * ((fraction - 0.75) * 4.0 - 1.0)
+    }
+}
+
+fn main() {
+    env_logger::init();
+
+    let options = Options::parse().unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(2);
+    });
+
+    let safe_state = SafeState::new();
+    safe_state.install_panic_hook();
+    // Ctrl-C part way through a ramp must not leave a wheel turning
+    let ctrlc_safe_state = safe_state.clone();
+    ctrlc::set_handler(move || {
+        ctrlc_safe_state.engage("interrupted");
+        process::exit(130);
+    }).unwrap_or_else(|e| warn!("Cannot set Ctrl-C handler: {:?}", e));
+
+    let mut motors = Motors::open(&safe_state, options.sides).unwrap_or_else(|e| {
+        eprintln!("Cannot set up the motors ({}); is this run as root on a Pi with the H-bridge wired to the PWM pins?", e);
+        process::exit(1);
+    });
+
+    let sides: Vec<Sides> = match options.sides {
+        Sides::Both => vec![Sides::Left, Sides::Right],
+        side => vec![side]
+    };
+    for side in sides {
+        println!("Ramping {:?} motor over {}s", side, options.duration);
+        let started = Instant::now();
+        let mut last_printed = -1;
+        while started.elapsed().as_secs_f64() < options.duration {
+            let speed = ramp(started.elapsed().as_secs_f64() / options.duration, options.speed);
+            match side {
+                Sides::Left => motors.set_speeds(speed, 0.0),
+                _ => motors.set_speeds(0.0, speed)
+            }
+            // A line every tenth of the ramp is enough to follow it
+            let tenth = (started.elapsed().as_secs_f64() * 10.0 / options.duration) as i32;
+            if tenth != last_printed {
+                let (left, right) = motors.applied_speeds();
+                println!("  left {:6.3}  right {:6.3}", left, right);
+                last_printed = tenth;
+            }
+            sleep(UPDATE_INTERVAL);
+        }
+        motors.stop_all();
+    }
+    println!("Done");
+}
@@ -0,0 +1,131 @@
+//
+// Copyright (C) 2020 Abstract Horizon
+// All rights reserved. This program and the accompanying materials
+// are made available under the terms of the Apache License v2.0
+// which accompanies this distribution, and is available at
+// https://www.apache.org/licenses/LICENSE-2.0
+//
+//  Contributors:
+//    Daniel Sendula - initial API and implementation
+//
+
+// Sweeps the motor PWM frequency, for finding one that is quiet and still drives the motors well:
+// runs the motors at a fixed duty and steps the frequency up, changing the board's timing in place
+// rather than building it again for each step.
+//
+//   sudo pwm-sweep [--side left|right|both] [--from <Hz>] [--to <Hz>] [--step <Hz>] [--dwell <s>]
+//                  [--duty <0..1>] [--steps <duty steps>]
+//
+// Frequencies are as near as the clock divisor gets them; the line for each step gives the actual one.
+
+#[macro_use] extern crate log;
+
+use std::env;
+use std::process;
+use std::thread::sleep;
+use std::time::Duration;
+
+use dma_gpio::pi::{MAX_CYCLE_TIME, MAX_PWM_DIVISOR};
+
+use balancing_rover::motors::{Motors, Sides};
+use balancing_rover::safe_state::SafeState;
+
+
+struct Options {
+    sides: Sides,
+    from: f64,
+    to: f64,
+    step: f64,
+    dwell: f64,
+    duty: f32,
+    steps: usize,
+}
+
+impl Options {
+    fn parse() -> Result<Options, String> {
+        let mut options = Options {
+            sides: Sides::Both,
+            from: 2000.0,
+            to: 25000.0,
+            step: 1000.0,
+            dwell: 2.0,
+            duty: 0.3,
+            // Fewer than the motors' usual 100, so 25 kHz stays within what the DMA can pace
+            steps: 50,
+        };
+        let mut args = env::args().skip(1);
+        while let Some(arg) = args.next() {
+            let value = args.next().ok_or(format!("Missing value for {}", arg))?;
+            match arg.as_str() {
+                "--side" => options.sides = Sides::parse(&value)?,
+                "--from" => options.from = value.parse().map_err(|_| format!("Invalid frequency {}", value))?,
+                "--to" => options.to = value.parse().map_err(|_| format!("Invalid frequency {}", value))?,
+                "--step" => options.step = value.parse().map_err(|_| format!("Invalid step {}", value))?,
+                "--dwell" => options.dwell = value.parse().map_err(|_| format!("Invalid dwell {}", value))?,
+                "--duty" => options.duty = value.parse().map_err(|_| format!("Invalid duty {}", value))?,
+                "--steps" => options.steps = value.parse().map_err(|_| format!("Invalid steps {}", value))?,
+                _ => return Err(format!("Unknown option {}", arg))
+            }
+        }
+        if [options.from, options.to, options.step].iter().any(|value| value.is_nan())
+            || options.from <= 0.0 || options.from > options.to || options.step <= 0.0 {
+            return Err("--from must be above 0 and no more than --to, and --step above 0".to_string());
+        }
+        if options.dwell.is_nan() || options.dwell <= 0.0 || options.duty.is_nan() || options.duty <= 0.0 || options.duty > 1.0 {
+            return Err("--dwell must be above 0 and --duty between 0 and 1".to_string());
+        }
+        if options.steps < 2 || options.steps > MAX_CYCLE_TIME {
+            return Err(format!("--steps must be between 2 and {}", MAX_CYCLE_TIME));
+        }
+        Ok(options)
+    }
+}
+
+// Divisor, cycle time and sample delay nearest to frequency with the given steps: the longest cycle
+// time that is a multiple of them, so the divisor, and the frequency with it, is the finest.
+fn timing_for(frequency: f64, steps: usize, clock_hz: usize) -> (usize, usize, usize) {
+    let cycle_time = MAX_CYCLE_TIME / steps * steps;
+    let divisor = (clock_hz as f64 / (frequency * cycle_time as f64)).round() as usize;
+    (divisor.clamp(1, MAX_PWM_DIVISOR), cycle_time, cycle_time / steps)
+}
+
+fn main() {
+    env_logger::init();
+
+    let options = Options::parse().unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(2);
+    });
+
+    let safe_state = SafeState::new();
+    safe_state.install_panic_hook();
+    // Ctrl-C part way through the sweep must not leave a wheel turning
+    let ctrlc_safe_state = safe_state.clone();
+    ctrlc::set_handler(move || {
+        ctrlc_safe_state.engage("interrupted");
+        process::exit(130);
+    }).unwrap_or_else(|e| warn!("Cannot set Ctrl-C handler: {:?}", e));
+
+    let mut motors = Motors::open(&safe_state, options.sides).unwrap_or_else(|e| {
+        eprintln!("Cannot set up the motors ({}); is this run as root on a Pi with the H-bridge wired to the PWM pins?", e);
+        process::exit(1);
+    });
+    let clock_hz = motors.board_info().plld_frequency;
+
+    let mut frequency = options.from;
+    while frequency <= options.to {
+        let (divisor, cycle_time, sample_delay) = timing_for(frequency, options.steps, clock_hz);
+        match motors.reconfigure_timing(divisor, cycle_time, sample_delay) {
+            Ok(info) => {
+                println!("{:6.0} Hz: divisor {}, cycle time {}, sample delay {} -> {:.1} Hz, {} steps",
+                         frequency, divisor, cycle_time, sample_delay, info.pwm_frequency, info.pwm_steps);
+                motors.set_speeds(options.duty, options.duty);
+                sleep(Duration::from_secs_f64(options.dwell));
+            },
+            Err(e) => println!("{:6.0} Hz: {}", frequency, e)
+        }
+        frequency += options.step;
+    }
+    motors.stop_all();
+    println!("Done");
+}
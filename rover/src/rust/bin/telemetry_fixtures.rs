@@ -0,0 +1,43 @@
+//
+// Copyright (C) 2020 Abstract Horizon
+// All rights reserved. This program and the accompanying materials
+// are made available under the terms of the Apache License v2.0
+// which accompanies this distribution, and is available at
+// https://www.apache.org/licenses/LICENSE-2.0
+//
+//  Contributors:
+//    Daniel Sendula - initial API and implementation
+//
+
+// Writes the telemetry wire format fixtures and their manifest (see telemetry_fixtures.rs):
+//
+//   telemetry-fixtures [output directory, default src/rust/telemetry_fixtures]
+
+use std::fs;
+use std::path::PathBuf;
+use std::process;
+
+use balancing_rover::telemetry_fixtures;
+
+
+fn main() {
+    let dir = PathBuf::from(std::env::args().nth(1).unwrap_or("src/rust/telemetry_fixtures".to_string()));
+
+    let files = telemetry_fixtures::generate();
+    let mut written = vec![(dir.join("manifest.json"), telemetry_fixtures::manifest(&files).into_bytes())];
+    written.extend(files.into_iter().map(|file| (dir.join(file.name), file.bytes)));
+
+    if let Err(e) = fs::create_dir_all(&dir) {
+        println!("Cannot create {:?}: {}", dir, e);
+        process::exit(1);
+    }
+    for (path, bytes) in written {
+        match fs::write(&path, &bytes) {
+            Ok(()) => println!("Written {:?} ({} bytes)", path, bytes.len()),
+            Err(e) => {
+                println!("Cannot write {:?}: {}", path, e);
+                process::exit(1);
+            }
+        }
+    }
+}
@@ -0,0 +1,175 @@
+//
+// Copyright (C) 2020 Abstract Horizon
+// All rights reserved. This program and the accompanying materials
+// are made available under the terms of the Apache License v2.0
+// which accompanies this distribution, and is available at
+// https://www.apache.org/licenses/LICENSE-2.0
+//
+//  Contributors:
+//    Daniel Sendula - initial API and implementation
+//
+
+// One report of what this rover was built with and what it found at startup, for debugging a
+// fleet remotely. Published retained to balancing/capabilities, appended to telemetry META
+// frames and printed by --self-check.
+//
+// The schema is fixed (see capabilities_golden.json): every key is always present and parts
+// that were not gathered, because their subsystem is not running, are reported as "unavailable".
+// Bump CAPABILITIES_VERSION when changing it.
+
+use dma_gpio::pi::BoardInfo;
+use rppal::i2c::Error;
+
+use crate::i2c_bus::SharedBus;
+
+
+pub const CAPABILITIES_VERSION: u32 = 1;
+const UNAVAILABLE: &str = "\"unavailable\"";
+
+
+#[derive(Clone, Debug)]
+pub struct SensorProbe {
+    pub name: &'static str,
+    pub bus: u8,
+    pub address: u16,
+    // Value of the id (or status) register; None if the bus is not open or the device did not answer
+    pub id: Option<u8>,
+    pub detected: bool,
+}
+
+impl SensorProbe {
+    fn to_json(&self) -> String {
+        format!("{{ \"name\" : \"{}\", \"bus\" : {}, \"address\" : {}, \"id\" : {}, \"detected\" : {} }}",
+            self.name, self.bus, self.address,
+            self.id.map_or(UNAVAILABLE.to_string(), |id| id.to_string()),
+            self.detected)
+    }
+}
+
+// Reads register of the device at address; the device is detected when it answers, and with
+// expected set, only when it answers with that value.
+pub fn probe_sensor(name: &'static str, bus_no: u8, bus: Option<&SharedBus>, address: u16, register: u8, expected: Option<u8>) -> SensorProbe {
+    let id = bus.and_then(|bus| {
+        let result: Result<u8, Error> = bus.with_device(address, |dev| dev.smbus_read_byte(register));
+        result.ok()
+    });
+    let detected = match (id, expected) {
+        (Some(id), Some(expected)) => id == expected,
+        (Some(_), None) => true,
+        (None, _) => false,
+    };
+    SensorProbe { name, bus: bus_no, address, id, detected }
+}
+
+
+pub struct Capabilities {
+    // Compile time features of the rover and of dma_gpio (prefixed "dma_gpio/")
+    pub features: Vec<(String, bool)>,
+    // Motor board, once the motors are set up
+    pub board: Option<BoardInfo>,
+    pub sensors: Option<Vec<SensorProbe>>,
+    // Telemetry transports, e.g. "tcp" or "tcp+serial"
+    pub telemetry: Option<String>,
+    pub heading_source: Option<&'static str>,
+}
+
+impl Capabilities {
+    // Only the compile time features are known up front.
+    pub fn new() -> Capabilities {
+        Capabilities {
+            features: compiled_features(),
+            board: None,
+            sensors: None,
+            telemetry: None,
+            heading_source: None,
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        let features: Vec<String> = self.features.iter().map(|(name, on)| format!("\"{}\" : {}", name, on)).collect();
+
+        let board = match &self.board {
+            Some(board) => {
+                let pins: Vec<String> = board.pins.iter().map(|pin| pin.to_string()).collect();
+                format!("{{ \"model\" : {}, \"revision\" : \"{:#x}\", \"pacing\" : \"{}\", \"dma_channel\" : {}, \"pins\" : [{}], \"pwm_frequency\" : {}, \"pwm_steps\" : {}, \"dry_run\" : {} }}",
+                    board.model, board.revision, board.pacing, board.dma_channel, pins.join(", "), board.pwm_frequency, board.pwm_steps, board.dry_run)
+            },
+            None => UNAVAILABLE.to_string()
+        };
+
+        let sensors = match &self.sensors {
+            Some(sensors) => {
+                let sensors: Vec<String> = sensors.iter().map(|sensor| sensor.to_json()).collect();
+                format!("[{}]", sensors.join(", "))
+            },
+            None => UNAVAILABLE.to_string()
+        };
+
+        let pacing = self.board.as_ref().map_or(UNAVAILABLE.to_string(), |board| format!("\"{}\"", board.pacing));
+        let platform = self.board.as_ref().map_or(UNAVAILABLE.to_string(), |board| format!("\"{}\"", if board.dry_run { "dry-run" } else { "hardware" }));
+        let telemetry = self.telemetry.as_ref().map_or(UNAVAILABLE.to_string(), |telemetry| format!("\"{}\"", telemetry));
+        let heading_source = self.heading_source.map_or(UNAVAILABLE.to_string(), |heading_source| format!("\"{}\"", heading_source));
+
+        format!("{{ \"version\" : {}, \"features\" : {{ {} }}, \"board\" : {}, \"sensors\" : {}, \"backends\" : {{ \"platform\" : {}, \"pacing\" : {}, \"telemetry\" : {}, \"heading_source\" : {} }} }}",
+            CAPABILITIES_VERSION, features.join(", "), board, sensors, platform, pacing, telemetry, heading_source)
+    }
+}
+
+impl Default for Capabilities {
+    fn default() -> Capabilities {
+        Capabilities::new()
+    }
+}
+
+pub fn compiled_features() -> Vec<(String, bool)> {
+    let mut features = vec![("mdns".to_string(), cfg!(feature = "mdns"))];
+    for (name, on) in dma_gpio::FEATURES.iter() {
+        features.push((format!("dma_gpio/{}", name), *on));
+    }
+    features
+}
+
+
+#[cfg(test)]
+mod tests {
+    use dma_gpio::pi::BoardBuilder;
+
+    use crate::test_support::lock_boards;
+    use super::*;
+
+    fn features() -> Vec<(String, bool)> {
+        vec![
+            ("mdns".to_string(), false),
+            ("dma_gpio/bind_process".to_string(), false),
+            ("dma_gpio/debug".to_string(), false),
+            ("dma_gpio/stats".to_string(), true),
+        ]
+    }
+
+    // Downstream tools parse the report; its layout must only change on purpose.
+    #[test]
+    fn nothing_gathered_yet_is_reported_as_unavailable() {
+        let mut capabilities = Capabilities::new();
+        capabilities.features = features();
+        assert_eq!(capabilities.to_json().trim(), include_str!("capabilities_unavailable_golden.json").trim());
+    }
+
+    #[test]
+    fn gathered_report_keeps_its_layout() {
+        let _boards = lock_boards();
+        let board = BoardBuilder::new().dry_run(true).divide_pwm(1000).set_cycle_time(200).set_sample_delay(2)
+            .build_with_pins(vec![20, 26]).unwrap();
+
+        let mut capabilities = Capabilities::new();
+        capabilities.features = features();
+        capabilities.board = Some(board.info());
+        capabilities.sensors = Some(vec![
+            SensorProbe { name: "L3G4200D", bus: 1, address: 0x69, id: Some(0xD3), detected: true },
+            SensorProbe { name: "ADXL345", bus: 1, address: 0x53, id: Some(0x00), detected: false },
+            probe_sensor("AS5600 left", 0, None, 0x36, 0x0B, None),
+        ]);
+        capabilities.telemetry = Some("tcp+serial".to_string());
+        capabilities.heading_source = Some("gyro");
+        assert_eq!(capabilities.to_json().trim(), include_str!("capabilities_golden.json").trim());
+    }
+}
@@ -0,0 +1,143 @@
+//
+// Copyright (C) 2020 Abstract Horizon
+// All rights reserved. This program and the accompanying materials
+// are made available under the terms of the Apache License v2.0
+// which accompanies this distribution, and is available at
+// https://www.apache.org/licenses/LICENSE-2.0
+//
+//  Contributors:
+//    Daniel Sendula - initial API and implementation
+//
+
+// ConfigData as a single JSON object of its config topic fields, for balancing/config/save and
+// balancing/config/load:
+//
+//   { "combine_gyro_factor" : 0.3, "gyro_range" : 250, ..., "log_every_n" : 1 }
+//
+// A loaded snapshot may name only some of the fields; the others keep their current values.
+
+use crate::applied::Applied;
+use crate::balance::ConfigData;
+use crate::config_topics;
+use crate::telemetry_reader;
+
+
+// What a snapshot changed over the current config.
+pub struct LoadOutcome {
+    pub config_data: ConfigData,
+    // Config topic, field and value of every live field in the snapshot
+    pub applied: Vec<(&'static str, &'static str, f64)>,
+    // Structural fields that differ from the current config; they only take effect on restart
+    pub deferred: Vec<&'static str>,
+}
+
+// Why a snapshot was refused; field is None when the payload itself could not be read.
+pub struct Rejected {
+    pub field: Option<String>,
+    pub reason: String,
+}
+
+impl Rejected {
+    fn field(field: &str, reason: String) -> Rejected {
+        Rejected { field: Some(field.to_string()), reason }
+    }
+
+    pub fn to_json(&self) -> String {
+        format!("\"field\" : {}, \"error\" : {:?}",
+            match &self.field { Some(field) => format!("{:?}", field), None => "null".to_string() },
+            self.reason)
+    }
+}
+
+pub fn to_json(config_data: &ConfigData) -> String {
+    let fields: Vec<String> = config_topics::CONFIG_TOPICS.iter()
+        .filter_map(|config_topic| config_topic.field.as_ref())
+        .map(|field| format!("\"{}\" : {}", field.name, (field.get)(config_data)))
+        .collect();
+    format!("{{ {} }}", fields.join(", "))
+}
+
+// Unlike single config topics, values out of range are not clamped: one bad field refuses the
+// whole snapshot, so the balance loop never gets half of it.
+pub fn load(json: &str, current: &ConfigData) -> Result<LoadOutcome, Rejected> {
+    let members = telemetry_reader::parse_number_members(json).map_err(|reason| Rejected { field: None, reason })?;
+
+    let mut values = vec![];
+    for (name, value) in members {
+        match config_topics::CONFIG_TOPICS.iter().find(|config_topic| config_topic.field.as_ref().is_some_and(|field| field.name == name)) {
+            Some(config_topic) => values.push((config_topic, value)),
+            None => return Err(Rejected::field(&name, "unknown field".to_string()))
+        }
+    }
+    let outcome = config_topics::apply_fields(current, values, |config_topic, value| config_topic.check(value).map(|_| Applied::Applied(value)))
+        .map_err(|(field, reason)| Rejected::field(field, reason))?;
+    outcome.config_data.validate().map_err(|(field, reason)| Rejected::field(field, reason))?;
+    Ok(LoadOutcome {
+        applied: outcome.applied.iter()
+            .filter_map(|(config_topic, _)| config_topic.field.as_ref().map(|field| (config_topic.topic, field.name, (field.get)(&outcome.config_data))))
+            .collect(),
+        config_data: outcome.config_data,
+        deferred: outcome.deferred,
+    })
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::gyro::Range;
+    use super::*;
+
+    fn tuned() -> ConfigData {
+        let mut tuned = ConfigData::new();
+        tuned.pid_kp = 1.25;
+        tuned.gyro_range = Range::Range500;
+        tuned.rezero_on_gyro_overrun = true;
+        tuned
+    }
+
+    #[test]
+    fn saved_snapshot_loads_back_whole() {
+        let defaults = ConfigData::new();
+        assert!(defaults.validate().is_ok());
+
+        let saved = to_json(&tuned());
+        let outcome = load(&saved, &defaults).unwrap_or_else(|e| panic!("saved snapshot refused: {}", e.reason));
+        assert_eq!(to_json(&outcome.config_data), saved);
+    }
+
+    #[test]
+    fn partial_snapshot_leaves_other_fields_alone() {
+        let outcome = load("{ \"pid_kd\" : 0.1 }", &tuned()).unwrap_or_else(|e| panic!("partial snapshot refused: {}", e.reason));
+        assert_eq!(outcome.config_data.pid_kd, 0.1);
+        assert_eq!(outcome.config_data.pid_kp, 1.25);
+        assert_eq!(outcome.applied, vec![("balance/pid_inner/d", "pid_kd", 0.1)]);
+    }
+
+    #[test]
+    fn one_bad_field_refuses_the_whole_snapshot() {
+        for (json, field) in [
+            ("{ \"pid_kp\" : 0.5, \"max_degree\" : 1000 }", Some("max_degree")),
+            ("{ \"pid_kp\" : 0.5, \"pid_kq\" : 1 }", Some("pid_kq")),
+            ("{ \"pid_kp\" : \"fast\" }", None),
+            ("{ \"pid_kp\" : 0.5", None),
+        ].iter() {
+            match load(json, &tuned()) {
+                Ok(_) => panic!("{} accepted", json),
+                Err(rejected) => assert_eq!(rejected.field.as_deref(), *field, "{} refused: {}", json, rejected.reason)
+            }
+        }
+    }
+
+    #[test]
+    fn structural_fields_are_checked_but_deferred() {
+        let outcome = load("{ \"pid_kd\" : 0.1, \"i2c_soft_deadline\" : 0.005 }", &tuned()).unwrap_or_else(|e| panic!("snapshot refused: {}", e.reason));
+        assert_eq!(outcome.config_data.i2c_soft_deadline, tuned().i2c_soft_deadline);
+        assert_eq!(outcome.deferred, vec!["i2c_soft_deadline"]);
+        assert_eq!(outcome.applied, vec![("balance/pid_inner/d", "pid_kd", 0.1)]);
+
+        match load("{ \"i2c_soft_deadline\" : 5 }", &tuned()) {
+            Ok(_) => panic!("deadline of 5s accepted"),
+            Err(rejected) => assert_eq!(rejected.field.as_deref(), Some("i2c_soft_deadline"))
+        }
+    }
+}
@@ -0,0 +1,490 @@
+//
+// Copyright (C) 2020 Abstract Horizon
+// All rights reserved. This program and the accompanying materials
+// are made available under the terms of the Apache License v2.0
+// which accompanies this distribution, and is available at
+// https://www.apache.org/licenses/LICENSE-2.0
+//
+//  Contributors:
+//    Daniel Sendula - initial API and implementation
+//
+
+use std::collections::HashMap;
+
+use crate::accel::Averaging;
+use crate::applied::Applied;
+use crate::gyro::Range;
+use crate::balance::{ConfigData, HeadingSource, MIN_OUTPUT_SCALE, MAX_OUTPUT_SCALE, MAX_TURN_OUTPUT,
+                     MIN_TURN_TOLERANCE, MAX_TURN_TOLERANCE, MIN_TURN_TIMEOUT, MAX_TURN_TIMEOUT, MAX_LOG_EVERY_N,
+                     MIN_START_DEGREE, MAX_START_DEGREE, MIN_MAX_DEGREE, MAX_MAX_DEGREE, MAX_DEAD_BAND, MAX_OUTER_DEAD_BAND,
+                     MIN_GAIN_SCALE, MAX_GAIN_SCALE, MIN_DRIVE_TIMEOUT, MAX_DRIVE_TIMEOUT,
+                     MIN_WATCHDOG_LOOP_TIME, MAX_WATCHDOG_LOOP_TIME, MAX_WATCHDOG_GYRO_FAILURES, MAX_WATCHDOG_ACCEL_FAILURES,
+                     MIN_LOOP_WARNING_TIME, MAX_LOOP_WARNING_TIME, MIN_I2C_DEADLINE, MAX_I2C_DEADLINE,
+                     MAX_SATURATION_STEPS, MIN_WINDUP_WARNING_TIME, MAX_WINDUP_WARNING_TIME,
+                     FusionAlgorithm, MAX_FUSION_KP, MAX_FUSION_KI,
+                     MIN_MOTOR_TRIM, MAX_MOTOR_TRIM, MAX_MOTOR_MIN_DUTY, MAX_MOTOR_DEADBAND, MAX_MOTOR_MAX_ACCEL,
+                     MIN_VELOCITY_FILTER};
+
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Apply {
+    // Sent to the balance loop as soon as it arrives
+    Live,
+    // Only picked up when the rover is restarted
+    Structural,
+}
+
+impl Apply {
+    fn name(&self) -> &'static str {
+        match self {
+            Apply::Live => "live",
+            Apply::Structural => "structural",
+        }
+    }
+}
+
+pub struct ConfigField {
+    pub name: &'static str,
+    pub get: fn(&ConfigData) -> f64,
+    pub set: fn(&mut ConfigData, f64),
+}
+
+pub struct ConfigTopic {
+    // Topic below storage/write/ and storage/read/
+    pub topic: &'static str,
+    pub value_type: &'static str,
+    pub min: Option<f64>,
+    pub max: Option<f64>,
+    pub apply: Apply,
+    // None for topics that are subscribed to but not wired to anything yet
+    pub field: Option<ConfigField>,
+    pub description: &'static str,
+}
+
+impl ConfigTopic {
+    // Clamps value into the topic's range; values that are not numbers are rejected.
+    pub fn validate(&self, value: f64) -> Applied {
+        Applied::clamp(value, self.min, self.max)
+    }
+
+    // Strict form of validate, for whole snapshots: out of range values are refused, not clamped.
+    pub fn check(&self, value: f64) -> Result<(), String> {
+        if !value.is_finite() {
+            Err(format!("{} is not a number", value))
+        } else if self.min.is_some_and(|min| value < min) {
+            Err(format!("{} is below {}", value, self.min.unwrap()))
+        } else if self.max.is_some_and(|max| value > max) {
+            Err(format!("{} is above {}", value, self.max.unwrap()))
+        } else {
+            Ok(())
+        }
+    }
+
+    pub fn default_value(&self) -> Option<f64> {
+        self.field.as_ref().map(|field| (field.get)(&ConfigData::new()))
+    }
+}
+
+pub static CONFIG_TOPICS: [ConfigTopic; 48] = [
+    ConfigTopic {
+        topic: "balance/gyro/filter", value_type: "float", min: None, max: None, apply: Apply::Live,
+        field: Some(ConfigField { name: "combine_gyro_factor", get: |c| c.combine_gyro_factor, set: |c, f| c.combine_gyro_factor = f }),
+        description: "Low pass filter factor applied to gyro readings",
+    },
+    ConfigTopic {
+        topic: "balance/gyro/range", value_type: "int", min: Some(250.0), max: Some(2000.0), apply: Apply::Live,
+        field: Some(ConfigField { name: "gyro_range", get: |c| c.gyro_range.dps(), set: |c, f| c.gyro_range = Range::from_dps(f) }),
+        description: "Gyro full scale in degrees per second: 250, 500 or 2000; other values take the next range up",
+    },
+    ConfigTopic {
+        topic: "balance/accel/filter", value_type: "float", min: None, max: None, apply: Apply::Live,
+        field: Some(ConfigField { name: "combine_accel_factor", get: |c| c.combine_accel_factor, set: |c, f| c.combine_accel_factor = f }),
+        description: "Low pass filter factor applied to accelerometer readings",
+    },
+    ConfigTopic {
+        topic: "balance/accel/averaging", value_type: "int", min: Some(0.0), max: Some(1.0), apply: Apply::Live,
+        field: Some(ConfigField { name: "accel_averaging", get: |c| c.accel_averaging.value(), set: |c, f| c.accel_averaging = Averaging::from_value(f) }),
+        description: "Accelerometer samples read from its FIFO in one loop: 0 takes the newest, 1 their mean",
+    },
+    ConfigTopic {
+        topic: "balance/combine_factor_gyro", value_type: "float", min: None, max: None, apply: Apply::Live,
+        field: Some(ConfigField { name: "combine_gyro_accel_factor", get: |c| c.combine_gyro_accel_factor, set: |c, f| c.combine_gyro_accel_factor = f }),
+        description: "Complementary filter weight of the gyro against the accelerometer",
+    },
+    ConfigTopic {
+        topic: "balance/fusion/algorithm", value_type: "int", min: Some(0.0), max: Some(1.0), apply: Apply::Live,
+        field: Some(ConfigField { name: "fusion", get: |c| c.fusion.value(), set: |c, f| c.fusion = FusionAlgorithm::from_value(f) }),
+        description: "Sensor fusion: 0 complementary filter (balance/combine_factor_gyro), 1 Mahony filter (balance/fusion/kp and ki)",
+    },
+    ConfigTopic {
+        topic: "balance/fusion/kp", value_type: "float", min: Some(0.0), max: Some(MAX_FUSION_KP), apply: Apply::Live,
+        field: Some(ConfigField { name: "fusion_kp", get: |c| c.fusion_kp, set: |c, f| c.fusion_kp = f }),
+        description: "Mahony filter proportional gain pulling the attitude towards gravity",
+    },
+    ConfigTopic {
+        topic: "balance/fusion/ki", value_type: "float", min: Some(0.0), max: Some(MAX_FUSION_KI), apply: Apply::Live,
+        field: Some(ConfigField { name: "fusion_ki", get: |c| c.fusion_ki, set: |c, f| c.fusion_ki = f }),
+        description: "Mahony filter integral gain taking out gyro bias; 0 turns it off",
+    },
+    ConfigTopic {
+        topic: "balance/pid_inner/p", value_type: "float", min: None, max: None, apply: Apply::Live,
+        field: Some(ConfigField { name: "pid_kp", get: |c| c.pid_kp, set: |c, f| c.pid_kp = f }),
+        description: "Inner (angle) PID proportional gain",
+    },
+    ConfigTopic {
+        topic: "balance/pid_inner/i", value_type: "float", min: None, max: None, apply: Apply::Live,
+        field: Some(ConfigField { name: "pid_ki", get: |c| c.pid_ki, set: |c, f| c.pid_ki = f }),
+        description: "Inner (angle) PID integral gain",
+    },
+    ConfigTopic {
+        topic: "balance/pid_inner/d", value_type: "float", min: None, max: None, apply: Apply::Live,
+        field: Some(ConfigField { name: "pid_kd", get: |c| c.pid_kd, set: |c, f| c.pid_kd = f }),
+        description: "Inner (angle) PID derivative gain",
+    },
+    ConfigTopic {
+        topic: "balance/pid_inner/g", value_type: "float", min: None, max: None, apply: Apply::Live,
+        field: Some(ConfigField { name: "pid_gain", get: |c| c.pid_gain, set: |c, f| c.pid_gain = f }),
+        description: "Inner (angle) PID overall gain",
+    },
+    ConfigTopic {
+        topic: "balance/pid_inner/dead_band", value_type: "float", min: Some(0.0), max: Some(MAX_DEAD_BAND), apply: Apply::Live,
+        field: Some(ConfigField { name: "dead_band", get: |c| c.dead_band, set: |c, f| c.dead_band = f }),
+        description: "Angle error in degrees below which the inner PID does not react",
+    },
+    ConfigTopic {
+        topic: "balance/pid_inner/i_scale", value_type: "float", min: Some(MIN_GAIN_SCALE), max: Some(MAX_GAIN_SCALE), apply: Apply::Live,
+        field: Some(ConfigField { name: "i_gain_scale", get: |c| c.i_gain_scale, set: |c, f| c.i_gain_scale = f }),
+        description: "Scale of the time step the inner PID integrates over",
+    },
+    ConfigTopic {
+        topic: "balance/pid_inner/d_scale", value_type: "float", min: Some(MIN_GAIN_SCALE), max: Some(MAX_GAIN_SCALE), apply: Apply::Live,
+        field: Some(ConfigField { name: "d_gain_scale", get: |c| c.d_gain_scale, set: |c, f| c.d_gain_scale = f }),
+        description: "Scale of the time step the inner PID differentiates over",
+    },
+    ConfigTopic {
+        topic: "balance/output_scale", value_type: "float", min: Some(MIN_OUTPUT_SCALE), max: Some(MAX_OUTPUT_SCALE), apply: Apply::Live,
+        field: Some(ConfigField { name: "output_scale", get: |c| c.output_scale, set: |c, f| c.output_scale = f }),
+        description: "Scale applied to the PID output before it reaches the motors",
+    },
+    ConfigTopic {
+        topic: "balance/pid_outer/p", value_type: "float", min: None, max: None, apply: Apply::Live,
+        field: Some(ConfigField { name: "pid_outer_kp", get: |c| c.pid_outer_kp, set: |c, f| c.pid_outer_kp = f }),
+        description: "Outer (speed) PID proportional gain",
+    },
+    ConfigTopic {
+        topic: "balance/pid_outer/i", value_type: "float", min: None, max: None, apply: Apply::Live,
+        field: Some(ConfigField { name: "pid_outer_ki", get: |c| c.pid_outer_ki, set: |c, f| c.pid_outer_ki = f }),
+        description: "Outer (speed) PID integral gain",
+    },
+    ConfigTopic {
+        topic: "balance/pid_outer/d", value_type: "float", min: None, max: None, apply: Apply::Live,
+        field: Some(ConfigField { name: "pid_outer_kd", get: |c| c.pid_outer_kd, set: |c, f| c.pid_outer_kd = f }),
+        description: "Outer (speed) PID derivative gain",
+    },
+    ConfigTopic {
+        topic: "balance/pid_outer/g", value_type: "float", min: None, max: None, apply: Apply::Live,
+        field: Some(ConfigField { name: "pid_outer_gain", get: |c| c.pid_outer_gain, set: |c, f| c.pid_outer_gain = f }),
+        description: "Outer (speed) PID overall gain",
+    },
+    ConfigTopic {
+        topic: "balance/pid_outer/dead_band", value_type: "float", min: Some(0.0), max: Some(MAX_OUTER_DEAD_BAND), apply: Apply::Live,
+        field: Some(ConfigField { name: "pid_outer_dead_band", get: |c| c.pid_outer_dead_band, set: |c, f| c.pid_outer_dead_band = f }),
+        description: "Speed error in m/s below which the outer PID does not react",
+    },
+    ConfigTopic {
+        topic: "balance/velocity/gain", value_type: "float", min: None, max: None, apply: Apply::Live,
+        field: Some(ConfigField { name: "velocity_gain", get: |c| c.velocity_gain, set: |c, f| c.velocity_gain = f }),
+        description: "Motor command taken off per m/s of filtered wheel speed while balancing; 0 turns it off",
+    },
+    ConfigTopic {
+        topic: "balance/velocity/filter", value_type: "float", min: Some(MIN_VELOCITY_FILTER), max: Some(1.0), apply: Apply::Live,
+        field: Some(ConfigField { name: "velocity_filter", get: |c| c.velocity_filter, set: |c, f| c.velocity_filter = f }),
+        description: "Low pass filter factor applied to wheel speed for velocity feedback and the outer PID; 1 is no filtering",
+    },
+    ConfigTopic {
+        topic: "move/turn_by/p", value_type: "float", min: None, max: None, apply: Apply::Live,
+        field: Some(ConfigField { name: "turn_kp", get: |c| c.turn_kp, set: |c, f| c.turn_kp = f }),
+        description: "Heading PID proportional gain of move/turn_by",
+    },
+    ConfigTopic {
+        topic: "move/turn_by/i", value_type: "float", min: None, max: None, apply: Apply::Live,
+        field: Some(ConfigField { name: "turn_ki", get: |c| c.turn_ki, set: |c, f| c.turn_ki = f }),
+        description: "Heading PID integral gain of move/turn_by",
+    },
+    ConfigTopic {
+        topic: "move/turn_by/d", value_type: "float", min: None, max: None, apply: Apply::Live,
+        field: Some(ConfigField { name: "turn_kd", get: |c| c.turn_kd, set: |c, f| c.turn_kd = f }),
+        description: "Heading PID derivative gain of move/turn_by",
+    },
+    ConfigTopic {
+        topic: "move/turn_by/max_output", value_type: "float", min: Some(0.0), max: Some(MAX_TURN_OUTPUT), apply: Apply::Live,
+        field: Some(ConfigField { name: "turn_max_output", get: |c| c.turn_max_output, set: |c, f| c.turn_max_output = f }),
+        description: "Largest differential duty turns and move/rotate add on top of the balancing output; also the heading PID's integrator limit",
+    },
+    ConfigTopic {
+        topic: "move/turn_by/tolerance", value_type: "float", min: Some(MIN_TURN_TOLERANCE), max: Some(MAX_TURN_TOLERANCE), apply: Apply::Live,
+        field: Some(ConfigField { name: "turn_tolerance", get: |c| c.turn_tolerance, set: |c, f| c.turn_tolerance = f }),
+        description: "Degrees from the target heading at which a turn counts as done",
+    },
+    ConfigTopic {
+        topic: "move/turn_by/timeout", value_type: "float", min: Some(MIN_TURN_TIMEOUT), max: Some(MAX_TURN_TIMEOUT), apply: Apply::Live,
+        field: Some(ConfigField { name: "turn_timeout", get: |c| c.turn_timeout, set: |c, f| c.turn_timeout = f }),
+        description: "Seconds a turn may take before it is given up",
+    },
+    ConfigTopic {
+        topic: "move/turn_by/heading_source", value_type: "int", min: Some(0.0), max: Some(1.0), apply: Apply::Live,
+        field: Some(ConfigField { name: "turn_heading_source", get: |c| c.turn_heading_source.value(), set: |c, f| c.turn_heading_source = HeadingSource::from_value(f) }),
+        description: "Heading used for turns: 0 - integrated gyro, 1 - wheel odometry",
+    },
+    ConfigTopic {
+        topic: "move/drive/timeout", value_type: "float", min: Some(MIN_DRIVE_TIMEOUT), max: Some(MAX_DRIVE_TIMEOUT), apply: Apply::Live,
+        field: Some(ConfigField { name: "drive_timeout", get: |c| c.drive_timeout, set: |c, f| c.drive_timeout = f }),
+        description: "Seconds move/drive and move/rotate hold before ramping down to a stop",
+    },
+    ConfigTopic {
+        topic: "balance/max_degree", value_type: "float", min: Some(MIN_MAX_DEGREE), max: Some(MAX_MAX_DEGREE), apply: Apply::Live,
+        field: Some(ConfigField { name: "max_degree", get: |c| c.max_degree, set: |c, f| c.max_degree = f }),
+        description: "Pitch in degrees beyond which the motors are cut",
+    },
+    ConfigTopic {
+        topic: "balance/start_degree", value_type: "float", min: Some(MIN_START_DEGREE), max: Some(MAX_START_DEGREE), apply: Apply::Live,
+        field: Some(ConfigField { name: "start_degree", get: |c| c.start_degree, set: |c, f| c.start_degree = f }),
+        description: "Pitch in degrees within which balancing starts",
+    },
+    ConfigTopic {
+        topic: "balance/watchdog/loop_time", value_type: "float", min: Some(MIN_WATCHDOG_LOOP_TIME), max: Some(MAX_WATCHDOG_LOOP_TIME), apply: Apply::Live,
+        field: Some(ConfigField { name: "watchdog_loop_time", get: |c| c.watchdog_loop_time, set: |c, f| c.watchdog_loop_time = f }),
+        description: "Seconds a control loop may take before the watchdog stops the motors",
+    },
+    ConfigTopic {
+        topic: "balance/watchdog/gyro_failures", value_type: "int", min: Some(1.0), max: Some(MAX_WATCHDOG_GYRO_FAILURES), apply: Apply::Live,
+        field: Some(ConfigField { name: "watchdog_gyro_failures", get: |c| c.watchdog_gyro_failures as f64, set: |c, f| c.watchdog_gyro_failures = f as u32 }),
+        description: "Gyro reads failing in a row before the watchdog stops the motors",
+    },
+    ConfigTopic {
+        topic: "balance/watchdog/accel_failures", value_type: "int", min: Some(1.0), max: Some(MAX_WATCHDOG_ACCEL_FAILURES), apply: Apply::Live,
+        field: Some(ConfigField { name: "watchdog_accel_failures", get: |c| c.watchdog_accel_failures as f64, set: |c, f| c.watchdog_accel_failures = f as u32 }),
+        description: "Accelerometer reads failing in a row before the watchdog stops the motors",
+    },
+    ConfigTopic {
+        topic: "balance/loop/warning_time", value_type: "float", min: Some(MIN_LOOP_WARNING_TIME), max: Some(MAX_LOOP_WARNING_TIME), apply: Apply::Live,
+        field: Some(ConfigField { name: "loop_warning_time", get: |c| c.loop_warning_time, set: |c, f| c.loop_warning_time = f }),
+        description: "Seconds a pass of the control loop may take before it counts as an overrun and is warned about",
+    },
+    ConfigTopic {
+        topic: "balance/windup/steps", value_type: "int", min: Some(1.0), max: Some(MAX_SATURATION_STEPS), apply: Apply::Live,
+        field: Some(ConfigField { name: "saturation_steps", get: |c| c.saturation_steps as f64, set: |c, f| c.saturation_steps = f as u32 }),
+        description: "Loop passes in a row at full duty before the output counts as saturated",
+    },
+    ConfigTopic {
+        topic: "balance/windup/time", value_type: "float", min: Some(MIN_WINDUP_WARNING_TIME), max: Some(MAX_WINDUP_WARNING_TIME), apply: Apply::Live,
+        field: Some(ConfigField { name: "windup_warning_time", get: |c| c.windup_warning_time, set: |c, f| c.windup_warning_time = f }),
+        description: "Seconds saturation and a wound up inner PID integrator must last together before it is warned about on balance/warnings",
+    },
+    ConfigTopic {
+        topic: "balance/i2c/soft_deadline", value_type: "float", min: Some(MIN_I2C_DEADLINE), max: Some(MAX_I2C_DEADLINE), apply: Apply::Structural,
+        field: Some(ConfigField { name: "i2c_soft_deadline", get: |c| c.i2c_soft_deadline, set: |c, f| c.i2c_soft_deadline = f }),
+        description: "Seconds an i2c transaction may hold the bus before it is counted and logged as an overrun",
+    },
+    ConfigTopic {
+        topic: "balance/i2c/hard_deadline", value_type: "float", min: Some(MIN_I2C_DEADLINE), max: Some(MAX_I2C_DEADLINE), apply: Apply::Structural,
+        field: Some(ConfigField { name: "i2c_hard_deadline", get: |c| c.i2c_hard_deadline, set: |c, f| c.i2c_hard_deadline = f }),
+        description: "Seconds after which an i2c transaction fails as timed out; the kernel rounds it up to 10ms steps",
+    },
+    ConfigTopic {
+        topic: "balance/gyro/rezero_on_overrun", value_type: "int", min: Some(0.0), max: Some(1.0), apply: Apply::Live,
+        field: Some(ConfigField { name: "rezero_on_gyro_overrun", get: |c| c.rezero_on_gyro_overrun as u8 as f64, set: |c, f| c.rezero_on_gyro_overrun = f != 0.0 }),
+        description: "1 takes roll and pitch from the accelerometer alone after gyro samples were lost to a FIFO overrun",
+    },
+    ConfigTopic {
+        topic: "motors/left_trim", value_type: "float", min: Some(MIN_MOTOR_TRIM), max: Some(MAX_MOTOR_TRIM), apply: Apply::Live,
+        field: Some(ConfigField { name: "motor_left_trim", get: |c| c.motor_left_trim, set: |c, f| c.motor_left_trim = f }),
+        description: "Scale of the left motor's duty, to match a motor that turns slower or faster for the same duty",
+    },
+    ConfigTopic {
+        topic: "motors/right_trim", value_type: "float", min: Some(MIN_MOTOR_TRIM), max: Some(MAX_MOTOR_TRIM), apply: Apply::Live,
+        field: Some(ConfigField { name: "motor_right_trim", get: |c| c.motor_right_trim, set: |c, f| c.motor_right_trim = f }),
+        description: "Scale of the right motor's duty",
+    },
+    ConfigTopic {
+        topic: "motors/min_duty", value_type: "float", min: Some(0.0), max: Some(MAX_MOTOR_MIN_DUTY), apply: Apply::Live,
+        field: Some(ConfigField { name: "motor_min_duty", get: |c| c.motor_min_duty, set: |c, f| c.motor_min_duty = f }),
+        description: "Duty any nonzero speed starts from, so small outputs still overcome static friction",
+    },
+    ConfigTopic {
+        topic: "motors/deadband", value_type: "float", min: Some(0.0), max: Some(MAX_MOTOR_DEADBAND), apply: Apply::Live,
+        field: Some(ConfigField { name: "motor_deadband", get: |c| c.motor_deadband, set: |c, f| c.motor_deadband = f }),
+        description: "Speeds below this stop both motors instead of getting the minimum duty",
+    },
+    ConfigTopic {
+        topic: "motors/max_accel", value_type: "float", min: Some(0.0), max: Some(MAX_MOTOR_MAX_ACCEL), apply: Apply::Live,
+        field: Some(ConfigField { name: "motor_max_accel", get: |c| c.motor_max_accel, set: |c, f| c.motor_max_accel = f }),
+        description: "Full scale per second manual speeds and drive commands ramp by, 0 for no ramping; balancing corrections are not limited",
+    },
+    ConfigTopic {
+        topic: "balance/telemetry/divider", value_type: "int", min: Some(0.0), max: Some(MAX_LOG_EVERY_N), apply: Apply::Live,
+        field: Some(ConfigField { name: "log_every_n", get: |c| c.log_every_n as f64, set: |c, f| c.log_every_n = f as u16 }),
+        description: "Only every n-th sample is logged to telemetry; 0 turns telemetry off without affecting balancing",
+    },
+];
+
+pub fn find(topic: &str) -> Option<&'static ConfigTopic> {
+    CONFIG_TOPICS.iter().find(|config_topic| config_topic.topic == topic)
+}
+
+// What a set of field values changed over the current config.
+pub struct FieldsApplied {
+    pub config_data: ConfigData,
+    // Config topic and outcome of every live field among the values
+    pub applied: Vec<(&'static ConfigTopic, Applied)>,
+    // Structural fields that differ from the current config; they only take effect on restart
+    pub deferred: Vec<&'static str>,
+}
+
+// Sets the live fields among values on top of current, each as validate has it; structural ones
+// are left as they are and reported when they differ. The first field validate fails fails the lot.
+pub fn apply_fields(current: &ConfigData, values: impl IntoIterator<Item = (&'static ConfigTopic, f64)>,
+                    validate: impl Fn(&ConfigTopic, f64) -> Result<Applied, String>) -> Result<FieldsApplied, (&'static str, String)> {
+    let mut outcome = FieldsApplied { config_data: *current, applied: vec![], deferred: vec![] };
+    for (config_topic, value) in values {
+        let field = match &config_topic.field {
+            Some(field) => field,
+            None => continue
+        };
+        let applied = validate(config_topic, value).map_err(|reason| (field.name, reason))?;
+        if config_topic.apply == Apply::Structural {
+            if value != (field.get)(current) {
+                outcome.deferred.push(field.name);
+            }
+            continue;
+        }
+        if let Some(effective) = applied.effective() {
+            (field.set)(&mut outcome.config_data, effective);
+        }
+        outcome.applied.push((config_topic, applied));
+    }
+    Ok(outcome)
+}
+
+// Last outcome of each config topic, to report values that did not take effect as requested.
+#[derive(Default)]
+pub struct AdjustedTopics {
+    applied: HashMap<&'static str, Applied>,
+}
+
+impl AdjustedTopics {
+    pub fn new() -> AdjustedTopics {
+        AdjustedTopics::default()
+    }
+
+    pub fn record(&mut self, topic: &'static str, applied: Applied) {
+        self.applied.insert(topic, applied);
+    }
+
+    // Object with a member per topic whose last value was clamped or rejected, sorted by topic.
+    pub fn to_json(&self) -> String {
+        let mut topics: Vec<&&'static str> = self.applied.keys().filter(|topic| self.applied[**topic].is_adjusted()).collect();
+        topics.sort();
+
+        let mut s = String::from("");
+        let mut first = true;
+        for topic in topics {
+            if first { first = false; } else { s.push_str(", ") }
+            s.push_str(format!("\"{}\" : \"{}\"", topic, self.applied[*topic]).as_str());
+        }
+        format!("{{ {} }}", s)
+    }
+}
+
+fn json_number(value: Option<f64>) -> String {
+    match value {
+        Some(value) => format!("{}", value),
+        None => "null".to_string()
+    }
+}
+
+pub fn to_json() -> String {
+    let mut s = String::from("[");
+    let mut first = true;
+    for config_topic in CONFIG_TOPICS.iter() {
+        if first { first = false; } else { s.push_str(", ") }
+        s.push_str(format!(
+            "{{ \"topic\" : \"{}\", \"field\" : {}, \"type\" : \"{}\", \"min\" : {}, \"max\" : {}, \"default\" : {}, \"apply\" : \"{}\", \"description\" : {:?} }}",
+            config_topic.topic,
+            match &config_topic.field { Some(field) => format!("\"{}\"", field.name), None => "null".to_string() },
+            config_topic.value_type,
+            json_number(config_topic.min),
+            json_number(config_topic.max),
+            json_number(config_topic.default_value()),
+            config_topic.apply.name(),
+            config_topic.description).as_str());
+    }
+    s.push(']');
+    s
+}
+
+pub fn to_markdown() -> String {
+    fn cell(value: Option<f64>) -> String {
+        match value {
+            Some(value) => format!("{}", value),
+            None => "-".to_string()
+        }
+    }
+
+    let mut s = String::from("| Topic | Field | Type | Min | Max | Default | Apply | Description |\n");
+    s.push_str("|---|---|---|---|---|---|---|---|\n");
+    for config_topic in CONFIG_TOPICS.iter() {
+        s.push_str(format!("| `{}` | {} | {} | {} | {} | {} | {} | {} |\n",
+            config_topic.topic,
+            match &config_topic.field { Some(field) => format!("`{}`", field.name), None => "-".to_string() },
+            config_topic.value_type,
+            cell(config_topic.min),
+            cell(config_topic.max),
+            cell(config_topic.default_value()),
+            config_topic.apply.name(),
+            config_topic.description).as_str());
+    }
+    s
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn defaults_are_accepted_as_they_are_by_their_own_topics() {
+        for config_topic in CONFIG_TOPICS.iter() {
+            if let Some(default_value) = config_topic.default_value() {
+                let applied = config_topic.validate(default_value);
+                assert!(!applied.is_adjusted(), "default of {} is {}", config_topic.topic, applied);
+            }
+        }
+    }
+
+    #[test]
+    fn clamped_topic_is_reported_under_adjusted_until_a_value_takes_effect() {
+        let dead_band = find("balance/pid_inner/dead_band").unwrap();
+        let kp = find("balance/pid_inner/p").unwrap();
+        let mut adjusted = AdjustedTopics::new();
+
+        adjusted.record(dead_band.topic, dead_band.validate(MAX_DEAD_BAND * 2.0));
+        adjusted.record(kp.topic, kp.validate(0.5));
+        assert_eq!(adjusted.to_json(), format!("{{ \"balance/pid_inner/dead_band\" : \"{} clamped to {}\" }}", MAX_DEAD_BAND * 2.0, MAX_DEAD_BAND));
+
+        adjusted.record(dead_band.topic, dead_band.validate(1.0));
+        assert_eq!(adjusted.to_json(), "{  }");
+    }
+
+    #[test]
+    fn gyro_range_topic_keeps_the_range_in_dps() {
+        let topic = find("balance/gyro/range").unwrap();
+        let field = topic.field.as_ref().unwrap();
+        assert_eq!(topic.default_value(), Some(250.0));
+
+        let mut config_data = ConfigData::new();
+        (field.set)(&mut config_data, 500.0);
+        assert_eq!(config_data.gyro_range, Range::Range500);
+        assert_eq!((field.get)(&config_data), 500.0);
+    }
+}
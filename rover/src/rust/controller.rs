@@ -0,0 +1,289 @@
+//
+// Copyright (C) 2020 Abstract Horizon
+// All rights reserved. This program and the accompanying materials
+// are made available under the terms of the Apache License v2.0
+// which accompanies this distribution, and is available at
+// https://www.apache.org/licenses/LICENSE-2.0
+//
+//  Contributors:
+//    Daniel Sendula - initial API and implementation
+//
+
+// Sensor fusion and the balance PIDs: from one loop's sensor readings to what the motors get.
+// Balance::run_loop feeds it from the sensors, a replay (see replay.rs) from a recording of the
+// balance-input stream, which holds every SensorInput the loop had while recording was on.
+
+use crate::balance::{ConfigData, FusionAlgorithm, HoldDecision, State};
+use crate::fusion::{Complementary, Mahony, SensorFusion};
+use crate::orientation::{self, AxisMapping, Orientation};
+use crate::pid::{PID, SIMPLE_DIFFERENCE};
+use crate::telemetry_reader::Value;
+use crate::telemetry_socket_server::{SocketTelemetryServer, StreamHandle};
+use crate::telemetry_stream::{Storable, TelemetryStreamDefinition, Timebase};
+use crate::velocity::VelocityFeedback;
+
+// Bumped whenever fields of the balance-input stream change; well clear of balance-data's id.
+pub const BALANCE_INPUT_STREAM_VERSION: u32 = 101;
+
+pub const BALANCE_SET_POINT: f64 = -2.6;
+// Degrees the outer PID may move the angle set point by
+pub const MAX_OUTER_ANGLE: f64 = 10.0;
+
+
+pub fn create_input_logger(timebase: Timebase) -> TelemetryStreamDefinition {
+    TelemetryStreamDefinition::new("balance-input", BALANCE_INPUT_STREAM_VERSION,
+        vec![
+            // Loop time the PIDs ran at, seconds since the loop started
+            TelemetryStreamDefinition::double_field("now"),
+            TelemetryStreamDefinition::unsigned_byte_field("hold"),
+            TelemetryStreamDefinition::unsigned_byte_field("state"),
+            TelemetryStreamDefinition::unsigned_byte_field("overrun"),
+            // Raw deltas, then sensor frame deg/s with the bias taken out
+            TelemetryStreamDefinition::signed_word_field("gdx"),
+            TelemetryStreamDefinition::signed_word_field("gdy"),
+            TelemetryStreamDefinition::signed_word_field("gdz"),
+            TelemetryStreamDefinition::double_field("gsx"),
+            TelemetryStreamDefinition::double_field("gsy"),
+            TelemetryStreamDefinition::double_field("gsz"),
+            TelemetryStreamDefinition::double_field("gyro_dt"),
+            // Raw readings, then sensor frame g with the offsets taken out
+            TelemetryStreamDefinition::signed_word_field("adx"),
+            TelemetryStreamDefinition::signed_word_field("ady"),
+            TelemetryStreamDefinition::signed_word_field("adz"),
+            TelemetryStreamDefinition::double_field("asx"),
+            TelemetryStreamDefinition::double_field("asy"),
+            TelemetryStreamDefinition::double_field("asz"),
+            TelemetryStreamDefinition::double_field("left_vel"),
+            TelemetryStreamDefinition::double_field("right_vel"),
+            TelemetryStreamDefinition::double_field("drive"),
+        ]
+    ).with_timebase(timebase)
+}
+
+
+// What one loop of the controller works from.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SensorInput {
+    // Loop time, seconds
+    pub now: f64,
+    pub hold: HoldDecision,
+    // State the loop was in when the PIDs ran
+    pub state: State,
+    pub gyro_overrun: bool,
+    // Sensor frame, deg/s, and the gyro sample period they were measured over
+    pub gyro: (f64, f64, f64),
+    pub gyro_dt: f64,
+    // Sensor frame, g
+    pub accel: (f64, f64, f64),
+    // Left and right, deg/s
+    pub wheel_velocity: (f64, f64),
+    // Forward drive command, -1 to 1, as ramped
+    pub drive_forward: f64,
+}
+
+impl SensorInput {
+    // From the values of a balance-input record.
+    pub fn from_record(values: &[(String, Value)]) -> Result<SensorInput, String> {
+        let number = |name: &str| -> Result<f64, String> {
+            match values.iter().find(|(field, _)| field == name).map(|(_, value)| value) {
+                Some(Value::Unsigned(value)) => Ok(*value as f64),
+                Some(Value::Signed(value)) => Ok(*value as f64),
+                Some(Value::Float(value)) => Ok(*value),
+                Some(value) => Err(format!("{} is not a number: {:?}", name, value)),
+                None => Err(format!("no {}", name))
+            }
+        };
+        let hold = number("hold")?;
+        let state = number("state")?;
+        Ok(SensorInput {
+            now: number("now")?,
+            hold: HoldDecision::from_value(hold as u8).ok_or(format!("unknown hold {}", hold))?,
+            state: State::from_value(state as u8).ok_or(format!("unknown state {}", state))?,
+            gyro_overrun: number("overrun")? != 0.0,
+            gyro: (number("gsx")?, number("gsy")?, number("gsz")?),
+            gyro_dt: number("gyro_dt")?,
+            accel: (number("asx")?, number("asy")?, number("asz")?),
+            wheel_velocity: (number("left_vel")?, number("right_vel")?),
+            drive_forward: number("drive")?,
+        })
+    }
+}
+
+// Raw sensor readings go along for reference; the controller does not use them.
+pub fn log_input(telemetry_server: &SocketTelemetryServer, logger: &StreamHandle, input: &SensorInput,
+                 gyro_raw: (i16, i16, i16), accel_raw: (i16, i16, i16)) {
+    log_with_time!(
+        telemetry_server, logger,
+        input.now, input.hold.value(), input.state.value(), input.gyro_overrun as u8,
+        gyro_raw.0, gyro_raw.1, gyro_raw.2,
+        input.gyro.0, input.gyro.1, input.gyro.2, input.gyro_dt,
+        accel_raw.0, accel_raw.1, accel_raw.2,
+        input.accel.0, input.accel.1, input.accel.2,
+        input.wheel_velocity.0, input.wheel_velocity.1, input.drive_forward);
+}
+
+
+// What the controller made of one SensorInput.
+#[derive(Clone, Copy, Debug)]
+pub struct ControlStep {
+    // Body frame rates (deg/s) and gravity (g), and the tilts the accelerometer alone gives
+    pub rates: (f64, f64, f64),
+    pub gravity: (f64, f64, f64),
+    pub accel_tilt: (f64, f64),
+    pub orientation: Orientation,
+    pub last_pitch: f64,
+    // m/s, forward positive, and as filtered for velocity feedback
+    pub speed: f64,
+    pub velocity: f64,
+    pub outer_output: f64,
+    // Pitch the inner PID aims for, and its output
+    pub target: f64,
+    pub pid_output: f64,
+    // Motor output while balancing, before any turn
+    pub control: f64,
+}
+
+
+pub struct Controller {
+    orientation: Orientation,
+    fusion: Box<dyn SensorFusion + Send>,
+    fusion_settings: (FusionAlgorithm, f64, f64, f64),
+    // Orientation so far is in the old body frame once the mapping changes
+    fused_axis_mapping: AxisMapping,
+    pub pid: PID,
+    pub pid_outer: PID,
+    pub velocity_feedback: VelocityFeedback,
+    last_pid_output: f64,
+}
+
+impl Controller {
+    pub fn new(config_data: &ConfigData) -> Controller {
+        let orientation = Orientation::new();
+        Controller {
+            orientation,
+            fusion: Controller::fusion(config_data, orientation),
+            fusion_settings: Controller::fusion_settings(config_data),
+            fused_axis_mapping: config_data.axis_mapping,
+            pid: PID::new(
+                config_data.pid_kp, config_data.pid_ki, config_data.pid_kd,
+                config_data.pid_gain, config_data.dead_band,
+                config_data.i_gain_scale, config_data.d_gain_scale, SIMPLE_DIFFERENCE).with_i_limit(config_data.pid_i_limit),
+            pid_outer: Controller::outer_pid(config_data),
+            velocity_feedback: VelocityFeedback::new(config_data.velocity_gain, config_data.velocity_filter),
+            last_pid_output: 0.0,
+        }
+    }
+
+    // Speed in m/s against a set point of 0, so the rover holds its place.
+    pub fn outer_pid(config_data: &ConfigData) -> PID {
+        PID::new(
+            config_data.pid_outer_kp, config_data.pid_outer_ki, config_data.pid_outer_kd,
+            config_data.pid_outer_gain, config_data.pid_outer_dead_band, 1.0, 1.0, SIMPLE_DIFFERENCE).with_i_limit(MAX_OUTER_ANGLE)
+    }
+
+    // Starts from the given orientation, so switching filters does not make the angle jump.
+    fn fusion(config_data: &ConfigData, orientation: Orientation) -> Box<dyn SensorFusion + Send> {
+        match config_data.fusion {
+            FusionAlgorithm::Complementary => Box::new(Complementary::new(config_data.combine_gyro_accel_factor, orientation)),
+            FusionAlgorithm::Mahony => Box::new(Mahony::new(config_data.fusion_kp, config_data.fusion_ki, orientation)),
+        }
+    }
+
+    fn fusion_settings(config_data: &ConfigData) -> (FusionAlgorithm, f64, f64, f64) {
+        (config_data.fusion, config_data.combine_gyro_accel_factor, config_data.fusion_kp, config_data.fusion_ki)
+    }
+
+    pub fn fusion_error(&self) -> (f64, f64, f64) {
+        self.fusion.error_terms()
+    }
+
+    // Held samples keep the last orientation and PID output; the outer PID only runs while
+    // balancing and starts afresh each time balancing does.
+    pub fn step(&mut self, config_data: &ConfigData, input: &SensorInput) -> ControlStep {
+        let axis_mapping = config_data.axis_mapping;
+        let gravity = axis_mapping.to_body(input.accel.0, input.accel.1, input.accel.2);
+        let (gravity_x, gravity_y, gravity_z) = gravity;
+        let accel_tilt = (orientation::tilt_about_x(gravity_x, gravity_y, gravity_z), orientation::tilt_about_y(gravity_x, gravity_y, gravity_z));
+        let rates = axis_mapping.to_body(input.gyro.0, input.gyro.1, input.gyro.2);
+
+        let last_pitch = self.orientation.pitch;
+
+        if Controller::fusion_settings(config_data) != self.fusion_settings {
+            self.fusion = Controller::fusion(config_data, self.orientation);
+            self.fusion_settings = Controller::fusion_settings(config_data);
+        }
+        let fresh = input.hold == HoldDecision::Fresh;
+        if fresh {
+            let remapped = axis_mapping != self.fused_axis_mapping;
+            self.fused_axis_mapping = axis_mapping;
+            self.orientation = if (input.gyro_overrun && config_data.rezero_on_gyro_overrun) || remapped {
+                self.fusion.reset_tilt(gravity);
+                self.fusion.orientation()
+            } else {
+                self.fusion.update(rates, gravity, input.gyro_dt)
+            };
+        }
+
+        let speed = ((input.wheel_velocity.0 + input.wheel_velocity.1) / 2.0).to_radians() * config_data.wheel_radius;
+        self.velocity_feedback.gain = config_data.velocity_gain;
+        self.velocity_feedback.filter = config_data.velocity_filter;
+        let velocity = self.velocity_feedback.update(speed);
+
+        let pitch = self.orientation.pitch;
+
+        let outer_output = if input.state != State::Balancing {
+            self.pid_outer = Controller::outer_pid(config_data);
+            0.0
+        } else if fresh {
+            self.pid_outer.process(input.now, input.drive_forward * config_data.drive_max_speed, velocity).clamp(-MAX_OUTER_ANGLE, MAX_OUTER_ANGLE)
+        } else {
+            self.pid_outer.last_output.clamp(-MAX_OUTER_ANGLE, MAX_OUTER_ANGLE)
+        };
+
+        // Leaning into the requested speed up front, rather than waiting for the outer PID to ask for it
+        let target = BALANCE_SET_POINT + outer_output + input.drive_forward * config_data.drive_max_lean;
+        let pid_output = if fresh {
+            self.pid.process(input.now, target, pitch)
+        } else {
+            self.last_pid_output
+        };
+        self.last_pid_output = pid_output;
+
+        ControlStep {
+            rates,
+            gravity,
+            accel_tilt,
+            orientation: self.orientation,
+            last_pitch,
+            speed,
+            velocity,
+            outer_output,
+            target,
+            pid_output,
+            control: pid_output * config_data.output_scale - self.velocity_feedback.contribution(),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn outer_pid_has_its_own_dead_band() {
+        let mut config_data = ConfigData::new();
+        config_data.pid_outer_kp = 1.0;
+        config_data.dead_band = 1.0;
+
+        // Half a m/s is well inside the inner PID's dead band of 1 degree
+        let mut pid_outer = Controller::outer_pid(&config_data);
+        pid_outer.process(0.0, 0.0, 0.5);
+        assert_eq!(pid_outer.process(0.01, 0.0, 0.5), -0.5);
+
+        config_data.pid_outer_dead_band = 0.6;
+        let mut pid_outer = Controller::outer_pid(&config_data);
+        pid_outer.process(0.0, 0.0, 0.5);
+        assert_eq!(pid_outer.process(0.01, 0.0, 0.5), 0.0);
+    }
+}
@@ -0,0 +1,125 @@
+//
+// Copyright (C) 2020 Abstract Horizon
+// All rights reserved. This program and the accompanying materials
+// are made available under the terms of the Apache License v2.0
+// which accompanies this distribution, and is available at
+// https://www.apache.org/licenses/LICENSE-2.0
+//
+//  Contributors:
+//    Daniel Sendula - initial API and implementation
+//
+
+use std::collections::HashMap;
+
+use mdns_sd::{ServiceDaemon, ServiceInfo};
+
+
+pub const TELEMETRY_SERVICE_TYPE: &str = "_rover-telemetry._tcp.local.";
+pub const MQTT_CLIENT_SERVICE_TYPE: &str = "_rover-mqtt-client._tcp.local.";
+
+
+pub fn txt_properties(robot_name: &str, state_topic: &str) -> HashMap<String, String> {
+    let mut properties = HashMap::new();
+    properties.insert("name".to_string(), robot_name.to_string());
+    properties.insert("version".to_string(), env!("CARGO_PKG_VERSION").to_string());
+    properties.insert("state_topic".to_string(), state_topic.to_string());
+    properties
+}
+
+// MQTT clients talk to the broker, not to the rover, so it is where they should connect.
+pub fn mqtt_client_properties(robot_name: &str, state_topic: &str, broker_host: &str, broker_port: u16) -> HashMap<String, String> {
+    let mut properties = txt_properties(robot_name, state_topic);
+    properties.insert("broker_host".to_string(), broker_host.to_string());
+    properties.insert("broker_port".to_string(), broker_port.to_string());
+    properties
+}
+
+
+pub struct Advertiser {
+    daemon: ServiceDaemon,
+    robot_name: String,
+    state_topic: String,
+    telemetry_fullname: String,
+    mqtt_client_fullname: String,
+}
+
+impl Advertiser {
+    pub fn start(robot_name: &str, state_topic: &str, telemetry_port: u16, broker_host: &str, broker_port: u16) -> Result<Advertiser, mdns_sd::Error> {
+        let daemon = ServiceDaemon::new()?;
+
+        // Nothing listens for MQTT clients on the rover itself; the broker is in the TXT record
+        let mqtt_client_service = Advertiser::service_info(MQTT_CLIENT_SERVICE_TYPE, robot_name, 0,
+            mqtt_client_properties(robot_name, state_topic, broker_host, broker_port))?;
+        let mqtt_client_fullname = mqtt_client_service.get_fullname().to_string();
+        daemon.register(mqtt_client_service)?;
+
+        let mut advertiser = Advertiser {
+            daemon,
+            robot_name: robot_name.to_string(),
+            state_topic: state_topic.to_string(),
+            telemetry_fullname: String::new(),
+            mqtt_client_fullname,
+        };
+
+        advertiser.update_telemetry_port(telemetry_port)?;
+
+        Ok(advertiser)
+    }
+
+    fn service_info(service_type: &str, robot_name: &str, port: u16, properties: HashMap<String, String>) -> Result<ServiceInfo, mdns_sd::Error> {
+        let host_name = format!("{}.local.", robot_name);
+        Ok(ServiceInfo::new(service_type, robot_name, &host_name, "", port, properties)?.enable_addr_auto())
+    }
+
+    // Withdraws the telemetry service advertised so far, if any, and advertises it on the given port.
+    pub fn update_telemetry_port(&mut self, telemetry_port: u16) -> Result<(), mdns_sd::Error> {
+        if !self.telemetry_fullname.is_empty() {
+            let _ = self.daemon.unregister(&self.telemetry_fullname)?.recv();
+        }
+
+        let telemetry_service = Advertiser::service_info(TELEMETRY_SERVICE_TYPE, &self.robot_name, telemetry_port,
+            txt_properties(&self.robot_name, &self.state_topic))?;
+        self.telemetry_fullname = telemetry_service.get_fullname().to_string();
+        self.daemon.register(telemetry_service)?;
+
+        info!("Advertising {} on port {}", self.telemetry_fullname, telemetry_port);
+        Ok(())
+    }
+
+    pub fn stop(self) {
+        for fullname in [&self.telemetry_fullname, &self.mqtt_client_fullname].iter() {
+            match self.daemon.unregister(fullname) {
+                Ok(receiver) => { let _ = receiver.recv(); },
+                Err(e) => warn!("Failed to withdraw {}: {:?}", fullname, e)
+            }
+        }
+        let _ = self.daemon.shutdown();
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn txt_record_carries_name_version_and_state_topic() {
+        let properties = txt_properties("balance-r", "balancing/info");
+
+        let mut keys: Vec<&String> = properties.keys().collect();
+        keys.sort();
+        assert_eq!(keys, vec!["name", "state_topic", "version"]);
+        assert_eq!(properties["name"], "balance-r");
+        assert_eq!(properties["state_topic"], "balancing/info");
+        assert_eq!(properties["version"], env!("CARGO_PKG_VERSION"));
+    }
+
+    #[test]
+    fn mqtt_client_record_points_at_the_broker() {
+        let properties = mqtt_client_properties("balance-r", "balancing/info", "172.24.1.174", 1883);
+
+        assert_eq!(properties["name"], "balance-r");
+        assert_eq!(properties["broker_host"], "172.24.1.174");
+        assert_eq!(properties["broker_port"], "1883");
+    }
+}
@@ -0,0 +1,144 @@
+//
+// Copyright (C) 2020 Abstract Horizon
+// All rights reserved. This program and the accompanying materials
+// are made available under the terms of the Apache License v2.0
+// which accompanies this distribution, and is available at
+// https://www.apache.org/licenses/LICENSE-2.0
+//
+//  Contributors:
+//    Daniel Sendula - initial API and implementation
+//
+
+// Wheel odometry from the two AS5600 magnetic encoders. Angles are in degrees, 0 to 360, with
+// the direction sign applied so that both wheels count up when driving forwards.
+
+use std::time::Duration;
+
+use crate::as5600::{self, AS5600};
+use crate::i2c_bus::{I2cBus, SharedBus};
+use crate::turn;
+
+
+// Bits of EncoderReading::magnet_fault
+pub const LEFT_MAGNET_FAULT: u8 = 1;
+pub const RIGHT_MAGNET_FAULT: u8 = 2;
+
+
+// Angle, travel since the previous update and speed of one wheel.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct WheelMotion {
+    pub deg: f64,
+    // Degrees, shortest way round, so crossing 0/360 is a small step and not a whole turn
+    pub delta: f64,
+    // Degrees per second
+    pub velocity: f64,
+    last_deg: Option<f64>,
+    // Seconds since the last successful reading
+    elapsed: f64,
+}
+
+impl WheelMotion {
+    pub fn new() -> WheelMotion {
+        WheelMotion::default()
+    }
+
+    // Reading is None when the encoder could not be read; the wheel then keeps its angle and
+    // speed, and the next reading's travel is spread over all the time since the last one.
+    pub fn update(&mut self, reading: Option<f64>, delta_time: f64) {
+        self.elapsed += delta_time;
+        self.delta = 0.0;
+        if let Some(deg) = reading {
+            if let Some(last_deg) = self.last_deg {
+                self.delta = turn::wrap_degrees(deg - last_deg);
+                if self.elapsed > 0.0 {
+                    self.velocity = self.delta / self.elapsed;
+                }
+            }
+            self.deg = deg;
+            self.last_deg = Some(deg);
+            self.elapsed = 0.0;
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct EncoderReading {
+    pub left: WheelMotion,
+    pub right: WheelMotion,
+    // LEFT_MAGNET_FAULT and/or RIGHT_MAGNET_FAULT while a magnet is missing or too weak
+    pub magnet_fault: u8,
+}
+
+pub struct Encoders {
+    left: AS5600,
+    right: AS5600,
+    reading: EncoderReading,
+}
+
+impl Encoders {
+    // Each encoder on its own bus, or on the same one behind a mux; dir is -1 for an encoder
+    // that counts down when its wheel drives forwards.
+    pub fn new(left_bus: &SharedBus, left_dir: i8, right_bus: &SharedBus, right_dir: i8) -> Encoders {
+        Encoders {
+            left: AS5600::new(left_bus, left_dir),
+            right: AS5600::new(right_bus, right_dir),
+            reading: EncoderReading::default(),
+        }
+    }
+
+    pub fn set_deadlines(&mut self, soft_deadline: Duration, hard_deadline: Duration) {
+        self.left.bus.set_deadlines(soft_deadline, hard_deadline);
+        self.right.bus.set_deadlines(soft_deadline, hard_deadline);
+    }
+
+    pub fn buses(&mut self) -> [&mut I2cBus; 2] {
+        [&mut self.left.bus, &mut self.right.bus]
+    }
+
+    // Reads both encoders; delta_time is the time since the previous read.
+    pub fn read(&mut self, delta_time: f64) -> EncoderReading {
+        let left = match self.left.read() {
+            Ok(deg) => Some(deg),
+            Err(e) => { debug!("Failed to read left wheel: {:?}", e); None }
+        };
+        let right = match self.right.read() {
+            Ok(deg) => Some(deg),
+            Err(e) => { debug!("Failed to read right wheel: {:?}", e); None }
+        };
+        self.reading.left.update(left, delta_time);
+        self.reading.right.update(right, delta_time);
+
+        self.reading.magnet_fault = 0;
+        if as5600::magnet_fault(self.left.status) {
+            self.reading.magnet_fault |= LEFT_MAGNET_FAULT;
+        }
+        if as5600::magnet_fault(self.right.status) {
+            self.reading.magnet_fault |= RIGHT_MAGNET_FAULT;
+        }
+        self.reading
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wheel_crosses_zero_the_short_way_and_holds_its_speed_over_a_missed_reading() {
+        let mut wheel = WheelMotion::new();
+        for (reading, deg, delta, velocity) in [
+            (Some(350.0), 350.0, 0.0, 0.0),
+            (Some(355.0), 355.0, 5.0, 500.0),
+            (Some(5.0), 5.0, 10.0, 1000.0),
+            (None, 5.0, 0.0, 1000.0),
+            (Some(15.0), 15.0, 10.0, 500.0),
+            (Some(350.0), 350.0, -25.0, -2500.0),
+        ].iter() {
+            wheel.update(*reading, 0.01);
+            assert!((wheel.deg - deg).abs() < 1e-9, "after {:?}: {} deg", reading, wheel.deg);
+            assert!((wheel.delta - delta).abs() < 1e-9, "after {:?}: {} delta", reading, wheel.delta);
+            assert!((wheel.velocity - velocity).abs() < 1e-6, "after {:?}: {} deg/s", reading, wheel.velocity);
+        }
+    }
+}
@@ -0,0 +1,297 @@
+//
+// Copyright (C) 2020 Abstract Horizon
+// All rights reserved. This program and the accompanying materials
+// are made available under the terms of the Apache License v2.0
+// which accompanies this distribution, and is available at
+// https://www.apache.org/licenses/LICENSE-2.0
+//
+//  Contributors:
+//    Daniel Sendula - initial API and implementation
+//
+
+// Retrieval of recorded telemetry files over MQTT, one file at a time:
+//
+//   telemetry/files/list  - replies on telemetry/files/list/reply with [{ name, size, modified }]
+//   telemetry/files/get   - payload "name[,offset[,chunk_size]]"; the file is sent from offset in
+//                           raw chunks on telemetry/files/data/<name>/<offset>, followed by a
+//                           "done" status. A lost chunk is fetched again by asking from its offset.
+//
+// Progress and errors go to telemetry/files/status. Only plain files directly inside the
+// recording directory with a valid name are served.
+
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::PathBuf;
+use std::time::UNIX_EPOCH;
+
+
+pub const DEFAULT_CHUNK_SIZE: usize = 4096;
+pub const MAX_CHUNK_SIZE: usize = 16384;
+const MIN_CHUNK_SIZE: usize = 256;
+const MAX_NAME_LEN: usize = 128;
+
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum TransferError {
+    InvalidName,
+    InvalidRequest,
+    NotFound,
+    // Another file is being sent
+    Busy,
+    Io(String),
+}
+
+impl TransferError {
+    pub fn name(&self) -> &'static str {
+        match self {
+            TransferError::InvalidName => "invalid_name",
+            TransferError::InvalidRequest => "invalid_request",
+            TransferError::NotFound => "not_found",
+            TransferError::Busy => "busy",
+            TransferError::Io(_) => "io_error",
+        }
+    }
+}
+
+impl From<io::Error> for TransferError {
+    fn from(e: io::Error) -> TransferError {
+        if e.kind() == io::ErrorKind::NotFound { TransferError::NotFound } else { TransferError::Io(e.to_string()) }
+    }
+}
+
+// Letters, digits, '-', '_' and '.', not starting with '.'; so never a path, "..", or a hidden file.
+pub fn validate_name(name: &str) -> Result<(), TransferError> {
+    if name.is_empty() || name.len() > MAX_NAME_LEN || name.starts_with('.')
+            || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.') {
+        Err(TransferError::InvalidName)
+    } else {
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct GetRequest {
+    pub name: String,
+    pub offset: u64,
+    pub chunk_size: usize,
+}
+
+impl GetRequest {
+    // "name[,offset[,chunk_size]]"; the chunk size is clamped to MIN_CHUNK_SIZE..MAX_CHUNK_SIZE.
+    pub fn parse(payload: &str) -> Result<GetRequest, TransferError> {
+        let mut parts = payload.split(',').map(|part| part.trim());
+        let name = parts.next().unwrap_or("").to_string();
+        validate_name(&name)?;
+        let offset = match parts.next() {
+            Some(offset) => offset.parse::<u64>().map_err(|_| TransferError::InvalidRequest)?,
+            None => 0
+        };
+        let chunk_size = match parts.next() {
+            Some(chunk_size) => chunk_size.parse::<usize>().map_err(|_| TransferError::InvalidRequest)?,
+            None => DEFAULT_CHUNK_SIZE
+        };
+        if parts.next().is_some() {
+            return Err(TransferError::InvalidRequest);
+        }
+        Ok(GetRequest { name, offset, chunk_size: chunk_size.clamp(MIN_CHUNK_SIZE, MAX_CHUNK_SIZE) })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum TransferEvent {
+    Chunk { name: String, offset: u64, data: Vec<u8> },
+    Done { name: String, size: u64 },
+    Failed { name: String, error: TransferError },
+}
+
+struct Transfer {
+    name: String,
+    file: File,
+    offset: u64,
+    size: u64,
+    chunk_size: usize,
+}
+
+pub struct FileTransfer {
+    pub dir: PathBuf,
+    // Bytes per second all chunks together may use
+    pub rate: f64,
+    active: Option<Transfer>,
+    allowance: f64,
+    last_refill: Option<f64>,
+}
+
+impl FileTransfer {
+    pub fn new(dir: PathBuf, rate: f64) -> FileTransfer {
+        FileTransfer { dir, rate, active: None, allowance: 0.0, last_refill: None }
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.active.is_some()
+    }
+
+    // Plain files with valid names, sorted by name.
+    pub fn list(&self) -> Result<String, TransferError> {
+        let mut files = vec![];
+        for entry in fs::read_dir(&self.dir)? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().to_string();
+            let metadata = fs::symlink_metadata(entry.path())?;
+            if validate_name(&name).is_ok() && metadata.is_file() {
+                let modified = metadata.modified().ok()
+                    .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+                    .map_or(0, |modified| modified.as_secs());
+                files.push((name, metadata.len(), modified));
+            }
+        }
+        files.sort();
+        let files: Vec<String> = files.iter()
+            .map(|(name, size, modified)| format!("{{ \"name\" : \"{}\", \"size\" : {}, \"modified\" : {} }}", name, size, modified))
+            .collect();
+        Ok(format!("[{}]", files.join(", ")))
+    }
+
+    // Asking again for the file being sent restarts it from the new offset; any other file is
+    // refused until the current one is done. Returns the file size.
+    pub fn start(&mut self, request: &GetRequest) -> Result<u64, TransferError> {
+        validate_name(&request.name)?;
+        if let Some(active) = &self.active {
+            if active.name != request.name {
+                return Err(TransferError::Busy);
+            }
+        }
+
+        let path = self.dir.join(&request.name);
+        let metadata = fs::symlink_metadata(&path)?;
+        if !metadata.is_file() {
+            return Err(TransferError::NotFound);
+        }
+        let size = metadata.len();
+        if request.offset > size {
+            return Err(TransferError::InvalidRequest);
+        }
+        let mut file = File::open(&path)?;
+        file.seek(SeekFrom::Start(request.offset))?;
+
+        self.active = Some(Transfer { name: request.name.clone(), file, offset: request.offset, size, chunk_size: request.chunk_size });
+        Ok(size)
+    }
+
+    pub fn cancel(&mut self) {
+        self.active = None;
+    }
+
+    // Next chunk if the rate allows one now, then Done once the file is sent; None otherwise.
+    pub fn poll(&mut self, now: f64) -> Option<TransferEvent> {
+        let transfer = self.active.as_mut()?;
+
+        // Up to a second's worth (and at least one chunk) may go out in a burst
+        let burst = self.rate.max(transfer.chunk_size as f64);
+        self.allowance = match self.last_refill {
+            Some(last_refill) => (self.allowance + (now - last_refill).max(0.0) * self.rate).min(burst),
+            None => burst
+        };
+        self.last_refill = Some(now);
+
+        if transfer.offset >= transfer.size {
+            let done = TransferEvent::Done { name: transfer.name.clone(), size: transfer.size };
+            self.active = None;
+            return Some(done);
+        }
+
+        let len = (transfer.size - transfer.offset).min(transfer.chunk_size as u64) as usize;
+        if self.allowance < len as f64 {
+            return None;
+        }
+
+        let mut data = vec![0u8; len];
+        match transfer.file.read_exact(&mut data) {
+            Ok(()) => {
+                self.allowance -= len as f64;
+                let chunk = TransferEvent::Chunk { name: transfer.name.clone(), offset: transfer.offset, data };
+                transfer.offset += len as u64;
+                Some(chunk)
+            },
+            Err(e) => {
+                let failed = TransferEvent::Failed { name: transfer.name.clone(), error: TransferError::from(e) };
+                self.active = None;
+                Some(failed)
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TempDir;
+
+    fn recordings() -> (TempDir, Vec<u8>) {
+        let dir = TempDir::new("recordings");
+        let content: Vec<u8> = (0..10000u32).map(|i| (i % 251) as u8).collect();
+        fs::create_dir_all(dir.path().join("sub")).unwrap();
+        fs::write(dir.path().join("run-1.bin"), &content).unwrap();
+        fs::write(dir.path().join("run-2.bin"), b"second").unwrap();
+        fs::write(dir.path().join(".hidden"), b"hidden").unwrap();
+        std::os::unix::fs::symlink("/etc/passwd", dir.path().join("passwd")).unwrap();
+        (dir, content)
+    }
+
+    fn drain(transfer: &mut FileTransfer, now: f64) -> Vec<(u64, Vec<u8>)> {
+        let mut chunks = vec![];
+        while let Some(TransferEvent::Chunk { offset, data, .. }) = transfer.poll(now) {
+            chunks.push((offset, data));
+        }
+        chunks
+    }
+
+    #[test]
+    fn nothing_outside_the_directory_is_reachable() {
+        for name in ["", ".", "..", "../etc/passwd", "/etc/passwd", "sub/../run-1.bin", "..\\run-1.bin", "run-1.bin\0",
+                     "%2e%2e", ".hidden", "name with space", &"a".repeat(200)].iter() {
+            assert_eq!(GetRequest::parse(name), Err(TransferError::InvalidName), "{:?}", name);
+        }
+        assert_eq!(GetRequest::parse("run-1.bin,-5"), Err(TransferError::InvalidRequest));
+        assert_eq!(GetRequest::parse("run-1.bin,0,1,2"), Err(TransferError::InvalidRequest));
+
+        let (dir, _) = recordings();
+        let mut transfer = FileTransfer::new(dir.path().to_path_buf(), 1.0e9);
+        let list = transfer.list().unwrap();
+        assert!(list.contains("\"run-1.bin\", \"size\" : 10000") && list.contains("run-2.bin"), "{}", list);
+        assert!(!list.contains("hidden") && !list.contains("passwd") && !list.contains("sub"), "{}", list);
+        assert_eq!(transfer.start(&GetRequest::parse("passwd").unwrap()), Err(TransferError::NotFound));
+    }
+
+    #[test]
+    fn recording_goes_out_in_order_and_resumes_from_any_offset() {
+        let (dir, content) = recordings();
+        let mut transfer = FileTransfer::new(dir.path().to_path_buf(), 1.0e9);
+
+        transfer.start(&GetRequest::parse("run-1.bin,0,4096").unwrap()).unwrap();
+        assert_eq!(transfer.start(&GetRequest::parse("run-2.bin").unwrap()), Err(TransferError::Busy));
+        let chunks = drain(&mut transfer, 1.0);
+        assert_eq!(chunks.iter().map(|(offset, _)| *offset).collect::<Vec<u64>>(), vec![0, 4096, 8192]);
+        assert_eq!(chunks.into_iter().flat_map(|(_, data)| data).collect::<Vec<u8>>(), content);
+        assert!(!transfer.is_active());
+
+        // Resuming after a lost chunk
+        transfer.start(&GetRequest::parse("run-1.bin, 5000, 4096").unwrap()).unwrap();
+        let chunks = drain(&mut transfer, 2.0);
+        assert_eq!(chunks.len(), 2);
+        assert_eq!(chunks[0].0, 5000);
+        assert_eq!(chunks[0].1[..], content[5000..9096]);
+        assert_eq!(chunks[1].1[..], content[9096..]);
+        assert_eq!(transfer.start(&GetRequest::parse("run-1.bin,10001").unwrap()), Err(TransferError::InvalidRequest));
+    }
+
+    // 1000 bytes a second: a second's worth straight away, then a 300 byte chunk every 0.3s
+    #[test]
+    fn chunks_are_throttled_to_the_rate() {
+        let (dir, _) = recordings();
+        let mut transfer = FileTransfer::new(dir.path().to_path_buf(), 1000.0);
+        transfer.start(&GetRequest { name: "run-1.bin".to_string(), offset: 0, chunk_size: 300 }).unwrap();
+        let sent: Vec<usize> = [0.0, 0.1, 0.2, 0.5].iter().map(|now| drain(&mut transfer, *now).len()).collect();
+        assert_eq!(sent, vec![3, 0, 1, 1]);
+    }
+}
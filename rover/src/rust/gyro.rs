@@ -9,13 +9,20 @@
 //    Daniel Sendula - initial API and implementation
 //
 
+use std::io;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
 use byteorder::{ByteOrder, LittleEndian};
 
 use phf::phf_map;
 
-use rppal::i2c::I2c;
+use rppal::i2c::Error;
+
+use crate::i2c_bus::{BusDevice, I2cBus, SharedBus};
 
 
+pub const WHO_AM_I: u8 = 0x0F;
+pub const DEVICE_ID: u8 = 0xD3;
 const _CTRL_REG1: u8 = 0x20;
 const _CTRL_REG2: u8 = 0x21;
 const _CTRL_REG3: u8 = 0x22;
@@ -43,6 +50,12 @@ const _INT1_TSH_ZL: u8 = 0x37;
 const _INT1_DURATION: u8 = 0x38;
 
 
+// FIFO_SRC_REG: the FIFO filled up and samples were overwritten before they were read
+pub const FIFO_OVERRUN: u8 = 0x40;
+// FIFO_CTRL_REG modes; going through bypass empties the FIFO and clears the overrun
+const FIFO_MODE_BYPASS: u8 = 0x00;
+const FIFO_MODE_STREAM: u8 = 0x60;
+
 const _FREQ_BANDWIDTH_100_12_5: u8 = 0x00;
 const _FREQ_BANDWIDTH_100_25: u8 = 0x10;
 const _FREQ_BANDWIDTH_200_12_5: u8 = 0x40;
@@ -58,30 +71,43 @@ const _FREQ_BANDWIDTH_800_35: u8 = 0xD0;
 const _FREQ_BANDWIDTH_800_50: u8 = 0xE0;
 const _FREQ_BANDWIDTH_800_111: u8 = 0xF0;
 
-// #[derive(Clone)]
+#[derive(Clone)]
 pub struct DataPoint {
     pub dx: i16,
     pub dy: i16,
     pub dz: i16,
     pub status: u16,
-    pub fifo_status: u8
+    pub fifo_status: u8,
+    // Estimated measurement time, seconds since the epoch
+    pub time: f64,
 }
 
 impl DataPoint {
-//    fn empty() -> DataPoint {
-//        DataPoint { dx: 0, dy: 0, dz: 0, status: 0, fifo_status: 0 }
-//    }
+    pub fn empty() -> DataPoint {
+        DataPoint { dx: 0, dy: 0, dz: 0, status: 0, fifo_status: 0, time: 0.0 }
+    }
 
     fn new(dx: i16, dy: i16, dz: i16, status: u16, fifo_status: u8) -> DataPoint {
-        DataPoint { dx, dy, dz, status, fifo_status }
+        DataPoint { dx, dy, dz, status, fifo_status, time: 0.0 }
     }
 }
 
+// FIFO samples are read in one batch; the newest one was measured one sample period before the batch
+// was read and each older one a period before that. Samples lost to a FIFO overrun are older than the
+// whole batch, so anchoring on the read time keeps the ones we do get correct.
+pub fn sample_time(read_time: f64, batch_len: usize, index: usize, sample_period: f64) -> f64 {
+    read_time - (batch_len - index) as f64 * sample_period
+}
+
 const FREQ_100: phf::Map<&'static str, u8> = phf_map! {"_" => 0x00, "12.5" => 0, "25" => 0x10};
 const FREQ_200: phf::Map<&'static str, u8> = phf_map! {"_" => 0x40, "12.5" => 0, "25" => 0x10, "50" => 0x20, "70" => 0x30};
 const FREQ_400: phf::Map<&'static str, u8> = phf_map! {"_" => 0x80, "20" => 0, "25" => 0x10, "50" => 0x20, "110" => 0x30};
 const FREQ_800: phf::Map<&'static str, u8> = phf_map! {"_" => 0xC0, "30" => 0, "35" => 0x10, "50" => 0x20, "110" => 0x30};
 
+// A stuck bus or sensor must not keep the balance loop polling forever
+const POLL_TIMEOUT: Duration = Duration::from_millis(50);
+const FIFO_DEPTH: usize = 32;
+
 const ALLOWED_FREQ_BANDWIDTH_COMBINATIONS: phf::Map<u16, phf::Map<&'static str, u8>> = phf_map! {
     100u16 => FREQ_100,
     200u16 => FREQ_200,
@@ -90,11 +116,62 @@ const ALLOWED_FREQ_BANDWIDTH_COMBINATIONS: phf::Map<u16, phf::Map<&'static str,
 };
 
 
+// Full scale range, CTRL_REG4 FS bits. Bias and bias points are raw counts, so a range change
+// rescales them.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Range {
+    Range250,
+    Range500,
+    Range2000,
+}
+
+impl Range {
+    // Smallest range that covers dps.
+    pub fn from_dps(dps: f64) -> Range {
+        if dps <= 250.0 {
+            Range::Range250
+        } else if dps <= 500.0 {
+            Range::Range500
+        } else {
+            Range::Range2000
+        }
+    }
+
+    pub fn dps(&self) -> f64 {
+        match self {
+            Range::Range250 => 250.0,
+            Range::Range500 => 500.0,
+            Range::Range2000 => 2000.0,
+        }
+    }
+
+    // Degrees per second per LSB, from the datasheet
+    pub fn sensitivity(&self) -> f64 {
+        match self {
+            Range::Range250 => 0.00875,
+            Range::Range500 => 0.0175,
+            Range::Range2000 => 0.07,
+        }
+    }
+
+    // Block data update (no mixed samples), LSB @ lower address, the FS bits, self test disabled
+    fn ctrl_reg4(&self) -> u8 {
+        match self {
+            Range::Range250 => 0x80,
+            Range::Range500 => 0x90,
+            Range::Range2000 => 0xA0,
+        }
+    }
+}
+
+
 pub struct L3G4200D {
-    bus: I2c,
+    pub bus: I2cBus,
 //    address: u8,
     freq_u16: u16,
     pub freq: f64,
+    // Time between FIFO samples; starts as 1 / freq
+    pub sample_period: f64,
     pub bandwidth: &'static str,
     pub combine_filter: f64,
     pub px: f64,
@@ -105,88 +182,228 @@ pub struct L3G4200D {
     pub cz: f64,
 //    buffer_len_in_time: f64,
 //    data_buffer: Vec<DataPoint>,
+    range: Range,
     sensitivity: f64,
+    // FIFO overruns seen since start
+    pub overruns: u32,
+    // OUT_TEMP as of the last FIFO read
+    pub temperature: i8,
+    cold_bias: Option<BiasPoint>,
+    warm_bias: Option<BiasPoint>,
+    // Sample times are seconds since this, or wall clock time when None
+    time_origin: Option<Instant>,
+}
+
+// The two bias points temperature compensation draws its line through.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BiasPointKind {
+    Cold,
+    Warm,
+}
+
+impl BiasPointKind {
+    pub fn parse(s: &str) -> Result<BiasPointKind, String> {
+        match s.trim() {
+            "cold" => Ok(BiasPointKind::Cold),
+            "warm" => Ok(BiasPointKind::Warm),
+            _ => Err(format!("Bias point must be cold or warm, not {}", s))
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            BiasPointKind::Cold => "cold",
+            BiasPointKind::Warm => "warm",
+        }
+    }
+}
+
+// Bias (raw units of the 250 dps range, whatever range the gyro is in) calibrated at an OUT_TEMP reading. OUT_TEMP drops by about one per degree C
+// as the die warms and has no fixed zero, so points are only good for the gyro they came from.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BiasPoint {
+    pub temperature: f64,
+    pub cx: f64,
+    pub cy: f64,
+    pub cz: f64,
+}
+
+impl BiasPoint {
+    // "temperature,cx,cy,cz", as to_storage writes it.
+    pub fn parse(s: &str) -> Result<BiasPoint, String> {
+        let values = s.split(',').map(|value| value.trim().parse::<f64>()).collect::<Result<Vec<f64>, _>>()
+            .map_err(|e| format!("cannot parse gyro bias point '{}': {}", s, e))?;
+        match values.as_slice() {
+            [temperature, cx, cy, cz] if values.iter().all(|value| value.is_finite()) =>
+                Ok(BiasPoint { temperature: *temperature, cx: *cx, cy: *cy, cz: *cz }),
+            _ => Err(format!("expected finite temperature,cx,cy,cz gyro bias point, got '{}'", s))
+        }
+    }
+
+    pub fn to_storage(&self) -> String {
+        format!("{},{},{},{}", self.temperature, self.cx, self.cy, self.cz)
+    }
+}
+
+// Bias at temperature on the line through cold and warm, extended past them as well; None when
+// both were taken at the same temperature.
+pub fn bias_at(cold: &BiasPoint, warm: &BiasPoint, temperature: f64) -> Option<(f64, f64, f64)> {
+    let span = warm.temperature - cold.temperature;
+    if span == 0.0 {
+        return None;
+    }
+    let fraction = (temperature - cold.temperature) / span;
+    Some((
+        cold.cx + (warm.cx - cold.cx) * fraction,
+        cold.cy + (warm.cy - cold.cy) * fraction,
+        cold.cz + (warm.cz - cold.cz) * fraction,
+    ))
+}
+
+// Samples read_deltas got, and whether samples before them were lost to a FIFO overrun.
+pub struct GyroDeltas {
+    pub data_points: Vec<DataPoint>,
+    pub overrun: bool,
 }
 
 impl L3G4200D {
-    pub fn new(address: u8, freq: u16, bandwidth: &'static str, combine_filter: f64) -> L3G4200D {
+    pub fn new(bus: &SharedBus, address: u8, freq: u16, bandwidth: &'static str, range: Range, combine_filter: f64) -> L3G4200D {
+        L3G4200D::open(bus, address, freq, bandwidth, range, combine_filter).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    // As new, but a bad setting or a gyro that does not answer is an error rather than a panic.
+    pub fn open(bus: &SharedBus, address: u8, freq: u16, bandwidth: &'static str, range: Range, combine_filter: f64) -> Result<L3G4200D, String> {
 
         match ALLOWED_FREQ_BANDWIDTH_COMBINATIONS.get(&freq) {
             Some(map) =>  if !map.contains_key(&bandwidth) {
-                // panic!("Bandwidth {} for frequency {} can be only one of: {}", bandwidth, freq, map);
-                panic!("L3G4200D: Bandwidth {} for frequency {} is not valid.", bandwidth, freq);
+                return Err(format!("L3G4200D: Bandwidth {} for frequency {} is not valid.", bandwidth, freq));
             },
-            None => panic!("L3G4200D: Fequency can be only one of: 100, 200, 400 or 800; but got {}", freq)
+            None => return Err(format!("L3G4200D: Fequency can be only one of: 100, 200, 400 or 800; but got {}", freq))
         }
-        let mut bus = I2c::with_bus(1).expect("L3G4200D: Cannot initialise i2c bus 1");
-        bus.set_slave_address(address as u16).unwrap_or_else(|_| panic!("L3G4200D: Cannot set slave address {}", address));
-
+        let bus = I2cBus::new("L3G4200D", bus, address as u16);
 
-        let result = L3G4200D {
+        let mut result = L3G4200D {
             bus,
             freq_u16: freq,
             freq: freq as f64,
+            sample_period: 1.0 / freq as f64,
             bandwidth,
             combine_filter,
             px: 0.0, py: 0.0, pz: 0.0,
             cx: 0.0, cy: 0.0, cz: 0.0,
-            sensitivity: 0.00875,
+            range,
+            sensitivity: range.sensitivity(),
+            overruns: 0,
+            temperature: 0,
+            cold_bias: None,
+            warm_bias: None,
+            time_origin: None,
         };
 
-        result.init_gyro();
+        result.init_gyro().map_err(|e| format!("L3G4200D: Cannot initialise control registers on i2c: {:?}", e))?;
 
-        result
+        Ok(result)
     }
     
-    fn init_gyro(&self) {
+    fn init_gyro(&mut self) -> Result<(), Error> {
         let selected_freq = ALLOWED_FREQ_BANDWIDTH_COMBINATIONS.get(&self.freq_u16).unwrap();
         let ctrl1 = 0xf + selected_freq.get("_").unwrap() + selected_freq.get(self.bandwidth).unwrap();
+        let ctrl4 = self.range.ctrl_reg4();
 
-        self.bus.smbus_write_byte(_CTRL_REG1, ctrl1).expect("L3G4200D: Cannot set REG1 on i2c");  // Output data rate 800Hz, freq cut-off 50 (Hz?), normal mode (not power down), all axes (x, y, z) enabled
-        self.bus.smbus_write_byte(_CTRL_REG2, 0x0).expect("L3G4200D: Cannot set REG2 on i2c");
-        self.bus.smbus_write_byte(_CTRL_REG3, 0x0).expect("L3G4200D: Cannot set REG3 on i2c");
-        self.bus.smbus_write_byte(_CTRL_REG4, 0x80).expect("L3G4200D: Cannot set REG4 on i2c");  // Not block (continuous update), LSB @ lower address, FSR 500dps, self test disabled, i2c interface
-        self.bus.smbus_write_byte(_CTRL_REG5, 0x40).expect("L3G4200D: Cannot set REG5 on i2c");  // FIFO enabled
-        self.bus.smbus_write_byte(_FIFO_CTRL_REG, 0x60).expect("L3G4200D: Cannot set _FIFO_CTRL_REG on i2c");  // FIFO Stream mode
+        self.bus.transaction(|dev| {
+            dev.smbus_write_byte(_CTRL_REG1, ctrl1)?;  // Output data rate 800Hz, freq cut-off 50 (Hz?), normal mode (not power down), all axes (x, y, z) enabled
+            dev.smbus_write_byte(_CTRL_REG2, 0x0)?;
+            dev.smbus_write_byte(_CTRL_REG3, 0x0)?;
+            dev.smbus_write_byte(_CTRL_REG4, ctrl4)?;
+            dev.smbus_write_byte(_CTRL_REG5, 0x40)?;  // FIFO enabled
+            dev.smbus_write_byte(_FIFO_CTRL_REG, FIFO_MODE_STREAM)
+        })?;
 
         println!("Initialised L3G4200D i2c device.");
+        Ok(())
     }
 
-    fn read_data(&self, status: u16, fifo_status: u8) -> DataPoint {
+    fn read_data(dev: &mut BusDevice, status: u16, fifo_status: u8) -> Result<DataPoint, Error> {
         let command: [u8; 1] = [_OUT_X_L + 0x80];
         let mut buf = [0u8; 6];
-        let _ = self.bus.write_read(&command, &mut buf).expect("Cannot read 6 bytes from i2c");
+        dev.write_read(&command, &mut buf)?;
 
         let dx = LittleEndian::read_i16(&buf[0..2]);
         let dy = LittleEndian::read_i16(&buf[2..4]);
         let dz = LittleEndian::read_i16(&buf[4..6]);
 
-        DataPoint::new(dx, dy, dz, status, fifo_status)
+        Ok(DataPoint::new(dx, dy, dz, status, fifo_status))
     }
 
-    pub fn read_deltas(&mut self) -> Vec<DataPoint> {
+    // Raw samples waiting in the FIFO, with their estimated measurement times, and whether it had overrun.
+    // An overrun FIFO is reset once read, so the next read starts from fresh samples. Also reads
+    // OUT_TEMP into temperature.
+    fn read_fifo(&mut self) -> Result<(Vec<DataPoint>, bool), Error> {
         let mut result_data: Vec<DataPoint> = vec![];
 
         let mut waited_for_data = false;
-        let mut status: u16 = self.bus.smbus_read_byte(_STATUS_REG).expect("L3G4200D: Cannot read status from i2c bus") as u16;
+        let started = Instant::now();
+        // Each poll is its own transaction so the encoders are not held off while we wait
+        let mut status: u16 = self.bus.transaction(|dev| dev.smbus_read_byte(_STATUS_REG))? as u16;
 
         while status & 0xf != 0xf {
-            // TODO add check for imdefinite wait
+            if started.elapsed() > POLL_TIMEOUT {
+                return Err(poll_timeout("no new data"));
+            }
             waited_for_data = true;
-            status = self.bus.smbus_read_byte(_STATUS_REG).expect("L3G4200D: Cannot status byte from i2c bus") as u16;
+            status = self.bus.transaction(|dev| dev.smbus_read_byte(_STATUS_REG))? as u16;
         }
 
         if waited_for_data {
             status += 256
         }
 
-        let mut fifo_status: u8 = self.bus.smbus_read_byte(_FIFO_SRC_REG).expect("L3G4200D: Cannot read fifo_status from i2c bus");
+        // FIFO status and the samples it describes are read in one transaction
+        let (overrun, temperature) = self.bus.transaction(|dev| {
+            let mut fifo_status: u8 = dev.smbus_read_byte(_FIFO_SRC_REG)?;
+            let overrun = fifo_status & FIFO_OVERRUN != 0;
+
+            while fifo_status & 0x1f != 0 {
+                // The FIFO cannot hold more than this, so it is not being emptied
+                if result_data.len() >= FIFO_DEPTH || started.elapsed() > POLL_TIMEOUT {
+                    return Err(poll_timeout("FIFO does not empty"));
+                }
+                let data_point = L3G4200D::read_data(dev, status, fifo_status)?;
+                result_data.push(data_point);
+                fifo_status = dev.smbus_read_byte(_FIFO_SRC_REG)?;
+            }
+            if overrun {
+                dev.smbus_write_byte(_FIFO_CTRL_REG, FIFO_MODE_BYPASS)?;
+                dev.smbus_write_byte(_FIFO_CTRL_REG, FIFO_MODE_STREAM)?;
+            }
+            Ok((overrun, dev.smbus_read_byte(_OUT_TEMP)? as i8))
+        })?;
+        self.temperature = temperature;
+        if overrun {
+            self.overruns = self.overruns.wrapping_add(1);
+        }
 
-        while fifo_status & 0x1f != 0 {
-            // TODO add check for imdefinite wait
-            let data_point = self.read_data(status, fifo_status);
-            result_data.push(data_point);
-            fifo_status = self.bus.smbus_read_byte(_FIFO_SRC_REG).expect("L3G4200D: Cannot read fifo_status from i2c bus");
+        let read_time = match self.time_origin {
+            Some(origin) => origin.elapsed().as_secs_f64(),
+            None => SystemTime::now().duration_since(UNIX_EPOCH).expect("Time went backwards").as_secs_f64()
+        };
+        let batch_len = result_data.len();
+        for (index, data_point) in result_data.iter_mut().enumerate() {
+            data_point.time = sample_time(read_time, batch_len, index, self.sample_period);
+        }
+
+        Ok((result_data, overrun))
+    }
+
+    pub fn read_deltas(&mut self) -> Result<GyroDeltas, Error> {
+        let (result_data, overrun) = self.read_fifo()?;
+        if overrun {
+            debug!("L3G4200D FIFO overrun, {} so far", self.overruns);
+        }
+        if let Some((cx, cy, cz)) = self.compensated_bias() {
+            self.cx = cx;
+            self.cy = cy;
+            self.cz = cz;
         }
 
         for data_point in &result_data {
@@ -200,6 +417,149 @@ impl L3G4200D {
             self.pz = z * self.combine_filter + invert_combine_filter * self.pz;
         }
 
-        result_data
+        Ok(GyroDeltas { data_points: result_data, overrun })
+    }
+
+    // So sample times line up with the time of the telemetry records they are logged in.
+    pub fn set_time_origin(&mut self, origin: Option<Instant>) {
+        self.time_origin = origin;
+    }
+
+    // OUT_TEMP register, also read with every FIFO read; see BiasPoint.
+    pub fn read_temperature(&mut self) -> Result<i8, Error> {
+        self.temperature = self.bus.transaction(|dev| dev.smbus_read_byte(_OUT_TEMP))? as i8;
+        Ok(self.temperature)
+    }
+
+    // While both cold and warm points are set, read_deltas takes the bias from them at the current
+    // temperature instead of from calibrate.
+    pub fn set_bias_point(&mut self, kind: BiasPointKind, point: BiasPoint) {
+        match kind {
+            BiasPointKind::Cold => self.cold_bias = Some(point),
+            BiasPointKind::Warm => self.warm_bias = Some(point),
+        }
+    }
+
+    // Bias point at the current temperature from a bias in raw units of the current range.
+    pub fn bias_point(&self, cx: f64, cy: f64, cz: f64) -> BiasPoint {
+        let scale = self.sensitivity / Range::Range250.sensitivity();
+        BiasPoint { temperature: self.temperature as f64, cx: cx * scale, cy: cy * scale, cz: cz * scale }
+    }
+
+    // In raw units of the current range.
+    pub fn compensated_bias(&self) -> Option<(f64, f64, f64)> {
+        let scale = Range::Range250.sensitivity() / self.sensitivity;
+        match (&self.cold_bias, &self.warm_bias) {
+            (Some(cold), Some(warm)) => bias_at(cold, warm, self.temperature as f64).map(|(cx, cy, cz)| (cx * scale, cy * scale, cz * scale)),
+            _ => None
+        }
+    }
+
+    // Bias is rescaled so it holds in the new range; samples already in the FIFO are read as if
+    // they were taken in it.
+    pub fn set_range(&mut self, range: Range) -> Result<(), Error> {
+        self.bus.transaction(|dev| dev.smbus_write_byte(_CTRL_REG4, range.ctrl_reg4()))?;
+        let scale = self.sensitivity / range.sensitivity();
+        self.cx *= scale;
+        self.cy *= scale;
+        self.cz *= scale;
+        self.range = range;
+        self.sensitivity = range.sensitivity();
+        Ok(())
+    }
+
+    // WHO_AM_I register; DEVICE_ID for a gyro that answers as it should.
+    pub fn who_am_i(&mut self) -> Result<u8, Error> {
+        self.bus.transaction(|dev| dev.smbus_read_byte(WHO_AM_I))
     }
-}
\ No newline at end of file
+
+    // Samples per second the FIFO fills at, counted over window after emptying it.
+    pub fn measure_sample_rate(&mut self, window: Duration) -> Result<f64, Error> {
+        self.read_fifo()?;
+        let started = Instant::now();
+        let mut samples: usize = 0;
+        while started.elapsed() < window {
+            samples += self.read_fifo()?.0.len();
+        }
+        Ok(samples as f64 / started.elapsed().as_secs_f64())
+    }
+
+    // Averages the given number of raw samples into the bias (cx, cy, cz) read_deltas subtracts.
+    // The rover must be held still meanwhile. On error the previous bias is kept.
+    pub fn calibrate(&mut self, samples: usize) -> Result<(f64, f64, f64), Error> {
+        self.calibrate_with_progress(samples, |_| {})
+    }
+
+    // As calibrate; progress is called with the number of samples collected so far after each FIFO read.
+    pub fn calibrate_with_progress<F: FnMut(usize)>(&mut self, samples: usize, mut progress: F) -> Result<(f64, f64, f64), Error> {
+        let samples = samples.max(1);
+        let mut sum_x: f64 = 0.0;
+        let mut sum_y: f64 = 0.0;
+        let mut sum_z: f64 = 0.0;
+        let mut collected: usize = 0;
+
+        // Whatever is in the FIFO now may have been measured before the rover was still
+        self.read_fifo()?;
+
+        while collected < samples {
+            for data_point in self.read_fifo()?.0.iter().take(samples - collected) {
+                sum_x += data_point.dx as f64;
+                sum_y += data_point.dy as f64;
+                sum_z += data_point.dz as f64;
+                collected += 1;
+            }
+            progress(collected);
+        }
+
+        self.cx = sum_x / collected as f64;
+        self.cy = sum_y / collected as f64;
+        self.cz = sum_z / collected as f64;
+        self.px = 0.0;
+        self.py = 0.0;
+        self.pz = 0.0;
+
+        Ok((self.cx, self.cy, self.cz))
+    }
+}
+
+fn poll_timeout(reason: &str) -> Error {
+    Error::Io(io::Error::new(io::ErrorKind::TimedOut, format!("L3G4200D: {} within {:?}", reason, POLL_TIMEOUT)))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bias_follows_the_line_through_both_points_beyond_them_too() {
+        let cold = BiasPoint { temperature: 20.0, cx: 10.0, cy: -4.0, cz: 2.0 };
+        let warm = BiasPoint { temperature: 10.0, cx: 30.0, cy: -8.0, cz: 2.0 };
+        assert_eq!(bias_at(&cold, &warm, 20.0), Some((10.0, -4.0, 2.0)));
+        assert_eq!(bias_at(&cold, &warm, 15.0), Some((20.0, -6.0, 2.0)));
+        assert_eq!(bias_at(&cold, &warm, 5.0), Some((40.0, -10.0, 2.0)));
+        assert_eq!(bias_at(&cold, &cold, 20.0), None);
+    }
+
+    #[test]
+    fn bias_points_survive_storage_and_bad_ones_are_refused() {
+        let warm = BiasPoint { temperature: 10.0, cx: 30.0, cy: -8.0, cz: 2.0 };
+        assert_eq!(BiasPoint::parse(&warm.to_storage()), Ok(warm));
+        for stored in ["1,2,3", "1,2,3,x", "1,2,3,inf"].iter() {
+            assert!(BiasPoint::parse(stored).is_err(), "{:?}", stored);
+        }
+        assert_eq!(BiasPointKind::parse("warm"), Ok(BiasPointKind::Warm));
+        assert!(BiasPointKind::parse("hot").is_err());
+    }
+
+    #[test]
+    fn ranges_take_the_datasheet_sensitivity_and_values_between_the_next_one_up() {
+        let cases = [(100.0, Range::Range250), (250.0, Range::Range250), (300.0, Range::Range500), (500.0, Range::Range500), (2000.0, Range::Range2000), (5000.0, Range::Range2000)];
+        for (dps, expected) in cases.iter() {
+            assert_eq!(Range::from_dps(*dps), *expected, "{} dps", dps);
+        }
+        assert_eq!(Range::Range250.sensitivity(), 0.00875);
+        assert_eq!(Range::Range500.sensitivity(), 0.0175);
+        assert_eq!(Range::Range2000.sensitivity(), 0.07);
+    }
+}
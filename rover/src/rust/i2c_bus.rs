@@ -0,0 +1,419 @@
+//
+// Copyright (C) 2020 Abstract Horizon
+// All rights reserved. This program and the accompanying materials
+// are made available under the terms of the Apache License v2.0
+// which accompanies this distribution, and is available at
+// https://www.apache.org/licenses/LICENSE-2.0
+//
+//  Contributors:
+//    Daniel Sendula - initial API and implementation
+//
+
+// Devices on the same i2c bus share one SharedBus. Every exchange with a device is a transaction:
+//
+//   bus.with_device(address, |dev| { dev.write_read(&command, &mut buf) })
+//
+// holds the bus from setting the slave address to the end of the closure, so multi step
+// exchanges are never split by another thread talking to another device. Transactions must
+// not sleep (use i2c_bus::sleep, which checks) and must not nest.
+
+use std::cell::Cell;
+use std::io;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use rppal::i2c::{I2c, Error};
+
+use crate::pi_mutex::PiMutex;
+
+
+pub const DEFAULT_SOFT_DEADLINE: Duration = Duration::from_millis(2);
+pub const DEFAULT_HARD_DEADLINE: Duration = Duration::from_millis(10);
+
+const OVERRUN_LOG_INTERVAL: Duration = Duration::from_secs(1);
+// Kernel i2c timeouts are set in units of 10ms
+const KERNEL_TIMEOUT_RESOLUTION_MS: u32 = 10;
+
+thread_local! {
+    static HOLDING_BUS: Cell<bool> = const { Cell::new(false) };
+}
+
+// Marks the current thread as holding a bus, until dropped (also when the transaction panics).
+struct Holding;
+
+impl Holding {
+    fn start() -> Holding {
+        HOLDING_BUS.with(|holding| holding.set(true));
+        Holding
+    }
+}
+
+impl Drop for Holding {
+    fn drop(&mut self) {
+        HOLDING_BUS.with(|holding| holding.set(false));
+    }
+}
+
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Deadline {
+    Within,
+    // Took longer than the soft deadline; counted and logged
+    Soft,
+    // Took longer than the hard deadline; reported as a timeout
+    Hard,
+}
+
+pub fn classify(duration: Duration, soft_deadline: Duration, hard_deadline: Duration) -> Deadline {
+    if duration > hard_deadline {
+        Deadline::Hard
+    } else if duration > soft_deadline {
+        Deadline::Soft
+    } else {
+        Deadline::Within
+    }
+}
+
+#[derive(Clone, Copy, Default, Debug)]
+pub struct I2cStats {
+    pub transactions: u64,
+    pub soft_overruns: u32,
+    pub hard_overruns: u32,
+    // Longest transaction since the last take_longest, in seconds
+    pub longest: f64,
+}
+
+impl I2cStats {
+    pub fn record(&mut self, duration: Duration, deadline: Deadline) {
+        self.transactions += 1;
+        match deadline {
+            Deadline::Within => {},
+            Deadline::Soft => self.soft_overruns += 1,
+            Deadline::Hard => self.hard_overruns += 1,
+        }
+        self.longest = self.longest.max(duration.as_secs_f64());
+    }
+
+    pub fn add(&mut self, other: &I2cStats) {
+        self.transactions += other.transactions;
+        self.soft_overruns += other.soft_overruns;
+        self.hard_overruns += other.hard_overruns;
+        self.longest = self.longest.max(other.longest);
+    }
+}
+
+// How long transactions held the bus and how long they waited for it, in seconds, since the last take.
+#[derive(Clone, Copy, Default, Debug)]
+pub struct LockStats {
+    pub transactions: u64,
+    pub max_hold: f64,
+    pub max_wait: f64,
+}
+
+impl LockStats {
+    pub fn add(&mut self, other: &LockStats) {
+        self.transactions += other.transactions;
+        self.max_hold = self.max_hold.max(other.max_hold);
+        self.max_wait = self.max_wait.max(other.max_wait);
+    }
+}
+
+
+// What a bus needs from the underlying adapter; implemented by rppal's I2c and by test doubles.
+pub trait I2cTransport: Send {
+    fn set_slave_address(&mut self, address: u16) -> Result<(), Error>;
+    fn set_timeout(&mut self, timeout_ms: u32) -> Result<(), Error>;
+    fn smbus_read_byte(&mut self, command: u8) -> Result<u8, Error>;
+    fn smbus_write_byte(&mut self, command: u8, value: u8) -> Result<(), Error>;
+    fn write_read(&mut self, write_buffer: &[u8], read_buffer: &mut [u8]) -> Result<(), Error>;
+}
+
+impl I2cTransport for I2c {
+    fn set_slave_address(&mut self, address: u16) -> Result<(), Error> { I2c::set_slave_address(self, address) }
+    fn set_timeout(&mut self, timeout_ms: u32) -> Result<(), Error> { I2c::set_timeout(self, timeout_ms) }
+    fn smbus_read_byte(&mut self, command: u8) -> Result<u8, Error> { I2c::smbus_read_byte(self, command) }
+    fn smbus_write_byte(&mut self, command: u8, value: u8) -> Result<(), Error> { I2c::smbus_write_byte(self, command, value) }
+    fn write_read(&mut self, write_buffer: &[u8], read_buffer: &mut [u8]) -> Result<(), Error> { I2c::write_read(self, write_buffer, read_buffer) }
+}
+
+// A device on the bus for the duration of one transaction.
+pub struct BusDevice<'a> {
+    transport: &'a mut dyn I2cTransport,
+}
+
+impl<'a> BusDevice<'a> {
+    pub fn smbus_read_byte(&mut self, command: u8) -> Result<u8, Error> {
+        self.transport.smbus_read_byte(command)
+    }
+
+    pub fn smbus_write_byte(&mut self, command: u8, value: u8) -> Result<(), Error> {
+        self.transport.smbus_write_byte(command, value)
+    }
+
+    pub fn write_read(&mut self, write_buffer: &[u8], read_buffer: &mut [u8]) -> Result<(), Error> {
+        self.transport.write_read(write_buffer, read_buffer)
+    }
+}
+
+struct BusState {
+    transport: Box<dyn I2cTransport>,
+    address: Option<u16>,
+    lock_stats: LockStats,
+}
+
+#[derive(Clone)]
+pub struct SharedBus {
+    // Taken by the balance thread; see pi_mutex
+    state: Arc<PiMutex<BusState>>,
+}
+
+impl SharedBus {
+    pub fn new(bus: u8) -> SharedBus {
+        SharedBus::open(bus).unwrap_or_else(|_| panic!("Cannot initialise i2c bus {}", bus))
+    }
+
+    pub fn open(bus: u8) -> Result<SharedBus, Error> {
+        Ok(SharedBus::with_transport(Box::new(I2c::with_bus(bus)?)))
+    }
+
+    pub fn with_transport(transport: Box<dyn I2cTransport>) -> SharedBus {
+        SharedBus {
+            state: Arc::new(PiMutex::new(BusState { transport, address: None, lock_stats: LockStats::default() })),
+        }
+    }
+
+    pub fn with_device<R>(&self, address: u16, transaction: impl FnOnce(&mut BusDevice) -> Result<R, Error>) -> Result<R, Error> {
+        self.with_device_timed(address, transaction).0
+    }
+
+    // Also returns how long the bus was held.
+    fn with_device_timed<R>(&self, address: u16, transaction: impl FnOnce(&mut BusDevice) -> Result<R, Error>) -> (Result<R, Error>, Duration) {
+        debug_assert!(!HOLDING_BUS.with(|holding| holding.get()), "i2c transactions must not nest");
+
+        let waiting = Instant::now();
+        let mut state = self.state.lock().unwrap();
+        let held = Instant::now();
+        let wait = held - waiting;
+
+        let mut result = Ok(());
+        if state.address != Some(address) {
+            state.address = None;
+            result = state.transport.set_slave_address(address);
+            if result.is_ok() {
+                state.address = Some(address);
+            }
+        }
+        let result = result.and_then(|_| {
+            let _holding = Holding::start();
+            let mut device = BusDevice { transport: state.transport.as_mut() };
+            transaction(&mut device)
+        });
+
+        let hold = held.elapsed();
+        state.lock_stats.add(&LockStats { transactions: 1, max_hold: hold.as_secs_f64(), max_wait: wait.as_secs_f64() });
+        (result, hold)
+    }
+
+    pub fn set_timeout(&self, timeout_ms: u32) -> Result<(), Error> {
+        self.state.lock().unwrap().transport.set_timeout(timeout_ms)
+    }
+
+    pub fn take_lock_stats(&self) -> LockStats {
+        let mut state = self.state.lock().unwrap();
+        let lock_stats = state.lock_stats;
+        state.lock_stats = LockStats::default();
+        lock_stats
+    }
+}
+
+// Drivers sleep through this so sleeping with the bus held is caught in debug builds.
+pub fn sleep(duration: Duration) {
+    debug_assert!(!HOLDING_BUS.with(|holding| holding.get()), "i2c bus held across a sleep");
+    thread::sleep(duration);
+}
+
+
+// One device on a shared bus, with deadlines on its transactions. Transactions over the hard
+// deadline are cut short by the kernel where the adapter supports it, and are reported as
+// timeouts either way.
+pub struct I2cBus {
+    bus: SharedBus,
+    address: u16,
+    device: &'static str,
+    soft_deadline: Duration,
+    hard_deadline: Duration,
+    stats: I2cStats,
+    last_overrun_log: Option<Instant>,
+}
+
+impl I2cBus {
+    pub fn new(device: &'static str, bus: &SharedBus, address: u16) -> I2cBus {
+        let mut result = I2cBus {
+            bus: bus.clone(),
+            address,
+            device,
+            soft_deadline: DEFAULT_SOFT_DEADLINE,
+            hard_deadline: DEFAULT_HARD_DEADLINE,
+            stats: I2cStats::default(),
+            last_overrun_log: None,
+        };
+        result.set_deadlines(DEFAULT_SOFT_DEADLINE, DEFAULT_HARD_DEADLINE);
+        result
+    }
+
+    // The kernel timeout is per bus, so the last device to set it wins.
+    pub fn set_deadlines(&mut self, soft_deadline: Duration, hard_deadline: Duration) {
+        self.soft_deadline = soft_deadline;
+        self.hard_deadline = hard_deadline;
+
+        let ms = hard_deadline.as_millis() as u32;
+        let kernel_timeout = ms.div_ceil(KERNEL_TIMEOUT_RESOLUTION_MS).max(1) * KERNEL_TIMEOUT_RESOLUTION_MS;
+        if let Err(e) = self.bus.set_timeout(kernel_timeout) {
+            warn!("{}: Cannot set i2c timeout to {}ms: {:?}", self.device, kernel_timeout, e);
+        }
+    }
+
+    pub fn stats(&self) -> I2cStats {
+        self.stats
+    }
+
+    pub fn take_longest(&mut self) -> f64 {
+        let longest = self.stats.longest;
+        self.stats.longest = 0.0;
+        longest
+    }
+
+    // Deadlines apply to the time the transaction held the bus, not to waiting for it.
+    pub fn transaction<R>(&mut self, transaction: impl FnOnce(&mut BusDevice) -> Result<R, Error>) -> Result<R, Error> {
+        let (result, duration) = self.bus.with_device_timed(self.address, transaction);
+
+        let deadline = classify(duration, self.soft_deadline, self.hard_deadline);
+        self.stats.record(duration, deadline);
+        if deadline != Deadline::Within && self.last_overrun_log.is_none_or(|last| last.elapsed() >= OVERRUN_LOG_INTERVAL) {
+            warn!("{}: i2c transaction took {:?} ({} soft, {} hard overruns so far)",
+                self.device, duration, self.stats.soft_overruns, self.stats.hard_overruns);
+            self.last_overrun_log = Some(Instant::now());
+        }
+
+        match deadline {
+            Deadline::Hard => Err(Error::Io(io::Error::new(io::ErrorKind::TimedOut,
+                format!("{}: i2c transaction took {:?}", self.device, duration)))),
+            _ => result
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    // Records which slave address every write_read went to, and the first byte written.
+    struct RecordingTransport {
+        address: u16,
+        log: Arc<Mutex<Vec<(u16, u8)>>>,
+    }
+
+    impl I2cTransport for RecordingTransport {
+        fn set_slave_address(&mut self, address: u16) -> Result<(), Error> { self.address = address; Ok(()) }
+        fn set_timeout(&mut self, _timeout_ms: u32) -> Result<(), Error> { Ok(()) }
+        fn smbus_read_byte(&mut self, _command: u8) -> Result<u8, Error> { Ok(0) }
+        fn smbus_write_byte(&mut self, _command: u8, _value: u8) -> Result<(), Error> { Ok(()) }
+        fn write_read(&mut self, write_buffer: &[u8], _read_buffer: &mut [u8]) -> Result<(), Error> {
+            self.log.lock().unwrap().push((self.address, write_buffer[0]));
+            // Give the other thread every chance to get in between
+            thread::yield_now();
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn transactions_are_counted_against_their_deadlines() {
+        let soft = Duration::from_millis(2);
+        let hard = Duration::from_millis(10);
+
+        let mut stats = I2cStats::default();
+        for ms in [1, 2, 3, 10, 12].iter() {
+            let duration = Duration::from_millis(*ms);
+            stats.record(duration, classify(duration, soft, hard));
+        }
+        assert_eq!(classify(Duration::from_millis(12), soft, hard), Deadline::Hard);
+        assert_eq!(stats.transactions, 5);
+        assert_eq!(stats.soft_overruns, 2);
+        assert_eq!(stats.hard_overruns, 1);
+        assert_eq!(stats.longest, 0.012);
+    }
+
+    // Every write_read takes as long as an adapter held up by another master.
+    struct SlowTransport(Duration);
+
+    impl I2cTransport for SlowTransport {
+        fn set_slave_address(&mut self, _address: u16) -> Result<(), Error> { Ok(()) }
+        fn set_timeout(&mut self, _timeout_ms: u32) -> Result<(), Error> { Ok(()) }
+        fn smbus_read_byte(&mut self, _command: u8) -> Result<u8, Error> { Ok(0) }
+        fn smbus_write_byte(&mut self, _command: u8, _value: u8) -> Result<(), Error> { Ok(()) }
+        fn write_read(&mut self, _write_buffer: &[u8], _read_buffer: &mut [u8]) -> Result<(), Error> {
+            thread::sleep(self.0);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn transaction_past_the_hard_deadline_times_out() {
+        let bus = SharedBus::with_transport(Box::new(SlowTransport(Duration::from_millis(15))));
+        let mut device = I2cBus::new("slow", &bus, 0x36);
+        device.set_deadlines(Duration::from_millis(2), Duration::from_millis(10));
+
+        match device.transaction(|dev| dev.write_read(&[0], &mut [0u8; 2])) {
+            Err(Error::Io(e)) => assert_eq!(e.kind(), io::ErrorKind::TimedOut),
+            other => panic!("slow transaction gave {:?}", other)
+        }
+        let stats = device.stats();
+        assert_eq!((stats.transactions, stats.soft_overruns, stats.hard_overruns), (1, 0, 1));
+        assert!(stats.longest >= 0.015, "longest {}", stats.longest);
+    }
+
+    #[test]
+    fn transactions_of_devices_sharing_a_bus_are_never_split() {
+        const TRANSACTIONS: usize = 500;
+
+        let log = Arc::new(Mutex::new(vec![]));
+        let bus = SharedBus::with_transport(Box::new(RecordingTransport { address: 0, log: log.clone() }));
+
+        let threads: Vec<thread::JoinHandle<()>> = [0x36u16, 0x69u16].iter().map(|address| {
+            let mut device = I2cBus::new("test", &bus, *address);
+            device.set_deadlines(Duration::from_secs(1), Duration::from_secs(2));
+            thread::spawn(move || {
+                for _ in 0..TRANSACTIONS {
+                    device.transaction(|dev| {
+                        let mut buf = [0u8; 2];
+                        dev.write_read(&[0], &mut buf)?;
+                        dev.write_read(&[1], &mut buf)?;
+                        dev.write_read(&[2], &mut buf)
+                    }).unwrap();
+                }
+            })
+        }).collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        let log = log.lock().unwrap();
+        assert_eq!(log.len(), 2 * TRANSACTIONS * 3);
+        for transaction in log.chunks(3) {
+            assert_eq!(transaction.iter().map(|(_, step)| *step).collect::<Vec<u8>>(), vec![0, 1, 2], "split: {:?}", transaction);
+            assert!(transaction.iter().all(|(address, _)| *address == transaction[0].0), "split: {:?}", transaction);
+        }
+        assert_eq!(bus.take_lock_stats().transactions, 2 * TRANSACTIONS as u64);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "i2c bus held across a sleep")]
+    fn sleeping_with_the_bus_held_is_caught() {
+        let bus = SharedBus::with_transport(Box::new(RecordingTransport { address: 0, log: Arc::new(Mutex::new(vec![])) }));
+        let _ = bus.with_device(0x36, |_| { sleep(Duration::from_millis(1)); Ok(()) });
+    }
+}
@@ -0,0 +1,53 @@
+//
+// Copyright (C) 2020 Abstract Horizon
+// All rights reserved. This program and the accompanying materials
+// are made available under the terms of the Apache License v2.0
+// which accompanies this distribution, and is available at
+// https://www.apache.org/licenses/LICENSE-2.0
+//
+//  Contributors:
+//    Daniel Sendula - initial API and implementation
+//
+
+// The rover's modules, shared by the balancing-rover binary and the tools in bin/.
+
+pub mod telemetry_stream;
+
+pub mod telemetry_socket_server;
+pub mod telemetry_serial;
+pub mod telemetry_file;
+pub mod telemetry_reader;
+pub mod telemetry_fixtures;
+
+pub mod motors;
+pub mod balance;
+pub mod controller;
+pub mod replay;
+pub mod loop_timing;
+pub mod capabilities;
+pub mod as5600;
+pub mod encoders;
+pub mod selftest;
+pub mod gyro;
+pub mod accel;
+pub mod i2c_bus;
+pub mod config_topics;
+pub mod topic_stats;
+pub mod config_snapshot;
+pub mod profiles;
+pub mod wear;
+pub mod outbox;
+pub mod file_transfer;
+pub mod storage;
+pub mod safe_state;
+pub mod pi_mutex;
+#[cfg(feature = "mdns")]
+pub mod discovery;
+#[cfg(feature = "simulation")]
+pub mod simulation;
+#[cfg(test)]
+mod test_support;
+
+#[macro_use] extern crate log;
+
+pub use rover_control_core::{pid, orientation, fusion, windup, applied, turn, drive, slew, velocity};
@@ -0,0 +1,194 @@
+//
+// Copyright (C) 2020 Abstract Horizon
+// All rights reserved. This program and the accompanying materials
+// are made available under the terms of the Apache License v2.0
+// which accompanies this distribution, and is available at
+// https://www.apache.org/licenses/LICENSE-2.0
+//
+//  Contributors:
+//    Daniel Sendula - initial API and implementation
+//
+
+// Where the time of each pass of the balance loop goes, summed up once a second for the
+// balance-timing stream.
+//
+// The gyro read waits for the gyro's next sample, so at 200 Hz most of the 5 ms a pass has shows up
+// there; a pass that takes longer than that is the loop falling behind the gyro.
+
+use crate::telemetry_stream::{TelemetryStreamDefinition, Timebase};
+
+pub const BALANCE_TIMING_STREAM_VERSION: u32 = 102;
+// Seconds each balance-timing record covers
+pub const TIMING_WINDOW: f64 = 1.0;
+
+
+pub fn create_timing_logger(timebase: Timebase) -> TelemetryStreamDefinition {
+    TelemetryStreamDefinition::new("balance-timing", BALANCE_TIMING_STREAM_VERSION,
+        vec![
+            // Seconds, longest and mean over the window
+            TelemetryStreamDefinition::double_field("gyro_max"),
+            TelemetryStreamDefinition::double_field("gyro_mean"),
+            TelemetryStreamDefinition::double_field("accel_max"),
+            TelemetryStreamDefinition::double_field("accel_mean"),
+            TelemetryStreamDefinition::double_field("control_max"),
+            TelemetryStreamDefinition::double_field("control_mean"),
+            TelemetryStreamDefinition::double_field("log_max"),
+            TelemetryStreamDefinition::double_field("log_mean"),
+            TelemetryStreamDefinition::double_field("loop_max"),
+            TelemetryStreamDefinition::double_field("loop_mean"),
+            TelemetryStreamDefinition::unsigned_integer_field("passes"),
+            TelemetryStreamDefinition::unsigned_integer_field("overruns"),
+        ]
+    ).with_timebase(timebase)
+}
+
+
+// Seconds the sections of one pass took. Logging is 0 for passes that logged nothing, so its mean
+// is what logging costs a pass on average.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct LoopSections {
+    pub gyro: f64,
+    pub accel: f64,
+    // Controller, state machine and motors
+    pub control: f64,
+    pub log: f64,
+    // The whole pass, commands and the rest included
+    pub total: f64,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct SectionStats {
+    pub max: f64,
+    pub sum: f64,
+}
+
+impl SectionStats {
+    fn add(&mut self, seconds: f64) {
+        self.max = self.max.max(seconds);
+        self.sum += seconds;
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct TimingWindow {
+    pub gyro: SectionStats,
+    pub accel: SectionStats,
+    pub control: SectionStats,
+    pub log: SectionStats,
+    pub total: SectionStats,
+    pub passes: u32,
+    // Passes that took longer than the overrun time
+    pub overruns: u32,
+}
+
+impl TimingWindow {
+    pub fn mean(&self, stats: &SectionStats) -> f64 {
+        if self.passes == 0 { 0.0 } else { stats.sum / self.passes as f64 }
+    }
+}
+
+
+pub struct LoopTiming {
+    window: TimingWindow,
+    window_started: f64,
+}
+
+impl LoopTiming {
+    pub fn new(now: f64) -> LoopTiming {
+        LoopTiming { window: TimingWindow::default(), window_started: now }
+    }
+
+    // Adds one pass, which overran if it took longer than overrun_time; once the window has
+    // covered TIMING_WINDOW it is returned and the next one started.
+    pub fn add(&mut self, now: f64, sections: &LoopSections, overrun_time: f64) -> Option<TimingWindow> {
+        self.window.gyro.add(sections.gyro);
+        self.window.accel.add(sections.accel);
+        self.window.control.add(sections.control);
+        self.window.log.add(sections.log);
+        self.window.total.add(sections.total);
+        self.window.passes += 1;
+        if sections.total > overrun_time {
+            self.window.overruns += 1;
+        }
+
+        if now - self.window_started < TIMING_WINDOW {
+            return None;
+        }
+        self.window_started = now;
+        Some(std::mem::take(&mut self.window))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+    use std::time::Duration;
+
+    use crate::telemetry_reader::{Frame, Value};
+    use crate::telemetry_socket_server::SocketTelemetryServerBuilder;
+    use crate::telemetry_stream::Storable;
+    use crate::test_support::{connect, read_frames};
+    use super::*;
+
+    // 250 passes at 200 a second, every 50th overrunning
+    fn one_window() -> Vec<TimingWindow> {
+        let mut timing = LoopTiming::new(0.0);
+        let mut windows = vec![];
+        for pass in 1..=250 {
+            let total = if pass % 50 == 0 { 0.009 } else { 0.005 };
+            let sections = LoopSections { gyro: total - 0.001, accel: 0.0004, control: 0.0002, log: 0.0004, total };
+            if let Some(window) = timing.add(pass as f64 / 200.0, &sections, 0.0075) {
+                windows.push(window);
+            }
+        }
+        windows
+    }
+
+    #[test]
+    fn second_of_passes_makes_one_window() {
+        let windows = one_window();
+        assert_eq!(windows.len(), 1);
+        let window = windows[0];
+        assert_eq!(window.passes, 200);
+        assert_eq!(window.overruns, 4);
+        assert_eq!(window.total.max, 0.009);
+        assert_eq!(window.gyro.max, 0.008);
+        assert!((window.mean(&window.total) - 0.00508).abs() < 1e-9, "loop mean {}", window.mean(&window.total));
+    }
+
+    #[test]
+    fn window_reaches_clients_next_to_another_stream() {
+        let window = one_window()[0];
+        let mut builder = SocketTelemetryServerBuilder::new();
+        builder.register_stream(TelemetryStreamDefinition::new("balance-data", 20, vec![TelemetryStreamDefinition::unsigned_byte_field("i")])).unwrap();
+        let timing_logger = builder.register_stream(create_timing_logger(Timebase::Monotonic)).unwrap();
+        let server = builder.create(0);
+        let mut client = connect(server.port());
+        thread::sleep(Duration::from_millis(200));
+        log_with_time!(
+            server, timing_logger,
+            window.gyro.max, window.mean(&window.gyro), window.accel.max, window.mean(&window.accel),
+            window.control.max, window.mean(&window.control), window.log.max, window.mean(&window.log),
+            window.total.max, window.mean(&window.total),
+            window.passes, window.overruns);
+
+        let frames = read_frames(&mut client, |frame| matches!(frame, Frame::Record { .. }));
+        server.stop().unwrap();
+
+        let definitions: Vec<&str> = frames.iter().filter_map(|frame| match frame {
+            Frame::Definition(definition) => Some(definition.name.as_str()),
+            _ => None
+        }).collect();
+        assert_eq!(definitions, vec!["balance-data", "balance-timing"]);
+        match frames.last() {
+            Some(Frame::Record { stream_id, values, .. }) => {
+                assert_eq!(*stream_id, BALANCE_TIMING_STREAM_VERSION);
+                let value = |name: &str| values.iter().find(|(field, _)| field == name).map(|(_, value)| value.clone());
+                assert_eq!(value("loop_max"), Some(Value::Float(0.009)));
+                assert_eq!(value("overruns"), Some(Value::Unsigned(4)));
+            },
+            other => panic!("expected a record, got {:?}", other.map(|frame| frame.to_json()))
+        }
+    }
+}
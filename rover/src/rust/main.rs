@@ -9,101 +9,587 @@
 //    Daniel Sendula - initial API and implementation
 //
 
+#[macro_use] extern crate log;
 
-mod telemetry_stream;
+use balancing_rover::{
+    applied, balance, capabilities, config_snapshot, config_topics, file_transfer, gyro, i2c_bus, motors,
+    orientation, outbox, profiles, safe_state, storage, telemetry_stream, topic_stats, turn, wear
+};
+#[cfg(feature = "mdns")]
+use balancing_rover::discovery;
 
-mod telemetry_socket_server;
-
-mod pid;
-mod motors;
-mod balance;
-mod as5600;
-mod gyro;
-mod accel;
-
-use balance::{Balance, BalanceControl, ConfigData};
+use applied::Applied;
+use balance::{Balance, BalanceControl, BalanceEvent, CalibrationStatus, Hardware};
+use capabilities::Capabilities;
+use config_topics::{AdjustedTopics, Apply};
+use i2c_bus::SharedBus;
+use motors::EnableGate;
+use orientation::AxisMapping;
+use file_transfer::{FileTransfer, GetRequest, TransferEvent};
+use gyro::{BiasPoint, BiasPointKind};
+use outbox::{Outbox, OutgoingMessage, Priority};
+use profiles::Profiles;
+use safe_state::SafeState;
+use storage::Storage;
+use telemetry_stream::Timebase;
+use topic_stats::TopicCounters;
+use turn::{TurnRejected, TurnRequest, TurnResult};
+use wear::{WearCounters, WearPersistence};
 
 use std::collections::HashMap;
-//use std::time::Duration;
+use std::path::PathBuf;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 //use std::thread;
 
-use crossbeam_channel::select;
-use ctrlc;
+use crossbeam_channel::{select, Receiver};
+
+use rumqtt::{ClientError, MqttClient, MqttOptions, QoS, Notification};
+
+
+// MQTT client id and mDNS instance name, overridden by ROVER_NAME and then by --name
+const DEFAULT_ROBOT_NAME: &str = "balance-r";
+const ROBOT_NAME_ENV: &str = "ROVER_NAME";
+// Broker as host[:port], overridden by ROVER_MQTT_BROKER and then by --mqtt-broker
+const DEFAULT_MQTT_BROKER: &str = "172.24.1.174";
+const MQTT_BROKER_ENV: &str = "ROVER_MQTT_BROKER";
+const DEFAULT_MQTT_PORT: u16 = 1883;
+const MQTT_FIRST_RETRY_DELAY: Duration = Duration::from_secs(1);
+const MQTT_MAX_RETRY_DELAY: Duration = Duration::from_secs(30);
+const STATE_TOPIC: &str = "balancing/info";
+const BALANCING_STATE_TOPIC: &str = "balancing/state";
+const WARNINGS_TOPIC: &str = "balance/warnings";
+const TOPICS_TOPIC: &str = "balancing/topics";
+const CONFIG_REQUEST_TOPIC: &str = "balance/config/request";
+const CONFIG_CURRENT_TOPIC: &str = "balance/config/current";
+const SNAPSHOT_CURRENT_TOPIC: &str = "balancing/config/current";
+const SNAPSHOT_ACK_TOPIC: &str = "balancing/config/ack";
+const PROFILES_STORAGE_TOPIC: &str = "balance/profiles";
+const PROFILE_REPLY_TOPIC: &str = "balance/profile/reply";
+const WEAR_STORAGE_TOPIC: &str = "motors/wear/counters";
+const WEAR_TOPIC: &str = "motors/wear";
+const TURN_DONE_TOPIC: &str = "move/turn_by/done";
+const CAPABILITIES_TOPIC: &str = "balancing/capabilities";
+const CALIBRATE_STATUS_TOPIC: &str = "balancing/calibrate/status";
+const FAULT_TOPIC: &str = "balancing/fault";
+const TELEMETRY_DROPPED_TOPIC: &str = "balancing/telemetry/dropped";
+const SELFTEST_RESPONSE_TOPIC: &str = "balancing/selftest/response";
+const ACCEL_OFFSETS_STORAGE_TOPIC: &str = "balance/accel/offsets";
+// Payload as AxisMapping::parse takes it, e.g. "y,-x,z"
+const AXIS_MAPPING_STORAGE_TOPIC: &str = "balance/axis_mapping";
+const GYRO_COLD_BIAS_STORAGE_TOPIC: &str = "balance/gyro/bias/cold";
+const GYRO_WARM_BIAS_STORAGE_TOPIC: &str = "balance/gyro/bias/warm";
+const FILES_LIST_REPLY_TOPIC: &str = "telemetry/files/list/reply";
+const FILES_STATUS_TOPIC: &str = "telemetry/files/status";
+const FILES_DATA_TOPIC: &str = "telemetry/files/data";
+
+const DEFAULT_RECORDINGS_DIR: &str = "recordings";
+// File chunks may use this many bytes per second
+const FILE_TRANSFER_RATE: f64 = 32768.0;
+
+const WEAR_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+const WEAR_PERSIST_INTERVAL: f64 = 60.0;
+// Counters are only written once stored ones were restored, or storage did not answer in this time
+const WEAR_RESTORE_GRACE: Duration = Duration::from_secs(10);
+
+const OUTBOX_FLUSH_INTERVAL: Duration = Duration::from_millis(20);
+// Telemetry records dropped since the last check are reported this often, if there were any
+const TELEMETRY_DROPS_INTERVAL: Duration = Duration::from_secs(10);
+// Bulk messages are dropped beyond this queue depth
+const OUTBOX_BULK_LIMIT: usize = 32;
+// Normal and bulk messages per second
+const OUTBOX_BUDGET: f64 = 20.0;
 
-use rumqtt::{MqttClient, MqttOptions, QoS, Notification};
-use mqtt311;
+const SLOW_NOTIFICATION_THRESHOLD: Duration = Duration::from_millis(5);
+const SLOW_NOTIFICATION_LOG_INTERVAL: Duration = Duration::from_secs(1);
 
 
+// Where MQTTClient publishes and subscribes: the broker through rumqtt, or a recording one in tests.
+// Errors are rumqtt's own, however large.
+#[allow(clippy::result_large_err)]
+trait Broker: Send {
+    fn publish(&mut self, topic: &str, qos: QoS, retain: bool, payload: Vec<u8>) -> Result<(), ClientError>;
+    fn subscribe(&mut self, topic: &str, qos: QoS) -> Result<(), ClientError>;
+}
+
+impl Broker for MqttClient {
+    fn publish(&mut self, topic: &str, qos: QoS, retain: bool, payload: Vec<u8>) -> Result<(), ClientError> {
+        MqttClient::publish(self, topic, qos, retain, payload)
+    }
+
+    fn subscribe(&mut self, topic: &str, qos: QoS) -> Result<(), ClientError> {
+        MqttClient::subscribe(self, topic, qos)
+    }
+}
+
 
 struct MQTTClient {
-    mqtt_client: MqttClient,
-    subscriptions: HashMap<&'static str, fn(msg: mqtt311::Publish, mqtt_client: &mut MQTTClient)>,
+    mqtt_client: Box<dyn Broker>,
+    subscriptions: HashMap<String, fn(msg: mqtt311::Publish, mqtt_client: &mut MQTTClient)>,
+    // Read again after every reconnection, as the broker or storage may have restarted
+    storage_topics: Vec<&'static str>,
+    topic_counters: TopicCounters,
+    applied: AdjustedTopics,
+    profiles: Profiles,
+    wear_restored: bool,
+    wear_persistence: WearPersistence,
+    outbox: Outbox,
+    file_transfer: FileTransfer,
+    // Last values from move/drive and move/rotate and when they came; each message sends both
+    drive_forward: (f64, Instant),
+    drive_turn: (f64, Instant),
     balance_control: BalanceControl,
+    // Set with --storage, when there is no storage service to answer storage/read/
+    storage: Option<Storage>,
+    started: Instant,
+    // Telemetry records dropped as of the last report
+    reported_dropped_records: usize,
 }
 
 impl MQTTClient {
-    fn new(mqtt_client: MqttClient, balance_control: BalanceControl) -> MQTTClient {
+    fn new(mqtt_client: Box<dyn Broker>, balance_control: BalanceControl, recordings_dir: PathBuf, storage: Option<Storage>) -> MQTTClient {
         MQTTClient {
             mqtt_client,
             subscriptions: HashMap::new(),
+            storage_topics: vec![],
+            topic_counters: TopicCounters::new(),
+            applied: AdjustedTopics::new(),
+            profiles: Profiles::new(),
+            wear_restored: false,
+            wear_persistence: WearPersistence::new(WEAR_PERSIST_INTERVAL),
+            outbox: Outbox::new(OUTBOX_BULK_LIMIT, OUTBOX_BUDGET),
+            file_transfer: FileTransfer::new(recordings_dir, FILE_TRANSFER_RATE),
+            drive_forward: (0.0, Instant::now()),
+            drive_turn: (0.0, Instant::now()),
             balance_control,
+            storage,
+            started: Instant::now(),
+            reported_dropped_records: 0,
+        }
+    }
+
+    fn status_snapshot(&self) -> String {
+        let status = *self.balance_control.status.lock().unwrap();
+        format!("{{ \"state\" : \"{}\", \"state_value\" : {}, \"uptime\" : {}, \"config\" : {{ {} }}, \"axis_mapping\" : \"{}\", \"output_scale\" : {}, \"saturated\" : {}, \"integrator_fraction\" : {}, \"windup\" : {}, \"pid_fault\" : {}, \"telemetry\" : {{ \"connections\" : {}, \"rejected_connections\" : {}, \"dropped_connections\" : {}, \"dropped_records\" : {}, \"serial_records\" : {}, \"serial_dropped\" : {}, \"serial_reopened\" : {}, \"file_records\" : {}, \"file_dropped\" : {}, \"files\" : {} }}, \"wear\" : {}, \"outbox\" : {}, \"adjusted\" : {}, \"topics\" : {} }}",
+            status.state.name(), status.state.value(), self.started.elapsed().as_secs_f64(), self.config_json(),
+            self.balance_control.config_data.axis_mapping,
+            self.balance_control.config_data.output_scale,
+            status.windup.saturated, status.windup.integrator_fraction, status.windup.windup,
+            status.pid_fault,
+            status.telemetry.connections, status.telemetry.rejected_connections, status.telemetry.dropped_connections,
+            status.telemetry.dropped_records,
+            status.telemetry.serial_records, status.telemetry.serial_dropped, status.telemetry.serial_reopened,
+            status.telemetry.file_records, status.telemetry.file_dropped, status.telemetry.files,
+            status.wear.to_json(),
+            self.outbox.stats().to_json(),
+            self.applied.to_json(),
+            self.topic_counters.to_json())
+    }
+
+    // Effective value of every config field after clamping, plus what was adjusted on the way.
+    fn config_snapshot(&self) -> String {
+        format!("{{ \"config\" : {{ {} }}, \"adjusted\" : {} }}", self.config_json(), self.applied.to_json())
+    }
+
+    // Config fields as members of a JSON object, without the braces.
+    fn config_json(&self) -> String {
+        let mut s = String::from("");
+        let mut first = true;
+        for config_topic in config_topics::CONFIG_TOPICS.iter() {
+            if let Some(field) = &config_topic.field {
+                if first { first = false; } else { s.push_str(", ") }
+                s.push_str(format!("\"{}\" : {}", field.name, (field.get)(&self.balance_control.config_data)).as_str());
+            }
+        }
+        s
+    }
+
+    // Everything published goes through the outbox; see flush.
+    fn publish<V: Into<Vec<u8>>>(&mut self, priority: Priority, topic: &str, qos: QoS, retain: bool, payload: V) {
+        self.outbox.push(priority, OutgoingMessage { topic: topic.to_string(), qos, retain, payload: payload.into() });
+    }
+
+    fn flush(&mut self) {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).expect("Time went backwards").as_secs_f64();
+        self.send_file_chunk(now);
+        while let Some(message) = self.outbox.pop(now) {
+            if let Err(e) = self.mqtt_client.publish(message.topic.as_str(), message.qos, message.retain, message.payload) {
+                warn!("Failed to publish to {}: {:?}", message.topic, e);
+            }
         }
     }
 
     fn subscribe(&mut self, topic: &'static str, callback: fn(msg: mqtt311::Publish, mqtt_client: &mut MQTTClient) -> ()) {
         self.mqtt_client.subscribe(topic, QoS::AtMostOnce).unwrap();
-        self.subscriptions.insert(topic, callback);
+        self.subscriptions.insert(topic.to_string(), callback);
+    }
+
+    // Must come before the first subscribe_storage, so its read requests are seen.
+    fn subscribe_storage_service(&mut self) {
+        if self.storage.is_some() {
+            for topic in [storage::READ_PREFIX, storage::WRITE_PREFIX].iter() {
+                self.mqtt_client.subscribe(&(topic.to_string() + "#"), QoS::AtLeastOnce).unwrap();
+            }
+        }
+    }
+
+    // Our own config topics are under storage/write/ too, so writes still go to their subscriptions
+    // afterwards; returns true when nothing else is subscribed to the topic.
+    fn serve_storage(&mut self, msg: &mqtt311::Publish) -> bool {
+        let storage = match &mut self.storage {
+            Some(storage) => storage,
+            None => return false
+        };
+        if let Some(key) = msg.topic_name.strip_prefix(storage::READ_PREFIX) {
+            match storage.get(key).map(|value| value.to_string()) {
+                Some(value) => self.publish(Priority::Normal, &(storage::WRITE_PREFIX.to_string() + key), QoS::AtLeastOnce, false, value),
+                None => debug!("Nothing stored for {}", key)
+            }
+            true
+        } else if let Some(key) = msg.topic_name.strip_prefix(storage::WRITE_PREFIX) {
+            if let Err(e) = storage.set(key, &String::from_utf8_lossy(&msg.payload)) {
+                warn!("Cannot store {}: {:?}", key, e);
+            }
+            !self.subscriptions.contains_key(&msg.topic_name)
+        } else {
+            false
+        }
     }
 
     fn subscribe_storage(&mut self, topic: &'static str, callback: fn(msg: mqtt311::Publish, mqtt_client: &mut MQTTClient) -> ()) {
         self.mqtt_client.subscribe(&("storage/write/".to_string() + topic), QoS::AtMostOnce).unwrap();
-        let _ = self.mqtt_client.publish(&("storage/read/".to_string() + topic), QoS::AtLeastOnce, false, "");
-        self.subscriptions.insert(Box::leak(("storage/write/".to_string() + topic).into_boxed_str()), callback);
+        self.publish(Priority::Normal, &("storage/read/".to_string() + topic), QoS::AtLeastOnce, false, "");
+        self.subscriptions.insert("storage/write/".to_string() + topic, callback);
+        self.storage_topics.push(topic);
     }
 
     fn process(&mut self, notification: Notification) {
         match notification {
             Notification::Publish(msg) => {
-                match self.subscriptions.get(&msg.topic_name.as_str()) {
+                let now = SystemTime::now().duration_since(UNIX_EPOCH).expect("Time went backwards").as_secs_f64();
+                self.topic_counters.record(&msg.topic_name, &msg.payload, now);
+                if self.serve_storage(&msg) {
+                    return;
+                }
+
+                match self.subscriptions.get(&msg.topic_name) {
                     Some(f) => f(msg, self),
-                    _ => println!("Cannot find notification for topic {}", msg.topic_name)
+                    _ => warn!("Cannot find notification for topic {}", msg.topic_name)
                 }
             },
+            Notification::Disconnection => self.outbox.set_connected(false),
             Notification::Reconnection => {
-                for key in self.subscriptions.keys() {
-                    let topic : &'static str = key;
-                    let _ = self.mqtt_client.subscribe(topic, QoS::AtMostOnce);
+                self.outbox.set_connected(true);
+                for topic in self.subscriptions.keys() {
+                    let _ = self.mqtt_client.subscribe(topic.as_str(), QoS::AtMostOnce);
+                }
+                self.subscribe_storage_service();
+                for topic in self.storage_topics.clone() {
+                    self.publish(Priority::Normal, &("storage/read/".to_string() + topic), QoS::AtLeastOnce, false, "");
                 }
             },
             _ => { }
         }
     }
 
-    fn stop(self) {
-        self.balance_control.stop();
+    // The balance loop ramps a command down once it times out, so an older value must not come back
+    // with the next message for the other one.
+    fn send_drive(&self) {
+        let timeout = Duration::from_secs_f64(self.balance_control.config_data.drive_timeout);
+        let current = |(value, at): (f64, Instant)| if at.elapsed() <= timeout { value } else { 0.0 };
+        self.balance_control.drive(current(self.drive_forward), current(self.drive_turn));
+    }
+
+    fn persist_wear(&mut self, wear: &WearCounters) {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).expect("Time went backwards").as_secs_f64();
+        self.publish(Priority::Normal, &("storage/write/".to_string() + WEAR_STORAGE_TOPIC), QoS::AtLeastOnce, false, wear.to_storage());
+        self.publish(Priority::Bulk, WEAR_TOPIC, QoS::AtMostOnce, false, wear.to_json());
+        self.wear_persistence.persisted(now, wear);
+        self.wear_restored = true;
+    }
+
+    // A file chunk only goes out once everything else has, so control traffic never waits behind a
+    // transfer and the done status cannot overtake the last chunk.
+    fn send_file_chunk(&mut self, now: f64) {
+        if !self.file_transfer.is_active() || !self.outbox.is_connected() || !self.outbox.is_empty() {
+            return;
+        }
+        match self.file_transfer.poll(now) {
+            Some(TransferEvent::Chunk { name, offset, data }) => {
+                self.publish(Priority::Bulk, &format!("{}/{}/{}", FILES_DATA_TOPIC, name, offset), QoS::AtMostOnce, false, data);
+            },
+            Some(TransferEvent::Done { name, size }) => {
+                info!("Sent recording {} ({} bytes)", name, size);
+                self.publish(Priority::Normal, FILES_STATUS_TOPIC, QoS::AtLeastOnce, false,
+                    format!("{{ \"name\" : \"{}\", \"state\" : \"done\", \"size\" : {} }}", name, size));
+            },
+            Some(TransferEvent::Failed { name, error }) => {
+                warn!("Sending recording {} failed: {:?}", name, error);
+                self.publish(Priority::Normal, FILES_STATUS_TOPIC, QoS::AtLeastOnce, false,
+                    format!("{{ \"name\" : \"{}\", \"state\" : \"failed\", \"error\" : \"{}\" }}", name, error.name()));
+            },
+            None => {}
+        }
+    }
+
+    fn check_wear(&mut self, started: Instant) {
+        if !self.wear_restored && started.elapsed() < WEAR_RESTORE_GRACE {
+            return;
+        }
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).expect("Time went backwards").as_secs_f64();
+        let wear = self.balance_control.status.lock().unwrap().wear;
+        if self.wear_persistence.should_persist(now, &wear) {
+            self.persist_wear(&wear);
+        }
+    }
+
+    fn check_telemetry_drops(&mut self) {
+        let dropped_records = self.balance_control.status.lock().unwrap().telemetry.dropped_records;
+        if dropped_records > self.reported_dropped_records {
+            let since_last = dropped_records - self.reported_dropped_records;
+            warn!("Dropped {} telemetry record(s) in the last {:?}", since_last, TELEMETRY_DROPS_INTERVAL);
+            self.publish(Priority::Normal, TELEMETRY_DROPPED_TOPIC, QoS::AtMostOnce, false,
+                format!("{{ \"dropped_records\" : {}, \"since_last\" : {} }}", dropped_records, since_last));
+            self.reported_dropped_records = dropped_records;
+        }
+    }
+
+    fn process_balance_event(&mut self, event: BalanceEvent) {
+        match event {
+            BalanceEvent::Warning(warning) => {
+                warn!("{}", warning);
+                self.publish(Priority::Critical, WARNINGS_TOPIC, QoS::AtMostOnce, false, warning);
+            },
+            BalanceEvent::Turn(turn) => {
+                self.publish(Priority::Critical, TURN_DONE_TOPIC, QoS::AtLeastOnce, false, turn_done_json(turn));
+            },
+            BalanceEvent::Capabilities(capabilities) => {
+                self.publish(Priority::Normal, CAPABILITIES_TOPIC, QoS::AtLeastOnce, true, capabilities);
+            },
+            BalanceEvent::Fault(fault) => {
+                warn!("Motors stopped by the watchdog: {:?}", fault);
+                self.publish(Priority::Critical, FAULT_TOPIC, QoS::AtLeastOnce, false, fault.to_json());
+            },
+            BalanceEvent::Calibration(calibration) => {
+                if let CalibrationStatus::AccelDone(x, y, z) = calibration {
+                    self.publish(Priority::Normal, &("storage/write/".to_string() + ACCEL_OFFSETS_STORAGE_TOPIC), QoS::AtLeastOnce, false,
+                        format!("{},{},{}", x, y, z));
+                }
+                if let CalibrationStatus::BiasPoint(kind, point) = calibration {
+                    let topic = match kind {
+                        BiasPointKind::Cold => GYRO_COLD_BIAS_STORAGE_TOPIC,
+                        BiasPointKind::Warm => GYRO_WARM_BIAS_STORAGE_TOPIC,
+                    };
+                    self.publish(Priority::Normal, &("storage/write/".to_string() + topic), QoS::AtLeastOnce, false, point.to_storage());
+                }
+                self.publish(Priority::Critical, CALIBRATE_STATUS_TOPIC, QoS::AtLeastOnce, false, calibration.to_json());
+            },
+            BalanceEvent::State(change) => {
+                info!("Balancing state {:?} -> {:?} ({:?})", change.previous, change.state, change.cause);
+                self.publish(Priority::Critical, BALANCING_STATE_TOPIC, QoS::AtLeastOnce, true, change.to_json());
+            },
+            BalanceEvent::SelfTest(report) => {
+                self.publish(Priority::Normal, SELFTEST_RESPONSE_TOPIC, QoS::AtLeastOnce, false, report.to_json());
+            },
+        }
+    }
+
+    // True when the balance loop stopped the motors and finished cleanly; see BalanceControl::stop.
+    fn stop(mut self) -> bool {
+        self.flush();
+        self.balance_control.stop()
     }
 }
 
-fn config_float_payload(msg: mqtt311::Publish, mqtt_client: &mut MQTTClient, update: fn(&mut ConfigData, f: f64) -> ()) {
-    match String::from_utf8(msg.payload.to_vec()) {
-        Ok(s) => match s.parse() {
-            Ok(f) => {
-                // println!("Got combine_gyro_factor {}", f);
-                update(&mut mqtt_client.balance_control.config_data, f);
-                mqtt_client.balance_control.send_config();
+fn config_topic_payload(msg: mqtt311::Publish, mqtt_client: &mut MQTTClient) {
+    let config_topic = match config_topics::find(msg.topic_name.trim_start_matches("storage/write/")) {
+        Some(config_topic) => config_topic,
+        None => {
+            warn!("No config topic registered for {}", msg.topic_name);
+            return;
+        }
+    };
+    if let Some(field) = &config_topic.field {
+        match String::from_utf8(msg.payload.to_vec()).ok().and_then(|s| s.parse().ok()) {
+            Some(f) => {
+                let applied = config_topic.validate(f);
+                if applied.is_adjusted() {
+                    warn!("{} {}", field.name, applied);
+                }
+                if let Some(f) = applied.effective() {
+                    if config_topic.apply == Apply::Structural {
+                        info!("{} {} takes effect after restart", field.name, f);
+                    }
+                    (field.set)(&mut mqtt_client.balance_control.config_data, f);
+                    mqtt_client.balance_control.send_config();
+                }
+                mqtt_client.applied.record(config_topic.topic, applied);
             },
-            _ => println!("Failed to parse {} for  {}", s, msg.topic_name)
+            None => println!("Failed to parse {:?} for  {}", msg.payload, msg.topic_name)
+        }
+    }
+}
+
+fn get_file(msg: mqtt311::Publish, mqtt_client: &mut MQTTClient) {
+    let payload = String::from_utf8_lossy(&msg.payload).to_string();
+    let status = match GetRequest::parse(&payload).and_then(|request| mqtt_client.file_transfer.start(&request).map(|size| (request, size))) {
+        Ok((request, size)) => {
+            info!("Sending recording {} from offset {}", request.name, request.offset);
+            format!("{{ \"name\" : \"{}\", \"state\" : \"started\", \"offset\" : {}, \"size\" : {}, \"chunk_size\" : {} }}",
+                request.name, request.offset, size, request.chunk_size)
         },
-        _ => println!("Failed to convert to utf8 {:?} for  {}", msg.payload, msg.topic_name)
+        Err(e) => {
+            warn!("Cannot send recording for request {:?}: {:?}", payload, e);
+            format!("{{ \"request\" : {:?}, \"state\" : \"rejected\", \"error\" : \"{}\" }}", payload, e.name())
+        }
+    };
+    mqtt_client.publish(Priority::Normal, FILES_STATUS_TOPIC, QoS::AtLeastOnce, false, status);
+}
+
+fn profile_name(msg: &mqtt311::Publish) -> String {
+    String::from_utf8_lossy(&msg.payload).trim().to_string()
+}
+
+fn profile_reply(mqtt_client: &mut MQTTClient, action: &str, name: &str, result: Result<String, String>) {
+    let reply = match result {
+        Ok(details) => format!("{{ \"action\" : \"{}\", \"name\" : {:?}, \"ok\" : true{} }}", action, name, details),
+        Err(e) => {
+            warn!("Cannot {} profile {:?}: {}", action, name, e);
+            format!("{{ \"action\" : \"{}\", \"name\" : {:?}, \"ok\" : false, \"error\" : {:?} }}", action, name, e)
+        }
+    };
+    mqtt_client.publish(Priority::Normal, PROFILE_REPLY_TOPIC, QoS::AtMostOnce, false, reply);
+}
+
+fn store_profiles(mqtt_client: &mut MQTTClient) {
+    let stored = mqtt_client.profiles.to_storage();
+    mqtt_client.publish(Priority::Normal, &("storage/write/".to_string() + PROFILES_STORAGE_TOPIC), QoS::AtLeastOnce, false, stored);
+}
+
+fn save_profile(msg: mqtt311::Publish, mqtt_client: &mut MQTTClient) {
+    let name = profile_name(&msg);
+    let config_data = mqtt_client.balance_control.config_data;
+    let result = mqtt_client.profiles.save(&name, config_data).map(|_| String::from(""));
+    if result.is_ok() {
+        store_profiles(mqtt_client);
+    }
+    profile_reply(mqtt_client, "save", &name, result);
+}
+
+fn delete_profile(msg: mqtt311::Publish, mqtt_client: &mut MQTTClient) {
+    let name = profile_name(&msg);
+    let result = mqtt_client.profiles.delete(&name).map(|_| String::from(""));
+    if result.is_ok() {
+        store_profiles(mqtt_client);
     }
+    profile_reply(mqtt_client, "delete", &name, result);
+}
+
+// All live fields go to the balance loop in a single NewConfig; structural ones are only reported.
+fn load_profile(msg: mqtt311::Publish, mqtt_client: &mut MQTTClient) {
+    let name = profile_name(&msg);
+    let current = mqtt_client.balance_control.config_data;
+    let result = match mqtt_client.profiles.load(&name, &current) {
+        Ok(outcome) => {
+            for (topic, applied) in outcome.applied {
+                if applied.is_adjusted() {
+                    warn!("{} {}", topic, applied);
+                }
+                mqtt_client.applied.record(topic, applied);
+            }
+            if !outcome.deferred.is_empty() {
+                warn!("Profile {:?} fields {:?} take effect after restart", name, outcome.deferred);
+            }
+            mqtt_client.balance_control.config_data = outcome.config_data;
+            mqtt_client.balance_control.send_config();
+
+            // Keep per topic storage (and the UI listening to it) in line with the loaded profile
+            for config_topic in config_topics::CONFIG_TOPICS.iter() {
+                if let Some(field) = &config_topic.field {
+                    let value = format!("{}", (field.get)(&outcome.config_data));
+                    mqtt_client.publish(Priority::Normal, &("storage/write/".to_string() + config_topic.topic), QoS::AtLeastOnce, false, value);
+                }
+            }
+            store_profiles(mqtt_client);
+
+            let deferred: Vec<String> = outcome.deferred.iter().map(|field| format!("\"{}\"", field)).collect();
+            Ok(format!(", \"adjusted\" : {}, \"deferred\" : [{}]", mqtt_client.applied.to_json(), deferred.join(", ")))
+        },
+        Err(e) => Err(e)
+    };
+    profile_reply(mqtt_client, "load", &name, result);
+}
+
+// The whole config, retained, so a known good set can be kept and sent back to balancing/config/load.
+fn save_snapshot(_: mqtt311::Publish, mqtt_client: &mut MQTTClient) {
+    let snapshot = config_snapshot::to_json(&mqtt_client.balance_control.config_data);
+    mqtt_client.publish(Priority::Normal, SNAPSHOT_CURRENT_TOPIC, QoS::AtLeastOnce, true, snapshot);
+    mqtt_client.publish(Priority::Normal, SNAPSHOT_ACK_TOPIC, QoS::AtMostOnce, false, "{ \"action\" : \"save\", \"ok\" : true }");
+}
+
+// Fields of the payload go to the balance loop in a single NewConfig, or none of them when one is invalid.
+fn load_snapshot(msg: mqtt311::Publish, mqtt_client: &mut MQTTClient) {
+    let current = mqtt_client.balance_control.config_data;
+    let reply = match config_snapshot::load(&String::from_utf8_lossy(&msg.payload), &current) {
+        Ok(outcome) => {
+            if !outcome.deferred.is_empty() {
+                warn!("Config snapshot fields {:?} take effect after restart", outcome.deferred);
+            }
+            mqtt_client.balance_control.config_data = outcome.config_data;
+            mqtt_client.balance_control.send_config();
+
+            // Keep per topic storage (and the UI listening to it) in line with the snapshot
+            let mut applied = vec![];
+            for (topic, name, value) in outcome.applied {
+                mqtt_client.applied.record(topic, Applied::Applied(value));
+                mqtt_client.publish(Priority::Normal, &("storage/write/".to_string() + topic), QoS::AtLeastOnce, false, format!("{}", value));
+                applied.push(format!("\"{}\" : {}", name, value));
+            }
+            let snapshot = config_snapshot::to_json(&outcome.config_data);
+            mqtt_client.publish(Priority::Normal, SNAPSHOT_CURRENT_TOPIC, QoS::AtLeastOnce, true, snapshot);
+
+            let deferred: Vec<String> = outcome.deferred.iter().map(|field| format!("\"{}\"", field)).collect();
+            format!("{{ \"action\" : \"load\", \"ok\" : true, \"applied\" : {{ {} }}, \"deferred\" : [{}] }}", applied.join(", "), deferred.join(", "))
+        },
+        Err(rejected) => {
+            warn!("Cannot load config snapshot: {} {}", rejected.field.as_deref().unwrap_or("payload"), rejected.reason);
+            format!("{{ \"action\" : \"load\", \"ok\" : false, {} }}", rejected.to_json())
+        }
+    };
+    mqtt_client.publish(Priority::Normal, SNAPSHOT_ACK_TOPIC, QoS::AtMostOnce, false, reply);
+}
+
+fn turn_done_json(turn: Result<TurnResult, TurnRejected>) -> String {
+    match turn {
+        Ok(result) => format!("{{ \"outcome\" : \"{}\", \"requested\" : {}, \"achieved\" : {:.1} }}",
+            result.outcome.name(), result.requested, result.achieved),
+        Err(rejected) => format!("{{ \"outcome\" : \"rejected\", \"reason\" : \"{}\" }}", rejected.name())
+    }
+}
+
+// Payload is the angle to turn by in degrees, counter-clockwise positive (e.g. "+90"), or "cancel".
+fn turn_by(msg: mqtt311::Publish, mqtt_client: &mut MQTTClient) {
+    let payload = String::from_utf8_lossy(&msg.payload).trim().to_string();
+    let request = if payload == "cancel" {
+        TurnRequest::Cancel
+    } else {
+        match payload.parse() {
+            Ok(angle) => TurnRequest::TurnBy(angle),
+            Err(_) => {
+                let reply = turn_done_json(Err(TurnRejected::InvalidAngle));
+                mqtt_client.publish(Priority::Critical, TURN_DONE_TOPIC, QoS::AtMostOnce, false, reply);
+                return;
+            }
+        }
+    };
+    mqtt_client.balance_control.turn(request);
 }
 
-fn float_payload(msg: mqtt311::Publish, mut mqtt_client: &mut MQTTClient, process: fn(&mut MQTTClient, f: f64) -> ()) {
+fn float_payload(msg: mqtt311::Publish, mqtt_client: &mut MQTTClient, process: fn(&mut MQTTClient, f: f64) -> ()) {
     match String::from_utf8(msg.payload.to_vec()) {
         Ok(s) => match s.parse() {
             Ok(f) => {
                 // println!("Got combine_gyro_factor {}", f);
-                process(&mut mqtt_client, f);
+                process(mqtt_client, f);
             },
             _ => println!("Failed to parse {} for  {}", s, msg.topic_name)
         },
@@ -111,79 +597,558 @@ fn float_payload(msg: mqtt311::Publish, mut mqtt_client: &mut MQTTClient, proces
     }
 }
 
-fn main() {
-    match MqttClient::start(MqttOptions::new("balance-r", "172.24.1.174", 1883).set_keep_alive(10)) {
-        Ok((mqtt_client, notifications)) => {
-
-            let balance = Balance::new();
-
-            let balance_control = balance.start();
-
-            let mut mqtt_client = MQTTClient::new(mqtt_client, balance_control);
-
-            mqtt_client.subscribe_storage("balance/gyro/filter", |msg, mqtt_client| 
-                config_float_payload(msg, mqtt_client, |config_data, f| config_data.combine_gyro_factor = f)
-            );
-            mqtt_client.subscribe_storage("balance/accel/filter", |msg, mqtt_client|
-                config_float_payload(msg, mqtt_client, |config_data, f| config_data.combine_accel_factor = f)
-            );
-            mqtt_client.subscribe_storage("balance/combine_factor_gyro", |msg, mqtt_client|
-                config_float_payload(msg, mqtt_client, |config_data, f| config_data.combine_gyro_accel_factor = f)
-            );
-            mqtt_client.subscribe_storage("balance/pid_inner/p", |msg, mqtt_client|
-                config_float_payload(msg, mqtt_client, |config_data, f| config_data.pid_kp = f)
-            );
-            mqtt_client.subscribe_storage("balance/pid_inner/i", |msg, mqtt_client|
-                config_float_payload(msg, mqtt_client, |config_data, f| config_data.pid_ki = f)
-            );
-            mqtt_client.subscribe_storage("balance/pid_inner/d", |msg, mqtt_client|
-                config_float_payload(msg, mqtt_client, |config_data, f| config_data.pid_kd = f)
-            );
-            mqtt_client.subscribe_storage("balance/pid_inner/g", |msg, mqtt_client|
-                config_float_payload(msg, mqtt_client, |config_data, f| config_data.pid_gain = f)
-            );
-            mqtt_client.subscribe("storage/write/balance/pid_outer/p", |_msg, _mqtt_client| {});
-            mqtt_client.subscribe("storage/write/balance/pid_outer/i", |_msg, _mqtt_client| {});
-            mqtt_client.subscribe("storage/write/balance/pid_outer/d", |_msg, _mqtt_client| {});
-            mqtt_client.subscribe("storage/write/balance/pid_outer/g", |_msg, _mqtt_client| {});
-
-            mqtt_client.subscribe("balancing/calibrate", |_, mqtt_client| {
-                mqtt_client.balance_control.calibrate();
-            });
-            mqtt_client.subscribe("balancing/start", |_, mqtt_client| {
-                mqtt_client.balance_control.start_balancing();
-            });
-            mqtt_client.subscribe("manual", |msg, mqtt_client|
-                float_payload(msg, mqtt_client, |mqtt_client, f| mqtt_client.balance_control.manual(f))
-            );
-            mqtt_client.subscribe("balancing/stop", |_, mqtt_client| {
-                mqtt_client.balance_control.stop_balancing();
-            });
-            // mqtt_client.subscribe("balancing/request-info", |_, mqtt_client| {});
-
-            let (stop_sender, stop_receiver) = crossbeam_channel::bounded(1);
-
-            ctrlc::set_handler(move || {
-                let _ = stop_sender.send(true);
-            }).expect("Error setting Ctrl-C handler");
-
-            loop {
-                select! {
-                    recv(notifications) -> notification => {
-                        println!("Received {:?}", notification);
-                        match notification {
-                            Ok(notification) => mqtt_client.process(notification),
-                            _ => {}
+// "x,y,z" as stored under ACCEL_OFFSETS_STORAGE_TOPIC, in g.
+fn parse_accel_offsets(s: &str) -> Result<(f64, f64, f64), String> {
+    let values = s.split(',').map(|value| value.trim().parse::<f64>()).collect::<Result<Vec<f64>, _>>()
+        .map_err(|e| format!("cannot parse accel offsets '{}': {}", s, e))?;
+    match values.as_slice() {
+        [x, y, z] if values.iter().all(|value| value.is_finite()) => Ok((*x, *y, *z)),
+        _ => Err(format!("expected finite x,y,z accel offsets, got '{}'", s))
+    }
+}
+
+// Broker given as host or host:port.
+fn parse_mqtt_broker(s: &str) -> Result<(String, u16), String> {
+    let (host, port) = match s.rfind(':') {
+        Some(i) => (&s[..i], s[i + 1..].parse::<u16>().map_err(|_| format!("invalid port in MQTT broker {:?}", s))?),
+        None => (s, DEFAULT_MQTT_PORT)
+    };
+    if host.is_empty() || port == 0 {
+        return Err(format!("invalid MQTT broker {:?}", s));
+    }
+    Ok((host.to_string(), port))
+}
+
+// Delay before the given (0 based) retry of the first connection to the broker.
+fn mqtt_retry_delay(retry: u32) -> Duration {
+    MQTT_FIRST_RETRY_DELAY.checked_mul(1 << retry.min(16)).unwrap_or(MQTT_MAX_RETRY_DELAY).min(MQTT_MAX_RETRY_DELAY)
+}
+
+// Keeps trying until the broker answers; once connected rumqtt reconnects by itself.
+fn connect_mqtt(robot_name: &str, host: &str, port: u16) -> (MqttClient, crossbeam_channel::Receiver<Notification>) {
+    let mut retry = 0;
+    loop {
+        match MqttClient::start(MqttOptions::new(robot_name, host, port).set_keep_alive(10)) {
+            Ok(connection) => {
+                info!("Connected to MQTT broker {}:{}", host, port);
+                return connection;
+            },
+            Err(e) => {
+                let delay = mqtt_retry_delay(retry);
+                warn!("Failed to connect to MQTT broker {}:{}: {:?}; retrying in {:?}", host, port, e, delay);
+                std::thread::sleep(delay);
+                retry += 1;
+            }
+        }
+    }
+}
+
+fn dump_topics(format: Option<String>) {
+    match format.as_deref() {
+        None | Some("json") => println!("{}", config_topics::to_json()),
+        Some("markdown") => print!("{}", config_topics::to_markdown()),
+        Some(other) => println!("Unknown --dump-topics format {}; expected json or markdown", other)
+    }
+}
+
+// Prints the capabilities that can be gathered without starting the rover; the motor board is
+// left alone as it may be in use by a running instance. Fails when a sensor is missing.
+fn self_check(telemetry_serial_device: &Option<String>) -> bool {
+    let mut capabilities = Capabilities::new();
+    let bus0 = SharedBus::open(0).map_err(|e| warn!("Cannot open i2c bus 0: {:?}", e)).ok();
+    let bus1 = SharedBus::open(1).map_err(|e| warn!("Cannot open i2c bus 1: {:?}", e)).ok();
+    let sensors = balance::probe_sensors(bus0.as_ref(), bus1.as_ref());
+    let all_detected = sensors.iter().all(|sensor| sensor.detected);
+    capabilities.sensors = Some(sensors);
+    capabilities.telemetry = Some(if telemetry_serial_device.is_some() { "tcp+serial" } else { "tcp" }.to_string());
+    println!("{}", capabilities.to_json());
+    all_detected
+}
+
+// Everything the rover answers on MQTT, with the storage topics read back first.
+fn subscribe_topics(mqtt_client: &mut MQTTClient) {
+    mqtt_client.subscribe_storage_service();
+
+    for config_topic in config_topics::CONFIG_TOPICS.iter() {
+        mqtt_client.subscribe_storage(config_topic.topic, config_topic_payload);
+    }
+    mqtt_client.publish(Priority::Normal, TOPICS_TOPIC, QoS::AtLeastOnce, true, config_topics::to_json());
+
+    mqtt_client.subscribe_storage(PROFILES_STORAGE_TOPIC, |msg, mqtt_client| {
+        mqtt_client.profiles = Profiles::from_storage(&String::from_utf8_lossy(&msg.payload));
+    });
+    // Our own writes come back on the same topic; only the first (stored) value is restored
+    mqtt_client.subscribe_storage(WEAR_STORAGE_TOPIC, |msg, mqtt_client| {
+        if mqtt_client.wear_restored {
+            return;
+        }
+        match WearCounters::from_storage(&String::from_utf8_lossy(&msg.payload)) {
+            Ok(stored) => mqtt_client.balance_control.restore_wear(stored),
+            Err(e) => warn!("Cannot restore motor wear counters: {}", e)
+        }
+        mqtt_client.wear_restored = true;
+    });
+    // Our own writes after a calibration come back here too and set the same offsets again
+    mqtt_client.subscribe_storage(ACCEL_OFFSETS_STORAGE_TOPIC, |msg, mqtt_client| {
+        match parse_accel_offsets(&String::from_utf8_lossy(&msg.payload)) {
+            Ok((x, y, z)) => mqtt_client.balance_control.set_accel_offsets(x, y, z),
+            Err(e) => warn!("Cannot restore accel offsets: {}", e)
+        }
+    });
+    // Mistyped mappings are reported and leave the one in use as it is
+    mqtt_client.subscribe_storage(AXIS_MAPPING_STORAGE_TOPIC, |msg, mqtt_client| {
+        let payload = String::from_utf8_lossy(&msg.payload);
+        match AxisMapping::parse(&payload) {
+            Ok(axis_mapping) => {
+                mqtt_client.balance_control.config_data.axis_mapping = axis_mapping;
+                mqtt_client.balance_control.send_config();
+            },
+            Err(e) => {
+                let warning = format!("Cannot use axis mapping {:?}: {}", payload, e);
+                warn!("{}", warning);
+                mqtt_client.publish(Priority::Critical, WARNINGS_TOPIC, QoS::AtMostOnce, false, warning);
+            }
+        }
+    });
+    // Temperature compensation only starts once both points are back
+    mqtt_client.subscribe_storage(GYRO_COLD_BIAS_STORAGE_TOPIC, |msg, mqtt_client| {
+        match BiasPoint::parse(&String::from_utf8_lossy(&msg.payload)) {
+            Ok(point) => mqtt_client.balance_control.set_bias_point(BiasPointKind::Cold, point),
+            Err(e) => warn!("Cannot restore gyro cold bias point: {}", e)
+        }
+    });
+    mqtt_client.subscribe_storage(GYRO_WARM_BIAS_STORAGE_TOPIC, |msg, mqtt_client| {
+        match BiasPoint::parse(&String::from_utf8_lossy(&msg.payload)) {
+            Ok(point) => mqtt_client.balance_control.set_bias_point(BiasPointKind::Warm, point),
+            Err(e) => warn!("Cannot restore gyro warm bias point: {}", e)
+        }
+    });
+    mqtt_client.subscribe("motors/wear/reset", |_, mqtt_client| {
+        info!("Resetting motor wear counters");
+        mqtt_client.balance_control.reset_wear();
+        mqtt_client.persist_wear(&WearCounters::default());
+    });
+    mqtt_client.subscribe("balance/profile/list", |_, mqtt_client| {
+        let profiles = mqtt_client.profiles.to_json();
+        mqtt_client.publish(Priority::Normal, PROFILE_REPLY_TOPIC, QoS::AtMostOnce, false, profiles);
+    });
+    mqtt_client.subscribe("telemetry/files/list", |_, mqtt_client| {
+        let reply = match mqtt_client.file_transfer.list() {
+            Ok(files) => files,
+            Err(e) => {
+                warn!("Cannot list recordings in {:?}: {:?}", mqtt_client.file_transfer.dir, e);
+                "[]".to_string()
+            }
+        };
+        mqtt_client.publish(Priority::Normal, FILES_LIST_REPLY_TOPIC, QoS::AtLeastOnce, false, reply);
+    });
+    mqtt_client.subscribe("telemetry/files/get", get_file);
+    mqtt_client.subscribe("telemetry/files/cancel", |_, mqtt_client| mqtt_client.file_transfer.cancel());
+    mqtt_client.subscribe("balance/profile/save", save_profile);
+    mqtt_client.subscribe("balance/profile/load", load_profile);
+    mqtt_client.subscribe("balance/profile/delete", delete_profile);
+    mqtt_client.subscribe("balancing/config/save", save_snapshot);
+    mqtt_client.subscribe("balancing/config/load", load_snapshot);
+
+    mqtt_client.subscribe("balancing/calibrate", |_, mqtt_client| {
+        mqtt_client.balance_control.calibrate();
+    });
+    // Payload cold or warm; the gyro must be still and at that temperature
+    mqtt_client.subscribe("balancing/calibrate/gyro_bias", |msg, mqtt_client| {
+        match BiasPointKind::parse(&String::from_utf8_lossy(&msg.payload)) {
+            Ok(kind) => mqtt_client.balance_control.calibrate_bias_point(kind),
+            Err(e) => warn!("Cannot calibrate gyro bias point: {}", e)
+        }
+    });
+    mqtt_client.subscribe("balancing/selftest", |_, mqtt_client| {
+        mqtt_client.balance_control.self_test();
+    });
+    mqtt_client.subscribe("balancing/start", |_, mqtt_client| {
+        mqtt_client.balance_control.start_balancing();
+    });
+    mqtt_client.subscribe("manual", |msg, mqtt_client|
+        float_payload(msg, mqtt_client, |mqtt_client, f| mqtt_client.balance_control.manual(f))
+    );
+    mqtt_client.subscribe("move/turn_by", turn_by);
+    mqtt_client.subscribe("move/drive", |msg, mqtt_client|
+        float_payload(msg, mqtt_client, |mqtt_client, f| {
+            mqtt_client.drive_forward = (f, Instant::now());
+            mqtt_client.send_drive();
+        })
+    );
+    mqtt_client.subscribe("move/rotate", |msg, mqtt_client|
+        float_payload(msg, mqtt_client, |mqtt_client, f| {
+            mqtt_client.drive_turn = (f, Instant::now());
+            mqtt_client.send_drive();
+        })
+    );
+    mqtt_client.subscribe("balancing/stop", |_, mqtt_client| {
+        mqtt_client.balance_control.stop_balancing();
+    });
+    // Not queued behind other commands: the motors see it before their next change, and it stays
+    // latched until balancing/reset
+    mqtt_client.subscribe("balancing/estop", |_, mqtt_client| {
+        warn!("Emergency stop requested");
+        mqtt_client.balance_control.estop();
+    });
+    mqtt_client.subscribe("balancing/reset", |_, mqtt_client| {
+        mqtt_client.balance_control.reset();
+    });
+    mqtt_client.subscribe(CONFIG_REQUEST_TOPIC, |_, mqtt_client| {
+        let snapshot = mqtt_client.config_snapshot();
+        mqtt_client.publish(Priority::Normal, CONFIG_CURRENT_TOPIC, QoS::AtMostOnce, false, snapshot);
+    });
+    mqtt_client.subscribe("balancing/request-info", |_, mqtt_client| {
+        let snapshot = mqtt_client.status_snapshot();
+        mqtt_client.publish(Priority::Bulk, STATE_TOPIC, QoS::AtMostOnce, false, snapshot);
+    });
+}
+
+// Dispatches notifications and balance events until told to stop, then hands the client back for
+// shutting down.
+fn run(mut mqtt_client: MQTTClient, notifications: Receiver<Notification>, balance_events: Receiver<BalanceEvent>, stop_receiver: Receiver<bool>) -> MQTTClient {
+    let mut last_slow_log = Instant::now() - SLOW_NOTIFICATION_LOG_INTERVAL;
+    let mut slow_notifications: u32 = 0;
+
+    let started = Instant::now();
+    let wear_ticks = crossbeam_channel::tick(WEAR_CHECK_INTERVAL);
+    let flush_ticks = crossbeam_channel::tick(OUTBOX_FLUSH_INTERVAL);
+    let telemetry_drops_ticks = crossbeam_channel::tick(TELEMETRY_DROPS_INTERVAL);
+
+    loop {
+        select! {
+            recv(notifications) -> notification => {
+                debug!("Received {:?}", notification);
+                if let Ok(notification) = notification {
+                    let started = Instant::now();
+                    mqtt_client.process(notification);
+                    let elapsed = started.elapsed();
+                    if elapsed > SLOW_NOTIFICATION_THRESHOLD {
+                        slow_notifications += 1;
+                        if last_slow_log.elapsed() >= SLOW_NOTIFICATION_LOG_INTERVAL {
+                            warn!("Processing notification took {:?}; {} slow notification(s) since last report", elapsed, slow_notifications);
+                            last_slow_log = Instant::now();
+                            slow_notifications = 0;
                         }
                     }
-                    recv(stop_receiver) -> _done => break
                 }
             }
+            recv(balance_events) -> event => {
+                if let Ok(event) = event {
+                    mqtt_client.process_balance_event(event);
+                }
+            }
+            recv(wear_ticks) -> _ => mqtt_client.check_wear(started),
+            recv(flush_ticks) -> _ => mqtt_client.flush(),
+            recv(telemetry_drops_ticks) -> _ => mqtt_client.check_telemetry_drops(),
+            recv(stop_receiver) -> _done => break
+        }
+    }
+    mqtt_client
+}
+
+fn main() {
+    env_logger::init();
+
+    let safe_state = SafeState::new();
+
+    let mut telemetry_serial_device: Option<String> = None;
+    let mut telemetry_file: Option<String> = None;
+    let mut record_inputs = false;
+    let mut telemetry_timebase = Timebase::Wall;
+    let mut run_self_check = false;
+    let mut recordings_dir = PathBuf::from(DEFAULT_RECORDINGS_DIR);
+    let mut storage_file: Option<PathBuf> = None;
+    let mut enable_gate: Option<EnableGate> = None;
+    let mut force_enable = false;
+    let mut mqtt_broker = std::env::var(MQTT_BROKER_ENV).unwrap_or(DEFAULT_MQTT_BROKER.to_string());
+    let mut robot_name = std::env::var(ROBOT_NAME_ENV).unwrap_or(DEFAULT_ROBOT_NAME.to_string());
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == "--dump-topics" {
+            dump_topics(args.next());
+            return;
+        }
+        if arg == "--telemetry-serial" {
+            telemetry_serial_device = args.next();
+        }
+        if arg == "--telemetry-file" {
+            telemetry_file = args.next();
+        }
+        // Sensor inputs of every sample go to the balance-input stream, for replays
+        if arg == "--record-inputs" {
+            record_inputs = true;
+        }
+        if arg == "--telemetry-timebase" {
+            match args.next().map(|value| Timebase::parse(&value)) {
+                Some(Ok(timebase)) => telemetry_timebase = timebase,
+                Some(Err(e)) => {
+                    println!("{}", e);
+                    std::process::exit(1);
+                },
+                None => {
+                    println!("--telemetry-timebase needs wall or monotonic");
+                    std::process::exit(1);
+                }
+            }
+        }
+        if arg == "--mqtt-broker" {
+            if let Some(broker) = args.next() {
+                mqtt_broker = broker;
+            }
+        }
+        if arg == "--name" {
+            if let Some(name) = args.next() {
+                robot_name = name;
+            }
+        }
+        if arg == "--recordings" {
+            if let Some(dir) = args.next() {
+                recordings_dir = PathBuf::from(dir);
+            }
+        }
+        if arg == "--storage" {
+            storage_file = args.next().map(PathBuf::from);
+        }
+        if arg == "--enable-pin" {
+            match args.next().map(|value| EnableGate::parse(&value)) {
+                Some(Ok(gate)) => enable_gate = Some(gate),
+                Some(Err(e)) => {
+                    println!("{}", e);
+                    std::process::exit(1);
+                },
+                None => {
+                    println!("--enable-pin needs <pin> or <pin>:low");
+                    std::process::exit(1);
+                }
+            }
+        }
+        // Bench testing only: motors run whatever the enable pin says
+        if arg == "--force-enable" {
+            force_enable = true;
+        }
+        if arg == "--self-check" {
+            run_self_check = true;
+        }
+    }
+    if record_inputs && telemetry_file.is_none() {
+        println!("--record-inputs needs --telemetry-file");
+        std::process::exit(1);
+    }
+    if run_self_check {
+        std::process::exit(if self_check(&telemetry_serial_device) { 0 } else { 1 });
+    }
+
+    let (mqtt_host, mqtt_port) = match parse_mqtt_broker(&mqtt_broker) {
+        Ok(broker) => broker,
+        Err(e) => {
+            println!("{}", e);
+            std::process::exit(1);
+        }
+    };
+
+    safe_state.install_panic_hook();
+
+    let (mqtt_client, notifications) = connect_mqtt(&robot_name, &mqtt_host, mqtt_port);
+
+    let balance = Balance::new(Hardware::rover(), telemetry_serial_device, telemetry_file, telemetry_timebase, record_inputs, enable_gate, force_enable, &safe_state);
+
+    let balance_control = balance.start();
+    info!("Telemetry server listening on port {}", balance_control.telemetry_port);
+
+    #[cfg(feature = "mdns")]
+    let advertiser = match discovery::Advertiser::start(&robot_name, STATE_TOPIC, balance_control.telemetry_port, &mqtt_host, mqtt_port) {
+        Ok(advertiser) => Some(advertiser),
+        Err(e) => {
+            warn!("Failed to start mDNS advertisement: {:?}", e);
+            None
+        }
+    };
+
+    let balance_events = balance_control.events.clone();
+
+    let storage = storage_file.map(|path| {
+        let storage = Storage::open(&path);
+        info!("Serving storage from {:?}, {} keys", path, storage.key_count());
+        storage
+    });
+    let mut mqtt_client = MQTTClient::new(Box::new(mqtt_client), balance_control, recordings_dir, storage);
+    subscribe_topics(&mut mqtt_client);
+
+    let (stop_sender, stop_receiver) = crossbeam_channel::bounded(1);
+
+    // A second interrupt while still shutting down skips the rest of it
+    let interrupted_safe_state = safe_state.clone();
+    let mut interrupted = false;
+    ctrlc::set_handler(move || {
+        if interrupted {
+            interrupted_safe_state.engage("interrupted twice");
+            std::process::exit(130);
+        }
+        interrupted = true;
+        let _ = stop_sender.send(true);
+    }).expect("Error setting Ctrl-C handler");
+
+    let mqtt_client = run(mqtt_client, notifications, balance_events, stop_receiver);
+
+    println!("Finishing...");
+    #[cfg(feature = "mdns")]
+    {
+        if let Some(advertiser) = advertiser {
+            advertiser.stop();
+        }
+    }
+    mqtt_client.stop();
+    safe_state.engage("shutdown");
+    println!("Done.");
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use balancing_rover::balance::State;
+    use balancing_rover::simulation::{Driver, Scenario, Step, TestHarness, SHUTDOWN_TIMEOUT};
+    use crossbeam_channel::Sender;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+
+    // Topics and payloads, in the order they were published
+    type Published = Arc<Mutex<Vec<(String, Vec<u8>)>>>;
+
+    struct RecordingBroker {
+        published: Published,
+    }
+
+    impl Broker for RecordingBroker {
+        fn publish(&mut self, topic: &str, _qos: QoS, _retain: bool, payload: Vec<u8>) -> Result<(), ClientError> {
+            self.published.lock().unwrap().push((topic.to_string(), payload));
+            Ok(())
+        }
+
+        fn subscribe(&mut self, _topic: &str, _qos: QoS) -> Result<(), ClientError> {
+            Ok(())
+        }
+    }
+
+    // Scenario commands as messages from the broker.
+    struct MqttDriver {
+        notifications: Sender<Notification>,
+    }
+
+    impl MqttDriver {
+        fn send(&self, topic: &str, payload: &str) {
+            self.notifications.send(Notification::Publish(mqtt311::Publish {
+                dup: false,
+                qos: QoS::AtMostOnce,
+                retain: false,
+                topic_name: topic.to_string(),
+                pkid: None,
+                payload: Arc::new(payload.as_bytes().to_vec()),
+            })).unwrap();
+        }
+    }
+
+    impl Driver for MqttDriver {
+        fn start_balancing(&mut self) {
+            self.send("balancing/start", "");
+        }
+
+        fn stop_balancing(&mut self) {
+            self.send("balancing/stop", "");
+        }
+
+        fn estop(&mut self) {
+            self.send("balancing/estop", "");
+        }
+
+        fn reset(&mut self) {
+            self.send("balancing/reset", "");
+        }
+
+        fn configure(&mut self, topic: &'static str, value: f64) {
+            self.send(&("storage/write/".to_string() + topic), &value.to_string());
+        }
+    }
+
+    #[test]
+    fn broker_parses_with_and_without_a_port() {
+        assert_eq!(parse_mqtt_broker("localhost"), Ok(("localhost".to_string(), DEFAULT_MQTT_PORT)));
+        assert_eq!(parse_mqtt_broker("10.0.0.2:1884"), Ok(("10.0.0.2".to_string(), 1884)));
+        for broker in [":1883", "localhost:", "localhost:0", "localhost:70000"].iter() {
+            assert!(parse_mqtt_broker(broker).is_err(), "{:?} accepted", broker);
+        }
+    }
+
+    #[test]
+    fn retries_back_off_up_to_the_limit() {
+        let delays: Vec<u64> = [0, 1, 2, 4, 5, 100].iter().map(|retry| mqtt_retry_delay(*retry).as_secs()).collect();
+        assert_eq!(delays, vec![1, 2, 4, 16, 30, 30]);
+    }
+
+    #[test]
+    fn stored_accel_offsets_parse_back_and_bad_ones_are_refused() {
+        assert_eq!(parse_accel_offsets("0.02,-0.015,0.5"), Ok((0.02, -0.015, 0.5)));
+        assert_eq!(parse_accel_offsets(" 1 , 2 , 3 "), Ok((1.0, 2.0, 3.0)));
+        for payload in ["", "1,2", "1,2,3,4", "a,b,c", "1,NaN,3"].iter() {
+            assert!(parse_accel_offsets(payload).is_err(), "{:?} accepted", payload);
+        }
+    }
+
+    // Everything the rover runs, MQTT dispatch included, through 10s of commands, bumps and a gyro dropout.
+    #[test]
+    fn ten_seconds_of_mqtt_commands_go_through_the_balancing_states_in_order() {
+        let (mut harness, balance_control) = TestHarness::start();
+        let balance_events = balance_control.events.clone();
+        let published: Published = Arc::new(Mutex::new(vec![]));
+        let mut mqtt_client = MQTTClient::new(Box::new(RecordingBroker { published: published.clone() }), balance_control, std::env::temp_dir(), None);
+        subscribe_topics(&mut mqtt_client);
+        let (notification_sender, notifications) = crossbeam_channel::unbounded();
+        let (stop_sender, stop_receiver) = crossbeam_channel::bounded(1);
+        let dispatch = thread::spawn(move || run(mqtt_client, notifications, balance_events, stop_receiver));
+
+        let scenario = Scenario::new(10.0)
+            .at(0.5, Step::Configure("balance/max_degree", 30.0))
+            .at(1.0, Step::Bump(35.0))
+            .at(2.5, Step::Stop)
+            .at(3.0, Step::Start)
+            // Long enough for the watchdog, after holding the last reading and waiting for the gyro
+            .at(4.0, Step::SensorDropout(1.0))
+            .at(5.5, Step::Start)
+            .at(6.5, Step::EStop)
+            // Latched until reset
+            .at(7.0, Step::Start)
+            .at(7.5, Step::Reset)
+            .at(8.0, Step::Start)
+            .at(9.0, Step::Bump(35.0));
+        harness.run(&scenario, &mut MqttDriver { notifications: notification_sender });
+        let shutdown = harness.shut_down(|| {
+            stop_sender.send(true).unwrap();
+            dispatch.join().unwrap().stop()
+        });
+        assert!(shutdown < SHUTDOWN_TIMEOUT, "Shutting down took {:?}", shutdown);
+
+        harness.assert_motors_stopped_unless_driven();
+        let transitions = harness.transitions();
+        assert_eq!(transitions, vec![
+            State::Balancing, State::WaitingForReady, State::Balancing,
+            State::Stopped, State::Balancing,
+            State::WaitingForReady, State::Stopped, State::Balancing,
+            State::EStop, State::Stopped, State::Balancing,
+            State::WaitingForReady, State::Balancing,
+        ]);
+        // A record every loop at 200Hz, less the loops waiting out the gyro during the dropout
+        let records = harness.records().len();
+        assert!((900..=2050).contains(&records), "{} records", records);
 
-            println!("Finishing...");
-            mqtt_client.stop();
-            println!("Done.");
+        let states: Vec<String> = published.lock().unwrap().iter()
+            .filter(|(topic, _)| topic == BALANCING_STATE_TOPIC)
+            .map(|(_, payload)| String::from_utf8_lossy(payload).into_owned())
+            .collect();
+        let expected: Vec<String> = transitions.iter().map(|state| format!("{{ \"state\" : \"{}\"", state.name())).collect();
+        assert_eq!(states.len(), expected.len(), "{:?}", states);
+        for (state, expected) in states.iter().zip(expected.iter()) {
+            assert!(state.starts_with(expected), "{} is not {}", state, expected);
         }
-        _ => println!("Failed to connect to mosquito broker on this host")
     }
 }
@@ -9,9 +9,16 @@
 //    Daniel Sendula - initial API and implementation
 //
 
-use rppal::gpio::{Gpio, OutputPin};
+use std::io;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
 
-use dma_gpio::pi::{BoardBuilder, Board};
+use dma_gpio::pi::{BoardBuilder, Board, BoardInfo, DmaStatus, Pull};
+use rppal::gpio::Gpio;
+
+use crate::safe_state::SafeState;
+use crate::slew::SlewLimiter;
 
 const LEFT_PWM_PIN_NO: u8 = 20;
 const LEFT_IN1_PIN_NO: u8 = 6;
@@ -19,122 +26,484 @@ const LEFT_IN2_PIN_NO: u8 = 5;
 const RIGHT_PWM_PIN_NO: u8 = 26;
 const RIGHT_IN1_PIN_NO: u8 = 13;
 const RIGHT_IN2_PIN_NO: u8 = 19;
+// Driver standby/enable pin, active high; the driver on the rover has it tied high
+const STANDBY_PIN_NO: Option<u8> = None;
 
+const NON_FINITE_LOG_INTERVAL: Duration = Duration::from_secs(1);
+// How often poll_enable reads the enable pin between speed changes
+const ENABLE_CHECK_INTERVAL: Duration = Duration::from_millis(20);
 
 
-fn sanitise_speed(speed: f32) -> (f32, i32) {
-    let mut speed = speed;
+// Input from an emergency stop or arming switch; the motors only run while it is active.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct EnableGate {
+    pub pin: u8,
+    pub active_high: bool,
+}
 
-    if speed > 0.0001 {
-        if speed > 1.0 {
-            speed = 1.0;
-        } else if speed < 0.01 {
-            speed = 0.0;
-        }
-        (speed, 1)
-    } else if speed < -0.00001 {
-        speed *= -1.0;
-        if speed > 1.0 {
-            speed = 1.0;
-        } else if speed < 0.01 {
-            speed = 0.0;
+impl EnableGate {
+    // <pin> for a switch that pulls the pin high when the motors may run, <pin>:low for one
+    // that pulls it low.
+    pub fn parse(s: &str) -> Result<EnableGate, String> {
+        let (pin, level) = match s.split_once(':') {
+            Some((pin, level)) => (pin, level),
+            None => (s, "high")
+        };
+        let active_high = match level {
+            "high" => true,
+            "low" => false,
+            _ => return Err(format!("Enable pin level must be high or low, not {}", level))
+        };
+        let pin: u8 = pin.parse().map_err(|_| format!("Invalid enable pin {}", pin))?;
+        Ok(EnableGate { pin, active_high })
+    }
+
+    // A level that cannot be read counts as inactive.
+    pub fn is_active(&self, level: Option<bool>) -> bool {
+        level == Some(self.active_high)
+    }
+}
+
+
+// H-bridges wired up; the other side's pins are left alone.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Sides {
+    Both,
+    Left,
+    Right,
+}
+
+impl Sides {
+    pub fn parse(s: &str) -> Result<Sides, String> {
+        match s {
+            "both" => Ok(Sides::Both),
+            "left" => Ok(Sides::Left),
+            "right" => Ok(Sides::Right),
+            _ => Err(format!("Side must be left, right or both, not {}", s))
         }
-        (speed, -1)
+    }
+
+    pub fn left(&self) -> bool {
+        *self != Sides::Right
+    }
+
+    pub fn right(&self) -> bool {
+        *self != Sides::Left
+    }
+}
+
+
+fn sanitise_speed(speed: f32) -> f32 {
+    if speed > 1.0 {
+        1.0
+    } else if speed < -1.0 {
+        -1.0
+    } else if speed.abs() < 0.01 {
+        0.0
     } else {
-        (0.0, 0)
+        speed
     }
 }
 
+// Makes up for a motor that needs more duty than the other to turn: speeds below deadband stop it,
+// the rest are scaled by trim and lifted above min_duty, the least it starts turning at.
+pub fn compensate(speed: f32, trim: f32, min_duty: f32, deadband: f32) -> f32 {
+    if speed == 0.0 || speed.abs() < deadband {
+        return 0.0;
+    }
+    let duty = min_duty + (1.0 - min_duty) * (speed.abs() * trim).min(1.0);
+    duty.min(1.0).copysign(speed)
+}
+
+// Both inputs high brakes the motor whatever the PWM pin does, so this does not need the Board
+// (which may be mid update on another thread, or already gone).
+fn register_brake(safe_state: &SafeState) {
+    let gpio = match Gpio::new() {
+        Ok(gpio) => gpio,
+        Err(e) => {
+            error!("Cannot open GPIO for the motor safe state: {:?}", e);
+            return;
+        }
+    };
+    safe_state.register("motors", move || {
+        for pin in [LEFT_IN1_PIN_NO, LEFT_IN2_PIN_NO, RIGHT_IN1_PIN_NO, RIGHT_IN2_PIN_NO].iter() {
+            match gpio.get(*pin) {
+                Ok(pin) => {
+                    let mut pin = pin.into_output();
+                    pin.set_reset_on_drop(false);
+                    pin.set_high();
+                },
+                Err(e) => error!("Cannot brake on pin {}: {:?}", pin, e)
+            }
+        }
+        if let Some(standby) = STANDBY_PIN_NO {
+            match gpio.get(standby) {
+                Ok(pin) => {
+                    let mut pin = pin.into_output();
+                    pin.set_reset_on_drop(false);
+                    pin.set_low();
+                },
+                Err(e) => error!("Cannot deassert standby pin {}: {:?}", standby, e)
+            }
+        }
+    });
+}
+
 
 pub struct Motors {
-    left_in1_pin: OutputPin,
-    left_in2_pin: OutputPin,
-    left_last_direction: i32,
-    right_in1_pin: OutputPin,
-    right_in2_pin: OutputPin,
-    right_last_direction: i32,
-    board: Board
+    board: Board,
+    sides: Sides,
+    last_non_finite_log: Option<Instant>,
+    safe_state: SafeState,
+    left: f32,
+    right: f32,
+    left_trim: f32,
+    right_trim: f32,
+    min_duty: f32,
+    deadband: f32,
+    enable_gate: Option<EnableGate>,
+    force_enable: bool,
+    enabled: bool,
+    estop: Arc<AtomicBool>,
+    last_enable_check: Instant,
+    // Speeds as set_speeds_ramped ramps them; set_speeds jumps them
+    left_slew: SlewLimiter,
+    right_slew: SlewLimiter,
+    started: Instant,
 }
 
 impl Motors {
-    pub fn new() -> Motors {
+    // Both motors on a board from board_builder, or from the same builder set to dry run.
+    pub fn new(board_builder: BoardBuilder, safe_state: &SafeState) -> Motors {
+        Motors::open_with(board_builder, safe_state, Sides::Both).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    // PWM timing the motors run at.
+    pub fn board_builder() -> BoardBuilder {
+        BoardBuilder::new()
+            .divide_pwm(1000)
+            .set_cycle_time(200)
+            .set_sample_delay(2)
+            .auto_idle()
+    }
+
+    // As new, but only for the given sides, and pins that cannot be set up are an error rather
+    // than a panic. Speeds for a side that is not there are ignored.
+    pub fn open(safe_state: &SafeState, sides: Sides) -> Result<Motors, String> {
+        Motors::open_with(Motors::board_builder(), safe_state, sides)
+    }
+
+    // As open, on a board from the given builder. A dry-run board has no pins to brake with, so
+    // nothing is registered with the safe state for it.
+    pub fn open_with(board_builder: BoardBuilder, safe_state: &SafeState, sides: Sides) -> Result<Motors, String> {
+        let mut pwm_pins = vec![];
+        if sides.left() {
+            pwm_pins.push(LEFT_PWM_PIN_NO);
+        }
+        if sides.right() {
+            pwm_pins.push(RIGHT_PWM_PIN_NO);
+        }
+        let board = board_builder
+            .build_with_pins(pwm_pins.clone()).map_err(|e| format!("Cannot get setup PWM for pins {:?}: {}", pwm_pins, e))?;
 
         let mut motors = Motors {
-            left_in1_pin: Gpio::new().unwrap_or_else(|_| panic!("Cannot get left in1 pin {}", LEFT_IN1_PIN_NO))
-                .get(LEFT_IN1_PIN_NO).unwrap_or_else(|_| panic!("Cannot get left in2 pin {}", LEFT_IN1_PIN_NO))
-                .into_output(),
-            left_in2_pin: Gpio::new().unwrap_or_else(|_| panic!("Cannot get left in2 pin {}", LEFT_IN2_PIN_NO))
-                .get(LEFT_IN2_PIN_NO).unwrap_or_else(|_| panic!("Cannot get left in2 pin {}", LEFT_IN2_PIN_NO))
-                .into_output(),
-            left_last_direction: 0,
-            right_in1_pin: Gpio::new().unwrap_or_else(|_| panic!("Cannot get right in1 pin {}", RIGHT_IN1_PIN_NO))
-                .get(RIGHT_IN1_PIN_NO).unwrap_or_else(|_| panic!("Cannot get right in1 pin {}", RIGHT_IN1_PIN_NO))
-                .into_output(),
-            right_in2_pin: Gpio::new().unwrap_or_else(|_| panic!("Cannot get right in2 pin {}", RIGHT_IN2_PIN_NO))
-                .get(RIGHT_IN2_PIN_NO).unwrap_or_else(|_| panic!("Cannot get right in2 pin {}", RIGHT_IN2_PIN_NO))
-                .into_output(),
-            right_last_direction: 0,
-            board: BoardBuilder::new()
-                .divide_pwm(1250)
-                .set_cycle_time(200)
-                .set_sample_delay(2)
-                .build_with_pins(vec![LEFT_PWM_PIN_NO, RIGHT_PWM_PIN_NO]).unwrap_or_else(|_| panic!("Cannot get setup PWM for pins {} and {}", LEFT_PWM_PIN_NO, RIGHT_PWM_PIN_NO))
+            board,
+            sides,
+            last_non_finite_log: None,
+            safe_state: safe_state.clone(),
+            left: 0.0,
+            right: 0.0,
+            left_trim: 1.0,
+            right_trim: 1.0,
+            min_duty: 0.0,
+            deadband: 0.0,
+            enable_gate: None,
+            force_enable: false,
+            enabled: true,
+            estop: Arc::new(AtomicBool::new(false)),
+            last_enable_check: Instant::now(),
+            left_slew: SlewLimiter::new(0.0),
+            right_slew: SlewLimiter::new(0.0),
+            started: Instant::now(),
         };
 
+        // Forward is IN1 low, IN2 high
+        for (_, pwm_pin, in_pin, forward_high) in motors.direction_pins() {
+            motors.board.bind_direction(pwm_pin, in_pin, forward_high).map_err(|e| format!("Cannot get direction pin {}: {}", in_pin, e))?;
+        }
+
+        let adjusted: Vec<String> = motors.board.adjusted_settings().iter()
+            .map(|setting| format!("{} {} -> {}", setting.name, setting.requested, setting.effective))
+            .collect();
+        if !adjusted.is_empty() {
+            warn!("Motor PWM settings adjusted: {}", adjusted.join(", "));
+        }
+        // A clock that did not take its divisor runs the motors at some other PWM frequency
+        let info = motors.board.info();
+        if info.clock_divisor != info.pacing_divisor {
+            return Err(format!("{} clock divider reads back {}, not the {} programmed", info.pacing, info.clock_divisor, info.pacing_divisor));
+        }
+
         motors.stop_all();
+        if !motors.board.is_dry_run() {
+            register_brake(safe_state);
+        }
 
-        motors
+        Ok(motors)
     }
 
-    pub fn stop_all(&mut self) {
-        self.left_speed(0.0);
-        self.right_speed(0.0);
-
-//        self.left_in1_pin.set_high();
-//        self.left_in2_pin.set_high();
-//        self.right_in1_pin.set_high();
-//        self.right_in2_pin.set_high();
-//        self.board.set_all_pwm(0.0).unwrap();
-    }
-
-
-    pub fn left_speed(&mut self, speed: f32) {
-        let (speed, direction) = sanitise_speed(speed);
-
-        if self.left_last_direction != direction {
-            self.left_last_direction = direction;
-            if direction == 1 {
-                self.left_in1_pin.set_low();
-                self.left_in2_pin.set_high();
-            } else if direction == -1 {
-                self.left_in1_pin.set_high();
-                self.left_in2_pin.set_low();
-            } else {
-                self.left_in1_pin.set_high();
-                self.left_in2_pin.set_high();
+    // Name, PWM pin, pin and whether it is high for forward, of the direction pins of the sides there.
+    fn direction_pins(&self) -> Vec<(&'static str, u8, u8, bool)> {
+        let mut pins = vec![];
+        if self.sides.left() {
+            pins.push(("left_in1", LEFT_PWM_PIN_NO, LEFT_IN1_PIN_NO, false));
+            pins.push(("left_in2", LEFT_PWM_PIN_NO, LEFT_IN2_PIN_NO, true));
+        }
+        if self.sides.right() {
+            pins.push(("right_in1", RIGHT_PWM_PIN_NO, RIGHT_IN1_PIN_NO, false));
+            pins.push(("right_in2", RIGHT_PWM_PIN_NO, RIGHT_IN2_PIN_NO, true));
+        }
+        pins
+    }
+
+    // Motors only run while pin is at the active level; it is pulled the other way, so a broken
+    // wire stops them too.
+    pub fn with_enable_pin(mut self, pin: u8, active_high: bool) -> Motors {
+        let pull = if active_high { Pull::Down } else { Pull::Up };
+        if let Err(e) = self.board.set_mode_input(pin).and_then(|_| self.board.set_pull(pin, pull)) {
+            error!("Cannot set up enable pin {}, motors stay disabled: {:?}", pin, e);
+        }
+        self.enable_gate = Some(EnableGate { pin, active_high });
+        self.stop_all();
+        self
+    }
+
+    // Motors stay stopped while estop is set, whichever thread sets it and whenever; force_enable
+    // does not override it.
+    pub fn with_estop(mut self, estop: Arc<AtomicBool>) -> Motors {
+        self.estop = estop;
+        self
+    }
+
+    // Bench testing only: runs the motors whatever the enable pin says. Off by default.
+    pub fn force_enable(&mut self, force: bool) {
+        if force {
+            warn!("Motor enable pin overridden");
+        }
+        self.force_enable = force;
+    }
+
+    // Whether the motors may run, as of the last speed change or poll_enable.
+    pub fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    fn read_enable(&mut self) -> bool {
+        self.last_enable_check = Instant::now();
+        self.enabled = match self.enable_gate {
+            Some(gate) if !self.force_enable => gate.is_active(self.board.read_pin(gate.pin).ok()),
+            _ => true
+        };
+        self.enabled
+    }
+
+    // Reads the enable pin every ENABLE_CHECK_INTERVAL and stops the motors when it goes inactive,
+    // for when no speed changes come in. Returns enabled().
+    pub fn poll_enable(&mut self) -> bool {
+        if self.last_enable_check.elapsed() >= ENABLE_CHECK_INTERVAL {
+            let was_enabled = self.enabled;
+            if !self.read_enable() && was_enabled {
+                warn!("Motor enable pin went inactive, stopping motors");
+                self.stop_all();
             }
         }
+        self.enabled
+    }
 
-        self.board.set_pwm(LEFT_PWM_PIN_NO, speed).unwrap_or_else(|_| panic!("Cannot get set PWM for pin {}", LEFT_PWM_PIN_NO));
+    // Lets auto-idle pause the PWM DMA once the motors have been stopped long enough, for when no
+    // speed changes come in.
+    pub fn poll_idle(&mut self) {
+        self.board.poll_idle();
     }
 
-    pub fn right_speed(&mut self, speed: f32) {
-        let (speed, direction) = sanitise_speed(speed);
+    pub fn board_info(&self) -> BoardInfo {
+        self.board.info()
+    }
+
+    // PWM timing as BoardBuilder's divide_pwm, set_cycle_time and set_sample_delay take it; speeds
+    // carry over. Checked against the clock as open checks it. Only pwm-sweep changes it.
+    pub fn reconfigure_timing(&mut self, pwm_divisor: usize, cycle_time: usize, sample_delay: usize) -> Result<BoardInfo, String> {
+        self.board.reconfigure_timing(pwm_divisor, cycle_time, sample_delay)
+            .map_err(|e| format!("Cannot set divisor {}, cycle time {}, sample delay {}: {}", pwm_divisor, cycle_time, sample_delay, e))?;
+        let info = self.board.info();
+        if info.clock_divisor != info.pacing_divisor {
+            return Err(format!("{} clock divider reads back {}, not the {} programmed", info.pacing, info.clock_divisor, info.pacing_divisor));
+        }
+        Ok(info)
+    }
 
-        if self.right_last_direction != direction {
-            self.right_last_direction = direction;
-            if direction == 1 {
-                self.right_in1_pin.set_low();
-                self.right_in2_pin.set_high();
-            } else if direction == -1 {
-                self.right_in1_pin.set_high();
-                self.right_in2_pin.set_low();
-            } else {
-                self.right_in1_pin.set_high();
-                self.right_in2_pin.set_high();
+    // Status of the PWM DMA when it reports an error or has stopped while it should be running;
+    // auto-idle pausing it is not a fault.
+    pub fn dma_fault(&self) -> Option<DmaStatus> {
+        let status = self.board.dma_status();
+        let stopped = !status.active && !self.board.is_idle() && !self.board.is_paused();
+        if status.has_error() || stopped { Some(status) } else { None }
+    }
+
+    pub fn stop_all(&mut self) {
+        self.set_speeds(0.0, 0.0);
+    }
+
+
+    // Non-finite speeds stop the motor instead of reaching the PWM sample maths.
+    fn finite_speed(&mut self, motor: &str, speed: f32) -> f32 {
+        if speed.is_finite() {
+            speed
+        } else {
+            if self.last_non_finite_log.is_none_or(|last| last.elapsed() >= NON_FINITE_LOG_INTERVAL) {
+                error!("Got {} speed for {} motor, stopping it", speed, motor);
+                self.last_non_finite_log = Some(Instant::now());
             }
+            0.0
+        }
+    }
+
+    // Sets and clears each direction pin with both motors stopped, so the wheels do not move, and
+    // returns how many of the two levels read back right for each; pins are left as they were.
+    pub fn check_direction_pins(&mut self) -> Vec<(&'static str, Result<f64, String>)> {
+        self.stop_all();
+        self.direction_pins().iter()
+            .map(|(name, _, pin, _)| (*name, self.check_direction_pin(*pin).map_err(|e| format!("{:?}", e))))
+            .collect()
+    }
+
+    fn check_direction_pin(&mut self, pin: u8) -> Result<f64, io::Error> {
+        let level = self.board.read_pin(pin)?;
+        let mut right_levels = 0;
+        for high in [true, false].iter() {
+            self.board.set_output(pin, *high)?;
+            if self.board.read_pin(pin)? == *high {
+                right_levels += 1;
+            }
+        }
+        self.board.set_output(pin, level)?;
+        Ok(right_levels as f64)
+    }
+
+    // Taken by the next set_speeds; trims of 1.0 and a min_duty of 0.0 leave speeds as they are.
+    pub fn set_calibration(&mut self, left_trim: f32, right_trim: f32, min_duty: f32) {
+        self.left_trim = left_trim;
+        self.right_trim = right_trim;
+        self.min_duty = min_duty;
+    }
+
+    // Speeds below this (after sanitising) stop both motors instead of getting min_duty.
+    pub fn set_deadband(&mut self, deadband: f32) {
+        self.deadband = deadband;
+    }
+
+    // Full scale per second set_speeds_ramped may change speeds by; 0 for no limit.
+    pub fn set_max_accel(&mut self, per_second: f32) {
+        self.left_slew.max_rate = per_second as f64;
+        self.right_slew.max_rate = per_second as f64;
+    }
+
+    // Duties as last sent to the board, after sanitising and compensation.
+    pub fn applied_speeds(&self) -> (f32, f32) {
+        (self.left, self.right)
+    }
+
+    // Both wheels change in the same PWM cycle. Takes effect at once, for corrections that cannot
+    // wait, and cancels any ramp set_speeds_ramped was on.
+    pub fn set_speeds(&mut self, left: f32, right: f32) {
+        let enabled = self.read_enable();
+        let (left, right) = if self.safe_state.is_engaged() || self.estop.load(Ordering::SeqCst) || !enabled {
+            (0.0, 0.0)
+        } else {
+            (self.finite_speed("left", left), self.finite_speed("right", right))
+        };
+        let (left, right) = (sanitise_speed(left), sanitise_speed(right));
+        let time = self.started.elapsed().as_secs_f64();
+        self.left_slew.reset(time, left as f64);
+        self.right_slew.reset(time, right as f64);
+        self.left = compensate(left, self.left_trim, self.min_duty, self.deadband);
+        self.right = compensate(right, self.right_trim, self.min_duty, self.deadband);
+        let mut updates = vec![];
+        if self.sides.left() {
+            updates.push((LEFT_PWM_PIN_NO, self.left));
         }
+        if self.sides.right() {
+            updates.push((RIGHT_PWM_PIN_NO, self.right));
+        }
+        self.board.set_multi_signed_pwm(&updates)
+            .unwrap_or_else(|_| panic!("Cannot get set PWM for pins {} and {}", LEFT_PWM_PIN_NO, RIGHT_PWM_PIN_NO));
+    }
 
-        self.board.set_pwm(RIGHT_PWM_PIN_NO, speed).unwrap_or_else(|_| panic!("Cannot get set PWM for pin {}", LEFT_PWM_PIN_NO));
+    // As set_speeds, but ramping towards the speeds by at most set_max_accel per second since the
+    // last call; to be called every loop until they are reached.
+    pub fn set_speeds_ramped(&mut self, left: f32, right: f32) {
+        let time = self.started.elapsed().as_secs_f64();
+        let left = self.left_slew.update(time, sanitise_speed(left) as f64) as f32;
+        let right = self.right_slew.update(time, sanitise_speed(right) as f64) as f32;
+        self.set_speeds(left, right);
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn speeds_are_trimmed_lifted_to_min_duty_and_cut_below_deadband() {
+        let cases = [
+            ((0.5, 1.0, 0.0, 0.0), 0.5),
+            ((0.0, 1.2, 0.2, 0.05), 0.0),
+            ((0.03, 1.0, 0.2, 0.05), 0.0),
+            ((-0.5, 1.0, 0.2, 0.0), -0.6),
+            ((0.5, 1.2, 0.0, 0.0), 0.6),
+            ((1.0, 1.5, 0.2, 0.0), 1.0),
+        ];
+        for ((speed, trim, min_duty, deadband), expected) in cases.iter() {
+            let duty = compensate(*speed, *trim, *min_duty, *deadband);
+            assert!((duty - expected).abs() < 1e-6, "speed {} with trim {}, min duty {}, deadband {} gave {}", speed, trim, min_duty, deadband, duty);
+        }
+    }
+
+    #[test]
+    fn enable_pin_parses_with_its_active_level() {
+        let cases = [
+            ("17", Some((17, true))),
+            ("17:high", Some((17, true))),
+            ("4:low", Some((4, false))),
+            ("4:open", None),
+            ("x:low", None),
+            ("", None),
+        ];
+        for (option, expected) in cases.iter() {
+            assert_eq!(EnableGate::parse(option).ok().map(|gate| (gate.pin, gate.active_high)), *expected, "{:?}", option);
+        }
+    }
+
+    #[test]
+    fn unreadable_enable_pin_counts_as_inactive() {
+        let gate = EnableGate { pin: 4, active_high: false };
+        assert!(gate.is_active(Some(false)));
+        assert!(!gate.is_active(Some(true)));
+        assert!(!gate.is_active(None));
+    }
+
+    #[test]
+    fn each_side_only_drives_its_own_bridge() {
+        let cases = [
+            ("both", Some((true, true))),
+            ("left", Some((true, false))),
+            ("right", Some((false, true))),
+            ("Left", None),
+            ("", None),
+        ];
+        for (option, expected) in cases.iter() {
+            assert_eq!(Sides::parse(option).ok().map(|sides| (sides.left(), sides.right())), *expected, "{:?}", option);
+        }
     }
 }
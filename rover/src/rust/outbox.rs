@@ -0,0 +1,206 @@
+//
+// Copyright (C) 2020 Abstract Horizon
+// All rights reserved. This program and the accompanying materials
+// are made available under the terms of the Apache License v2.0
+// which accompanies this distribution, and is available at
+// https://www.apache.org/licenses/LICENSE-2.0
+//
+//  Contributors:
+//    Daniel Sendula - initial API and implementation
+//
+
+// Outgoing MQTT messages wait here before they are handed to the client. rumqtt blocks the
+// caller once its own request queue is full, so on a weak link we decide what goes out first:
+//
+//   Critical - warnings and state changes; always first, never dropped
+//   Normal   - config and storage writes; only the latest payload per topic is kept while queued
+//   Bulk     - snapshots and wear; dropped while disconnected or when the queue is too deep
+//
+// Normal and Bulk messages are also limited to a send budget per second.
+
+use std::collections::VecDeque;
+
+use rumqtt::QoS;
+
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Priority {
+    Critical,
+    Normal,
+    Bulk,
+}
+
+#[derive(Clone, Debug)]
+pub struct OutgoingMessage {
+    pub topic: String,
+    pub qos: QoS,
+    pub retain: bool,
+    pub payload: Vec<u8>,
+}
+
+#[derive(Clone, Copy, Default, Debug)]
+pub struct OutboxStats {
+    pub queued: usize,
+    pub sent: u64,
+    pub dropped: u64,
+    pub coalesced: u64,
+    pub max_queued: usize,
+}
+
+impl OutboxStats {
+    pub fn to_json(&self) -> String {
+        format!("{{ \"queued\" : {}, \"sent\" : {}, \"dropped\" : {}, \"coalesced\" : {}, \"max_queued\" : {} }}",
+            self.queued, self.sent, self.dropped, self.coalesced, self.max_queued)
+    }
+}
+
+pub struct Outbox {
+    // Bulk messages are dropped once this many messages are queued
+    pub bulk_limit: usize,
+    // Normal and Bulk messages per second
+    pub budget: f64,
+    connected: bool,
+    tokens: f64,
+    last_refill: Option<f64>,
+    critical: VecDeque<OutgoingMessage>,
+    normal: VecDeque<OutgoingMessage>,
+    bulk: VecDeque<OutgoingMessage>,
+    stats: OutboxStats,
+}
+
+impl Outbox {
+    pub fn new(bulk_limit: usize, budget: f64) -> Outbox {
+        Outbox {
+            bulk_limit,
+            budget,
+            connected: true,
+            tokens: budget,
+            last_refill: None,
+            critical: VecDeque::new(),
+            normal: VecDeque::new(),
+            bulk: VecDeque::new(),
+            stats: OutboxStats::default(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.critical.len() + self.normal.len() + self.bulk.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.critical.is_empty() && self.normal.is_empty() && self.bulk.is_empty()
+    }
+
+    pub fn push(&mut self, priority: Priority, message: OutgoingMessage) {
+        match priority {
+            Priority::Critical => self.critical.push_back(message),
+            Priority::Normal => {
+                match self.normal.iter_mut().find(|queued| queued.topic == message.topic) {
+                    Some(queued) => {
+                        *queued = message;
+                        self.stats.coalesced += 1;
+                    },
+                    None => self.normal.push_back(message)
+                }
+            },
+            Priority::Bulk => {
+                if !self.connected || self.len() >= self.bulk_limit {
+                    self.stats.dropped += 1;
+                } else {
+                    self.bulk.push_back(message);
+                }
+            }
+        }
+        self.stats.max_queued = self.stats.max_queued.max(self.len());
+    }
+
+    // Queued Bulk messages are dropped on disconnection; the rest wait for the broker to come back.
+    pub fn set_connected(&mut self, connected: bool) {
+        self.connected = connected;
+        if !connected {
+            self.stats.dropped += self.bulk.len() as u64;
+            self.bulk.clear();
+        }
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.connected
+    }
+
+    // Next message to hand to the client, if any may go out now.
+    pub fn pop(&mut self, now: f64) -> Option<OutgoingMessage> {
+        if !self.connected {
+            return None;
+        }
+        if let Some(message) = self.critical.pop_front() {
+            self.stats.sent += 1;
+            return Some(message);
+        }
+
+        if let Some(last_refill) = self.last_refill {
+            self.tokens = (self.tokens + (now - last_refill).max(0.0) * self.budget).min(self.budget);
+        }
+        self.last_refill = Some(now);
+        if self.tokens < 1.0 {
+            return None;
+        }
+
+        let message = match self.normal.pop_front() {
+            Some(message) => Some(message),
+            None => self.bulk.pop_front()
+        };
+        if message.is_some() {
+            self.tokens -= 1.0;
+            self.stats.sent += 1;
+        }
+        message
+    }
+
+    pub fn stats(&self) -> OutboxStats {
+        let mut stats = self.stats;
+        stats.queued = self.len();
+        stats
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(topic: &str, payload: &str) -> OutgoingMessage {
+        OutgoingMessage { topic: topic.to_string(), qos: QoS::AtMostOnce, retain: false, payload: payload.as_bytes().to_vec() }
+    }
+
+    fn drain(outbox: &mut Outbox, now: f64) -> Vec<String> {
+        let mut sent = vec![];
+        while let Some(message) = outbox.pop(now) {
+            sent.push(format!("{}={}", message.topic, String::from_utf8_lossy(&message.payload)));
+        }
+        sent
+    }
+
+    // Broker stalls with messages of every class queued, then comes back.
+    #[test]
+    fn stalled_broker_gets_critical_first_then_the_rest_within_budget() {
+        let mut outbox = Outbox::new(4, 2.0);
+        outbox.push(Priority::Bulk, message("snapshot", "1"));
+        outbox.push(Priority::Normal, message("storage/write/a", "1"));
+        outbox.set_connected(false);
+        outbox.push(Priority::Bulk, message("snapshot", "2"));
+        outbox.push(Priority::Normal, message("storage/write/b", "1"));
+        outbox.push(Priority::Normal, message("storage/write/a", "2"));
+        outbox.push(Priority::Critical, message("warnings", "fell over"));
+        assert!(outbox.pop(0.0).is_none());
+
+        outbox.set_connected(true);
+        outbox.push(Priority::Bulk, message("snapshot", "3"));
+        outbox.push(Priority::Bulk, message("snapshot", "4"));
+        // Budget of 2 a second: critical plus two others now, the rest a second later
+        assert_eq!(drain(&mut outbox, 1.0), vec!["warnings=fell over", "storage/write/a=2", "storage/write/b=1"]);
+        assert_eq!(drain(&mut outbox, 2.0), vec!["snapshot=3"]);
+
+        let stats = outbox.stats();
+        assert_eq!((stats.dropped, stats.coalesced, stats.sent, stats.queued), (3, 1, 4, 0));
+    }
+}
@@ -0,0 +1,246 @@
+//
+// Copyright (C) 2020 Abstract Horizon
+// All rights reserved. This program and the accompanying materials
+// are made available under the terms of the Apache License v2.0
+// which accompanies this distribution, and is available at
+// https://www.apache.org/licenses/LICENSE-2.0
+//
+//  Contributors:
+//    Daniel Sendula - initial API and implementation
+//
+
+// Mutex with priority inheritance (PTHREAD_PRIO_INHERIT): while a higher priority thread waits
+// for it, the holder runs at that thread's priority, so a normal thread holding a lock the
+// balance thread wants cannot be held up by the threads in between. Used like std's Mutex,
+// poisoning included; where priority inheritance is not available it is a std Mutex.
+//
+// Locks the balance thread takes, all of them PiMutex:
+//
+//   SharedBus state (i2c_bus)  - every sensor and encoder transaction
+//   BalanceStatus (balance)    - once per loop, to update the snapshot MQTT publishes
+//
+// The balance thread must not take any other lock while balancing. The Board is owned by
+// Motors on the balance thread and is not shared, so it needs none.
+
+use std::cell::UnsafeCell;
+use std::marker::PhantomData;
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{LockResult, Mutex, MutexGuard, PoisonError};
+use std::thread;
+
+
+enum RawLock {
+    // Boxed, as a pthread mutex must not move once initialised
+    #[cfg(target_os = "linux")]
+    Inheriting(Box<UnsafeCell<libc::pthread_mutex_t>>),
+    Plain(Mutex<()>),
+}
+
+pub struct PiMutex<T> {
+    lock: RawLock,
+    poisoned: AtomicBool,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Send for PiMutex<T> {}
+unsafe impl<T: Send> Sync for PiMutex<T> {}
+
+#[cfg(target_os = "linux")]
+fn inheriting_lock() -> Option<RawLock> {
+    unsafe {
+        let mut attr: libc::pthread_mutexattr_t = std::mem::zeroed();
+        if libc::pthread_mutexattr_init(&mut attr) != 0 {
+            return None;
+        }
+        let mutex = Box::new(UnsafeCell::new(libc::PTHREAD_MUTEX_INITIALIZER));
+        let ok = libc::pthread_mutexattr_setprotocol(&mut attr, libc::PTHREAD_PRIO_INHERIT) == 0
+            && libc::pthread_mutex_init(mutex.get(), &attr) == 0;
+        libc::pthread_mutexattr_destroy(&mut attr);
+        if ok { Some(RawLock::Inheriting(mutex)) } else { None }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn inheriting_lock() -> Option<RawLock> {
+    None
+}
+
+impl<T> PiMutex<T> {
+    pub fn new(data: T) -> PiMutex<T> {
+        PiMutex {
+            lock: inheriting_lock().unwrap_or_else(|| RawLock::Plain(Mutex::new(()))),
+            poisoned: AtomicBool::new(false),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    // Always a std Mutex underneath; to compare against.
+    pub fn without_inheritance(data: T) -> PiMutex<T> {
+        PiMutex { lock: RawLock::Plain(Mutex::new(())), poisoned: AtomicBool::new(false), data: UnsafeCell::new(data) }
+    }
+
+    pub fn is_inheriting(&self) -> bool {
+        match self.lock {
+            #[cfg(target_os = "linux")]
+            RawLock::Inheriting(_) => true,
+            RawLock::Plain(_) => false,
+        }
+    }
+
+    pub fn lock(&self) -> LockResult<PiMutexGuard<'_, T>> {
+        let plain_guard = match &self.lock {
+            #[cfg(target_os = "linux")]
+            RawLock::Inheriting(mutex) => {
+                let result = unsafe { libc::pthread_mutex_lock(mutex.get()) };
+                assert_eq!(result, 0, "pthread_mutex_lock failed");
+                None
+            },
+            // Poisoning is tracked below, for both kinds
+            RawLock::Plain(mutex) => Some(mutex.lock().unwrap_or_else(|e| e.into_inner())),
+        };
+        let guard = PiMutexGuard { mutex: self, _plain_guard: plain_guard, _marker: PhantomData };
+        if self.poisoned.load(Ordering::SeqCst) {
+            Err(PoisonError::new(guard))
+        } else {
+            Ok(guard)
+        }
+    }
+}
+
+impl<T> Drop for PiMutex<T> {
+    fn drop(&mut self) {
+        #[cfg(target_os = "linux")]
+        {
+            if let RawLock::Inheriting(mutex) = &self.lock {
+                unsafe { libc::pthread_mutex_destroy(mutex.get()) };
+            }
+        }
+    }
+}
+
+/// Shared between threads only where `T` itself may be, as with std's `MutexGuard`:
+///
+/// ```compile_fail
+/// use balancing_rover::pi_mutex::PiMutex;
+/// use std::cell::Cell;
+///
+/// fn shared<T: Sync>(_: &T) {}
+///
+/// let mutex = PiMutex::new(Cell::new(0));
+/// shared(&mutex.lock().unwrap());
+/// ```
+pub struct PiMutexGuard<'a, T> {
+    mutex: &'a PiMutex<T>,
+    _plain_guard: Option<MutexGuard<'a, ()>>,
+    // Without it the guard would be Sync whenever T is Send
+    _marker: PhantomData<&'a mut T>,
+}
+
+impl<T> Deref for PiMutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.data.get() }
+    }
+}
+
+impl<T> DerefMut for PiMutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.data.get() }
+    }
+}
+
+impl<T> Drop for PiMutexGuard<'_, T> {
+    fn drop(&mut self) {
+        if thread::panicking() {
+            self.mutex.poisoned.store(true, Ordering::SeqCst);
+        }
+        #[cfg(target_os = "linux")]
+        {
+            if let RawLock::Inheriting(mutex) = &self.mutex.lock {
+                unsafe { libc::pthread_mutex_unlock(mutex.get()) };
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::sync::atomic::AtomicBool;
+    use std::time::{Duration, Instant};
+
+    // A niced thread keeps taking the lock and working with it held, while a thread standing in for
+    // the balance loop (SCHED_FIFO when allowed) takes it every millisecond; returns the longest
+    // time the latter waited.
+    fn contended_lock_wait(lock: Arc<PiMutex<u64>>) -> Duration {
+        const ROUNDS: usize = 200;
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let low_stop = stop.clone();
+        let low_lock = lock.clone();
+        let low = thread::spawn(move || {
+            unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, 19) };
+            while !low_stop.load(Ordering::SeqCst) {
+                let mut value = low_lock.lock().unwrap();
+                for i in 0..20000u64 {
+                    *value = value.wrapping_mul(31).wrapping_add(i);
+                }
+                drop(value);
+                thread::yield_now();
+            }
+        });
+
+        let high = thread::spawn(move || {
+            unsafe {
+                let param = libc::sched_param { sched_priority: 10 };
+                libc::pthread_setschedparam(libc::pthread_self(), libc::SCHED_FIFO, &param);
+            }
+            let mut longest = Duration::default();
+            for _ in 0..ROUNDS {
+                let waiting = Instant::now();
+                let mut value = lock.lock().unwrap();
+                longest = longest.max(waiting.elapsed());
+                *value += 1;
+                drop(value);
+                thread::sleep(Duration::from_millis(1));
+            }
+            longest
+        });
+
+        let longest = high.join().unwrap();
+        stop.store(true, Ordering::SeqCst);
+        low.join().unwrap();
+        longest
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn locks_inherit_priority_on_linux() {
+        assert!(PiMutex::new(0).is_inheriting());
+        assert!(!PiMutex::without_inheritance(0).is_inheriting());
+    }
+
+    #[test]
+    fn panic_while_held_poisons_the_lock() {
+        let lock = Arc::new(PiMutex::new(0u64));
+        let poisoned = lock.clone();
+        assert!(thread::spawn(move || { let _value = poisoned.lock().unwrap(); panic!("poison"); }).join().is_err());
+
+        match lock.lock() {
+            Err(e) => *e.into_inner() = 1,
+            Ok(_) => panic!("not poisoned by a panic while held")
+        }
+        assert_eq!(*lock.lock().unwrap_or_else(|e| e.into_inner()), 1);
+    }
+
+    // Timing on a shared machine is noisy, so the bound is loose
+    #[test]
+    fn waiting_thread_only_waits_for_the_holder_to_finish() {
+        let longest = contended_lock_wait(Arc::new(PiMutex::new(0)));
+        assert!(longest < Duration::from_millis(50), "waited up to {:?} with priority inheritance", longest);
+    }
+}
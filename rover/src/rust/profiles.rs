@@ -0,0 +1,250 @@
+//
+// Copyright (C) 2020 Abstract Horizon
+// All rights reserved. This program and the accompanying materials
+// are made available under the terms of the Apache License v2.0
+// which accompanies this distribution, and is available at
+// https://www.apache.org/licenses/LICENSE-2.0
+//
+//  Contributors:
+//    Daniel Sendula - initial API and implementation
+//
+
+use std::collections::BTreeMap;
+
+use crate::applied::Applied;
+use crate::balance::ConfigData;
+use crate::config_topics;
+
+
+pub const MAX_NAME_LEN: usize = 32;
+
+
+// Named ConfigData snapshots plus the one loaded last. Persisted through the storage service
+// as ini like text:
+//
+//   active=carpet
+//   [carpet]
+//   pid_kp=0.75
+//   ...
+pub struct Profiles {
+    pub active: Option<String>,
+    profiles: BTreeMap<String, ConfigData>,
+}
+
+// Result of loading a profile over the current config.
+pub struct LoadOutcome {
+    pub config_data: ConfigData,
+    pub applied: Vec<(&'static str, Applied)>,
+    // Structural fields that differ from the current config; they only take effect on restart
+    pub deferred: Vec<&'static str>,
+}
+
+impl Profiles {
+    pub fn new() -> Profiles {
+        Profiles { active: None, profiles: BTreeMap::new() }
+    }
+
+    // Names are shown in the UI and used as section headers in storage.
+    pub fn validate_name(name: &str) -> Result<(), String> {
+        if name.is_empty() {
+            Err("profile name must not be empty".to_string())
+        } else if name.len() > MAX_NAME_LEN {
+            Err(format!("profile name must not be longer than {} characters", MAX_NAME_LEN))
+        } else if name.trim() != name {
+            Err("profile name must not start or end with whitespace".to_string())
+        } else if !name.chars().all(|c| c.is_ascii_alphanumeric() || c == ' ' || c == '-' || c == '_') {
+            Err(format!("profile name {:?} may only contain letters, digits, spaces, '-' and '_'", name))
+        } else {
+            Ok(())
+        }
+    }
+
+    pub fn save(&mut self, name: &str, config_data: ConfigData) -> Result<(), String> {
+        Profiles::validate_name(name)?;
+        self.profiles.insert(name.to_string(), config_data);
+        Ok(())
+    }
+
+    pub fn delete(&mut self, name: &str) -> Result<(), String> {
+        match self.profiles.remove(name) {
+            Some(_) => {
+                if self.active.as_deref() == Some(name) {
+                    self.active = None;
+                }
+                Ok(())
+            },
+            None => Err(format!("no profile named {:?}", name))
+        }
+    }
+
+    // Applies every config topic field of the named profile on top of current. Values go through
+    // the same validation as the config topics; structural fields are left as they are and reported.
+    pub fn load(&mut self, name: &str, current: &ConfigData) -> Result<LoadOutcome, String> {
+        let profile = match self.profiles.get(name) {
+            Some(profile) => profile,
+            None => return Err(format!("no profile named {:?}", name))
+        };
+
+        let values = config_topics::CONFIG_TOPICS.iter()
+            .filter_map(|config_topic| config_topic.field.as_ref().map(|field| (config_topic, (field.get)(profile))));
+        let outcome = config_topics::apply_fields(current, values, |config_topic, value| Ok(config_topic.validate(value)))
+            .map_err(|(field, reason)| format!("{}: {}", field, reason))?;
+        self.active = Some(name.to_string());
+        Ok(LoadOutcome {
+            config_data: outcome.config_data,
+            applied: outcome.applied.into_iter().map(|(config_topic, applied)| (config_topic.topic, applied)).collect(),
+            deferred: outcome.deferred,
+        })
+    }
+
+    pub fn to_storage(&self) -> String {
+        let mut s = String::from("");
+        if let Some(active) = &self.active {
+            s.push_str(format!("active={}\n", active).as_str());
+        }
+        for (name, config_data) in &self.profiles {
+            s.push_str(format!("[{}]\n", name).as_str());
+            for config_topic in config_topics::CONFIG_TOPICS.iter() {
+                if let Some(field) = &config_topic.field {
+                    s.push_str(format!("{}={}\n", field.name, (field.get)(config_data)).as_str());
+                }
+            }
+        }
+        s
+    }
+
+    // Fields missing from a stored profile keep their defaults; malformed lines are skipped.
+    pub fn from_storage(s: &str) -> Profiles {
+        let mut profiles = Profiles::new();
+        let mut current: Option<String> = None;
+        for line in s.lines().map(|line| line.trim()).filter(|line| !line.is_empty()) {
+            if line.starts_with('[') && line.ends_with(']') {
+                let name = &line[1..line.len() - 1];
+                match Profiles::validate_name(name) {
+                    Ok(()) => {
+                        profiles.profiles.insert(name.to_string(), ConfigData::new());
+                        current = Some(name.to_string());
+                    },
+                    Err(e) => {
+                        warn!("Skipping stored profile: {}", e);
+                        current = None;
+                    }
+                }
+                continue;
+            }
+            let (key, value) = match line.find('=') {
+                Some(i) => (&line[..i], &line[i + 1..]),
+                None => {
+                    warn!("Skipping stored profile line {:?}", line);
+                    continue;
+                }
+            };
+            match &current {
+                None if key == "active" => profiles.active = Some(value.to_string()),
+                None => {},
+                Some(name) => {
+                    let field = config_topics::CONFIG_TOPICS.iter()
+                        .filter_map(|config_topic| config_topic.field.as_ref())
+                        .find(|field| field.name == key);
+                    match (field, value.parse()) {
+                        (Some(field), Ok(f)) => (field.set)(profiles.profiles.get_mut(name).unwrap(), f),
+                        _ => warn!("Skipping field {:?} of stored profile {}", line, name)
+                    }
+                }
+            }
+        }
+        if let Some(active) = &profiles.active {
+            if !profiles.profiles.contains_key(active) {
+                profiles.active = None;
+            }
+        }
+        profiles
+    }
+
+    pub fn to_json(&self) -> String {
+        let names: Vec<String> = self.profiles.keys().map(|name| format!("{:?}", name)).collect();
+        format!("{{ \"active\" : {}, \"profiles\" : [{}] }}",
+            match &self.active { Some(active) => format!("{:?}", active), None => "null".to_string() },
+            names.join(", "))
+    }
+}
+
+impl Default for Profiles {
+    fn default() -> Profiles {
+        Profiles::new()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::balance::MAX_MAX_DEGREE;
+    use super::*;
+
+    #[test]
+    fn saved_profile_loads_back_after_storage_until_deleted() {
+        let mut config_data = ConfigData::new();
+        config_data.pid_kp = 1.25;
+
+        let mut profiles = Profiles::new();
+        profiles.save("hard floor", config_data).unwrap();
+        assert!(profiles.save("bad/name", config_data).is_err());
+
+        let mut restored = Profiles::from_storage(&profiles.to_storage());
+        let outcome = restored.load("hard floor", &ConfigData::new()).unwrap();
+        assert_eq!(outcome.config_data.pid_kp, 1.25);
+
+        restored.delete("hard floor").unwrap();
+        assert!(restored.active.is_none());
+        assert!(restored.load("hard floor", &ConfigData::new()).is_err());
+    }
+
+    #[test]
+    fn load_applies_live_fields_and_defers_structural_ones() {
+        let mut config_data = ConfigData::new();
+        config_data.pid_kd = 0.1;
+        config_data.i2c_hard_deadline = 0.02;
+        // Stored by hand, beyond its topic's range
+        config_data.max_degree = 1000.0;
+        let mut profiles = Profiles::default();
+        profiles.save("carpet", config_data).unwrap();
+
+        let current = ConfigData::new();
+        let outcome = profiles.load("carpet", &current).unwrap();
+        assert_eq!(outcome.config_data.pid_kd, 0.1);
+        assert_eq!(outcome.config_data.max_degree, MAX_MAX_DEGREE);
+        assert_eq!(outcome.config_data.i2c_hard_deadline, current.i2c_hard_deadline);
+        assert_eq!(outcome.deferred, vec!["i2c_hard_deadline"]);
+        assert!(outcome.applied.iter().all(|(topic, _)| *topic != "balance/i2c/hard_deadline"));
+        assert_eq!(outcome.applied.iter().find(|(topic, _)| *topic == "balance/max_degree").unwrap().1,
+            Applied::Clamped { requested: 1000.0, effective: MAX_MAX_DEGREE });
+        assert_eq!(profiles.active.as_deref(), Some("carpet"));
+    }
+
+    #[test]
+    fn deleting_the_active_profile_leaves_none_active() {
+        let mut profiles = Profiles::new();
+        profiles.save("carpet", ConfigData::new()).unwrap();
+        profiles.save("hard floor", ConfigData::new()).unwrap();
+        profiles.load("carpet", &ConfigData::new()).unwrap();
+
+        profiles.delete("hard floor").unwrap();
+        assert_eq!(profiles.active.as_deref(), Some("carpet"));
+        profiles.delete("carpet").unwrap();
+        assert!(profiles.active.is_none());
+        assert!(profiles.delete("carpet").is_err());
+        assert_eq!(Profiles::from_storage(&profiles.to_storage()).to_json(), "{ \"active\" : null, \"profiles\" : [] }");
+    }
+
+    #[test]
+    fn names_must_be_short_trimmed_and_plain() {
+        let longest = "x".repeat(MAX_NAME_LEN);
+        for name in ["carpet", "hard floor", "run-2_b", longest.as_str()].iter() {
+            assert_eq!(Profiles::validate_name(name), Ok(()), "{:?} refused", name);
+        }
+        let too_long = "x".repeat(MAX_NAME_LEN + 1);
+        for name in ["", too_long.as_str(), " carpet", "carpet\t", "bad/name", "[carpet]", "tapis=1", "café"].iter() {
+            assert!(Profiles::validate_name(name).is_err(), "{:?} accepted", name);
+        }
+    }
+}
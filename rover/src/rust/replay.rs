@@ -0,0 +1,316 @@
+//
+// Copyright (C) 2020 Abstract Horizon
+// All rights reserved. This program and the accompanying materials
+// are made available under the terms of the Apache License v2.0
+// which accompanies this distribution, and is available at
+// https://www.apache.org/licenses/LICENSE-2.0
+//
+//  Contributors:
+//    Daniel Sendula - initial API and implementation
+//
+
+// Feeds a recording of the balance-input stream (a telemetry file written with --record-inputs)
+// through the same Controller the balance loop runs, under a config of choice, without any of
+// the hardware. The outputs can be written to a telemetry file of their own, as the balance-replay
+// stream.
+//
+// Replays are open loop: what the sensors recorded is how the rover moved under the outputs of
+// the time, not the replayed ones. They show what other settings would have asked for, not how
+// the rover would have balanced with them.
+
+use std::cell::RefCell;
+use std::fs;
+
+use crate::balance::{ConfigData, State};
+use crate::controller::{self, Controller, SensorInput};
+use crate::telemetry_file;
+use crate::telemetry_reader::Frame;
+use crate::telemetry_socket_server::{definitions_frames, meta_frame_at};
+use crate::telemetry_stream::{Storable, TelemetryStreamDefinition, Timebase, WireByteOrder};
+
+
+const BALANCE_REPLAY_STREAM_VERSION: u32 = 1;
+
+
+fn create_replay_logger() -> TelemetryStreamDefinition {
+    TelemetryStreamDefinition::new("balance-replay", BALANCE_REPLAY_STREAM_VERSION,
+        vec![
+            TelemetryStreamDefinition::double_field("roll"),
+            TelemetryStreamDefinition::double_field("pitch"),
+            TelemetryStreamDefinition::double_field("yaw"),
+            TelemetryStreamDefinition::double_field("fe_x"),
+            TelemetryStreamDefinition::double_field("fe_y"),
+            TelemetryStreamDefinition::double_field("fe_z"),
+            TelemetryStreamDefinition::double_field("target"),
+            TelemetryStreamDefinition::double_field("pi_p"),
+            TelemetryStreamDefinition::double_field("pi_i"),
+            TelemetryStreamDefinition::double_field("pi_d"),
+            TelemetryStreamDefinition::double_field("pi_o"),
+            TelemetryStreamDefinition::double_field("speed"),
+            TelemetryStreamDefinition::double_field("po_o"),
+            TelemetryStreamDefinition::double_field("vel"),
+            TelemetryStreamDefinition::double_field("vel_o"),
+            TelemetryStreamDefinition::double_field("out"),
+            TelemetryStreamDefinition::unsigned_byte_field("state"),
+        ]
+    ).with_timebase(Timebase::Monotonic)
+}
+
+// Takes records from the log macros, as the telemetry server would.
+struct RecordBuffer {
+    records: RefCell<Vec<u8>>,
+}
+
+impl RecordBuffer {
+    fn buffer(&self, capacity: usize) -> Vec<u8> {
+        Vec::with_capacity(capacity)
+    }
+
+    fn log(&self, buf: Vec<u8>) {
+        self.records.borrow_mut().extend_from_slice(&buf);
+    }
+}
+
+
+// Over the samples recorded while balancing.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ReplaySummary {
+    pub samples: usize,
+    pub balancing_samples: usize,
+    // Seconds
+    pub balancing_time: f64,
+    // Degrees between pitch and the inner PID's set point
+    pub max_angle_error: f64,
+    pub rms_angle_error: f64,
+    // Motor output (1 is full duty) integrated over time, and its largest magnitude
+    pub control_effort: f64,
+    pub max_control: f64,
+}
+
+
+pub struct Replay {
+    pub config_data: ConfigData,
+    inputs: Vec<SensorInput>,
+}
+
+impl Replay {
+    // Every balance-input record of the file, in order; records of other streams are skipped.
+    pub fn from_recording(path: &str, config_data: ConfigData) -> Result<Replay, String> {
+        let mut inputs = vec![];
+        for frame in telemetry_file::read_records(path).map_err(|e| format!("Cannot read {}: {}", path, e))? {
+            match frame.map_err(|e| format!("{}: {}", path, e))? {
+                Frame::Record { stream_id, values, .. } if stream_id == controller::BALANCE_INPUT_STREAM_VERSION =>
+                    inputs.push(SensorInput::from_record(&values).map_err(|e| format!("{}: record {}: {}", path, inputs.len(), e))?),
+                _ => {}
+            }
+        }
+        if inputs.is_empty() {
+            return Err(format!("{} has no balance-input records; was it recorded with --record-inputs?", path));
+        }
+        Ok(Replay { config_data, inputs })
+    }
+
+    pub fn inputs(&self) -> &[SensorInput] {
+        &self.inputs
+    }
+
+    // From a fresh controller each time; the outputs go to a telemetry file at output, if given.
+    pub fn run(&self, output: Option<&str>) -> Result<ReplaySummary, String> {
+        let stream = create_replay_logger();
+        let logger = RecordBuffer { records: RefCell::new(vec![]) };
+        let mut controller = Controller::new(&self.config_data);
+        let mut summary = ReplaySummary { samples: self.inputs.len(), ..ReplaySummary::default() };
+        let mut squared_error = 0.0;
+        let mut last_now: Option<f64> = None;
+
+        for input in &self.inputs {
+            let step = controller.step(&self.config_data, input);
+            let dt = last_now.map_or(0.0, |last_now| input.now - last_now);
+            last_now = Some(input.now);
+            let balancing = input.state == State::Balancing;
+            let control = if balancing { step.control } else { 0.0 };
+
+            if balancing {
+                let error = step.orientation.pitch - step.target;
+                summary.balancing_samples += 1;
+                summary.balancing_time += dt;
+                summary.max_angle_error = summary.max_angle_error.max(error.abs());
+                squared_error += error * error;
+                summary.control_effort += control.abs() * dt;
+                summary.max_control = summary.max_control.max(control.abs());
+            }
+
+            if output.is_some() {
+                let fusion_error = controller.fusion_error();
+                let pid = &controller.pid;
+                log!(
+                    logger, stream, input.now,
+                    step.orientation.roll, step.orientation.pitch, step.orientation.yaw,
+                    fusion_error.0, fusion_error.1, fusion_error.2,
+                    step.target, pid.p * pid.kp, pid.i * pid.ki, pid.d * pid.kd, step.pid_output,
+                    step.speed, step.outer_output, step.velocity, controller.velocity_feedback.contribution(),
+                    control, input.state.value());
+            }
+        }
+        if summary.balancing_samples > 0 {
+            summary.rms_angle_error = (squared_error / summary.balancing_samples as f64).sqrt();
+        }
+
+        if let Some(output) = output {
+            let mut file = definitions_frames(&[stream.to_json().into_bytes()], WireByteOrder::Little);
+            file.extend_from_slice(&meta_frame_at(0.0, 0.0, WireByteOrder::Little, b""));
+            file.extend_from_slice(&logger.records.borrow());
+            fs::write(output, &file).map_err(|e| format!("Cannot write {}: {}", output, e))?;
+        }
+        Ok(summary)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::f64::consts::PI;
+    use std::thread;
+    use std::time::Duration;
+
+    use crate::balance::HoldDecision;
+    use crate::telemetry_reader::Value;
+    use crate::telemetry_socket_server::SocketTelemetryServerBuilder;
+    use crate::test_support::TempDir;
+    use super::*;
+
+    const TICKS: usize = 600;
+
+    // Rocking two degrees either way at 2 Hz, after a second waiting to be upright, with a dropout
+    fn rocking() -> Vec<SensorInput> {
+        (0..TICKS).map(|i| {
+            let now = i as f64 * 0.005;
+            let pitch = 2.0 * (now * 4.0 * PI).sin();
+            let rate = 8.0 * PI * (now * 4.0 * PI).cos();
+            SensorInput {
+                now,
+                hold: if i % 100 == 50 { HoldDecision::Hold } else { HoldDecision::Fresh },
+                state: if i < 200 { State::WaitingForReady } else { State::Balancing },
+                gyro_overrun: false,
+                // Sensor frame; the default mapping swaps x and z
+                gyro: (0.0, rate, 0.0),
+                gyro_dt: 0.005,
+                accel: (pitch.to_radians().cos(), 0.0, pitch.to_radians().sin()),
+                wheel_velocity: (20.0, 24.0),
+                drive_forward: 0.0,
+            }
+        }).collect()
+    }
+
+    // Logs the inputs as the balance loop does with --record-inputs, between records of another stream.
+    fn record(path: &str, inputs: &[SensorInput]) {
+        let mut builder = SocketTelemetryServerBuilder::new();
+        let other = builder.register_stream(TelemetryStreamDefinition::new("counter", 1, vec![
+            TelemetryStreamDefinition::unsigned_byte_field("i"),
+        ])).unwrap();
+        let input_logger = builder.register_stream(controller::create_input_logger(Timebase::Monotonic)).unwrap();
+        builder.file_output(path, Duration::from_millis(50), 1024 * 1024);
+        let server = builder.create(0);
+        for (i, input) in inputs.iter().enumerate() {
+            log_with_time!(server, other, i as u8);
+            controller::log_input(&server, &input_logger, input, (1, 2, 3), (4, 5, 6));
+            if i % 100 == 0 {
+                thread::sleep(Duration::from_millis(20));
+            }
+        }
+        thread::sleep(Duration::from_millis(200));
+        let stats = server.stats();
+        server.stop().unwrap();
+        assert_eq!(stats.file_dropped, 0, "file sink dropped records: {:?}", stats);
+    }
+
+    fn float(values: &[(String, Value)], name: &str) -> f64 {
+        match values.iter().find(|(field, _)| field == name) {
+            Some((_, Value::Float(value))) => *value,
+            other => panic!("{} is {:?}", name, other)
+        }
+    }
+
+    #[test]
+    fn recorded_inputs_read_back_as_logged() {
+        let dir = TempDir::new("replay-read-back");
+        let path = dir.file("recording.bin");
+        let inputs = rocking();
+        record(&path, &inputs);
+
+        let replay = Replay::from_recording(&telemetry_file::file_path(&path, 0), ConfigData::new()).unwrap();
+        assert!(replay.inputs() == &inputs[..], "read back {} inputs, not the {} logged", replay.inputs().len(), inputs.len());
+    }
+
+    #[test]
+    fn replay_gives_the_outputs_of_the_live_loop() {
+        let dir = TempDir::new("replay-outputs");
+        let path = dir.file("recording.bin");
+        let output = dir.file("replayed.bin");
+        let inputs = rocking();
+        record(&path, &inputs);
+
+        let config_data = ConfigData::new();
+        let mut controller = Controller::new(&config_data);
+        let live: Vec<(f64, f64, f64, f64)> = inputs.iter().map(|input| {
+            let step = controller.step(&config_data, input);
+            let control = if input.state == State::Balancing { step.control } else { 0.0 };
+            (step.orientation.pitch, step.target, step.pid_output, control)
+        }).collect();
+
+        let replay = Replay::from_recording(&telemetry_file::file_path(&path, 0), config_data).unwrap();
+        replay.run(Some(&output)).unwrap();
+        let replayed = telemetry_file::read_records(&output).unwrap().collect::<Result<Vec<Frame>, String>>().unwrap();
+        assert_eq!(replayed.len(), TICKS);
+        for (tick, (frame, expected)) in replayed.iter().zip(live.iter()).enumerate() {
+            match frame {
+                Frame::Record { time, values, .. } => {
+                    assert_eq!(*time, inputs[tick].now);
+                    let outputs = (float(values, "pitch"), float(values, "target"), float(values, "pi_o"), float(values, "out"));
+                    assert_eq!(outputs, *expected, "tick {}", tick);
+                },
+                other => panic!("tick {} replayed as {:?}", tick, other)
+            }
+        }
+    }
+
+    #[test]
+    fn replay_summary_covers_the_balancing_samples() {
+        let dir = TempDir::new("replay-summary");
+        let path = dir.file("recording.bin");
+        let inputs = rocking();
+        record(&path, &inputs);
+
+        let config_data = ConfigData::new();
+        let replay = Replay::from_recording(&telemetry_file::file_path(&path, 0), config_data).unwrap();
+        let summary = replay.run(None).unwrap();
+        let mut controller = Controller::new(&config_data);
+        let mut max_angle_error: f64 = 0.0;
+        for input in &inputs {
+            let step = controller.step(&config_data, input);
+            if input.state == State::Balancing {
+                max_angle_error = max_angle_error.max((step.orientation.pitch - step.target).abs());
+            }
+        }
+        assert_eq!(summary.samples, TICKS);
+        assert_eq!(summary.balancing_samples, 400);
+        assert!((summary.balancing_time - 2.0).abs() < 1e-9, "{:?}", summary);
+        assert_eq!(summary.max_angle_error, max_angle_error);
+        assert!(summary.control_effort > 0.0, "{:?}", summary);
+
+        let again = replay.run(None).unwrap();
+        assert_eq!(again.max_angle_error, summary.max_angle_error);
+        assert_eq!(again.control_effort, summary.control_effort);
+    }
+
+    #[test]
+    fn replayed_outputs_are_not_a_recording() {
+        let dir = TempDir::new("replay-not-recording");
+        let path = dir.file("recording.bin");
+        let output = dir.file("replayed.bin");
+        record(&path, &rocking());
+
+        Replay::from_recording(&telemetry_file::file_path(&path, 0), ConfigData::new()).unwrap().run(Some(&output)).unwrap();
+        assert!(Replay::from_recording(&output, ConfigData::new()).is_err());
+    }
+}
@@ -0,0 +1,168 @@
+//
+// Copyright (C) 2020 Abstract Horizon
+// All rights reserved. This program and the accompanying materials
+// are made available under the terms of the Apache License v2.0
+// which accompanies this distribution, and is available at
+// https://www.apache.org/licenses/LICENSE-2.0
+//
+//  Contributors:
+//    Daniel Sendula - initial API and implementation
+//
+
+// What the rover must look like whenever it exits: motors braked, driver disabled, lights off.
+// Components that drive outputs register how to get them there; the safe state is engaged
+// once, from whichever exit path gets there first - the end of the ordered shutdown, the panic
+// hook or a repeated interrupt. Actions run in reverse registration order and must be quick
+// and must not block; they may run on any thread, including one that is panicking.
+
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+
+// All actions together should be done in this time
+pub const SAFE_STATE_BUDGET: Duration = Duration::from_millis(50);
+
+struct SafeAction {
+    name: &'static str,
+    action: Box<dyn Fn() + Send + Sync>,
+}
+
+#[derive(Clone)]
+pub struct SafeState {
+    engaged: Arc<AtomicBool>,
+    actions: Arc<Mutex<Vec<SafeAction>>>,
+}
+
+impl SafeState {
+    pub fn new() -> SafeState {
+        SafeState {
+            engaged: Arc::new(AtomicBool::new(false)),
+            actions: Arc::new(Mutex::new(vec![])),
+        }
+    }
+
+    // Registering after the safe state was engaged runs the action straight away.
+    pub fn register(&self, name: &'static str, action: impl Fn() + Send + Sync + 'static) {
+        let safe_action = SafeAction { name, action: Box::new(action) };
+        let mut actions = self.actions.lock().unwrap_or_else(|e| e.into_inner());
+        if self.is_engaged() {
+            drop(actions);
+            run_action(&safe_action);
+        } else {
+            actions.push(safe_action);
+        }
+    }
+
+    // Outputs should stay put once this is true.
+    pub fn is_engaged(&self) -> bool {
+        self.engaged.load(Ordering::SeqCst)
+    }
+
+    // Runs every registered action, newest first. Only the first call does anything; returns
+    // whether this call was it.
+    pub fn engage(&self, reason: &str) -> bool {
+        if self.engaged.swap(true, Ordering::SeqCst) {
+            return false;
+        }
+        // Taken out rather than run under the lock: a panicking action gets back here through
+        // the panic hook. A register that comes later runs its action by itself
+        let actions = std::mem::take(&mut *self.actions.lock().unwrap_or_else(|e| e.into_inner()));
+
+        let started = Instant::now();
+        for safe_action in actions.iter().rev() {
+            run_action(safe_action);
+        }
+        let elapsed = started.elapsed();
+        if elapsed > SAFE_STATE_BUDGET {
+            warn!("Safe state ({}) took {:?} for {} action(s)", reason, elapsed, actions.len());
+        } else {
+            info!("Safe state ({}) reached in {:?}", reason, elapsed);
+        }
+        true
+    }
+
+    // Engages the safe state before the default panic message is printed.
+    pub fn install_panic_hook(&self) {
+        let safe_state = self.clone();
+        let previous_hook = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            if !safe_state.is_engaged() {
+                safe_state.engage("panic");
+            }
+            previous_hook(info);
+        }));
+    }
+}
+
+impl Default for SafeState {
+    fn default() -> SafeState {
+        SafeState::new()
+    }
+}
+
+// A failing action must not stop the ones after it.
+fn run_action(safe_action: &SafeAction) {
+    if panic::catch_unwind(AssertUnwindSafe(|| (safe_action.action)())).is_err() {
+        error!("Safe state action {} failed", safe_action.name);
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+    use std::thread;
+
+    #[test]
+    fn actions_run_once_newest_first_even_when_one_fails() {
+        let ran = Arc::new(Mutex::new(vec![]));
+        let safe_state = SafeState::new();
+        for id in 1..4 {
+            let ran = ran.clone();
+            safe_state.register("mock", move || ran.lock().unwrap().push(id));
+        }
+        safe_state.register("failing", || panic!("cannot reach the pins"));
+
+        // Shutdown, panic hook and signal handler racing each other
+        let engaged: Vec<bool> = (0..3).map(|_| {
+            let safe_state = safe_state.clone();
+            thread::spawn(move || safe_state.engage("test"))
+        }).collect::<Vec<thread::JoinHandle<bool>>>().into_iter().map(|thread| thread.join().unwrap()).collect();
+
+        assert_eq!(engaged.iter().filter(|engaged| **engaged).count(), 1, "engaged by {:?}", engaged);
+        assert_eq!(*ran.lock().unwrap(), vec![3, 2, 1]);
+        assert!(!safe_state.engage("again"));
+        assert!(safe_state.is_engaged());
+    }
+
+    #[test]
+    fn late_registration_runs_straight_away() {
+        let ran = Arc::new(Mutex::new(vec![]));
+        let safe_state = SafeState::new();
+        safe_state.engage("test");
+
+        let late = ran.clone();
+        safe_state.register("late", move || late.lock().unwrap().push(4));
+        assert_eq!(*ran.lock().unwrap(), vec![4]);
+    }
+
+    #[test]
+    fn panicking_action_does_not_deadlock_the_panic_hook() {
+        let ran = Arc::new(Mutex::new(vec![]));
+        let safe_state = SafeState::default();
+        safe_state.install_panic_hook();
+        let brake = ran.clone();
+        safe_state.register("brake", move || brake.lock().unwrap().push("brake"));
+        safe_state.register("failing", || panic!("cannot reach the pins"));
+
+        let (done, finished) = mpsc::channel();
+        let engaging = safe_state.clone();
+        thread::spawn(move || done.send(engaging.engage("test")).unwrap());
+
+        assert_eq!(finished.recv_timeout(Duration::from_secs(2)), Ok(true));
+        assert_eq!(*ran.lock().unwrap(), vec!["brake"]);
+    }
+}
@@ -0,0 +1,125 @@
+//
+// Copyright (C) 2020 Abstract Horizon
+// All rights reserved. This program and the accompanying materials
+// are made available under the terms of the Apache License v2.0
+// which accompanies this distribution, and is available at
+// https://www.apache.org/licenses/LICENSE-2.0
+//
+//  Contributors:
+//    Daniel Sendula - initial API and implementation
+//
+
+// Results of the hardware self-test: each check passes or fails on a measured value, or fails
+// because the device could not be read at all.
+
+use std::time::Duration;
+
+
+// Long enough for 20 gyro samples at 200Hz, short enough not to overflow the FIFO at 800Hz between reads
+pub const GYRO_RATE_WINDOW: Duration = Duration::from_millis(100);
+// Fraction the gyro sample rate may be off the configured one
+pub const GYRO_RATE_TOLERANCE: f64 = 0.2;
+pub const ACCEL_GRAVITY_SAMPLES: usize = 10;
+// g the magnitude of the accelerometer vector at rest may be off 1g
+pub const ACCEL_GRAVITY_TOLERANCE: f64 = 0.1;
+// Each direction pin must read back high once set and low once cleared
+pub const DIRECTION_PIN_LEVELS: f64 = 2.0;
+
+
+pub struct Check {
+    pub name: String,
+    pub passed: bool,
+    // None when the device could not be read
+    pub value: Option<f64>,
+    // What passes, for whoever reads the report
+    pub expected: String,
+    pub error: Option<String>,
+}
+
+impl Check {
+    pub fn exact(name: &str, measured: Result<f64, String>, expected: f64) -> Check {
+        Check::new(name, measured, format!("{}", expected), |value| value == expected)
+    }
+
+    pub fn within(name: &str, measured: Result<f64, String>, expected: f64, tolerance: f64) -> Check {
+        Check::new(name, measured, format!("{} +- {}", expected, tolerance), |value| (value - expected).abs() <= tolerance)
+    }
+
+    fn new<F: Fn(f64) -> bool>(name: &str, measured: Result<f64, String>, expected: String, passes: F) -> Check {
+        match measured {
+            Ok(value) => Check { name: name.to_string(), passed: passes(value), value: Some(value), expected, error: None },
+            Err(error) => Check { name: name.to_string(), passed: false, value: None, expected, error: Some(error) }
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        let value = match self.value {
+            Some(value) if value.is_finite() => format!("{}", value),
+            _ => "null".to_string()
+        };
+        let error = match &self.error {
+            Some(error) => format!(", \"error\" : \"{}\"", error.replace('\\', "\\\\").replace('"', "\\\"")),
+            None => String::new()
+        };
+        format!("{{ \"name\" : \"{}\", \"passed\" : {}, \"value\" : {}, \"expected\" : \"{}\"{} }}",
+            self.name, self.passed, value, self.expected, error)
+    }
+}
+
+pub struct SelfTestReport {
+    pub checks: Vec<Check>,
+}
+
+impl SelfTestReport {
+    pub fn new() -> SelfTestReport {
+        SelfTestReport { checks: vec![] }
+    }
+
+    pub fn add(&mut self, check: Check) {
+        self.checks.push(check);
+    }
+
+    pub fn passed(&self) -> bool {
+        self.checks.iter().all(|check| check.passed)
+    }
+
+    pub fn to_json(&self) -> String {
+        let checks: Vec<String> = self.checks.iter().map(|check| check.to_json()).collect();
+        format!("{{ \"passed\" : {}, \"checks\" : [ {} ] }}", self.passed(), checks.join(", "))
+    }
+}
+
+impl Default for SelfTestReport {
+    fn default() -> SelfTestReport {
+        SelfTestReport::new()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn report_passes_only_when_every_check_does() {
+        let mut report = SelfTestReport::new();
+        report.add(Check::exact("gyro_who_am_i", Ok(211.0), 211.0));
+        report.add(Check::within("gyro_sample_rate", Ok(190.0), 200.0, 200.0 * GYRO_RATE_TOLERANCE));
+        report.add(Check::within("accel_gravity", Ok(0.97), 1.0, ACCEL_GRAVITY_TOLERANCE));
+        assert!(report.passed(), "{}", report.to_json());
+
+        report.add(Check::within("accel_gravity", Ok(0.5), 1.0, ACCEL_GRAVITY_TOLERANCE));
+        assert!(!report.passed());
+    }
+
+    #[test]
+    fn check_without_a_reading_fails_with_its_error() {
+        let mut report = SelfTestReport::new();
+        report.add(Check::exact("gyro_who_am_i", Ok(211.0), 211.0));
+        report.add(Check::exact("left_in1", Err("GPIO \"6\" unavailable".to_string()), DIRECTION_PIN_LEVELS));
+        assert_eq!(report.to_json(), concat!(
+            "{ \"passed\" : false, \"checks\" : [ ",
+            "{ \"name\" : \"gyro_who_am_i\", \"passed\" : true, \"value\" : 211, \"expected\" : \"211\" }, ",
+            "{ \"name\" : \"left_in1\", \"passed\" : false, \"value\" : null, \"expected\" : \"2\", \"error\" : \"GPIO \\\"6\\\" unavailable\" } ] }"));
+    }
+}
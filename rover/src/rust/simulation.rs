@@ -0,0 +1,733 @@
+//
+// Copyright (C) 2020 Abstract Horizon
+// All rights reserved. This program and the accompanying materials
+// are made available under the terms of the Apache License v2.0
+// which accompanies this distribution, and is available at
+// https://www.apache.org/licenses/LICENSE-2.0
+//
+//  Contributors:
+//    Daniel Sendula - initial API and implementation
+//
+
+// The rover without its hardware, for tests: the gyro, accelerometer and encoders answering on
+// simulated i2c buses, a dry-run motor board, and TestHarness, which runs Balance on them through
+// a scripted Scenario and checks what it did from its telemetry.
+//
+// The simulated rover does not fall over. Its pitch is whatever the scenario tilts it to, as if it
+// were held in the hand, which is enough to take Balance through all of its states. The wheels do
+// not turn.
+//
+// New features get a scenario of their own rather than new test plumbing:
+//
+//   let (mut harness, mut balance_control) = TestHarness::start();
+//   harness.run(&Scenario::new(3.0).at(1.0, Step::Bump(60.0)), &mut balance_control);
+//   assert!(harness.shut_down(|| balance_control.stop()) < SHUTDOWN_TIMEOUT);
+//   harness.assert_motors_stopped_unless_driven();
+//   assert_eq!(harness.transitions(), vec![State::Balancing, State::WaitingForReady, State::Balancing]);
+
+use std::io::{self, Read};
+use std::net::TcpStream;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use byteorder::{BigEndian, ByteOrder, LittleEndian};
+use rppal::i2c::Error;
+
+use crate::accel;
+use crate::as5600;
+use crate::balance::{Balance, BalanceControl, Hardware, State, ACCEL_ADDRESS, GYRO_ADDRESS};
+use crate::config_topics;
+use crate::gyro::{self, Range};
+use crate::i2c_bus::{I2cTransport, SharedBus};
+use crate::motors::Motors;
+use crate::safe_state::SafeState;
+use crate::telemetry_reader::{Frame, TelemetryReader, Value};
+use crate::telemetry_stream::{Timebase, WireByteOrder};
+
+
+// Registers the drivers in gyro.rs and accel.rs use, from the L3G4200D and ADXL345 datasheets
+const GYRO_CTRL_REG1: u8 = 0x20;
+const GYRO_CTRL_REG4: u8 = 0x23;
+const GYRO_OUT_TEMP: u8 = 0x26;
+const GYRO_STATUS_REG: u8 = 0x27;
+const GYRO_OUT_X_L: u8 = 0x28;
+const GYRO_FIFO_CTRL_REG: u8 = 0x2E;
+const GYRO_FIFO_SRC_REG: u8 = 0x2F;
+const GYRO_FIFO_MODE_BYPASS: u8 = 0x00;
+// New x, y and z samples
+const GYRO_DATA_READY: u8 = 0x0F;
+const GYRO_TEMPERATURE: u8 = 25;
+const ACCEL_BW_RATE: u8 = 0x2C;
+const ACCEL_POWER_CTL: u8 = 0x2D;
+const ACCEL_DATA_FORMAT: u8 = 0x31;
+const ACCEL_AXES_DATA: u8 = 0x32;
+const ACCEL_FIFO_STATUS: u8 = 0x39;
+const ACCEL_MEASURE: u8 = 0x08;
+// Full resolution, 3.9mg per LSB
+const ACCEL_LSB_PER_G: f64 = 256.0;
+const FIFO_DEPTH: u64 = 32;
+const AS5600_MAGNET_DETECTED: u8 = 0x20;
+// Quarter of a turn, in AS5600 steps
+const WHEEL_ANGLE: u16 = 1024;
+
+// About what reading a register takes on a 100kHz bus; STATUS_REG polls take this long
+const REGISTER_READ_TIME: Duration = Duration::from_micros(400);
+// Degrees per second bumps tilt the rover at, there and back
+const TILT_RATE: f64 = 200.0;
+// How long a bump holds the rover over before it comes back upright
+const BUMP_TIME: Duration = Duration::from_millis(500);
+// Telemetry definitions come after the server's handshake timeout
+const TELEMETRY_READY_TIMEOUT: Duration = Duration::from_secs(2);
+const TELEMETRY_CLOSE_TIMEOUT: Duration = Duration::from_secs(2);
+const TELEMETRY_READ_TIMEOUT: Duration = Duration::from_millis(100);
+// Longest BalanceControl::stop may take: acknowledging the shutdown, then finishing the loop
+pub const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(3);
+
+// Boards claim their DMA channel and pacing hardware for the whole process, dry-run ones too
+static BOARDS: Mutex<()> = Mutex::new(());
+
+
+// Held by everything that builds a Board in tests, TestHarness included, so they take turns with
+// the pacing hardware.
+pub fn lock_boards() -> MutexGuard<'static, ()> {
+    BOARDS.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+fn no_ack() -> Error {
+    Error::Io(io::Error::new(io::ErrorKind::NotFound, "no simulated i2c device at that address"))
+}
+
+
+// Pitch of the rover and how fast it is changing, which all of its sensors see.
+struct Body {
+    pitch: f64,
+    // Degrees per second, as the gyro measures it
+    rate: f64,
+    // Pitch the rover is held at until the given time, then it comes back upright
+    tilt: Option<(f64, Instant)>,
+    // The gyro takes no samples until then, as after a brown-out
+    dropout_until: Option<Instant>,
+    updated: Instant,
+}
+
+impl Body {
+    fn advance(&mut self, now: Instant) {
+        let dt = now.saturating_duration_since(self.updated).as_secs_f64();
+        if dt <= 0.0 {
+            return;
+        }
+        self.updated = now;
+        let target = match self.tilt {
+            Some((pitch, until)) if now < until => pitch,
+            _ => 0.0
+        };
+        let step = (target - self.pitch).max(-TILT_RATE * dt).min(TILT_RATE * dt);
+        self.pitch += step;
+        self.rate = step / dt;
+    }
+
+    fn dropped_out(&self, now: Instant) -> bool {
+        matches!(self.dropout_until, Some(until) if now < until)
+    }
+}
+
+
+// The rover's sensors, all looking at the same body.
+#[derive(Clone)]
+pub struct SimulatedRover {
+    body: Arc<Mutex<Body>>,
+}
+
+impl SimulatedRover {
+    // Standing upright and still.
+    pub fn new() -> SimulatedRover {
+        SimulatedRover {
+            body: Arc::new(Mutex::new(Body { pitch: 0.0, rate: 0.0, tilt: None, dropout_until: None, updated: Instant::now() })),
+        }
+    }
+
+    // Degrees; positive is the way the balance-data pitch field counts it.
+    pub fn pitch(&self) -> f64 {
+        let mut body = self.body.lock().unwrap();
+        body.advance(Instant::now());
+        body.pitch
+    }
+
+    // Knocks the rover over to pitch, where it stays for BUMP_TIME before coming back upright.
+    pub fn bump(&self, pitch: f64) {
+        let mut body = self.body.lock().unwrap();
+        body.advance(Instant::now());
+        body.tilt = Some((pitch, Instant::now() + BUMP_TIME));
+    }
+
+    // The gyro stops sampling for the given time, so reading it times out; the rest carry on.
+    pub fn sensor_dropout(&self, duration: Duration) {
+        self.body.lock().unwrap().dropout_until = Some(Instant::now() + duration);
+    }
+
+    // Buses with the sensors where the rover has them, a dry-run motor board and any free
+    // telemetry port.
+    pub fn hardware(&self) -> Hardware {
+        Hardware {
+            buses: [
+                SharedBus::with_transport(Box::new(SimulatedBus::new(&self.body, false))),
+                SharedBus::with_transport(Box::new(SimulatedBus::new(&self.body, true))),
+            ],
+            motor_board: Motors::board_builder().dry_run(true),
+            telemetry_port: 0,
+        }
+    }
+}
+
+impl Default for SimulatedRover {
+    fn default() -> SimulatedRover {
+        SimulatedRover::new()
+    }
+}
+
+
+// Samples a sensor takes at its rate into a FIFO of FIFO_DEPTH; older ones are lost.
+struct SampleFifo {
+    // Set once the sensor is told to start sampling
+    started: Option<Instant>,
+    freq: f64,
+    // Samples taken so far that were read or lost
+    gone: u64,
+    overrun: bool,
+}
+
+impl SampleFifo {
+    fn new(freq: f64) -> SampleFifo {
+        SampleFifo { started: None, freq, gone: 0, overrun: false }
+    }
+
+    fn taken(&self, now: Instant) -> u64 {
+        self.started.map_or(0, |started| (now.saturating_duration_since(started).as_secs_f64() * self.freq) as u64)
+    }
+
+    fn waiting(&mut self, now: Instant) -> u64 {
+        let taken = self.taken(now);
+        if taken - self.gone > FIFO_DEPTH {
+            self.gone = taken - FIFO_DEPTH;
+            self.overrun = true;
+        }
+        taken - self.gone
+    }
+
+    // Nothing taken until now is read any more.
+    fn clear(&mut self, now: Instant) {
+        self.gone = self.taken(now);
+        self.overrun = false;
+    }
+
+    fn pop(&mut self, now: Instant) {
+        if self.waiting(now) > 0 {
+            self.gone += 1;
+        }
+    }
+}
+
+
+// One of the rover's i2c buses: an AS5600 on each, the gyro and the accelerometer on bus 1.
+struct SimulatedBus {
+    body: Arc<Mutex<Body>>,
+    imu: bool,
+    address: u16,
+    gyro: SampleFifo,
+    gyro_range: Range,
+    accel: SampleFifo,
+    accel_data_format: u8,
+}
+
+impl SimulatedBus {
+    fn new(body: &Arc<Mutex<Body>>, imu: bool) -> SimulatedBus {
+        SimulatedBus {
+            body: body.clone(),
+            imu,
+            address: 0,
+            gyro: SampleFifo::new(100.0),
+            gyro_range: Range::Range250,
+            accel: SampleFifo::new(100.0),
+            accel_data_format: 0,
+        }
+    }
+
+    fn is_gyro(&self) -> bool {
+        self.imu && self.address == GYRO_ADDRESS as u16
+    }
+
+    fn is_accel(&self) -> bool {
+        self.imu && self.address == ACCEL_ADDRESS as u16
+    }
+
+    // Samples waiting in the gyro's FIFO; none are taken while it has dropped out.
+    fn gyro_waiting(&mut self, now: Instant) -> u64 {
+        if self.body.lock().unwrap().dropped_out(now) {
+            self.gyro.clear(now);
+        }
+        self.gyro.waiting(now)
+    }
+
+    // Pitch rate as the gyro's y axis measures it, and gravity along the accelerometer's x and z;
+    // the rover's axis mapping has body z on sensor x and body x on sensor z.
+    fn gyro_sample(&mut self) -> (i16, i16, i16) {
+        let mut body = self.body.lock().unwrap();
+        body.advance(Instant::now());
+        (0, raw(body.rate / self.gyro_range.sensitivity()), 0)
+    }
+
+    fn accel_sample(&mut self) -> (i16, i16, i16) {
+        let mut body = self.body.lock().unwrap();
+        body.advance(Instant::now());
+        let pitch = body.pitch.to_radians();
+        (raw(pitch.cos() * ACCEL_LSB_PER_G), 0, raw(pitch.sin() * ACCEL_LSB_PER_G))
+    }
+}
+
+fn raw(value: f64) -> i16 {
+    value.round().max(i16::MIN as f64).min(i16::MAX as f64) as i16
+}
+
+fn write_sample(buf: &mut [u8], (x, y, z): (i16, i16, i16)) {
+    LittleEndian::write_i16(&mut buf[0..2], x);
+    LittleEndian::write_i16(&mut buf[2..4], y);
+    LittleEndian::write_i16(&mut buf[4..6], z);
+}
+
+impl I2cTransport for SimulatedBus {
+    fn set_slave_address(&mut self, address: u16) -> Result<(), Error> {
+        self.address = address;
+        Ok(())
+    }
+
+    fn set_timeout(&mut self, _timeout_ms: u32) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn smbus_read_byte(&mut self, command: u8) -> Result<u8, Error> {
+        let now = Instant::now();
+        if self.is_gyro() {
+            Ok(match command {
+                gyro::WHO_AM_I => gyro::DEVICE_ID,
+                GYRO_STATUS_REG => {
+                    if self.gyro_waiting(now) == 0 {
+                        thread::sleep(REGISTER_READ_TIME);
+                    }
+                    if self.gyro_waiting(Instant::now()) > 0 { GYRO_DATA_READY } else { 0 }
+                },
+                GYRO_FIFO_SRC_REG => {
+                    let waiting = self.gyro_waiting(now).min(0x1f) as u8;
+                    if self.gyro.overrun { waiting | gyro::FIFO_OVERRUN } else { waiting }
+                },
+                GYRO_OUT_TEMP => GYRO_TEMPERATURE,
+                _ => 0
+            })
+        } else if self.is_accel() {
+            Ok(match command {
+                accel::DEVID => accel::DEVICE_ID,
+                ACCEL_DATA_FORMAT => self.accel_data_format,
+                ACCEL_FIFO_STATUS => self.accel.waiting(now) as u8,
+                _ => 0
+            })
+        } else if self.address == as5600::ADDRESS {
+            Ok(AS5600_MAGNET_DETECTED)
+        } else {
+            Err(no_ack())
+        }
+    }
+
+    fn smbus_write_byte(&mut self, command: u8, value: u8) -> Result<(), Error> {
+        let now = Instant::now();
+        if self.is_gyro() {
+            match command {
+                // Output data rate in bits 7 and 6: 100, 200, 400 or 800Hz
+                GYRO_CTRL_REG1 => {
+                    self.gyro.freq = (100u32 << (value >> 6)) as f64;
+                    self.gyro.started = Some(now);
+                    self.gyro.gone = 0;
+                },
+                // Full scale in bits 5 and 4
+                GYRO_CTRL_REG4 => self.gyro_range = match (value >> 4) & 3 {
+                    0 => Range::Range250,
+                    1 => Range::Range500,
+                    _ => Range::Range2000,
+                },
+                GYRO_FIFO_CTRL_REG if value == GYRO_FIFO_MODE_BYPASS => self.gyro.clear(now),
+                _ => {}
+            }
+            Ok(())
+        } else if self.is_accel() {
+            match command {
+                // 0x0F for 1600Hz, halving with each step down
+                ACCEL_BW_RATE => self.accel.freq = (1600u32 >> 0x0Fu8.saturating_sub(value & 0x0F)) as f64,
+                ACCEL_DATA_FORMAT => self.accel_data_format = value,
+                ACCEL_POWER_CTL if value & ACCEL_MEASURE != 0 => {
+                    self.accel.started = Some(now);
+                    self.accel.gone = 0;
+                },
+                _ => {}
+            }
+            Ok(())
+        } else if self.address == as5600::ADDRESS {
+            Ok(())
+        } else {
+            Err(no_ack())
+        }
+    }
+
+    fn write_read(&mut self, write_buffer: &[u8], read_buffer: &mut [u8]) -> Result<(), Error> {
+        let now = Instant::now();
+        let command = write_buffer.first().copied().unwrap_or(0);
+        if self.is_gyro() && command & 0x7f == GYRO_OUT_X_L && read_buffer.len() >= 6 {
+            self.gyro.pop(now);
+            let sample = self.gyro_sample();
+            write_sample(read_buffer, sample);
+            Ok(())
+        } else if self.is_accel() && command == ACCEL_AXES_DATA && read_buffer.len() >= 6 {
+            self.accel.pop(now);
+            let sample = self.accel_sample();
+            write_sample(read_buffer, sample);
+            Ok(())
+        } else if self.address == as5600::ADDRESS && command == as5600::STATUS && read_buffer.len() >= 5 {
+            read_buffer[0] = AS5600_MAGNET_DETECTED;
+            BigEndian::write_u16(&mut read_buffer[3..5], WHEEL_ANGLE);
+            Ok(())
+        } else {
+            Err(no_ack())
+        }
+    }
+}
+
+
+// How the commands of a scenario reach the rover: straight to BalanceControl below, or through
+// the MQTT dispatch of the balancing-rover binary in its tests.
+pub trait Driver {
+    fn start_balancing(&mut self);
+    fn stop_balancing(&mut self);
+    fn estop(&mut self);
+    fn reset(&mut self);
+    // Value for one of config_topics' topics, as if written to storage/write/<topic>
+    fn configure(&mut self, topic: &'static str, value: f64);
+}
+
+impl Driver for BalanceControl {
+    fn start_balancing(&mut self) {
+        BalanceControl::start_balancing(self);
+    }
+
+    fn stop_balancing(&mut self) {
+        BalanceControl::stop_balancing(self);
+    }
+
+    fn estop(&mut self) {
+        BalanceControl::estop(self);
+    }
+
+    fn reset(&mut self) {
+        BalanceControl::reset(self);
+    }
+
+    fn configure(&mut self, topic: &'static str, value: f64) {
+        let config_topic = config_topics::find(topic).unwrap_or_else(|| panic!("No config topic {}", topic));
+        if let (Some(field), Some(value)) = (&config_topic.field, config_topic.validate(value).effective()) {
+            (field.set)(&mut self.config_data, value);
+            self.send_config();
+        }
+    }
+}
+
+
+pub enum Step {
+    Start,
+    Stop,
+    EStop,
+    Reset,
+    Configure(&'static str, f64),
+    // Knocks the rover over to the pitch, degrees; see SimulatedRover::bump
+    Bump(f64),
+    // Seconds the gyro stops sampling for
+    SensorDropout(f64),
+}
+
+// Steps at given times, in seconds from the start of the run.
+pub struct Scenario {
+    duration: f64,
+    steps: Vec<(f64, Step)>,
+}
+
+impl Scenario {
+    pub fn new(duration: f64) -> Scenario {
+        Scenario { duration, steps: vec![] }
+    }
+
+    // Steps at the same time are taken in the order they were added.
+    pub fn at(mut self, time: f64, step: Step) -> Scenario {
+        self.steps.push((time, step));
+        self.steps.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap_or(std::cmp::Ordering::Equal));
+        self
+    }
+}
+
+
+// What the harness keeps of each balance-data record.
+#[derive(Clone, Copy, Debug)]
+pub struct Record {
+    // Seconds since the harness started, when it was received
+    pub at: f64,
+    // None for a value State does not know
+    pub state: Option<State>,
+    pub duty_l: f64,
+    pub duty_r: f64,
+    pub enabled: bool,
+}
+
+fn number(values: &[(String, Value)], name: &str) -> f64 {
+    match values.iter().find(|(field, _)| field == name).map(|(_, value)| value) {
+        Some(Value::Unsigned(value)) => *value as f64,
+        Some(Value::Signed(value)) => *value as f64,
+        Some(Value::Float(value)) => *value,
+        other => panic!("balance-data has {:?} for {}", other, name)
+    }
+}
+
+// Loopback client collecting the balance-data records, until the server closes the connection.
+struct TelemetryClient {
+    records: Arc<Mutex<Vec<Record>>>,
+    // balance-data's definition came
+    ready: Arc<AtomicBool>,
+    closed: Arc<AtomicBool>,
+    stopping: Arc<AtomicBool>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl TelemetryClient {
+    fn connect(port: u16, started: Instant) -> TelemetryClient {
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap_or_else(|e| panic!("Cannot connect to telemetry on port {}: {}", port, e));
+        stream.set_read_timeout(Some(TELEMETRY_READ_TIMEOUT)).unwrap();
+        let records = Arc::new(Mutex::new(vec![]));
+        let ready = Arc::new(AtomicBool::new(false));
+        let closed = Arc::new(AtomicBool::new(false));
+        let stopping = Arc::new(AtomicBool::new(false));
+        let (thread_records, thread_ready, thread_closed, thread_stopping) = (records.clone(), ready.clone(), closed.clone(), stopping.clone());
+
+        let thread = thread::spawn(move || {
+            let mut reader = TelemetryReader::new(WireByteOrder::Little);
+            let mut received: Vec<u8> = vec![];
+            let mut balance_data: Option<u32> = None;
+            let mut buf = [0u8; 4096];
+            while !thread_stopping.load(Ordering::SeqCst) {
+                match stream.read(&mut buf) {
+                    Ok(0) => break,
+                    Ok(n) => received.extend_from_slice(&buf[..n]),
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => continue,
+                    Err(_) => break
+                }
+                let at = started.elapsed().as_secs_f64();
+                while let Some((len, frame)) = reader.read(&received).unwrap_or_else(|e| panic!("Bad telemetry: {}", e)) {
+                    received.drain(..len);
+                    match frame {
+                        Frame::Definition(definition) if definition.name == "balance-data" => {
+                            balance_data = Some(definition.id);
+                            thread_ready.store(true, Ordering::SeqCst);
+                        },
+                        Frame::Record { stream_id, values, .. } if Some(stream_id) == balance_data => {
+                            thread_records.lock().unwrap().push(Record {
+                                at,
+                                state: State::from_value(number(&values, "state") as u8),
+                                duty_l: number(&values, "duty_l"),
+                                duty_r: number(&values, "duty_r"),
+                                enabled: number(&values, "enabled") != 0.0,
+                            });
+                        },
+                        _ => {}
+                    }
+                }
+            }
+            thread_closed.store(true, Ordering::SeqCst);
+        });
+
+        TelemetryClient { records, ready, closed, stopping, thread: Some(thread) }
+    }
+
+    fn wait_for(flag: &AtomicBool, timeout: Duration) -> bool {
+        let started = Instant::now();
+        while !flag.load(Ordering::SeqCst) {
+            if started.elapsed() >= timeout {
+                return false;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        true
+    }
+}
+
+impl Drop for TelemetryClient {
+    fn drop(&mut self) {
+        self.stopping.store(true, Ordering::SeqCst);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+
+// Balance on a SimulatedRover, listened to over telemetry from before its first loop. Holds
+// lock_boards while it lives.
+pub struct TestHarness {
+    pub rover: SimulatedRover,
+    telemetry: TelemetryClient,
+    _boards: MutexGuard<'static, ()>,
+}
+
+impl TestHarness {
+    // The BalanceControl is for whatever drives the scenario, directly or through MQTT dispatch.
+    pub fn start() -> (TestHarness, BalanceControl) {
+        let boards = lock_boards();
+        let started = Instant::now();
+        let rover = SimulatedRover::new();
+        let balance = Balance::new(rover.hardware(), None, None, Timebase::Monotonic, false, None, false, &SafeState::new());
+
+        let telemetry = TelemetryClient::connect(balance.telemetry_port(), started);
+        assert!(TelemetryClient::wait_for(&telemetry.ready, TELEMETRY_READY_TIMEOUT), "No balance-data definition within {:?}", TELEMETRY_READY_TIMEOUT);
+
+        (TestHarness { rover, telemetry, _boards: boards }, balance.start())
+    }
+
+    // Takes the steps at their times and returns once the scenario's duration is up.
+    pub fn run(&mut self, scenario: &Scenario, driver: &mut dyn Driver) {
+        let started = Instant::now();
+        let wait_until = |time: f64| {
+            let at = started + Duration::from_secs_f64(time);
+            let now = Instant::now();
+            if at > now {
+                thread::sleep(at - now);
+            }
+        };
+        for (time, step) in &scenario.steps {
+            wait_until(*time);
+            match step {
+                Step::Start => driver.start_balancing(),
+                Step::Stop => driver.stop_balancing(),
+                Step::EStop => driver.estop(),
+                Step::Reset => driver.reset(),
+                Step::Configure(topic, value) => driver.configure(topic, *value),
+                Step::Bump(pitch) => self.rover.bump(*pitch),
+                Step::SensorDropout(duration) => self.rover.sensor_dropout(Duration::from_secs_f64(*duration)),
+            }
+        }
+        wait_until(scenario.duration);
+    }
+
+    // Times stop, which is to stop Balance and return true when it finished cleanly, and waits
+    // for the telemetry server to close the connection as it goes.
+    pub fn shut_down(&mut self, stop: impl FnOnce() -> bool) -> Duration {
+        let started = Instant::now();
+        assert!(stop(), "Balance did not finish cleanly");
+        let shutdown = started.elapsed();
+        assert!(TelemetryClient::wait_for(&self.telemetry.closed, TELEMETRY_CLOSE_TIMEOUT), "Telemetry connection still open {:?} after shutting down", TELEMETRY_CLOSE_TIMEOUT);
+        shutdown
+    }
+
+    // Every balance-data record so far.
+    pub fn records(&self) -> Vec<Record> {
+        self.telemetry.records.lock().unwrap().clone()
+    }
+
+    // States the records went through, each once per visit.
+    pub fn transitions(&self) -> Vec<State> {
+        let mut states: Vec<State> = self.records().iter().filter_map(|record| record.state).collect();
+        states.dedup();
+        states
+    }
+
+    // Whenever neither balancing nor manual drive has the motors, both duties are zero; they are
+    // never past full scale.
+    pub fn assert_motors_stopped_unless_driven(&self) {
+        for record in self.records() {
+            let state = record.state.unwrap_or_else(|| panic!("Unknown state at {:.3}s", record.at));
+            assert!(record.duty_l.abs() <= 1.0 && record.duty_r.abs() <= 1.0, "Duties past full scale at {:.3}s: {:?}", record.at, record);
+            if state != State::Balancing && state != State::Manual {
+                assert!(record.duty_l == 0.0 && record.duty_r == 0.0, "Motors driven while {} at {:.3}s: {:?}", state.name(), record.at, record);
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::orientation::{self, ROVER_AXIS_MAPPING};
+
+    fn bus(rover: &SimulatedRover) -> SimulatedBus {
+        SimulatedBus::new(&rover.body, true)
+    }
+
+    #[test]
+    fn tilted_rover_reads_as_that_pitch_from_the_accelerometer() {
+        let rover = SimulatedRover::new();
+        rover.body.lock().unwrap().pitch = 30.0;
+        let mut bus = bus(&rover);
+        bus.set_slave_address(ACCEL_ADDRESS as u16).unwrap();
+        let (x, y, z) = bus.accel_sample();
+        assert_eq!(y, 0);
+        let (x, y, z) = ROVER_AXIS_MAPPING.to_body(x as f64, y as f64, z as f64);
+        assert!((orientation::tilt_about_y(x, y, z) - 30.0).abs() < 0.5);
+    }
+
+    #[test]
+    fn gyro_fifo_fills_at_its_rate_and_overruns_past_its_depth() {
+        let rover = SimulatedRover::new();
+        let mut bus = bus(&rover);
+        bus.set_slave_address(GYRO_ADDRESS as u16).unwrap();
+        // 800Hz
+        bus.smbus_write_byte(GYRO_CTRL_REG1, 0xCF).unwrap();
+        let started = bus.gyro.started.unwrap();
+        assert_eq!(bus.gyro.waiting(started + Duration::from_millis(10)), 8);
+        assert!(!bus.gyro.overrun);
+        assert_eq!(bus.gyro.waiting(started + Duration::from_millis(100)), FIFO_DEPTH);
+        assert!(bus.gyro.overrun);
+
+        bus.gyro.clear(started + Duration::from_millis(100));
+        assert_eq!(bus.gyro.waiting(started + Duration::from_millis(100)), 0);
+        assert!(!bus.gyro.overrun);
+    }
+
+    #[test]
+    fn dropped_out_gyro_takes_no_samples() {
+        let rover = SimulatedRover::new();
+        let mut bus = bus(&rover);
+        bus.set_slave_address(GYRO_ADDRESS as u16).unwrap();
+        bus.smbus_write_byte(GYRO_CTRL_REG1, 0xCF).unwrap();
+        rover.sensor_dropout(Duration::from_secs(10));
+        thread::sleep(Duration::from_millis(10));
+        assert_eq!(bus.smbus_read_byte(GYRO_STATUS_REG).unwrap(), 0);
+        assert_eq!(bus.smbus_read_byte(GYRO_FIFO_SRC_REG).unwrap(), 0);
+    }
+
+    #[test]
+    fn bumped_rover_goes_over_and_comes_back_upright() {
+        let rover = SimulatedRover::new();
+        rover.bump(40.0);
+        thread::sleep(BUMP_TIME / 2);
+        assert_eq!(rover.pitch(), 40.0);
+        thread::sleep(BUMP_TIME);
+        assert_eq!(rover.pitch(), 0.0);
+    }
+
+    // Straight to BalanceControl; the full 10s run, through MQTT, is in the balancing-rover binary.
+    #[test]
+    fn bump_past_the_cutoff_stops_the_motors_until_upright_again() {
+        let (mut harness, mut balance_control) = TestHarness::start();
+        let scenario = Scenario::new(2.5)
+            .at(0.5, Step::Configure("balance/max_degree", 30.0))
+            .at(1.0, Step::Bump(40.0));
+        harness.run(&scenario, &mut balance_control);
+        assert!(harness.shut_down(|| balance_control.stop()) < SHUTDOWN_TIMEOUT);
+
+        harness.assert_motors_stopped_unless_driven();
+        assert_eq!(harness.transitions(), vec![State::Balancing, State::WaitingForReady, State::Balancing]);
+    }
+}
@@ -0,0 +1,188 @@
+//
+// Copyright (C) 2020 Abstract Horizon
+// All rights reserved. This program and the accompanying materials
+// are made available under the terms of the Apache License v2.0
+// which accompanies this distribution, and is available at
+// https://www.apache.org/licenses/LICENSE-2.0
+//
+//  Contributors:
+//    Daniel Sendula - initial API and implementation
+//
+
+// Stand in for the storage service, for setups without one (enabled with --storage <file>):
+//
+//   storage/read/<key>   - answered with the stored value on storage/write/<key>; keys never
+//                          written are not answered, as the storage service does
+//   storage/write/<key>  - the payload is kept as the value of key
+//
+// Values are kept in a TOML file, one quoted key and string per line:
+//
+//   "balance/pid/p" = "0.75"
+//   "balance/profiles" = "active=carpet\n[carpet]\n..."
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+
+pub const READ_PREFIX: &str = "storage/read/";
+pub const WRITE_PREFIX: &str = "storage/write/";
+
+
+pub struct Storage {
+    path: PathBuf,
+    values: BTreeMap<String, String>,
+}
+
+impl Storage {
+    // A missing file starts empty; lines that cannot be read are skipped, so one bad line does
+    // not lose every other value.
+    pub fn open(path: &Path) -> Storage {
+        let values = match fs::read_to_string(path) {
+            Ok(text) => {
+                let (values, bad_lines) = from_toml(&text);
+                if !bad_lines.is_empty() {
+                    warn!("Skipped unreadable lines {:?} of storage file {:?}", bad_lines, path);
+                }
+                values
+            },
+            Err(e) if e.kind() == io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => {
+                warn!("Cannot read storage file {:?}, starting empty: {:?}", path, e);
+                BTreeMap::new()
+            }
+        };
+        Storage { path: path.to_path_buf(), values }
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.values.get(key).map(|value| value.as_str())
+    }
+
+    pub fn key_count(&self) -> usize {
+        self.values.len()
+    }
+
+    // The file is only written when the value changed, as our own replies come back as writes.
+    pub fn set(&mut self, key: &str, value: &str) -> io::Result<()> {
+        if self.get(key) == Some(value) {
+            return Ok(());
+        }
+        self.values.insert(key.to_string(), value.to_string());
+        self.save()
+    }
+
+    // Written next to the file and renamed over it, so power loss leaves the old or the new one.
+    fn save(&self) -> io::Result<()> {
+        let mut tmp = self.path.clone().into_os_string();
+        tmp.push(".tmp");
+        fs::write(&tmp, to_toml(&self.values))?;
+        fs::rename(&tmp, &self.path)
+    }
+}
+
+pub fn to_toml(values: &BTreeMap<String, String>) -> String {
+    values.iter().map(|(key, value)| format!("{} = {}\n", quote(key), quote(value))).collect()
+}
+
+// Values and the numbers (1 based) of lines that are neither a key = value pair, a comment nor empty.
+pub fn from_toml(text: &str) -> (BTreeMap<String, String>, Vec<usize>) {
+    let mut values = BTreeMap::new();
+    let mut bad_lines = vec![];
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        match parse_line(line) {
+            Some((key, value)) => { values.insert(key, value); },
+            None => bad_lines.push(i + 1)
+        }
+    }
+    (values, bad_lines)
+}
+
+fn parse_line(line: &str) -> Option<(String, String)> {
+    let (key, rest) = unquote(line)?;
+    let rest = rest.trim_start().strip_prefix('=')?.trim_start();
+    let (value, rest) = unquote(rest)?;
+    if rest.trim().is_empty() { Some((key, value)) } else { None }
+}
+
+fn quote(s: &str) -> String {
+    let mut quoted = String::with_capacity(s.len() + 2);
+    quoted.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if c.is_control() => quoted.push_str(&format!("\\u{:04X}", c as u32)),
+            c => quoted.push(c)
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+// A basic string at the start of s and what follows it.
+fn unquote(s: &str) -> Option<(String, &str)> {
+    let mut chars = s.strip_prefix('"')?.char_indices();
+    let mut unquoted = String::new();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' => return Some((unquoted, &s[i + 2..])),
+            '\\' => match chars.next()?.1 {
+                '"' => unquoted.push('"'),
+                '\\' => unquoted.push('\\'),
+                'n' => unquoted.push('\n'),
+                'r' => unquoted.push('\r'),
+                't' => unquoted.push('\t'),
+                'u' => {
+                    let hex: String = (0..4).filter_map(|_| chars.next().map(|(_, c)| c)).collect();
+                    unquoted.push(std::char::from_u32(u32::from_str_radix(&hex, 16).ok()?)?);
+                },
+                _ => return None
+            },
+            c => unquoted.push(c)
+        }
+    }
+    None
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TempDir;
+
+    #[test]
+    fn values_with_quotes_newlines_and_control_characters_survive_a_restart() {
+        let dir = TempDir::new("storage");
+        let path = dir.path().join("storage.toml");
+
+        let mut store = Storage::open(&path);
+        assert_eq!(store.key_count(), 0);
+        assert_eq!(store.get("balance/pid/p"), None);
+
+        let profiles = "active=carpet\n[carpet]\npid_kp=0.75\n\"quoted\" \\ tab\t bell\u{7}";
+        store.set("balance/pid/p", "0.75").unwrap();
+        store.set("balance/profiles", profiles).unwrap();
+        store.set("balance/pid/p", "0.8").unwrap();
+
+        let reopened = Storage::open(&path);
+        assert_eq!(reopened.key_count(), 2);
+        assert_eq!(reopened.get("balance/pid/p"), Some("0.8"));
+        assert_eq!(reopened.get("balance/profiles"), Some(profiles));
+    }
+
+    #[test]
+    fn damaged_line_only_loses_its_own_value() {
+        let (values, bad_lines) = from_toml("# comment\n\n\"a\" = \"1\"\n\"b\" = \"unterminated\n\"c\" = \"3\" trailing\n\"d\"=\"4\"\n");
+        assert_eq!(values.keys().collect::<Vec<_>>(), vec!["a", "d"]);
+        assert_eq!(bad_lines, vec![4, 5]);
+    }
+}
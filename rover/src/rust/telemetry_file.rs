@@ -0,0 +1,338 @@
+//
+// Copyright (C) 2020 Abstract Horizon
+// All rights reserved. This program and the accompanying materials
+// are made available under the terms of the Apache License v2.0
+// which accompanies this distribution, and is available at
+// https://www.apache.org/licenses/LICENSE-2.0
+//
+//  Contributors:
+//    Daniel Sendula - initial API and implementation
+//
+
+// Keeps telemetry in local files for when no client is in range. Every file holds exactly what a
+// TCP client asking for little endian byte order receives: STRS and the STDF definitions, a META
+// frame, then records. Files are named <path>.0, <path>.1, ... starting after the highest one
+// already there, so a restart never overwrites earlier runs; a new one is started whenever the
+// current one would grow past the size limit.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crossbeam_channel::{bounded, Sender, Receiver, RecvTimeoutError, TrySendError};
+
+use crate::telemetry_reader::{Frame, TelemetryReader};
+use crate::telemetry_socket_server::{clock, definitions_frames, meta_frame_at};
+use crate::telemetry_stream::WireByteOrder;
+
+
+pub const DEFAULT_FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+pub const DEFAULT_MAX_FILE_SIZE: u64 = 64 * 1024 * 1024;
+
+// Records queued for the file writer; anything over this is dropped rather than holding up the logging thread.
+const QUEUE_LENGTH: usize = 1024;
+const WRITE_BUFFER_SIZE: usize = 64 * 1024;
+const REOPEN_INTERVAL: Duration = Duration::from_secs(1);
+const ERROR_LOG_INTERVAL: Duration = Duration::from_secs(10);
+
+
+pub struct FileOutputConfig {
+    pub path: String,
+    // Buffered records are written out at least this often
+    pub flush_interval: Duration,
+    pub max_file_size: u64,
+}
+
+#[derive(Default)]
+pub struct FileCounters {
+    pub records: AtomicUsize,
+    pub dropped: AtomicUsize,
+    pub files: AtomicUsize,
+}
+
+// Fed by the logging thread; writing to the file happens on its own thread.
+pub struct FileTelemetrySink {
+    // Definitions of every stream so far, shared with the writer; they only ever grow
+    definitions: Arc<Mutex<Vec<Vec<u8>>>>,
+    sender: Option<Sender<Vec<u8>>>,
+    counters: Arc<FileCounters>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl FileTelemetrySink {
+    pub fn start(config: FileOutputConfig, stream_definitions: &[Vec<u8>], started: Instant,
+                 counters: Arc<FileCounters>, capabilities: Arc<Mutex<Vec<u8>>>) -> FileTelemetrySink {
+        let (sender, receiver) = bounded(QUEUE_LENGTH);
+        let writer_counters = counters.clone();
+        let definitions = Arc::new(Mutex::new(stream_definitions.to_vec()));
+        let writer_definitions = definitions.clone();
+
+        FileTelemetrySink {
+            definitions,
+            sender: Some(sender),
+            counters,
+            thread: Some(thread::spawn(move || {
+                file_writer(config, writer_definitions, receiver, started, writer_counters, capabilities);
+            })),
+        }
+    }
+
+    // For a stream registered after the sink started; written to the open file ahead of the next
+    // record and at the start of every file after it.
+    pub fn add_definition(&mut self, definition: Vec<u8>) {
+        self.definitions.lock().unwrap().push(definition);
+    }
+
+    // Never blocks; records that do not fit in the queue are counted and dropped.
+    pub fn offer(&mut self, record: &[u8]) {
+        if let Some(sender) = &self.sender {
+            match sender.try_send(record.to_vec()) {
+                Ok(()) => {},
+                Err(TrySendError::Full(_)) | Err(TrySendError::Disconnected(_)) => {
+                    self.counters.dropped.fetch_add(1, Ordering::SeqCst);
+                }
+            }
+        }
+    }
+
+    // Writes out whatever is still buffered.
+    pub fn stop(&mut self) {
+        self.sender = None;
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+struct OpenFile {
+    writer: BufWriter<File>,
+    size: u64,
+    // How many of the definitions the file has
+    definitions: usize,
+}
+
+fn file_writer(config: FileOutputConfig, definitions: Arc<Mutex<Vec<Vec<u8>>>>, receiver: Receiver<Vec<u8>>,
+               started: Instant, counters: Arc<FileCounters>, capabilities: Arc<Mutex<Vec<u8>>>) {
+    let mut file: Option<OpenFile> = None;
+    let mut next_index = next_file_index(&config.path);
+    let mut last_open_attempt: Option<Instant> = None;
+    let mut last_flush = Instant::now();
+    let mut last_error_log: Option<Instant> = None;
+
+    let mut log_error = |message: String| {
+        if last_error_log.is_none_or(|last| last.elapsed() >= ERROR_LOG_INTERVAL) {
+            warn!("{}", message);
+            last_error_log = Some(Instant::now());
+        }
+    };
+
+    loop {
+        let record = match receiver.recv_timeout(config.flush_interval) {
+            Ok(record) => Some(record),
+            Err(RecvTimeoutError::Timeout) => None,
+            Err(RecvTimeoutError::Disconnected) => break
+        };
+
+        if let Some(record) = &record {
+            let full = file.as_ref().is_some_and(|open| open.size + record.len() as u64 > config.max_file_size);
+            if full {
+                if let Some(mut open) = file.take() {
+                    let _ = open.writer.flush();
+                }
+                last_open_attempt = None;
+            }
+            if file.is_none() && last_open_attempt.is_none_or(|last| last.elapsed() >= REOPEN_INTERVAL) {
+                last_open_attempt = Some(Instant::now());
+                let path = file_path(&config.path, next_index);
+                match open_file(&path, &definitions, started, &capabilities) {
+                    Ok(open) => {
+                        info!("Telemetry file {}", path);
+                        counters.files.fetch_add(1, Ordering::SeqCst);
+                        next_index += 1;
+                        file = Some(open);
+                    },
+                    Err(e) => log_error(format!("Cannot create telemetry file {}: {:?}", path, e))
+                }
+            }
+            if let Some(open) = &mut file {
+                if let Err(e) = write_new_definitions(open, &definitions) {
+                    log_error(format!("Telemetry file {} failed, starting a new one: {:?}", config.path, e));
+                    file = None;
+                }
+            }
+            match &mut file {
+                Some(open) => match open.writer.write_all(record) {
+                    Ok(()) => {
+                        open.size += record.len() as u64;
+                        counters.records.fetch_add(1, Ordering::SeqCst);
+                    },
+                    Err(e) => {
+                        log_error(format!("Telemetry file {} failed, starting a new one: {:?}", config.path, e));
+                        counters.dropped.fetch_add(1, Ordering::SeqCst);
+                        file = None;
+                    }
+                },
+                None => {
+                    counters.dropped.fetch_add(1, Ordering::SeqCst);
+                }
+            }
+        }
+
+        if last_flush.elapsed() >= config.flush_interval {
+            last_flush = Instant::now();
+            if let Some(open) = &mut file {
+                if let Err(e) = open.writer.flush() {
+                    log_error(format!("Cannot flush telemetry file {}: {:?}", config.path, e));
+                }
+            }
+        }
+    }
+    if let Some(mut open) = file {
+        let _ = open.writer.flush();
+    }
+    println!("Finishing telemetry file thread.");
+}
+
+fn open_file(path: &str, definitions: &Mutex<Vec<Vec<u8>>>, started: Instant, capabilities: &Mutex<Vec<u8>>) -> io::Result<OpenFile> {
+    let mut writer = BufWriter::with_capacity(WRITE_BUFFER_SIZE, OpenOptions::new().write(true).create_new(true).open(path)?);
+    let (wall, monotonic) = clock(started);
+    let meta = meta_frame_at(wall, monotonic, WireByteOrder::Little, &capabilities.lock().unwrap());
+    let definitions = definitions.lock().unwrap();
+    let frames = definitions_frames(&definitions, WireByteOrder::Little);
+    writer.write_all(&frames)?;
+    writer.write_all(&meta)?;
+    Ok(OpenFile { writer, size: (frames.len() + meta.len()) as u64, definitions: definitions.len() })
+}
+
+// Definitions added since the file was opened, as an STRS with just them, as TCP clients get them.
+fn write_new_definitions(open: &mut OpenFile, definitions: &Mutex<Vec<Vec<u8>>>) -> io::Result<()> {
+    let definitions = definitions.lock().unwrap();
+    if open.definitions < definitions.len() {
+        let frames = definitions_frames(&definitions[open.definitions..], WireByteOrder::Little);
+        open.writer.write_all(&frames)?;
+        open.size += frames.len() as u64;
+        open.definitions = definitions.len();
+    }
+    Ok(())
+}
+
+pub fn file_path(path: &str, index: usize) -> String {
+    format!("{}.{}", path, index)
+}
+
+// One past the highest index of the files already written with this path.
+fn next_file_index(path: &str) -> usize {
+    let path = Path::new(path);
+    let prefix = match path.file_name() {
+        Some(name) => format!("{}.", name.to_string_lossy()),
+        None => return 0
+    };
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new(".")
+    };
+    fs::read_dir(dir).map(|entries| {
+        entries.filter_map(|entry| entry.ok())
+            .filter_map(|entry| {
+                let name = entry.file_name().to_string_lossy().into_owned();
+                if name.starts_with(&prefix) { name[prefix.len()..].parse::<usize>().ok() } else { None }
+            })
+            .map(|index| index + 1)
+            .max()
+            .unwrap_or(0)
+    }).unwrap_or(0)
+}
+
+
+// Records of a telemetry file, in the order they were logged. A record cut short at the end of the
+// file, as left by losing power, ends the iteration quietly; anything else that does not decode is
+// returned as an error, after which the iteration ends.
+pub struct FileRecords {
+    buf: Vec<u8>,
+    pos: usize,
+    reader: TelemetryReader,
+}
+
+pub fn read_records(path: &str) -> io::Result<FileRecords> {
+    Ok(FileRecords { buf: fs::read(path)?, pos: 0, reader: TelemetryReader::new(WireByteOrder::Little) })
+}
+
+impl Iterator for FileRecords {
+    type Item = Result<Frame, String>;
+
+    fn next(&mut self) -> Option<Result<Frame, String>> {
+        while self.pos < self.buf.len() {
+            match self.reader.read(&self.buf[self.pos..]) {
+                Ok(Some((len, frame))) => {
+                    self.pos += len;
+                    if let Frame::Record { .. } = frame {
+                        return Some(Ok(frame));
+                    }
+                },
+                Ok(None) => break,
+                Err(e) => {
+                    self.pos = self.buf.len();
+                    return Some(Err(e));
+                }
+            }
+        }
+        self.pos = self.buf.len();
+        None
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::telemetry_reader::Value;
+    use crate::telemetry_socket_server::SocketTelemetryServerBuilder;
+    use crate::telemetry_stream::{Storable, TelemetryStreamDefinition};
+    use crate::test_support::TempDir;
+    use super::*;
+
+    // Files read back in order across rotations, after the one an earlier run left.
+    #[test]
+    fn logged_records_read_back_in_order_across_rotations() {
+        let dir = TempDir::new("telemetry-file");
+        let path = dir.file("run.bin");
+        fs::write(file_path(&path, 4), b"earlier run").unwrap();
+
+        let mut builder = SocketTelemetryServerBuilder::new();
+        let stream = builder.register_stream(TelemetryStreamDefinition::new("counter", 1, vec![
+            TelemetryStreamDefinition::unsigned_byte_field("i"),
+        ])).unwrap();
+        builder.file_output(&path, Duration::from_millis(50), 1024);
+        let server = builder.create(0);
+        for i in 0..200u8 {
+            log_with_time!(server, stream, i);
+        }
+        // Let the writer catch up, so none are dropped from its queue when stopping
+        thread::sleep(Duration::from_millis(200));
+        let stats = server.stats();
+        server.stop().unwrap();
+
+        assert_eq!((stats.file_records, stats.file_dropped), (200, 0), "{:?}", stats);
+        assert!(stats.files >= 2, "{:?}", stats);
+        let mut logged = vec![];
+        for index in 5..5 + stats.files {
+            for frame in read_records(&file_path(&path, index)).unwrap() {
+                match frame.unwrap() {
+                    Frame::Record { values, .. } => logged.push(values[0].1.clone()),
+                    other => panic!("not a record: {:?}", other)
+                }
+            }
+        }
+        assert_eq!(logged, (0..200u64).map(Value::Unsigned).collect::<Vec<Value>>());
+
+        // Cut short by power loss, a file still reads up to its last whole record
+        let first = fs::read(file_path(&path, 5)).unwrap();
+        let truncated = dir.file("truncated.bin");
+        fs::write(&truncated, &first[..first.len() - 3]).unwrap();
+        let records: Vec<Frame> = read_records(&truncated).unwrap().collect::<Result<_, String>>().unwrap();
+        assert_eq!(records.len(), read_records(&file_path(&path, 5)).unwrap().count() - 1);
+    }
+}
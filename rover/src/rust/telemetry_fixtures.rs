@@ -0,0 +1,360 @@
+//
+// Copyright (C) 2020 Abstract Horizon
+// All rights reserved. This program and the accompanying materials
+// are made available under the terms of the Apache License v2.0
+// which accompanies this distribution, and is available at
+// https://www.apache.org/licenses/LICENSE-2.0
+//
+//  Contributors:
+//    Daniel Sendula - initial API and implementation
+//
+
+// Conformance fixtures for the telemetry wire format: an example of every frame and of every
+// field type, boundary values included, as a client receives them over TCP (in both byte
+// orders) and over the serial line. manifest.json lists the frames a client should decode from
+// each file, in order.
+//
+// The files are checked in under src/rust/telemetry_fixtures/ for clients (the Python viewer)
+// to test their parsers against. cargo test fails when they no longer match what is generated
+// here, so any change to the wire format has to come with regenerated fixtures:
+//
+//   cargo run --bin telemetry-fixtures -- src/rust/telemetry_fixtures
+
+use crate::telemetry_reader::{FieldDefinition, Frame, StreamDefinition, Value};
+use crate::telemetry_serial::{self, FRAME_DEFINITIONS, FRAME_META, FRAME_RECORD};
+use crate::telemetry_socket_server::{definitions_frames, meta_frame_at, pong_frame, BUSY_FRAME};
+use crate::telemetry_stream::{Storable, TelemetryStreamDefinition, Timebase, WireByteOrder};
+
+
+// Bump when the manifest's layout changes
+pub const MANIFEST_VERSION: u32 = 2;
+
+const ALL_TYPES_STREAM_ID: u32 = 1;
+// Over 255, so the record header has a two byte stream id and length
+const LONG_RECORD_STREAM_ID: u32 = 300;
+const STRING_SIZE: usize = 16;
+const BYTES_SIZE: usize = 8;
+const LONG_STRING_SIZE: usize = 300;
+const LONG_BYTES_SIZE: usize = 255;
+
+const WALL: f64 = 1600000000.25;
+const MONOTONIC: f64 = 12.5;
+const CLIENT_TIME: f64 = 1234.5;
+const CAPABILITIES: &str = "{ \"version\" : 1, \"board\" : \"unavailable\" }";
+
+const DECISIONS: [&str; 5] = [
+    "records come in the connection's byte order; the definitions always say little",
+    "signed is the string true or false, not a JSON boolean",
+    "strings are UTF-8, NUL padded to the field size; trailing NULs are not part of the value",
+    "floats and doubles are passed on as they are; NaN and infinities are written as the strings NaN, inf and -inf here",
+    "record times are in the definition's timebase: wall clock seconds, or monotonic seconds as in META frames; definitions without one are wall clock",
+];
+
+
+pub struct FixtureFile {
+    pub name: &'static str,
+    pub byte_order: WireByteOrder,
+    pub bytes: Vec<u8>,
+    // Over TCP: the frames in order
+    pub frames: Vec<Frame>,
+    // Over serial: type of each serial frame with the frames in its payload
+    pub serial_frames: Vec<(u8, Vec<Frame>)>,
+}
+
+impl FixtureFile {
+    fn to_json(&self) -> String {
+        let (transport, frames) = if self.serial_frames.is_empty() {
+            ("tcp", frames_to_json(&self.frames))
+        } else {
+            let serial_frames: Vec<String> = self.serial_frames.iter()
+                .map(|(frame_type, frames)| format!("{{ \"type\" : \"{}\", \"payload\" : {} }}", *frame_type as char, frames_to_json(frames)))
+                .collect();
+            ("serial", format!("[\n    {}\n  ]", serial_frames.join(",\n    ")))
+        };
+        let byte_order = match self.byte_order { WireByteOrder::Little => "little", WireByteOrder::Network => "network" };
+        format!("{{ \"name\" : \"{}\", \"transport\" : \"{}\", \"byte_order\" : \"{}\", \"frames\" : {} }}", self.name, transport, byte_order, frames)
+    }
+}
+
+fn frames_to_json(frames: &[Frame]) -> String {
+    let frames: Vec<String> = frames.iter().map(|frame| frame.to_json()).collect();
+    format!("[\n    {}\n  ]", frames.join(",\n    "))
+}
+
+pub fn manifest(files: &[FixtureFile]) -> String {
+    let decisions: Vec<String> = DECISIONS.iter().map(|decision| format!("\"{}\"", decision)).collect();
+    let files: Vec<String> = files.iter().map(|file| file.to_json()).collect();
+    format!("{{ \"version\" : {},\n  \"decisions\" : [\n    {}\n  ],\n  \"files\" : [\n  {}\n  ]\n}}\n",
+        MANIFEST_VERSION, decisions.join(",\n    "), files.join(",\n  "))
+}
+
+
+fn streams() -> Vec<TelemetryStreamDefinition> {
+    vec![
+        TelemetryStreamDefinition::new("all-types", ALL_TYPES_STREAM_ID, vec![
+            TelemetryStreamDefinition::unsigned_byte_field("ub"),
+            TelemetryStreamDefinition::signed_byte_field("sb"),
+            TelemetryStreamDefinition::unsigned_word_field("uw"),
+            TelemetryStreamDefinition::signed_word_field("sw"),
+            TelemetryStreamDefinition::unsigned_integer_field("ui"),
+            TelemetryStreamDefinition::signed_integer_field("si"),
+            TelemetryStreamDefinition::unsigned_long_field("ul"),
+            TelemetryStreamDefinition::signed_long_field("sl"),
+            TelemetryStreamDefinition::float_field("f"),
+            TelemetryStreamDefinition::double_field("d"),
+            TelemetryStreamDefinition::string_field("s", STRING_SIZE),
+            TelemetryStreamDefinition::bytes_field("a", BYTES_SIZE),
+        ]),
+        TelemetryStreamDefinition::new("long-record", LONG_RECORD_STREAM_ID, vec![
+            TelemetryStreamDefinition::string_field("text", LONG_STRING_SIZE),
+            TelemetryStreamDefinition::bytes_field("data", LONG_BYTES_SIZE),
+        ]).with_timebase(Timebase::Monotonic),
+    ]
+}
+
+// Strings are written as they are; shorter ones must be padded by whoever logs them.
+fn padded(s: &str, size: usize) -> String {
+    let mut padded = s.to_string();
+    while padded.len() < size {
+        padded.push('\0');
+    }
+    padded
+}
+
+struct AllTypes(u8, i8, u16, i16, u32, i32, u64, i64, f32, f64, &'static str, [u8; BYTES_SIZE]);
+
+fn all_types_records() -> Vec<AllTypes> {
+    vec![
+        AllTypes(0, 0, 0, 0, 0, 0, 0, 0, 0.0, 0.0, "", [0; BYTES_SIZE]),
+        AllTypes(u8::MAX, i8::MAX, u16::MAX, i16::MAX, u32::MAX, i32::MAX, u64::MAX, i64::MAX, f32::MAX, f64::MAX, "ABCDEFGHIJKLMNOP", [255; BYTES_SIZE]),
+        AllTypes(1, i8::MIN, 1, i16::MIN, 1, i32::MIN, 1, i64::MIN, f32::MIN, f64::MIN, "grüße", [0, 1, 2, 3, 4, 5, 6, 7]),
+        // Distinct bytes, so a wrong byte order shows
+        AllTypes(0x12, -1, 0x1234, -2, 0x12345678, -3, 0x0123456789abcdef, -4, f32::NAN, f64::NAN, "NaN", [0x80, 0x7f, 0, 0, 0, 0, 0, 1]),
+        AllTypes(0xfe, -128, 0xfffe, -32768, 0xfffffffe, -2147483648, 0xfffffffffffffffe, -9223372036854775808, f32::INFINITY, f64::NEG_INFINITY, "infinities", [1; BYTES_SIZE]),
+        AllTypes(2, 2, 2, 2, 2, 2, 2, 2, -0.0, f64::from_bits(1), "-0.0 and 5e-324", [2; BYTES_SIZE]),
+        AllTypes(3, 3, 3, 3, 3, 3, 3, 3, f32::from_bits(1), f64::EPSILON, "subnormal float", [3; BYTES_SIZE]),
+    ]
+}
+
+fn record_time(timebase: Timebase, index: usize) -> f64 {
+    let start = match timebase {
+        Timebase::Wall => WALL,
+        Timebase::Monotonic => MONOTONIC,
+    };
+    start + index as f64 * 0.005
+}
+
+// Records in little endian as the logging thread gets them, with the values a client should decode.
+fn records(streams: &[TelemetryStreamDefinition]) -> Vec<(Vec<u8>, Frame)> {
+    let mut records = vec![];
+    for (index, r) in all_types_records().iter().enumerate() {
+        let stream = &streams[0];
+        let time = record_time(stream.timebase(), index);
+        let text = padded(r.10, STRING_SIZE);
+        let mut buf = vec![];
+        stream.write_header(&mut buf);
+        time.store(&mut buf);
+        r.0.store(&mut buf);
+        r.1.store(&mut buf);
+        r.2.store(&mut buf);
+        r.3.store(&mut buf);
+        r.4.store(&mut buf);
+        r.5.store(&mut buf);
+        r.6.store(&mut buf);
+        r.7.store(&mut buf);
+        r.8.store(&mut buf);
+        r.9.store(&mut buf);
+        (&text).store(&mut buf);
+        (&r.11[..]).store(&mut buf);
+        assert_eq!(buf.len(), stream.size());
+
+        let values = vec![
+            ("ub", Value::Unsigned(r.0 as u64)),
+            ("sb", Value::Signed(r.1 as i64)),
+            ("uw", Value::Unsigned(r.2 as u64)),
+            ("sw", Value::Signed(r.3 as i64)),
+            ("ui", Value::Unsigned(r.4 as u64)),
+            ("si", Value::Signed(r.5 as i64)),
+            ("ul", Value::Unsigned(r.6)),
+            ("sl", Value::Signed(r.7)),
+            ("f", Value::Float(r.8 as f64)),
+            ("d", Value::Float(r.9)),
+            ("s", Value::Text(r.10.to_string())),
+            ("a", Value::Bytes(r.11.to_vec())),
+        ].into_iter().map(|(name, value)| (name.to_string(), value)).collect();
+        records.push((buf, Frame::Record { stream_id: ALL_TYPES_STREAM_ID, time, values }));
+    }
+
+    let stream = &streams[1];
+    let time = record_time(stream.timebase(), records.len());
+    let text: String = (0..LONG_STRING_SIZE).map(|i| (b'a' + (i % 26) as u8) as char).collect();
+    let data: Vec<u8> = (0..LONG_BYTES_SIZE).map(|i| i as u8).collect();
+    let mut buf = vec![];
+    stream.write_header(&mut buf);
+    time.store(&mut buf);
+    (&text).store(&mut buf);
+    (&data).store(&mut buf);
+    assert_eq!(buf.len(), stream.size());
+    let values = vec![("text".to_string(), Value::Text(text)), ("data".to_string(), Value::Bytes(data))];
+    records.push((buf, Frame::Record { stream_id: LONG_RECORD_STREAM_ID, time, values }));
+
+    records
+}
+
+fn f64_bytes(value: f64, byte_order: WireByteOrder) -> Vec<u8> {
+    let mut bytes = value.to_bits().to_le_bytes().to_vec();
+    if byte_order == WireByteOrder::Network {
+        bytes.reverse();
+    }
+    bytes
+}
+
+// A TCP connection from the handshake on: PONG, definitions, META before the capabilities are
+// known, every record and META with capabilities.
+fn tcp_file(name: &'static str, byte_order: WireByteOrder) -> FixtureFile {
+    let streams = streams();
+    let definitions: Vec<Vec<u8>> = streams.iter().map(|stream| stream.to_json().into_bytes()).collect();
+
+    let mut bytes = pong_frame(&f64_bytes(CLIENT_TIME, byte_order), WALL, MONOTONIC, byte_order);
+    let mut frames = vec![Frame::Pong { client_time: CLIENT_TIME, wall: WALL, monotonic: MONOTONIC }];
+
+    bytes.extend(definitions_frames(&definitions, byte_order));
+    frames.extend(expected_definitions());
+
+    bytes.extend(meta_frame_at(WALL, MONOTONIC, byte_order, &[]));
+    frames.push(Frame::Meta { wall: WALL, monotonic: MONOTONIC, capabilities: String::new() });
+
+    for (mut record, frame) in records(&streams) {
+        if byte_order == WireByteOrder::Network {
+            let stream = streams.iter().find(|stream| Some(stream.stream_id()) == record_stream_id(&frame)).unwrap();
+            stream.record_layout().to_network_order(&mut record);
+        }
+        bytes.extend(record);
+        frames.push(frame);
+    }
+
+    bytes.extend(meta_frame_at(WALL + 10.0, MONOTONIC + 10.0, byte_order, CAPABILITIES.as_bytes()));
+    frames.push(Frame::Meta { wall: WALL + 10.0, monotonic: MONOTONIC + 10.0, capabilities: CAPABILITIES.to_string() });
+
+    FixtureFile { name, byte_order, bytes, frames, serial_frames: vec![] }
+}
+
+fn serial_file() -> FixtureFile {
+    let streams = streams();
+    let definitions: Vec<Vec<u8>> = streams.iter().map(|stream| stream.to_json().into_bytes()).collect();
+
+    let mut bytes = telemetry_serial::frame(FRAME_DEFINITIONS, &telemetry_serial::definitions_payload(&definitions));
+    let mut serial_frames = vec![(FRAME_DEFINITIONS, expected_definitions())];
+
+    bytes.extend(telemetry_serial::frame(FRAME_META, &meta_frame_at(WALL, MONOTONIC, WireByteOrder::Little, CAPABILITIES.as_bytes())));
+    serial_frames.push((FRAME_META, vec![Frame::Meta { wall: WALL, monotonic: MONOTONIC, capabilities: CAPABILITIES.to_string() }]));
+
+    for (record, frame) in records(&streams) {
+        bytes.extend(telemetry_serial::frame(FRAME_RECORD, &record));
+        serial_frames.push((FRAME_RECORD, vec![frame]));
+    }
+
+    FixtureFile { name: "serial.bin", byte_order: WireByteOrder::Little, bytes, frames: vec![], serial_frames }
+}
+
+// What a client should make of the STDF frames of streams().
+fn expected_definitions() -> Vec<Frame> {
+    let all_types = [
+        ("ub", "b", false, 1), ("sb", "b", true, 1), ("uw", "w", false, 2), ("sw", "w", true, 2),
+        ("ui", "i", false, 4), ("si", "i", true, 4), ("ul", "l", false, 8), ("sl", "l", true, 8),
+        ("f", "f", false, 4), ("d", "d", false, 8), ("s", "s", false, STRING_SIZE), ("a", "a", false, BYTES_SIZE),
+    ];
+    let long_record = [("text", "s", false, LONG_STRING_SIZE), ("data", "a", false, LONG_BYTES_SIZE)];
+    let definition = |id: u32, name: &str, timebase: Timebase, fields: &[(&str, &str, bool, usize)]| Frame::Definition(StreamDefinition {
+        id,
+        name: name.to_string(),
+        timebase,
+        fields: fields.iter()
+            .map(|(name, type_code, signed, size)| FieldDefinition { name: name.to_string(), type_code: type_code.to_string(), signed: *signed, size: *size })
+            .collect(),
+    });
+    vec![
+        Frame::Streams(2),
+        definition(ALL_TYPES_STREAM_ID, "all-types", Timebase::Wall, &all_types),
+        definition(LONG_RECORD_STREAM_ID, "long-record", Timebase::Monotonic, &long_record),
+    ]
+}
+
+fn record_stream_id(frame: &Frame) -> Option<u32> {
+    match frame {
+        Frame::Record { stream_id, .. } => Some(*stream_id),
+        _ => None
+    }
+}
+
+pub fn generate() -> Vec<FixtureFile> {
+    vec![
+        tcp_file("little.bin", WireByteOrder::Little),
+        tcp_file("network.bin", WireByteOrder::Network),
+        serial_file(),
+        // What a connection over the limit gets instead of the definitions
+        FixtureFile { name: "busy.bin", byte_order: WireByteOrder::Little, bytes: BUSY_FRAME.to_vec(), frames: vec![Frame::Busy], serial_frames: vec![] },
+    ]
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::telemetry_reader::TelemetryReader;
+    use super::*;
+
+    const CHECKED_IN: [(&str, &[u8]); 4] = [
+        ("little.bin", include_bytes!("telemetry_fixtures/little.bin")),
+        ("network.bin", include_bytes!("telemetry_fixtures/network.bin")),
+        ("serial.bin", include_bytes!("telemetry_fixtures/serial.bin")),
+        ("busy.bin", include_bytes!("telemetry_fixtures/busy.bin")),
+    ];
+
+    // Frames read from a file as they were sent; serial files are deframed first.
+    fn read_back(file: &FixtureFile, bytes: &[u8]) -> Vec<(Option<u8>, Frame)> {
+        let mut reader = TelemetryReader::new(file.byte_order);
+        if file.serial_frames.is_empty() {
+            return reader.read_all(bytes).unwrap().into_iter().map(|frame| (None, frame)).collect();
+        }
+        let mut read = vec![];
+        let mut buf = bytes;
+        while let Some((consumed, frame_type, payload)) = telemetry_serial::deframe(buf) {
+            read.extend(reader.read_all(payload).unwrap().into_iter().map(|frame| (Some(frame_type), frame)));
+            buf = &buf[consumed..];
+        }
+        read
+    }
+
+    // Regenerate them with the telemetry-fixtures binary after changing the format.
+    #[test]
+    fn checked_in_fixtures_are_what_the_server_sends_today() {
+        let files = generate();
+        assert!(manifest(&files) == include_str!("telemetry_fixtures/manifest.json"), "manifest.json is out of date");
+        for file in &files {
+            let bytes = CHECKED_IN.iter().find(|(name, _)| *name == file.name).map(|(_, bytes)| *bytes)
+                .unwrap_or_else(|| panic!("{} is not checked in", file.name));
+            assert!(bytes == &file.bytes[..], "{} is out of date", file.name);
+        }
+    }
+
+    #[test]
+    fn fixtures_read_back_as_the_manifest_says() {
+        for file in &generate() {
+            let expected: Vec<(Option<u8>, Frame)> = if file.serial_frames.is_empty() {
+                file.frames.iter().map(|frame| (None, frame.clone())).collect()
+            } else {
+                file.serial_frames.iter()
+                    .flat_map(|(frame_type, frames)| frames.iter().map(move |frame| (Some(*frame_type), frame.clone())))
+                    .collect()
+            };
+            let read = read_back(file, &file.bytes);
+
+            // Compared as JSON, where NaN equals NaN
+            assert_eq!(read.len(), expected.len(), "{}", file.name);
+            for ((read_type, read), (expected_type, expected)) in read.iter().zip(expected.iter()) {
+                assert_eq!(read_type, expected_type, "{}", file.name);
+                assert_eq!(read.to_json(), expected.to_json(), "{}", file.name);
+            }
+        }
+    }
+}
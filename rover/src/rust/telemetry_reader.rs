@@ -0,0 +1,425 @@
+//
+// Copyright (C) 2020 Abstract Horizon
+// All rights reserved. This program and the accompanying materials
+// are made available under the terms of the Apache License v2.0
+// which accompanies this distribution, and is available at
+// https://www.apache.org/licenses/LICENSE-2.0
+//
+//  Contributors:
+//    Daniel Sendula - initial API and implementation
+//
+
+// Decodes the telemetry wire format the way a client sees it on a TCP connection (or in the
+// payloads of serial frames), so what the server sends can be checked against the fixtures in
+// telemetry_fixtures/ without the Python viewer.
+//
+// Decisions a client has to make that the definitions do not spell out:
+//
+//   - records come in the connection's byte order; the definitions always say "little"
+//   - "signed" is the string "true" or "false", not a JSON boolean
+//   - strings are UTF-8, NUL padded to the field size; trailing NULs are not part of the value
+//   - floats and doubles are passed on as they are, NaN and infinities included
+//   - definitions without a "timebase" (from servers before it) have wall clock times
+
+use std::collections::HashMap;
+
+use byteorder::{BigEndian, ByteOrder, LittleEndian};
+
+use crate::telemetry_stream::{Timebase, WireByteOrder};
+
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    Unsigned(u64),
+    Signed(i64),
+    Float(f64),
+    Text(String),
+    Bytes(Vec<u8>),
+}
+
+// JSON has no NaN or infinities; they are written as the strings "NaN", "inf" and "-inf".
+pub fn json_float(value: f64) -> String {
+    if value.is_nan() {
+        "\"NaN\"".to_string()
+    } else if value.is_infinite() {
+        if value > 0.0 { "\"inf\"".to_string() } else { "\"-inf\"".to_string() }
+    } else {
+        format!("{:?}", value)
+    }
+}
+
+impl Value {
+    pub fn to_json(&self) -> String {
+        match self {
+            Value::Unsigned(value) => value.to_string(),
+            Value::Signed(value) => value.to_string(),
+            Value::Float(value) => json_float(*value),
+            Value::Text(value) => format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\"")),
+            Value::Bytes(value) => {
+                let bytes: Vec<String> = value.iter().map(|byte| byte.to_string()).collect();
+                format!("[{}]", bytes.join(", "))
+            }
+        }
+    }
+}
+
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct FieldDefinition {
+    pub name: String,
+    pub type_code: String,
+    pub signed: bool,
+    pub size: usize,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct StreamDefinition {
+    pub id: u32,
+    pub name: String,
+    pub timebase: Timebase,
+    pub fields: Vec<FieldDefinition>,
+}
+
+impl StreamDefinition {
+    // From the JSON sent in an STDF frame.
+    pub fn parse(json: &str) -> Result<StreamDefinition, String> {
+        let root = JsonParser { s: json.as_bytes(), pos: 0 }.parse_document()?;
+        let id = root.get("id").and_then(|id| id.number()).ok_or("no id")? as u32;
+        let name = root.get("name").and_then(|name| name.string()).ok_or("no name")?.to_string();
+        let timebase = match root.get("timebase").and_then(|timebase| timebase.string()) {
+            Some(timebase) => Timebase::parse(timebase)?,
+            None => Timebase::Wall
+        };
+        let mut fields = vec![];
+        match root.get("fields") {
+            Some(Json::Object(members)) => {
+                for (field_name, field) in members {
+                    let type_code = field.get("type").and_then(|t| t.string()).ok_or(format!("no type for {}", field_name))?.to_string();
+                    let signed = field.get("signed").and_then(|signed| signed.string()) == Some("true");
+                    let size = match type_code.as_str() {
+                        "b" => 1,
+                        "w" => 2,
+                        "i" | "f" => 4,
+                        "l" | "d" => 8,
+                        "s" | "a" => field.get("size").and_then(|size| size.string()).and_then(|size| size.parse().ok())
+                            .ok_or(format!("no size for {}", field_name))?,
+                        _ => return Err(format!("unknown type {} of {}", type_code, field_name))
+                    };
+                    fields.push(FieldDefinition { name: field_name.clone(), type_code, signed, size });
+                }
+            },
+            _ => return Err("no fields".to_string())
+        }
+        Ok(StreamDefinition { id, name, timebase, fields })
+    }
+
+    // Time and values, without the header.
+    pub fn record_length(&self) -> usize {
+        8 + self.fields.iter().map(|field| field.size).sum::<usize>()
+    }
+}
+
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Frame {
+    // STRS: number of STDF frames that follow
+    Streams(u32),
+    Definition(StreamDefinition),
+    Meta { wall: f64, monotonic: f64, capabilities: String },
+    Pong { client_time: f64, wall: f64, monotonic: f64 },
+    Busy,
+    Record { stream_id: u32, time: f64, values: Vec<(String, Value)> },
+}
+
+impl Frame {
+    pub fn to_json(&self) -> String {
+        match self {
+            Frame::Streams(count) => format!("{{ \"frame\" : \"STRS\", \"streams\" : {} }}", count),
+            Frame::Definition(definition) => {
+                let fields: Vec<String> = definition.fields.iter()
+                    .map(|field| format!("{{ \"name\" : \"{}\", \"type\" : \"{}\", \"signed\" : {}, \"size\" : {} }}", field.name, field.type_code, field.signed, field.size))
+                    .collect();
+                format!("{{ \"frame\" : \"STDF\", \"id\" : {}, \"name\" : \"{}\", \"timebase\" : \"{}\", \"fields\" : [{}] }}",
+                        definition.id, definition.name, definition.timebase.name(), fields.join(", "))
+            },
+            Frame::Meta { wall, monotonic, capabilities } =>
+                format!("{{ \"frame\" : \"META\", \"wall\" : {}, \"monotonic\" : {}, \"capabilities\" : {} }}",
+                    json_float(*wall), json_float(*monotonic), Value::Text(capabilities.clone()).to_json()),
+            Frame::Pong { client_time, wall, monotonic } =>
+                format!("{{ \"frame\" : \"PONG\", \"client_time\" : {}, \"wall\" : {}, \"monotonic\" : {} }}",
+                    json_float(*client_time), json_float(*wall), json_float(*monotonic)),
+            Frame::Busy => "{ \"frame\" : \"BUSY\" }".to_string(),
+            Frame::Record { stream_id, time, values } => {
+                let values: Vec<String> = values.iter().map(|(name, value)| format!("\"{}\" : {}", name, value.to_json())).collect();
+                format!("{{ \"frame\" : \"record\", \"stream\" : {}, \"time\" : {}, \"values\" : {{ {} }} }}", stream_id, json_float(*time), values.join(", "))
+            }
+        }
+    }
+}
+
+
+pub struct TelemetryReader {
+    byte_order: WireByteOrder,
+    streams: HashMap<u32, StreamDefinition>,
+}
+
+impl TelemetryReader {
+    pub fn new(byte_order: WireByteOrder) -> TelemetryReader {
+        TelemetryReader { byte_order, streams: HashMap::new() }
+    }
+
+    // The frame at the start of buf and its length; None if buf does not hold all of it yet.
+    pub fn read(&mut self, buf: &[u8]) -> Result<Option<(usize, Frame)>, String> {
+        if buf.len() < 8 {
+            return Ok(None);
+        }
+        if buf[0] < 8 {
+            return self.read_record(buf);
+        }
+        let len = self.read_u32(&buf[4..8]) as usize;
+        match &buf[0..4] {
+            b"STRS" => Ok(Some((8, Frame::Streams(len as u32)))),
+            b"BUSY" => Ok(Some((8, Frame::Busy))),
+            tag => {
+                if buf.len() < 8 + len {
+                    return Ok(None);
+                }
+                let body = &buf[8..8 + len];
+                let frame = match tag {
+                    b"STDF" => {
+                        let json = std::str::from_utf8(body).map_err(|e| format!("definition is not UTF-8: {}", e))?;
+                        let definition = StreamDefinition::parse(json)?;
+                        self.streams.insert(definition.id, definition.clone());
+                        Frame::Definition(definition)
+                    },
+                    b"META" if len >= 16 => Frame::Meta {
+                        wall: self.read_f64(&body[0..8]),
+                        monotonic: self.read_f64(&body[8..16]),
+                        capabilities: String::from_utf8(body[16..].to_vec()).map_err(|e| format!("capabilities are not UTF-8: {}", e))?,
+                    },
+                    b"PONG" if len == 24 => Frame::Pong {
+                        client_time: self.read_f64(&body[0..8]),
+                        wall: self.read_f64(&body[8..16]),
+                        monotonic: self.read_f64(&body[16..24]),
+                    },
+                    _ => return Err(format!("unknown frame {:?} of {} bytes", String::from_utf8_lossy(tag), len))
+                };
+                Ok(Some((8 + len, frame)))
+            }
+        }
+    }
+
+    // Every frame in buf, which must end with a complete frame.
+    pub fn read_all(&mut self, mut buf: &[u8]) -> Result<Vec<Frame>, String> {
+        let mut frames = vec![];
+        while !buf.is_empty() {
+            match self.read(buf)? {
+                Some((len, frame)) => {
+                    frames.push(frame);
+                    buf = &buf[len..];
+                },
+                None => return Err(format!("{} bytes left over after {} frames", buf.len(), frames.len()))
+            }
+        }
+        Ok(frames)
+    }
+
+    fn read_record(&self, buf: &[u8]) -> Result<Option<(usize, Frame)>, String> {
+        let header_byte = buf[0];
+        let mut pos = 1;
+        let stream_id = if header_byte & 1 != 0 {
+            pos += 2;
+            self.read_u16(&buf[1..3]) as u32
+        } else {
+            pos += 1;
+            buf[1] as u32
+        };
+        let length_size = match header_byte & 6 { 0 => 1, 2 => 2, _ => 4 };
+        let length = match length_size {
+            1 => buf[pos] as usize,
+            2 => self.read_u16(&buf[pos..pos + 2]) as usize,
+            _ => self.read_u32(&buf[pos..pos + 4]) as usize,
+        };
+        pos += length_size;
+
+        let definition = self.streams.get(&stream_id).ok_or(format!("record of undefined stream {}", stream_id))?;
+        if length != definition.record_length() {
+            return Err(format!("record of stream {} is {} bytes, definition says {}", stream_id, length, definition.record_length()));
+        }
+        if buf.len() < pos + length {
+            return Ok(None);
+        }
+
+        let time = self.read_f64(&buf[pos..pos + 8]);
+        pos += 8;
+        let mut values = vec![];
+        for field in &definition.fields {
+            let bytes = &buf[pos..pos + field.size];
+            let value = match (field.type_code.as_str(), field.signed) {
+                ("b", false) => Value::Unsigned(bytes[0] as u64),
+                ("b", true) => Value::Signed(bytes[0] as i8 as i64),
+                ("w", false) => Value::Unsigned(self.read_u16(bytes) as u64),
+                ("w", true) => Value::Signed(self.read_u16(bytes) as i16 as i64),
+                ("i", false) => Value::Unsigned(self.read_u32(bytes) as u64),
+                ("i", true) => Value::Signed(self.read_u32(bytes) as i32 as i64),
+                ("l", false) => Value::Unsigned(self.read_u64(bytes)),
+                ("l", true) => Value::Signed(self.read_u64(bytes) as i64),
+                ("f", _) => Value::Float(f32::from_bits(self.read_u32(bytes)) as f64),
+                ("d", _) => Value::Float(self.read_f64(bytes)),
+                ("s", _) => {
+                    let end = bytes.iter().rposition(|byte| *byte != 0).map_or(0, |last| last + 1);
+                    Value::Text(String::from_utf8(bytes[..end].to_vec()).map_err(|e| format!("{} is not UTF-8: {}", field.name, e))?)
+                },
+                _ => Value::Bytes(bytes.to_vec()),
+            };
+            values.push((field.name.clone(), value));
+            pos += field.size;
+        }
+        Ok(Some((pos, Frame::Record { stream_id, time, values })))
+    }
+
+    fn read_u16(&self, buf: &[u8]) -> u16 {
+        match self.byte_order { WireByteOrder::Little => LittleEndian::read_u16(buf), WireByteOrder::Network => BigEndian::read_u16(buf) }
+    }
+
+    fn read_u32(&self, buf: &[u8]) -> u32 {
+        match self.byte_order { WireByteOrder::Little => LittleEndian::read_u32(buf), WireByteOrder::Network => BigEndian::read_u32(buf) }
+    }
+
+    fn read_u64(&self, buf: &[u8]) -> u64 {
+        match self.byte_order { WireByteOrder::Little => LittleEndian::read_u64(buf), WireByteOrder::Network => BigEndian::read_u64(buf) }
+    }
+
+    fn read_f64(&self, buf: &[u8]) -> f64 {
+        f64::from_bits(self.read_u64(buf))
+    }
+}
+
+
+// Members of a JSON object whose values are all numbers, such as a config snapshot.
+pub fn parse_number_members(json: &str) -> Result<Vec<(String, f64)>, String> {
+    let root = JsonParser { s: json.as_bytes(), pos: 0 }.parse_document()?;
+    match root {
+        Json::Object(members) => members.into_iter()
+            .map(|(name, value)| match value.number() {
+                Some(n) => Ok((name, n)),
+                None => Err(format!("{} is not a number", name))
+            })
+            .collect(),
+        _ => Err("expected an object".to_string())
+    }
+}
+
+
+// Just enough JSON for stream definitions: objects, strings and numbers.
+enum Json {
+    Object(Vec<(String, Json)>),
+    String(String),
+    Number(f64),
+}
+
+impl Json {
+    fn get(&self, key: &str) -> Option<&Json> {
+        match self {
+            Json::Object(members) => members.iter().find(|(name, _)| name == key).map(|(_, value)| value),
+            _ => None
+        }
+    }
+
+    fn string(&self) -> Option<&str> {
+        match self { Json::String(s) => Some(s), _ => None }
+    }
+
+    fn number(&self) -> Option<f64> {
+        match self { Json::Number(n) => Some(*n), _ => None }
+    }
+}
+
+struct JsonParser<'a> {
+    s: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> JsonParser<'a> {
+    fn parse_document(mut self) -> Result<Json, String> {
+        let value = self.parse_value()?;
+        self.skip_whitespace();
+        if self.pos != self.s.len() {
+            return Err(format!("unexpected content at {}", self.pos));
+        }
+        Ok(value)
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self.pos < self.s.len() && (self.s[self.pos] as char).is_ascii_whitespace() {
+            self.pos += 1;
+        }
+    }
+
+    fn expect(&mut self, c: u8) -> Result<(), String> {
+        self.skip_whitespace();
+        if self.s.get(self.pos) == Some(&c) {
+            self.pos += 1;
+            Ok(())
+        } else {
+            Err(format!("expected '{}' at {}", c as char, self.pos))
+        }
+    }
+
+    fn parse_value(&mut self) -> Result<Json, String> {
+        self.skip_whitespace();
+        match self.s.get(self.pos) {
+            Some(b'{') => self.parse_object(),
+            Some(b'"') => self.parse_string().map(Json::String),
+            Some(_) => {
+                let start = self.pos;
+                while self.pos < self.s.len() && (self.s[self.pos] == b'-' || self.s[self.pos] == b'.' || self.s[self.pos] == b'e' || self.s[self.pos] == b'+' || self.s[self.pos].is_ascii_digit()) {
+                    self.pos += 1;
+                }
+                std::str::from_utf8(&self.s[start..self.pos]).ok().and_then(|n| n.parse().ok()).map(Json::Number)
+                    .ok_or(format!("expected a value at {}", start))
+            },
+            None => Err("unexpected end".to_string())
+        }
+    }
+
+    fn parse_object(&mut self) -> Result<Json, String> {
+        self.expect(b'{')?;
+        let mut members = vec![];
+        self.skip_whitespace();
+        if self.s.get(self.pos) == Some(&b'}') {
+            self.pos += 1;
+            return Ok(Json::Object(members));
+        }
+        loop {
+            self.skip_whitespace();
+            let name = self.parse_string()?;
+            self.expect(b':')?;
+            members.push((name, self.parse_value()?));
+            self.skip_whitespace();
+            match self.s.get(self.pos) {
+                Some(b',') => self.pos += 1,
+                Some(b'}') => { self.pos += 1; return Ok(Json::Object(members)) },
+                _ => return Err(format!("expected ',' or '}}' at {}", self.pos))
+            }
+        }
+    }
+
+    fn parse_string(&mut self) -> Result<String, String> {
+        self.expect(b'"')?;
+        let mut bytes = vec![];
+        while let Some(&c) = self.s.get(self.pos) {
+            self.pos += 1;
+            match c {
+                b'"' => return String::from_utf8(bytes).map_err(|e| e.to_string()),
+                b'\\' => {
+                    let escaped = *self.s.get(self.pos).ok_or("unexpected end")?;
+                    self.pos += 1;
+                    bytes.push(match escaped { b'n' => b'\n', b't' => b'\t', other => other });
+                },
+                _ => bytes.push(c)
+            }
+        }
+        Err("unterminated string".to_string())
+    }
+}
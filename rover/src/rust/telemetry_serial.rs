@@ -0,0 +1,435 @@
+//
+// Copyright (C) 2020 Abstract Horizon
+// All rights reserved. This program and the accompanying materials
+// are made available under the terms of the Apache License v2.0
+// which accompanies this distribution, and is available at
+// https://www.apache.org/licenses/LICENSE-2.0
+//
+//  Contributors:
+//    Daniel Sendula - initial API and implementation
+//
+
+// Fallback telemetry over a serial line (HC-12 and similar radio modules) for when WiFi is down.
+//
+// A serial line has no connection to tell where the stream starts and may lose or corrupt
+// bytes, so everything is sent in frames:
+//
+//   0xA5 0x5A, type (1 byte), payload length (u16 LE), payload, CRC-16/CCITT (u16 LE)
+//
+// The CRC covers type, length and payload. Payloads are exactly the bytes a TCP client
+// receives, in little endian byte order:
+//
+//   'D' - STRS/STDF definitions of the whitelisted streams; repeated so a reader can join any time
+//   'M' - META frame
+//   'R' - a single record
+//
+// so concatenating the payloads of a capture (keeping only the first 'D') gives a TCP capture.
+
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use byteorder::{ByteOrder, LittleEndian};
+use crossbeam_channel::{bounded, Sender, Receiver, RecvTimeoutError, TrySendError};
+
+use crate::telemetry_socket_server::{clock, definitions_frames, meta_frame_at};
+use crate::telemetry_stream::WireByteOrder;
+
+
+pub const FRAME_SYNC: [u8; 2] = [0xA5, 0x5A];
+pub const FRAME_DEFINITIONS: u8 = b'D';
+pub const FRAME_META: u8 = b'M';
+pub const FRAME_RECORD: u8 = b'R';
+// Sync, type, length and CRC
+pub const FRAME_OVERHEAD: usize = 7;
+
+// Records queued for the serial line; anything over this is dropped rather than holding up the logging thread.
+const QUEUE_LENGTH: usize = 16;
+const REOPEN_INTERVAL: Duration = Duration::from_secs(1);
+// Definitions and META frames are repeated at this interval.
+const DEFINITIONS_INTERVAL: Duration = Duration::from_secs(10);
+// How long a single frame may wait for room in the tty's output buffer.
+const FRAME_WRITE_TIMEOUT: Duration = Duration::from_millis(100);
+const ERROR_LOG_INTERVAL: Duration = Duration::from_secs(10);
+
+
+pub struct SerialOutputConfig {
+    pub device: String,
+    pub baud: u32,
+    pub stream_whitelist: Vec<String>,
+    // Only every n-th record of each whitelisted stream is sent
+    pub decimation: u32,
+}
+
+#[derive(Default)]
+pub struct SerialCounters {
+    pub records: AtomicUsize,
+    pub dropped: AtomicUsize,
+    pub reopened: AtomicUsize,
+}
+
+pub fn crc16(data: &[u8]) -> u16 {
+    let mut crc: u16 = 0xFFFF;
+    for byte in data {
+        crc ^= (*byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 { (crc << 1) ^ 0x1021 } else { crc << 1 };
+        }
+    }
+    crc
+}
+
+pub fn frame(frame_type: u8, payload: &[u8]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(payload.len() + FRAME_OVERHEAD);
+    buf.extend_from_slice(&FRAME_SYNC);
+    buf.push(frame_type);
+    let mut len = [0u8; 2];
+    LittleEndian::write_u16(&mut len, payload.len() as u16);
+    buf.extend_from_slice(&len);
+    buf.extend_from_slice(payload);
+    let mut crc = [0u8; 2];
+    LittleEndian::write_u16(&mut crc, crc16(&buf[2..]));
+    buf.extend_from_slice(&crc);
+    buf
+}
+
+// Returns the type and payload of the first valid frame in buf and how many bytes were consumed,
+// skipping garbage and frames with a bad CRC. None if no complete frame is available yet.
+pub fn deframe(buf: &[u8]) -> Option<(usize, u8, &[u8])> {
+    let mut start = 0;
+    while start + FRAME_OVERHEAD <= buf.len() {
+        if buf[start..start + 2] != FRAME_SYNC {
+            start += 1;
+            continue;
+        }
+        let len = LittleEndian::read_u16(&buf[start + 3..start + 5]) as usize;
+        let end = start + 5 + len + 2;
+        if end > buf.len() {
+            return None;
+        }
+        if LittleEndian::read_u16(&buf[end - 2..end]) == crc16(&buf[start + 2..end - 2]) {
+            return Some((end, buf[start + 2], &buf[start + 5..end - 2]));
+        }
+        start += 1;
+    }
+    None
+}
+
+// Definitions payload as a TCP client would receive it, for the given streams only.
+pub fn definitions_payload(stream_definitions: &[Vec<u8>]) -> Vec<u8> {
+    definitions_frames(stream_definitions, WireByteOrder::Little)
+}
+
+fn meta_payload(started: Instant, capabilities: &Mutex<Vec<u8>>) -> Vec<u8> {
+    let (wall, monotonic) = clock(started);
+    meta_frame_at(wall, monotonic, WireByteOrder::Little, &capabilities.lock().unwrap())
+}
+
+
+// Picks the records that go over the serial line: whitelisted streams only, every n-th record of each.
+pub struct Decimator {
+    decimation: u32,
+    counts: HashMap<u32, u32>,
+}
+
+impl Decimator {
+    pub fn new(stream_ids: &[u32], decimation: u32) -> Decimator {
+        Decimator {
+            decimation: decimation.max(1),
+            counts: stream_ids.iter().map(|stream_id| (*stream_id, 0)).collect(),
+        }
+    }
+
+    pub fn accept(&mut self, stream_id: u32) -> bool {
+        match self.counts.get_mut(&stream_id) {
+            Some(count) => {
+                let accepted = *count == 0;
+                *count = (*count + 1) % self.decimation;
+                accepted
+            },
+            None => false
+        }
+    }
+}
+
+
+// Fed by the logging thread; writing to the tty happens on its own thread.
+pub struct SerialTransport {
+    decimator: Decimator,
+    sender: Option<Sender<Vec<u8>>>,
+    counters: Arc<SerialCounters>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl SerialTransport {
+    // stream_names and stream_definitions are all registered streams, in the same order.
+    pub fn start(config: SerialOutputConfig, stream_names: &[(&'static str, u32)], stream_definitions: &[Vec<u8>],
+                 started: Instant, counters: Arc<SerialCounters>, capabilities: Arc<Mutex<Vec<u8>>>) -> SerialTransport {
+        let mut stream_ids = vec![];
+        let mut whitelisted_definitions = vec![];
+        for name in &config.stream_whitelist {
+            match stream_names.iter().position(|(stream_name, _)| stream_name == name) {
+                Some(i) => {
+                    stream_ids.push(stream_names[i].1);
+                    whitelisted_definitions.push(stream_definitions[i].clone());
+                },
+                None => warn!("Serial telemetry: no stream named {:?}", name)
+            }
+        }
+
+        let (sender, receiver) = bounded(QUEUE_LENGTH);
+        let decimator = Decimator::new(&stream_ids, config.decimation);
+        let writer_counters = counters.clone();
+        let definitions = frame(FRAME_DEFINITIONS, &definitions_payload(&whitelisted_definitions));
+
+        SerialTransport {
+            decimator,
+            sender: Some(sender),
+            counters,
+            thread: Some(thread::spawn(move || {
+                serial_writer(config, definitions, receiver, started, writer_counters, capabilities);
+            })),
+        }
+    }
+
+    // Never blocks; records that do not fit in the queue are counted and dropped.
+    pub fn offer(&mut self, stream_id: u32, record: &[u8]) {
+        if !self.decimator.accept(stream_id) {
+            return;
+        }
+        if let Some(sender) = &self.sender {
+            match sender.try_send(frame(FRAME_RECORD, record)) {
+                Ok(()) => {},
+                Err(TrySendError::Full(_)) | Err(TrySendError::Disconnected(_)) => {
+                    self.counters.dropped.fetch_add(1, Ordering::SeqCst);
+                }
+            }
+        }
+    }
+
+    pub fn stop(&mut self) {
+        self.sender = None;
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn serial_writer(config: SerialOutputConfig, definitions: Vec<u8>, receiver: Receiver<Vec<u8>>,
+                 started: Instant, counters: Arc<SerialCounters>, capabilities: Arc<Mutex<Vec<u8>>>) {
+    let mut port: Option<File> = None;
+    let mut last_open_attempt: Option<Instant> = None;
+    let mut last_definitions = Instant::now();
+    let mut last_error_log: Option<Instant> = None;
+    let mut opened_before = false;
+
+    let mut log_error = |message: String| {
+        if last_error_log.is_none_or(|last| last.elapsed() >= ERROR_LOG_INTERVAL) {
+            warn!("{}", message);
+            last_error_log = Some(Instant::now());
+        }
+    };
+
+    loop {
+        if port.is_none() && last_open_attempt.is_none_or(|last| last.elapsed() >= REOPEN_INTERVAL) {
+            last_open_attempt = Some(Instant::now());
+            match open_tty(&config.device, config.baud) {
+                Ok(file) => {
+                    info!("Serial telemetry on {} at {} baud", config.device, config.baud);
+                    if opened_before {
+                        counters.reopened.fetch_add(1, Ordering::SeqCst);
+                    }
+                    opened_before = true;
+                    port = Some(file);
+                    // Force definitions out straight away
+                    last_definitions = Instant::now() - DEFINITIONS_INTERVAL;
+                },
+                Err(e) => log_error(format!("Cannot open serial telemetry device {}: {:?}", config.device, e))
+            }
+        }
+
+        let message = match receiver.recv_timeout(REOPEN_INTERVAL) {
+            Ok(message) => Some(message),
+            Err(RecvTimeoutError::Timeout) => None,
+            Err(RecvTimeoutError::Disconnected) => break
+        };
+
+        if let Some(file) = &mut port {
+            let mut result = Ok(());
+            if last_definitions.elapsed() >= DEFINITIONS_INTERVAL {
+                last_definitions = Instant::now();
+                result = write_frame(file, &definitions)
+                    .and_then(|_| write_frame(file, &frame(FRAME_META, &meta_payload(started, &capabilities))));
+            }
+            if let Some(message) = &message {
+                result = result.and_then(|_| write_frame(file, message));
+                match result {
+                    Ok(()) => counters.records.fetch_add(1, Ordering::SeqCst),
+                    Err(_) => counters.dropped.fetch_add(1, Ordering::SeqCst),
+                };
+            }
+            match result {
+                Ok(()) => {},
+                Err(ref e) if e.kind() == io::ErrorKind::TimedOut => {},
+                Err(e) => {
+                    log_error(format!("Serial telemetry device {} failed, reopening: {:?}", config.device, e));
+                    port = None;
+                }
+            }
+        } else if message.is_some() {
+            counters.dropped.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+    println!("Finishing serial telemetry thread.");
+}
+
+// The tty is non blocking; a frame that does not fit within FRAME_WRITE_TIMEOUT is cut short
+// and the reader resynchronises on the next frame.
+fn write_frame(file: &mut File, buf: &[u8]) -> io::Result<()> {
+    let started = Instant::now();
+    let mut written = 0;
+    while written < buf.len() {
+        match file.write(&buf[written..]) {
+            Ok(0) => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "serial device closed")),
+            Ok(n) => written += n,
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::Interrupted => {
+                if started.elapsed() >= FRAME_WRITE_TIMEOUT {
+                    return Err(io::Error::new(io::ErrorKind::TimedOut, "serial device is not keeping up"));
+                }
+                thread::sleep(Duration::from_millis(1));
+            },
+            Err(e) => return Err(e)
+        }
+    }
+    Ok(())
+}
+
+fn baud_rate(baud: u32) -> Option<libc::speed_t> {
+    match baud {
+        1200 => Some(libc::B1200),
+        2400 => Some(libc::B2400),
+        4800 => Some(libc::B4800),
+        9600 => Some(libc::B9600),
+        19200 => Some(libc::B19200),
+        38400 => Some(libc::B38400),
+        57600 => Some(libc::B57600),
+        115200 => Some(libc::B115200),
+        230400 => Some(libc::B230400),
+        460800 => Some(libc::B460800),
+        921600 => Some(libc::B921600),
+        _ => None
+    }
+}
+
+fn open_tty(device: &str, baud: u32) -> io::Result<File> {
+    let speed = match baud_rate(baud) {
+        Some(speed) => speed,
+        None => return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("unsupported baud rate {}", baud)))
+    };
+
+    let file = OpenOptions::new().write(true).custom_flags(libc::O_NOCTTY | libc::O_NONBLOCK).open(device)?;
+    let fd = file.as_raw_fd();
+    unsafe {
+        let mut termios: libc::termios = std::mem::zeroed();
+        if libc::tcgetattr(fd, &mut termios) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        libc::cfmakeraw(&mut termios);
+        termios.c_cflag |= libc::CLOCAL;
+        if libc::cfsetspeed(&mut termios, speed) != 0 || libc::tcsetattr(fd, libc::TCSANOW, &termios) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(file)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::CStr;
+    use std::io::Read;
+    use std::os::unix::io::FromRawFd;
+
+    use crate::telemetry_socket_server::SocketTelemetryServerBuilder;
+    use crate::telemetry_stream::{Storable, TelemetryStreamDefinition};
+    use super::*;
+
+    // Master side of a pseudo terminal and the path of its slave side.
+    fn open_pty() -> (File, String) {
+        unsafe {
+            let master = libc::posix_openpt(libc::O_RDWR | libc::O_NOCTTY | libc::O_NONBLOCK);
+            assert!(master >= 0 && libc::grantpt(master) == 0 && libc::unlockpt(master) == 0,
+                    "cannot open pty: {:?}", io::Error::last_os_error());
+            let name = libc::ptsname(master);
+            assert!(!name.is_null(), "no pty slave name");
+            (File::from_raw_fd(master), CStr::from_ptr(name).to_string_lossy().into_owned())
+        }
+    }
+
+    #[test]
+    fn corrupted_frame_is_skipped() {
+        let mut corrupted = frame(FRAME_RECORD, b"record");
+        corrupted[6] ^= 1;
+        corrupted.extend_from_slice(&frame(FRAME_META, b"meta"));
+        match deframe(&corrupted) {
+            Some((_, FRAME_META, b"meta")) => {},
+            other => panic!("corrupted frame not skipped: {:?}", other)
+        }
+    }
+
+    // Definitions come first, then every 3rd record of the whitelisted stream only.
+    #[test]
+    fn only_decimated_records_of_whitelisted_streams_go_out() {
+        let (mut master, slave) = open_pty();
+
+        let mut builder = SocketTelemetryServerBuilder::new();
+        let sent = builder.register_stream(TelemetryStreamDefinition::new("sent", 1, vec![
+            TelemetryStreamDefinition::unsigned_byte_field("i"),
+        ])).unwrap();
+        let skipped = builder.register_stream(TelemetryStreamDefinition::new("skipped", 2, vec![
+            TelemetryStreamDefinition::unsigned_byte_field("i"),
+        ])).unwrap();
+        builder.serial_output(&slave, 115200, vec!["sent"], 3);
+        let server = builder.create(0);
+
+        // Give the serial thread time to open the device
+        thread::sleep(Duration::from_millis(200));
+        for i in 0..7u8 {
+            log_with_time!(server, sent, i);
+            log_with_time!(server, skipped, i);
+        }
+
+        let mut received: Vec<u8> = vec![];
+        let mut records: Vec<u8> = vec![];
+        let mut frame_types: Vec<u8> = vec![];
+        let started = Instant::now();
+        while records.len() < 3 && started.elapsed() < Duration::from_secs(2) {
+            let mut buf = [0u8; 1024];
+            match master.read(&mut buf) {
+                Ok(n) => received.extend_from_slice(&buf[..n]),
+                Err(_) => thread::sleep(Duration::from_millis(10))
+            }
+            while let Some((consumed, frame_type, payload)) = deframe(&received) {
+                frame_types.push(frame_type);
+                if frame_type == FRAME_RECORD {
+                    records.push(*payload.last().unwrap());
+                }
+                received.drain(..consumed);
+            }
+        }
+        // Counters are updated once a write has completed
+        thread::sleep(Duration::from_millis(50));
+        let stats = server.stats();
+        server.stop().unwrap();
+
+        assert_eq!(frame_types.first(), Some(&FRAME_DEFINITIONS), "frames {:?}", frame_types);
+        assert_eq!(records, vec![0, 3, 6]);
+        assert_eq!((stats.serial_records, stats.serial_dropped), (3, 0), "{:?}", stats);
+    }
+}
@@ -12,134 +12,916 @@
 #![macro_use]
 
 use std::io::prelude::*;
-use std::net::{TcpStream, TcpListener};
+use std::io::ErrorKind;
+use std::net::{SocketAddr, TcpStream, TcpListener};
 use std::{thread, sync::Arc};
-use std::sync::mpsc;
-use byteorder::{ByteOrder, LittleEndian};
+use std::sync::{mpsc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::collections::{HashMap, HashSet};
+use std::ops::Deref;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use byteorder::{ByteOrder, BigEndian, LittleEndian};
+use crossbeam_channel::{bounded, Sender, Receiver, RecvTimeoutError, TrySendError};
 
 // use crate::telemetry_stream::{TelemetryStreamDefinition, TelemetryStreamField, FieldType, FieldTypeUnsignedByte};
 use crate::telemetry_stream::*;
+use crate::telemetry_serial::{SerialCounters, SerialOutputConfig, SerialTransport};
+use crate::telemetry_file::{self, FileCounters, FileOutputConfig, FileTelemetrySink};
 
 
+// Right after connecting clients may send handshake requests, each within HANDSHAKE_TIMEOUT of the previous one:
+//   NBOR - receive everything in network byte order
+//   PING + 8 byte client timestamp - answered with a PONG frame echoing the timestamp
+//          followed by the server's wall clock and monotonic time, for clock offset estimation
+const NETWORK_BYTE_ORDER_REQUEST: &[u8; 4] = b"NBOR";
+const PING_REQUEST: &[u8; 4] = b"PING";
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_millis(100);
+const MAX_HANDSHAKE_REQUESTS: usize = 64;
+
+// After the stream definitions clients may send, at any time and as often as they like:
+//   SUBS + 4 byte count + a 4 byte stream id per stream - from then on only records of these streams
+//          are sent; count and ids in the connection's byte order. Clients that never send one
+//          receive every stream.
+const SUBSCRIBE_REQUEST: &[u8; 4] = b"SUBS";
+const MAX_SUBSCRIBED_STREAMS: usize = 256;
+// Read from a client per logged record at most, so one cannot keep the logging thread reading
+const MAX_REQUEST_BYTES_PER_POLL: usize = 4096;
+
+// META frames carry the server's wall clock and monotonic time, followed by the capabilities JSON
+// once known (see set_capabilities); sent after the stream definitions and then periodically.
+const META_INTERVAL: Duration = Duration::from_secs(10);
+
+// Sent instead of the stream definitions to connections over the limit, framed like STRS/STDF with zero length.
+pub const BUSY_FRAME: &[u8; 8] = b"BUSY\0\0\0\0";
+const REJECT_LOG_INTERVAL: Duration = Duration::from_secs(10);
+// A single slow client must not hold up the logging thread for longer than this.
+const WRITE_TIMEOUT: Duration = Duration::from_millis(10);
+// Writes in a row that timed out before a connection is dropped as stalled.
+const MAX_CONSECUTIVE_FAILURES: u32 = 10;
+// Records waiting for the logging thread; when full one is dropped (see OverflowPolicy), so logging
+// never blocks. Some 10 s of balance data, well under a MB.
+const DEFAULT_LOG_QUEUE_LENGTH: usize = 2048;
+// New connections are picked up at least this often, even when nothing is logged.
+const CONNECTION_POLL_INTERVAL: Duration = Duration::from_millis(100);
+// The listener is non-blocking, so the connection thread sees a stop within this.
+const ACCEPT_POLL_INTERVAL: Duration = Duration::from_millis(20);
+// How often the connection thread looks for handshake requests while any connection is in its
+// handshake; PINGs are answered within this.
+const HANDSHAKE_POLL_INTERVAL: Duration = Duration::from_millis(1);
+// stop() gives up waiting for the threads after this; serial and file outputs are flushed in it.
+const STOP_TIMEOUT: Duration = Duration::from_secs(2);
+
+const DEFAULT_MAX_CONNECTIONS: usize = 8;
+const DEFAULT_DEAD_TIME: Duration = Duration::from_secs(5);
+
+
+// A registered stream, for the log macros; cheap to clone, so any thread that logs can have one.
+#[derive(Clone)]
+pub struct StreamHandle(Arc<TelemetryStreamDefinition>);
+
+impl Deref for StreamHandle {
+    type Target = TelemetryStreamDefinition;
+
+    fn deref(&self) -> &TelemetryStreamDefinition {
+        &self.0
+    }
+}
+
+// Streams registered so far, in registration order; no two share an id or a name.
+#[derive(Default)]
+struct StreamRegistry {
+    definitions: Vec<Vec<u8>>,
+    record_layouts: HashMap<u32, RecordLayout>,
+    names: Vec<(&'static str, u32)>,
+}
+
+impl StreamRegistry {
+    fn add(&mut self, stream: TelemetryStreamDefinition) -> Result<StreamHandle, String> {
+        let stream_id = stream.stream_id();
+        if stream_id > u16::MAX as u32 {
+            return Err(format!("Stream {} has id {}, over the largest a record header takes", stream.name(), stream_id));
+        }
+        if let Some((name, _)) = self.names.iter().find(|(_, id)| *id == stream_id) {
+            return Err(format!("Stream {} cannot have id {}, stream {} has it already", stream.name(), stream_id, name));
+        }
+        if self.names.iter().any(|(name, _)| *name == stream.name()) {
+            return Err(format!("Stream {} is registered already", stream.name()));
+        }
+        self.definitions.push(stream.to_json().into_bytes());
+        self.record_layouts.insert(stream_id, stream.record_layout());
+        self.names.push((stream.name(), stream_id));
+        Ok(StreamHandle(Arc::new(stream)))
+    }
+
+    // One past the highest id registered, starting from 1.
+    fn next_stream_id(&self) -> u32 {
+        self.names.iter().map(|(_, id)| id + 1).max().unwrap_or(1)
+    }
+}
+
+// Which record log drops when the logging thread has fallen behind and the queue is full.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OverflowPolicy {
+    // Clients see the latest, with a gap before it
+    DropOldest,
+    // Clients see everything up to the gap, which lasts until the queue drains
+    DropNewest,
+}
+
 pub struct SocketTelemetryServerBuilder {
-    stream_definitions: Vec<Vec<u8>>
+    streams: StreamRegistry,
+    max_connections: usize,
+    dead_time: Duration,
+    log_queue_length: usize,
+    overflow_policy: OverflowPolicy,
+    serial_output: Option<SerialOutputConfig>,
+    file_output: Option<FileOutputConfig>,
 }
 
 impl SocketTelemetryServerBuilder {
     pub fn new() -> SocketTelemetryServerBuilder {
         SocketTelemetryServerBuilder {
-            stream_definitions: vec![]
+            streams: StreamRegistry::default(),
+            max_connections: DEFAULT_MAX_CONNECTIONS,
+            dead_time: DEFAULT_DEAD_TIME,
+            log_queue_length: DEFAULT_LOG_QUEUE_LENGTH,
+            overflow_policy: OverflowPolicy::DropOldest,
+            serial_output: None,
+            file_output: None,
         }
     }
 
-    pub fn register_stream(&mut self, stream: TelemetryStreamDefinition) -> TelemetryStreamDefinition {
-        self.stream_definitions.push(stream.to_json().into_bytes());
-        stream
+    pub fn max_connections(&mut self, max_connections: usize) {
+        self.max_connections = max_connections;
+    }
+
+    // Connections that could not finish a write for this long are dropped.
+    pub fn dead_time(&mut self, dead_time: Duration) {
+        self.dead_time = dead_time;
+    }
+
+    // Records that may wait for the logging thread, at least 1.
+    pub fn log_queue_length(&mut self, log_queue_length: usize) {
+        self.log_queue_length = log_queue_length.max(1);
+    }
+
+    pub fn overflow_policy(&mut self, overflow_policy: OverflowPolicy) {
+        self.overflow_policy = overflow_policy;
+    }
+
+    // Also sends every decimation-th record of the named streams over a serial line, framed with a CRC.
+    // See telemetry_serial for the frame format. Only streams registered before create are sent.
+    pub fn serial_output(&mut self, device: &str, baud: u32, stream_whitelist: Vec<&str>, decimation: u32) {
+        self.serial_output = Some(SerialOutputConfig {
+            device: device.to_string(),
+            baud,
+            stream_whitelist: stream_whitelist.iter().map(|name| name.to_string()).collect(),
+            decimation,
+        });
+    }
+
+    // Also writes every record to local files; see telemetry_file for the file format and naming.
+    pub fn file_output(&mut self, path: &str, flush_interval: Duration, max_file_size: u64) {
+        self.file_output = Some(FileOutputConfig {
+            path: path.to_string(),
+            flush_interval,
+            max_file_size,
+        });
+    }
+
+    // Fails when another stream has the same id or name.
+    pub fn register_stream(&mut self, stream: TelemetryStreamDefinition) -> Result<StreamHandle, String> {
+        self.streams.add(stream)
+    }
+
+    // Same as register_stream with the next free id.
+    pub fn add_stream(&mut self, name: &'static str, fields: Vec<Box<dyn TelemetryStreamField + Sync + Send>>) -> Result<StreamHandle, String> {
+        let stream_id = self.streams.next_stream_id();
+        self.streams.add(TelemetryStreamDefinition::new(name, stream_id, fields))
     }
 
     pub fn create(self, port: u16) -> SocketTelemetryServer {
-        SocketTelemetryServer::new(port, Arc::new(self.stream_definitions.clone()))
+        SocketTelemetryServer::new(self, port)
+    }
+
+    // Same as create with the default flush interval and file size limit for file_output.
+    pub fn create_with_file(mut self, port: u16, path: &str) -> SocketTelemetryServer {
+        self.file_output(path, telemetry_file::DEFAULT_FLUSH_INTERVAL, telemetry_file::DEFAULT_MAX_FILE_SIZE);
+        self.create(port)
+    }
+}
+
+impl Default for SocketTelemetryServerBuilder {
+    fn default() -> SocketTelemetryServerBuilder {
+        SocketTelemetryServerBuilder::new()
+    }
+}
+
+#[derive(Clone, Copy, Default, Debug)]
+pub struct TelemetryServerStats {
+    pub connections: usize,
+    pub rejected_connections: usize,
+    pub dropped_connections: usize,
+    pub dropped_records: usize,
+    pub serial_records: usize,
+    pub serial_dropped: usize,
+    pub serial_reopened: usize,
+    pub file_records: usize,
+    pub file_dropped: usize,
+    pub files: usize,
+    // Record buffers handed out by buffer(), from the pool and newly allocated
+    pub reused_buffers: usize,
+    pub allocated_buffers: usize,
+}
+
+#[derive(Default)]
+struct Counters {
+    connections: AtomicUsize,
+    rejected_connections: AtomicUsize,
+    dropped_connections: AtomicUsize,
+    dropped_records: AtomicUsize,
+    reused_buffers: AtomicUsize,
+    allocated_buffers: AtomicUsize,
+    serial: Arc<SerialCounters>,
+    file: Arc<FileCounters>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ClientRequest {
+    Subscribe(Vec<u32>),
+}
+
+// Assembles requests from the bytes a client sends after the handshake, which may be split anywhere.
+// Anything that is not a request leaves the parser failed, ignoring the rest of what the client sends.
+pub struct RequestParser {
+    byte_order: WireByteOrder,
+    buf: Vec<u8>,
+    failed: bool,
+}
+
+impl RequestParser {
+    pub fn new(byte_order: WireByteOrder) -> RequestParser {
+        RequestParser { byte_order, buf: vec![], failed: false }
+    }
+
+    // Requests completed by bytes, in the order they were sent.
+    pub fn feed(&mut self, bytes: &[u8]) -> Vec<ClientRequest> {
+        let mut requests = vec![];
+        if self.failed {
+            return requests;
+        }
+        self.buf.extend_from_slice(bytes);
+        while self.buf.len() >= 8 {
+            let count = read_u32(&self.buf[4..8], self.byte_order) as usize;
+            if &self.buf[0..4] != SUBSCRIBE_REQUEST || count > MAX_SUBSCRIBED_STREAMS {
+                self.failed = true;
+                self.buf.clear();
+                break;
+            }
+            let len = 8 + count * 4;
+            if self.buf.len() < len {
+                break;
+            }
+            let stream_ids = self.buf[8..len].chunks(4).map(|id| read_u32(id, self.byte_order)).collect();
+            requests.push(ClientRequest::Subscribe(stream_ids));
+            self.buf.drain(..len);
+        }
+        requests
+    }
+
+    pub fn is_failed(&self) -> bool {
+        self.failed
+    }
+}
+
+struct Connection {
+    stream: TcpStream,
+    peer: Option<SocketAddr>,
+    byte_order: WireByteOrder,
+    last_progress: Instant,
+    // Rest of a message the socket only took part of; it goes out before anything else
+    pending: Vec<u8>,
+    consecutive_failures: u32,
+    requests: RequestParser,
+    // Stream ids the client subscribed to; None for all
+    subscriptions: Option<HashSet<u32>>,
+}
+
+impl Connection {
+    // received is whatever the handshake read past its own requests.
+    fn new(stream: TcpStream, byte_order: WireByteOrder, received: Vec<u8>) -> Connection {
+        let _ = stream.set_write_timeout(Some(WRITE_TIMEOUT));
+        let mut connection = Connection {
+            peer: stream.peer_addr().ok(),
+            stream,
+            byte_order,
+            last_progress: Instant::now(),
+            pending: vec![],
+            consecutive_failures: 0,
+            requests: RequestParser::new(byte_order),
+            subscriptions: None,
+        };
+        connection.process_requests(&received);
+        connection
+    }
+
+    // Takes in whatever the client has sent so far, without waiting for more.
+    fn poll_requests(&mut self) {
+        if self.requests.is_failed() || self.stream.set_nonblocking(true).is_err() {
+            return;
+        }
+        let mut received = vec![];
+        let mut buf = [0u8; 256];
+        while received.len() < MAX_REQUEST_BYTES_PER_POLL {
+            match self.stream.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => received.extend_from_slice(&buf[..n]),
+                Err(_) => break
+            }
+        }
+        let _ = self.stream.set_nonblocking(false);
+        self.process_requests(&received);
+    }
+
+    fn process_requests(&mut self, received: &[u8]) {
+        if received.is_empty() {
+            return;
+        }
+        for request in self.requests.feed(received) {
+            match request {
+                ClientRequest::Subscribe(stream_ids) => {
+                    println!("Telemetry connection {:?} subscribed to streams {:?}", self.peer, stream_ids);
+                    self.subscriptions = Some(stream_ids.into_iter().collect());
+                }
+            }
+        }
+        if self.requests.is_failed() {
+            warn!("Unexpected request from telemetry connection {:?}; ignoring anything else it sends", self.peer);
+        }
+    }
+
+    fn wants(&self, record: &[u8]) -> bool {
+        record.is_empty() || self.subscriptions.as_ref().is_none_or(|stream_ids| stream_ids.contains(&stream_id_of(record)))
+    }
+
+    // Sends buf after whatever is still pending instead of skipping it, for frames the client
+    // cannot do without.
+    fn send_all(&mut self, buf: &[u8]) -> bool {
+        self.pending.extend_from_slice(buf);
+        self.send(&[])
+    }
+
+    // Returns false once the connection is closed or has failed. While the rest of an earlier
+    // message is still pending buf is skipped, so the client only ever sees whole messages.
+    fn send(&mut self, buf: &[u8]) -> bool {
+        if self.pending.is_empty() {
+            // Writing nothing returns Ok(0), which would otherwise look like a closed connection
+            if buf.is_empty() {
+                return true;
+            }
+            self.pending.extend_from_slice(buf);
+        }
+        match self.stream.write(&self.pending) {
+            Ok(0) => false,
+            Ok(n) => {
+                self.pending.drain(..n);
+                self.last_progress = Instant::now();
+                self.consecutive_failures = 0;
+                true
+            },
+            Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock || e.kind() == std::io::ErrorKind::TimedOut => {
+                self.consecutive_failures += 1;
+                true
+            },
+            Err(_) => false
+        }
+    }
+
+    // Why the connection should be dropped, if it should.
+    fn stalled(&self, dead_time: Duration) -> Option<String> {
+        if self.consecutive_failures >= MAX_CONSECUTIVE_FAILURES {
+            Some(format!("{} writes in a row timed out", self.consecutive_failures))
+        } else if !self.pending.is_empty() && self.last_progress.elapsed() > dead_time {
+            Some(format!("nothing written for {:?}", self.last_progress.elapsed()))
+        } else {
+            None
+        }
+    }
+}
+
+// A connection still in its handshake. Read without blocking, so the connection thread can go on
+// accepting others while a client stays silent or keeps sending PINGs.
+struct Handshake {
+    stream: TcpStream,
+    byte_order: WireByteOrder,
+    // Bytes read but not taken by a request yet
+    buf: Vec<u8>,
+    requests: usize,
+    last_request: Instant,
+}
+
+impl Handshake {
+    fn new(stream: TcpStream) -> Handshake {
+        let _ = stream.set_nonblocking(true);
+        Handshake { stream, byte_order: WireByteOrder::Little, buf: vec![], requests: 0, last_request: Instant::now() }
+    }
+
+    // Answers the requests the client has sent so far; returns true once the handshake is over,
+    // either with a request that is not a handshake one, after MAX_HANDSHAKE_REQUESTS or when
+    // nothing came for HANDSHAKE_TIMEOUT.
+    fn poll(&mut self, started: Instant) -> bool {
+        let mut closed = false;
+        let mut chunk = [0u8; 256];
+        loop {
+            match self.stream.read(&mut chunk) {
+                Ok(0) => { closed = true; break },
+                Ok(n) => self.buf.extend_from_slice(&chunk[..n]),
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(_) => { closed = true; break }
+            }
+        }
+        while self.requests < MAX_HANDSHAKE_REQUESTS && self.buf.len() >= 4 {
+            if &self.buf[0..4] == NETWORK_BYTE_ORDER_REQUEST {
+                self.byte_order = WireByteOrder::Network;
+                self.buf.drain(..4);
+            } else if &self.buf[0..4] == PING_REQUEST {
+                if self.buf.len() < 12 {
+                    break;
+                }
+                let (wall, monotonic) = clock(started);
+                let _ = self.stream.write(&pong_frame(&self.buf[4..12], wall, monotonic, self.byte_order));
+                self.buf.drain(..12);
+            } else {
+                return true;
+            }
+            self.requests += 1;
+            self.last_request = Instant::now();
+        }
+        closed || self.requests >= MAX_HANDSHAKE_REQUESTS || self.last_request.elapsed() >= HANDSHAKE_TIMEOUT
+    }
+
+    // Whatever the client sent past the handshake goes to the connection's requests.
+    fn into_connection(self) -> Connection {
+        let _ = self.stream.set_nonblocking(false);
+        Connection::new(self.stream, self.byte_order, self.buf)
+    }
+}
+
+fn write_f64(buf: &mut [u8], value: f64, byte_order: WireByteOrder) {
+    match byte_order {
+        WireByteOrder::Little => LittleEndian::write_f64(buf, value),
+        WireByteOrder::Network => BigEndian::write_f64(buf, value),
+    }
+}
+
+// Wall clock (seconds since the epoch) and monotonic time (seconds since the server started).
+pub fn clock(started: Instant) -> (f64, f64) {
+    let wall = SystemTime::now().duration_since(UNIX_EPOCH).expect("Time went backwards").as_secs_f64();
+    (wall, started.elapsed().as_secs_f64())
+}
+
+// The frames below are the wire format; telemetry_fixtures/ holds an example of each; regenerate
+// it with the telemetry-fixtures binary when changing any of them.
+
+// STRS with the number of streams, then an STDF with the JSON definition of each.
+pub fn definitions_frames(stream_definitions: &[Vec<u8>], byte_order: WireByteOrder) -> Vec<u8> {
+    let mut buf = vec![0u8; 8];
+    buf[0..4].clone_from_slice("STRS".as_bytes());
+    write_u32(&mut buf[4..8], stream_definitions.len() as u32, byte_order);
+    for stream_definition in stream_definitions {
+        let mut header = [0u8; 8];
+        header[0..4].clone_from_slice("STDF".as_bytes());
+        write_u32(&mut header[4..], stream_definition.len() as u32, byte_order);
+        buf.extend_from_slice(&header);
+        buf.extend_from_slice(stream_definition);
+    }
+    buf
+}
+
+pub fn meta_frame_at(wall: f64, monotonic: f64, byte_order: WireByteOrder, capabilities: &[u8]) -> Vec<u8> {
+    let mut buf = vec![0u8; 24];
+    buf[0..4].clone_from_slice("META".as_bytes());
+    write_u32(&mut buf[4..8], 16 + capabilities.len() as u32, byte_order);
+    write_f64(&mut buf[8..16], wall, byte_order);
+    write_f64(&mut buf[16..24], monotonic, byte_order);
+    buf.extend_from_slice(capabilities);
+    buf
+}
+
+// client_time is echoed exactly as the client sent it.
+pub fn pong_frame(client_time: &[u8], wall: f64, monotonic: f64, byte_order: WireByteOrder) -> Vec<u8> {
+    let mut buf = vec![0u8; 32];
+    buf[0..4].clone_from_slice("PONG".as_bytes());
+    write_u32(&mut buf[4..8], 24, byte_order);
+    buf[8..16].clone_from_slice(client_time);
+    write_f64(&mut buf[16..24], wall, byte_order);
+    write_f64(&mut buf[24..32], monotonic, byte_order);
+    buf
+}
+
+fn meta_frame(started: Instant, byte_order: WireByteOrder, capabilities: &Mutex<Vec<u8>>) -> Vec<u8> {
+    let (wall, monotonic) = clock(started);
+    meta_frame_at(wall, monotonic, byte_order, &capabilities.lock().unwrap())
+}
+
+fn write_u32(buf: &mut [u8], value: u32, byte_order: WireByteOrder) {
+    match byte_order {
+        WireByteOrder::Little => LittleEndian::write_u32(buf, value),
+        WireByteOrder::Network => BigEndian::write_u32(buf, value),
+    }
+}
+
+fn read_u32(buf: &[u8], byte_order: WireByteOrder) -> u32 {
+    match byte_order {
+        WireByteOrder::Little => LittleEndian::read_u32(buf),
+        WireByteOrder::Network => BigEndian::read_u32(buf),
+    }
+}
+
+// SUBS request as a client sends it.
+pub fn subscribe_request(stream_ids: &[u32], byte_order: WireByteOrder) -> Vec<u8> {
+    let mut buf = vec![0u8; 8 + stream_ids.len() * 4];
+    buf[0..4].clone_from_slice(SUBSCRIBE_REQUEST);
+    write_u32(&mut buf[4..8], stream_ids.len() as u32, byte_order);
+    for (i, stream_id) in stream_ids.iter().enumerate() {
+        write_u32(&mut buf[8 + i * 4..12 + i * 4], *stream_id, byte_order);
+    }
+    buf
+}
+
+fn stream_id_of(record: &[u8]) -> u32 {
+    if record[0] & 1 != 0 {
+        LittleEndian::read_u16(&record[1..3]) as u32
+    } else {
+        record[1] as u32
+    }
+}
+
+// Buffers of records that went out, so logging does not allocate one per record. Keeps as many as
+// can be queued, so a full queue's worth comes back once it drains.
+#[derive(Clone)]
+struct BufferPool {
+    buffers: Arc<Mutex<Vec<Vec<u8>>>>,
+    max_buffers: usize,
+}
+
+impl BufferPool {
+    fn new(max_buffers: usize) -> BufferPool {
+        BufferPool { buffers: Arc::new(Mutex::new(vec![])), max_buffers }
+    }
+
+    fn take(&self) -> Option<Vec<u8>> {
+        self.buffers.lock().unwrap().pop()
+    }
+
+    // Buffers that never held anything are not worth keeping.
+    fn put(&self, mut buf: Vec<u8>) {
+        if buf.capacity() == 0 {
+            return;
+        }
+        let mut buffers = self.buffers.lock().unwrap();
+        if buffers.len() < self.max_buffers {
+            buf.clear();
+            buffers.push(buf);
+        }
     }
 }
 
 pub struct SocketTelemetryServer {
     port: u16,
-    log_sender: mpsc::Sender<Vec<u8>>,
-    stop_log_sender: mpsc::Sender<bool>,
-    stop_con_sender: mpsc::Sender<bool>,
+    log_sender: Sender<Vec<u8>>,
+    // Used to drop the oldest queued record when the queue is full
+    log_overflow: Receiver<Vec<u8>>,
+    overflow_policy: OverflowPolicy,
+    buffer_pool: BufferPool,
+    stopping: Arc<AtomicBool>,
+    counters: Arc<Counters>,
+    // Monotonic times count from here
+    started: Instant,
+    capabilities: Arc<Mutex<Vec<u8>>>,
+    streams: Mutex<StreamRegistry>,
+    // Streams registered after create, for the logging thread to announce
+    late_streams: Sender<StreamHandle>,
     con_thread: thread::JoinHandle<()>,
     log_thread: thread::JoinHandle<()>
 }
 
 impl SocketTelemetryServer {
-    pub fn new(port: u16, streams: Arc<Vec<Vec<u8>>>) -> SocketTelemetryServer {
+    fn new(builder: SocketTelemetryServerBuilder, port: u16) -> SocketTelemetryServer {
+        let SocketTelemetryServerBuilder { streams: registry, max_connections, dead_time, log_queue_length, overflow_policy, serial_output, file_output } = builder;
         let listener = TcpListener::bind(format!("0.0.0.0:{}", port)).unwrap();
+        let port = listener.local_addr().map(|addr| addr.port()).unwrap_or(port);
 
-        let (log_tx, log_rx) = mpsc::channel();
+        let (log_tx, log_rx) = bounded(log_queue_length);
         let (con_tx, con_rx) = mpsc::channel();
-        let (stop_log_tx, stop_log_rx) = mpsc::channel();
-        let (stop_con_tx, stop_con_rx) = mpsc::channel();
+        let (late_tx, late_rx) = crossbeam_channel::unbounded::<StreamHandle>();
+        let mut streams = registry.definitions.clone();
+        let mut record_layouts = registry.record_layouts.clone();
+        let stopping = Arc::new(AtomicBool::new(false));
+        let con_stopping = stopping.clone();
+        let counters = Arc::new(Counters::default());
+        let started = Instant::now();
+        let con_counters = counters.clone();
+        let log_counters = counters.clone();
+        let capabilities = Arc::new(Mutex::new(vec![]));
+        let log_capabilities = capabilities.clone();
+        let buffer_pool = BufferPool::new(log_queue_length);
+        let log_buffer_pool = buffer_pool.clone();
+        let mut serial = serial_output.map(|config| SerialTransport::start(config, &registry.names, &streams, started, counters.serial.clone(), capabilities.clone()));
+        let mut file = file_output.map(|config| FileTelemetrySink::start(config, &streams, started, counters.file.clone(), capabilities.clone()));
 
         SocketTelemetryServer {
             port,
             log_sender: log_tx,
-            stop_log_sender: stop_log_tx,
-            stop_con_sender: stop_con_tx,
+            log_overflow: log_rx.clone(),
+            overflow_policy,
+            buffer_pool,
+            stopping,
+            counters,
+            started,
+            capabilities,
+            streams: Mutex::new(registry),
+            late_streams: late_tx,
             con_thread: thread::spawn(move || {
-                for stream in listener.incoming() {
-                    match stop_con_rx.try_recv() {
-                        Ok(_) => break,
-                        _ => {}
-                    };
-
-                    match stream {
-                        Ok(stream) => {
-                            println!("Received new connection...");
-                            con_tx.send(stream).unwrap();
+                let mut last_reject_log: Option<Instant> = None;
+                let mut rejected_since_log: usize = 0;
+                // Count against max_connections from the moment they are accepted
+                let mut handshakes: Vec<Handshake> = vec![];
+                if let Err(e) = listener.set_nonblocking(true) {
+                    error!("Cannot make telemetry listener non-blocking, stopping it: {:?}", e);
+                    return;
+                }
+                'accept: while !con_stopping.load(Ordering::SeqCst) {
+                    let mut i = 0;
+                    while i < handshakes.len() {
+                        if handshakes[i].poll(started) {
+                            let connection = handshakes.swap_remove(i).into_connection();
+                            println!("Received new connection ({:?} byte order)...", connection.byte_order);
+                            if con_tx.send(connection).is_err() {
+                                break 'accept;
+                            }
+                        } else {
+                            i += 1;
+                        }
+                    }
+                    let poll_interval = if handshakes.is_empty() { ACCEPT_POLL_INTERVAL } else { HANDSHAKE_POLL_INTERVAL };
+                    match listener.accept() {
+                        Ok((mut stream, _)) => {
+                            // Accepted sockets may inherit non-blocking mode
+                            let _ = stream.set_nonblocking(false);
+                            if con_counters.connections.load(Ordering::SeqCst) >= max_connections {
+                                let _ = stream.write(BUSY_FRAME);
+                                con_counters.rejected_connections.fetch_add(1, Ordering::SeqCst);
+                                rejected_since_log += 1;
+                                if last_reject_log.is_none_or(|last| last.elapsed() >= REJECT_LOG_INTERVAL) {
+                                    warn!("Rejected {} telemetry connection(s), already at the limit of {}", rejected_since_log, max_connections);
+                                    last_reject_log = Some(Instant::now());
+                                    rejected_since_log = 0;
+                                }
+                                continue;
+                            }
+                            con_counters.connections.fetch_add(1, Ordering::SeqCst);
+                            handshakes.push(Handshake::new(stream));
+                        },
+                        Err(ref e) if e.kind() == ErrorKind::WouldBlock => thread::sleep(poll_interval),
+                        Err(e) => {
+                            debug!("Failed to accept telemetry connection: {:?}", e);
+                            thread::sleep(poll_interval);
                         }
-                        _ => {}
                     }
                 }
                 println!("Finishing connection thread.");
             }),
             log_thread: thread::spawn(move || {
-                let mut connections: Vec<TcpStream> = vec![];
-                for log_message in log_rx.iter() {
-                    match stop_log_rx.try_recv() {
-                        Ok(_) => break,
-                        _ => {}
+                let mut connections: Vec<Connection> = vec![];
+                let mut last_meta = Instant::now();
+                loop {
+                    // Ends once the server's sender is dropped in stop() and the records queued
+                    // before have gone out; on a timeout the empty message only gets new
+                    // connections going
+                    let log_message = match log_rx.recv_timeout(CONNECTION_POLL_INTERVAL) {
+                        Ok(log_message) => log_message,
+                        Err(RecvTimeoutError::Timeout) => vec![],
+                        Err(RecvTimeoutError::Disconnected) => break
                     };
 
+                    if last_meta.elapsed() >= META_INTERVAL {
+                        last_meta = Instant::now();
+                        for connection in connections.iter_mut() {
+                            let _ = connection.send(&meta_frame(started, connection.byte_order, &log_capabilities));
+                        }
+                    }
+
+                    // Connected clients get each new definition before any of its records
+                    for stream in late_rx.try_iter() {
+                        let definition = stream.to_json().into_bytes();
+                        for connection in connections.iter_mut() {
+                            let _ = connection.send_all(&definitions_frames(std::slice::from_ref(&definition), connection.byte_order));
+                        }
+                        if let Some(file) = &mut file {
+                            file.add_definition(definition.clone());
+                        }
+                        record_layouts.insert(stream.stream_id(), stream.record_layout());
+                        streams.push(definition);
+                    }
+
                     // println!("Received log {}", log);
-                    for connection in con_rx.try_iter() {
+                    for mut connection in con_rx.try_iter() {
                         // println!("   and received new connection, sending streams back {}", streams[0].to_json());
-                        let mut con = &connection;
-                        // let _ = con.write(b"STRS");
-                        let mut buf = [0u8; 8];
-                        buf[0..4].clone_from_slice("STRS".as_bytes());
-                        LittleEndian::write_u32(&mut buf[4..], streams.len() as u32);
-                        let _ = con.write(&buf);
-                        // println!("Sent out {:?}", buf);
-
-                        for stream_definition in streams.iter(){
-                            // let _ = con.write(b"STDF");
-                            let mut buf = [0u8; 8];
-                            buf[0..4].clone_from_slice("STDF".as_bytes());
-                            LittleEndian::write_u32(&mut buf[4..], stream_definition.len() as u32);
-                            let _ = con.write(&buf);
-                            // println!("Sent out {:?}", buf);
-                            let _ = con.write(stream_definition);
-                        }
+                        let byte_order = connection.byte_order;
+                        let _ = connection.send(&definitions_frames(&streams, byte_order));
+                        let _ = connection.send(&meta_frame(started, byte_order, &log_capabilities));
                         connections.push(connection);
                     }
 
-                    for mut connection in connections.iter() {
-                        let con = &mut connection;
+                    if let Some(serial) = &mut serial {
+                        if !log_message.is_empty() {
+                            serial.offer(stream_id_of(&log_message), &log_message);
+                        }
+                    }
+                    if let Some(file) = &mut file {
+                        if !log_message.is_empty() {
+                            file.offer(&log_message);
+                        }
+                    }
+
+                    let mut network_order_message: Option<Vec<u8>> = None;
+                    let connections_before = connections.len();
+                    connections.retain_mut(|connection| {
                         // println!("Should send logged statement here to the connection...");
                         // let _ = con.write(log.to_string().as_bytes());
-                        let _ = con.write(&log_message);
+                        connection.poll_requests();
+                        let alive = if !connection.wants(&log_message) {
+                            true
+                        } else {
+                            match connection.byte_order {
+                                WireByteOrder::Little => connection.send(&log_message),
+                                WireByteOrder::Network => {
+                                    if network_order_message.is_none() && !log_message.is_empty() {
+                                        let mut converted = log_message.clone();
+                                        if let Some(layout) = record_layouts.get(&stream_id_of(&log_message)) {
+                                            layout.to_network_order(&mut converted);
+                                        }
+                                        network_order_message = Some(converted);
+                                    }
+                                    match &network_order_message {
+                                        Some(message) => connection.send(message),
+                                        None => true
+                                    }
+                                }
+                            }
+                        };
+                        let reason = if alive { connection.stalled(dead_time) } else { Some("connection closed".to_string()) };
+                        match reason {
+                            Some(reason) => {
+                                warn!("Dropping telemetry connection {:?}: {}", connection.peer, reason);
+                                false
+                            },
+                            None => true
+                        }
+                    });
+                    let dropped = connections_before - connections.len();
+                    if dropped > 0 {
+                        log_counters.connections.fetch_sub(dropped, Ordering::SeqCst);
+                        log_counters.dropped_connections.fetch_add(dropped, Ordering::SeqCst);
                     }
+                    // Sockets, serial and file have all copied or written it by now
+                    log_buffer_pool.put(log_message);
+                }
+                if let Some(serial) = &mut serial {
+                    serial.stop();
+                }
+                if let Some(file) = &mut file {
+                    file.stop();
                 }
                 println!("Finishing logging thread.");
             })
         }
     }
 
-    pub fn stop(self) {
-        let _ = self.stop_log_sender.send(true);
-        let _ = self.stop_con_sender.send(true);
-        let _ = self.log_sender.send(vec![]);
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    pub fn started(&self) -> Instant {
+        self.started
+    }
+
+    // Time for the start of a record, in the stream's timebase.
+    pub fn timestamp(&self, timebase: Timebase) -> f64 {
+        let (wall, monotonic) = clock(self.started);
+        match timebase {
+            Timebase::Wall => wall,
+            Timebase::Monotonic => monotonic,
+        }
+    }
+
+    pub fn stats(&self) -> TelemetryServerStats {
+        TelemetryServerStats {
+            connections: self.counters.connections.load(Ordering::SeqCst),
+            rejected_connections: self.counters.rejected_connections.load(Ordering::SeqCst),
+            dropped_connections: self.counters.dropped_connections.load(Ordering::SeqCst),
+            dropped_records: self.counters.dropped_records.load(Ordering::SeqCst),
+            serial_records: self.counters.serial.records.load(Ordering::SeqCst),
+            serial_dropped: self.counters.serial.dropped.load(Ordering::SeqCst),
+            serial_reopened: self.counters.serial.reopened.load(Ordering::SeqCst),
+            file_records: self.counters.file.records.load(Ordering::SeqCst),
+            file_dropped: self.counters.file.dropped.load(Ordering::SeqCst),
+            files: self.counters.file.files.load(Ordering::SeqCst),
+            reused_buffers: self.counters.reused_buffers.load(Ordering::SeqCst),
+            allocated_buffers: self.counters.allocated_buffers.load(Ordering::SeqCst),
+        }
+    }
+
+    // Empty buffer for the next record, from those the logging thread is done with when there
+    // are any; the log macros build records in these.
+    pub fn buffer(&self, capacity: usize) -> Vec<u8> {
+        match self.buffer_pool.take() {
+            Some(mut buf) => {
+                self.counters.reused_buffers.fetch_add(1, Ordering::SeqCst);
+                buf.reserve(capacity);
+                buf
+            },
+            None => {
+                self.counters.allocated_buffers.fetch_add(1, Ordering::SeqCst);
+                Vec::with_capacity(capacity)
+            }
+        }
+    }
+
+    // For streams of modules that start after the server. Clients connected already get the
+    // definition before any of its records, later ones with the rest; files get it too, serial
+    // output does not. Fails when another stream has the same id or name.
+    pub fn register_stream(&self, stream: TelemetryStreamDefinition) -> Result<StreamHandle, String> {
+        let mut streams = self.streams.lock().unwrap();
+        let handle = streams.add(stream)?;
+        let _ = self.late_streams.send(handle.clone());
+        Ok(handle)
+    }
+
+    // Same as register_stream with the next free id.
+    pub fn add_stream(&self, name: &'static str, fields: Vec<Box<dyn TelemetryStreamField + Sync + Send>>) -> Result<StreamHandle, String> {
+        let mut streams = self.streams.lock().unwrap();
+        let stream_id = streams.next_stream_id();
+        let handle = streams.add(TelemetryStreamDefinition::new(name, stream_id, fields))?;
+        let _ = self.late_streams.send(handle.clone());
+        Ok(handle)
+    }
+
+    // Appended to every META frame from now on, over TCP, serial and in files.
+    pub fn set_capabilities(&self, capabilities_json: &str) {
+        *self.capabilities.lock().unwrap() = capabilities_json.as_bytes().to_vec();
+    }
 
-        let mut stream = TcpStream::connect(format!("127.0.0.1:{}", self.port)).unwrap();
+    // Returns within STOP_TIMEOUT whether or not any client ever connected; a thread still
+    // running by then is left to finish on its own.
+    pub fn stop(self) -> Result<(), String> {
+        let SocketTelemetryServer { log_sender, log_overflow, stopping, con_thread, log_thread, .. } = self;
+        stopping.store(true, Ordering::SeqCst);
+        drop(log_sender);
+        drop(log_overflow);
 
-        let _ = stream.write(&[1]);
+        let deadline = Instant::now() + STOP_TIMEOUT;
+        while !(con_thread.is_finished() && log_thread.is_finished()) {
+            if Instant::now() >= deadline {
+                return Err(format!("telemetry threads did not finish within {:?} (connection thread {}, logging thread {})",
+                    STOP_TIMEOUT,
+                    if con_thread.is_finished() { "finished" } else { "running" },
+                    if log_thread.is_finished() { "finished" } else { "running" }));
+            }
+            thread::sleep(ACCEPT_POLL_INTERVAL);
+        }
+        let con_result = con_thread.join().map_err(|_| "telemetry connection thread panicked".to_string());
+        let log_result = log_thread.join().map_err(|_| "telemetry logging thread panicked".to_string());
+        con_result.and(log_result)
+    }
 
-        let _ = self.log_thread.join();
-        let _ = self.con_thread.join();
+    // Records log dropped, as in stats.
+    pub fn dropped(&self) -> u64 {
+        self.counters.dropped_records.load(Ordering::SeqCst) as u64
     }
 
+    // Never blocks; when the logging thread has fallen behind a record is dropped as the overflow
+    // policy says.
     pub fn log(&self, buf: Vec<u8>) {
-        self.log_sender.send(buf).unwrap();
+        match self.log_sender.try_send(buf) {
+            Ok(()) => {},
+            Err(TrySendError::Full(buf)) if self.overflow_policy == OverflowPolicy::DropNewest => {
+                self.buffer_pool.put(buf);
+                self.counters.dropped_records.fetch_add(1, Ordering::SeqCst);
+            },
+            Err(TrySendError::Full(buf)) => {
+                if let Ok(dropped) = self.log_overflow.try_recv() {
+                    self.buffer_pool.put(dropped);
+                }
+                self.counters.dropped_records.fetch_add(1, Ordering::SeqCst);
+                if let Err(e) = self.log_sender.try_send(buf) {
+                    self.buffer_pool.put(e.into_inner());
+                    self.counters.dropped_records.fetch_add(1, Ordering::SeqCst);
+                }
+            },
+            Err(TrySendError::Disconnected(_)) => {}
+        }
     }
 }
 
@@ -147,11 +929,9 @@ impl SocketTelemetryServer {
 macro_rules! log_with_time {
     ( $logger: expr, $stream: expr, $( $value:expr ),* ) => {
         {
-            let mut buf: Vec<u8> = Vec::with_capacity($stream.size());
+            let mut buf: Vec<u8> = $logger.buffer($stream.size());
 
-            let start = SystemTime::now();
-            let since_the_epoch = start.duration_since(UNIX_EPOCH).expect("Time went backwards");
-            let now = since_the_epoch.as_secs_f64();
+            let now = $logger.timestamp($stream.timebase());
 
             $stream.write_header(&mut buf);
             now.store(&mut buf);
@@ -159,7 +939,7 @@ macro_rules! log_with_time {
             let mut fields = $stream.fields();
             let mut i = 0;
             $(
-                i = i + 1;
+                i += 1;
                 match fields.next() {
                     Some(_field) => {
                         $value.store(&mut buf);
@@ -194,7 +974,7 @@ macro_rules! log_with_time {
 macro_rules! log {
     ( $logger: expr, $stream: expr, $time:expr, $( $value:expr ),* ) => {
         {
-            let mut buf: Vec<u8> = Vec::with_capacity($stream.size());
+            let mut buf: Vec<u8> = $logger.buffer($stream.size());
 
             $stream.write_header(&mut buf);
             $time.store(&mut buf);
@@ -202,7 +982,7 @@ macro_rules! log {
             let mut fields = $stream.fields();
             let mut i = 0;
             $(
-                i = i + 1;
+                i += 1;
                 match fields.next() {
                     Some(_field) => {
                         $value.store(&mut buf);
@@ -231,3 +1011,306 @@ macro_rules! log {
         }
     };
 }
+
+
+#[cfg(test)]
+mod tests {
+    use crate::telemetry_reader::{Frame, StreamDefinition, Value};
+    use crate::test_support::{connect, read_frames};
+    use super::*;
+
+    fn stop_in_time(server: SocketTelemetryServer) {
+        let (done_sender, done_receiver) = mpsc::channel();
+        thread::spawn(move || {
+            let _ = done_sender.send(server.stop());
+        });
+        done_receiver.recv_timeout(STOP_TIMEOUT * 2).expect("server did not stop in time").unwrap();
+    }
+
+    fn counter_stream(name: &'static str, stream_id: u32) -> TelemetryStreamDefinition {
+        TelemetryStreamDefinition::new(name, stream_id, vec![TelemetryStreamDefinition::unsigned_byte_field("i")])
+    }
+
+    #[test]
+    fn client_gets_the_stream_definitions_and_the_server_stops_in_time() {
+        let mut builder = SocketTelemetryServerBuilder::new();
+        builder.register_stream(TelemetryStreamDefinition::new("defs", 1, vec![
+            TelemetryStreamDefinition::double_field("x"),
+        ])).unwrap();
+        let server = builder.create(0);
+
+        let mut client = connect(server.port());
+        // Definitions go out with the next logged record, after the handshake window
+        thread::sleep(Duration::from_millis(200));
+        server.log(vec![]);
+
+        let mut header = [0u8; 4];
+        client.read_exact(&mut header).unwrap();
+        assert_eq!(&header, b"STRS");
+        assert_eq!(server.stats().connections, 1);
+
+        stop_in_time(server);
+    }
+
+    // Nobody ever connects, so nothing but the stop itself can end the accept loop.
+    #[test]
+    fn server_without_clients_stops_in_time() {
+        let mut builder = SocketTelemetryServerBuilder::new();
+        builder.register_stream(counter_stream("counter", 1)).unwrap();
+        stop_in_time(builder.create(0));
+    }
+
+    #[test]
+    fn subscribe_requests_are_assembled_however_they_are_split() {
+        let first = subscribe_request(&[2, 7], WireByteOrder::Network);
+        let second = subscribe_request(&[], WireByteOrder::Network);
+        let mut parser = RequestParser::new(WireByteOrder::Network);
+        let mut requests = vec![];
+        for byte in first.iter().chain(second.iter()) {
+            requests.extend(parser.feed(&[*byte]));
+        }
+        assert_eq!(requests, vec![ClientRequest::Subscribe(vec![2, 7]), ClientRequest::Subscribe(vec![])]);
+
+        let mut both = first.clone();
+        both.extend_from_slice(&second);
+        assert_eq!(RequestParser::new(WireByteOrder::Network).feed(&both).len(), 2);
+
+        let mut parser = RequestParser::new(WireByteOrder::Little);
+        assert!(parser.feed(b"HELOxxxx").is_empty());
+        assert!(parser.is_failed());
+        assert!(parser.feed(&first).is_empty());
+    }
+
+    #[test]
+    fn subscribed_client_only_gets_records_of_its_streams() {
+        let mut builder = SocketTelemetryServerBuilder::new();
+        let unwanted = builder.register_stream(counter_stream("unwanted", 1)).unwrap();
+        let wanted = builder.register_stream(counter_stream("wanted", 2)).unwrap();
+        let server = builder.create(0);
+
+        let mut client = connect(server.port());
+        thread::sleep(Duration::from_millis(200));
+        client.write_all(&subscribe_request(&[2], WireByteOrder::Little)).unwrap();
+        thread::sleep(Duration::from_millis(50));
+        log_with_time!(server, unwanted, 1u8);
+        log_with_time!(server, wanted, 2u8);
+
+        let frames = read_frames(&mut client, |frame| matches!(frame, Frame::Record { .. }));
+        server.stop().unwrap();
+
+        match frames.last() {
+            Some(Frame::Record { stream_id, .. }) => assert_eq!(*stream_id, 2),
+            other => panic!("expected a record, got {:?}", other)
+        }
+    }
+
+    #[test]
+    fn stream_ids_and_names_are_unique_and_left_out_ids_come_after_the_highest() {
+        let mut builder = SocketTelemetryServerBuilder::new();
+        builder.register_stream(counter_stream("first", 5)).unwrap();
+        assert!(builder.register_stream(counter_stream("other", 5)).is_err());
+        assert!(builder.register_stream(counter_stream("first", 6)).is_err());
+        let second = builder.add_stream("second", vec![TelemetryStreamDefinition::unsigned_byte_field("i")]).unwrap();
+        assert_eq!(second.stream_id(), 6);
+
+        let server = builder.create(0);
+        assert!(server.register_stream(counter_stream("again", 6)).is_err());
+        server.stop().unwrap();
+    }
+
+    // The reader fails on records of streams it has no definition of
+    #[test]
+    fn late_stream_reaches_connected_clients_before_its_records() {
+        let mut builder = SocketTelemetryServerBuilder::new();
+        builder.register_stream(counter_stream("first", 5)).unwrap();
+        builder.add_stream("second", vec![TelemetryStreamDefinition::unsigned_byte_field("i")]).unwrap();
+        let server = builder.create(0);
+
+        let mut client = connect(server.port());
+        thread::sleep(Duration::from_millis(200));
+        server.log(vec![]);
+        thread::sleep(Duration::from_millis(50));
+
+        let late = server.add_stream("late", vec![TelemetryStreamDefinition::unsigned_byte_field("i")]).unwrap();
+        log_with_time!(server, late, 3u8);
+
+        let frames = read_frames(&mut client, |frame| matches!(frame, Frame::Record { .. }));
+        server.stop().unwrap();
+
+        let definitions: Vec<(String, u32)> = frames.iter().filter_map(|frame| match frame {
+            Frame::Definition(definition) => Some((definition.name.clone(), definition.id)),
+            _ => None
+        }).collect();
+        assert_eq!(definitions, vec![("first".to_string(), 5), ("second".to_string(), 6), ("late".to_string(), 7)]);
+        match frames.last() {
+            Some(Frame::Record { stream_id, .. }) => assert_eq!(*stream_id, 7),
+            other => panic!("expected a record, got {:?}", other)
+        }
+    }
+
+    // Logged one at a time, records go out in the buffer the previous one came back in
+    #[test]
+    fn pooled_buffers_carry_the_same_values_as_new_ones() {
+        let mut builder = SocketTelemetryServerBuilder::new();
+        let stream = builder.register_stream(TelemetryStreamDefinition::new("pooled", 1, vec![
+            TelemetryStreamDefinition::unsigned_word_field("i"),
+            TelemetryStreamDefinition::double_field("x"),
+        ])).unwrap();
+        let server = builder.create(0);
+
+        let mut client = connect(server.port());
+        thread::sleep(Duration::from_millis(200));
+
+        let count = 50u16;
+        for i in 0..count {
+            log_with_time!(server, stream, i, i as f64 / 2.0);
+            thread::sleep(Duration::from_millis(2));
+        }
+
+        let mut records = 0;
+        let frames = read_frames(&mut client, |frame| {
+            if let Frame::Record { .. } = frame {
+                records += 1;
+            }
+            records == count
+        });
+        let stats = server.stats();
+        server.stop().unwrap();
+
+        let values: Vec<Vec<Value>> = frames.into_iter().filter_map(|frame| match frame {
+            Frame::Record { values, .. } => Some(values.into_iter().map(|(_, value)| value).collect()),
+            _ => None
+        }).collect();
+        for (i, record) in values.iter().enumerate() {
+            assert_eq!(*record, vec![Value::Unsigned(i as u64), Value::Float(i as f64 / 2.0)]);
+        }
+        assert_eq!(stats.reused_buffers + stats.allocated_buffers, count as usize);
+        assert!(stats.allocated_buffers <= 2, "{} buffers allocated", stats.allocated_buffers);
+    }
+
+    // A new client gets the stream definitions while nothing is logged
+    #[test]
+    fn stalled_client_is_dropped_without_logging_blocking() {
+        let mut builder = SocketTelemetryServerBuilder::new();
+        let stream = builder.register_stream(TelemetryStreamDefinition::new("wide", 1, (0..16).map(|i| {
+            TelemetryStreamDefinition::double_field(["a", "b", "c", "d", "e", "f", "g", "h", "i", "j", "k", "l", "m", "n", "o", "p"][i])
+        }).collect())).unwrap();
+        builder.dead_time(Duration::from_millis(200));
+        builder.log_queue_length(64);
+        builder.overflow_policy(OverflowPolicy::DropNewest);
+        let server = builder.create(0);
+
+        let mut client = connect(server.port());
+        let mut header = [0u8; 4];
+        client.read_exact(&mut header).unwrap();
+        assert_eq!(&header, b"STRS");
+
+        // The client reads nothing more, so the socket buffers fill up
+        let started = Instant::now();
+        while server.stats().dropped_connections == 0 && started.elapsed() < Duration::from_secs(5) {
+            for _ in 0..1000 {
+                log_with_time!(server, stream, 1.0f64, 2.0f64, 3.0f64, 4.0f64, 5.0f64, 6.0f64, 7.0f64, 8.0f64,
+                               9.0f64, 10.0f64, 11.0f64, 12.0f64, 13.0f64, 14.0f64, 15.0f64, 16.0f64);
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+        let stats = server.stats();
+        let dropped = server.dropped();
+        server.stop().unwrap();
+
+        assert_eq!((stats.dropped_connections, stats.connections), (1, 0), "{:?}", stats);
+        // While the writes were timing out the queue must have filled up rather than blocked
+        assert!(stats.dropped_records > 0, "{:?}", stats);
+        assert!(dropped >= stats.dropped_records as u64, "{:?}, dropped() {}", stats, dropped);
+    }
+
+    // Monotonic time counts from the server start, as META frames do.
+    #[test]
+    fn records_are_timed_in_their_streams_timebase() {
+        let mut builder = SocketTelemetryServerBuilder::new();
+        let wall = builder.register_stream(TelemetryStreamDefinition::new("wall", 1, vec![])).unwrap();
+        let monotonic = builder.register_stream(TelemetryStreamDefinition::new("monotonic", 2, vec![]).with_timebase(Timebase::Monotonic)).unwrap();
+        let server = builder.create(0);
+        let times = (server.timestamp(wall.timebase()), server.timestamp(monotonic.timebase()));
+        let elapsed = server.started().elapsed().as_secs_f64();
+        server.stop().unwrap();
+        assert!(times.0 > 1e9, "wall time {}", times.0);
+        assert!(times.1 >= 0.0 && times.1 <= elapsed, "monotonic time {}, {} s after start", times.1, elapsed);
+
+        for stream in [&wall, &monotonic].iter() {
+            assert_eq!(StreamDefinition::parse(&stream.to_json()).unwrap().timebase, stream.timebase());
+        }
+        let old = StreamDefinition::parse("{ \"id\" : 1, \"name\" : \"old\", \"endianness\" : \"little\", \"fields\" : { } }").unwrap();
+        assert_eq!(old.timebase, Timebase::Wall);
+        assert!(Timebase::parse("boot").is_err());
+    }
+
+    #[test]
+    fn connections_over_the_limit_get_busy_and_are_closed() {
+        let mut builder = SocketTelemetryServerBuilder::new();
+        builder.max_connections(2);
+        let server = builder.create(0);
+
+        let mut accepted: Vec<TcpStream> = (0..2).map(|_| connect(server.port())).collect();
+        for stream in accepted.iter_mut() {
+            let mut header = [0u8; 8];
+            stream.read_exact(&mut header).unwrap();
+            assert_eq!(&header[0..4], b"STRS");
+        }
+
+        for _ in 0..3 {
+            let mut stream = connect(server.port());
+            let mut received = vec![];
+            stream.read_to_end(&mut received).unwrap();
+            assert_eq!(received, BUSY_FRAME);
+        }
+
+        let stats = server.stats();
+        assert_eq!(stats.rejected_connections, 3);
+        assert_eq!(stats.connections, 2);
+
+        drop(accepted);
+        server.stop().unwrap();
+    }
+
+    // PING as a client sends it, returning the echoed client time from the PONG.
+    fn ping(stream: &mut TcpStream, client_time: u64) -> u64 {
+        let mut request = PING_REQUEST.to_vec();
+        request.extend_from_slice(&client_time.to_le_bytes());
+        stream.write_all(&request).unwrap();
+        let mut pong = [0u8; 32];
+        stream.read_exact(&mut pong).unwrap();
+        assert_eq!(&pong[0..4], b"PONG");
+        LittleEndian::read_u64(&pong[8..16])
+    }
+
+    #[test]
+    fn a_client_still_in_its_handshake_does_not_hold_up_others() {
+        let server = SocketTelemetryServerBuilder::new().create(0);
+
+        // Keeps its handshake going for about a second, well past HANDSHAKE_TIMEOUT
+        let mut pinging = connect(server.port());
+        assert_eq!(ping(&mut pinging, 1), 1);
+        let pinger = thread::spawn(move || {
+            for client_time in 2..50 {
+                thread::sleep(Duration::from_millis(20));
+                assert_eq!(ping(&mut pinging, client_time), client_time);
+            }
+            pinging
+        });
+
+        thread::sleep(Duration::from_millis(50));
+        let mut other = connect(server.port());
+        let asked = Instant::now();
+        assert_eq!(ping(&mut other, 7), 7);
+        assert!(asked.elapsed() < Duration::from_millis(500), "PONG took {:?}", asked.elapsed());
+
+        let mut header = [0u8; 8];
+        other.read_exact(&mut header).unwrap();
+        assert_eq!(&header[0..4], b"STRS");
+        assert!(!pinger.is_finished(), "first client's handshake ended before the second one's");
+
+        drop(pinger.join().unwrap());
+        server.stop().unwrap();
+    }
+}
@@ -15,9 +15,50 @@ use std::io::Write;
 use byteorder::{WriteBytesExt, LittleEndian};
 
 
+// Byte order records are stored in. Connections may ask for network order instead.
+pub const STREAM_ENDIANNESS: &str = "little";
+
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum WireByteOrder {
+    Little,
+    Network,
+}
+
+
+// What the time at the start of each record counts from. Wall clock time lines up with other
+// logs but jumps when NTP steps the clock (the Pi syncs a minute or so after boot); monotonic
+// time is seconds since the telemetry server started, as in META frames, and never jumps.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Timebase {
+    Wall,
+    Monotonic,
+}
+
+impl Timebase {
+    pub fn parse(s: &str) -> Result<Timebase, String> {
+        match s {
+            "wall" => Ok(Timebase::Wall),
+            "monotonic" => Ok(Timebase::Monotonic),
+            _ => Err(format!("Timebase must be wall or monotonic, not {}", s))
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Timebase::Wall => "wall",
+            Timebase::Monotonic => "monotonic",
+        }
+    }
+}
+
+
 pub trait FieldType {
     fn size(&self) -> usize;
 
+    // String and byte array fields are passed through unchanged when converting byte order.
+    fn is_byte_order_dependent(&self) -> bool { true }
+
     fn type_shortcode(&self) -> &'static str;
 
     fn definition_to_json(&self, size: usize) -> String;
@@ -147,6 +188,8 @@ struct FieldTypeString;
 impl FieldType for FieldTypeString {
     fn size(&self) -> usize { 0 }
 
+    fn is_byte_order_dependent(&self) -> bool { false }
+
     fn type_shortcode(&self) -> &'static str { "s" }
 
     fn definition_to_json(&self, size: usize) -> String {
@@ -159,6 +202,8 @@ struct FieldTypeBytes;
 impl FieldType for FieldTypeBytes {
     fn size(&self) -> usize { 0 }
 
+    fn is_byte_order_dependent(&self) -> bool { false }
+
     fn type_shortcode(&self) -> &'static str { "a" }
 
     fn definition_to_json(&self, size: usize) -> String {
@@ -239,6 +284,7 @@ pub trait TelemetryStreamField {
     fn name(&self) -> &'static str;
     fn to_json(&self) -> String;
     fn size(&self) -> usize;
+    fn is_byte_order_dependent(&self) -> bool;
 }
 
 
@@ -248,6 +294,44 @@ impl<T: FieldType> TelemetryStreamField for TelemetryStreamFieldStruct<T> {
        self.field_type.definition_to_json(self.field_size)
     }
     fn size(&self) -> usize { self.field_size }
+    fn is_byte_order_dependent(&self) -> bool { self.field_type.is_byte_order_dependent() }
+}
+
+
+// ----------------------------------------------------------------------------------------------------------
+
+// Sizes of the values in a record after its header, used to convert records to network byte order.
+#[derive(Clone)]
+pub struct RecordLayout {
+    values: Vec<(usize, bool)>
+}
+
+impl RecordLayout {
+    pub fn to_network_order(&self, record: &mut [u8]) {
+        let header_byte = record[0];
+        let mut pos = 1;
+
+        if header_byte & 1 != 0 {
+            record[pos..pos + 2].reverse();
+            pos += 2;
+        } else {
+            pos += 1;
+        }
+
+        let length_size = match header_byte & 6 { 0 => 1, 2 => 2, _ => 4 };
+        record[pos..pos + length_size].reverse();
+        pos += length_size;
+
+        for (size, byte_order_dependent) in self.values.iter() {
+            if pos + size > record.len() {
+                break;
+            }
+            if *byte_order_dependent {
+                record[pos..pos + size].reverse();
+            }
+            pos += size;
+        }
+    }
 }
 
 
@@ -256,6 +340,7 @@ impl<T: FieldType> TelemetryStreamField for TelemetryStreamFieldStruct<T> {
 pub struct TelemetryStreamDefinition {
     pub name: &'static str,
     stream_id: u32,
+    timebase: Timebase,
     fixed_length: usize,
     header: Vec<u8>,
     fields:Vec<Box<dyn TelemetryStreamField + Sync + Send>>
@@ -287,17 +372,31 @@ impl TelemetryStreamDefinition {
         TelemetryStreamDefinition {
             name,
             stream_id,
+            timebase: Timebase::Wall,
             fields,
             fixed_length,
             header
         }
     }
 
-    #[allow(dead_code)]
+    // Wall clock unless set otherwise.
+    pub fn with_timebase(mut self, timebase: Timebase) -> TelemetryStreamDefinition {
+        self.timebase = timebase;
+        self
+    }
+
+    pub fn timebase(&self) -> Timebase {
+        self.timebase
+    }
+
     pub fn name(&self) -> &'static str {
         self.name
     }
 
+    pub fn stream_id(&self) -> u32 {
+        self.stream_id
+    }
+
     pub fn to_json(&self) -> String {
         let mut s = String::from("");
         let mut first = true;
@@ -305,7 +404,14 @@ impl TelemetryStreamDefinition {
             if first { first = false; } else { s.push_str(", ") }
             s.push_str(format!("\"{}\" : {{ {} }}", field.name(), field.to_json()).as_str());
         }
-        format!("{{ \"id\" : {}, \"name\" : \"{}\", \"fields\" : {{ {} }} }}", self.stream_id, self.name, s)
+        format!("{{ \"id\" : {}, \"name\" : \"{}\", \"endianness\" : \"{}\", \"timebase\" : \"{}\", \"fields\" : {{ {} }} }}",
+                self.stream_id, self.name, STREAM_ENDIANNESS, self.timebase.name(), s)
+    }
+
+    pub fn record_layout(&self) -> RecordLayout {
+        let mut values = vec![(8, true)]; // time f64
+        values.extend(self.fields.iter().map(|field| (field.size(), field.is_byte_order_dependent())));
+        RecordLayout { values }
     }
 
     pub fn size(&self) -> usize {
@@ -316,11 +422,10 @@ impl TelemetryStreamDefinition {
         let _ = buf.write(&self.header);
     }
 
-    pub fn fields(&self) -> Iter<Box<dyn TelemetryStreamField + Sync + Send>> {
+    pub fn fields(&self) -> Iter<'_, Box<dyn TelemetryStreamField + Sync + Send>> {
         self.fields.iter()
     }
 
-    #[allow(dead_code)]
     pub fn unsigned_byte_field(name: &'static str) -> Box<dyn TelemetryStreamField + Sync + Send> {
         Box::new(TelemetryStreamFieldStruct::<FieldTypeUnsignedByte> {
             field_name: name,
@@ -329,7 +434,6 @@ impl TelemetryStreamDefinition {
         })
     }
 
-    #[allow(dead_code)]
     pub fn signed_byte_field(name: &'static str) -> Box<dyn TelemetryStreamField + Sync + Send> {
         Box::new(TelemetryStreamFieldStruct::<FieldTypeSignedByte> {
             field_name: name,
@@ -338,7 +442,6 @@ impl TelemetryStreamDefinition {
         })
     }
 
-    #[allow(dead_code)]
     pub fn unsigned_word_field(name: &'static str) -> Box<dyn TelemetryStreamField + Sync + Send> {
         Box::new(TelemetryStreamFieldStruct::<FieldTypeUnsignedWord> {
             field_name: name,
@@ -347,7 +450,6 @@ impl TelemetryStreamDefinition {
         })
     }
 
-    #[allow(dead_code)]
     pub fn signed_word_field(name: &'static str) -> Box<dyn TelemetryStreamField + Sync + Send> {
         Box::new(TelemetryStreamFieldStruct::<FieldTypeSignedWord> {
             field_name: name,
@@ -356,7 +458,6 @@ impl TelemetryStreamDefinition {
         })
     }
 
-    #[allow(dead_code)]
     pub fn unsigned_integer_field(name: &'static str) -> Box<dyn TelemetryStreamField + Sync + Send> {
         Box::new(TelemetryStreamFieldStruct::<FieldTypeUnsignedInteger> {
             field_name: name,
@@ -365,7 +466,6 @@ impl TelemetryStreamDefinition {
         })
     }
 
-    #[allow(dead_code)]
     pub fn signed_integer_field(name: &'static str) -> Box<dyn TelemetryStreamField + Sync + Send> {
         Box::new(TelemetryStreamFieldStruct::<FieldTypeSignedInteger> {
             field_name: name,
@@ -374,7 +474,6 @@ impl TelemetryStreamDefinition {
         })
     }
 
-    #[allow(dead_code)]
     pub fn unsigned_long_field(name: &'static str) -> Box<dyn TelemetryStreamField + Sync + Send> {
         Box::new(TelemetryStreamFieldStruct::<FieldTypeUnsignedLong> {
             field_name: name,
@@ -383,7 +482,6 @@ impl TelemetryStreamDefinition {
         })
     }
 
-    #[allow(dead_code)]
     pub fn signed_long_field(name: &'static str) -> Box<dyn TelemetryStreamField + Sync + Send> {
         Box::new(TelemetryStreamFieldStruct::<FieldTypeSignedLong> {
             field_name: name,
@@ -392,7 +490,6 @@ impl TelemetryStreamDefinition {
         })
     }
 
-    #[allow(dead_code)]
     pub fn float_field(name: &'static str) -> Box<dyn TelemetryStreamField + Sync + Send> {
         Box::new(TelemetryStreamFieldStruct::<FieldTypeFloat> {
             field_name: name,
@@ -401,7 +498,6 @@ impl TelemetryStreamDefinition {
         })
     }
 
-    #[allow(dead_code)]
     pub fn double_field(name: &'static str) -> Box<dyn TelemetryStreamField + Sync + Send> {
         Box::new(TelemetryStreamFieldStruct::<FieldTypeDouble> {
             field_name: name,
@@ -410,7 +506,6 @@ impl TelemetryStreamDefinition {
         })
     }
 
-    #[allow(dead_code)]
     pub fn string_field(name: &'static str, string_size: usize) -> Box<dyn TelemetryStreamField + Sync + Send> {
         Box::new(TelemetryStreamFieldStruct::<FieldTypeString> {
             field_name: name,
@@ -419,7 +514,6 @@ impl TelemetryStreamDefinition {
         })
     }
 
-    #[allow(dead_code)]
     pub fn bytes_field(name: &'static str, bytes_size: usize) -> Box<dyn TelemetryStreamField + Sync + Send> {
         Box::new(TelemetryStreamFieldStruct::<FieldTypeBytes> {
             field_name: name,
@@ -0,0 +1,85 @@
+//
+// Copyright (C) 2020 Abstract Horizon
+// All rights reserved. This program and the accompanying materials
+// are made available under the terms of the Apache License v2.0
+// which accompanies this distribution, and is available at
+// https://www.apache.org/licenses/LICENSE-2.0
+//
+//  Contributors:
+//    Daniel Sendula - initial API and implementation
+//
+
+// Helpers shared by the unit tests of the modules.
+
+use std::fs;
+use std::io::Read;
+use std::net::TcpStream;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+pub use crate::simulation::lock_boards;
+use crate::telemetry_reader::{Frame, TelemetryReader};
+use crate::telemetry_stream::WireByteOrder;
+
+static NEXT_DIR: AtomicUsize = AtomicUsize::new(0);
+
+
+// Directory of its own for each test, removed with everything in it when dropped.
+pub struct TempDir {
+    path: PathBuf,
+}
+
+impl TempDir {
+    pub fn new(name: &str) -> TempDir {
+        let path = std::env::temp_dir().join(format!("rover-test-{}-{}-{}", name, process::id(), NEXT_DIR.fetch_add(1, Ordering::SeqCst)));
+        fs::create_dir_all(&path).unwrap_or_else(|e| panic!("Cannot create {:?}: {}", path, e));
+        TempDir { path }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    // Path of a file in the directory, as the telemetry and storage modules take it.
+    pub fn file(&self, name: &str) -> String {
+        self.path.join(name).to_string_lossy().into_owned()
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
+
+
+// Loopback telemetry client that gives up on reads the server never answers.
+pub fn connect(port: u16) -> TcpStream {
+    let stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+    stream
+}
+
+// Frames a little endian client gets, up to and including the first one until is true for.
+pub fn read_frames(client: &mut TcpStream, mut until: impl FnMut(&Frame) -> bool) -> Vec<Frame> {
+    let mut reader = TelemetryReader::new(WireByteOrder::Little);
+    let mut received: Vec<u8> = vec![];
+    let mut frames = vec![];
+    loop {
+        let mut buf = [0u8; 1024];
+        let n = client.read(&mut buf).unwrap_or_else(|e| panic!("read {} frames, then {:?}", frames.len(), e));
+        assert!(n > 0, "connection closed after {} frames", frames.len());
+        received.extend_from_slice(&buf[..n]);
+        while let Some((len, frame)) = reader.read(&received).unwrap() {
+            received.drain(..len);
+            let done = until(&frame);
+            frames.push(frame);
+            if done {
+                return frames;
+            }
+        }
+    }
+}
+
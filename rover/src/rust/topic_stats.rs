@@ -0,0 +1,111 @@
+//
+// Copyright (C) 2020 Abstract Horizon
+// All rights reserved. This program and the accompanying materials
+// are made available under the terms of the Apache License v2.0
+// which accompanies this distribution, and is available at
+// https://www.apache.org/licenses/LICENSE-2.0
+//
+//  Contributors:
+//    Daniel Sendula - initial API and implementation
+//
+
+// What came in on each MQTT topic, for the status snapshot now that notifications are no longer printed.
+
+use std::collections::HashMap;
+
+// Longer payloads are cut to this many bytes
+pub const MAX_LAST_PAYLOAD_LEN: usize = 64;
+
+
+#[derive(Default, Clone, Debug, PartialEq)]
+pub struct TopicStats {
+    pub received: u64,
+    pub last_payload: String,
+    pub last_timestamp: f64,
+}
+
+impl TopicStats {
+    pub fn record(&mut self, payload: &[u8], timestamp: f64) {
+        self.received += 1;
+        self.last_timestamp = timestamp;
+        self.last_payload = String::from_utf8_lossy(&payload[..payload.len().min(MAX_LAST_PAYLOAD_LEN)]).to_string();
+    }
+}
+
+
+#[derive(Default)]
+pub struct TopicCounters {
+    topics: HashMap<String, TopicStats>,
+}
+
+impl TopicCounters {
+    pub fn new() -> TopicCounters {
+        TopicCounters::default()
+    }
+
+    pub fn record(&mut self, topic: &str, payload: &[u8], timestamp: f64) {
+        self.topics.entry(topic.to_string()).or_default().record(payload, timestamp);
+    }
+
+    pub fn get(&self, topic: &str) -> Option<&TopicStats> {
+        self.topics.get(topic)
+    }
+
+    // Object with a member per topic, sorted by topic.
+    pub fn to_json(&self) -> String {
+        let mut topics: Vec<&String> = self.topics.keys().collect();
+        topics.sort();
+
+        let mut s = String::from("");
+        let mut first = true;
+        for topic in topics {
+            let stats = &self.topics[topic];
+            if first { first = false; } else { s.push_str(", ") }
+            s.push_str(format!("\"{}\" : {{ \"received\" : {}, \"last_payload\" : {:?}, \"last_timestamp\" : {} }}",
+                topic, stats.received, stats.last_payload, stats.last_timestamp).as_str());
+        }
+        format!("{{ {} }}", s)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_every_message_per_topic_and_keeps_the_last() {
+        let mut counters = TopicCounters::new();
+        counters.record("balancing/start", b"", 10.0);
+        counters.record("storage/write/balance/pid_inner/p", b"0.5", 11.0);
+        counters.record("storage/write/balance/pid_inner/p", b"0.75", 12.5);
+
+        assert_eq!(counters.get("balancing/start"), Some(&TopicStats { received: 1, last_payload: "".to_string(), last_timestamp: 10.0 }));
+        assert_eq!(counters.get("storage/write/balance/pid_inner/p"), Some(&TopicStats { received: 2, last_payload: "0.75".to_string(), last_timestamp: 12.5 }));
+        assert_eq!(counters.get("balancing/stop"), None);
+    }
+
+    #[test]
+    fn long_and_binary_payloads_are_cut_and_kept_printable() {
+        let mut counters = TopicCounters::new();
+        counters.record("long", &[b'a'; MAX_LAST_PAYLOAD_LEN + 10], 1.0);
+        counters.record("binary", &[0xff, b'x'], 1.0);
+
+        assert_eq!(counters.get("long").unwrap().last_payload, "a".repeat(MAX_LAST_PAYLOAD_LEN));
+        assert_eq!(counters.get("binary").unwrap().last_payload, "\u{fffd}x");
+    }
+
+    #[test]
+    fn snapshot_lists_topics_sorted_with_escaped_payloads() {
+        let mut counters = TopicCounters::new();
+        assert_eq!(counters.to_json(), "{  }");
+
+        counters.record("manual", b"0.25", 2.0);
+        counters.record("balance/profile/save", b"\"fast\"", 1.5);
+        counters.record("manual", b"0", 3.0);
+
+        assert_eq!(counters.to_json(), concat!(
+            "{ \"balance/profile/save\" : { \"received\" : 1, \"last_payload\" : \"\\\"fast\\\"\", \"last_timestamp\" : 1.5 }, ",
+            "\"manual\" : { \"received\" : 2, \"last_payload\" : \"0\", \"last_timestamp\" : 3 } }"));
+    }
+}
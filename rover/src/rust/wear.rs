@@ -0,0 +1,184 @@
+//
+// Copyright (C) 2020 Abstract Horizon
+// All rights reserved. This program and the accompanying materials
+// are made available under the terms of the Apache License v2.0
+// which accompanies this distribution, and is available at
+// https://www.apache.org/licenses/LICENSE-2.0
+//
+//  Contributors:
+//    Daniel Sendula - initial API and implementation
+//
+
+pub const HISTOGRAM_BUCKETS: usize = 10;
+
+// Loop gaps longer than this (start up, pauses) are not counted as run time.
+const MAX_DELTA_TIME: f64 = 1.0;
+
+
+// Wear of one gearmotor, accumulated from the duty it was commanded.
+#[derive(Clone, Copy, Default, Debug, PartialEq)]
+pub struct MotorWear {
+    // Seconds with nonzero duty
+    pub run_time: f64,
+    // Seconds spent in each tenth of |duty|
+    pub histogram: [f64; HISTOGRAM_BUCKETS],
+    pub reversals: u64,
+    last_direction: i8,
+}
+
+impl MotorWear {
+    pub fn record(&mut self, duty: f32, delta_time: f64) {
+        if duty == 0.0 || !duty.is_finite() {
+            return;
+        }
+
+        let direction = if duty > 0.0 { 1 } else { -1 };
+        if self.last_direction != 0 && self.last_direction != direction {
+            self.reversals += 1;
+        }
+        self.last_direction = direction;
+
+        if delta_time > 0.0 && delta_time <= MAX_DELTA_TIME {
+            let bucket = ((duty.abs() * HISTOGRAM_BUCKETS as f32) as usize).min(HISTOGRAM_BUCKETS - 1);
+            self.run_time += delta_time;
+            self.histogram[bucket] += delta_time;
+        }
+    }
+
+    fn add(&mut self, other: &MotorWear) {
+        self.run_time += other.run_time;
+        for (bucket, other_bucket) in self.histogram.iter_mut().zip(other.histogram.iter()) {
+            *bucket += other_bucket;
+        }
+        self.reversals += other.reversals;
+    }
+
+    fn to_json(self) -> String {
+        let histogram: Vec<String> = self.histogram.iter().map(|seconds| format!("{:.1}", seconds)).collect();
+        format!("{{ \"run_time\" : {:.1}, \"histogram\" : [{}], \"reversals\" : {} }}", self.run_time, histogram.join(", "), self.reversals)
+    }
+}
+
+#[derive(Clone, Copy, Default, Debug, PartialEq)]
+pub struct WearCounters {
+    pub left: MotorWear,
+    pub right: MotorWear,
+}
+
+impl WearCounters {
+    pub fn record(&mut self, left_duty: f32, right_duty: f32, delta_time: f64) {
+        self.left.record(left_duty, delta_time);
+        self.right.record(right_duty, delta_time);
+    }
+
+    // Counters restored from storage are added to whatever accumulated before they arrived.
+    pub fn restore(&mut self, stored: &WearCounters) {
+        self.left.add(&stored.left);
+        self.right.add(&stored.right);
+    }
+
+    pub fn to_json(&self) -> String {
+        format!("{{ \"left\" : {}, \"right\" : {} }}", self.left.to_json(), self.right.to_json())
+    }
+
+    // One motor per line: run time, reversals and then the histogram buckets.
+    pub fn to_storage(&self) -> String {
+        let mut s = String::from("");
+        for (name, wear) in [("left", &self.left), ("right", &self.right)].iter() {
+            let histogram: Vec<String> = wear.histogram.iter().map(|seconds| format!("{}", seconds)).collect();
+            s.push_str(format!("{}={},{},{}\n", name, wear.run_time, wear.reversals, histogram.join(",")).as_str());
+        }
+        s
+    }
+
+    pub fn from_storage(s: &str) -> Result<WearCounters, String> {
+        fn parse(value: &str) -> Result<MotorWear, String> {
+            let values: Vec<&str> = value.split(',').map(|v| v.trim()).collect();
+            if values.len() != 2 + HISTOGRAM_BUCKETS {
+                return Err(format!("expected {} values, got {}", 2 + HISTOGRAM_BUCKETS, values.len()));
+            }
+            let mut wear = MotorWear {
+                run_time: values[0].parse().map_err(|_| format!("invalid run time {:?}", values[0]))?,
+                reversals: values[1].parse().map_err(|_| format!("invalid reversals {:?}", values[1]))?,
+                ..MotorWear::default()
+            };
+            for (bucket, value) in wear.histogram.iter_mut().zip(values[2..].iter()) {
+                *bucket = value.parse().map_err(|_| format!("invalid histogram value {:?}", value))?;
+            }
+            Ok(wear)
+        }
+
+        let mut counters = WearCounters::default();
+        for line in s.lines().map(|line| line.trim()).filter(|line| !line.is_empty()) {
+            match line.find('=').map(|i| (&line[..i], &line[i + 1..])) {
+                Some(("left", value)) => counters.left = parse(value)?,
+                Some(("right", value)) => counters.right = parse(value)?,
+                _ => return Err(format!("unexpected line {:?}", line))
+            }
+        }
+        Ok(counters)
+    }
+}
+
+
+// Keeps the storage writes down to one per interval, and only when something changed.
+pub struct WearPersistence {
+    pub interval: f64,
+    last_persisted_time: Option<f64>,
+    last_persisted: WearCounters,
+}
+
+impl WearPersistence {
+    pub fn new(interval: f64) -> WearPersistence {
+        WearPersistence { interval, last_persisted_time: None, last_persisted: WearCounters::default() }
+    }
+
+    pub fn should_persist(&self, now: f64, counters: &WearCounters) -> bool {
+        *counters != self.last_persisted
+            && self.last_persisted_time.is_none_or(|last| now - last >= self.interval)
+    }
+
+    pub fn persisted(&mut self, now: f64, counters: &WearCounters) {
+        self.last_persisted_time = Some(now);
+        self.last_persisted = *counters;
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counters_restored_from_storage_add_to_the_new_ones() {
+        let mut before = WearCounters::default();
+        before.record(0.55, -0.2, 0.5);
+        before.record(-0.55, 0.0, 0.5);
+
+        let mut after = WearCounters::default();
+        after.record(1.0, 0.0, 0.25);
+        after.restore(&WearCounters::from_storage(&before.to_storage()).unwrap());
+
+        assert_eq!(after.left.run_time, 1.25);
+        assert_eq!(after.left.reversals, 1);
+        assert_eq!(after.left.histogram[5], 1.0);
+        assert_eq!(after.left.histogram[9], 0.25);
+        assert_eq!(after.right.run_time, 0.5);
+        assert_eq!(after.right.histogram[2], 0.5);
+    }
+
+    #[test]
+    fn writes_are_debounced_to_the_interval_and_only_on_change() {
+        let mut counters = WearCounters::default();
+        counters.record(0.55, -0.2, 0.5);
+
+        let mut persistence = WearPersistence::new(60.0);
+        assert!(persistence.should_persist(100.0, &counters));
+        persistence.persisted(100.0, &counters);
+        assert!(!persistence.should_persist(200.0, &counters));
+
+        counters.record(1.0, 0.0, 0.25);
+        assert!(!persistence.should_persist(130.0, &counters));
+        assert!(persistence.should_persist(160.0, &counters));
+    }
+}
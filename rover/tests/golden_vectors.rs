@@ -0,0 +1,91 @@
+//
+// Copyright (C) 2020 Abstract Horizon
+// All rights reserved. This program and the accompanying materials
+// are made available under the terms of the Apache License v2.0
+// which accompanies this distribution, and is available at
+// https://www.apache.org/licenses/LICENSE-2.0
+//
+//  Contributors:
+//    Daniel Sendula - initial API and implementation
+//
+
+// Inputs and expected outputs generated by src/python/balance/golden_vectors.py from the Python
+// implementation (see there for what each column is and where the port differs). The formulas
+// are the same on both sides, so only the last bits of atan2, sqrt and sin may differ between
+// libm and the C library Python uses.
+
+use std::f64::consts::PI;
+
+use balancing_rover::balance::ConfigData;
+use balancing_rover::orientation::{self, Orientation, ROVER_AXIS_MAPPING};
+use balancing_rover::pid::{PID, SIMPLE_DIFFERENCE};
+
+const GOLDEN_TOLERANCE: f64 = 1e-9;
+
+
+fn golden_sequence(name: &str, csv: &str) {
+    let mut lines = csv.lines();
+    let columns: Vec<&str> = lines.next().expect("empty file").split(',').collect();
+    let column = |column_name: &str| columns.iter().position(|column| *column == column_name)
+        .unwrap_or_else(|| panic!("{}: no column {}", name, column_name));
+    let (time, gyro_x, gyro_y, gyro_z) = (column("time"), column("gyro_x"), column("gyro_y"), column("gyro_z"));
+    let (accel_x, accel_y, accel_z) = (column("accel_x"), column("accel_y"), column("accel_z"));
+    let (cy, pid_column, roll, yaw, rust_pid) = (column("cy"), column("pid"), column("roll"), column("yaw"), column("rust_pid"));
+
+    let config_data = ConfigData::new();
+    // dt is 1 / freq for the filter in both, as the gyro delivers samples at that rate
+    let dt = 1.0 / config_data.freq as f64;
+    let mut orientation = Orientation::new();
+    // The PID's defaults in pid.py, unlimited as there is no anti-windup in Python
+    let mut reference_pid = PID::new(0.75, 0.2, 0.05, 1.0, 0.0001, 1.0, 100.0, SIMPLE_DIFFERENCE);
+    // As the balance loop sets it up
+    let mut balance_pid = PID::new(
+        config_data.pid_kp, config_data.pid_ki, config_data.pid_kd,
+        config_data.pid_gain, config_data.dead_band,
+        config_data.i_gain_scale, config_data.d_gain_scale, SIMPLE_DIFFERENCE).with_i_limit(config_data.pid_i_limit);
+
+    for (row_no, line) in lines.enumerate() {
+        let row = line.split(',').map(|value| value.parse::<f64>()).collect::<Result<Vec<f64>, _>>()
+            .unwrap_or_else(|e| panic!("{} row {}: {}", name, row_no, e));
+        let expect = |what: &str, column: usize, actual: f64| {
+            assert!((row[column] - actual).abs() <= GOLDEN_TOLERANCE, "{} row {}: {} is {}, expected {}", name, row_no, what, actual, row[column]);
+        };
+
+        let (gravity_x, gravity_y, gravity_z) = ROVER_AXIS_MAPPING.to_body(row[accel_x], row[accel_y], row[accel_z]);
+        let (rate_x, rate_y, rate_z) = ROVER_AXIS_MAPPING.to_body(row[gyro_x], row[gyro_y], row[gyro_z]);
+        orientation.update(
+            (rate_x, rate_y, rate_z),
+            (orientation::tilt_about_x(gravity_x, gravity_y, gravity_z), orientation::tilt_about_y(gravity_x, gravity_y, gravity_z)),
+            dt, config_data.combine_gyro_accel_factor);
+
+        // Same as cy in Python
+        expect("pitch", cy, orientation.pitch);
+        // Python integrates the yaw rate into cx; the roll rate is used here
+        expect("roll", roll, orientation.roll);
+        // Python pulls cz towards an accel tilt; yaw is not observable from gravity, so only integrated here
+        expect("yaw", yaw, orientation.yaw);
+
+        // Same controller given the same input as in Python, but with the sample time where
+        // the Python loop passes a time that never advances
+        let output = reference_pid.process(row[time], 0.0, (orientation.pitch * PI / 90.0).sin() * 2.0);
+        expect("pid", pid_column, output);
+        // Pitch is used unshaped against a set point of -2.6, with the integral term limited
+        let output = balance_pid.process(row[time], -2.6, orientation.pitch);
+        expect("balance pid", rust_pid, output);
+    }
+}
+
+#[test]
+fn standing() {
+    golden_sequence("standing", include_str!("golden_vectors/standing.csv"));
+}
+
+#[test]
+fn falling() {
+    golden_sequence("falling", include_str!("golden_vectors/falling.csv"));
+}
+
+#[test]
+fn turning() {
+    golden_sequence("turning", include_str!("golden_vectors/turning.csv"));
+}